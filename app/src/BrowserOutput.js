@@ -0,0 +1,161 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+"use strict";
+
+// Playing the queue in the browser, see docs/api.md#browser-output. The server
+// decides what plays: we report our progress about once per second, and the
+// response tells us which entry to play, and at what level.
+
+const REPORT_INTERVAL_MS = 1000;
+
+// The output that is playing, or null when we play on the audio card.
+let current = null;
+
+function canPlayFlac() {
+  return new Audio().canPlayType("audio/flac") !== "";
+}
+
+function streamUrl(url) {
+  // Track urls point to our own server, which can transcode. Radio urls point
+  // to the station, we play those as they are.
+  if (url.startsWith("api/track/") && !canPlayFlac()) {
+    return url + "?format=opus";
+  }
+  return url;
+}
+
+function halt(output) {
+  if (current !== output) {
+    return;
+  }
+  current = null;
+  clearInterval(output.timer);
+  output.audio.pause();
+  output.audio.removeAttribute("src");
+  output.onStop();
+}
+
+function follow(output, instruction) {
+  if (current !== output) {
+    return;
+  }
+  if (instruction.level !== null) {
+    output.audio.volume = instruction.level;
+  }
+  if (instruction.queue_id === output.queueId) {
+    return;
+  }
+  output.queueId = instruction.queue_id;
+  if (instruction.url === null) {
+    output.audio.pause();
+    output.audio.removeAttribute("src");
+  } else {
+    output.audio.src = streamUrl(instruction.url);
+    // When playback moved here from another output, the track continues where
+    // it was, rather than from the start.
+    output.audio.currentTime = instruction.start_seconds;
+    // If the browser refuses to play, the error event reports it.
+    output.audio.play().catch(function() {});
+  }
+}
+
+function report(output, kind, query) {
+  const url = "api/browser/" + output.session + "/" + kind + query;
+  fetch(url, { method: "POST" })
+    .then(function(response) {
+      // The session is over, another output took over.
+      if (response.status === 404) {
+        halt(output);
+        return;
+      }
+      // On other errors, we try again with the next report.
+      if (response.ok) {
+        return response.json().then(function(instruction) {
+          follow(output, instruction);
+        });
+      }
+    })
+    .catch(function() {});
+}
+
+function tick(output) {
+  const audio = output.audio;
+  const queueId = output.queueId;
+  if (queueId === null) {
+    report(output, "progress", "");
+  } else if (audio.error !== null) {
+    report(output, "failed", "?queue_id=" + queueId);
+  } else if (audio.ended) {
+    // We report this on the ended event already, but if that report got lost,
+    // we would be stuck at the end of the track forever.
+    report(output, "ended", "?queue_id=" + queueId);
+  } else if (audio.currentTime > 0) {
+    const positionMs = Math.floor(audio.currentTime * 1000);
+    report(output, "progress", "?queue_id=" + queueId + "&position_ms=" + positionMs);
+  } else {
+    // Still loading, but we need to let the server know that we are here.
+    report(output, "progress", "");
+  }
+}
+
+export const isPlaying = function() {
+  return current !== null;
+}
+
+export const start = function(onStop) {
+  return function() {
+    if (current !== null) {
+      return;
+    }
+    const output = {
+      session: null,
+      queueId: null,
+      // We create the element in response to the click, browsers only allow
+      // playback that the user started.
+      audio: new Audio(),
+      timer: null,
+      onStop: onStop,
+    };
+    current = output;
+    output.audio.addEventListener("ended", function() {
+      if (output.queueId !== null) {
+        report(output, "ended", "?queue_id=" + output.queueId);
+      }
+    });
+    output.audio.addEventListener("error", function() {
+      if (output.queueId !== null) {
+        report(output, "failed", "?queue_id=" + output.queueId);
+      }
+    });
+
+    fetch("api/browser", { method: "PUT" })
+      .then(function(response) {
+        if (!response.ok) {
+          throw new Error("Failed to start playing in the browser.");
+        }
+        return response.json();
+      })
+      .then(function(instruction) {
+        output.session = instruction.session;
+        follow(output, instruction);
+        output.timer = setInterval(function() { tick(output); }, REPORT_INTERVAL_MS);
+      })
+      .catch(function() { halt(output); });
+  }
+}
+
+export const stop = function() {
+  const output = current;
+  if (output === null) {
+    return;
+  }
+  if (output.session !== null) {
+    fetch("api/browser/" + output.session, { method: "DELETE" }).catch(function() {});
+  }
+  halt(output);
+}
@@ -0,0 +1,119 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+// Service worker that makes the webinterface load without a connection.
+//
+// The shell (the page, app.js, styles, and icons) comes from the network when
+// we can reach the server, and from the cache otherwise. The album list is
+// large, so we keep it in the cache, together with the library generation
+// from /api/sync, and only fetch it again when the generation changed.
+
+"use strict";
+
+const CACHE_NAME = "musium-v1";
+
+const SHELL = [
+  "./",
+  "app.js",
+  "style.css",
+  "manifest.json",
+  "icon.svg",
+  "icon-lowres.svg",
+];
+
+const GENERATION_HEADER = "X-Musium-Generation";
+
+const resolve = function(path) {
+  return new URL(path, self.registration.scope).href;
+}
+
+self.addEventListener("install", function(event) {
+  event.waitUntil(
+    caches.open(CACHE_NAME)
+      .then(cache => cache.addAll(SHELL))
+      .then(() => self.skipWaiting())
+  );
+});
+
+self.addEventListener("activate", function(event) {
+  event.waitUntil(
+    caches.keys()
+      .then(names => Promise.all(
+        names.filter(name => name !== CACHE_NAME).map(name => caches.delete(name))
+      ))
+      .then(() => self.clients.claim())
+  );
+});
+
+// Try the network, and update the cache with the response. Fall back to the
+// cache when the server is unreachable.
+const networkFirst = async function(request, cacheKey) {
+  const cache = await caches.open(CACHE_NAME);
+  try {
+    const response = await fetch(request);
+    if (response.ok) {
+      await cache.put(cacheKey, response.clone());
+    }
+    return response;
+  } catch (err) {
+    const cached = await cache.match(cacheKey);
+    if (cached) {
+      return cached;
+    }
+    throw err;
+  }
+}
+
+// Serve the album list from the cache, unless the library changed since we
+// stored it, or we have not stored it yet.
+const albumList = async function(request) {
+  const cache = await caches.open(CACHE_NAME);
+  const cached = await cache.match(request);
+  let generation;
+  try {
+    const sync = await fetch(resolve("api/sync"), { cache: "no-cache" });
+    generation = (await sync.json()).generation;
+  } catch (err) {
+    if (cached) {
+      return cached;
+    }
+    throw err;
+  }
+
+  if (cached && cached.headers.get(GENERATION_HEADER) === generation) {
+    return cached;
+  }
+
+  const response = await fetch(request);
+  if (response.ok) {
+    // The body is decoded already, so we only keep the content type of the
+    // original headers, and record the generation with it.
+    const body = await response.clone().arrayBuffer();
+    const headers = new Headers();
+    headers.set("Content-Type", response.headers.get("Content-Type"));
+    headers.set(GENERATION_HEADER, generation);
+    await cache.put(request, new Response(body, { headers: headers }));
+  }
+  return response;
+}
+
+self.addEventListener("fetch", function(event) {
+  const request = event.request;
+  if (request.method !== "GET") {
+    return;
+  }
+
+  const url = request.url;
+  if (request.mode === "navigate" && url === resolve("./")) {
+    event.respondWith(networkFirst(request, resolve("./")));
+  } else if (url === resolve("api/albums")) {
+    event.respondWith(albumList(request));
+  } else if (SHELL.map(resolve).includes(url)) {
+    event.respondWith(networkFirst(request, url));
+  }
+  // Other requests go to the network as usual.
+});
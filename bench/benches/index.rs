@@ -0,0 +1,30 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use musium::database::Connection;
+
+fn bench_index_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index_build");
+    group.sample_size(10);
+
+    for &num_tracks in &[10_000, 50_000] {
+        let connection = musium_bench::open_database().unwrap();
+        let mut db = Connection::new(&connection);
+        musium_bench::generate_library(&mut db, num_tracks).unwrap();
+
+        group.throughput(Throughput::Elements(num_tracks as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(num_tracks), &num_tracks, |b, _| {
+            b.iter(|| musium_bench::build_index(&mut db).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_index_build);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use musium::database::Connection;
+use musium::string_utils::normalize_words;
+use musium::MetaIndex;
+
+/// Queries that exercise a prefix, multiple words, and a typo.
+///
+/// The words are built from the same syllables as the generated library, so
+/// they have matches.
+const QUERIES: &[&str] = &["ka", "lomi", "venra dosu", "torelan", "solith marq"];
+
+fn bench_search(c: &mut Criterion) {
+    let connection = musium_bench::open_database().unwrap();
+    let mut db = Connection::new(&connection);
+    musium_bench::generate_library(&mut db, 50_000).unwrap();
+    let index = musium_bench::build_index(&mut db).unwrap();
+
+    c.bench_function("normalize_words", |b| {
+        let mut words = Vec::new();
+        b.iter(|| {
+            for query in QUERIES {
+                words.clear();
+                normalize_words(black_box(query), &mut words);
+            }
+        })
+    });
+
+    let mut group = c.benchmark_group("search");
+
+    for &max_edits in &[0, 1] {
+        group.bench_with_input(BenchmarkId::new("all", max_edits), &max_edits, |b, &max_edits| {
+            let mut words = Vec::new();
+            let mut artists = Vec::new();
+            let mut albums = Vec::new();
+            let mut tracks = Vec::new();
+            b.iter(|| {
+                for query in QUERIES {
+                    words.clear();
+                    artists.clear();
+                    albums.clear();
+                    tracks.clear();
+                    normalize_words(query, &mut words);
+                    index.search_artist(&words, max_edits, &mut artists);
+                    index.search_album(&words, max_edits, &mut albums);
+                    index.search_track(&words, max_edits, &mut tracks);
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_search);
+criterion_main!(benches);
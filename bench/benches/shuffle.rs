@@ -0,0 +1,36 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use musium::database::Connection;
+use musium::shuffle::{self, Prng};
+
+fn bench_shuffle(c: &mut Criterion) {
+    let connection = musium_bench::open_database().unwrap();
+    let mut db = Connection::new(&connection);
+    musium_bench::generate_library(&mut db, 50_000).unwrap();
+    let index = musium_bench::build_index(&mut db).unwrap();
+
+    let mut group = c.benchmark_group("shuffle");
+
+    for &len in &[1_000, 10_000, 50_000] {
+        group.throughput(Throughput::Elements(len as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &len, |b, &len| {
+            let mut rng = Prng::new_seed(42);
+            b.iter_batched_ref(
+                || musium_bench::build_queue(&index, len),
+                |queue| shuffle::shuffle(&index, &mut rng, queue),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_shuffle);
+criterion_main!(benches);
@@ -0,0 +1,173 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Synthetic libraries for benchmarking.
+//!
+//! The benchmarks should not depend on anybody's music collection, so we
+//! generate a library of the requested size instead, with made up names that
+//! are deterministic, so runs are comparable. We write the metadata into an
+//! in-memory database, the same way a scan would, and build the index from
+//! there, so index construction is benchmarked end to end.
+
+use musium::database::{self as db, Connection};
+use musium::database_utils;
+use musium::error::Result;
+use musium::player::{QueueId, QueuedTrack, Source};
+use musium::prim::Lufs;
+use musium::{MemoryMetaIndex, MetaIndex};
+
+/// Syllables to build words from. They only use ASCII letters, so every word
+/// survives normalization unchanged.
+const SYLLABLES: &[&str] = &[
+    "ka", "lo", "mi", "ra", "ven", "do", "su", "tor", "el", "an", "bri", "gar",
+    "ne", "sol", "u", "ith", "mar", "qu", "zen", "po", "li", "fa", "der", "oth",
+];
+
+/// A small deterministic generator, we only need variety, not quality.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        for _ in 0..2 + self.below(2) {
+            word.push_str(SYLLABLES[self.below(SYLLABLES.len() as u64) as usize]);
+        }
+        word
+    }
+
+    /// Return 1 to `max_words` capitalized words.
+    fn name(&mut self, max_words: u64) -> String {
+        let mut words = Vec::new();
+        for _ in 0..1 + self.below(max_words) {
+            let word = self.word();
+            let mut chars = word.chars();
+            let first = chars.next().expect("Words are not empty.");
+            words.push(first.to_ascii_uppercase().to_string() + chars.as_str());
+        }
+        words.join(" ")
+    }
+}
+
+/// Format a MusicBrainz-like id from the counter, spread over the id space.
+///
+/// Musium uses the first and last digits of the id, see `build::parse_uuid`,
+/// so we fill those with bits of a hash of the counter.
+fn mbid(kind: u64, i: u64) -> String {
+    let h = (kind << 40 | i).wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    format!("{:08x}-0000-4000-8000-{:012x}", h >> 32, h & 0xffff_ffff_ffff)
+}
+
+/// Fill the database with a library of roughly `num_tracks` tracks.
+///
+/// Albums have 8 to 16 tracks, and artists have 1 to 12 albums. The database
+/// must be empty.
+pub fn generate_library(db: &mut Connection, num_tracks: usize) -> Result<()> {
+    let mut rng = Lcg(42);
+    let mut tx = db.begin()?;
+
+    let mut n_tracks = 0;
+    let mut i_artist = 0;
+    let mut i_album = 0;
+
+    while n_tracks < num_tracks {
+        let artist = rng.name(3);
+        let artist_mbid = mbid(1, i_artist);
+        i_artist += 1;
+
+        for _ in 0..1 + rng.below(12) {
+            let album = rng.name(4);
+            let album_mbid = mbid(2, i_album);
+            let year = 1960 + rng.below(64);
+            i_album += 1;
+
+            for track_number in 1..=8 + rng.below(9) {
+                let title = rng.name(5);
+                let duration_seconds = 120 + rng.below(360) as i64;
+                let filename = format!("/music/{}/{} ({})/{:02} {}.flac", artist, album, year, track_number, title);
+                let file_id = db::insert_file(&mut tx, db::InsertFile {
+                    filename: &filename,
+                    mtime: 1_690_000_000,
+                    imported_at: "2023-07-22T04:26:40Z",
+                    streaminfo_channels: 2,
+                    streaminfo_bits_per_sample: 16,
+                    streaminfo_num_samples: Some(duration_seconds * 44_100),
+                    streaminfo_sample_rate: 44_100,
+                    size_bytes: duration_seconds * 110_000,
+                    inode: n_tracks as i64,
+                    streaminfo_md5: None,
+                })?;
+
+                let tags = [
+                    ("title", title),
+                    ("artist", artist.clone()),
+                    ("album", album.clone()),
+                    ("albumartist", artist.clone()),
+                    ("albumartistsort", artist.clone()),
+                    ("musicbrainz_albumartistid", artist_mbid.clone()),
+                    ("musicbrainz_albumid", album_mbid.clone()),
+                    ("originaldate", year.to_string()),
+                    ("date", year.to_string()),
+                    ("tracknumber", track_number.to_string()),
+                    ("discnumber", "1".to_string()),
+                ];
+                for (field_name, value) in &tags {
+                    db::insert_tag(&mut tx, file_id, field_name, value)?;
+                }
+
+                n_tracks += 1;
+            }
+        }
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Build the index from a database filled by `generate_library`.
+pub fn build_index(db: &mut Connection) -> Result<MemoryMetaIndex> {
+    // The album identity type is not exported, but we can parse it.
+    let album_identity = "musicbrainz".parse().expect("Valid album identity.");
+    let mut tx = db.begin()?;
+    let (index, _builder) = MemoryMetaIndex::from_database(&mut tx, album_identity)?;
+    tx.commit()?;
+    Ok(index)
+}
+
+/// Open an in-memory database, with the schema in place.
+pub fn open_database() -> Result<sqlite::Connection> {
+    let connection = database_utils::connect_read_write(":memory:")?;
+    database_utils::migrate(&connection)?;
+    Ok(connection)
+}
+
+/// Return a play queue of `len` tracks, cycling through the tracks of the index.
+pub fn build_queue(index: &MemoryMetaIndex, len: usize) -> Vec<QueuedTrack> {
+    index
+        .get_tracks()
+        .iter()
+        .cycle()
+        .take(len)
+        .enumerate()
+        .map(|(i, kv)| QueuedTrack::new(
+            QueueId(i as u64),
+            Source::Track(kv.track_id),
+            None,
+            None,
+            Lufs::default(),
+            Lufs::default(),
+        ))
+        .collect()
+}
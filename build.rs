@@ -0,0 +1,75 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Build script that embeds the webinterface in the binary, see `src/assets.rs`.
+//!
+//! Building the webinterface needs the Purescript toolchain, we don't run that
+//! from here. When `app/output/app.js` does not exist, we build without it, and
+//! the server can only serve the webinterface from `webinterface_dir`.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Static files in app/ with these extensions are part of the webinterface.
+const EXTENSIONS: [&str; 5] = ["css", "html", "js", "json", "svg"];
+
+/// Return whether the precompressed copy at `compressed` is up to date.
+fn is_fresh(original: &Path, compressed: &Path) -> bool {
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    match (modified(original), modified(compressed)) {
+        (Some(t0), Some(t1)) => t1 >= t0,
+        _ => false,
+    }
+}
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let app_dir = manifest_dir.join("app");
+    println!("cargo:rerun-if-changed=app");
+
+    // Pairs of (name in the url, path of the file).
+    let mut assets: Vec<(String, PathBuf)> = Vec::new();
+
+    for entry in fs::read_dir(&app_dir).expect("Failed to list app directory.") {
+        let path = entry.expect("Failed to list app directory.").path();
+        let is_asset = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| EXTENSIONS.contains(&ext));
+        if is_asset {
+            let name = path.file_name().unwrap().to_str().unwrap().to_string();
+            assets.push((name, path));
+        }
+    }
+
+    let app_js = app_dir.join("output").join("app.js");
+    if app_js.is_file() {
+        for suffix in ["", ".br", ".gz"].iter() {
+            let path = app_dir.join("output").join(format!("app.js{}", suffix));
+            if suffix.is_empty() || is_fresh(&app_js, &path) {
+                assets.push((format!("app.js{}", suffix), path));
+            }
+        }
+    } else {
+        println!(
+            "cargo:warning=app/output/app.js does not exist, building without \
+            the webinterface. Run 'make -C app' first to include it."
+        );
+    }
+
+    assets.sort();
+
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("assets.rs");
+    let mut out = fs::File::create(out_path).expect("Failed to create assets.rs.");
+    writeln!(out, "pub static EMBEDDED: &[(&str, &[u8])] = &[").unwrap();
+    for (name, path) in &assets {
+        writeln!(out, "    ({:?}, include_bytes!({:?})),", name, path).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+}
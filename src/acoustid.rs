@@ -0,0 +1,467 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Proposing tags for files that lack them, based on audio fingerprints.
+//!
+//! Files without the tags that Musium needs are not part of the library. When
+//! `acoustid_api_key` is configured, a scan computes the Chromaprint
+//! fingerprint of those files with `fpcalc`, and looks it up through the
+//! AcoustID API, which links fingerprints to MusicBrainz recordings. The tags
+//! of the best match go into the `acoustid_proposals` table, where they wait
+//! for review through the API. Accepting a proposal stores its tags like a
+//! metadata edit does, see `metadata_edit.rs`.
+//!
+//! Every file is looked up only once, the `acoustid_lookups` table records
+//! which files we looked up. Files get a new id when they change on disk, so
+//! after fixing the tags of a file by hand, it is not looked up again, but a
+//! file that changes without getting usable tags is.
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::SyncSender;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::build::BuildMetaIndex;
+use crate::database::{self as db, Connection, Transaction};
+use crate::error::{Error, Result};
+use crate::flac_tags::{self, TagUpdate};
+use crate::metadata_edit::WrittenFile;
+use crate::scan::Status;
+
+const API_URL: &str = "https://api.acoustid.org/v2/lookup";
+
+/// AcoustID allows at most three requests per second.
+const REQUEST_INTERVAL: Duration = Duration::from_millis(334);
+
+/// Matches with a lower score are likely wrong, we don't propose those.
+const MIN_SCORE: f64 = 0.5;
+
+#[derive(Debug, PartialEq)]
+pub struct Fingerprint {
+    pub duration_seconds: u32,
+    pub fingerprint: String,
+}
+
+/// Parse the output of `fpcalc -json`.
+fn parse_fpcalc_output(output: &[u8]) -> Option<Fingerprint> {
+    let json: Value = serde_json::from_slice(output).ok()?;
+    Some(Fingerprint {
+        duration_seconds: json.get("duration")?.as_f64()?.round() as u32,
+        fingerprint: json.get("fingerprint")?.as_str()?.to_string(),
+    })
+}
+
+/// Compute the Chromaprint fingerprint of the file with `fpcalc`.
+pub fn fingerprint(path: &Path) -> Result<Fingerprint> {
+    let output = Command::new("fpcalc")
+        .arg("-json")
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| Error::CommandError("Failed to spawn 'fpcalc'.", e))?;
+
+    if !output.status.success() {
+        return Err(Error::AcoustIdError(format!(
+            "fpcalc exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )));
+    }
+
+    parse_fpcalc_output(&output.stdout)
+        .ok_or_else(|| Error::AcoustIdError("Invalid output from fpcalc.".to_string()))
+}
+
+/// Look up the fingerprint, return the response.
+pub fn lookup(api_key: &str, fingerprint: &Fingerprint) -> Result<Value> {
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("client", api_key)
+        .append_pair("meta", "recordings releases tracks")
+        .append_pair("duration", &fingerprint.duration_seconds.to_string())
+        .append_pair("fingerprint", &fingerprint.fingerprint)
+        .finish();
+
+    let mut curl = Command::new("curl")
+        .args(["--silent", "--show-error"])
+        .args(["--max-time", "30"])
+        // Fingerprints are too long for a url, so we post them, and the
+        // request body comes from stdin.
+        .args(["--data-binary", "@-"])
+        .arg(API_URL)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::CommandError("Failed to spawn 'curl'.", e))?;
+
+    {
+        let stdin = curl.stdin.as_mut().expect("Stdin is piped.");
+        stdin
+            .write_all(body.as_bytes())
+            .map_err(|e| Error::CommandError("Failed to write to 'curl'.", e))?;
+    }
+
+    let output = curl
+        .wait_with_output()
+        .map_err(|e| Error::CommandError("Failed to wait for 'curl'.", e))?;
+
+    if !output.status.success() {
+        return Err(Error::AcoustIdError(format!(
+            "curl exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )));
+    }
+
+    let response: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::AcoustIdError(format!("Invalid response: {}", e)))?;
+
+    if response.get("status").and_then(|s| s.as_str()) != Some("ok") {
+        return Err(Error::AcoustIdError(format!(
+            "Error: {}",
+            response.pointer("/error/message").and_then(|m| m.as_str()).unwrap_or(""),
+        )));
+    }
+
+    Ok(response)
+}
+
+/// The tags that we propose for a file, named like in the `tags` table.
+#[derive(Debug, PartialEq)]
+pub struct Proposal {
+    pub acoustid: String,
+    pub score: f64,
+    pub musicbrainz_trackid: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub musicbrainz_albumid: String,
+    pub albumartist: String,
+    pub musicbrainz_albumartistid: String,
+    pub tracknumber: i64,
+    pub discnumber: i64,
+    pub originaldate: String,
+}
+
+/// Join an artist credit, including the join phrases, like "A feat. B".
+fn format_artist_credit(artists: &[Value]) -> Option<String> {
+    let mut result = String::new();
+    for artist in artists {
+        result.push_str(artist.get("name")?.as_str()?);
+        if let Some(join) = artist.get("joinphrase").and_then(|j| j.as_str()) {
+            result.push_str(join);
+        }
+    }
+    match result.is_empty() {
+        true => None,
+        false => Some(result),
+    }
+}
+
+/// Format an AcoustID date object as YYYY, YYYY-MM, or YYYY-MM-DD.
+fn format_date(date: &Value) -> Option<(String, (u64, u64, u64))> {
+    let year = date.get("year")?.as_u64()?;
+    let month = date.get("month").and_then(|m| m.as_u64());
+    let day = date.get("day").and_then(|d| d.as_u64());
+    let formatted = match (month, day) {
+        (Some(m), Some(d)) => format!("{:04}-{:02}-{:02}", year, m, d),
+        (Some(m), None) => format!("{:04}-{:02}", year, m),
+        _ => format!("{:04}", year),
+    };
+    Some((formatted, (year, month.unwrap_or(0), day.unwrap_or(0))))
+}
+
+/// Extract the release fields of a proposal from a release of the recording.
+///
+/// Returns the sort key for the release date along with the proposal, we
+/// prefer the earliest release.
+fn proposal_from_release(
+    result: &Value,
+    recording: &Value,
+    release: &Value,
+) -> Option<((u64, u64, u64), Proposal)> {
+    let album_artists = release.get("artists")?.as_array()?;
+    let medium = release.get("mediums")?.as_array()?.first()?;
+    let track = medium.get("tracks")?.as_array()?.first()?;
+    let (originaldate, date_key) = format_date(release.get("date")?)?;
+
+    let proposal = Proposal {
+        acoustid: result.get("id")?.as_str()?.to_string(),
+        score: result.get("score")?.as_f64()?,
+        musicbrainz_trackid: recording.get("id")?.as_str()?.to_string(),
+        title: recording.get("title")?.as_str()?.to_string(),
+        artist: format_artist_credit(recording.get("artists")?.as_array()?)?,
+        album: release.get("title")?.as_str()?.to_string(),
+        musicbrainz_albumid: release.get("id")?.as_str()?.to_string(),
+        albumartist: format_artist_credit(album_artists)?,
+        // For albums with multiple artists, the albumartists tag would be
+        // needed to pair names with ids, we only propose the first artist.
+        musicbrainz_albumartistid: album_artists.first()?.get("id")?.as_str()?.to_string(),
+        tracknumber: track.get("position")?.as_i64()?,
+        discnumber: medium.get("position")?.as_i64()?,
+        originaldate: originaldate,
+    };
+    Some((date_key, proposal))
+}
+
+/// Pick the best match from a lookup response.
+///
+/// We take the result with the highest score that has a recording with a
+/// dated release, and of those releases the earliest one, which is most likely
+/// the original release.
+pub fn best_proposal(response: &Value) -> Option<Proposal> {
+    let mut best: Option<((u64, u64, u64), Proposal)> = None;
+
+    for result in response.get("results")?.as_array()? {
+        let score = result.get("score").and_then(|s| s.as_f64()).unwrap_or(0.0);
+        if score < MIN_SCORE {
+            continue;
+        }
+        let empty = Vec::new();
+        let recordings = result.get("recordings").and_then(|r| r.as_array()).unwrap_or(&empty);
+        for recording in recordings {
+            let releases = recording.get("releases").and_then(|r| r.as_array()).unwrap_or(&empty);
+            for release in releases {
+                let candidate = match proposal_from_release(result, recording, release) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                best = match best {
+                    Some(b) if b.1.score > candidate.1.score => Some(b),
+                    Some(b) if b.1.score == candidate.1.score && b.0 <= candidate.0 => Some(b),
+                    _ => Some(candidate),
+                };
+            }
+        }
+    }
+
+    best.map(|(_, proposal)| proposal)
+}
+
+fn store_proposal(tx: &mut Transaction, file_id: i64, p: &Proposal) -> db::Result<()> {
+    db::insert_acoustid_proposal(tx, db::InsertAcoustidProposal {
+        file_id: file_id,
+        acoustid: &p.acoustid,
+        score: p.score,
+        musicbrainz_trackid: &p.musicbrainz_trackid,
+        title: &p.title,
+        artist: &p.artist,
+        album: &p.album,
+        musicbrainz_albumid: &p.musicbrainz_albumid,
+        albumartist: &p.albumartist,
+        musicbrainz_albumartistid: &p.musicbrainz_albumartistid,
+        tracknumber: p.tracknumber,
+        discnumber: p.discnumber,
+        originaldate: &p.originaldate,
+    })
+}
+
+/// Fingerprint the files that the index left out for lack of tags, and store
+/// proposals for the ones that AcoustID knows.
+///
+/// Failures for individual files are logged, those files are retried on the
+/// next scan. When `fpcalc` is not installed, we stop after the first file.
+pub fn fingerprint_files(
+    db: &mut Connection,
+    api_key: &str,
+    builder: &BuildMetaIndex,
+    looked_up_at: &str,
+    status: &mut Status,
+    status_sender: &mut SyncSender<Status>,
+) -> Result<()> {
+    let mut tx = db.begin()?;
+    let done = db::iter_acoustid_lookups(&mut tx)?.collect::<db::Result<HashSet<i64>>>()?;
+    tx.commit()?;
+
+    let files: Vec<_> = builder
+        .files_missing_tags
+        .iter()
+        .filter(|(file_id, _)| !done.contains(&file_id.0))
+        .collect();
+
+    status.files_to_fingerprint = files.len() as u64;
+    status_sender.send(*status).unwrap();
+
+    let mut last_request: Option<Instant> = None;
+
+    for (file_id, filename) in files {
+        let path = Path::new(&builder.filenames[filename.0 as usize]);
+        let fingerprint = match fingerprint(path) {
+            Ok(fp) => fp,
+            Err(err @ Error::CommandError(..)) => {
+                error!("Cannot fingerprint files, is Chromaprint installed? {:?}", err);
+                return Ok(());
+            }
+            Err(err) => {
+                warn!("Failed to fingerprint {:?}: {:?}", path, err);
+                continue;
+            }
+        };
+
+        if let Some(t) = last_request {
+            let elapsed = t.elapsed();
+            if elapsed < REQUEST_INTERVAL {
+                std::thread::sleep(REQUEST_INTERVAL - elapsed);
+            }
+        }
+        last_request = Some(Instant::now());
+
+        let response = match lookup(api_key, &fingerprint) {
+            Ok(response) => response,
+            Err(err) => {
+                warn!("Failed to look up the fingerprint of {:?}: {:?}", path, err);
+                continue;
+            }
+        };
+
+        let mut tx = db.begin()?;
+        if let Some(proposal) = best_proposal(&response) {
+            store_proposal(&mut tx, file_id.0, &proposal)?;
+        }
+        db::insert_acoustid_lookup(&mut tx, file_id.0, looked_up_at)?;
+        tx.commit()?;
+
+        status.files_fingerprinted += 1;
+        status_sender.send(*status).unwrap();
+    }
+
+    Ok(())
+}
+
+/// The tag updates that accepting the proposal makes.
+///
+/// Besides setting the proposed tags, this removes tags that would conflict
+/// with them, such as an unparseable `date`, or sort names for other artists.
+pub fn tag_updates(p: &db::AcoustidProposal) -> Vec<(&'static str, Option<String>)> {
+    vec![
+        ("musicbrainz_trackid", Some(p.musicbrainz_trackid.clone())),
+        ("title", Some(p.title.clone())),
+        ("artist", Some(p.artist.clone())),
+        ("album", Some(p.album.clone())),
+        ("musicbrainz_albumid", Some(p.musicbrainz_albumid.clone())),
+        ("albumartist", Some(p.albumartist.clone())),
+        ("musicbrainz_albumartistid", Some(p.musicbrainz_albumartistid.clone())),
+        ("tracknumber", Some(p.tracknumber.to_string())),
+        ("discnumber", Some(p.discnumber.to_string())),
+        ("originaldate", Some(p.originaldate.clone())),
+        ("date", None),
+        ("albumartists", None),
+        ("albumartistsort", None),
+        ("albumartistssort", None),
+    ]
+}
+
+/// Write the tags of the proposal into the file.
+pub fn write_file(p: &db::AcoustidProposal) -> Result<WrittenFile> {
+    let updates = tag_updates(p);
+    let updates: Vec<TagUpdate> = updates
+        .iter()
+        .map(|(field_name, value)| TagUpdate { field_name: *field_name, value: value.as_deref() })
+        .collect();
+    let path = Path::new(&p.filename);
+    flac_tags::write_tags(path, &updates)?;
+
+    let metadata = path.metadata()?;
+    Ok(WrittenFile {
+        file_id: p.file_id,
+        mtime: metadata.mtime(),
+        size_bytes: metadata.len() as i64,
+        inode: metadata.ino() as i64,
+    })
+}
+
+/// Store the tags of the proposal in the database, and mark it accepted.
+pub fn accept(
+    tx: &mut Transaction,
+    p: &db::AcoustidProposal,
+    written: Option<&WrittenFile>,
+) -> db::Result<()> {
+    for (field_name, value) in tag_updates(p) {
+        db::delete_file_tag(tx, p.file_id, field_name)?;
+        if let Some(value) = value {
+            db::insert_tag(tx, p.file_id, field_name, &value)?;
+        }
+    }
+    if let Some(file) = written {
+        db::update_file_mtime_size_inode(tx, file.file_id, file.mtime, file.size_bytes, file.inode)?;
+    }
+    db::update_acoustid_proposal_status(tx, p.file_id, "accepted")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{best_proposal, parse_fpcalc_output, Fingerprint, Proposal};
+
+    #[test]
+    fn parse_fpcalc_output_rounds_duration() {
+        let output = br#"{"duration": 268.69, "fingerprint": "AQADtEqUKEmkJEmS"}"#;
+        assert_eq!(
+            parse_fpcalc_output(output),
+            Some(Fingerprint { duration_seconds: 269, fingerprint: "AQADtEqUKEmkJEmS".to_string() }),
+        );
+        assert_eq!(parse_fpcalc_output(b"ERROR: Could not open the input file"), None);
+    }
+
+    #[test]
+    fn best_proposal_prefers_earliest_dated_release() {
+        let release = |id: &str, date: serde_json::Value, track: i64| serde_json::json!({
+            "id": id,
+            "title": "Takk…",
+            "artists": [{ "id": "f6f2326f-6b25-4170-b89d-e235b25508e8", "name": "Sigur Rós" }],
+            "date": date,
+            "mediums": [{ "position": 1, "track_count": 11, "tracks": [{ "position": track }] }],
+        });
+        let response = serde_json::json!({
+            "status": "ok",
+            "results": [
+                { "id": "low-score", "score": 0.3, "recordings": [] },
+                {
+                    "id": "9ff43b6a-4f16-427c-93c2-92307ca505e0",
+                    "score": 0.97,
+                    "recordings": [{
+                        "id": "cd2e7c47-16f5-46c6-a37c-a1eb7bf599ff",
+                        "title": "Hoppípolla",
+                        "artists": [
+                            { "name": "Sigur Rós", "joinphrase": " & " },
+                            { "name": "Amiina" },
+                        ],
+                        "releases": [
+                            release("reissue", serde_json::json!({ "year": 2015 }), 2),
+                            release("original", serde_json::json!({ "year": 2005, "month": 9, "day": 12 }), 2),
+                            release("undated", serde_json::json!(null), 2),
+                        ],
+                    }],
+                },
+            ],
+        });
+        assert_eq!(
+            best_proposal(&response),
+            Some(Proposal {
+                acoustid: "9ff43b6a-4f16-427c-93c2-92307ca505e0".to_string(),
+                score: 0.97,
+                musicbrainz_trackid: "cd2e7c47-16f5-46c6-a37c-a1eb7bf599ff".to_string(),
+                title: "Hoppípolla".to_string(),
+                artist: "Sigur Rós & Amiina".to_string(),
+                album: "Takk…".to_string(),
+                musicbrainz_albumid: "original".to_string(),
+                albumartist: "Sigur Rós".to_string(),
+                musicbrainz_albumartistid: "f6f2326f-6b25-4170-b89d-e235b25508e8".to_string(),
+                tracknumber: 2,
+                discnumber: 1,
+                originaldate: "2005-09-12".to_string(),
+            }),
+        );
+
+        let no_match = serde_json::json!({ "status": "ok", "results": [] });
+        assert_eq!(best_proposal(&no_match), None);
+    }
+}
@@ -0,0 +1,145 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Downloading an album as a zip archive.
+//!
+//! The archive contains a directory named after the album, with the tracks
+//! named after their number and title, so it unpacks to something sensible
+//! regardless of how the files are named in the library. A background thread
+//! writes the archive into a pipe, so we never hold an album in memory.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::error::{Error, Result};
+use crate::pipe::{self, PipeReader};
+use crate::prim::AlbumId;
+use crate::transcode::{self, Format};
+use crate::zip::ZipWriter;
+use crate::MetaIndex;
+
+/// A file to include in the archive.
+pub struct AlbumFile {
+    /// The path of the entry inside the archive.
+    pub name: String,
+
+    /// The path of the flac file in the library.
+    pub path: PathBuf,
+}
+
+/// Replace characters that are not allowed in file names on common platforms.
+///
+/// Slashes would create directories, and Windows rejects a few more.
+fn sanitize_file_name(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|ch| match ch {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            ch if ch.is_control() => '_',
+            ch => ch,
+        })
+        .collect();
+    // A leading dot would hide the file, and Windows strips trailing dots.
+    sanitized.trim().trim_matches('.').to_string()
+}
+
+/// Return the name of the archive's root directory, without extension.
+pub fn get_archive_name(index: &dyn MetaIndex, album_id: AlbumId) -> Option<String> {
+    let album = index.get_album(album_id)?;
+    let name = format!(
+        "{} - {} ({})",
+        index.get_string(album.artist),
+        index.get_string(album.title),
+        album.original_release_date.year,
+    );
+    Some(sanitize_file_name(&name))
+}
+
+/// List the files of the album, with their names in the archive.
+///
+/// The extension of the names is that of `format`, or flac for no format.
+pub fn get_album_files(
+    index: &dyn MetaIndex,
+    album_id: AlbumId,
+    archive_name: &str,
+    format: Option<Format>,
+) -> Vec<AlbumFile> {
+    let tracks = index.get_album_tracks(album_id);
+    let extension = format.map(|f| f.codec.extension()).unwrap_or("flac");
+    let is_multi_disc = tracks.iter().any(|t| t.track_id.disc_number() > 1);
+
+    tracks
+        .iter()
+        .map(|t| {
+            let title = sanitize_file_name(index.get_string(t.track.title));
+            let number = if is_multi_disc {
+                format!("{}-{:02}", t.track_id.disc_number(), t.track_id.track_number())
+            } else {
+                format!("{:02}", t.track_id.track_number())
+            };
+            AlbumFile {
+                name: format!("{}/{} {}.{}", archive_name, number, title, extension),
+                path: PathBuf::from(index.get_filename(t.track.filename)),
+            }
+        })
+        .collect()
+}
+
+fn write_archive<W: io::Write>(files: &[AlbumFile], format: Option<Format>, out: W) -> Result<()> {
+    let mut zip = ZipWriter::new(out);
+    for file in files {
+        // Use the modification time of the original for transcoded files too,
+        // the time of the download would make every download differ.
+        let modified = fs::metadata(&file.path)?.modified()?;
+        let modified = chrono::DateTime::<chrono::Local>::from(modified).naive_local();
+        match format {
+            None => zip.add_file(&file.name, modified, fs::File::open(&file.path)?)?,
+            Some(format) => {
+                let reader = transcode::spawn_transcode(&file.path, format)?;
+                zip.add_file(&file.name, modified, reader)?
+            }
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Write the archive on a background thread, return the reader for it.
+pub fn spawn_download(files: Vec<AlbumFile>, format: Option<Format>) -> PipeReader {
+    let (writer, reader) = pipe::pipe();
+
+    let builder = std::thread::Builder::new();
+    builder
+        .name("album_download".into())
+        .spawn(move || {
+            // When this fails, the response is truncated, and the client sees
+            // a broken archive. We can't change the status code any more.
+            // If the client went away, there is nothing to report.
+            match write_archive(&files, format, writer) {
+                Ok(()) => {}
+                Err(Error::IoError(err)) if err.kind() == io::ErrorKind::BrokenPipe => {}
+                Err(err) => error!("Error while writing album archive: {:?}", err),
+            }
+        })
+        .expect("Failed to spawn album download thread.");
+
+    reader
+}
+
+#[cfg(test)]
+mod test {
+    use super::sanitize_file_name;
+
+    #[test]
+    fn sanitize_file_name_replaces_path_separators() {
+        assert_eq!(sanitize_file_name("AC/DC"), "AC_DC");
+        assert_eq!(sanitize_file_name("What?: A \"Title\""), "What__ A _Title_");
+        assert_eq!(sanitize_file_name("...And Justice for All"), "And Justice for All");
+        assert_eq!(sanitize_file_name("Sigur Rós"), "Sigur Rós");
+    }
+}
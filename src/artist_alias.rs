@@ -0,0 +1,152 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Merging artists that are the same artist under different names.
+//!
+//! Releases of the same artist are sometimes credited to different MusicBrainz
+//! artists, for example "Sigur Rós" and "Sigur Ros". Merging the second into
+//! the first makes it an alias: the `artist_aliases` table maps its id to the
+//! canonical artist, and when we build the index, albums of the alias belong
+//! to the canonical artist, see `BuildMetaIndex::insert_full`. Because the
+//! mapping lives in the database, a rescan keeps applying it.
+//!
+//! Listens of the alias are attributed to the canonical artist in the database.
+//! Undoing the merge attributes the listens of the alias's albums back to it.
+//! Ratings refer to tracks, and track ids do not change, so they are unaffected.
+
+use std::collections::HashMap;
+
+use crate::database::{self as db, Transaction};
+use crate::prim::ArtistId;
+use crate::{MemoryMetaIndex, MetaIndex};
+
+/// An artist that was merged into another artist.
+#[derive(Debug)]
+pub struct Alias {
+    pub artist_id: ArtistId,
+    pub canonical_artist_id: ArtistId,
+    /// The name of the alias at the time of the merge.
+    pub name: String,
+    pub created_at: String,
+}
+
+pub fn load_aliases(tx: &mut Transaction) -> db::Result<Vec<Alias>> {
+    let mut result = Vec::new();
+    for row in db::iter_artist_aliases(tx)? {
+        let (artist_id, canonical_artist_id, name, created_at) = row?;
+        result.push(Alias {
+            artist_id: ArtistId(artist_id as u64),
+            canonical_artist_id: ArtistId(canonical_artist_id as u64),
+            name,
+            created_at,
+        });
+    }
+    Ok(result)
+}
+
+/// Return the artist that `artist_id` should become an alias of.
+///
+/// When the requested canonical artist is itself an alias, we merge into its
+/// canonical artist instead, so there are no chains of aliases.
+pub fn resolve_canonical(
+    aliases: &HashMap<ArtistId, ArtistId>,
+    artist_id: ArtistId,
+    canonical_artist_id: ArtistId,
+) -> Result<ArtistId, &'static str> {
+    if aliases.contains_key(&artist_id) {
+        return Err("The artist is merged already, undo that merge first.");
+    }
+    let canonical_artist_id = aliases
+        .get(&canonical_artist_id)
+        .copied()
+        .unwrap_or(canonical_artist_id);
+    if canonical_artist_id == artist_id {
+        return Err("An artist cannot be merged into itself.");
+    }
+    Ok(canonical_artist_id)
+}
+
+/// Make `artist_id` an alias of `canonical_artist_id`.
+///
+/// Aliases of `artist_id` become aliases of the canonical artist too, and all
+/// listens of `artist_id` are attributed to the canonical artist.
+pub fn merge(
+    tx: &mut Transaction,
+    artist_id: ArtistId,
+    canonical_artist_id: ArtistId,
+    name: &str,
+    created_at: &str,
+) -> db::Result<()> {
+    let old_id = artist_id.0 as i64;
+    let new_id = canonical_artist_id.0 as i64;
+    db::insert_artist_alias(tx, old_id, new_id, name, created_at)?;
+    db::update_artist_alias_canonical(tx, old_id, new_id)?;
+    db::update_listens_album_artist(tx, old_id, new_id)?;
+    Ok(())
+}
+
+/// Remove the alias, return the artist it was merged into, if it was an alias.
+pub fn unmerge(tx: &mut Transaction, artist_id: ArtistId) -> db::Result<Option<ArtistId>> {
+    let canonical_artist_id = load_aliases(tx)?
+        .into_iter()
+        .find(|alias| alias.artist_id == artist_id)
+        .map(|alias| alias.canonical_artist_id);
+    if canonical_artist_id.is_some() {
+        db::delete_artist_alias(tx, artist_id.0 as i64)?;
+    }
+    Ok(canonical_artist_id)
+}
+
+/// After undoing a merge, attribute the listens of the albums that belong to
+/// the former alias again back to it.
+///
+/// The `index` must be built without the alias. Listens are attributed to the
+/// first album artist, so we only restore albums where the alias is first.
+pub fn restore_listens(
+    tx: &mut Transaction,
+    index: &MemoryMetaIndex,
+    artist_id: ArtistId,
+    canonical_artist_id: ArtistId,
+) -> db::Result<()> {
+    for &(_, album_id) in index.get_albums_by_artist(artist_id) {
+        let album = match index.get_album(album_id) {
+            Some(album) => album,
+            None => continue,
+        };
+        if index.get_album_artists(album.artist_ids)[0] != artist_id {
+            continue;
+        }
+        db::update_listens_album_artist_for_album(
+            tx,
+            album_id.0 as i64,
+            canonical_artist_id.0 as i64,
+            artist_id.0 as i64,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::resolve_canonical;
+    use crate::prim::ArtistId;
+    use std::collections::HashMap;
+
+    #[test]
+    fn resolve_canonical_avoids_chains_and_cycles() {
+        let mut aliases = HashMap::new();
+        // "Sigur Ros" (2) is an alias of "Sigur Rós" (1).
+        aliases.insert(ArtistId(2), ArtistId(1));
+
+        assert_eq!(resolve_canonical(&aliases, ArtistId(3), ArtistId(1)), Ok(ArtistId(1)));
+        // Merging into an alias merges into its canonical artist.
+        assert_eq!(resolve_canonical(&aliases, ArtistId(3), ArtistId(2)), Ok(ArtistId(1)));
+        assert!(resolve_canonical(&aliases, ArtistId(1), ArtistId(1)).is_err());
+        assert!(resolve_canonical(&aliases, ArtistId(1), ArtistId(2)).is_err());
+        assert!(resolve_canonical(&aliases, ArtistId(2), ArtistId(3)).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! The static files of the webinterface.
+//!
+//! The build script embeds them in the binary, so a deployment is just the
+//! binary and a config file. When working on the webinterface, the server can
+//! load them from `webinterface_dir` instead, so changes show up without
+//! rebuilding the server.
+
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::http_utils::ContentEncoding;
+
+// Defines `EMBEDDED`, pairs of (name, contents), sorted by name.
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+/// Return the content type of an asset, or `None` if the name is not one.
+pub fn content_type(name: &str) -> Option<&'static str> {
+    let (_, extension) = name.rsplit_once('.')?;
+    match extension {
+        "css" => Some("text/css"),
+        "html" => Some("text/html"),
+        "js" => Some("text/javascript"),
+        // The only json file is the web app manifest.
+        "json" => Some("application/manifest+json"),
+        "svg" => Some("image/svg+xml"),
+        _ => None,
+    }
+}
+
+/// Return where an asset is in a checkout of the `app` directory.
+fn path_in_dir(dir: &Path, name: &str) -> PathBuf {
+    // The compiled app is in the output directory, the other files are sources.
+    if name.starts_with("app.js") {
+        dir.join("output").join(name)
+    } else {
+        dir.join(name)
+    }
+}
+
+/// Return whether the precompressed copy at `compressed` is up to date.
+fn is_fresh(original: &Path, compressed: &Path) -> bool {
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    match (modified(original), modified(compressed)) {
+        (Some(t0), Some(t1)) => t1 >= t0,
+        _ => false,
+    }
+}
+
+/// Load an asset from `dir` if set, or else the copy embedded in the binary.
+///
+/// For an `encoding` other than identity, this loads the precompressed copy,
+/// if there is one. See `make compress` in app/.
+pub fn load(dir: Option<&Path>, name: &str, encoding: ContentEncoding) -> Option<Cow<'static, [u8]>> {
+    let full_name = format!("{}{}", name, encoding.file_suffix());
+    match dir {
+        None => EMBEDDED
+            .binary_search_by_key(&full_name.as_str(), |&(n, _)| n)
+            .ok()
+            .map(|i| Cow::Borrowed(EMBEDDED[i].1)),
+        Some(dir) => {
+            let path = path_in_dir(dir, &full_name);
+            if encoding != ContentEncoding::Identity && !is_fresh(&path_in_dir(dir, name), &path) {
+                return None;
+            }
+            fs::read(path).ok().map(Cow::Owned)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{content_type, load, path_in_dir, EMBEDDED};
+    use crate::http_utils::ContentEncoding;
+
+    #[test]
+    fn content_type_only_accepts_webinterface_files() {
+        assert_eq!(content_type("style.css"), Some("text/css"));
+        assert_eq!(content_type("icon.svg"), Some("image/svg+xml"));
+        assert_eq!(content_type("app.js"), Some("text/javascript"));
+        assert_eq!(content_type("app.js.br"), None);
+        assert_eq!(content_type("packages.dhall"), None);
+        assert_eq!(content_type("css"), None);
+    }
+
+    #[test]
+    fn path_in_dir_finds_compiled_app_in_output() {
+        let dir = Path::new("/src/musium/app");
+        assert_eq!(path_in_dir(dir, "app.js.gz"), Path::new("/src/musium/app/output/app.js.gz"));
+        assert_eq!(path_in_dir(dir, "login.html"), Path::new("/src/musium/app/login.html"));
+    }
+
+    #[test]
+    fn embedded_assets_include_static_files() {
+        assert!(EMBEDDED.windows(2).all(|w| w[0].0 < w[1].0));
+        let login = load(None, "login.html", ContentEncoding::Identity).unwrap();
+        assert!(login.starts_with(b"<!DOCTYPE html>"));
+        assert!(load(None, "style.css", ContentEncoding::Identity).is_some());
+        assert!(load(None, "login.html", ContentEncoding::Brotli).is_none());
+    }
+}
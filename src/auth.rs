@@ -0,0 +1,303 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Authentication of API requests with bearer tokens.
+//!
+//! Tokens are configured in the config file, each with a name and a scope that
+//! determines what the token grants access to. Clients present the token in an
+//! `Authorization: Bearer` header, or, for the webinterface, in a cookie that
+//! the login page sets. Browsers send the cookie along with requests for audio
+//! and for the event stream, where the webinterface cannot set headers.
+//!
+//! A token can also name a user. Listens, ratings, and playlists belong to the
+//! user of the token that produced them, so people who share a server keep
+//! their own history. Tokens without a user all act as the default user, that
+//! is also who owns the data from before users existed.
+
+use std::fmt;
+use std::str::FromStr;
+
+use tiny_http::Method::{self, Get, Post, Put};
+use tiny_http::Request;
+
+/// Name of the cookie that holds the token for the webinterface.
+pub const COOKIE_NAME: &str = "musium_token";
+
+/// Tokens shorter than this are too easy to guess.
+const MIN_SECRET_LEN: usize = 16;
+
+/// What a token grants access to. Every scope includes the ones before it.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Scope {
+    /// Browse the library, view the queue and listens, and stream tracks.
+    Read,
+
+    /// Additionally enqueue and dequeue tracks, skip, and change the volume.
+    Queue,
+
+    /// Everything, including ratings, playlists, scans, and backups.
+    Full,
+}
+
+impl FromStr for Scope {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Scope, &'static str> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "queue" => Ok(Scope::Queue),
+            "full" => Ok(Scope::Full),
+            _ => Err("Invalid api_token scope, must be one of 'read', 'queue', or 'full'."),
+        }
+    }
+}
+
+/// User names go into the database and into urls, keep them simple.
+const MAX_USER_LEN: usize = 64;
+
+/// A named token from the config file.
+#[derive(Clone)]
+pub struct ApiToken {
+    pub name: String,
+    pub scope: Scope,
+    secret: String,
+    /// The user that the token acts as, `None` for the default user.
+    pub user: Option<String>,
+}
+
+impl fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The secret should not end up in logs.
+        write!(
+            f,
+            "ApiToken {{ name: {:?}, scope: {:?}, user: {:?} }}",
+            self.name, self.scope, self.user,
+        )
+    }
+}
+
+/// Return whether `user` is a valid user name for an `api_token`.
+pub fn is_valid_user(user: &str) -> bool {
+    !user.is_empty()
+        && user.len() <= MAX_USER_LEN
+        && user.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+impl FromStr for ApiToken {
+    type Err = &'static str;
+
+    /// Parse a token from the form `name scope secret [user]`.
+    fn from_str(s: &str) -> Result<ApiToken, &'static str> {
+        let mut parts = s.split_whitespace();
+        let (name, scope, secret, user) = match (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) {
+            (Some(name), Some(scope), Some(secret), user, None) => (name, scope, secret, user),
+            _ => return Err("Invalid api_token value, expected 'name scope secret [user]'."),
+        };
+        if secret.len() < MIN_SECRET_LEN {
+            return Err("Invalid api_token secret, must be at least 16 characters.");
+        }
+        // The secret goes into a cookie, so restrict it to characters that
+        // need no escaping there.
+        if !secret.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err("Invalid api_token secret, must consist of letters, digits, '-', and '_'.");
+        }
+        if let Some(user) = user {
+            if !is_valid_user(user) {
+                return Err("Invalid api_token user, must be at most 64 letters, digits, '-', and '_'.");
+            }
+        }
+        let token = ApiToken {
+            name: name.to_string(),
+            scope: Scope::from_str(scope)?,
+            secret: secret.to_string(),
+            user: user.map(|u| u.to_string()),
+        };
+        Ok(token)
+    }
+}
+
+impl ApiToken {
+    /// Return the user that the token acts as, `None` for the default user.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn matches(&self, candidate: &str) -> bool {
+        constant_time_eq(self.secret.as_bytes(), candidate.as_bytes())
+    }
+
+    /// Check a token in the form of the Subsonic API, `md5(secret + salt)`.
+    pub fn matches_salted_md5(&self, salt: &str, hash_hex: &str) -> bool {
+        let salted = format!("{}{}", self.secret, salt);
+        let expected = crate::md5::md5_hex(salted.as_bytes());
+        constant_time_eq(expected.as_bytes(), hash_hex.to_ascii_lowercase().as_bytes())
+    }
+}
+
+/// Compare in time independent of where the inputs differ.
+///
+/// This avoids leaking through response times how much of a guess was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0_u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Extract the token that the client presents, from the header or cookie.
+fn get_presented_token(request: &Request) -> Option<&str> {
+    for header in request.headers() {
+        if header.field.equiv("Authorization") {
+            if let Some(token) = header.value.as_str().strip_prefix("Bearer ") {
+                return Some(token.trim());
+            }
+        }
+    }
+    for header in request.headers() {
+        if header.field.equiv("Cookie") {
+            for cookie in header.value.as_str().split(';') {
+                if let Some((name, value)) = cookie.trim().split_once('=') {
+                    if name == COOKIE_NAME {
+                        return Some(value);
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Return the configured token that matches the one in the request, if any.
+pub fn authenticate<'a>(tokens: &'a [ApiToken], request: &Request) -> Option<&'a ApiToken> {
+    let presented = get_presented_token(request)?;
+    tokens.iter().find(|token| token.matches(presented))
+}
+
+/// Return the scope needed for a call to /api/«endpoint»/«arg1»/«arg2».
+pub fn required_scope(
+    method: &Method,
+    endpoint: &str,
+    arg1: Option<&str>,
+    arg2: Option<&str>,
+) -> Scope {
+    match (method, endpoint, arg1, arg2) {
+        // The backup contains everything, including listens of all clients,
+        // and playlists, so it is as sensitive as full access.
+        (&Get, "backup", _, _) => Scope::Full,
+        (&Get, _, _, _) => Scope::Read,
+        // GraphQL queries are posted, but they only read, there are no mutations.
+        (&Post, "graphql", None, None) => Scope::Read,
+        // Loving a track changes its rating, that's not a queue operation.
+        (_, "queue", Some("love"), _) => Scope::Full,
+        (_, "queue", _, _) => Scope::Queue,
+        (_, "player", _, _) => Scope::Queue,
+        (_, "volume", _, _) => Scope::Queue,
+        (_, "cast", _, _) => Scope::Queue,
+        (_, "browser", _, _) => Scope::Queue,
+        (_, "zone", _, _) => Scope::Queue,
+        (_, "playlist", Some(_), Some("enqueue")) => Scope::Queue,
+        (_, "radio", Some(_), Some("enqueue")) => Scope::Queue,
+        _ => Scope::Full,
+    }
+}
+
+/// Return the scope needed for a call in party mode.
+///
+/// In party mode, guests with a queue token can enqueue single tracks, and
+/// vote to skip. Skipping outright, clearing the queue, changing the volume,
+/// and the other queue operations need a full token.
+pub fn required_scope_party(
+    method: &Method,
+    endpoint: &str,
+    arg1: Option<&str>,
+    arg2: Option<&str>,
+) -> Scope {
+    match (method, endpoint, arg1, arg2) {
+        (&Post, "queue", Some("vote-skip"), None) => Scope::Queue,
+        (&Put, "queue", Some(_), None) => Scope::Queue,
+        _ => match required_scope(method, endpoint, arg1, arg2) {
+            Scope::Queue => Scope::Full,
+            scope => scope,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{required_scope, required_scope_party, ApiToken, Scope};
+    use std::str::FromStr;
+    use tiny_http::Method::{Delete, Get, Post, Put};
+
+    #[test]
+    fn api_token_parses_name_scope_and_secret() {
+        let token = ApiToken::from_str("kitchen  queue 0123456789abcdef").unwrap();
+        assert_eq!(token.name, "kitchen");
+        assert_eq!(token.scope, Scope::Queue);
+        assert!(token.matches("0123456789abcdef"));
+        assert!(!token.matches("0123456789abcdeg"));
+        assert!(!token.matches("0123456789abcde"));
+        assert!(ApiToken::from_str("kitchen queue short").is_err());
+        assert!(ApiToken::from_str("kitchen queue 0123456789abcdef;").is_err());
+        assert!(ApiToken::from_str("kitchen admin 0123456789abcdef").is_err());
+        assert!(ApiToken::from_str("kitchen 0123456789abcdef").is_err());
+        assert_eq!(token.user(), None);
+    }
+
+    #[test]
+    fn api_token_parses_optional_user() {
+        let token = ApiToken::from_str("phone full 0123456789abcdef alex").unwrap();
+        assert_eq!(token.name, "phone");
+        assert_eq!(token.user(), Some("alex"));
+        assert!(token.matches("0123456789abcdef"));
+        assert!(ApiToken::from_str("phone full 0123456789abcdef al/ex").is_err());
+        assert!(ApiToken::from_str("phone full 0123456789abcdef alex sam").is_err());
+    }
+
+    #[test]
+    fn api_token_debug_does_not_reveal_secret() {
+        let token = ApiToken::from_str("laptop full 0123456789abcdef").unwrap();
+        assert!(!format!("{:?}", token).contains("0123456789abcdef"));
+    }
+
+    #[test]
+    fn required_scope_separates_queue_from_full_access() {
+        assert_eq!(required_scope(&Get, "albums", None, None), Scope::Read);
+        assert_eq!(required_scope(&Get, "backup", None, None), Scope::Full);
+        assert_eq!(required_scope(&Put, "queue", Some("0000000000010102"), None), Scope::Queue);
+        assert_eq!(required_scope(&Delete, "queue", Some("0000000000000003"), None), Scope::Queue);
+        assert_eq!(required_scope(&Post, "queue", Some("love"), None), Scope::Full);
+        assert_eq!(required_scope(&Post, "volume", Some("up"), None), Scope::Queue);
+        assert_eq!(required_scope(&Post, "player", Some("pause"), None), Scope::Queue);
+        assert_eq!(required_scope(&Put, "cast", Some("abc123"), None), Scope::Queue);
+        assert_eq!(required_scope(&Post, "browser", Some("3"), Some("progress")), Scope::Queue);
+        assert_eq!(required_scope(&Put, "zone", Some("b8:27:eb:00:00:01"), Some("volume")), Scope::Queue);
+        assert_eq!(required_scope(&Post, "playlist", Some("1"), Some("enqueue")), Scope::Queue);
+        assert_eq!(required_scope(&Delete, "playlist", Some("1"), None), Scope::Full);
+        assert_eq!(required_scope(&Post, "radio", Some("1"), Some("enqueue")), Scope::Queue);
+        assert_eq!(required_scope(&Post, "radio", None, None), Scope::Full);
+        assert_eq!(required_scope(&Post, "scan", Some("start"), None), Scope::Full);
+        assert_eq!(required_scope(&Post, "graphql", None, None), Scope::Read);
+    }
+
+    #[test]
+    fn required_scope_party_limits_guests_to_enqueue_and_vote() {
+        assert_eq!(required_scope_party(&Get, "queue", None, None), Scope::Read);
+        assert_eq!(required_scope_party(&Put, "queue", Some("0000000000010102"), None), Scope::Queue);
+        assert_eq!(required_scope_party(&Post, "queue", Some("vote-skip"), None), Scope::Queue);
+        assert_eq!(required_scope_party(&Post, "queue", Some("skip"), None), Scope::Full);
+        assert_eq!(required_scope_party(&Post, "queue", Some("clear"), None), Scope::Full);
+        assert_eq!(required_scope_party(&Delete, "queue", Some("0000000000000003"), None), Scope::Full);
+        assert_eq!(required_scope_party(&Post, "volume", Some("up"), None), Scope::Full);
+        assert_eq!(required_scope_party(&Post, "playlist", Some("1"), Some("enqueue")), Scope::Full);
+    }
+}
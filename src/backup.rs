@@ -0,0 +1,70 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Making a consistent copy of the database while Musium is running.
+//!
+//! Copying the database file is not safe while Musium is running: the copy can
+//! contain a half-written transaction, and it would miss anything that is still
+//! in the WAL. Instead we use `VACUUM INTO`, which writes a snapshot of the
+//! database as of a single read transaction to a new file. Because it is a read
+//! transaction, it does not block playback or the history thread. The `sqlite`
+//! crate does not expose SQLite's online backup API, but for a one-off copy,
+//! `VACUUM INTO` is equivalent, and the copy is compacted as a bonus.
+
+use std::path::{Path, PathBuf};
+
+use crate::database_utils;
+use crate::error::{Error, Result};
+
+/// Write a snapshot of the database at `db_path` to a new file at `out_path`.
+///
+/// Fails if `out_path` exists already, we never overwrite files.
+pub fn backup(db_path: &Path, out_path: &Path) -> Result<()> {
+    if out_path.exists() {
+        return Err(Error::InvalidBackupPath("The backup file exists already."));
+    }
+    let out_str = match out_path.to_str() {
+        Some(s) => s,
+        None => return Err(Error::InvalidBackupPath("The backup path must be valid UTF-8.")),
+    };
+
+    let connection = database_utils::connect_readonly(db_path)?;
+    // Pragmas and VACUUM do not support parameters, so we have to quote the
+    // path ourselves. In SQL string literals, a quote is escaped by doubling it.
+    let sql = format!("VACUUM INTO '{}';", out_str.replace('\'', "''"));
+    connection.execute(sql)?;
+
+    Ok(())
+}
+
+/// Return a path next to the database, for a backup that we delete afterwards.
+///
+/// The path includes the process id and the time, so concurrent backups don't
+/// collide.
+pub fn temporary_path(db_path: &Path) -> PathBuf {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let mut file_name = db_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".backup-{}-{}", std::process::id(), nanos));
+    db_path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::temporary_path;
+    use std::path::Path;
+
+    #[test]
+    fn temporary_path_is_next_to_database() {
+        let path = temporary_path(Path::new("/var/lib/musium/musium.sqlite3"));
+        assert_eq!(path.parent(), Some(Path::new("/var/lib/musium")));
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("musium.sqlite3.backup-"));
+    }
+}
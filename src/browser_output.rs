@@ -0,0 +1,112 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Playing the queue in a browser tab, with the web app as the audio output.
+//!
+//! This works like casting: the queue stays on the server, and the browser
+//! fetches the tracks itself, as flac, or transcoded if it asks for that. The
+//! browser reports its progress about once per second, and in the response we
+//! tell it which entry to play, and at what level. The progress reports start
+//! and complete the queued tracks, so listens get logged on the server, the
+//! same way as for the audio card. See `docs/api.md` for the endpoints.
+//!
+//! A browser tab can disappear without a word, for example when a laptop goes
+//! to sleep. The watchdog thread notices when the reports stop, and then the
+//! queue moves back to the audio card.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::cast;
+use crate::player::{PlayerState, QueueId, Source};
+
+/// Move playback back to the audio card when the browser is silent for this long.
+pub const REPORT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the watchdog checks in on the browser.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the browser tells us about its playback.
+pub enum Report {
+    /// The browser has nothing loaded yet.
+    Idle,
+
+    /// The browser is playing the entry, at the given position.
+    Progress { queue_id: QueueId, position_ms: u64 },
+
+    /// The browser played the entry to the end.
+    Ended(QueueId),
+
+    /// The browser failed to load or play the entry.
+    Failed(QueueId),
+}
+
+/// What the browser should play, in response to a report.
+pub struct Instruction {
+    /// The entry to play, `None` when the queue is empty.
+    pub current: Option<(QueueId, Source)>,
+
+    /// Where to start playing the entry, when the browser loads it.
+    pub start_ms: u64,
+
+    /// The level to play at, from 0.0 to 1.0, see `cast::volume_to_level`.
+    pub level: Option<f64>,
+}
+
+/// Apply the report to the queue, and return what the browser should play.
+///
+/// Returns `None` if the session is over, then the browser should stop.
+pub fn handle_report(state: &mut PlayerState, session: u64, report: Report) -> Option<Instruction> {
+    if !state.touch_browser_output(session) {
+        return None;
+    }
+
+    match report {
+        Report::Idle => {}
+        Report::Progress { queue_id, position_ms } => state.set_cast_position(queue_id, position_ms),
+        Report::Ended(queue_id) => state.complete_cast_track(queue_id),
+        Report::Failed(queue_id) => {
+            warn!("Browser failed to play queued track {}, skipping it.", queue_id);
+            if state.current_track().map(|(q, _)| q) == Some(queue_id) {
+                state.skip();
+            }
+        }
+    }
+
+    let instruction = Instruction {
+        current: state.current_track(),
+        start_ms: state.current_resume_at_ms(),
+        level: state.target_volume_full_scale().map(cast::volume_to_level),
+    };
+    Some(instruction)
+}
+
+/// Watch over the browser output until its session ends.
+///
+/// When the browser stops reporting for longer than `REPORT_TIMEOUT`, we play
+/// on the audio card again.
+pub fn main(session: u64, state_mutex: Arc<Mutex<PlayerState>>) {
+    loop {
+        thread::sleep(CHECK_INTERVAL);
+        let mut state = state_mutex.lock().unwrap();
+        if state.cast_session() != session {
+            return;
+        }
+        match state.browser_output_silence() {
+            Some(silence) if silence > REPORT_TIMEOUT => {
+                warn!(
+                    "Browser did not report for {} seconds, playing on the audio card again.",
+                    silence.as_secs(),
+                );
+                state.stop_casting();
+                return;
+            }
+            _ => continue,
+        }
+    }
+}
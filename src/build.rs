@@ -5,8 +5,9 @@
 // you may not use this file except in compliance with the License.
 // A copy of the License has been included in the root of the repository.
 
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt;
+use std::result;
 use std::str::FromStr;
 
 use crate::database::{FileMetadata, Transaction, self as db};
@@ -74,6 +75,10 @@ pub enum IssueDetail {
 
     /// The file does not use either 16 or 24 bits per sample.
     UnsupportedBitDepth(u32),
+
+    /// Another file has the same track id, so the file is left out.
+    /// Contains the track id and the file name of the other file.
+    DuplicateTrack(TrackId, String),
 }
 
 impl IssueDetail {
@@ -105,6 +110,8 @@ impl fmt::Display for Issue {
                 write!(f, "error: the file is not stereo"),
             IssueDetail::UnsupportedBitDepth(bits) =>
                 write!(f, "error: {} bits per sample is not supported", bits),
+            IssueDetail::DuplicateTrack(track_id, ref other) =>
+                write!(f, "warning: skipped duplicate of track {} in '{}'.", track_id, other),
             IssueDetail::AlbumTitleMismatch(_id, ref title, ref alt) =>
                 write!(f, "warning: discarded inconsistent album title '{}' in favour of '{}'.", alt, title),
             IssueDetail::AlbumReleaseDateMismatch(_id, ref date, ref alt) =>
@@ -133,7 +140,68 @@ impl fmt::Display for Issue {
     }
 }
 
-fn parse_date(date_str: &str) -> Option<Date> {
+/// How to tell albums apart, configured with `album_identity`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AlbumIdentity {
+    /// Files with the same MusicBrainz release id form one album.
+    MusicBrainz,
+
+    /// Files with the same MusicBrainz release id form one album when they are
+    /// in the same directory. Copies of a release in different directories are
+    /// separate albums.
+    Directory,
+}
+
+impl FromStr for AlbumIdentity {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> result::Result<AlbumIdentity, &'static str> {
+        match s {
+            "musicbrainz" => Ok(AlbumIdentity::MusicBrainz),
+            "directory" => Ok(AlbumIdentity::Directory),
+            _ => Err("Invalid album_identity value, must be 'musicbrainz' or 'directory'."),
+        }
+    }
+}
+
+/// Return the directory that identifies the album of the file.
+///
+/// Discs of an album are often in subdirectories named "CD 1", "Disc 2", and
+/// so on. Those belong to the same album, so we use the parent directory.
+fn album_directory(filename: &str) -> &str {
+    let dir = match filename.rfind('/') {
+        Some(i) => &filename[..i],
+        None => return "",
+    };
+    let (parent, name) = match dir.rfind('/') {
+        Some(i) => (&dir[..i], &dir[i + 1..]),
+        None => ("", dir),
+    };
+    let name = name.to_ascii_lowercase();
+    let number = ["cd", "disc", "disk"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(*prefix))
+        .map(|rest| rest.trim_start_matches([' ', '_', '-']));
+    match number {
+        Some(n) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => parent,
+        _ => dir,
+    }
+}
+
+/// Derive the album id for a copy of a release in another directory.
+///
+/// The result is a 52-bit id like the ones from `parse_uuid_52bits`, but it no
+/// longer matches the MusicBrainz release id.
+fn album_id_for_directory(mbid_album: u64, directory: &str) -> AlbumId {
+    let hash = crate::md5::md5(directory.as_bytes());
+    let mut bits = 0_u64;
+    for &b in &hash[..8] {
+        bits = (bits << 8) | b as u64;
+    }
+    AlbumId((mbid_album ^ bits) & 0xf_ffff_ffff_ffff)
+}
+
+pub fn parse_date(date_str: &str) -> Option<Date> {
     // We expect at least a year.
     if date_str.len() < 4 { return None }
 
@@ -167,8 +235,11 @@ fn parse_date(date_str: &str) -> Option<Date> {
     Some(Date::new(year, month, day))
 }
 
+/// MusicBrainz id of the "Various Artists" artist, see `prim::VARIOUS_ARTISTS`.
+const VARIOUS_ARTISTS_MBID: &str = "89ad4ac3-39f7-470e-963a-56509c546377";
+
 /// Parse a part of a 128-bit hexadecimal UUID into a 64-bit unsigned integer.
-fn parse_uuid(uuid: &str) -> Option<u64> {
+pub fn parse_uuid(uuid: &str) -> Option<u64> {
     // Validate that the textual format of the UUID is as expected.
     // E.g. `1070cbb2-ad74-44ce-90a4-7fa1dfd8164e`.
     if uuid.len() != 36 { return None }
@@ -380,6 +451,45 @@ pub struct BuildMetaIndex {
     /// The first (oldest) recorded listen for the albums in this map.
     pub album_first_listens: HashMap<AlbumId, Instant>,
 
+    /// Disc subtitles from the `discsubtitle` tag, per album and disc number.
+    pub disc_subtitles: BTreeMap<(AlbumId, u8), StringRef>,
+
+    /// Pre-gaps in milliseconds, for tracks whose file has one.
+    pub pregaps: BTreeMap<TrackId, u32>,
+
+    /// The recorded import time for albums that were imported before.
+    ///
+    /// Albums that are new to the library are not in this map.
+    pub album_imports: HashMap<AlbumId, Instant>,
+
+    /// Files that were not inserted because their tags are missing or invalid.
+    ///
+    /// These are candidates for fingerprinting, see `acoustid.rs`.
+    pub files_missing_tags: Vec<(FileId, FilenameRef)>,
+
+    /// Artists that were merged into another artist, see `artist_alias.rs`.
+    pub artist_aliases: HashMap<ArtistId, ArtistId>,
+
+    /// Canonical artists whose name so far came from the tags of an alias.
+    ///
+    /// Once we encounter a file of the canonical artist itself, its name
+    /// replaces the name of the alias.
+    artists_named_by_alias: HashSet<ArtistId>,
+
+    /// Albums credited to an artist that was merged into another artist.
+    ///
+    /// Their credit is replaced by the name of the canonical artist when we
+    /// finish building, when we know that name.
+    albums_credited_to_alias: Vec<(AlbumId, ArtistId)>,
+
+    /// How to tell albums apart.
+    album_identity: AlbumIdentity,
+
+    /// For `AlbumIdentity::Directory`, the first directory that we found
+    /// every MusicBrainz release in. The files in there get the album id of
+    /// the release, copies in other directories get a derived id.
+    album_directories: HashMap<u64, String>,
+
     /// File name of the file currently being inserted.
     ///
     /// This is used to simplify helper methods for error reporting, to ensure
@@ -393,12 +503,22 @@ pub struct BuildMetaIndex {
 pub struct FileTask {
   file_id: FileId,
   filename: FilenameRef,
-  mtime: Instant,
+  imported_at: Instant,
   duration_seconds: u16,
 }
 
+impl FileTask {
+    pub fn file_id(&self) -> FileId {
+        self.file_id
+    }
+
+    pub fn filename(&self) -> FilenameRef {
+        self.filename
+    }
+}
+
 impl BuildMetaIndex {
-    pub fn new() -> BuildMetaIndex {
+    pub fn new(album_identity: AlbumIdentity) -> BuildMetaIndex {
         BuildMetaIndex {
             artists: BTreeMap::new(),
             albums: BTreeMap::new(),
@@ -408,9 +528,18 @@ impl BuildMetaIndex {
             filenames: Vec::new(),
             album_file_ids: HashMap::new(),
             album_first_listens: HashMap::new(),
+            album_imports: HashMap::new(),
+            disc_subtitles: BTreeMap::new(),
+            pregaps: BTreeMap::new(),
             words_artist: BTreeSet::new(),
             words_album: BTreeSet::new(),
             words_track: BTreeSet::new(),
+            files_missing_tags: Vec::new(),
+            artist_aliases: HashMap::new(),
+            artists_named_by_alias: HashSet::new(),
+            albums_credited_to_alias: Vec::new(),
+            album_identity: album_identity,
+            album_directories: HashMap::new(),
             // Initially we set this to a sentinel value even though we don't
             // have a backing file yet; dereferencing this should not happen.
             current_filename: FilenameRef(0),
@@ -539,10 +668,15 @@ impl BuildMetaIndex {
             panic!("Track is longer than {} seconds.", u16::MAX);
         }
 
+        let imported_at = match Instant::from_iso8601(&file.imported_at) {
+            Some(t) => t,
+            None => panic!("Encountered invalid imported_at timestamp: {:?}", file.imported_at),
+        };
+
         let result = FileTask {
             file_id: FileId(file.id),
             filename: filename_id,
-            mtime: Instant { posix_seconds_utc: file.mtime },
+            imported_at: imported_at,
             duration_seconds: seconds as u16,
         };
 
@@ -561,6 +695,7 @@ impl BuildMetaIndex {
 
         let mut tag_date = None;
         let mut tag_discnumber = None;
+        let mut tag_discsubtitle = None;
         let mut tag_musicbrainz_albumid = None;
         let mut tag_musicbrainz_albumartistid = Vec::new();
         let mut tag_originaldate = None;
@@ -572,6 +707,7 @@ impl BuildMetaIndex {
         let mut tag_albumartistsort = None;
         let mut tag_albumartists = Vec::new();
         let mut tag_albumartistssort = Vec::new();
+        let mut tag_compilation = None;
 
         for opt_pair in db::iter_file_tags(tx, file.file_id.0)? {
             let (field_name, value) = opt_pair?;
@@ -585,8 +721,11 @@ impl BuildMetaIndex {
                 "albumartistssort" => tag_albumartistssort.push(value),
                 "artist" => tag_artist = Some(value),
                 "artists" => continue, // Currently unused.
+                "compilation" => tag_compilation = Some(value),
                 "date" => tag_date = Some(value),
                 "discnumber" => tag_discnumber = Some(value),
+                "discsubtitle" => tag_discsubtitle = Some(value),
+                "genre" => continue, // Used by library views, not part of the index.
                 "musicbrainz_albumartistid" => tag_musicbrainz_albumartistid.push(value),
                 "musicbrainz_albumid" => tag_musicbrainz_albumid = Some(value),
                 "musicbrainz_trackid" => continue, // Currently unused.
@@ -602,10 +741,12 @@ impl BuildMetaIndex {
             tag_tracknumber.as_ref(),
             |v| u8::from_str(v).ok(),
         )?;
+        // Some taggers write the disc number as "1/3", including the total.
+        // Track ids have room for 15 discs, larger box sets are not supported.
         let disc_number = self.parse(
             "discnumber",
             tag_discnumber.as_ref(),
-            |v| u8::from_str(v).ok(),
+            |v| u8::from_str(v.split('/').next().unwrap_or(v)).ok().filter(|&n| n < 16),
         )?;
         // If the disc number is not set, assume disc 1.
         let disc_number = disc_number.unwrap_or(1);
@@ -633,6 +774,28 @@ impl BuildMetaIndex {
             None => return self.error_missing_field("originaldate"),
         };
 
+        let is_compilation = self.parse(
+            "compilation",
+            tag_compilation.as_ref(),
+            |v| match v.as_str() {
+                "1" => Some(true),
+                "0" => Some(false),
+                _ => None,
+            },
+        )?;
+        let is_compilation = is_compilation.unwrap_or(false);
+
+        // Compilations all belong to "Various Artists", whatever their album
+        // artist tags say, so they don't need those tags. The track artists
+        // are still part of the index, see the words of the track artist below.
+        if is_compilation {
+            tag_albumartist = Some("Various Artists".to_string());
+            tag_albumartists.clear();
+            tag_albumartistssort.clear();
+            tag_albumartistsort = None;
+            tag_musicbrainz_albumartistid = vec![VARIOUS_ARTISTS_MBID.to_string()];
+        }
+
         let title = self.require_and_insert_string("title", tag_title)?;
         let track_artist = self.require_and_insert_string("artist", tag_artist)?;
         let album = self.require_and_insert_string("album", tag_album)?;
@@ -657,6 +820,8 @@ impl BuildMetaIndex {
 
         // Album artist id, name, and sort name.
         let mut album_artists: Vec<(ArtistId, StringRef, StringRef)> = Vec::new();
+        // The canonical artists among those, that we found through an alias.
+        let mut via_alias: Vec<ArtistId> = Vec::new();
         for ((tag_aa_mbid, tag_aa_name), tag_aa_name_sort) in tag_musicbrainz_albumartistid
             .iter()
             .zip(tag_albumartists)
@@ -666,11 +831,23 @@ impl BuildMetaIndex {
                 Some(tag_aa_mbid),
                 |v| parse_uuid(v),
             )?;
+            let artist_id = match self.artist_aliases.get(&ArtistId(mbid_artist)) {
+                Some(&canonical_id) => {
+                    via_alias.push(canonical_id);
+                    canonical_id
+                }
+                None => ArtistId(mbid_artist),
+            };
+            // When an album is credited to an artist and its alias, list the
+            // artist only once.
+            if album_artists.iter().any(|aa| aa.0 == artist_id) {
+                continue;
+            }
             let aa_name = self.strings.insert(&tag_aa_name);
             let aa_name_sort = self.strings.insert(&tag_aa_name_sort);
             album_artists.push(
                 (
-                    ArtistId(mbid_artist),
+                    artist_id,
                     StringRef(aa_name),
                     StringRef(aa_name_sort),
                 )
@@ -686,9 +863,27 @@ impl BuildMetaIndex {
             }
         }
 
-        let album_id = AlbumId(mbid_album);
+        let album_id = match self.album_identity {
+            AlbumIdentity::MusicBrainz => AlbumId(mbid_album),
+            AlbumIdentity::Directory => {
+                let directory = album_directory(self.get_current_filename()).to_string();
+                let first = self.album_directories.entry(mbid_album).or_insert_with(|| directory.clone());
+                match *first == directory {
+                    true => AlbumId(mbid_album),
+                    false => album_id_for_directory(mbid_album, &directory),
+                }
+            }
+        };
         let track_id = TrackId::new(album_id, disc_number, track_number);
 
+        // With `AlbumIdentity::MusicBrainz`, a second copy of a release has
+        // the same track ids as the first. We keep the first one.
+        if let Some(existing) = self.tracks.get(&track_id) {
+            let other = self.filenames[existing.filename.0 as usize].clone();
+            let _ = self.issue::<()>(IssueDetail::DuplicateTrack(track_id, other));
+            return Ok(());
+        }
+
         // Record the maximum file id per album, so we can use it to invalidate
         // per-album data later.
         self.album_file_ids
@@ -809,21 +1004,40 @@ impl BuildMetaIndex {
         // album artists so the album can refer to this.
         let album_artists_ref = self.album_artists.insert(album_artists.iter().map(|tuple| tuple.0));
 
+        // An album credited to just the alias gets the canonical name instead,
+        // when we know it, see `finish_artist_aliases`.
+        if album_artists.len() == 1
+            && via_alias.contains(&album_artists[0].0)
+            && album_artists[0].1 == StringRef(album_artist)
+        {
+            self.albums_credited_to_alias.push((album_id, album_artists[0].0));
+        }
+
         for (artist_id, aa_name, aa_name_sort) in album_artists {
             let artist = Artist {
                 name: aa_name,
                 name_for_sort: aa_name_sort,
             };
+            let is_via_alias = via_alias.contains(&artist_id);
             match self.artists.get(&artist_id) {
+                // The name of an alias does not have to match the canonical name.
+                Some(_) if is_via_alias => {}
                 Some(existing_artist) => if let Some(detail) = artists_different(
                     &self.strings,
                     artist_id,
                     existing_artist,
                     &artist,
                 ) {
-                    let _ = self.issue::<()>(detail);
+                    if self.artists_named_by_alias.remove(&artist_id) {
+                        self.artists.insert(artist_id, artist);
+                    } else {
+                        let _ = self.issue::<()>(detail);
+                    }
                 }
                 None => {
+                    if is_via_alias {
+                        self.artists_named_by_alias.insert(artist_id);
+                    }
                     self.artists.insert(artist_id, artist);
                 }
             }
@@ -842,21 +1056,14 @@ impl BuildMetaIndex {
             artist: StringRef(album_artist),
             title: StringRef(album),
             original_release_date: release_date,
-            first_seen: file.mtime,
+            first_seen: file.imported_at,
             loudness: album_loudness,
         };
 
         let mut add_album = true;
 
-        // Check for consistency if duplicates occur.
-        if self.tracks.get(&track_id).is_some() {
-            // TODO: This should report an `Issue`, not panic.
-            let filename = self.get_current_filename();
-            panic!("Duplicate track {}, file {}.", track_id, filename);
-        }
-
         if let Some(existing_album) = self.albums.get_mut(&album_id) {
-            // If we have an existing album, take the max import date over all
+            // If we have an existing album, take the min import date over all
             // files in that album. This is not a material difference for the
             // difference check below.
             let first_seen = album.first_seen.min(existing_album.first_seen);
@@ -877,6 +1084,11 @@ impl BuildMetaIndex {
 
         self.tracks.insert(track_id, track);
 
+        if let Some(subtitle) = tag_discsubtitle {
+            let subtitle = StringRef(self.strings.insert(&subtitle));
+            self.disc_subtitles.entry((album_id, disc_number)).or_insert(subtitle);
+        }
+
         if add_album {
             self.albums.insert(album_id, album);
         }
@@ -902,6 +1114,66 @@ impl BuildMetaIndex {
 
         Ok(())
     }
+
+    /// Load the artist aliases, this must happen before inserting files.
+    pub fn insert_artist_aliases(&mut self, tx: &mut Transaction) -> db::Result<()> {
+        for row in db::iter_artist_aliases(tx)? {
+            let (artist_id, canonical_artist_id, _name, _created_at) = row?;
+            self.artist_aliases.insert(ArtistId(artist_id as u64), ArtistId(canonical_artist_id as u64));
+        }
+        Ok(())
+    }
+
+    /// Credit albums of aliases to the canonical artist, after inserting all files.
+    pub fn finish_artist_aliases(&mut self) {
+        for (album_id, artist_id) in self.albums_credited_to_alias.drain(..) {
+            let name = self.artists.get(&artist_id).expect("Album artists are in the index.").name;
+            if let Some(album) = self.albums.get_mut(&album_id) {
+                album.artist = name;
+            }
+        }
+    }
+
+    /// Load the album's import times from the `album_imports` table.
+    pub fn insert_album_imports(&mut self, tx: &mut Transaction) -> db::Result<()> {
+        for row in db::iter_album_imports(tx)? {
+            let (album_id_i64, imported_at_iso8601) = row?;
+            let album_id = AlbumId(album_id_i64 as u64);
+            let imported_at = match Instant::from_iso8601(&imported_at_iso8601) {
+                Some(t) => t,
+                None => panic!("Encountered invalid imported_at timestamp: {:?}", imported_at_iso8601),
+            };
+            self.album_imports.insert(album_id, imported_at);
+        }
+
+        Ok(())
+    }
+
+    /// Load the pre-gaps of the files from the `file_pregaps` table.
+    ///
+    /// Must be called after inserting the files, only files that became a
+    /// track get a pre-gap.
+    pub fn insert_pregaps(&mut self, tx: &mut Transaction) -> db::Result<()> {
+        let track_ids: HashMap<FileId, TrackId> = self
+            .tracks
+            .iter()
+            .map(|(track_id, track)| (track.file_id, *track_id))
+            .collect();
+
+        for row in db::iter_file_pregaps(tx)? {
+            let (file_id, pregap_samples, sample_rate) = row?;
+            let track_id = match track_ids.get(&FileId(file_id)) {
+                Some(tid) => *tid,
+                None => continue,
+            };
+            // The sample rate comes from the streaminfo block, it is not 0 for
+            // files that we could insert.
+            let pregap_ms = pregap_samples * 1000 / sample_rate.max(1);
+            self.pregaps.insert(track_id, pregap_ms.min(u32::MAX as i64) as u32);
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -909,6 +1181,9 @@ mod test {
     use super::{ArtistId, AlbumArtistsDeduper};
     use super::{Date, parse_date};
     use super::{parse_uuid, parse_uuid_52bits};
+    use super::{album_directory, album_id_for_directory};
+    use super::VARIOUS_ARTISTS_MBID;
+    use crate::prim::VARIOUS_ARTISTS;
 
     #[test]
     fn parse_uuid_parses_uuid() {
@@ -917,6 +1192,11 @@ mod test {
         assert_eq!(parse_uuid("nonsense"), None);
     }
 
+    #[test]
+    fn various_artists_id_matches_mbid() {
+        assert_eq!(parse_uuid(VARIOUS_ARTISTS_MBID), Some(VARIOUS_ARTISTS.0));
+    }
+
     #[test]
     #[allow(clippy::unusual_byte_groupings)]
     fn parse_uuid_52bit_parses_uuid() {
@@ -992,4 +1272,24 @@ mod test {
         assert_eq!(ab1, ab2);
         assert_eq!(ac1, ac2);
     }
+
+    #[test]
+    fn album_directory_skips_disc_directories() {
+        assert_eq!(album_directory("/music/Takk/01 Takk.flac"), "/music/Takk");
+        assert_eq!(album_directory("/music/Box/CD 2/01 Intro.flac"), "/music/Box");
+        assert_eq!(album_directory("/music/Box/disc_10/01 Intro.flac"), "/music/Box");
+        assert_eq!(album_directory("/music/Box/Bonus CD/01 Intro.flac"), "/music/Box/Bonus CD");
+        assert_eq!(album_directory("/music/Discovery/01 One More Time.flac"), "/music/Discovery");
+        assert_eq!(album_directory("01 Takk.flac"), "");
+    }
+
+    #[test]
+    fn album_id_for_directory_fits_52_bits() {
+        let mbid = parse_uuid_52bits("9c9f1380-2516-4fc9-a3e6-f9f61941d090").unwrap();
+        let a = album_id_for_directory(mbid, "/music/Takk (remaster)");
+        let b = album_id_for_directory(mbid, "/music/Takk (vinyl)");
+        assert_ne!(a, b);
+        assert_ne!(a.0, mbid);
+        assert_eq!(a.0 >> 52, 0);
+    }
 }
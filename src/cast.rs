@@ -0,0 +1,813 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Casting playback to Chromecast devices.
+//!
+//! Devices announce themselves over mDNS as `_googlecast._tcp.local`. To cast,
+//! we talk to the device over TLS with the Cast v2 protocol: every message is
+//! a length-prefixed protobuf `CastMessage` envelope with a json payload. We
+//! launch the default media receiver on the device, and tell it to load the
+//! current track from our http api, with its metadata and cover art.
+//!
+//! The queue stays in Musium. While casting, the decode and playback threads
+//! are idle, and the cast thread takes their role: when the device finishes a
+//! track, it completes the track in the queue, and loads the next one.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde_json::{json, Value};
+
+use crate::mvar::Var;
+use crate::player::{Millibel, PlayerState, QueueId, Source};
+use crate::prim::TrackId;
+use crate::radio;
+use crate::{MemoryMetaIndex, MetaIndex};
+
+/// The service that cast devices announce over mDNS.
+const SERVICE_NAME: &str = "_googlecast._tcp.local";
+
+/// The app id of the default media receiver, which plays a media url.
+const DEFAULT_MEDIA_RECEIVER: &str = "CC1AD845";
+
+const NS_CONNECTION: &str = "urn:x-cast:com.google.cast.tp.connection";
+const NS_HEARTBEAT: &str = "urn:x-cast:com.google.cast.tp.heartbeat";
+const NS_RECEIVER: &str = "urn:x-cast:com.google.cast.receiver";
+const NS_MEDIA: &str = "urn:x-cast:com.google.cast.media";
+
+const SENDER_ID: &str = "sender-0";
+const RECEIVER_ID: &str = "receiver-0";
+
+/// Messages larger than this are invalid according to the protocol.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// How long to wait for a message before we check the queue again.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The device closes the connection if it does not hear from us for a while.
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A cast device on the local network.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Device {
+    /// The stable identifier of the device.
+    pub id: String,
+
+    /// The name that the user gave the device, for example "Living Room".
+    pub name: String,
+
+    /// The model name, for example "Google Home Mini".
+    pub model: String,
+
+    /// The host name of the device, we use it for TLS.
+    pub host: String,
+
+    pub address: SocketAddr,
+}
+
+fn build_query() -> Vec<u8> {
+    // A header with one question and no records, then the question.
+    let mut query = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in SERVICE_NAME.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // The root label, then type PTR (12), class IN (1).
+    query.extend_from_slice(&[0, 0, 12, 0, 1]);
+    query
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    let bytes = packet.get(pos..pos + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Read a possibly compressed domain name, return it and the position after it.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bound the number of jumps, so a malicious packet cannot make us loop.
+    for _ in 0..32 {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let offset = read_u16(packet, pos)? as usize & 0x3fff;
+            end = end.or(Some(pos + 2));
+            pos = offset;
+            continue;
+        }
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    None
+}
+
+/// Extract the cast devices from an mDNS response.
+///
+/// The device that sent the response is at `source`, we take its address from
+/// there rather than from the address records.
+fn parse_response(packet: &[u8], source: IpAddr) -> Option<Vec<Device>> {
+    let n_questions = read_u16(packet, 4)?;
+    let n_records = read_u16(packet, 6)? as usize + read_u16(packet, 8)? as usize + read_u16(packet, 10)? as usize;
+    let mut pos = 12;
+    for _ in 0..n_questions {
+        pos = read_name(packet, pos)?.1 + 4;
+    }
+
+    let mut services: HashMap<String, (u16, String)> = HashMap::new();
+    let mut txts: HashMap<String, HashMap<String, String>> = HashMap::new();
+    for _ in 0..n_records {
+        let (owner, after_name) = read_name(packet, pos)?;
+        let rtype = read_u16(packet, after_name)?;
+        let rdlen = read_u16(packet, after_name + 8)? as usize;
+        let rdata_pos = after_name + 10;
+        let rdata = packet.get(rdata_pos..rdata_pos + rdlen)?;
+        match rtype {
+            // SRV: priority, weight, port, target.
+            33 => {
+                let port = read_u16(packet, rdata_pos + 4)?;
+                let (target, _) = read_name(packet, rdata_pos + 6)?;
+                services.insert(owner, (port, target));
+            }
+            // TXT: a sequence of length-prefixed key=value strings.
+            16 => {
+                let mut entries = HashMap::new();
+                let mut i = 0;
+                while i < rdata.len() {
+                    let len = rdata[i] as usize;
+                    let entry = String::from_utf8_lossy(rdata.get(i + 1..i + 1 + len)?).into_owned();
+                    if let Some((k, v)) = entry.split_once('=') {
+                        entries.insert(k.to_string(), v.to_string());
+                    }
+                    i += 1 + len;
+                }
+                txts.insert(owner, entries);
+            }
+            _ => {}
+        }
+        pos = rdata_pos + rdlen;
+    }
+
+    let devices = services
+        .into_iter()
+        .filter(|(instance, _)| instance.ends_with(SERVICE_NAME))
+        .map(|(instance, (port, target))| {
+            let txt = txts.remove(&instance).unwrap_or_default();
+            let get = |k: &str| txt.get(k).cloned();
+            Device {
+                id: get("id").unwrap_or_else(|| instance.clone()),
+                name: get("fn").unwrap_or_else(|| instance.trim_end_matches(SERVICE_NAME).trim_end_matches('.').to_string()),
+                model: get("md").unwrap_or_default(),
+                host: target,
+                address: SocketAddr::new(source, port),
+            }
+        })
+        .collect();
+    Some(devices)
+}
+
+/// Find cast devices on the local network, waiting `timeout` for responses.
+pub fn discover(timeout: Duration) -> io::Result<Vec<Device>> {
+    // When we query from a port other than 5353, responders send their answer
+    // to us directly, so we don't need to join the multicast group.
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.send_to(&build_query(), (Ipv4Addr::new(224, 0, 0, 251), 5353))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut devices: Vec<Device> = Vec::new();
+    let mut buf = [0_u8; 9000];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (len, source) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => break,
+            Err(err) => return Err(err),
+        };
+        for device in parse_response(&buf[..len], source.ip()).unwrap_or_default() {
+            if !devices.iter().any(|d| d.id == device.id) {
+                devices.push(device);
+            }
+        }
+    }
+
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(devices)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut x = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let b = *buf.get(*pos)?;
+        *pos += 1;
+        x |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(x);
+        }
+    }
+    None
+}
+
+/// A `CastMessage` with a string payload, the only kind we use.
+#[derive(Debug, Eq, PartialEq)]
+struct CastMessage {
+    source: String,
+    destination: String,
+    namespace: String,
+    payload: String,
+}
+
+impl CastMessage {
+    /// Encode the protobuf message, without the length prefix.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        // Field 1, protocol_version, is CASTV2_1_0 (0), the default.
+        // Field 5, payload_type, is STRING (0), the default.
+        out.extend_from_slice(&[0x08, 0x00]);
+        for (field, value) in [(2, &self.source), (3, &self.destination), (4, &self.namespace)] {
+            out.push(field << 3 | 2);
+            write_varint(&mut out, value.len() as u64);
+            out.extend_from_slice(value.as_bytes());
+        }
+        out.extend_from_slice(&[0x28, 0x00]);
+        out.push(6 << 3 | 2);
+        write_varint(&mut out, self.payload.len() as u64);
+        out.extend_from_slice(self.payload.as_bytes());
+        out
+    }
+
+    fn decode(buf: &[u8]) -> Option<CastMessage> {
+        let mut message = CastMessage {
+            source: String::new(),
+            destination: String::new(),
+            namespace: String::new(),
+            payload: String::new(),
+        };
+        let mut pos = 0;
+        while pos < buf.len() {
+            let key = read_varint(buf, &mut pos)?;
+            match key & 7 {
+                0 => {
+                    read_varint(buf, &mut pos)?;
+                }
+                2 => {
+                    let len = read_varint(buf, &mut pos)? as usize;
+                    let bytes = buf.get(pos..pos.checked_add(len)?)?;
+                    pos += len;
+                    let value = String::from_utf8_lossy(bytes).into_owned();
+                    match key >> 3 {
+                        2 => message.source = value,
+                        3 => message.destination = value,
+                        4 => message.namespace = value,
+                        6 => message.payload = value,
+                        _ => {}
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(message)
+    }
+}
+
+/// Accept the certificate of the device.
+///
+/// Cast devices use a self-signed certificate, which Google signs through a
+/// separate device authentication message. We don't verify that: the device
+/// only gets urls from us, and we only learn its playback state.
+struct AcceptDeviceCertificate;
+
+impl rustls::client::ServerCertVerifier for AcceptDeviceCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// A connection to a cast device.
+pub struct Connection {
+    stream: rustls::StreamOwned<rustls::ClientConnection, TcpStream>,
+
+    /// Received bytes that do not form a complete message yet.
+    buffer: Vec<u8>,
+
+    next_request_id: u64,
+}
+
+impl Connection {
+    pub fn open(device: &Device) -> io::Result<Connection> {
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AcceptDeviceCertificate))
+            .with_no_client_auth();
+        let invalid_host = |_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid cast device host name.");
+        let server_name = rustls::ServerName::try_from(device.host.trim_end_matches('.')).map_err(invalid_host)?;
+        let tls = rustls::ClientConnection::new(Arc::new(config), server_name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let socket = TcpStream::connect_timeout(&device.address, Duration::from_secs(5))?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut connection = Connection {
+            stream: rustls::StreamOwned::new(tls, socket),
+            buffer: Vec::new(),
+            next_request_id: 1,
+        };
+        connection.send(RECEIVER_ID, NS_CONNECTION, json!({"type": "CONNECT"}))?;
+        connection.stream.sock.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(connection)
+    }
+
+    /// The address of our end, which is an address that the device can reach.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.sock.local_addr()
+    }
+
+    fn send(&mut self, destination: &str, namespace: &str, payload: Value) -> io::Result<()> {
+        let message = CastMessage {
+            source: SENDER_ID.to_string(),
+            destination: destination.to_string(),
+            namespace: namespace.to_string(),
+            payload: payload.to_string(),
+        };
+        let encoded = message.encode();
+        let mut frame = (encoded.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&encoded);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    /// Send a message that the device responds to, return its request id.
+    fn request(&mut self, destination: &str, namespace: &str, mut payload: Value) -> io::Result<u64> {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        payload["requestId"] = json!(request_id);
+        self.send(destination, namespace, payload)?;
+        Ok(request_id)
+    }
+
+    /// Return the next message, or `None` if none arrived in the poll interval.
+    fn receive(&mut self) -> io::Result<Option<CastMessage>> {
+        loop {
+            if self.buffer.len() >= 4 {
+                let len = u32::from_be_bytes([self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]]) as usize;
+                if len > MAX_MESSAGE_LEN {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Cast message is too long."));
+                }
+                if self.buffer.len() >= 4 + len {
+                    let message = CastMessage::decode(&self.buffer[4..4 + len]);
+                    self.buffer.drain(..4 + len);
+                    match message {
+                        Some(m) => return Ok(Some(m)),
+                        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid cast message.")),
+                    }
+                }
+            }
+
+            let mut buf = [0_u8; 4096];
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Cast device closed the connection."));
+                }
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                    return Ok(None);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Where the cast device can fetch tracks and covers.
+#[derive(Clone, Debug)]
+pub struct MediaSource {
+    /// The url of the server, as the device can reach it, without trailing slash.
+    pub base_url: String,
+
+    /// The secret that grants the device access to tracks and covers.
+    pub key: String,
+
+    /// The transcode profile to stream with, or `None` to stream flac.
+    pub profile: Option<String>,
+
+    pub content_type: &'static str,
+}
+
+impl MediaSource {
+    fn track_url(&self, track_id: TrackId) -> String {
+        let mut url = format!("{}/api/track/{}.flac?cast_key={}", self.base_url, track_id, self.key);
+        if let Some(profile) = &self.profile {
+            url.push_str("&profile=");
+            url.push_str(profile);
+        }
+        url
+    }
+}
+
+/// Build the `LOAD` request for a queued track, to start playing at `start_ms`.
+fn build_load(
+    index: &MemoryMetaIndex,
+    media: &MediaSource,
+    session_id: &str,
+    source: &Source,
+    start_ms: u64,
+) -> Option<Value> {
+    let track_id = match source {
+        Source::Track(track_id) => *track_id,
+        Source::Radio(station) => return Some(build_radio_load(session_id, station)),
+    };
+    let track = index.get_track(track_id)?;
+    let album = index.get_album(track_id.album_id())?;
+    let cover_url = format!("{}/api/cover/{}?cast_key={}", media.base_url, track_id.album_id(), media.key);
+    let load = json!({
+        "type": "LOAD",
+        "sessionId": session_id,
+        "autoplay": true,
+        "currentTime": start_ms as f64 / 1000.0,
+        "media": {
+            "contentId": media.track_url(track_id),
+            "contentType": media.content_type,
+            "streamType": "BUFFERED",
+            "duration": track.duration_seconds,
+            "metadata": {
+                // This is the type for music tracks.
+                "metadataType": 3,
+                "title": index.get_string(track.title),
+                "artist": index.get_string(track.artist),
+                "albumName": index.get_string(album.title),
+                "albumArtist": index.get_string(album.artist),
+                "trackNumber": track_id.track_number(),
+                "discNumber": track_id.disc_number(),
+                "releaseDate": album.original_release_date.year.to_string(),
+                "images": [{"url": cover_url}],
+            },
+        },
+    });
+    Some(load)
+}
+
+/// Build the request to load a radio station, the device fetches the stream.
+fn build_radio_load(session_id: &str, station: &radio::Station) -> Value {
+    json!({
+        "type": "LOAD",
+        "sessionId": session_id,
+        "autoplay": true,
+        "media": {
+            "contentId": station.url,
+            "contentType": "audio/mpeg",
+            "streamType": "LIVE",
+            "metadata": {
+                // This is the generic type, radio has no album or track number.
+                "metadataType": 0,
+                "title": station.name,
+            },
+        },
+    })
+}
+
+/// Convert the volume relative to full scale to the level of the device.
+///
+/// The device level is between 0.0 and 1.0, we treat it as amplitude, so
+/// loudness normalization and the volume control carry over approximately.
+pub fn volume_to_level(volume: Millibel) -> f64 {
+    10.0_f64.powf(volume.0 as f64 / 2000.0).min(1.0)
+}
+
+/// What we last heard from the media receiver about the loaded track.
+struct MediaState {
+    queue_id: QueueId,
+
+    /// The request id of the `LOAD`, its response tells the media session.
+    load_request_id: u64,
+
+    /// Identifies the loaded media in status messages, once we know it.
+    media_session_id: Option<i64>,
+
+    /// Position of the last status, and when we received it, if playing.
+    playing_since: Option<(u64, Instant)>,
+}
+
+impl MediaState {
+    fn position_ms(&self) -> Option<u64> {
+        self.playing_since.map(|(position_ms, at)| position_ms + at.elapsed().as_millis() as u64)
+    }
+}
+
+/// What to do with the queue after a media status update.
+enum MediaUpdate {
+    None,
+    Finished(QueueId),
+    Failed(QueueId),
+}
+
+/// Launch the default media receiver, return its transport and session id.
+fn launch_receiver(connection: &mut Connection) -> io::Result<(String, String)> {
+    let launch = json!({"type": "LAUNCH", "appId": DEFAULT_MEDIA_RECEIVER});
+    connection.request(RECEIVER_ID, NS_RECEIVER, launch)?;
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while Instant::now() < deadline {
+        let message = match connection.receive()? {
+            Some(m) => m,
+            None => continue,
+        };
+        let payload: Value = serde_json::from_str(&message.payload).unwrap_or_default();
+        match (&message.namespace[..], payload["type"].as_str()) {
+            (NS_HEARTBEAT, Some("PING")) => connection.send(&message.source, NS_HEARTBEAT, json!({"type": "PONG"}))?,
+            (NS_RECEIVER, Some("RECEIVER_STATUS")) => {
+                let apps = payload["status"]["applications"].as_array().cloned().unwrap_or_default();
+                let app = apps.iter().find(|app| app["appId"] == DEFAULT_MEDIA_RECEIVER);
+                if let Some(app) = app {
+                    let transport_id = app["transportId"].as_str().unwrap_or_default().to_string();
+                    let session_id = app["sessionId"].as_str().unwrap_or_default().to_string();
+                    return Ok((transport_id, session_id));
+                }
+            }
+            (NS_RECEIVER, Some("LAUNCH_ERROR")) => break,
+            _ => {}
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::Other, "Failed to launch the media receiver on the cast device."))
+}
+
+/// Play the queue on the device until casting stops.
+///
+/// Returns `Ok` when somebody selected another output, which changes the cast
+/// session in the player state, or an error when the device went away.
+pub fn main(
+    mut connection: Connection,
+    session: u64,
+    state_mutex: Arc<Mutex<PlayerState>>,
+    index_var: Var<MemoryMetaIndex>,
+    media: MediaSource,
+) -> io::Result<()> {
+    let (transport_id, session_id) = launch_receiver(&mut connection)?;
+    connection.send(&transport_id, NS_CONNECTION, json!({"type": "CONNECT"}))?;
+
+    let mut loaded: Option<MediaState> = None;
+    let mut level = None;
+    let mut last_ping = Instant::now();
+
+    loop {
+        let update = match connection.receive()? {
+            Some(message) => handle_message(&mut connection, &message, &transport_id, &mut loaded)?,
+            None => MediaUpdate::None,
+        };
+
+        if last_ping.elapsed() > PING_INTERVAL {
+            connection.send(RECEIVER_ID, NS_HEARTBEAT, json!({"type": "PING"}))?;
+            last_ping = Instant::now();
+        }
+
+        let (current, start_ms, target_volume) = {
+            let mut state = state_mutex.lock().unwrap();
+            if state.cast_session() != session {
+                // Another output was selected, stop playing on the device.
+                let stop = json!({"type": "STOP", "sessionId": session_id});
+                connection.request(RECEIVER_ID, NS_RECEIVER, stop)?;
+                return Ok(());
+            }
+            match update {
+                MediaUpdate::None => {}
+                MediaUpdate::Finished(queue_id) => {
+                    state.complete_cast_track(queue_id);
+                    loaded = None;
+                }
+                MediaUpdate::Failed(queue_id) => {
+                    warn!("Cast device failed to play queued track {}, skipping it.", queue_id);
+                    if state.current_track().map(|(q, _)| q) == Some(queue_id) {
+                        state.skip();
+                    }
+                    loaded = None;
+                }
+            }
+            if let Some(media_state) = &loaded {
+                if let Some(position_ms) = media_state.position_ms() {
+                    state.set_cast_position(media_state.queue_id, position_ms);
+                }
+            }
+            (state.current_track(), state.current_resume_at_ms(), state.target_volume_full_scale())
+        };
+
+        let new_level = target_volume.map(volume_to_level);
+        if new_level.is_some() && new_level != level {
+            let set_volume = json!({"type": "SET_VOLUME", "volume": {"level": new_level}});
+            connection.request(RECEIVER_ID, NS_RECEIVER, set_volume)?;
+            level = new_level;
+        }
+
+        match (current, &loaded) {
+            (Some((queue_id, _)), Some(m)) if m.queue_id == queue_id => continue,
+            (Some((queue_id, source)), _) => {
+                let index = index_var.get();
+                let load = match build_load(&index, &media, &session_id, &source, start_ms) {
+                    Some(load) => load,
+                    // The track is not in the index any more, after a rescan.
+                    None => {
+                        state_mutex.lock().unwrap().skip();
+                        continue;
+                    }
+                };
+                let load_request_id = connection.request(&transport_id, NS_MEDIA, load)?;
+                loaded = Some(MediaState {
+                    queue_id: queue_id,
+                    load_request_id: load_request_id,
+                    media_session_id: None,
+                    playing_since: None,
+                });
+            }
+            (None, Some(m)) => {
+                if let Some(media_session_id) = m.media_session_id {
+                    let stop = json!({"type": "STOP", "mediaSessionId": media_session_id});
+                    connection.request(&transport_id, NS_MEDIA, stop)?;
+                }
+                loaded = None;
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+/// Handle a message from the device, return what it means for the queue.
+fn handle_message(
+    connection: &mut Connection,
+    message: &CastMessage,
+    transport_id: &str,
+    loaded: &mut Option<MediaState>,
+) -> io::Result<MediaUpdate> {
+    let payload: Value = serde_json::from_str(&message.payload).unwrap_or_default();
+    let message_type = payload["type"].as_str().unwrap_or_default();
+    match (&message.namespace[..], message_type) {
+        (NS_HEARTBEAT, "PING") => {
+            connection.send(&message.source, NS_HEARTBEAT, json!({"type": "PONG"}))?;
+        }
+        (NS_CONNECTION, "CLOSE") if message.source == transport_id => {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "The cast session ended."));
+        }
+        (NS_RECEIVER, "RECEIVER_STATUS") => {
+            // When somebody else casts to the device, our receiver is gone.
+            let apps = payload["status"]["applications"].as_array().cloned().unwrap_or_default();
+            if !apps.iter().any(|app| app["transportId"] == transport_id) {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "The cast session ended."));
+            }
+        }
+        (NS_MEDIA, "LOAD_FAILED") | (NS_MEDIA, "LOAD_CANCELLED") => {
+            if let Some(m) = loaded {
+                if payload["requestId"] == m.load_request_id {
+                    return Ok(MediaUpdate::Failed(m.queue_id));
+                }
+            }
+        }
+        (NS_MEDIA, "MEDIA_STATUS") => {
+            let m = match loaded {
+                Some(m) => m,
+                None => return Ok(MediaUpdate::None),
+            };
+            let statuses = payload["status"].as_array().cloned().unwrap_or_default();
+            for status in statuses {
+                let media_session_id = status["mediaSessionId"].as_i64();
+                // The response to our load tells us the id of the new media,
+                // until then, statuses are about the previous one.
+                if payload["requestId"] == m.load_request_id {
+                    m.media_session_id = media_session_id;
+                }
+                if m.media_session_id.is_none() || media_session_id != m.media_session_id {
+                    continue;
+                }
+                let position_ms = (status["currentTime"].as_f64().unwrap_or(0.0) * 1000.0) as u64;
+                match (status["playerState"].as_str(), status["idleReason"].as_str()) {
+                    (Some("PLAYING"), _) => m.playing_since = Some((position_ms, Instant::now())),
+                    (Some("IDLE"), Some("FINISHED")) => return Ok(MediaUpdate::Finished(m.queue_id)),
+                    (Some("IDLE"), Some("ERROR")) => return Ok(MediaUpdate::Failed(m.queue_id)),
+                    // Buffering or paused on the device, hold the position.
+                    (Some(_), _) => m.playing_since = None,
+                    (None, _) => {}
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(MediaUpdate::None)
+}
+
+/// Generate a random key for cast devices to access the media with.
+pub fn generate_key() -> io::Result<String> {
+    let mut bytes = [0_u8; 16];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{build_query, parse_response, read_name, CastMessage};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn cast_message_roundtrips_through_protobuf() {
+        let message = CastMessage {
+            source: "sender-0".to_string(),
+            destination: "receiver-0".to_string(),
+            namespace: "urn:x-cast:com.google.cast.tp.connection".to_string(),
+            payload: r#"{"type":"CONNECT"}"#.repeat(10),
+        };
+        let encoded = message.encode();
+        // The payload is longer than 127 bytes, so its length takes two bytes.
+        assert_eq!(&encoded[..4], &[0x08, 0x00, 0x12, 0x08]);
+        assert_eq!(CastMessage::decode(&encoded), Some(message));
+        assert_eq!(CastMessage::decode(&[0x12, 0x08, b'a']), None);
+    }
+
+    #[test]
+    fn read_name_follows_compression_pointers() {
+        let query = build_query();
+        assert_eq!(read_name(&query, 12), Some(("_googlecast._tcp.local".to_string(), query.len() - 4)));
+        // A name "x" followed by a pointer to the name in the question.
+        let mut packet = query.clone();
+        packet.extend_from_slice(&[1, b'x', 0xc0, 12]);
+        let start = query.len();
+        assert_eq!(read_name(&packet, start), Some(("x._googlecast._tcp.local".to_string(), start + 4)));
+        // A pointer to itself must not loop forever.
+        let looping = [0xc0, 0x00];
+        assert_eq!(read_name(&looping, 0), None);
+    }
+
+    #[test]
+    fn parse_response_extracts_devices() {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 3, 0, 0, 0, 0];
+        // A PTR record for the service, then SRV and TXT for the instance.
+        let name_at = |offset: u8| [0xc0, offset];
+        packet.extend_from_slice(&[11]);
+        packet.extend_from_slice(b"_googlecast");
+        packet.extend_from_slice(&[4]);
+        packet.extend_from_slice(b"_tcp");
+        packet.extend_from_slice(&[5]);
+        packet.extend_from_slice(b"local");
+        packet.push(0);
+        packet.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0, 120, 0, 8]);
+        let instance_at = packet.len() as u8;
+        packet.extend_from_slice(&[5]);
+        packet.extend_from_slice(b"Kitch");
+        packet.extend_from_slice(&name_at(12));
+        // SRV record.
+        packet.extend_from_slice(&name_at(instance_at));
+        packet.extend_from_slice(&[0, 33, 0x80, 1, 0, 0, 0, 120, 0, 18, 0, 0, 0, 0, 0x1f, 0x49]);
+        packet.extend_from_slice(&[4]);
+        packet.extend_from_slice(b"host");
+        packet.extend_from_slice(&[5]);
+        packet.extend_from_slice(b"local");
+        packet.push(0);
+        // TXT record.
+        let txt: &[&[u8]] = &[b"id=abc123", b"fn=Kitchen speaker", b"md=Google Home"];
+        let txt_len: usize = txt.iter().map(|t| t.len() + 1).sum();
+        packet.extend_from_slice(&name_at(instance_at));
+        packet.extend_from_slice(&[0, 16, 0x80, 1, 0, 0, 0, 120, 0, txt_len as u8]);
+        for entry in txt {
+            packet.push(entry.len() as u8);
+            packet.extend_from_slice(entry);
+        }
+
+        let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let devices = parse_response(&packet, source).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id, "abc123");
+        assert_eq!(devices[0].name, "Kitchen speaker");
+        assert_eq!(devices[0].model, "Google Home");
+        assert_eq!(devices[0].host, "host.local");
+        assert_eq!(devices[0].address.to_string(), "192.168.1.20:8009");
+
+        // Truncated packets are rejected rather than causing a panic.
+        for len in 0..packet.len() {
+            let _ = parse_response(&packet[..len], source);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Talking to the API of a running Musium server, for `musium tui`.
+//!
+//! Like the scrobbler, we make requests through `curl`, so servers behind
+//! https work without pulling in a TLS stack. The url of the server and the
+//! token come from the `MUSIUM_URL` and `MUSIUM_TOKEN` environment variables,
+//! so the token does not end up in the shell history.
+
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use serde_json::Value;
+
+use crate::error::{Error, Result};
+
+/// Where we find the server when `MUSIUM_URL` is not set.
+const DEFAULT_URL: &str = "http://localhost:8233";
+
+pub struct Client {
+    /// Url of the server, without trailing slash.
+    base_url: String,
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn new(base_url: &str, token: Option<String>) -> Client {
+        Client {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: token,
+        }
+    }
+
+    /// Connect to `url`, or to `MUSIUM_URL`, with the token from `MUSIUM_TOKEN`.
+    pub fn from_env(url: Option<&str>) -> Client {
+        let env_url = env::var("MUSIUM_URL").ok();
+        let base_url = url.or(env_url.as_deref()).unwrap_or(DEFAULT_URL);
+        Client::new(base_url, env::var("MUSIUM_TOKEN").ok())
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Make a request to `/api/«path»`, return the json response.
+    ///
+    /// Returns `Value::Null` when the response is empty.
+    pub fn call(&self, method: &str, path: &str) -> Result<Value> {
+        let url = format!("{}/api/{}", self.base_url, path);
+        let mut curl = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail"])
+            .args(["--max-time", "10"])
+            .args(["--request", method])
+            // Read the headers from stdin, so the token does not show up in
+            // the process list.
+            .args(["--header", "@-"])
+            .arg(&url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::CommandError("Failed to spawn 'curl'.", e))?;
+
+        {
+            let stdin = curl.stdin.as_mut().expect("Stdin is piped.");
+            if let Some(token) = self.token.as_ref() {
+                writeln!(stdin, "Authorization: Bearer {}", token)
+                    .map_err(|e| Error::CommandError("Failed to write to 'curl'.", e))?;
+            }
+        }
+
+        let output = curl
+            .wait_with_output()
+            .map_err(|e| Error::CommandError("Failed to wait for 'curl'.", e))?;
+
+        if !output.status.success() {
+            return Err(Error::ClientError(format!(
+                "{} {} failed: {}",
+                method,
+                url,
+                String::from_utf8_lossy(&output.stderr).trim(),
+            )));
+        }
+
+        if output.stdout.iter().all(|b| b.is_ascii_whitespace()) {
+            return Ok(Value::Null);
+        }
+
+        serde_json::from_slice(&output.stdout)
+            .map_err(|e| Error::ClientError(format!("Invalid response from {}: {}", url, e)))
+    }
+
+    pub fn get(&self, path: &str) -> Result<Value> {
+        self.call("GET", path)
+    }
+
+    pub fn post(&self, path: &str) -> Result<Value> {
+        self.call("POST", path)
+    }
+
+    pub fn put(&self, path: &str) -> Result<Value> {
+        self.call("PUT", path)
+    }
+
+    pub fn delete(&self, path: &str) -> Result<Value> {
+        self.call("DELETE", path)
+    }
+}
+
+/// Percent-encode a value for use in a query string.
+pub fn encode_query_value(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+/// Format a duration in seconds as `m:ss`, or `h:mm:ss` when it is long.
+pub fn format_duration(seconds: u64) -> String {
+    match seconds {
+        s if s >= 3600 => format!("{}:{:02}:{:02}", s / 3600, (s / 60) % 60, s % 60),
+        s => format!("{}:{:02}", s / 60, s % 60),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_query_value, format_duration, Client};
+
+    #[test]
+    fn client_strips_trailing_slash() {
+        assert_eq!(Client::new("http://musium:8233/", None).base_url(), "http://musium:8233");
+    }
+
+    #[test]
+    fn format_duration_formats_minutes_and_hours() {
+        assert_eq!(format_duration(0), "0:00");
+        assert_eq!(format_duration(296), "4:56");
+        assert_eq!(format_duration(3725), "1:02:05");
+    }
+
+    #[test]
+    fn encode_query_value_escapes_reserved_characters() {
+        assert_eq!(encode_query_value("sigur rós & co"), "sigur+r%C3%B3s+%26+co");
+    }
+}
@@ -11,35 +11,227 @@
 
 //! Configuration file parser.
 
+use std::env;
 use std::fmt;
-use std::path::PathBuf;
+use std::fs;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::result;
 use std::str::FromStr;
 
+use crate::auth::ApiToken;
+use crate::build::AlbumIdentity;
+use crate::enrichment::Source as EnrichmentSource;
+use crate::dbus::Bus;
 use crate::error::{Error, Result};
+use crate::library_view::ViewRule;
+use crate::listens::ListenThreshold;
+use crate::log;
+use crate::player;
 use crate::prim::Hertz;
+use crate::proxy;
+use crate::resample::SampleRatePolicy;
+use crate::transcode::Profile;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub listen: String,
+    pub mpd_listen: Option<String>,
+    pub mpris_bus: Option<Bus>,
+    pub dlna_name: Option<String>,
     pub library_path: PathBuf,
     pub db_path: PathBuf,
-    // TODO: Make this optional; pick the first one by default.
-    pub audio_device: String,
-    pub audio_volume_control: String,
+    // TODO: Pick the first one by default when neither this nor Snapcast is set.
+    pub audio_device: Option<String>,
+    pub audio_volume_control: Option<String>,
+    pub snapcast_fifo: Option<PathBuf>,
+    pub snapcast_sample_rate: Hertz,
+    pub snapcast_control: Option<String>,
     pub high_pass_cutoff: Hertz,
+    pub bit_perfect: bool,
+    pub digital_volume: bool,
+    pub sample_rate_policy: SampleRatePolicy,
+    pub fixed_sample_rate: Hertz,
+    pub decode_ahead_seconds: u64,
+    pub decode_buffer_mb: u64,
     pub exec_pre_playback_path: Option<PathBuf>,
     pub exec_post_idle_path: Option<PathBuf>,
+    pub pre_playback_delay_ms: u64,
     pub idle_timeout_seconds: u64,
+    pub exec_status_change_path: Option<PathBuf>,
+    pub gpio_playing: Option<u32>,
+    pub gpio_error: Option<u32>,
+    pub resume_min_duration_seconds: u64,
+    pub search_max_edits: u32,
+    pub album_identity: AlbumIdentity,
+    pub lastfm_api_key: Option<String>,
+    pub lastfm_api_secret: Option<String>,
+    pub lastfm_session_key: Option<String>,
+    pub scrobble_threshold_percent: u32,
+    pub scrobble_threshold_seconds: u32,
+    pub timezone: Option<String>,
+    pub acoustid_api_key: Option<String>,
+    pub enrichment: Option<EnrichmentSource>,
+    pub webhook_urls: Vec<String>,
+    pub maintenance_interval_hours: Option<u64>,
+    pub api_tokens: Vec<ApiToken>,
+    pub library_views: Vec<ViewRule>,
+    pub unauthenticated: bool,
+    pub graphql: bool,
+    pub base_path: String,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub cors_origins: Vec<String>,
+    pub rate_limit_per_minute: Option<u32>,
+    pub tls_certificate_path: Option<PathBuf>,
+    pub tls_private_key_path: Option<PathBuf>,
+    pub transcode_profiles: Vec<Profile>,
+    pub cast_base_url: Option<String>,
+    pub cast_profile: Option<String>,
+    pub players: Vec<String>,
+    pub party_mode: bool,
+    pub party_skip_votes: u32,
+    pub party_enqueues_per_hour: u32,
+    pub webinterface_dir: Option<PathBuf>,
+    pub thumbnail_threads: Option<usize>,
+    pub log_level: log::Filter,
+    pub log_format: log::Format,
+}
+
+impl Config {
+    /// Return the Last.fm credentials, if all of them are configured.
+    pub fn lastfm_credentials(&self) -> Option<(&str, &str, &str)> {
+        match (&self.lastfm_api_key, &self.lastfm_api_secret, &self.lastfm_session_key) {
+            (Some(key), Some(secret), Some(session)) => Some((key, secret, session)),
+            _ => None,
+        }
+    }
+
+    /// Return how much of a track needs to play for its listen to count.
+    pub fn listen_threshold(&self) -> ListenThreshold {
+        ListenThreshold {
+            percent: self.scrobble_threshold_percent,
+            seconds: self.scrobble_threshold_seconds,
+        }
+    }
+
+    /// Return the certificate and private key paths, if TLS is enabled.
+    pub fn tls_paths(&self) -> Option<(&Path, &Path)> {
+        match (&self.tls_certificate_path, &self.tls_private_key_path) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            _ => None,
+        }
+    }
+
+    /// Return the transcode profile with the given name, if there is one.
+    pub fn get_transcode_profile(&self, name: &str) -> Option<&Profile> {
+        self.transcode_profiles.iter().find(|p| p.name == name)
+    }
+
+    /// Check that the addresses and paths in the config can be used.
+    ///
+    /// This only inspects the file system and resolves addresses, it does not
+    /// bind or open anything. Returns a description of every problem found.
+    pub fn check_paths(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let addrs = [("listen", Some(&self.listen)), ("mpd_listen", self.mpd_listen.as_ref())];
+        for &(key, addr) in &addrs {
+            if let Some(addr) = addr {
+                if addr.to_socket_addrs().is_err() {
+                    problems.push(format!("{} = {} is not a valid address and port.", key, addr));
+                }
+            }
+        }
+
+        let dirs = [
+            ("library_path", Some(&self.library_path)),
+            ("webinterface_dir", self.webinterface_dir.as_ref()),
+        ];
+        for &(key, path) in &dirs {
+            if let Some(path) = path {
+                if !path.is_dir() {
+                    problems.push(format!("{} = {} is not a directory.", key, path.to_string_lossy()));
+                }
+            }
+        }
+
+        // The database itself is created on the first scan, but the directory
+        // that it goes in must exist.
+        match self.db_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => problems.push(format!(
+                "db_path = {} is in a directory that does not exist.",
+                self.db_path.to_string_lossy(),
+            )),
+            _ => {}
+        }
+
+        let files = [
+            ("exec_pre_playback_path", self.exec_pre_playback_path.as_ref()),
+            ("exec_post_idle_path", self.exec_post_idle_path.as_ref()),
+            ("exec_status_change_path", self.exec_status_change_path.as_ref()),
+            ("tls_certificate_path", self.tls_certificate_path.as_ref()),
+            ("tls_private_key_path", self.tls_private_key_path.as_ref()),
+        ];
+        for &(key, path) in &files {
+            if let Some(path) = path {
+                if !path.is_file() {
+                    problems.push(format!("{} = {} is not a file.", key, path.to_string_lossy()));
+                }
+            }
+        }
+
+        if let Some(tz) = &self.timezone {
+            if !Path::new("/usr/share/zoneinfo").join(tz).is_file() {
+                problems.push(format!("timezone = {} is not a known time zone.", tz));
+            }
+        }
+
+        problems
+    }
 }
 
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "  listen                 = {}", self.listen)?;
+        match self.mpd_listen.as_ref() {
+            Some(addr) => writeln!(f, "  mpd_listen             = {}", addr)?,
+            None => writeln!(f, "  mpd_listen             is not set")?,
+        }
+        match self.mpris_bus {
+            Some(Bus::Session) => writeln!(f, "  mpris_bus              = session")?,
+            Some(Bus::System) => writeln!(f, "  mpris_bus              = system")?,
+            None => writeln!(f, "  mpris_bus              is not set")?,
+        }
+        match self.dlna_name.as_ref() {
+            Some(name) => writeln!(f, "  dlna_name              = {}", name)?,
+            None => writeln!(f, "  dlna_name              is not set")?,
+        }
         writeln!(f, "  library_path           = {}", self.library_path.to_string_lossy())?;
         writeln!(f, "  db_path                = {}", self.db_path.to_string_lossy())?;
-        writeln!(f, "  audio_device           = {}", self.audio_device)?;
-        writeln!(f, "  audio_volume_control   = {}", self.audio_volume_control)?;
+        match self.audio_device.as_ref() {
+            Some(device) => writeln!(f, "  audio_device           = {}", device)?,
+            None => writeln!(f, "  audio_device           is not set")?,
+        }
+        match self.audio_volume_control.as_ref() {
+            Some(control) => writeln!(f, "  audio_volume_control   = {}", control)?,
+            None => writeln!(f, "  audio_volume_control   is not set")?,
+        }
+        match self.snapcast_fifo.as_ref() {
+            Some(path) => writeln!(f, "  snapcast_fifo          = {}", path.to_string_lossy())?,
+            None => writeln!(f, "  snapcast_fifo          is not set")?,
+        }
+        writeln!(f, "  snapcast_sample_rate   = {}", self.snapcast_sample_rate)?;
+        match self.snapcast_control.as_ref() {
+            Some(addr) => writeln!(f, "  snapcast_control       = {}", addr)?,
+            None => writeln!(f, "  snapcast_control       is not set")?,
+        }
         writeln!(f, "  high_pass_cutoff       = {}", self.high_pass_cutoff)?;
+        writeln!(f, "  bit_perfect            = {}", self.bit_perfect)?;
+        writeln!(f, "  digital_volume         = {}", self.digital_volume)?;
+        writeln!(f, "  sample_rate_policy     = {}", self.sample_rate_policy)?;
+        writeln!(f, "  fixed_sample_rate      = {}", self.fixed_sample_rate)?;
+        writeln!(f, "  decode_ahead_seconds   = {}", self.decode_ahead_seconds)?;
+        writeln!(f, "  decode_buffer_mb       = {}", self.decode_buffer_mb)?;
         match self.exec_pre_playback_path.as_ref() {
             Some(path) => writeln!(f, "  exec_pre_playback_path = {}", path.to_string_lossy())?,
             None => writeln!(f, "  exec_pre_playback_path is not set")?,
@@ -48,80 +240,902 @@ impl fmt::Display for Config {
             Some(path) => writeln!(f, "  exec_post_idle_path    = {}", path.to_string_lossy())?,
             None => writeln!(f, "  exec_post_idle_path    is not set")?,
         }
-        write!(f, "  idle_timeout_seconds   = {}", self.idle_timeout_seconds)?;
+        writeln!(f, "  pre_playback_delay_ms  = {}", self.pre_playback_delay_ms)?;
+        writeln!(f, "  idle_timeout_seconds   = {}", self.idle_timeout_seconds)?;
+        match self.exec_status_change_path.as_ref() {
+            Some(path) => writeln!(f, "  exec_status_change_path = {}", path.to_string_lossy())?,
+            None => writeln!(f, "  exec_status_change_path is not set")?,
+        }
+        match self.gpio_playing {
+            Some(line) => writeln!(f, "  gpio_playing           = {}", line)?,
+            None => writeln!(f, "  gpio_playing           is not set")?,
+        }
+        match self.gpio_error {
+            Some(line) => writeln!(f, "  gpio_error             = {}", line)?,
+            None => writeln!(f, "  gpio_error             is not set")?,
+        }
+        writeln!(f, "  resume_min_duration_seconds = {}", self.resume_min_duration_seconds)?;
+        writeln!(f, "  search_max_edits       = {}", self.search_max_edits)?;
+        match self.album_identity {
+            AlbumIdentity::MusicBrainz => writeln!(f, "  album_identity         = musicbrainz")?,
+            AlbumIdentity::Directory => writeln!(f, "  album_identity         = directory")?,
+        }
+        match self.maintenance_interval_hours {
+            Some(hours) => writeln!(f, "  maintenance_interval_hours = {}", hours)?,
+            None => writeln!(f, "  maintenance_interval_hours is not set")?,
+        }
+        // We don't print the Last.fm credentials, they are secrets.
+        match self.lastfm_credentials() {
+            Some(..) => writeln!(f, "  lastfm credentials     are set")?,
+            None => writeln!(f, "  lastfm credentials     are not set")?,
+        }
+        writeln!(f, "  scrobble_threshold_percent = {}", self.scrobble_threshold_percent)?;
+        writeln!(f, "  scrobble_threshold_seconds = {}", self.scrobble_threshold_seconds)?;
+        match self.timezone {
+            Some(ref tz) => writeln!(f, "  timezone               = {}", tz)?,
+            None => writeln!(f, "  timezone               is not set")?,
+        }
+        match self.acoustid_api_key {
+            Some(..) => writeln!(f, "  acoustid_api_key       is set")?,
+            None => writeln!(f, "  acoustid_api_key       is not set")?,
+        }
+        match self.enrichment {
+            Some(source) => writeln!(f, "  enrichment             = {}", source.name())?,
+            None => writeln!(f, "  enrichment             is not set")?,
+        }
+        // Webhook urls can contain secrets too, so we only print how many.
+        writeln!(f, "  webhook_url            is set {} times", self.webhook_urls.len())?;
+        // Likewise for the tokens, we print only their names.
+        for token in &self.api_tokens {
+            match token.user() {
+                Some(user) => writeln!(f, "  api_token              = {} {:?} {}", token.name, token.scope, user)?,
+                None => writeln!(f, "  api_token              = {} {:?}", token.name, token.scope)?,
+            }
+        }
+        for rule in &self.library_views {
+            writeln!(f, "  library_view           = {} {:?}", rule.user, rule.rule)?;
+        }
+        writeln!(f, "  unauthenticated        = {}", self.unauthenticated)?;
+        writeln!(f, "  graphql                = {}", self.graphql)?;
+        match self.base_path.as_str() {
+            "" => writeln!(f, "  base_path              is not set")?,
+            path => writeln!(f, "  base_path              = {}", path)?,
+        }
+        for addr in &self.trusted_proxies {
+            writeln!(f, "  trusted_proxy          = {}", addr)?;
+        }
+        for origin in &self.cors_origins {
+            writeln!(f, "  cors_origin            = {}", origin)?;
+        }
+        match self.rate_limit_per_minute {
+            Some(n) => writeln!(f, "  rate_limit_per_minute  = {}", n)?,
+            None => writeln!(f, "  rate_limit_per_minute  is not set")?,
+        }
+        for profile in &self.transcode_profiles {
+            writeln!(
+                f,
+                "  transcode_profile      = {} {:?} {}",
+                profile.name, profile.format.codec, profile.format.bitrate_kbps,
+            )?;
+        }
+        match self.cast_base_url.as_ref() {
+            Some(url) => writeln!(f, "  cast_base_url          = {}", url)?,
+            None => writeln!(f, "  cast_base_url          is not set")?,
+        }
+        match self.cast_profile.as_ref() {
+            Some(name) => writeln!(f, "  cast_profile           = {}", name)?,
+            None => writeln!(f, "  cast_profile           is not set")?,
+        }
+        for name in &self.players {
+            writeln!(f, "  player                 = {}", name)?;
+        }
+        writeln!(f, "  party_mode             = {}", self.party_mode)?;
+        writeln!(f, "  party_skip_votes       = {}", self.party_skip_votes)?;
+        writeln!(f, "  party_enqueues_per_hour = {}", self.party_enqueues_per_hour)?;
+        match self.webinterface_dir.as_ref() {
+            Some(path) => writeln!(f, "  webinterface_dir       = {}", path.to_string_lossy())?,
+            None => writeln!(f, "  webinterface_dir       is not set")?,
+        }
+        match self.thumbnail_threads {
+            Some(n) => writeln!(f, "  thumbnail_threads      = {}", n)?,
+            None => writeln!(f, "  thumbnail_threads      is not set")?,
+        }
+        writeln!(f, "  log_level              = {}", self.log_level)?;
+        match self.log_format {
+            log::Format::Text => writeln!(f, "  log_format             = text")?,
+            log::Format::Json => writeln!(f, "  log_format             = json")?,
+        }
+        match self.tls_paths() {
+            Some((cert, key)) => {
+                writeln!(f, "  tls_certificate_path   = {}", cert.to_string_lossy())?;
+                write!(f, "  tls_private_key_path   = {}", key.to_string_lossy())?;
+            }
+            None => write!(f, "  tls is not enabled")?,
+        }
 
         Ok(())
     }
 }
 
+/// Describe why loading the config file at `path` failed, for the user.
+pub fn describe_error(path: &Path, err: &Error) -> String {
+    let fname = path.to_string_lossy();
+    match err {
+        Error::InvalidConfig(lineno, msg) => format!("{}:{}: {}", fname, lineno, msg),
+        Error::InvalidConfigEnv(var, msg) => format!("Invalid value in {}: {}", var, msg),
+        Error::IncompleteConfig(msg) => format!("{}: {}", fname, msg),
+        Error::IoError(err) => format!("Failed to read {}: {}", fname, err),
+        err => format!("Failed to load {}: {:?}", fname, err),
+    }
+}
+
+/// All keys that the config file supports.
+const KEYS: &[&str] = &[
+    "listen",
+    "mpd_listen",
+    "mpris_bus",
+    "dlna_name",
+    "library_path",
+    "db_path",
+    "audio_device",
+    "audio_volume_control",
+    "snapcast_fifo",
+    "snapcast_sample_rate",
+    "snapcast_control",
+    "high_pass_cutoff",
+    "bit_perfect",
+    "digital_volume",
+    "sample_rate_policy",
+    "fixed_sample_rate",
+    "decode_ahead_seconds",
+    "decode_buffer_mb",
+    "exec_pre_playback_path",
+    "exec_post_idle_path",
+    "pre_playback_delay_ms",
+    "idle_timeout_seconds",
+    "exec_status_change_path",
+    "gpio_playing",
+    "gpio_error",
+    "resume_min_duration_seconds",
+    "search_max_edits",
+    "album_identity",
+    "lastfm_api_key",
+    "lastfm_api_secret",
+    "lastfm_session_key",
+    "scrobble_threshold_percent",
+    "scrobble_threshold_seconds",
+    "timezone",
+    "acoustid_api_key",
+    "enrichment",
+    "webhook_url",
+    "maintenance_interval_hours",
+    "api_token",
+    "library_view",
+    "unauthenticated",
+    "graphql",
+    "base_path",
+    "trusted_proxy",
+    "cors_origin",
+    "rate_limit_per_minute",
+    "tls_certificate_path",
+    "tls_private_key_path",
+    "transcode_profile",
+    "cast_base_url",
+    "cast_profile",
+    "player",
+    "party_mode",
+    "party_skip_votes",
+    "party_enqueues_per_hour",
+    "webinterface_dir",
+    "thumbnail_threads",
+    "log_level",
+    "log_format",
+];
+
+/// Keys that can be repeated, or that take an array of values.
+const LIST_KEYS: &[&str] = &[
+    "webhook_url",
+    "api_token",
+    "library_view",
+    "trusted_proxy",
+    "cors_origin",
+    "transcode_profile",
+    "player",
+];
+
+/// Where a setting was read from, to point at it in error messages.
+enum Source {
+    /// A line in the config file, 1-based.
+    Line(usize),
+    /// An environment variable with the given name.
+    Env(String),
+}
+
+/// A `key = value` pair, with the value already unquoted.
+struct Assignment {
+    source: Source,
+    key: String,
+    values: Vec<String>,
+}
+
+impl Assignment {
+    fn invalid(&self, msg: &'static str) -> Error {
+        match &self.source {
+            Source::Line(lineno) => Error::InvalidConfig(*lineno, msg),
+            Source::Env(var) => Error::InvalidConfigEnv(var.clone(), msg),
+        }
+    }
+}
+
+/// Skip whitespace (including newlines) and `#`-comments.
+fn skip_space(mut s: &str) -> &str {
+    loop {
+        s = s.trim_start();
+        if !s.starts_with('#') {
+            return s
+        }
+        s = match s.find('\n') {
+            Some(i) => &s[i + 1..],
+            None => "",
+        };
+    }
+}
+
+/// Parse a TOML basic (`"`) or literal (`'`) string at the start of `s`.
+///
+/// Returns the contents of the string and the remainder of the input.
+fn parse_string(s: &str) -> result::Result<(String, &str), &'static str> {
+    let unterminated = "Unterminated string, the closing quote is missing.";
+
+    if let Some(literal) = s.strip_prefix('\'') {
+        return match literal.find(|c: char| c == '\'' || c == '\n') {
+            Some(i) if &literal[i..i + 1] == "'" => Ok((literal[..i].to_string(), &literal[i + 1..])),
+            _ => Err(unterminated),
+        }
+    }
+
+    let mut result = String::new();
+    let mut chars = s[1..].char_indices();
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '"' => return Ok((result, &s[1 + i + 1..])),
+            '\n' => return Err(unterminated),
+            '\\' => match chars.next() {
+                Some((_, '"')) => result.push('"'),
+                Some((_, '\\')) => result.push('\\'),
+                Some((_, 'n')) => result.push('\n'),
+                Some((_, 'r')) => result.push('\r'),
+                Some((_, 't')) => result.push('\t'),
+                _ => return Err(
+                    "Invalid escape sequence in string, \
+                    supported are \\\", \\\\, \\n, \\r, and \\t."
+                ),
+            }
+            _ => result.push(ch),
+        }
+    }
+
+    Err(unterminated)
+}
+
+/// Parse one element of an array, a string or a bare value.
+fn parse_array_element(s: &str) -> result::Result<(String, &str), &'static str> {
+    if s.starts_with('"') || s.starts_with('\'') {
+        return parse_string(s)
+    }
+    if s.starts_with('[') {
+        return Err("Nested arrays are not supported.")
+    }
+    let end = s
+        .find(|c: char| c == ',' || c == ']' || c == '#' || c.is_whitespace())
+        .unwrap_or(s.len());
+    match end {
+        0 => Err("Expected a value in the array."),
+        _ => Ok((s[..end].to_string(), &s[end..])),
+    }
+}
+
+/// Check that only whitespace and comments follow a value.
+fn expect_end(s: &str) -> result::Result<(), &'static str> {
+    match skip_space(s) {
+        "" => Ok(()),
+        _ => Err("Unexpected text after the value. Use '#' to start a comment."),
+    }
+}
+
+/// Parse the value of a `key = value` pair.
+///
+/// The value can be a TOML string, an array, or a bare value. Bare values are
+/// taken verbatim up to a trailing comment. This covers TOML integers and
+/// booleans, and also the unquoted strings of older config files. Returns
+/// `None` when the value is an array that continues on the next line.
+fn parse_value(s: &str) -> result::Result<Option<Vec<String>>, &'static str> {
+    let s = s.trim();
+
+    if s.starts_with('"') || s.starts_with('\'') {
+        let (value, rest) = parse_string(s)?;
+        expect_end(rest)?;
+        return Ok(Some(vec![value]))
+    }
+
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = skip_space(rest);
+            if rest.is_empty() {
+                return Ok(None)
+            }
+            if let Some(after) = rest.strip_prefix(']') {
+                expect_end(after)?;
+                return Ok(Some(values))
+            }
+            let (value, after) = parse_array_element(rest)?;
+            values.push(value);
+            rest = skip_space(after);
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.is_empty() && !rest.starts_with(']') {
+                return Err("Expected ',' or ']' after array element.")
+            }
+        }
+    }
+
+    // A '#' only starts a comment after whitespace, so urls with a fragment
+    // can still be written unquoted.
+    let end = s
+        .char_indices()
+        .find(|&(i, ch)| ch == '#' && s[..i].ends_with(char::is_whitespace))
+        .map(|(i, _)| i)
+        .unwrap_or(s.len());
+    Ok(Some(vec![s[..end].trim_end().to_string()]))
+}
+
+/// Parse frequencies in the '50 Hz' form, or as a bare integer in Hz.
+fn parse_hertz(value: &str) -> result::Result<Hertz, &'static str> {
+    match u32::from_str(value) {
+        Ok(hz) => Ok(Hertz(hz)),
+        Err(_) => Hertz::from_str(value),
+    }
+}
+
+/// Split the lines of a config file into key-value pairs.
+fn parse_assignments<I, S>(lines: I) -> Result<Vec<Assignment>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut assignments = Vec::new();
+
+    // An array that spans multiple lines, as (line number, key, value so far).
+    let mut pending: Option<(usize, String, String)> = None;
+
+    for (i, line_raw) in lines.into_iter().enumerate() {
+        let lineno = i + 1;
+        let line = line_raw.as_ref().trim();
+
+        let (start, key, value) = match pending.take() {
+            Some((start, key, mut value)) => {
+                value.push('\n');
+                value.push_str(line);
+                (start, key, value)
+            }
+            None => {
+                // Allow empty lines and '#'-comments in the config file.
+                if line.is_empty() || line.starts_with('#') {
+                    continue
+                }
+
+                if line.starts_with('[') {
+                    let msg = "Tables are not supported, all keys must be at the top level.";
+                    return Err(Error::InvalidConfig(lineno, msg))
+                }
+
+                match line.find('=') {
+                    Some(n) => (lineno, line[..n].trim().to_string(), line[n + 1..].to_string()),
+                    None => {
+                        let msg = "Line contains no '='. \
+                            Expected key-value pair like 'audio_device = \"UCM404HD 192k\"'.";
+                        return Err(Error::InvalidConfig(lineno, msg))
+                    }
+                }
+            }
+        };
+
+        match parse_value(&value) {
+            Ok(Some(values)) => assignments.push(Assignment {
+                source: Source::Line(start),
+                key: key,
+                values: values,
+            }),
+            Ok(None) => pending = Some((start, key, value)),
+            Err(msg) => return Err(Error::InvalidConfig(start, msg)),
+        }
+    }
+
+    if let Some((start, ..)) = pending {
+        let msg = "Unterminated array, the closing ']' is missing.";
+        return Err(Error::InvalidConfig(start, msg))
+    }
+
+    Ok(assignments)
+}
+
+/// Read `MUSIUM_<KEY>` environment variables for all supported keys.
+fn parse_env_overrides<F>(get_env: F) -> Result<Vec<Assignment>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut assignments = Vec::new();
+
+    for key in KEYS {
+        let var = format!("MUSIUM_{}", key.to_uppercase());
+        let value = match get_env(&var) {
+            Some(value) => value,
+            None => continue,
+        };
+        match parse_value(&value) {
+            Ok(Some(values)) => assignments.push(Assignment {
+                source: Source::Env(var),
+                key: key.to_string(),
+                values: values,
+            }),
+            Ok(None) => {
+                let msg = "Unterminated array, the closing ']' is missing.";
+                return Err(Error::InvalidConfigEnv(var, msg))
+            }
+            Err(msg) => return Err(Error::InvalidConfigEnv(var, msg)),
+        }
+    }
+
+    Ok(assignments)
+}
+
 impl Config {
+    /// Read and parse the config file, with overrides from the environment.
+    pub fn load(path: &Path) -> Result<Config> {
+        let src = fs::read_to_string(path)?;
+        Config::parse_with_env(src.lines(), |var| env::var(var).ok())
+    }
+
+    /// Parse a config file, without overrides from the environment.
     pub fn parse<I, S>(lines: I) -> Result<Config>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<str>,
+    {
+        Config::parse_with_env(lines, |_| None)
+    }
+
+    /// Parse a config file, and apply overrides from `MUSIUM_<KEY>` variables.
+    ///
+    /// The environment is accessed through `get_env`, so tests do not depend
+    /// on the environment of the process.
+    pub fn parse_with_env<I, S, F>(lines: I, get_env: F) -> Result<Config>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+        F: Fn(&str) -> Option<String>,
     {
         let mut listen = None;
+        let mut mpd_listen = None;
+        let mut mpris_bus = None;
+        let mut dlna_name = None;
         let mut library_path = None;
         let mut db_path = None;
         let mut audio_device = None;
         let mut audio_volume_control = None;
+        let mut snapcast_fifo = None;
+        let mut snapcast_sample_rate = None;
+        let mut snapcast_control = None;
         let mut high_pass_cutoff = None;
+        let mut bit_perfect = false;
+        let mut digital_volume = false;
+        let mut sample_rate_policy = SampleRatePolicy::Switch;
+        let mut fixed_sample_rate = None;
+        let mut decode_ahead_seconds = 30;
+        let mut decode_buffer_mb = 105;
         let mut exec_pre_playback_path = None;
         let mut exec_post_idle_path = None;
+        let mut pre_playback_delay_ms = 0;
         let mut idle_timeout_seconds = 180;
+        let mut exec_status_change_path = None;
+        let mut gpio_playing = None;
+        let mut gpio_error = None;
+        let mut resume_min_duration_seconds = 1200;
+        let mut search_max_edits = 1;
+        let mut album_identity = AlbumIdentity::MusicBrainz;
+        let mut lastfm_api_key = None;
+        let mut lastfm_api_secret = None;
+        let mut lastfm_session_key = None;
+        let mut scrobble_threshold_percent = 50;
+        let mut scrobble_threshold_seconds = 240;
+        let mut timezone = None;
+        let mut acoustid_api_key = None;
+        let mut enrichment = None;
+        let mut webhook_urls = Vec::new();
+        let mut maintenance_interval_hours = None;
+        let mut api_tokens = Vec::new();
+        let mut library_views = Vec::new();
+        let mut unauthenticated = false;
+        let mut graphql = false;
+        let mut base_path = String::new();
+        let mut trusted_proxies = Vec::new();
+        let mut cors_origins = Vec::new();
+        let mut rate_limit_per_minute = None;
+        let mut tls_certificate_path = None;
+        let mut tls_private_key_path = None;
+        let mut transcode_profiles = Vec::new();
+        let mut cast_base_url = None;
+        let mut cast_profile = None;
+        let mut players = Vec::new();
+        let mut party_mode = false;
+        let mut party_skip_votes = 3;
+        let mut party_enqueues_per_hour = 10;
+        let mut webinterface_dir = None;
+        let mut thumbnail_threads = None;
+        let mut log_level = log::Filter::new();
+        let mut log_format = log::Format::Text;
+
+        let mut assignments = parse_assignments(lines)?;
+
+        // Settings from the environment replace those from the file entirely,
+        // also for keys that can be set multiple times.
+        let overrides = parse_env_overrides(get_env)?;
+        assignments.retain(|a| !overrides.iter().any(|o| o.key == a.key));
+        assignments.extend(overrides);
 
-        for (lineno, line_raw) in lines.into_iter().enumerate() {
-            let line = line_raw.as_ref();
+        for assignment in &assignments {
+            let key = assignment.key.as_str();
 
-            // Allow empty lines in the config file.
-            if line.len() == 0 {
-                continue
+            if !KEYS.contains(&key) {
+                let msg = "Unknown key. See the configuration docs for supported keys.";
+                return Err(assignment.invalid(msg))
             }
 
-            // Skip lines starting with '#' to allow comments.
-            if line.starts_with('#') {
-                continue
+            if assignment.values.len() != 1 && !LIST_KEYS.contains(&key) {
+                let msg = "This key takes a single value, not an array.";
+                return Err(assignment.invalid(msg))
             }
 
-            if let Some(n) = line.find('=') {
-                let key = line[..n].trim();
-                let value = line[n + 1..].trim();
+            for value in &assignment.values {
+                let value = value.as_str();
                 match key {
                     "listen" => listen = Some(String::from(value)),
+                    "mpd_listen" => mpd_listen = Some(String::from(value)),
+                    "mpris_bus" => match Bus::from_str(value) {
+                        Ok(bus) => mpris_bus = Some(bus),
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "dlna_name" => dlna_name = Some(String::from(value)),
                     "library_path" => library_path = Some(PathBuf::from(value)),
                     "db_path" => db_path = Some(PathBuf::from(value)),
                     "audio_device" => audio_device = Some(String::from(value)),
                     "audio_volume_control" => audio_volume_control = Some(String::from(value)),
-                    "high_pass_cutoff" => match Hertz::from_str(value) {
+                    "snapcast_fifo" => snapcast_fifo = Some(PathBuf::from(value)),
+                    "snapcast_sample_rate" => match parse_hertz(value) {
+                        Ok(hz) if hz.0 >= 8_000 => snapcast_sample_rate = Some(hz),
+                        Ok(_) => {
+                            let msg = "Invalid snapcast_sample_rate value, must be at least 8000 Hz.";
+                            return Err(assignment.invalid(msg));
+                        }
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "snapcast_control" => snapcast_control = Some(String::from(value)),
+                    "high_pass_cutoff" => match parse_hertz(value) {
                         Ok(hz) => high_pass_cutoff = Some(hz),
-                        Err(msg) => return Err(Error::InvalidConfig(lineno, msg)),
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "bit_perfect" => match value {
+                        "true" => bit_perfect = true,
+                        "false" => bit_perfect = false,
+                        _ => {
+                            let msg = "Invalid bit_perfect value, must be 'true' or 'false'.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "digital_volume" => match value {
+                        "true" => digital_volume = true,
+                        "false" => digital_volume = false,
+                        _ => {
+                            let msg = "Invalid digital_volume value, must be 'true' or 'false'.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "sample_rate_policy" => match SampleRatePolicy::from_str(value) {
+                        Ok(policy) => sample_rate_policy = policy,
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "fixed_sample_rate" => match parse_hertz(value) {
+                        Ok(hz) if hz.0 >= 8_000 => fixed_sample_rate = Some(hz),
+                        Ok(_) => {
+                            let msg = "Invalid fixed_sample_rate value, must be at least 8000 Hz.";
+                            return Err(assignment.invalid(msg));
+                        }
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "decode_ahead_seconds" => match u64::from_str(value) {
+                        Ok(seconds) if seconds > 0 => decode_ahead_seconds = seconds,
+                        _ => {
+                            let msg = "Invalid decode_ahead_seconds value, must be a positive integer.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "decode_buffer_mb" => match u64::from_str(value) {
+                        Ok(mb) if mb >= 10 => decode_buffer_mb = mb,
+                        _ => {
+                            let msg = "Invalid decode_buffer_mb value, must be an integer of at least 10.";
+                            return Err(assignment.invalid(msg));
+                        }
                     }
                     "exec_pre_playback_path" => exec_pre_playback_path = Some(PathBuf::from(value)),
                     "exec_post_idle_path" => exec_post_idle_path = Some(PathBuf::from(value)),
+                    "pre_playback_delay_ms" => match u64::from_str(value) {
+                        Ok(ms) if ms <= 60_000 => pre_playback_delay_ms = ms,
+                        _ => {
+                            let msg = "Invalid pre_playback_delay_ms value, must be an integer of at most 60000.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
                     "idle_timeout_seconds" => match u64::from_str(value) {
                         Ok(seconds) => idle_timeout_seconds = seconds,
                         Err(_) => {
                             let msg = "Invalid idle_timeout_seconds value, must be an integer.";
-                            return Err(Error::InvalidConfig(lineno, msg));
+                            return Err(assignment.invalid(msg));
                         }
                     }
-                    _ => {
-                        let msg = "Unknown key. See the configuration docs for supported keys.";
-                        return Err(Error::InvalidConfig(lineno, msg))
+                    "exec_status_change_path" => exec_status_change_path = Some(PathBuf::from(value)),
+                    "gpio_playing" => match u32::from_str(value) {
+                        Ok(line) => gpio_playing = Some(line),
+                        Err(_) => {
+                            let msg = "Invalid gpio_playing value, must be a GPIO number.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "gpio_error" => match u32::from_str(value) {
+                        Ok(line) => gpio_error = Some(line),
+                        Err(_) => {
+                            let msg = "Invalid gpio_error value, must be a GPIO number.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "resume_min_duration_seconds" => match u64::from_str(value) {
+                        Ok(seconds) => resume_min_duration_seconds = seconds,
+                        Err(_) => {
+                            let msg = "Invalid resume_min_duration_seconds value, must be an integer.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "search_max_edits" => match u32::from_str(value) {
+                        Ok(n) => search_max_edits = n,
+                        Err(_) => {
+                            let msg = "Invalid search_max_edits value, must be an integer.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "album_identity" => match AlbumIdentity::from_str(value) {
+                        Ok(identity) => album_identity = identity,
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "lastfm_api_key" => lastfm_api_key = Some(String::from(value)),
+                    "lastfm_api_secret" => lastfm_api_secret = Some(String::from(value)),
+                    "lastfm_session_key" => lastfm_session_key = Some(String::from(value)),
+                    "scrobble_threshold_percent" => match u32::from_str(value) {
+                        Ok(percent) if (1..=100).contains(&percent) => scrobble_threshold_percent = percent,
+                        _ => {
+                            let msg = "Invalid scrobble_threshold_percent value, must be an integer from 1 to 100.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "scrobble_threshold_seconds" => match u32::from_str(value) {
+                        Ok(seconds) => scrobble_threshold_seconds = seconds,
+                        Err(_) => {
+                            let msg = "Invalid scrobble_threshold_seconds value, must be an integer.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    // The name is a path under /usr/share/zoneinfo, so we
+                    // don't allow it to point anywhere else.
+                    "timezone" if value.is_empty() || value.starts_with('/') || value.contains("..") => {
+                        let msg = "Invalid timezone value, must be a time zone name like 'Europe/Amsterdam'.";
+                        return Err(assignment.invalid(msg));
+                    }
+                    "timezone" => timezone = Some(String::from(value)),
+                    "acoustid_api_key" => acoustid_api_key = Some(String::from(value)),
+                    "enrichment" => match EnrichmentSource::from_str(value) {
+                        Ok(source) => enrichment = Some(source),
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "webhook_url" => webhook_urls.push(String::from(value)),
+                    "maintenance_interval_hours" => match u64::from_str(value) {
+                        Ok(hours) if hours > 0 => maintenance_interval_hours = Some(hours),
+                        _ => {
+                            let msg = "Invalid maintenance_interval_hours value, must be a positive integer.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "api_token" => match ApiToken::from_str(value) {
+                        Ok(token) => api_tokens.push(token),
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "library_view" => match ViewRule::from_str(value) {
+                        Ok(rule) => library_views.push(rule),
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "unauthenticated" => match value {
+                        "true" => unauthenticated = true,
+                        "false" => unauthenticated = false,
+                        _ => {
+                            let msg = "Invalid unauthenticated value, must be 'true' or 'false'.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "graphql" => match value {
+                        "true" => graphql = true,
+                        "false" => graphql = false,
+                        _ => {
+                            let msg = "Invalid graphql value, must be 'true' or 'false'.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "base_path" => match proxy::parse_base_path(value) {
+                        Ok(path) => base_path = path,
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "trusted_proxy" => match IpAddr::from_str(value) {
+                        Ok(addr) => trusted_proxies.push(addr),
+                        Err(_) => {
+                            let msg = "Invalid trusted_proxy value, must be an IP address.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "cors_origin" => cors_origins.push(String::from(value)),
+                    "rate_limit_per_minute" => match u32::from_str(value) {
+                        Ok(n) if n > 0 => rate_limit_per_minute = Some(n),
+                        _ => {
+                            let msg = "Invalid rate_limit_per_minute value, must be a positive integer.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "tls_certificate_path" => tls_certificate_path = Some(PathBuf::from(value)),
+                    "tls_private_key_path" => tls_private_key_path = Some(PathBuf::from(value)),
+                    "transcode_profile" => match Profile::from_str(value) {
+                        Ok(profile) => transcode_profiles.push(profile),
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "cast_base_url" => cast_base_url = Some(value.trim_end_matches('/').to_string()),
+                    "cast_profile" => cast_profile = Some(String::from(value)),
+                    "player" => {
+                        let is_valid = !value.is_empty() && value.chars().all(
+                            |c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'
+                        );
+                        if !is_valid {
+                            let msg = "Invalid player name, use lowercase letters, digits, and dashes.";
+                            return Err(assignment.invalid(msg));
+                        }
+                        if value == player::DEFAULT_PLAYER || players.iter().any(|p| p == value) {
+                            let msg = "Invalid player name, every player needs a different name.";
+                            return Err(assignment.invalid(msg));
+                        }
+                        players.push(String::from(value));
+                    }
+                    "party_mode" => match value {
+                        "true" => party_mode = true,
+                        "false" => party_mode = false,
+                        _ => {
+                            let msg = "Invalid party_mode value, must be 'true' or 'false'.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "party_skip_votes" => match u32::from_str(value) {
+                        Ok(n) if n > 0 => party_skip_votes = n,
+                        _ => {
+                            let msg = "Invalid party_skip_votes value, must be a positive integer.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "party_enqueues_per_hour" => match u32::from_str(value) {
+                        Ok(n) if n > 0 => party_enqueues_per_hour = n,
+                        _ => {
+                            let msg = "Invalid party_enqueues_per_hour value, must be a positive integer.";
+                            return Err(assignment.invalid(msg));
+                        }
+                    }
+                    "webinterface_dir" => webinterface_dir = Some(PathBuf::from(value)),
+                    "thumbnail_threads" => match usize::from_str(value) {
+                        Ok(n) if n > 0 => thumbnail_threads = Some(n),
+                        _ => {
+                            let msg = "Invalid thumbnail_threads value, must be a positive integer.";
+                            return Err(assignment.invalid(msg));
+                        }
                     }
+                    "log_level" => match log::Filter::from_str(value) {
+                        Ok(filter) => log_level = filter,
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    "log_format" => match log::Format::from_str(value) {
+                        Ok(format) => log_format = format,
+                        Err(msg) => return Err(assignment.invalid(msg)),
+                    }
+                    _ => unreachable!("Unknown keys are rejected before the match."),
                 }
-            } else {
-                let msg = "Line contains no '='. \
-                    Expected key-value pair like 'audio_device = UCM404HD 192k'.";
-                return Err(Error::InvalidConfig(lineno, msg))
             }
         }
 
+        if tls_certificate_path.is_some() != tls_private_key_path.is_some() {
+            return Err(Error::IncompleteConfig(
+                "TLS needs both 'tls_certificate_path =' and 'tls_private_key_path ='-lines."
+            ));
+        }
+
+        // Last.fm needs an API key, but not the secret and session key, those
+        // are only for scrobbling.
+        if enrichment == Some(EnrichmentSource::LastFm) && lastfm_api_key.is_none() {
+            return Err(Error::IncompleteConfig(
+                "Enrichment from Last.fm needs an API key. Expected 'lastfm_api_key ='-line."
+            ));
+        }
+
+        // The profile can be defined after the cast_profile line, so we can
+        // only check it after reading all lines.
+        if let Some(name) = cast_profile.as_ref() {
+            if !transcode_profiles.iter().any(|p| &p.name == name) {
+                return Err(Error::IncompleteConfig(
+                    "The cast_profile must be the name of a 'transcode_profile ='-line."
+                ));
+            }
+        }
+
+        // A view for a user that no token has would silently do nothing, which
+        // is likely a typo that would leave the library visible.
+        for rule in &library_views {
+            if !api_tokens.iter().any(|t| t.user() == Some(&rule.user[..])) {
+                return Err(Error::IncompleteConfig(
+                    "The library_view user must be the user of an 'api_token ='-line."
+                ));
+            }
+        }
+
+        // Anybody who can reach the server can control it without tokens, so
+        // we only allow that when it is explicitly asked for.
+        if api_tokens.is_empty() && !unauthenticated {
+            return Err(Error::IncompleteConfig(
+                "No API tokens set. Expected 'api_token ='-line, \
+                or 'unauthenticated = true' to allow access without token."
+            ));
+        }
+
+        // Snapcast resamples everything to its own rate, and the high-pass
+        // filter changes the samples, so neither can be bit-perfect.
+        if bit_perfect && snapcast_fifo.is_some() {
+            return Err(Error::IncompleteConfig(
+                "The bit_perfect mode plays on the audio card, it can't be used with 'snapcast_fifo ='."
+            ));
+        }
+        if bit_perfect && high_pass_cutoff.map_or(false, |hz| hz.0 > 0) {
+            return Err(Error::IncompleteConfig(
+                "The bit_perfect mode bypasses the high-pass filter, remove the 'high_pass_cutoff ='-line."
+            ));
+        }
+
+        if bit_perfect && digital_volume {
+            return Err(Error::IncompleteConfig(
+                "The bit_perfect mode leaves the samples unchanged, it can't be used with 'digital_volume = true'."
+            ));
+        }
+        if bit_perfect && sample_rate_policy != SampleRatePolicy::Switch {
+            return Err(Error::IncompleteConfig(
+                "The bit_perfect mode plays every track at its own rate, it requires 'sample_rate_policy = switch'."
+            ));
+        }
+
         let config = Config {
             listen: match listen {
                 Some(b) => b,
                 None => String::from("0.0.0.0:8233"),
             },
+            mpd_listen: mpd_listen,
+            mpris_bus: mpris_bus,
+            dlna_name: dlna_name,
             library_path: match library_path {
                 Some(p) => p,
                 None => return Err(Error::IncompleteConfig(
@@ -134,25 +1148,80 @@ impl Config {
                     "Database path not set. Expected 'db_path ='-line."
                 )),
             },
+            // When we play to Snapcast, we don't need an audio card.
             audio_device: match audio_device {
-                Some(d) => d,
-                None => return Err(Error::IncompleteConfig(
-                    "Audio device not set. Expected 'audio_device ='-line."
+                None if snapcast_fifo.is_none() => return Err(Error::IncompleteConfig(
+                    "Audio device not set. Expected 'audio_device ='-line, \
+                    or 'snapcast_fifo ='-line to play through Snapcast."
                 )),
+                d => d,
             },
             audio_volume_control: match audio_volume_control {
-                Some(d) => d,
-                None => return Err(Error::IncompleteConfig(
+                None if snapcast_fifo.is_none() => return Err(Error::IncompleteConfig(
                     "Audio volume control not set. Expected 'audio_volume_control ='-line."
                 )),
+                d => d,
             },
+            snapcast_fifo: snapcast_fifo,
+            snapcast_sample_rate: match snapcast_sample_rate {
+                Some(hz) => hz,
+                None => Hertz(48_000),
+            },
+            snapcast_control: snapcast_control,
             high_pass_cutoff: match high_pass_cutoff {
                 Some(hz) => hz,
                 None => Hertz(0),
             },
+            bit_perfect: bit_perfect,
+            digital_volume: digital_volume,
+            sample_rate_policy: sample_rate_policy,
+            fixed_sample_rate: match fixed_sample_rate {
+                Some(hz) => hz,
+                None => Hertz(48_000),
+            },
+            decode_ahead_seconds: decode_ahead_seconds,
+            decode_buffer_mb: decode_buffer_mb,
             exec_pre_playback_path: exec_pre_playback_path,
             exec_post_idle_path: exec_post_idle_path,
+            pre_playback_delay_ms: pre_playback_delay_ms,
             idle_timeout_seconds: idle_timeout_seconds,
+            exec_status_change_path: exec_status_change_path,
+            gpio_playing: gpio_playing,
+            gpio_error: gpio_error,
+            resume_min_duration_seconds: resume_min_duration_seconds,
+            search_max_edits: search_max_edits,
+            album_identity: album_identity,
+            lastfm_api_key: lastfm_api_key,
+            lastfm_api_secret: lastfm_api_secret,
+            lastfm_session_key: lastfm_session_key,
+            scrobble_threshold_percent: scrobble_threshold_percent,
+            scrobble_threshold_seconds: scrobble_threshold_seconds,
+            timezone: timezone,
+            acoustid_api_key: acoustid_api_key,
+            enrichment: enrichment,
+            webhook_urls: webhook_urls,
+            maintenance_interval_hours: maintenance_interval_hours,
+            api_tokens: api_tokens,
+            library_views: library_views,
+            unauthenticated: unauthenticated,
+            graphql: graphql,
+            base_path: base_path,
+            trusted_proxies: trusted_proxies,
+            cors_origins: cors_origins,
+            rate_limit_per_minute: rate_limit_per_minute,
+            tls_certificate_path: tls_certificate_path,
+            tls_private_key_path: tls_private_key_path,
+            transcode_profiles: transcode_profiles,
+            cast_base_url: cast_base_url,
+            cast_profile: cast_profile,
+            players: players,
+            party_mode: party_mode,
+            party_skip_votes: party_skip_votes,
+            party_enqueues_per_hour: party_enqueues_per_hour,
+            webinterface_dir: webinterface_dir,
+            thumbnail_threads: thumbnail_threads,
+            log_level: log_level,
+            log_format: log_format,
         };
 
         Ok(config)
@@ -162,7 +1231,18 @@ impl Config {
 #[cfg(test)]
 mod test {
     use std::path::Path;
-    use super::{Config, Hertz};
+    use super::{log, AlbumIdentity, Config, EnrichmentSource, Error, Hertz, ListenThreshold, SampleRatePolicy};
+
+    /// Return the lines of a minimal valid config, tests add the keys they exercise.
+    fn minimal_config_lines() -> Vec<&'static str> {
+        vec![
+            "library_path = /home/user/music",
+            "db_path = /home/user/.local/share/musium/db.sqlite3",
+            "audio_device = UCM404HD 192k",
+            "audio_volume_control = UMC404HD 192k Output",
+            "unauthenticated = true",
+        ]
+    }
 
     #[test]
     pub fn config_can_be_parsed() {
@@ -175,13 +1255,358 @@ mod test {
             "audio_device = UCM404HD 192k",
             "audio_volume_control = UMC404HD 192k Output",
             "high_pass_cutoff = 50 Hz",
+            "api_token = laptop full 0123456789abcdef",
         ];
         let config = Config::parse(&config_lines).unwrap();
         assert_eq!(&config.listen[..], "localhost:8000");
+        assert_eq!(config.mpd_listen, None);
+        assert_eq!(config.mpris_bus, None);
+        assert_eq!(config.dlna_name, None);
         assert_eq!(config.library_path.as_path(), Path::new("/home/user/music"));
         assert_eq!(config.db_path.as_path(), Path::new("/home/user/.local/share/musium/db.sqlite3"));
-        assert_eq!(&config.audio_device[..], "UCM404HD 192k");
-        assert_eq!(&config.audio_volume_control[..], "UMC404HD 192k Output");
+        assert_eq!(config.audio_device.as_deref(), Some("UCM404HD 192k"));
+        assert_eq!(config.audio_volume_control.as_deref(), Some("UMC404HD 192k Output"));
+        assert_eq!(config.snapcast_fifo, None);
+        assert_eq!(config.snapcast_sample_rate, Hertz(48_000));
         assert_eq!(config.high_pass_cutoff, Hertz(50));
+        assert_eq!(config.decode_ahead_seconds, 30);
+        assert_eq!(config.decode_buffer_mb, 105);
+        assert_eq!(config.search_max_edits, 1);
+        assert_eq!(config.album_identity, AlbumIdentity::MusicBrainz);
+        assert_eq!(config.lastfm_credentials(), None);
+        assert_eq!(config.listen_threshold(), ListenThreshold { percent: 50, seconds: 240 });
+        assert_eq!(config.acoustid_api_key, None);
+        assert_eq!(config.enrichment, None);
+        assert!(config.webhook_urls.is_empty());
+        assert_eq!(config.maintenance_interval_hours, None);
+        assert_eq!(config.api_tokens.len(), 1);
+        assert!(config.library_views.is_empty());
+        assert_eq!(config.log_level.to_string(), "info");
+        assert!(!config.unauthenticated);
+        assert_eq!(config.base_path, "");
+        assert!(config.trusted_proxies.is_empty());
+        assert!(config.cors_origins.is_empty());
+        assert_eq!(config.rate_limit_per_minute, None);
+        assert_eq!(config.tls_paths(), None);
+        assert!(config.transcode_profiles.is_empty());
+        assert_eq!(config.cast_base_url, None);
+        assert_eq!(config.cast_profile, None);
+        assert!(!config.party_mode);
+        assert_eq!(config.party_skip_votes, 3);
+        assert_eq!(config.party_enqueues_per_hour, 10);
+        assert_eq!(config.webinterface_dir, None);
+        assert_eq!(config.thumbnail_threads, None);
+    }
+
+    #[test]
+    pub fn config_allows_multiple_webhook_urls() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.extend_from_slice(&[
+            "webhook_url = http://localhost:8123/api/webhook/musium",
+            "webhook_url = http://localhost:9000/hook",
+        ]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(
+            config.webhook_urls,
+            vec!["http://localhost:8123/api/webhook/musium", "http://localhost:9000/hook"],
+        );
+    }
+
+    #[test]
+    pub fn config_parses_reverse_proxy_settings() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.extend_from_slice(&[
+            "base_path = /music/",
+            "trusted_proxy = 127.0.0.1",
+            "trusted_proxy = ::1",
+            "cors_origin = https://music.example.com",
+        ]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.base_path, "/music");
+        assert_eq!(config.trusted_proxies.len(), 2);
+        assert_eq!(config.cors_origins, vec!["https://music.example.com"]);
+    }
+
+    #[test]
+    pub fn config_requires_api_token_unless_unauthenticated() {
+        let mut config_lines = minimal_config_lines();
+        assert!(Config::parse(&config_lines).is_ok());
+        config_lines.retain(|line| *line != "unauthenticated = true");
+        assert!(Config::parse(&config_lines).is_err());
+    }
+
+    #[test]
+    pub fn config_requires_library_view_user_to_have_a_token() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.extend_from_slice(&[
+            "api_token = laptop full 0123456789abcdef",
+            "library_view = kids exclude_explicit",
+            "library_view = kids exclude_genre Heavy Metal",
+        ]);
+        assert!(Config::parse(&config_lines).is_err());
+        config_lines.push("api_token = tablet read fedcba9876543210 kids");
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.library_views.len(), 2);
+        assert!(config.library_views.iter().all(|r| r.user == "kids"));
+    }
+
+    #[test]
+    pub fn config_parses_log_settings() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.extend_from_slice(&[
+            "log_level = warn,scan=debug",
+            "log_format = json",
+        ]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.log_level.level("scan"), log::Level::Debug);
+        assert_eq!(config.log_level.level("player"), log::Level::Warn);
+        assert_eq!(config.log_format, log::Format::Json);
+        config_lines.push("log_level = verbose");
+        assert!(Config::parse(&config_lines).is_err());
+    }
+
+    #[test]
+    pub fn config_allows_multiple_transcode_profiles() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.extend_from_slice(&[
+            "transcode_profile = mobile opus 96",
+            "transcode_profile = car mp3 192",
+        ]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.transcode_profiles.len(), 2);
+        assert_eq!(config.get_transcode_profile("car").unwrap().format.bitrate_kbps, 192);
+        assert!(config.get_transcode_profile("desktop").is_none());
+    }
+
+    #[test]
+    pub fn config_requires_cast_profile_to_exist() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.push("cast_profile = speaker");
+        assert!(Config::parse(&config_lines).is_err());
+        config_lines.push("transcode_profile = speaker mp3 256");
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.cast_profile.as_deref(), Some("speaker"));
+    }
+
+    #[test]
+    pub fn config_requires_unique_player_names() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.extend_from_slice(&[
+            "player = office",
+            "player = kitchen",
+        ]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.players, ["office", "kitchen"]);
+        config_lines.push("player = office");
+        assert!(Config::parse(&config_lines).is_err());
+        config_lines.pop();
+        config_lines.push("player = default");
+        assert!(Config::parse(&config_lines).is_err());
+        config_lines.pop();
+        config_lines.push("player = Living Room");
+        assert!(Config::parse(&config_lines).is_err());
+    }
+
+    #[test]
+    pub fn config_parses_party_mode() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.extend_from_slice(&[
+            "party_mode = true",
+            "party_skip_votes = 2",
+            "party_enqueues_per_hour = 6",
+        ]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert!(config.party_mode);
+        assert_eq!(config.party_skip_votes, 2);
+        assert_eq!(config.party_enqueues_per_hour, 6);
+        config_lines.push("party_skip_votes = 0");
+        assert!(Config::parse(&config_lines).is_err());
+    }
+
+    #[test]
+    pub fn config_requires_lastfm_api_key_for_enrichment() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.push("enrichment = listenbrainz");
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.enrichment, Some(EnrichmentSource::ListenBrainz));
+
+        config_lines.push("enrichment = lastfm");
+        assert!(Config::parse(&config_lines).is_err());
+        config_lines.push("lastfm_api_key = 0123456789abcdef");
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.enrichment, Some(EnrichmentSource::LastFm));
+
+        config_lines.push("enrichment = musicbrainz");
+        assert!(Config::parse(&config_lines).is_err());
+    }
+
+    #[test]
+    pub fn config_parses_scrobble_threshold() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.extend_from_slice(&[
+            "scrobble_threshold_percent = 80",
+            "scrobble_threshold_seconds = 30",
+        ]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.listen_threshold(), ListenThreshold { percent: 80, seconds: 30 });
+
+        config_lines.push("scrobble_threshold_percent = 100");
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.listen_threshold(), ListenThreshold { percent: 100, seconds: 30 });
+
+        config_lines.pop();
+        config_lines.push("scrobble_threshold_percent = 0");
+        assert!(Config::parse(&config_lines).is_err());
+
+        config_lines.pop();
+        config_lines.push("scrobble_threshold_percent = 101");
+        assert!(Config::parse(&config_lines).is_err());
+    }
+
+    #[test]
+    pub fn config_parses_timezone() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.push("timezone = Europe/Amsterdam");
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.timezone.as_deref(), Some("Europe/Amsterdam"));
+
+        config_lines.push("timezone = ../../etc/passwd");
+        assert!(Config::parse(&config_lines).is_err());
+    }
+
+    #[test]
+    pub fn config_parses_bit_perfect() {
+        let mut config_lines = minimal_config_lines();
+        assert!(!Config::parse(&config_lines).unwrap().bit_perfect);
+
+        config_lines.push("bit_perfect = true");
+        assert!(Config::parse(&config_lines).unwrap().bit_perfect);
+
+        let mut digital_lines = config_lines.clone();
+        digital_lines.push("digital_volume = true");
+        assert!(Config::parse(&digital_lines).is_err());
+
+        config_lines.push("high_pass_cutoff = 30 Hz");
+        assert!(Config::parse(&config_lines).is_err());
+    }
+
+    #[test]
+    pub fn config_parses_sample_rate_policy() {
+        let mut config_lines = minimal_config_lines();
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.sample_rate_policy, SampleRatePolicy::Switch);
+
+        config_lines.push("sample_rate_policy = fixed");
+        config_lines.push("fixed_sample_rate = 96000 Hz");
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.sample_rate_policy, SampleRatePolicy::Fixed);
+        assert_eq!(config.fixed_sample_rate, Hertz(96_000));
+
+        config_lines.push("bit_perfect = true");
+        assert!(Config::parse(&config_lines).is_err());
+    }
+
+    #[test]
+    pub fn config_does_not_require_audio_device_with_snapcast() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.retain(|line| !line.starts_with("audio_"));
+        assert!(Config::parse(&config_lines).is_err());
+        config_lines.push("snapcast_fifo = /tmp/snapfifo");
+        config_lines.push("snapcast_sample_rate = 44100 Hz");
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.audio_device, None);
+        assert_eq!(config.snapcast_fifo.as_deref(), Some(Path::new("/tmp/snapfifo")));
+        assert_eq!(config.snapcast_sample_rate, Hertz(44_100));
+    }
+
+    #[test]
+    pub fn config_parses_decode_buffer_settings() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.extend_from_slice(&[
+            "decode_ahead_seconds = 120",
+            "decode_buffer_mb = 400",
+        ]);
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.decode_ahead_seconds, 120);
+        assert_eq!(config.decode_buffer_mb, 400);
+        config_lines.push("decode_buffer_mb = 1");
+        assert!(Config::parse(&config_lines).is_err());
+    }
+
+    #[test]
+    pub fn config_accepts_toml_syntax() {
+        let config_lines = [
+            "library_path = \"/home/user/music\"  # Scanned recursively.",
+            "db_path = '/home/user/.local/share/musium/db.sqlite3'",
+            "audio_device = \"UCM404HD 192k\"",
+            "audio_volume_control = \"UMC404HD 192k Output\"",
+            "high_pass_cutoff = 30",
+            "idle_timeout_seconds = 60 # One minute.",
+            "graphql = true",
+            "api_token = [\"laptop full 0123456789abcdef\"]",
+            "webhook_url = [",
+            "    \"http://localhost:8123/api/webhook/musium\",",
+            "    # Comments are allowed inside arrays.",
+            "    'http://localhost:9000/hook#fragment',",
+            "]",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.library_path.as_path(), Path::new("/home/user/music"));
+        assert_eq!(config.audio_device.as_deref(), Some("UCM404HD 192k"));
+        assert_eq!(config.high_pass_cutoff, Hertz(30));
+        assert_eq!(config.idle_timeout_seconds, 60);
+        assert!(config.graphql);
+        assert_eq!(config.api_tokens.len(), 1);
+        assert_eq!(
+            config.webhook_urls,
+            vec!["http://localhost:8123/api/webhook/musium", "http://localhost:9000/hook#fragment"],
+        );
+    }
+
+    #[test]
+    pub fn config_reports_line_of_invalid_value() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.push("idle_timeout_seconds = \"soon\"");
+        match Config::parse(&config_lines) {
+            Err(Error::InvalidConfig(6, _)) => {}
+            other => panic!("Expected error on line 6, got {:?}", other),
+        }
+        let config_lines = ["library_path = [\"/music\", \"/more-music\"]"];
+        match Config::parse(&config_lines) {
+            Err(Error::InvalidConfig(1, _)) => {}
+            other => panic!("Expected error on line 1, got {:?}", other),
+        }
+        let config_lines = ["webhook_url = [", "\"http://localhost:9000/hook\","];
+        match Config::parse(&config_lines) {
+            Err(Error::InvalidConfig(1, _)) => {}
+            other => panic!("Expected error on line 1, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn config_can_be_overridden_from_env() {
+        let mut config_lines = minimal_config_lines();
+        config_lines.extend_from_slice(&[
+            "api_token = laptop full 0123456789abcdef",
+            "api_token = kitchen queue fedcba9876543210",
+        ]);
+        let get_env = |var: &str| match var {
+            "MUSIUM_LISTEN" => Some("localhost:8000".to_string()),
+            "MUSIUM_API_TOKEN" => Some("[\"tablet read 00112233445566778899\"]".to_string()),
+            _ => None,
+        };
+        let config = Config::parse_with_env(&config_lines, get_env).unwrap();
+        assert_eq!(&config.listen[..], "localhost:8000");
+        assert_eq!(config.api_tokens.len(), 1);
+        assert_eq!(config.api_tokens[0].name, "tablet");
+
+        let get_env = |var: &str| match var {
+            "MUSIUM_SEARCH_MAX_EDITS" => Some("many".to_string()),
+            _ => None,
+        };
+        match Config::parse_with_env(&config_lines, get_env) {
+            Err(Error::InvalidConfigEnv(var, _)) => assert_eq!(var, "MUSIUM_SEARCH_MAX_EDITS"),
+            other => panic!("Expected error in environment variable, got {:?}", other),
+        }
     }
 }
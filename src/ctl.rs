@@ -0,0 +1,175 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! One-shot commands to control a running server, for `musium ctl`.
+//!
+//! These are meant for keybindings and scripts. Like `musium tui`, they talk
+//! to the server through the API, see `client.rs`.
+
+use serde_json::Value;
+
+use crate::client::{encode_query_value, format_duration, Client};
+use crate::error::{Error, Result};
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum CtlCommand {
+    Play,
+    Pause,
+    Next,
+    Prev,
+    Status,
+    Queue { query: String },
+}
+
+impl CtlCommand {
+    /// Parse the arguments after `ctl`.
+    pub fn parse(args: &[&str]) -> Option<CtlCommand> {
+        let cmd = match args {
+            ["play"] => CtlCommand::Play,
+            ["pause"] => CtlCommand::Pause,
+            ["next"] => CtlCommand::Next,
+            ["prev"] => CtlCommand::Prev,
+            ["status"] => CtlCommand::Status,
+            ["queue", query @ ..] if query.len() > 0 => CtlCommand::Queue { query: query.join(" ") },
+            _ => return None,
+        };
+        Some(cmd)
+    }
+}
+
+fn get_str<'a>(v: &'a Value, key: &str) -> &'a str {
+    v.get(key).and_then(|x| x.as_str()).unwrap_or("")
+}
+
+/// Describe the current track of a `/api/player` response.
+fn format_status(player: &Value) -> String {
+    let current = match player.get("current") {
+        Some(current) if !current.is_null() => current,
+        _ => return "stopped, the queue is empty".to_string(),
+    };
+    let state = get_str(player, "state");
+    let queue_length = player.get("queue_length").and_then(|n| n.as_u64()).unwrap_or(0);
+    let position = current.get("position_seconds").and_then(|x| x.as_f64()).unwrap_or(0.0);
+
+    let now_playing = match current.get("station") {
+        Some(station) => format!(
+            "{}: {} ({})",
+            state,
+            get_str(current, "stream_title"),
+            station.as_str().unwrap_or(""),
+        ),
+        None => format!(
+            "{}: {} — {} ({})\n{} / {}",
+            state,
+            get_str(current, "artist"),
+            get_str(current, "title"),
+            get_str(current, "album"),
+            format_duration(position as u64),
+            format_duration(current.get("duration_seconds").and_then(|x| x.as_u64()).unwrap_or(0)),
+        ),
+    };
+
+    match queue_length {
+        1 => format!("{}\n1 track in the queue", now_playing),
+        n => format!("{}\n{} tracks in the queue", now_playing, n),
+    }
+}
+
+/// Enqueue the best match for `query`: the first track, or else the first album.
+fn enqueue_query(client: &Client, query: &str) -> Result<()> {
+    let results = client.get(&format!("search?q={}", encode_query_value(query)))?;
+    let empty = Vec::new();
+    let tracks = results.get("tracks").and_then(|x| x.as_array()).unwrap_or(&empty);
+    let albums = results.get("albums").and_then(|x| x.as_array()).unwrap_or(&empty);
+
+    if let Some(track) = tracks.first() {
+        client.put(&format!("queue/{}?client=ctl", get_str(track, "id")))?;
+        println!("Enqueued {} by {}.", get_str(track, "title"), get_str(track, "artist"));
+        return Ok(());
+    }
+
+    if let Some(album) = albums.first() {
+        let album_id = get_str(album, "id");
+        let album_json = client.get(&format!("album/{}", album_id))?;
+        let album_tracks = album_json.get("tracks").and_then(|x| x.as_array()).unwrap_or(&empty);
+        for track in album_tracks {
+            client.put(&format!("queue/{}?client=ctl", get_str(track, "id")))?;
+        }
+        println!("Enqueued {} by {}.", get_str(album, "title"), get_str(album, "artist"));
+        return Ok(());
+    }
+
+    Err(Error::ClientError(format!("Nothing in the library matches '{}'.", query)))
+}
+
+pub fn main(client: &Client, cmd: CtlCommand) -> Result<()> {
+    match cmd {
+        CtlCommand::Status => {
+            let player = client.get("player")?;
+            println!("{}", format_status(&player));
+        }
+        // Both return the player, so we can print the status right away.
+        CtlCommand::Play => {
+            let player = client.post("player/play")?;
+            println!("{}", format_status(&player));
+        }
+        CtlCommand::Pause => {
+            let player = client.post("player/pause")?;
+            println!("{}", format_status(&player));
+        }
+        CtlCommand::Next => {
+            client.post("queue/skip")?;
+            let player = client.get("player")?;
+            println!("{}", format_status(&player));
+        }
+        CtlCommand::Prev => {
+            client.post("queue/previous")?;
+            let player = client.get("player")?;
+            println!("{}", format_status(&player));
+        }
+        CtlCommand::Queue { query } => enqueue_query(client, &query)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_status, CtlCommand};
+
+    #[test]
+    fn parse_joins_query_words() {
+        assert_eq!(CtlCommand::parse(&["next"]), Some(CtlCommand::Next));
+        assert_eq!(CtlCommand::parse(&["pause"]), Some(CtlCommand::Pause));
+        assert_eq!(
+            CtlCommand::parse(&["queue", "sigur", "ros"]),
+            Some(CtlCommand::Queue { query: "sigur ros".to_string() }),
+        );
+        assert_eq!(CtlCommand::parse(&["queue"]), None);
+        assert_eq!(CtlCommand::parse(&["next", "now"]), None);
+    }
+
+    #[test]
+    fn format_status_describes_current_track() {
+        let player = serde_json::json!({
+            "state": "playing",
+            "queue_length": 2,
+            "current": {
+                "title": "Hoppípolla",
+                "artist": "Sigur Rós",
+                "album": "Takk…",
+                "duration_seconds": 268,
+                "position_seconds": 61.5,
+            },
+        });
+        assert_eq!(
+            format_status(&player),
+            "playing: Sigur Rós — Hoppípolla (Takk…)\n1:01 / 4:28\n2 tracks in the queue",
+        );
+        let stopped = serde_json::json!({ "state": "stopped", "queue_length": 0, "current": null });
+        assert_eq!(format_status(&stopped), "stopped, the queue is empty");
+    }
+}
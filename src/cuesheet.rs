@@ -0,0 +1,172 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Reading the pre-gap of a flac file from its cuesheet block.
+//!
+//! On a CD, a track starts at INDEX 01, and the region between INDEX 00 and
+//! INDEX 01 is its pre-gap. Usually that is a few seconds of silence, but it can
+//! be the tail of a live recording, or in the pre-gap of the first track, an
+//! entire hidden track. Rippers that keep the pre-gap with the track, and record
+//! the index points in the cuesheet block, let us find it, see
+//! <https://xiph.org/flac/format.html#metadata_block_cuesheet>.
+//!
+//! We play files from the start, so the pre-gap always plays, also in gapless
+//! transitions. We only report how long it is, so clients can show where the
+//! track proper starts. Claxon skips the cuesheet block, so we parse it here.
+
+use std::fs;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+const BLOCK_CUESHEET: u8 = 5;
+
+/// Length of the media catalog number, lead-in, and reserved fields, and the track count.
+const CUESHEET_HEADER_LEN: usize = 128 + 8 + 259 + 1;
+
+/// Length of a track entry, excluding its index points.
+const TRACK_HEADER_LEN: usize = 8 + 1 + 12 + 14 + 1;
+
+/// Length of an index point.
+const INDEX_LEN: usize = 8 + 1 + 3;
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u64(data: &[u8], at: usize) -> u64 {
+    let mut bytes = [0_u8; 8];
+    bytes.copy_from_slice(&data[at..at + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Return the number of samples before INDEX 01 of the first track.
+///
+/// For a file with one track, this is the pre-gap of that track. Returns
+/// `None` when the block is malformed.
+fn parse_pregap_samples(data: &[u8]) -> Option<u64> {
+    if data.len() < CUESHEET_HEADER_LEN {
+        return None;
+    }
+    let num_tracks = data[CUESHEET_HEADER_LEN - 1];
+    let mut at = CUESHEET_HEADER_LEN;
+
+    for _ in 0..num_tracks {
+        if data.len() < at + TRACK_HEADER_LEN {
+            return None;
+        }
+        let track_offset = read_u64(data, at);
+        let track_number = data[at + 8];
+        let num_indices = data[at + TRACK_HEADER_LEN - 1] as usize;
+        let indices_at = at + TRACK_HEADER_LEN;
+        at = indices_at + num_indices * INDEX_LEN;
+        if data.len() < at {
+            return None;
+        }
+
+        // The lead-out track has number 170 on a CD, or 255 otherwise, and it
+        // has no index points. Tracks are in order, so the first one with an
+        // INDEX 01 is the one we are looking for.
+        if track_number == 170 || track_number == 255 {
+            continue;
+        }
+        for i in 0..num_indices {
+            let index_at = indices_at + i * INDEX_LEN;
+            if data[index_at + 8] == 1 {
+                // Index offsets are relative to the start of the track.
+                return Some(track_offset + read_u64(data, index_at));
+            }
+        }
+    }
+
+    Some(0)
+}
+
+/// Read the pre-gap of the flac file in samples, 0 if it has no cuesheet.
+pub fn read_pregap_samples(path: &Path) -> io::Result<u64> {
+    let mut r = BufReader::new(fs::File::open(path)?);
+
+    let mut magic = [0_u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != b"fLaC" {
+        return Err(invalid("Not a flac file."));
+    }
+
+    loop {
+        let mut header = [0_u8; 4];
+        r.read_exact(&mut header)?;
+        let is_last = header[0] & 0x80 != 0;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+
+        if header[0] & 0x7f == BLOCK_CUESHEET {
+            let mut data = vec![0_u8; len as usize];
+            r.read_exact(&mut data)?;
+            return parse_pregap_samples(&data).ok_or_else(|| invalid("Invalid cuesheet block."));
+        }
+
+        if is_last {
+            return Ok(0);
+        }
+        r.seek_relative(len as i64)?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_pregap_samples, CUESHEET_HEADER_LEN};
+
+    /// Build a cuesheet block with the given tracks of `(offset, number, [(index offset, index number)])`.
+    fn cuesheet(tracks: &[(u64, u8, &[(u64, u8)])]) -> Vec<u8> {
+        let mut data = vec![0_u8; CUESHEET_HEADER_LEN];
+        data[CUESHEET_HEADER_LEN - 1] = tracks.len() as u8;
+        for &(offset, number, indices) in tracks {
+            data.extend_from_slice(&offset.to_be_bytes());
+            data.push(number);
+            data.extend_from_slice(&[0_u8; 12 + 14]);
+            data.push(indices.len() as u8);
+            for &(index_offset, index_number) in indices {
+                data.extend_from_slice(&index_offset.to_be_bytes());
+                data.push(index_number);
+                data.extend_from_slice(&[0_u8; 3]);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn parse_pregap_samples_returns_start_of_index_1() {
+        let data = cuesheet(&[
+            (0, 1, &[(0, 0), (88_200, 1)]),
+            (1_000_000, 170, &[]),
+        ]);
+        assert_eq!(parse_pregap_samples(&data), Some(88_200));
+    }
+
+    #[test]
+    fn parse_pregap_samples_includes_hidden_track_before_first_track() {
+        // A hidden track before track 1 in an album image: track 1 starts
+        // after the hidden audio, at INDEX 01 with offset 0.
+        let data = cuesheet(&[
+            (4_410_000, 1, &[(0, 1)]),
+            (9_000_000, 2, &[(0, 1)]),
+            (20_000_000, 170, &[]),
+        ]);
+        assert_eq!(parse_pregap_samples(&data), Some(4_410_000));
+    }
+
+    #[test]
+    fn parse_pregap_samples_returns_zero_without_pregap() {
+        let data = cuesheet(&[(0, 1, &[(0, 1)]), (1_000_000, 255, &[])]);
+        assert_eq!(parse_pregap_samples(&data), Some(0));
+    }
+
+    #[test]
+    fn parse_pregap_samples_rejects_truncated_block() {
+        let mut data = cuesheet(&[(0, 1, &[(0, 0), (88_200, 1)])]);
+        data.truncate(data.len() - 4);
+        assert_eq!(parse_pregap_samples(&data), None);
+    }
+}
@@ -108,7 +108,7 @@ pub fn ensure_schema_exists(tx: &mut Transaction) -> Result<()> {
         , disc_number      integer null
         
         -- Source of the listen. Should be either 'musium' if we produced the
-        -- listen, or 'listenbrainz' if we backfilled it from Listenbrainz.
+        -- listen, or 'listenbrainz' or 'lastfm' if we backfilled it from an export.
         , source           string  not null
         
         -- ISO-8601 time with UTC offset at which we scrobbled the track to Last.fm.
@@ -148,6 +148,118 @@ pub fn ensure_schema_exists(tx: &mut Transaction) -> Result<()> {
         Done => {}
     }
 
+    let sql = r#"
+        -- The statistics aggregate listens per album artist, album, or track. Filtering
+        -- on the time range uses the index above, these indexes serve the per-album
+        -- and per-artist listings, and the play counts.
+        create index if not exists ix_listens_album_artist_id on listens (album_artist_id);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create index if not exists ix_listens_album_id on listens (album_id);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create index if not exists ix_listens_track_id on listens (track_id);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- The client that enqueued the track of a listen, for listens that we produced.
+        -- This is a name chosen by the client, such as 'kitchen' or 'kids-tablet'.
+        -- Listens of tracks enqueued without a client name have no row here.
+        create table if not exists listen_clients
+        ( listen_id integer primary key references listens (id)
+        , client    string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create index if not exists ix_listen_clients_client on listen_clients (client);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- Tracks that the user skipped before they completed. The listen for the track
+        -- is in the listens table, without completion time.
+        create table if not exists skips
+        ( id               integer primary key
+        -- ISO-8601 time with UTC offset at which we skipped the track.
+        , skipped_at       string  not null
+        -- The listen that we skipped. NULL if the history thread missed the start.
+        , listen_id        integer null     references listens (id)
+        , queue_id         integer not null
+        , track_id         integer not null
+        -- Playback position in the track at the time of the skip.
+        , position_seconds integer not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create index if not exists ix_skips_track_id on skips (track_id);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
     let sql = r#"
         create table if not exists ratings
         ( id          integer primary key
@@ -189,6 +301,47 @@ pub fn ensure_schema_exists(tx: &mut Transaction) -> Result<()> {
         Done => {}
     }
 
+    let sql = r#"
+        -- Ratings for albums, with the same structure and rating levels as the track
+        -- ratings above. Like there, we don't enforce a foreign key relation.
+        create table if not exists album_ratings
+        ( id          integer primary key
+        , created_at  string  not null unique
+        , album_id    integer not null
+        , rating      integer not null check ((rating >= -1) and (rating <= 2))
+        , source      string not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- Ratings for album artists, with the same structure as the album ratings.
+        create table if not exists artist_ratings
+        ( id          integer primary key
+        , created_at  string  not null unique
+        , artist_id   integer not null
+        , rating      integer not null check ((rating >= -1) and (rating <= 2))
+        , source      string not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
     let sql = r#"
         create table if not exists files
         -- First an id, and properties about the file, but not its contents.
@@ -219,6 +372,8 @@ pub fn ensure_schema_exists(tx: &mut Transaction) -> Result<()> {
     }
 
     let sql = r#"
+        -- Schema version 2 adds more columns to this table, see add_file_identity below.
+        
         create table if not exists tags
         ( id         integer primary key
         , file_id    integer not null references files (id) on delete cascade
@@ -321,434 +476,5251 @@ pub fn ensure_schema_exists(tx: &mut Transaction) -> Result<()> {
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    let result = match statement.next()? {
+    match statement.next()? {
         Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
-        Done => (),
-    };
-    Ok(result)
-}
+        Done => {}
+    }
 
-#[derive(Debug)]
-pub struct InsertFile<'a> {
-    pub filename: &'a str,
-    pub mtime: i64,
-    pub imported_at: &'a str,
-    pub streaminfo_channels: i64,
-    pub streaminfo_bits_per_sample: i64,
-    pub streaminfo_num_samples: Option<i64>,
-    pub streaminfo_sample_rate: i64,
-}
+    let sql = r#"
+        -- The time at which an album first entered the library. There is deliberately
+        -- no reference to the files table, such that the import time survives rescans
+        -- of the files in the album, which delete and re-insert the file rows.
+        create table if not exists album_imports
+        ( album_id    integer primary key
+        -- ISO-8601 timestamp at which the album was first imported.
+        , imported_at string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
 
-pub fn insert_file(tx: &mut Transaction, metadata: InsertFile) -> Result<i64> {
     let sql = r#"
-        insert into files
-        ( filename
-        , mtime
-        , imported_at
-        , streaminfo_channels
-        , streaminfo_bits_per_sample
-        , streaminfo_num_samples
-        , streaminfo_sample_rate
-        )
-        values
-        ( :filename
-        , :mtime
-        , :imported_at
-        , :streaminfo_channels
-        , :streaminfo_bits_per_sample
-        , :streaminfo_num_samples
-        , :streaminfo_sample_rate
-        )
-        returning id;
+        -- Named playlists, created by the user.
+        create table if not exists playlists
+        ( id          integer primary key
+        , name        string  not null
+        -- ISO-8601 timestamp at which the playlist was created.
+        , created_at  string  not null
+        -- For smart playlists, the query that selects the tracks, see smart_playlist.rs.
+        -- NULL for static playlists, which have their tracks in playlist_entries.
+        , query       string  null
+        );
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, metadata.filename)?;
-    statement.bind(2, metadata.mtime)?;
-    statement.bind(3, metadata.imported_at)?;
-    statement.bind(4, metadata.streaminfo_channels)?;
-    statement.bind(5, metadata.streaminfo_bits_per_sample)?;
-    statement.bind(6, metadata.streaminfo_num_samples)?;
-    statement.bind(7, metadata.streaminfo_sample_rate)?;
-    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
-    let result = match statement.next()? {
-        Row => decode_row(statement)?,
-        Done => panic!("Query 'insert_file' should return exactly one row."),
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- The tracks in a playlist. A track can occur in a playlist multiple times, so
+        -- entries have their own id. Like for ratings, the track id is not a foreign
+        -- key, such that the entry survives a re-import of the track.
+        create table if not exists playlist_entries
+        ( id          integer primary key
+        , playlist_id integer not null references playlists (id) on delete cascade
+        -- 0-based position of the entry in the playlist.
+        , position    integer not null
+        , track_id    integer not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
-    if statement.next()? != Done {
-        panic!("Query 'insert_file' should return exactly one row.");
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
+        Done => {}
     }
-    Ok(result)
-}
 
-pub fn insert_tag(tx: &mut Transaction, file_id: i64, field_name: &str, value: &str) -> Result<()> {
     let sql = r#"
-        insert into
-          tags (file_id, field_name, value)
-          values (:file_id, :field_name, :value);
+        create index if not exists ix_playlist_entries_playlist_id
+        on playlist_entries (playlist_id, position);
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, file_id)?;
-    statement.bind(2, field_name)?;
-    statement.bind(3, value)?;
     let result = match statement.next()? {
-        Row => panic!("Query 'insert_tag' unexpectedly returned a row."),
+        Row => panic!("Query 'ensure_schema_exists' unexpectedly returned a row."),
         Done => (),
     };
     Ok(result)
 }
 
-/// Delete a file and everything referencing it (cascade to tags, waveforms, etc.)
-///
-/// Note that album loudness is not deleted, it is not based on any single file.
-pub fn delete_file(tx: &mut Transaction, file_id: i64) -> Result<()> {
+/// The schema version, see database_utils::migrate. It is 0 for a new database,
+/// and for databases created before we tracked the version.
+pub fn select_schema_version(tx: &mut Transaction) -> Result<i64> {
     let sql = r#"
-        delete from files where id = :file_id;
+        select user_version from pragma_user_version;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, file_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
     let result = match statement.next()? {
-        Row => panic!("Query 'delete_file' unexpectedly returned a row."),
-        Done => (),
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_schema_version' should return exactly one row."),
     };
+    if statement.next()? != Done {
+        panic!("Query 'select_schema_version' should return exactly one row.");
+    }
     Ok(result)
 }
 
-#[derive(Debug)]
-pub struct FileMetadataSimple {
-    pub id: i64,
-    pub filename: String,
-    pub mtime: i64,
-}
-
-pub fn iter_file_mtime<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, FileMetadataSimple>> {
+/// Schema version 2: columns that identify a file when it moves, so that a scan
+/// can update its path, instead of deleting it and scanning it as a new file.
+/// They are NULL for files scanned before version 2, until the next scan.
+pub fn add_file_identity(tx: &mut Transaction) -> Result<()> {
     let sql = r#"
-        select
-            id
-          , filename
-          , mtime
-        from
-          files
-        order by
-          filename asc;
+        -- Size of the file in bytes, and its inode number, as returned by 'stat'.
+        alter table files add column size_bytes integer null;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    let decode_row = |statement: &Statement| Ok(FileMetadataSimple {
-        id: statement.read(0)?,
-        filename: statement.read(1)?,
-        mtime: statement.read(2)?,
-    });
-    let result = Iter { statement, decode_row };
-    Ok(result)
-}
+    match statement.next()? {
+        Row => panic!("Query 'add_file_identity' unexpectedly returned a row."),
+        Done => {}
+    }
 
-#[derive(Debug)]
+    let sql = r#"
+        alter table files add column inode integer null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_file_identity' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- The MD5 of the unencoded audio, from the streaminfo block, in lowercase hex.
+        -- NULL if the encoder did not compute it.
+        alter table files add column streaminfo_md5 string null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_file_identity' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Schema version 3: users, see auth.rs. Listens, ratings, and playlists of the
+/// default user have no user, that includes everything from before version 3.
+pub fn add_users(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        -- The user whose token enqueued the track of a listen, for listens that we
+        -- produced. Like for listen_clients, listens without a user have no row here.
+        create table if not exists listen_users
+        ( listen_id integer primary key references listens (id)
+        , user_name string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_users' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create index if not exists ix_listen_users_user_name on listen_users (user_name);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_users' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        alter table ratings add column user_name string null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_users' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        alter table album_ratings add column user_name string null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_users' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        alter table artist_ratings add column user_name string null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_users' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        alter table playlists add column user_name string null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_users' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- Two users can rate a track in the same second, so the second only needs to
+        -- be unique per user.
+        drop index if exists ix_ratings_unique_second;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_users' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create unique index if not exists ix_ratings_unique_user_second
+        on ratings (coalesce(user_name, ''), cast(strftime('%s', created_at) as integer));
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_users' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Schema version 4: the play queue, saved when the server shuts down, so it can
+/// resume where it left off when it starts again. Entries are either a track or
+/// a radio station. Only the first entry has a nonzero position.
+pub fn add_saved_queue(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table if not exists saved_queue
+        ( position     integer primary key
+        , track_id     integer null
+        , station_id   integer null
+        , position_ms  integer not null
+        , client       string  null
+        , user_name    string  null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_saved_queue' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn add_metadata_edits(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table if not exists metadata_edits
+        ( id          integer primary key
+        -- ISO-8601 time with UTC offset at which the edit was made.
+        , edited_at   string  not null
+        , file_id     integer not null
+        , track_id    integer not null
+        -- The lowercase tag name, as in the tags table.
+        , field_name  string  not null
+        -- All values of the tag before the edit, separated by "; ", NULL if there were none.
+        , old_value   string  null
+        -- The new value, NULL if the edit removed the tag.
+        , new_value   string  null
+        -- 1 if the edit was also written to the file, 0 if only to the database.
+        , wrote_tags  integer not null
+        , user_name   string  null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_metadata_edits' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn add_artist_aliases(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table if not exists artist_aliases
+        ( artist_id           integer primary key
+        , canonical_artist_id integer not null
+        -- The name of the artist when it was merged, the index no longer has it.
+        , name                string  not null
+        -- ISO-8601 time with UTC offset at which the artists were merged.
+        , created_at          string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_artist_aliases' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn add_acoustid(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table if not exists acoustid_lookups
+        ( file_id      integer primary key
+        -- ISO-8601 time with UTC offset at which we looked up the file.
+        , looked_up_at string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_acoustid' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create table if not exists acoustid_proposals
+        ( file_id                   integer primary key
+        , acoustid                  string  not null
+        -- How well the fingerprint matches, from 0.0 to 1.0, as reported by AcoustID.
+        , score                     real    not null
+        -- The proposed tags, named like the tags in the tags table.
+        , musicbrainz_trackid       string  not null
+        , title                     string  not null
+        , artist                    string  not null
+        , album                     string  not null
+        , musicbrainz_albumid       string  not null
+        , albumartist               string  not null
+        , musicbrainz_albumartistid string  not null
+        , tracknumber               integer not null
+        , discnumber                integer not null
+        , originaldate              string  not null
+        -- One of 'pending', 'accepted', or 'rejected'.
+        , status                    string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_acoustid' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Check the database for corruption. Yields a single "ok" row if all is well,
+/// or one row per problem otherwise.
+/// Schema version 8: where playback of long tracks, such as DJ mixes and
+/// audiobooks, stopped before the end, so it can resume there when the track is
+/// enqueued again. A track has at most one position, its latest one. Like for
+/// ratings, the track is not a foreign key.
+pub fn add_resume_positions(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table if not exists resume_positions
+        ( track_id     integer primary key
+        , position_ms  integer not null
+        -- ISO-8601 time with UTC offset at which playback stopped at this position.
+        , saved_at     string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_resume_positions' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn add_file_pregaps(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table if not exists file_pregaps
+        ( file_id        integer primary key references files (id) on delete cascade
+        -- Number of samples per channel, at the sample rate of the file.
+        , pregap_samples integer not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_file_pregaps' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn add_exclusions(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table if not exists track_exclusions
+        ( track_id   integer not null
+        -- ISO-8601 time with UTC offset at which the track was excluded.
+        , created_at string  not null
+        , user_name  string  null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_exclusions' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create unique index if not exists ix_track_exclusions_unique
+        on track_exclusions (track_id, coalesce(user_name, ''));
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_exclusions' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create table if not exists album_exclusions
+        ( album_id   integer not null
+        -- ISO-8601 time with UTC offset at which the album was excluded.
+        , created_at string  not null
+        , user_name  string  null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_exclusions' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create unique index if not exists ix_album_exclusions_unique
+        on album_exclusions (album_id, coalesce(user_name, ''));
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_exclusions' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Similar artists and tags of album artists, fetched from Last.fm or
+/// ListenBrainz when `enrichment` is configured, see enrichment.rs. This is a
+/// cache: `artist_enrichment` has a row per artist that we fetched, also when
+/// fetching failed, and the other tables hold the results. Deleting the row
+/// deletes the results.
+pub fn add_artist_enrichment(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table if not exists artist_enrichment
+        ( artist_id          integer primary key
+        -- The service that we fetched from, 'lastfm' or 'listenbrainz'.
+        , source             string  not null
+        -- Seconds since the epoch after which we fetch again.
+        , expires_at_seconds integer not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_artist_enrichment' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create table if not exists artist_enrichment_similar
+        ( artist_id integer not null references artist_enrichment (artist_id) on delete cascade
+        -- Position in the list of the service, most similar first.
+        , rank      integer not null
+        , name      string  not null
+        -- The MusicBrainz id of the similar artist, if the service reports it.
+        , mbid      string  null
+        , score     real    not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_artist_enrichment' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create index if not exists ix_artist_enrichment_similar_artist_id
+        on artist_enrichment_similar (artist_id);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_artist_enrichment' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create table if not exists artist_enrichment_tags
+        ( artist_id integer not null references artist_enrichment (artist_id) on delete cascade
+        , rank      integer not null
+        , tag       string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_artist_enrichment' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create index if not exists ix_artist_enrichment_tags_artist_id
+        on artist_enrichment_tags (artist_id);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_artist_enrichment' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Listening sessions. A listen that starts within half an hour after the
+/// previous listen of the same user ended, is part of the same session, see
+/// sessions.rs. Listens that are not yet assigned to a session have null.
+pub fn add_listen_sessions(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        alter table listens add column session_id integer null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_listen_sessions' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create index if not exists ix_listens_session_id on listens (session_id);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_listen_sessions' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// The first listen of every track, album, and album artist, per user. We
+/// could find these in the listens, but that takes a scan of the full history,
+/// and we need them for the discovery statistics, see listens.rs.
+pub fn add_first_listens(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table if not exists first_listens
+        ( kind       string  not null  -- One of 'track', 'album', or 'artist'.
+        , item_id    integer not null
+        , user_name  string  null
+        , listen_id  integer not null references listens (id) on delete cascade
+        , started_at string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_first_listens' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create unique index if not exists ix_first_listens_item
+        on first_listens (kind, item_id, coalesce(user_name, ''));
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_first_listens' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create index if not exists ix_first_listens_started_at
+        on first_listens (cast(strftime('%s', started_at) as integer));
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_first_listens' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        insert into first_listens (kind, item_id, user_name, listen_id, started_at)
+        select kind, item_id, user_name, id, min(started_at)
+        from (
+          select 'track' as kind, track_id as item_id, user_name, id, started_at
+          from listens left join listen_users on listen_users.listen_id = listens.id
+          union all
+          select 'album', album_id, user_name, id, started_at
+          from listens left join listen_users on listen_users.listen_id = listens.id
+          union all
+          select 'artist', album_artist_id, user_name, id, started_at
+          from listens left join listen_users on listen_users.listen_id = listens.id
+        )
+        group by kind, item_id, user_name;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_first_listens' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// How much of the track played, from 0.0 to 1.0, for listens that ended, see
+/// `ListenThreshold` in listens.rs. Listens from before we recorded this, and
+/// imported listens, have null.
+pub fn add_listen_played_fraction(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        alter table listens add column played_fraction real null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_listen_played_fraction' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// The offset from UTC of the reporting time zone at the time of the listen, so
+/// day and hour statistics are in the time of day where the listen happened.
+/// Listens from before we recorded this have null, for those we use the local
+/// time of the server.
+pub fn add_listen_utc_offset(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        alter table listens add column utc_offset_seconds integer null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_listen_utc_offset' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// The true peak of tracks and albums, in dBTP, see `TruePeakMeter` in
+/// loudness.rs. Loudness that we analyzed before we measured the peak has null,
+/// the next scan analyzes those tracks again.
+pub fn add_loudness_true_peak(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        alter table track_loudness add column true_peak_dbtp real null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_loudness_true_peak' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        alter table album_loudness add column true_peak_dbtp real null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_loudness_true_peak' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Problems with files that we found outside of a scan, see verify.rs. There is
+/// at most one issue of every kind per file. For now the only kind is 'verify',
+/// for files whose decoded audio does not match the MD5 in their streaminfo.
+pub fn add_file_issues(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table if not exists file_issues
+        ( file_id  integer not null references files (id) on delete cascade
+        , kind     string  not null
+        , detail   string  not null
+        , found_at string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_file_issues' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        create unique index if not exists ix_file_issues_file_id_kind
+        on file_issues (file_id, kind);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_file_issues' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Internet radio stations, that can be enqueued like tracks, and their listens.
+pub fn add_radio_stations(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        create table if not exists radio_stations
+        ( id          integer primary key
+        , name        string  not null
+        , url         string  not null
+        -- ISO-8601 timestamp at which the station was added.
+        , created_at  string  not null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'add_radio_stations' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        -- Listens of radio stations. A station plays many songs, we record one listen
+        -- per title that the station announces in its stream. Radio listens do not
+        -- refer to tracks in the library, so unlike listens from the library, they
+        -- don't count towards statistics, and we don't scrobble them.
+        create table if not exists radio_listens
+        ( id           integer primary key
+        -- ISO-8601 time with UTC offset at which the title started playing.
+        , started_at   string  not null
+        -- ISO-8601 time with UTC offset at which the title stopped playing.
+        -- NULL if it is still playing, or if playback stopped unexpectedly.
+        , completed_at string  null     check (started_at < completed_at)
+        , queue_id     integer not null
+        -- References the radio_stations table, but like for listens, we keep the
+        -- listen when the station is deleted, that's why we also store the name.
+        , station_id   integer not null
+        , station_name string  not null
+        -- The title as the station announced it, often "Artist - Title". NULL when
+        -- the station does not announce titles.
+        , stream_title string  null
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'add_radio_stations' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn iter_integrity_check<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, String>> {
+    let sql = r#"
+        select integrity_check from pragma_integrity_check;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Rows that reference a row in another table that does not exist. There should
+/// be none, but the references were not enforced when foreign keys were off.
+pub fn iter_foreign_key_violations<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (String, i64, String)>> {
+    let sql = r#"
+        select "table", rowid, parent from pragma_foreign_key_check;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+        statement.read(2)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Return the size of the database in bytes, not counting the WAL.
+pub fn select_database_size(tx: &mut Transaction) -> Result<i64> {
+    let sql = r#"
+        select page_count * page_size from pragma_page_count, pragma_page_size;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_database_size' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_database_size' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+/// Return the auto_vacuum mode: 0 is none, 1 is full, 2 is incremental.
+pub fn select_auto_vacuum(tx: &mut Transaction) -> Result<i64> {
+    let sql = r#"
+        select auto_vacuum from pragma_auto_vacuum;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_auto_vacuum' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_auto_vacuum' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+/// Take the write lock without changing anything, for the health check. The
+/// caller rolls back the transaction afterwards.
+pub fn probe_write(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        delete from playlists where false;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'probe_write' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Gather statistics about the tables and indexes, for the query planner.
+pub fn analyze(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        analyze;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'analyze' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct InsertFile<'a> {
+    pub filename: &'a str,
+    pub mtime: i64,
+    pub imported_at: &'a str,
+    pub streaminfo_channels: i64,
+    pub streaminfo_bits_per_sample: i64,
+    pub streaminfo_num_samples: Option<i64>,
+    pub streaminfo_sample_rate: i64,
+    pub size_bytes: i64,
+    pub inode: i64,
+    pub streaminfo_md5: Option<&'a str>,
+}
+
+pub fn insert_file(tx: &mut Transaction, metadata: InsertFile) -> Result<i64> {
+    let sql = r#"
+        insert into files
+        ( filename
+        , mtime
+        , imported_at
+        , streaminfo_channels
+        , streaminfo_bits_per_sample
+        , streaminfo_num_samples
+        , streaminfo_sample_rate
+        , size_bytes
+        , inode
+        , streaminfo_md5
+        )
+        values
+        ( :filename
+        , :mtime
+        , :imported_at
+        , :streaminfo_channels
+        , :streaminfo_bits_per_sample
+        , :streaminfo_num_samples
+        , :streaminfo_sample_rate
+        , :size_bytes
+        , :inode
+        , :streaminfo_md5
+        )
+        returning id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, metadata.filename)?;
+    statement.bind(2, metadata.mtime)?;
+    statement.bind(3, metadata.imported_at)?;
+    statement.bind(4, metadata.streaminfo_channels)?;
+    statement.bind(5, metadata.streaminfo_bits_per_sample)?;
+    statement.bind(6, metadata.streaminfo_num_samples)?;
+    statement.bind(7, metadata.streaminfo_sample_rate)?;
+    statement.bind(8, metadata.size_bytes)?;
+    statement.bind(9, metadata.inode)?;
+    statement.bind(10, metadata.streaminfo_md5)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'insert_file' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'insert_file' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+pub fn insert_tag(tx: &mut Transaction, file_id: i64, field_name: &str, value: &str) -> Result<()> {
+    let sql = r#"
+        insert into
+          tags (file_id, field_name, value)
+          values (:file_id, :field_name, :value);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, file_id)?;
+    statement.bind(2, field_name)?;
+    statement.bind(3, value)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_tag' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Delete a file and everything referencing it (cascade to tags, waveforms, etc.)
+///
+/// Note that album loudness is not deleted, it is not based on any single file.
+pub fn delete_file(tx: &mut Transaction, file_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from files where id = :file_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, file_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_file' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Files that were moved keep their id, so their loudness, waveform, and
+/// thumbnail remain valid, and their listens keep referring to them.
+pub fn update_file_path(tx: &mut Transaction, file_id: i64, filename: &str, inode: i64) -> Result<()> {
+    let sql = r#"
+        update files
+        set filename = :filename, inode = :inode
+        where id = :file_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, filename)?;
+    statement.bind(2, inode)?;
+    statement.bind(3, file_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_file_path' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Fill in the size and inode for files scanned before we recorded those.
+pub fn update_file_size_inode(tx: &mut Transaction, file_id: i64, size_bytes: i64, inode: i64) -> Result<()> {
+    let sql = r#"
+        update files
+        set size_bytes = :size_bytes, inode = :inode
+        where id = :file_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, size_bytes)?;
+    statement.bind(2, inode)?;
+    statement.bind(3, file_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_file_size_inode' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn iter_files_without_inode<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, String)>> {
+    let sql = r#"
+        select id, filename from files where inode is null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn insert_file_pregap(tx: &mut Transaction, file_id: i64, pregap_samples: i64) -> Result<()> {
+    let sql = r#"
+        insert or replace into
+          file_pregaps (file_id, pregap_samples)
+        values
+          (:file_id, :pregap_samples);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, file_id)?;
+    statement.bind(2, pregap_samples)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_file_pregap' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn iter_files_without_pregap<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, String)>> {
+    let sql = r#"
+        select id, filename from files where id not in (select file_id from file_pregaps);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Iterate the files that have a pre-gap, for the index.
+///
+/// Yields tuples `(file_id, pregap_samples, sample_rate)`.
+pub fn iter_file_pregaps<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, i64, i64)>> {
+    let sql = r#"
+        select
+          files.id, file_pregaps.pregap_samples, files.streaminfo_sample_rate
+        from
+          file_pregaps, files
+        where
+          files.id = file_pregaps.file_id
+          and file_pregaps.pregap_samples > 0;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+        statement.read(2)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct FileIdentity {
+    pub id: i64,
+    pub filename: String,
+    pub mtime: i64,
+    pub size_bytes: Option<i64>,
+    pub inode: Option<i64>,
+    pub streaminfo_md5: Option<String>,
+}
+
+pub fn iter_file_identities<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, FileIdentity>> {
+    let sql = r#"
+        select
+            id
+          , filename
+          , mtime
+          , size_bytes
+          , inode
+          , streaminfo_md5
+        from
+          files;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(FileIdentity {
+        id: statement.read(0)?,
+        filename: statement.read(1)?,
+        mtime: statement.read(2)?,
+        size_bytes: statement.read(3)?,
+        inode: statement.read(4)?,
+        streaminfo_md5: statement.read(5)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct FileMetadataSimple {
+    pub id: i64,
+    pub filename: String,
+    pub mtime: i64,
+}
+
+pub fn iter_file_mtime<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, FileMetadataSimple>> {
+    let sql = r#"
+        select
+            id
+          , filename
+          , mtime
+        from
+          files
+        order by
+          filename asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(FileMetadataSimple {
+        id: statement.read(0)?,
+        filename: statement.read(1)?,
+        mtime: statement.read(2)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
 pub struct FileMetadata {
     pub id: i64,
-    pub filename: String,
-    pub mtime: i64,
-    pub streaminfo_channels: i64,
-    pub streaminfo_bits_per_sample: i64,
-    pub streaminfo_num_samples: Option<i64>,
-    pub streaminfo_sample_rate: i64,
+    pub filename: String,
+    pub mtime: i64,
+    pub imported_at: String,
+    pub streaminfo_channels: i64,
+    pub streaminfo_bits_per_sample: i64,
+    pub streaminfo_num_samples: Option<i64>,
+    pub streaminfo_sample_rate: i64,
+}
+
+pub fn iter_files<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, FileMetadata>> {
+    let sql = r#"
+        select
+            id
+          , filename
+          , mtime
+          , imported_at
+          , streaminfo_channels
+          , streaminfo_bits_per_sample
+          , streaminfo_num_samples
+          , streaminfo_sample_rate
+        from
+          files
+        order by
+          filename asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(FileMetadata {
+        id: statement.read(0)?,
+        filename: statement.read(1)?,
+        mtime: statement.read(2)?,
+        imported_at: statement.read(3)?,
+        streaminfo_channels: statement.read(4)?,
+        streaminfo_bits_per_sample: statement.read(5)?,
+        streaminfo_num_samples: statement.read(6)?,
+        streaminfo_sample_rate: statement.read(7)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct FileFormat {
+    pub id: i64,
+    pub streaminfo_bits_per_sample: i64,
+    pub streaminfo_sample_rate: i64,
+    pub size_bytes: Option<i64>,
+}
+
+/// Iterate the audio properties and sizes of all files, for library statistics.
+pub fn iter_file_formats<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, FileFormat>> {
+    let sql = r#"
+        select
+            id
+          , streaminfo_bits_per_sample
+          , streaminfo_sample_rate
+          , size_bytes
+        from
+          files;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(FileFormat {
+        id: statement.read(0)?,
+        streaminfo_bits_per_sample: statement.read(1)?,
+        streaminfo_sample_rate: statement.read(2)?,
+        size_bytes: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Iterate all `(field_name, value)` pairs for the given file.
+pub fn iter_file_tags<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, file_id: i64) -> Result<Iter<'i, 'a, (String, String)>> {
+    let sql = r#"
+        select
+          field_name, value
+        from
+          tags
+        where
+          file_id = :file_id
+        order by
+          -- We have to order by id, which is increasing with insert order, because some
+          -- tags can occur multiple times, and we have to preserve the order in which
+          -- we found them in the file.
+          id asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, file_id)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Remove all values of a tag from a file, before inserting the edited value.
+pub fn delete_file_tag(tx: &mut Transaction, file_id: i64, field_name: &str) -> Result<()> {
+    let sql = r#"
+        delete from tags where file_id = :file_id and field_name = :field_name;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, file_id)?;
+    statement.bind(2, field_name)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_file_tag' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Record the new mtime, size, and inode of a file after we rewrote its tags,
+/// so the next scan does not consider it modified.
+pub fn update_file_mtime_size_inode(tx: &mut Transaction, file_id: i64, mtime: i64, size_bytes: i64, inode: i64) -> Result<()> {
+    let sql = r#"
+        update files
+        set mtime = :mtime, size_bytes = :size_bytes, inode = :inode
+        where id = :file_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, mtime)?;
+    statement.bind(2, size_bytes)?;
+    statement.bind(3, inode)?;
+    statement.bind(4, file_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_file_mtime_size_inode' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn insert_album_thumbnail(tx: &mut Transaction, album_id: i64, file_id: i64, data: &[u8]) -> Result<()> {
+    let sql = r#"
+        insert into thumbnails (album_id, file_id, data)
+        values (:album_id, :file_id, :data)
+        on conflict (album_id) do update set data = :data;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    statement.bind(2, file_id)?;
+    statement.bind(3, data)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_album_thumbnail' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn insert_album_loudness(tx: &mut Transaction, album_id: i64, file_id: i64, loudness: f64, true_peak: f64) -> Result<()> {
+    let sql = r#"
+        insert into album_loudness (album_id, file_id, bs17704_loudness_lufs, true_peak_dbtp)
+        values (:album_id, :file_id, :loudness, :true_peak)
+        on conflict (album_id) do update set bs17704_loudness_lufs = :loudness, true_peak_dbtp = :true_peak;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    statement.bind(2, file_id)?;
+    statement.bind(3, loudness)?;
+    statement.bind(4, true_peak)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_album_loudness' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn insert_track_loudness(tx: &mut Transaction, track_id: i64, file_id: i64, loudness: f64, true_peak: f64) -> Result<()> {
+    let sql = r#"
+        insert into track_loudness (track_id, file_id, bs17704_loudness_lufs, true_peak_dbtp)
+        values (:track_id, :file_id, :loudness, :true_peak)
+        on conflict (track_id) do update set bs17704_loudness_lufs = :loudness, true_peak_dbtp = :true_peak;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, file_id)?;
+    statement.bind(3, loudness)?;
+    statement.bind(4, true_peak)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_track_loudness' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn insert_track_waveform(tx: &mut Transaction, track_id: i64, file_id: i64, data: &[u8]) -> Result<()> {
+    let sql = r#"
+        insert into waveforms (track_id, file_id, data)
+        values (:track_id, :file_id, :data)
+        on conflict (track_id) do update set data = :data;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, file_id)?;
+    statement.bind(3, data)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_track_waveform' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct Listen<'a> {
+    pub started_at: &'a str,
+    pub file_id: i64,
+    pub queue_id: i64,
+    pub track_id: i64,
+    pub album_id: i64,
+    pub album_artist_id: i64,
+    pub track_title: &'a str,
+    pub track_artist: &'a str,
+    pub album_title: &'a str,
+    pub album_artist: &'a str,
+    pub duration_seconds: i64,
+    pub track_number: i64,
+    pub disc_number: i64,
+    pub utc_offset_seconds: i64,
+}
+
+pub fn insert_listen_started(tx: &mut Transaction, listen: Listen) -> Result<i64> {
+    let sql = r#"
+        insert into
+          listens
+          ( started_at
+          , file_id
+          , queue_id
+          , track_id
+          , album_id
+          , album_artist_id
+          , track_title
+          , track_artist
+          , album_title
+          , album_artist
+          , duration_seconds
+          , track_number
+          , disc_number
+          , utc_offset_seconds
+          , source
+          )
+        values
+          ( :started_at
+          , :file_id
+          , :queue_id
+          , :track_id
+          , :album_id
+          , :album_artist_id
+          , :track_title
+          , :track_artist
+          , :album_title
+          , :album_artist
+          , :duration_seconds
+          , :track_number
+          , :disc_number
+          , :utc_offset_seconds
+          , 'musium'
+          )
+        returning
+          id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen.started_at)?;
+    statement.bind(2, listen.file_id)?;
+    statement.bind(3, listen.queue_id)?;
+    statement.bind(4, listen.track_id)?;
+    statement.bind(5, listen.album_id)?;
+    statement.bind(6, listen.album_artist_id)?;
+    statement.bind(7, listen.track_title)?;
+    statement.bind(8, listen.track_artist)?;
+    statement.bind(9, listen.album_title)?;
+    statement.bind(10, listen.album_artist)?;
+    statement.bind(11, listen.duration_seconds)?;
+    statement.bind(12, listen.track_number)?;
+    statement.bind(13, listen.disc_number)?;
+    statement.bind(14, listen.utc_offset_seconds)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'insert_listen_started' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'insert_listen_started' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+/// Mark the listen completed, after the track played for `played_seconds`.
+pub fn update_listen_completed(tx: &mut Transaction, listen_id: i64, queue_id: i64, track_id: i64, completed_at: &str, played_seconds: i64) -> Result<()> {
+    let sql = r#"
+        update listens
+          set
+            completed_at = :completed_at
+          , played_fraction = min(1.0, :played_seconds * 1.0 / max(duration_seconds, 1))
+        where
+          id = :listen_id
+          and queue_id = :queue_id
+          and track_id = :track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, completed_at)?;
+    statement.bind(2, played_seconds)?;
+    statement.bind(3, listen_id)?;
+    statement.bind(4, queue_id)?;
+    statement.bind(5, track_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_listen_completed' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ListenRow {
+    pub id: i64,
+    pub started_at_seconds: i64,
+    pub started_at: String,
+    pub completed_at: Option<String>,
+    pub track_id: i64,
+    pub album_id: i64,
+    pub album_artist_id: i64,
+    pub track_title: String,
+    pub track_artist: String,
+    pub album_title: String,
+    pub album_artist: String,
+    pub duration_seconds: i64,
+    pub track_number: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub source: String,
+    pub scrobbled_at: Option<String>,
+    pub played_fraction: Option<f64>,
+    pub client: Option<String>,
+}
+
+/// Iterate listens of the user that started in the interval [since, until),
+/// newest first, optionally only for one album, or one album artist. The user
+/// is NULL for the default user, see listen_users. Seconds are unique per
+/// listen, so the start second of the last row can serve as a cursor for the
+/// next page.
+pub fn iter_listens_page<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_seconds: i64, until_seconds: i64, album_id: Option<i64>, album_artist_id: Option<i64>, client: Option<&str>, user: Option<&str>, limit: i64) -> Result<Iter<'i, 'a, ListenRow>> {
+    let sql = r#"
+        select
+            id
+          , cast(strftime('%s', started_at) as integer) as started_at_seconds
+          , started_at
+          , completed_at
+          , track_id
+          , album_id
+          , album_artist_id
+          , track_title
+          , track_artist
+          , album_title
+          , album_artist
+          , duration_seconds
+          , track_number
+          , disc_number
+          , source
+          , scrobbled_at
+          , played_fraction
+          , ( select client
+              from listen_clients
+              where listen_clients.listen_id = listens.id
+            ) as client
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and (:album_id is null or album_id = :album_id)
+          and (:album_artist_id is null or album_artist_id = :album_artist_id)
+          and (:client is null or id in (select listen_id from listen_clients where client = :client))
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+        order by
+          cast(strftime('%s', started_at) as integer) desc
+        limit
+          :limit;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, album_id)?;
+    statement.bind(4, album_artist_id)?;
+    statement.bind(5, client)?;
+    statement.bind(6, user)?;
+    statement.bind(7, limit)?;
+    let decode_row = |statement: &Statement| Ok(ListenRow {
+        id: statement.read(0)?,
+        started_at_seconds: statement.read(1)?,
+        started_at: statement.read(2)?,
+        completed_at: statement.read(3)?,
+        track_id: statement.read(4)?,
+        album_id: statement.read(5)?,
+        album_artist_id: statement.read(6)?,
+        track_title: statement.read(7)?,
+        track_artist: statement.read(8)?,
+        album_title: statement.read(9)?,
+        album_artist: statement.read(10)?,
+        duration_seconds: statement.read(11)?,
+        track_number: statement.read(12)?,
+        disc_number: statement.read(13)?,
+        source: statement.read(14)?,
+        scrobbled_at: statement.read(15)?,
+        played_fraction: statement.read(16)?,
+        client: statement.read(17)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Return the number of listens in the interval [since, until), and the time
+/// spent listening. We only know the listening time for completed listens,
+/// which count with the full duration of the track.
+pub fn select_listen_totals(tx: &mut Transaction, since_seconds: i64, until_seconds: i64, client: Option<&str>, user: Option<&str>) -> Result<(i64, i64)> {
+    let sql = r#"
+        select
+            count(*)
+          , coalesce(sum(case when completed_at is null then 0 else duration_seconds end), 0)
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and (:client is null or id in (select listen_id from listen_clients where client = :client))
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, client)?;
+    statement.bind(4, user)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_listen_totals' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_listen_totals' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+/// Return the number of listens in the interval [since, until) that played for
+/// at least the threshold, see `ListenThreshold` in listens.rs. The others are
+/// samples. Listens that are still playing do not count yet.
+pub fn select_counted_listen_count(tx: &mut Transaction, since_seconds: i64, until_seconds: i64, client: Option<&str>, user: Option<&str>, threshold_percent: i64, threshold_seconds: i64) -> Result<i64> {
+    let sql = r#"
+        select
+          count(*)
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and (:client is null or id in (select listen_id from listen_clients where client = :client))
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+          and coalesce(
+            played_fraction * duration_seconds,
+            cast(strftime('%s', completed_at) as integer) - cast(strftime('%s', started_at) as integer),
+            0
+          ) >= min(duration_seconds * :threshold_percent / 100.0, :threshold_seconds);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, client)?;
+    statement.bind(4, user)?;
+    statement.bind(5, threshold_percent)?;
+    statement.bind(6, threshold_seconds)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_counted_listen_count' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_counted_listen_count' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+pub fn insert_listen_client(tx: &mut Transaction, listen_id: i64, client: &str) -> Result<()> {
+    let sql = r#"
+        insert into listen_clients (listen_id, client) values (:listen_id, :client);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen_id)?;
+    statement.bind(2, client)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_listen_client' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn insert_listen_user(tx: &mut Transaction, listen_id: i64, user: &str) -> Result<()> {
+    let sql = r#"
+        insert into listen_users (listen_id, user_name) values (:listen_id, :user);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen_id)?;
+    statement.bind(2, user)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_listen_user' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Record how long a listen played, when the track was skipped.
+pub fn update_listen_played(tx: &mut Transaction, listen_id: i64, played_seconds: i64) -> Result<()> {
+    let sql = r#"
+        update listens
+          set played_fraction = min(1.0, :played_seconds * 1.0 / max(duration_seconds, 1))
+        where
+          id = :listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, played_seconds)?;
+    statement.bind(2, listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_listen_played' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn insert_skip(tx: &mut Transaction, skipped_at: &str, listen_id: Option<i64>, queue_id: i64, track_id: i64, position_seconds: i64) -> Result<()> {
+    let sql = r#"
+        insert into
+          skips (skipped_at, listen_id, queue_id, track_id, position_seconds)
+        values
+          (:skipped_at, :listen_id, :queue_id, :track_id, :position_seconds);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, skipped_at)?;
+    statement.bind(2, listen_id)?;
+    statement.bind(3, queue_id)?;
+    statement.bind(4, track_id)?;
+    statement.bind(5, position_seconds)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_skip' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn select_skip_count(tx: &mut Transaction, since_seconds: i64, until_seconds: i64, client: Option<&str>, user: Option<&str>) -> Result<i64> {
+    let sql = r#"
+        select
+          count(*)
+        from
+          skips
+        where
+          cast(strftime('%s', skipped_at) as integer) >= :since_seconds
+          and cast(strftime('%s', skipped_at) as integer) < :until_seconds
+          and (:client is null or listen_id in (select listen_id from listen_clients where client = :client))
+          and (select user_name from listen_users where listen_users.listen_id = skips.listen_id) is :user;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, client)?;
+    statement.bind(4, user)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_skip_count' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_skip_count' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct SkippedTrack {
+    pub track_id: i64,
+    pub skip_count: i64,
+    pub listen_count: i64,
+}
+
+/// Return the most skipped tracks in the interval [since, until), with the
+/// number of listens in the same interval, which includes the skipped ones.
+pub fn iter_top_skipped_tracks<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_seconds: i64, until_seconds: i64, client: Option<&str>, user: Option<&str>, limit: i64) -> Result<Iter<'i, 'a, SkippedTrack>> {
+    let sql = r#"
+        select
+            track_id
+          , count(*) as skip_count
+          , ( select count(*)
+              from listens
+              where
+                listens.track_id = skips.track_id
+                and cast(strftime('%s', listens.started_at) as integer) >= :since_seconds
+                and cast(strftime('%s', listens.started_at) as integer) < :until_seconds
+                and (:client is null or listens.id in (select listen_id from listen_clients where client = :client))
+                and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+            ) as listen_count
+        from
+          skips
+        where
+          cast(strftime('%s', skipped_at) as integer) >= :since_seconds
+          and cast(strftime('%s', skipped_at) as integer) < :until_seconds
+          and (:client is null or skips.listen_id in (select listen_id from listen_clients where client = :client))
+          and (select user_name from listen_users where listen_users.listen_id = skips.listen_id) is :user
+        group by
+          track_id
+        order by
+          skip_count desc, listen_count asc
+        limit
+          :limit;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, client)?;
+    statement.bind(4, user)?;
+    statement.bind(5, limit)?;
+    let decode_row = |statement: &Statement| Ok(SkippedTrack {
+        track_id: statement.read(0)?,
+        skip_count: statement.read(1)?,
+        listen_count: statement.read(2)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct TopArtist {
+    pub album_artist_id: i64,
+    pub album_artist: String,
+    pub listen_count: i64,
+    pub listen_seconds: i64,
+}
+
+pub fn iter_top_artists<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_seconds: i64, until_seconds: i64, client: Option<&str>, user: Option<&str>, limit: i64) -> Result<Iter<'i, 'a, TopArtist>> {
+    let sql = r#"
+        select
+            album_artist_id
+          , max(album_artist) as album_artist
+          , count(*) as listen_count
+          , sum(case when completed_at is null then 0 else duration_seconds end)
+            as listen_seconds
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and (:client is null or id in (select listen_id from listen_clients where client = :client))
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+        group by
+          album_artist_id
+        order by
+          listen_count desc, listen_seconds desc
+        limit
+          :limit;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, client)?;
+    statement.bind(4, user)?;
+    statement.bind(5, limit)?;
+    let decode_row = |statement: &Statement| Ok(TopArtist {
+        album_artist_id: statement.read(0)?,
+        album_artist: statement.read(1)?,
+        listen_count: statement.read(2)?,
+        listen_seconds: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct TopAlbum {
+    pub album_id: i64,
+    pub album_title: String,
+    pub album_artist: String,
+    pub listen_count: i64,
+    pub listen_seconds: i64,
+}
+
+pub fn iter_top_albums<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_seconds: i64, until_seconds: i64, client: Option<&str>, user: Option<&str>, limit: i64) -> Result<Iter<'i, 'a, TopAlbum>> {
+    let sql = r#"
+        select
+            album_id
+          , max(album_title) as album_title
+          , max(album_artist) as album_artist
+          , count(*) as listen_count
+          , sum(case when completed_at is null then 0 else duration_seconds end)
+            as listen_seconds
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and (:client is null or id in (select listen_id from listen_clients where client = :client))
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+        group by
+          album_id
+        order by
+          listen_count desc, listen_seconds desc
+        limit
+          :limit;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, client)?;
+    statement.bind(4, user)?;
+    statement.bind(5, limit)?;
+    let decode_row = |statement: &Statement| Ok(TopAlbum {
+        album_id: statement.read(0)?,
+        album_title: statement.read(1)?,
+        album_artist: statement.read(2)?,
+        listen_count: statement.read(3)?,
+        listen_seconds: statement.read(4)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct TopTrack {
+    pub track_id: i64,
+    pub track_title: String,
+    pub track_artist: String,
+    pub album_title: String,
+    pub listen_count: i64,
+    pub listen_seconds: i64,
+}
+
+pub fn iter_top_tracks<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_seconds: i64, until_seconds: i64, client: Option<&str>, user: Option<&str>, limit: i64) -> Result<Iter<'i, 'a, TopTrack>> {
+    let sql = r#"
+        select
+            track_id
+          , max(track_title) as track_title
+          , max(track_artist) as track_artist
+          , max(album_title) as album_title
+          , count(*) as listen_count
+          , sum(case when completed_at is null then 0 else duration_seconds end)
+            as listen_seconds
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and (:client is null or id in (select listen_id from listen_clients where client = :client))
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+        group by
+          track_id
+        order by
+          listen_count desc, listen_seconds desc
+        limit
+          :limit;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, client)?;
+    statement.bind(4, user)?;
+    statement.bind(5, limit)?;
+    let decode_row = |statement: &Statement| Ok(TopTrack {
+        track_id: statement.read(0)?,
+        track_title: statement.read(1)?,
+        track_artist: statement.read(2)?,
+        album_title: statement.read(3)?,
+        listen_count: statement.read(4)?,
+        listen_seconds: statement.read(5)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Count listens per day of the week (0 is Sunday) and hour of the day, in the
+/// local time of the listen, see `add_listen_utc_offset`.
+pub fn iter_listens_per_weekday_hour<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_seconds: i64, until_seconds: i64, client: Option<&str>, user: Option<&str>) -> Result<Iter<'i, 'a, (i64, i64, i64)>> {
+    let sql = r#"
+        select
+            cast(strftime('%w', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) as integer) as weekday
+          , cast(strftime('%H', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) as integer) as hour
+          , count(*)
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and (:client is null or id in (select listen_id from listen_clients where client = :client))
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+        group by
+          weekday, hour;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, client)?;
+    statement.bind(4, user)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+        statement.read(2)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct AlbumDiscovery {
+    pub album_id: i64,
+    pub album_title: String,
+    pub album_artist: String,
+    pub listen_count: i64,
+}
+
+/// Albums whose first listen ever by the user falls in the interval [since,
+/// until), by the number of listens in that interval.
+pub fn iter_album_discoveries<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_seconds: i64, until_seconds: i64, user: Option<&str>, limit: i64) -> Result<Iter<'i, 'a, AlbumDiscovery>> {
+    let sql = r#"
+        select
+            album_id
+          , max(album_title) as album_title
+          , max(album_artist) as album_artist
+          , count(*) as listen_count
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+          and album_id not in (
+            select album_id
+            from listens
+            where
+              cast(strftime('%s', started_at) as integer) < :since_seconds
+              and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+          )
+        group by
+          album_id
+        order by
+          listen_count desc
+        limit
+          :limit;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, user)?;
+    statement.bind(4, limit)?;
+    let decode_row = |statement: &Statement| Ok(AlbumDiscovery {
+        album_id: statement.read(0)?,
+        album_title: statement.read(1)?,
+        album_artist: statement.read(2)?,
+        listen_count: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct DayOfYearAlbum {
+    pub year: i64,
+    pub album_id: i64,
+    pub album_title: String,
+    pub album_artist: String,
+    pub listen_count: i64,
+}
+
+/// Count listens per year and album on the given day of the year, formatted as
+/// '%m-%d', in years before the given year. Days and years are in the local
+/// time of the listen. This needs a full table scan, because the index is on
+/// the timestamp, not on the day of the year.
+pub fn iter_listens_on_day_of_year<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, month_day: &str, before_year: i64, user: Option<&str>) -> Result<Iter<'i, 'a, DayOfYearAlbum>> {
+    let sql = r#"
+        select
+            cast(strftime('%Y', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) as integer) as year
+          , album_id
+          , max(album_title) as album_title
+          , max(album_artist) as album_artist
+          , count(*) as listen_count
+        from
+          listens
+        where
+          strftime('%m-%d', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) = :month_day
+          and cast(strftime('%Y', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) as integer) < :before_year
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+        group by
+          year, album_id
+        order by
+          year desc, listen_count desc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, month_day)?;
+    statement.bind(2, before_year)?;
+    statement.bind(3, user)?;
+    let decode_row = |statement: &Statement| Ok(DayOfYearAlbum {
+        year: statement.read(0)?,
+        album_id: statement.read(1)?,
+        album_title: statement.read(2)?,
+        album_artist: statement.read(3)?,
+        listen_count: statement.read(4)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Iterate the listens of the user since the given time, grouped by album, and
+/// oldest first within an album, as `(album_id, track_id, started_at_seconds)`.
+/// For finding albums that the user listened to partway, see listens.rs.
+pub fn iter_album_listens_since<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_seconds: i64, user: Option<&str>) -> Result<Iter<'i, 'a, (i64, i64, i64)>> {
+    let sql = r#"
+        select
+            album_id
+          , track_id
+          , cast(strftime('%s', started_at) as integer) as started_at_seconds
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+        order by
+          album_id, started_at_seconds;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, user)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+        statement.read(2)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Iterate all listens of the user, grouped by session, as
+/// `(track_id, album_artist_id, session_id)`. For finding tracks and artists
+/// that get played in the same session, see similar.rs. A listen that is not
+/// yet part of a session is a session of its own.
+pub fn iter_listens_for_similarity<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, user: Option<&str>) -> Result<Iter<'i, 'a, (i64, i64, i64)>> {
+    let sql = r#"
+        select
+            track_id
+          , album_artist_id
+          , coalesce(session_id, -id) as session
+        from
+          listens
+        where
+          (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+        order by
+          session;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, user)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+        statement.read(2)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Iterate the listens that are not yet part of a session, oldest first, as
+/// (id, started_at_seconds, user_name).
+pub fn iter_listens_without_session<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, i64, Option<String>)>> {
+    let sql = r#"
+        select
+            id
+          , cast(strftime('%s', started_at) as integer)
+          , (select user_name from listen_users where listen_users.listen_id = listens.id)
+        from
+          listens
+        where
+          session_id is null
+        order by
+          started_at;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+        statement.read(2)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Return the session of the latest listen of the user that started before the
+/// given time, and when that listen ended, as (session_id, ended_at_seconds).
+/// Skipped and unfinished listens have no completion time, for those we take
+/// the start.
+pub fn select_previous_listen_session(tx: &mut Transaction, started_at_seconds: i64, user: Option<&str>) -> Result<Option<(Option<i64>, i64)>> {
+    let sql = r#"
+        select
+            session_id
+          , cast(strftime('%s', coalesce(completed_at, started_at)) as integer)
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) < :started_at_seconds
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+        order by
+          cast(strftime('%s', started_at) as integer) desc
+        limit
+          1;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, started_at_seconds)?;
+    statement.bind(2, user)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_previous_listen_session' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+pub fn select_max_listen_session_id(tx: &mut Transaction) -> Result<i64> {
+    let sql = r#"
+        select coalesce(max(session_id), 0) from listens;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_max_listen_session_id' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_max_listen_session_id' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+pub fn update_listen_session(tx: &mut Transaction, listen_id: i64, session_id: i64) -> Result<()> {
+    let sql = r#"
+        update listens set session_id = :session_id where id = :listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, session_id)?;
+    statement.bind(2, listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_listen_session' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Iterate the sessions of the user with listens in the time range, as
+/// (started_at_seconds, ended_at_seconds, listen_count, start_hour), where the
+/// hour is in the local time of the listen.
+pub fn iter_listen_sessions<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_seconds: i64, until_seconds: i64, client: Option<&str>, user: Option<&str>) -> Result<Iter<'i, 'a, (i64, i64, i64, i64)>> {
+    let sql = r#"
+        select
+            min(cast(strftime('%s', started_at) as integer))
+          , max(cast(strftime('%s', coalesce(completed_at, started_at)) as integer))
+          , count(*)
+          , cast(strftime('%H', min(started_at), coalesce(utc_offset_seconds || ' seconds', 'localtime')) as integer)
+        from
+          listens
+        where
+          session_id is not null
+          and cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and (:client is null or id in (select listen_id from listen_clients where client = :client))
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+        group by
+          session_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, client)?;
+    statement.bind(4, user)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+        statement.read(2)?,
+        statement.read(3)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Record the listen as the first listen of its track, album, and album artist,
+/// for those that do not have one yet. Listens start in chronological order, so
+/// the first one we record is the earliest.
+pub fn insert_first_listen(tx: &mut Transaction, listen_id: i64) -> Result<()> {
+    let sql = r#"
+        insert or ignore into first_listens (kind, item_id, user_name, listen_id, started_at)
+        with listen as (
+          select
+              id
+            , track_id
+            , album_id
+            , album_artist_id
+            , started_at
+            , (select user_name from listen_users where listen_users.listen_id = listens.id) as user_name
+          from
+            listens
+          where
+            id = :listen_id
+        )
+        select 'track', track_id, user_name, id, started_at from listen
+        union all
+        select 'album', album_id, user_name, id, started_at from listen
+        union all
+        select 'artist', album_artist_id, user_name, id, started_at from listen;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_first_listen' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Recompute the first listens from scratch. For after an import or a repair,
+/// where listens do not arrive in chronological order.
+pub fn rebuild_first_listens(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        delete from first_listens;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'rebuild_first_listens' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        insert into first_listens (kind, item_id, user_name, listen_id, started_at)
+        select kind, item_id, user_name, id, min(started_at)
+        from (
+          select 'track' as kind, track_id as item_id, user_name, id, started_at
+          from listens left join listen_users on listen_users.listen_id = listens.id
+          union all
+          select 'album', album_id, user_name, id, started_at
+          from listens left join listen_users on listen_users.listen_id = listens.id
+          union all
+          select 'artist', album_artist_id, user_name, id, started_at
+          from listens left join listen_users on listen_users.listen_id = listens.id
+        )
+        group by kind, item_id, user_name;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'rebuild_first_listens' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Count the tracks, albums, and album artists that the user listened to for
+/// the first time in the interval [since, until).
+pub fn select_discovery_counts(tx: &mut Transaction, since_seconds: i64, until_seconds: i64, user: Option<&str>) -> Result<(i64, i64, i64)> {
+    let sql = r#"
+        select
+            coalesce(sum(kind = 'track'), 0)
+          , coalesce(sum(kind = 'album'), 0)
+          , coalesce(sum(kind = 'artist'), 0)
+        from
+          first_listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and user_name is :user;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, user)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+        statement.read(2)?,
+));
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_discovery_counts' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_discovery_counts' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+/// Count the first listens of the user in the interval [since, until) per
+/// month, as (month, tracks, albums, artists), oldest month first. Months are
+/// formatted as YYYY-MM, in the local time of the listen.
+pub fn iter_discoveries_per_month<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_seconds: i64, until_seconds: i64, user: Option<&str>) -> Result<Iter<'i, 'a, (String, i64, i64, i64)>> {
+    let sql = r#"
+        select
+            strftime('%Y-%m', started_at, coalesce(
+              (select utc_offset_seconds from listens where listens.id = first_listens.listen_id) || ' seconds',
+              'localtime'
+            )) as month
+          , sum(kind = 'track')
+          , sum(kind = 'album')
+          , sum(kind = 'artist')
+        from
+          first_listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and user_name is :user
+        group by
+          month
+        order by
+          month;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, user)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+        statement.read(2)?,
+        statement.read(3)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Count the listens of the user in the interval [since, until) per month, as
+/// (month, listens), oldest month first, like `iter_discoveries_per_month`.
+pub fn iter_listens_per_month<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_seconds: i64, until_seconds: i64, user: Option<&str>) -> Result<Iter<'i, 'a, (String, i64)>> {
+    let sql = r#"
+        select
+            strftime('%Y-%m', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) as month
+          , count(*)
+        from
+          listens
+        where
+          cast(strftime('%s', started_at) as integer) >= :since_seconds
+          and cast(strftime('%s', started_at) as integer) < :until_seconds
+          and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+        group by
+          month
+        order by
+          month;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, since_seconds)?;
+    statement.bind(2, until_seconds)?;
+    statement.bind(3, user)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ImportedListen<'a> {
+    pub started_at: &'a str,
+    pub completed_at: &'a str,
+    pub file_id: i64,
+    pub track_id: i64,
+    pub album_id: i64,
+    pub album_artist_id: i64,
+    pub track_title: &'a str,
+    pub track_artist: &'a str,
+    pub album_title: &'a str,
+    pub album_artist: &'a str,
+    pub duration_seconds: i64,
+    pub track_number: i64,
+    pub disc_number: i64,
+    pub utc_offset_seconds: i64,
+    pub source: &'a str,
+}
+
+/// Insert a listen imported from an export of an external service. Returns
+/// nothing when we already have a listen that started in the same second, for
+/// example because we produced the listen ourselves and scrobbled it, or
+/// because we imported the same export before.
+pub fn insert_listen_imported(tx: &mut Transaction, listen: ImportedListen) -> Result<Option<i64>> {
+    let sql = r#"
+        insert or ignore into
+          listens
+          ( started_at
+          , completed_at
+          , file_id
+          , track_id
+          , album_id
+          , album_artist_id
+          , track_title
+          , track_artist
+          , album_title
+          , album_artist
+          , duration_seconds
+          , track_number
+          , disc_number
+          , utc_offset_seconds
+          , source
+          )
+        values
+          ( :started_at
+          , :completed_at
+          , :file_id
+          , :track_id
+          , :album_id
+          , :album_artist_id
+          , :track_title
+          , :track_artist
+          , :album_title
+          , :album_artist
+          , :duration_seconds
+          , :track_number
+          , :disc_number
+          , :utc_offset_seconds
+          , :source
+          )
+        returning
+          id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen.started_at)?;
+    statement.bind(2, listen.completed_at)?;
+    statement.bind(3, listen.file_id)?;
+    statement.bind(4, listen.track_id)?;
+    statement.bind(5, listen.album_id)?;
+    statement.bind(6, listen.album_artist_id)?;
+    statement.bind(7, listen.track_title)?;
+    statement.bind(8, listen.track_artist)?;
+    statement.bind(9, listen.album_title)?;
+    statement.bind(10, listen.album_artist)?;
+    statement.bind(11, listen.duration_seconds)?;
+    statement.bind(12, listen.track_number)?;
+    statement.bind(13, listen.disc_number)?;
+    statement.bind(14, listen.utc_offset_seconds)?;
+    statement.bind(15, listen.source)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'insert_listen_imported' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ListenToScrobble {
+    pub id: i64,
+    pub started_at_seconds: i64,
+    pub track_title: String,
+    pub track_artist: String,
+    pub album_title: String,
+    pub album_artist: String,
+    pub duration_seconds: i64,
+    pub track_number: Option<i64>,
+    pub track_mbid: Option<String>,
+}
+
+/// Iterate listens that are eligible for scrobbling to Last.fm, but that we
+/// have not scrobbled yet, oldest first, at most one batch of 50.
+///
+/// Last.fm does not accept tracks shorter than 30 seconds, and a track should
+/// be scrobbled after it played for the threshold, by default half its duration
+/// or four minutes, whichever comes first, see `ListenThreshold` in listens.rs.
+/// This includes listens that were skipped after the threshold. For listens
+/// from before we recorded how long they played, we go by the completion time.
+/// We only scrobble listens that started after `since_posix_seconds`, because
+/// Last.fm rejects scrobbles that are too old.
+/// The track MusicBrainz id comes from the tags of the file, if it is still
+/// there.
+pub fn iter_listens_to_scrobble<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, since_posix_seconds: i64, threshold_percent: i64, threshold_seconds: i64) -> Result<Iter<'i, 'a, ListenToScrobble>> {
+    let sql = r#"
+        select
+            listens.id
+          , cast(strftime('%s', started_at) as integer) as started_at_seconds
+          , track_title
+          , track_artist
+          , album_title
+          , album_artist
+          , duration_seconds
+          , track_number
+          , tags.value as track_mbid
+        from
+          listens
+          left join tags
+            on tags.file_id = listens.file_id
+            and tags.field_name = 'musicbrainz_trackid'
+        where
+          scrobbled_at is null
+          and source = 'musium'
+          and (completed_at is not null or played_fraction is not null)
+          and duration_seconds > 30
+          and coalesce(
+            played_fraction * duration_seconds,
+            cast(strftime('%s', completed_at) as integer) - cast(strftime('%s', started_at) as integer)
+          ) >= min(duration_seconds * :threshold_percent / 100.0, :threshold_seconds)
+          and cast(strftime('%s', started_at) as integer) > :since_posix_seconds
+          and listens.id not in (select listen_id from listen_users)
+        order by
+          started_at asc
+        limit
+          50;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, threshold_percent)?;
+    statement.bind(2, threshold_seconds)?;
+    statement.bind(3, since_posix_seconds)?;
+    let decode_row = |statement: &Statement| Ok(ListenToScrobble {
+        id: statement.read(0)?,
+        started_at_seconds: statement.read(1)?,
+        track_title: statement.read(2)?,
+        track_artist: statement.read(3)?,
+        album_title: statement.read(4)?,
+        album_artist: statement.read(5)?,
+        duration_seconds: statement.read(6)?,
+        track_number: statement.read(7)?,
+        track_mbid: statement.read(8)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn update_listen_scrobbled(tx: &mut Transaction, listen_id: i64, scrobbled_at: &str) -> Result<()> {
+    let sql = r#"
+        update listens set scrobbled_at = :scrobbled_at where id = :listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, scrobbled_at)?;
+    statement.bind(2, listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_listen_scrobbled' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ExportListen {
+    pub id: i64,
+    pub started_at: String,
+    pub completed_at: Option<String>,
+    pub track_id: i64,
+    pub album_id: i64,
+    pub album_artist_id: i64,
+    pub track_title: String,
+    pub track_artist: String,
+    pub album_title: String,
+    pub album_artist: String,
+    pub duration_seconds: i64,
+    pub track_number: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub source: String,
+    pub client: Option<String>,
+    pub scrobbled_at: Option<String>,
+    pub recording_mbid: Option<String>,
+    pub release_mbid: Option<String>,
+}
+
+/// Iterate all listens for exporting, oldest first. The MusicBrainz ids come
+/// from the tags of the file, if it is still there.
+pub fn iter_listens_for_export<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, ExportListen>> {
+    let sql = r#"
+        select
+            id
+          , started_at
+          , completed_at
+          , track_id
+          , album_id
+          , album_artist_id
+          , track_title
+          , track_artist
+          , album_title
+          , album_artist
+          , duration_seconds
+          , track_number
+          , disc_number
+          , source
+          , ( select client
+              from listen_clients
+              where listen_clients.listen_id = listens.id
+            ) as client
+          , scrobbled_at
+          , ( select value
+              from tags
+              where tags.file_id = listens.file_id
+                and tags.field_name = 'musicbrainz_trackid'
+              limit 1
+            ) as recording_mbid
+          , ( select value
+              from tags
+              where tags.file_id = listens.file_id
+                and tags.field_name = 'musicbrainz_albumid'
+              limit 1
+            ) as release_mbid
+        from
+          listens
+        order by
+          started_at asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(ExportListen {
+        id: statement.read(0)?,
+        started_at: statement.read(1)?,
+        completed_at: statement.read(2)?,
+        track_id: statement.read(3)?,
+        album_id: statement.read(4)?,
+        album_artist_id: statement.read(5)?,
+        track_title: statement.read(6)?,
+        track_artist: statement.read(7)?,
+        album_title: statement.read(8)?,
+        album_artist: statement.read(9)?,
+        duration_seconds: statement.read(10)?,
+        track_number: statement.read(11)?,
+        disc_number: statement.read(12)?,
+        source: statement.read(13)?,
+        client: statement.read(14)?,
+        scrobbled_at: statement.read(15)?,
+        recording_mbid: statement.read(16)?,
+        release_mbid: statement.read(17)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct RepairListen {
+    pub id: i64,
+    pub started_at: String,
+    pub started_at_seconds: i64,
+    pub completed_at: Option<String>,
+    pub track_id: i64,
+    pub duration_seconds: i64,
+    pub source: String,
+    pub scrobbled_at: Option<String>,
+    pub is_skipped: i64,
+}
+
+/// Iterate all listens with the fields needed to find duplicates and orphans,
+/// ordered by start time.
+pub fn iter_listens_for_repair<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, RepairListen>> {
+    let sql = r#"
+        select
+            id
+          , started_at
+          , cast(strftime('%s', started_at) as integer) as started_at_seconds
+          , completed_at
+          , track_id
+          , duration_seconds
+          , source
+          , scrobbled_at
+          , exists (
+              select 1 from skips where skips.listen_id = listens.id
+            ) as is_skipped
+        from
+          listens
+        order by
+          started_at_seconds asc, id asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(RepairListen {
+        id: statement.read(0)?,
+        started_at: statement.read(1)?,
+        started_at_seconds: statement.read(2)?,
+        completed_at: statement.read(3)?,
+        track_id: statement.read(4)?,
+        duration_seconds: statement.read(5)?,
+        source: statement.read(6)?,
+        scrobbled_at: statement.read(7)?,
+        is_skipped: statement.read(8)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Fill the completion and scrobble time of a listen from a duplicate that we
+/// are about to delete, where the listen does not have them already.
+pub fn update_listen_merge(tx: &mut Transaction, listen_id: i64, completed_at: Option<&str>, scrobbled_at: Option<&str>) -> Result<()> {
+    let sql = r#"
+        update
+          listens
+        set
+          completed_at = coalesce(completed_at, :completed_at),
+          scrobbled_at = coalesce(scrobbled_at, :scrobbled_at)
+        where
+          id = :listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, completed_at)?;
+    statement.bind(2, scrobbled_at)?;
+    statement.bind(3, listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_listen_merge' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn update_listen_completed_at(tx: &mut Transaction, listen_id: i64, completed_at: &str) -> Result<()> {
+    let sql = r#"
+        update listens set completed_at = :completed_at where id = :listen_id and completed_at is null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, completed_at)?;
+    statement.bind(2, listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_listen_completed_at' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Move the client of a listen to the listen it is merged into. When both
+/// listens have a client, the client of the merged-into listen wins, and the
+/// other one needs to be deleted separately.
+pub fn update_listen_client_listen(tx: &mut Transaction, old_listen_id: i64, new_listen_id: i64) -> Result<()> {
+    let sql = r#"
+        update or ignore listen_clients set listen_id = :new_listen_id where listen_id = :old_listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, new_listen_id)?;
+    statement.bind(2, old_listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_listen_client_listen' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Like update_listen_client_listen, but for the user of the listen.
+pub fn update_listen_user_listen(tx: &mut Transaction, old_listen_id: i64, new_listen_id: i64) -> Result<()> {
+    let sql = r#"
+        update or ignore listen_users set listen_id = :new_listen_id where listen_id = :old_listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, new_listen_id)?;
+    statement.bind(2, old_listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_listen_user_listen' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn update_skips_listen(tx: &mut Transaction, old_listen_id: i64, new_listen_id: i64) -> Result<()> {
+    let sql = r#"
+        update skips set listen_id = :new_listen_id where listen_id = :old_listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, new_listen_id)?;
+    statement.bind(2, old_listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_skips_listen' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_listen(tx: &mut Transaction, listen_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from listens where id = :listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_listen' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_listen_client(tx: &mut Transaction, listen_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from listen_clients where listen_id = :listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_listen_client' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_listen_user(tx: &mut Transaction, listen_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from listen_users where listen_id = :listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_listen_user' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Iterate the files that have the given tag, with the tag value.
+pub fn iter_tag_values<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, field_name: &str) -> Result<Iter<'i, 'a, (i64, String)>> {
+    let sql = r#"
+        select file_id, value from tags where field_name = :field_name;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, field_name)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn select_album_loudness_lufs(tx: &mut Transaction, album_id: i64) -> Result<Option<f64>> {
+    let sql = r#"
+        select bs17704_loudness_lufs from album_loudness where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_album_loudness_lufs' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+pub fn select_track_loudness_lufs(tx: &mut Transaction, track_id: i64) -> Result<Option<f64>> {
+    let sql = r#"
+        select bs17704_loudness_lufs from track_loudness where track_id = :track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_track_loudness_lufs' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+pub fn select_track_true_peak_dbtp(tx: &mut Transaction, track_id: i64) -> Result<Option<f64>> {
+    let sql = r#"
+        select true_peak_dbtp from track_loudness where track_id = :track_id and true_peak_dbtp is not null;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_track_true_peak_dbtp' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+/// Return the loudness and true peak of the album.
+pub fn select_album_dynamics(tx: &mut Transaction, album_id: i64) -> Result<Option<(f64, Option<f64>)>> {
+    let sql = r#"
+        select bs17704_loudness_lufs, true_peak_dbtp from album_loudness where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_album_dynamics' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+/// Return the loudness and true peak of the track.
+pub fn select_track_dynamics(tx: &mut Transaction, track_id: i64) -> Result<Option<(f64, Option<f64>)>> {
+    let sql = r#"
+        select bs17704_loudness_lufs, true_peak_dbtp from track_loudness where track_id = :track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_track_dynamics' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+/// Iterate the loudness and true peak of all albums. This includes albums that
+/// are no longer in the library.
+pub fn iter_album_dynamics<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, f64, Option<f64>)>> {
+    let sql = r#"
+        select album_id, bs17704_loudness_lufs, true_peak_dbtp from album_loudness;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+        statement.read(2)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Iterate the loudness and true peak of all tracks.
+pub fn iter_track_dynamics<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, f64, Option<f64>)>> {
+    let sql = r#"
+        select track_id, bs17704_loudness_lufs, true_peak_dbtp from track_loudness;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+        statement.read(2)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn select_track_waveform(tx: &mut Transaction, track_id: i64) -> Result<Option<Vec<u8>>> {
+    let sql = r#"
+        select data from waveforms where track_id = :track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_track_waveform' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+/// Delete data derived from files that no longer exist. The foreign keys
+/// cascade deletes of files to these tables, but only when foreign keys are
+/// enabled, and in the past, not every connection enabled them.
+pub fn delete_orphaned_file_data(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        delete from tags where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'delete_orphaned_file_data' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        delete from track_loudness where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'delete_orphaned_file_data' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        delete from album_loudness where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'delete_orphaned_file_data' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        delete from waveforms where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'delete_orphaned_file_data' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        delete from thumbnails where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'delete_orphaned_file_data' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        delete from acoustid_lookups where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    match statement.next()? {
+        Row => panic!("Query 'delete_orphaned_file_data' unexpectedly returned a row."),
+        Done => {}
+    }
+
+    let sql = r#"
+        delete from acoustid_proposals where file_id not in (select id from files);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_orphaned_file_data' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Return the sum of the sizes (in bytes) of all thumbnails.
+pub fn select_thumbnails_count_and_total_size(tx: &mut Transaction) -> Result<(i64, i64)> {
+    let sql = r#"
+        select count(*), sum(length(data)) from thumbnails;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_thumbnails_count_and_total_size' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_thumbnails_count_and_total_size' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+/// Iterate the ids of all albums that have a thumbnail, also those that are no
+/// longer in the library.
+pub fn iter_thumbnail_album_ids<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, i64>> {
+    let sql = r#"
+        select album_id from thumbnails;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Delete the thumbnail of the album, if it has one.
+pub fn delete_thumbnail(tx: &mut Transaction, album_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from thumbnails where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_thumbnail' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Delete all thumbnails, so the next thumbnail generation recreates them.
+pub fn delete_thumbnails(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        delete from thumbnails;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_thumbnails' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct Thumbnail {
+    pub album_id: i64,
+    pub data: Vec<u8>,
+}
+
+pub fn iter_thumbnails<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, Thumbnail>> {
+    let sql = r#"
+        select album_id, data from thumbnails;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(Thumbnail {
+        album_id: statement.read(0)?,
+        data: statement.read(1)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Return whether a thumbnail for the album exists (1 if it does, 0 otherwise).
+pub fn select_thumbnail_exists(tx: &mut Transaction, album_id: i64) -> Result<i64> {
+    let sql = r#"
+        select count(*) from thumbnails where album_id = :album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_thumbnail_exists' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_thumbnail_exists' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+/// For every album, return the earliest listen in the listens table.
+///
+/// Yields tuples `(album_id, started_at_iso8601)`.
+pub fn iter_album_first_listens<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, String)>> {
+    let sql = r#"
+        select
+          -- We rely on the fact here that asciibetical sorting of ISO-8601 strings
+          -- with the same time zone offset is also chronological, and our listens all
+          -- have Z suffix (+00 UTC offset).
+          album_id, min(started_at)
+        from
+          listens
+        group by
+          album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct TrackPlayStats {
+    pub track_id: i64,
+    pub play_count: i64,
+    pub last_played_at: String,
+    pub user_name: Option<String>,
+}
+
+/// For every user and track, return the number of listens, and the most recent one.
+pub fn iter_track_play_stats<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, TrackPlayStats>> {
+    let sql = r#"
+        select
+            track_id
+          , count(*) as play_count
+          , max(started_at) as last_played_at
+          , ( select user_name
+              from listen_users
+              where listen_users.listen_id = listens.id
+            ) as user_name
+        from
+          listens
+        group by
+          user_name, track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(TrackPlayStats {
+        track_id: statement.read(0)?,
+        play_count: statement.read(1)?,
+        last_played_at: statement.read(2)?,
+        user_name: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct AlbumPlayStats {
+    pub album_id: i64,
+    pub play_count: i64,
+    pub last_played_at: String,
+    pub user_name: Option<String>,
+}
+
+pub fn iter_album_play_stats<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, AlbumPlayStats>> {
+    let sql = r#"
+        select
+            album_id
+          , count(*) as play_count
+          , max(started_at) as last_played_at
+          , ( select user_name
+              from listen_users
+              where listen_users.listen_id = listens.id
+            ) as user_name
+        from
+          listens
+        group by
+          user_name, album_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(AlbumPlayStats {
+        album_id: statement.read(0)?,
+        play_count: statement.read(1)?,
+        last_played_at: statement.read(2)?,
+        user_name: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ArtistPlayStats {
+    pub album_artist_id: i64,
+    pub play_count: i64,
+    pub last_played_at: String,
+    pub user_name: Option<String>,
+}
+
+pub fn iter_artist_play_stats<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, ArtistPlayStats>> {
+    let sql = r#"
+        select
+            album_artist_id
+          , count(*) as play_count
+          , max(started_at) as last_played_at
+          , ( select user_name
+              from listen_users
+              where listen_users.listen_id = listens.id
+            ) as user_name
+        from
+          listens
+        group by
+          user_name, album_artist_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(ArtistPlayStats {
+        album_artist_id: statement.read(0)?,
+        play_count: statement.read(1)?,
+        last_played_at: statement.read(2)?,
+        user_name: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Record the import time of an album, unless we have one for it already.
+pub fn insert_album_import(tx: &mut Transaction, album_id: i64, imported_at: &str) -> Result<()> {
+    let sql = r#"
+        insert or ignore into
+          album_imports (album_id, imported_at)
+        values
+          (:album_id, :imported_at);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    statement.bind(2, imported_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_album_import' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Return the import time of every album that was ever imported.
+///
+/// Yields tuples `(album_id, imported_at_iso8601)`.
+pub fn iter_album_imports<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, String)>> {
+    let sql = r#"
+        select
+          album_id, imported_at
+        from
+          album_imports;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Insert a rating for a given track.
+///
+/// When the `created_at` timestamp is not unique for the user, this replaces the
+/// previous rating that was present for that timestamp. This might happen when
+/// the user edits the rating in quick succession; then we only store the last
+/// write. The user is NULL for the default user.
+pub fn insert_or_replace_rating(tx: &mut Transaction, track_id: i64, created_at: &str, rating: i64, user: Option<&str>) -> Result<()> {
+    let sql = r#"
+        insert or replace into
+          ratings (track_id, created_at, rating, source, user_name)
+        values
+          (:track_id, :created_at, :rating, 'musium', :user);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, created_at)?;
+    statement.bind(3, rating)?;
+    statement.bind(4, user)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_or_replace_rating' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Backfill a rating for a given track.
+///
+/// The timestamp must be unique on the second.
+pub fn insert_rating(tx: &mut Transaction, track_id: i64, created_at: &str, rating: i64, source: &str) -> Result<()> {
+    let sql = r#"
+        insert into
+          ratings (track_id, created_at, rating, source)
+        values
+          (:track_id, :created_at, :rating, :source);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, created_at)?;
+    statement.bind(3, rating)?;
+    statement.bind(4, source)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_rating' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct TrackRating {
+    pub id: i64,
+    pub track_id: i64,
+    pub rating: i64,
+    pub user_name: Option<String>,
+}
+
+pub fn iter_ratings<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, TrackRating>> {
+    let sql = r#"
+        select
+            id
+          , track_id
+          , rating
+          , user_name
+        from
+          ratings
+        order by
+          -- Order by ascending creation time to ensure we can clamp to rating ranges,
+          -- should we need to.
+          cast(strftime('%s', created_at) as integer) asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(TrackRating {
+        id: statement.read(0)?,
+        track_id: statement.read(1)?,
+        rating: statement.read(2)?,
+        user_name: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Insert a rating for a given album.
+///
+/// Like `insert_or_replace_rating`, but for albums.
+pub fn insert_or_replace_album_rating(tx: &mut Transaction, album_id: i64, created_at: &str, rating: i64, user: Option<&str>) -> Result<()> {
+    let sql = r#"
+        insert or replace into
+          album_ratings (album_id, created_at, rating, source, user_name)
+        values
+          (:album_id, :created_at, :rating, 'musium', :user);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    statement.bind(2, created_at)?;
+    statement.bind(3, rating)?;
+    statement.bind(4, user)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_or_replace_album_rating' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct AlbumRating {
+    pub id: i64,
+    pub album_id: i64,
+    pub rating: i64,
+    pub user_name: Option<String>,
+}
+
+pub fn iter_album_ratings<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, AlbumRating>> {
+    let sql = r#"
+        select
+            id
+          , album_id
+          , rating
+          , user_name
+        from
+          album_ratings
+        order by
+          created_at asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(AlbumRating {
+        id: statement.read(0)?,
+        album_id: statement.read(1)?,
+        rating: statement.read(2)?,
+        user_name: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Insert a rating for a given album artist.
+///
+/// Like `insert_or_replace_rating`, but for artists.
+pub fn insert_or_replace_artist_rating(tx: &mut Transaction, artist_id: i64, created_at: &str, rating: i64, user: Option<&str>) -> Result<()> {
+    let sql = r#"
+        insert or replace into
+          artist_ratings (artist_id, created_at, rating, source, user_name)
+        values
+          (:artist_id, :created_at, :rating, 'musium', :user);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, artist_id)?;
+    statement.bind(2, created_at)?;
+    statement.bind(3, rating)?;
+    statement.bind(4, user)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_or_replace_artist_rating' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct ArtistRating {
+    pub id: i64,
+    pub artist_id: i64,
+    pub rating: i64,
+    pub user_name: Option<String>,
+}
+
+pub fn iter_artist_ratings<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, ArtistRating>> {
+    let sql = r#"
+        select
+            id
+          , artist_id
+          , rating
+          , user_name
+        from
+          artist_ratings
+        order by
+          created_at asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(ArtistRating {
+        id: statement.read(0)?,
+        artist_id: statement.read(1)?,
+        rating: statement.read(2)?,
+        user_name: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Exclude a track from shuffling for the user. Excluding it twice is a no-op.
+pub fn insert_track_exclusion(tx: &mut Transaction, track_id: i64, created_at: &str, user: Option<&str>) -> Result<()> {
+    let sql = r#"
+        insert or ignore into
+          track_exclusions (track_id, created_at, user_name)
+        values
+          (:track_id, :created_at, :user);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, created_at)?;
+    statement.bind(3, user)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_track_exclusion' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_track_exclusion(tx: &mut Transaction, track_id: i64, user: Option<&str>) -> Result<()> {
+    let sql = r#"
+        delete from track_exclusions where track_id = :track_id and user_name is :user;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    statement.bind(2, user)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_track_exclusion' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn iter_track_exclusions<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, Option<String>)>> {
+    let sql = r#"
+        select track_id, user_name from track_exclusions;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Like `insert_track_exclusion`, but for albums.
+pub fn insert_album_exclusion(tx: &mut Transaction, album_id: i64, created_at: &str, user: Option<&str>) -> Result<()> {
+    let sql = r#"
+        insert or ignore into
+          album_exclusions (album_id, created_at, user_name)
+        values
+          (:album_id, :created_at, :user);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    statement.bind(2, created_at)?;
+    statement.bind(3, user)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_album_exclusion' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_album_exclusion(tx: &mut Transaction, album_id: i64, user: Option<&str>) -> Result<()> {
+    let sql = r#"
+        delete from album_exclusions where album_id = :album_id and user_name is :user;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, album_id)?;
+    statement.bind(2, user)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_album_exclusion' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn iter_album_exclusions<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, Option<String>)>> {
+    let sql = r#"
+        select album_id, user_name from album_exclusions;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn insert_playlist(tx: &mut Transaction, name: &str, query: Option<&str>, created_at: &str, user: Option<&str>) -> Result<i64> {
+    let sql = r#"
+        insert into playlists (name, query, created_at, user_name)
+        values (:name, :query, :created_at, :user)
+        returning id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    statement.bind(2, query)?;
+    statement.bind(3, created_at)?;
+    statement.bind(4, user)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'insert_playlist' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'insert_playlist' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+pub fn update_playlist_name(tx: &mut Transaction, playlist_id: i64, name: &str) -> Result<()> {
+    let sql = r#"
+        update playlists set name = :name where id = :playlist_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    statement.bind(2, playlist_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_playlist_name' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Delete a playlist and its entries (through the cascade).
+pub fn delete_playlist(tx: &mut Transaction, playlist_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from playlists where id = :playlist_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, playlist_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_playlist' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn update_playlist_query(tx: &mut Transaction, playlist_id: i64, query: &str) -> Result<()> {
+    let sql = r#"
+        update playlists set query = :query where id = :playlist_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, query)?;
+    statement.bind(2, playlist_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_playlist_query' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn select_playlist_name(tx: &mut Transaction, playlist_id: i64) -> Result<Option<String>> {
+    let sql = r#"
+        select name from playlists where id = :playlist_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, playlist_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_playlist_name' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct PlaylistHeader {
+    pub name: String,
+    pub query: Option<String>,
+}
+
+/// Return the playlist, if it exists and belongs to the user.
+pub fn select_playlist(tx: &mut Transaction, playlist_id: i64, user: Option<&str>) -> Result<Option<PlaylistHeader>> {
+    let sql = r#"
+        select
+            name
+          , query
+        from
+          playlists
+        where
+          id = :playlist_id
+          and user_name is :user;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, playlist_id)?;
+    statement.bind(2, user)?;
+    let decode_row = |statement: &Statement| Ok(PlaylistHeader {
+        name: statement.read(0)?,
+        query: statement.read(1)?,
+    });
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_playlist' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct Playlist {
+    pub id: i64,
+    pub name: String,
+    pub query: Option<String>,
+    pub track_count: i64,
+}
+
+/// Iterate the playlists of the user, ordered by name.
+pub fn iter_playlists<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, user: Option<&str>) -> Result<Iter<'i, 'a, Playlist>> {
+    let sql = r#"
+        select
+            playlists.id
+          , playlists.name
+          , playlists.query
+          , count(playlist_entries.id) as track_count
+        from
+          playlists
+          left join playlist_entries on playlist_entries.playlist_id = playlists.id
+        where
+          playlists.user_name is :user
+        group by
+          playlists.id
+        order by
+          playlists.name asc, playlists.id asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, user)?;
+    let decode_row = |statement: &Statement| Ok(Playlist {
+        id: statement.read(0)?,
+        name: statement.read(1)?,
+        query: statement.read(2)?,
+        track_count: statement.read(3)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct PlaylistEntry {
+    pub id: i64,
+    pub track_id: i64,
+}
+
+/// Iterate the entries of a playlist, in playlist order.
+pub fn iter_playlist_entries<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, playlist_id: i64) -> Result<Iter<'i, 'a, PlaylistEntry>> {
+    let sql = r#"
+        select
+            id
+          , track_id
+        from
+          playlist_entries
+        where
+          playlist_id = :playlist_id
+        order by
+          position asc, id asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, playlist_id)?;
+    let decode_row = |statement: &Statement| Ok(PlaylistEntry {
+        id: statement.read(0)?,
+        track_id: statement.read(1)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Return the position one past the last entry of the playlist.
+pub fn select_playlist_next_position(tx: &mut Transaction, playlist_id: i64) -> Result<i64> {
+    let sql = r#"
+        select
+          coalesce(max(position) + 1, 0) as next_position
+        from
+          playlist_entries
+        where
+          playlist_id = :playlist_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, playlist_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'select_playlist_next_position' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'select_playlist_next_position' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+pub fn insert_playlist_entry(tx: &mut Transaction, playlist_id: i64, position: i64, track_id: i64) -> Result<i64> {
+    let sql = r#"
+        insert into
+          playlist_entries (playlist_id, position, track_id)
+        values
+          (:playlist_id, :position, :track_id)
+        returning id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, playlist_id)?;
+    statement.bind(2, position)?;
+    statement.bind(3, track_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'insert_playlist_entry' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'insert_playlist_entry' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+pub fn update_playlist_entry_position(tx: &mut Transaction, entry_id: i64, position: i64) -> Result<()> {
+    let sql = r#"
+        update playlist_entries set position = :position where id = :entry_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, position)?;
+    statement.bind(2, entry_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_playlist_entry_position' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_playlist_entry(tx: &mut Transaction, playlist_id: i64, entry_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from playlist_entries where playlist_id = :playlist_id and id = :entry_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, playlist_id)?;
+    statement.bind(2, entry_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_playlist_entry' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn insert_radio_station(tx: &mut Transaction, name: &str, url: &str, created_at: &str) -> Result<i64> {
+    let sql = r#"
+        insert into radio_stations (name, url, created_at)
+        values (:name, :url, :created_at)
+        returning id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, name)?;
+    statement.bind(2, url)?;
+    statement.bind(3, created_at)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'insert_radio_station' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'insert_radio_station' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+pub fn delete_radio_station(tx: &mut Transaction, station_id: i64) -> Result<()> {
+    let sql = r#"
+        delete from radio_stations where id = :station_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, station_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_radio_station' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn select_radio_station(tx: &mut Transaction, station_id: i64) -> Result<Option<(String, String)>> {
+    let sql = r#"
+        select name, url from radio_stations where id = :station_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, station_id)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = match statement.next()? {
+        Row => Some(decode_row(statement)?),
+        Done => None,
+    };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_radio_station' should return at most one row.");
+        }
+    }
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct RadioStation {
+    pub id: i64,
+    pub name: String,
+    pub url: String,
+}
+
+/// Iterate all radio stations, ordered by name.
+pub fn iter_radio_stations<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, RadioStation>> {
+    let sql = r#"
+        select
+            id
+          , name
+          , url
+        from
+          radio_stations
+        order by
+          name asc, id asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(RadioStation {
+        id: statement.read(0)?,
+        name: statement.read(1)?,
+        url: statement.read(2)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn insert_radio_listen(tx: &mut Transaction, started_at: &str, queue_id: i64, station_id: i64, station_name: &str, stream_title: Option<&str>) -> Result<i64> {
+    let sql = r#"
+        insert into
+          radio_listens (started_at, queue_id, station_id, station_name, stream_title)
+        values
+          (:started_at, :queue_id, :station_id, :station_name, :stream_title)
+        returning id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, started_at)?;
+    statement.bind(2, queue_id)?;
+    statement.bind(3, station_id)?;
+    statement.bind(4, station_name)?;
+    statement.bind(5, stream_title)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = match statement.next()? {
+        Row => decode_row(statement)?,
+        Done => panic!("Query 'insert_radio_listen' should return exactly one row."),
+    };
+    if statement.next()? != Done {
+        panic!("Query 'insert_radio_listen' should return exactly one row.");
+    }
+    Ok(result)
+}
+
+pub fn update_radio_listen_completed(tx: &mut Transaction, listen_id: i64, completed_at: &str) -> Result<()> {
+    let sql = r#"
+        update radio_listens set completed_at = :completed_at where id = :listen_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, completed_at)?;
+    statement.bind(2, listen_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_radio_listen_completed' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn delete_saved_queue(tx: &mut Transaction) -> Result<()> {
+    let sql = r#"
+        delete from saved_queue;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_saved_queue' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn insert_saved_queue_entry(tx: &mut Transaction, position: i64, track_id: Option<i64>, station_id: Option<i64>, position_ms: i64, client: Option<&str>, user: Option<&str>) -> Result<()> {
+    let sql = r#"
+        insert into
+          saved_queue (position, track_id, station_id, position_ms, client, user_name)
+        values
+          (:position, :track_id, :station_id, :position_ms, :client, :user);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, position)?;
+    statement.bind(2, track_id)?;
+    statement.bind(3, station_id)?;
+    statement.bind(4, position_ms)?;
+    statement.bind(5, client)?;
+    statement.bind(6, user)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_saved_queue_entry' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct SavedQueueEntry {
+    pub track_id: Option<i64>,
+    pub station_id: Option<i64>,
+    pub name: Option<String>,
+    pub url: Option<String>,
+    pub position_ms: i64,
+    pub client: Option<String>,
+    pub user_name: Option<String>,
+}
+
+/// Iterate the saved queue, in queue order. For radio stations, the name and
+/// url are NULL when the station was deleted in the meantime.
+pub fn iter_saved_queue<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, SavedQueueEntry>> {
+    let sql = r#"
+        select
+            saved_queue.track_id
+          , saved_queue.station_id
+          , radio_stations.name
+          , radio_stations.url
+          , saved_queue.position_ms
+          , saved_queue.client
+          , saved_queue.user_name
+        from
+          saved_queue
+          left join radio_stations on radio_stations.id = saved_queue.station_id
+        order by
+          saved_queue.position asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(SavedQueueEntry {
+        track_id: statement.read(0)?,
+        station_id: statement.read(1)?,
+        name: statement.read(2)?,
+        url: statement.read(3)?,
+        position_ms: statement.read(4)?,
+        client: statement.read(5)?,
+        user_name: statement.read(6)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct InsertMetadataEdit<'a> {
+    pub edited_at: &'a str,
+    pub file_id: i64,
+    pub track_id: i64,
+    pub field_name: &'a str,
+    pub old_value: Option<&'a str>,
+    pub new_value: Option<&'a str>,
+    pub wrote_tags: i64,
+    pub user_name: Option<&'a str>,
+}
+
+pub fn insert_metadata_edit(tx: &mut Transaction, edit: InsertMetadataEdit) -> Result<()> {
+    let sql = r#"
+        insert into metadata_edits
+        ( edited_at
+        , file_id
+        , track_id
+        , field_name
+        , old_value
+        , new_value
+        , wrote_tags
+        , user_name
+        )
+        values
+        ( :edited_at
+        , :file_id
+        , :track_id
+        , :field_name
+        , :old_value
+        , :new_value
+        , :wrote_tags
+        , :user_name
+        );
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, edit.edited_at)?;
+    statement.bind(2, edit.file_id)?;
+    statement.bind(3, edit.track_id)?;
+    statement.bind(4, edit.field_name)?;
+    statement.bind(5, edit.old_value)?;
+    statement.bind(6, edit.new_value)?;
+    statement.bind(7, edit.wrote_tags)?;
+    statement.bind(8, edit.user_name)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_metadata_edit' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct MetadataEdit {
+    pub id: i64,
+    pub edited_at: String,
+    pub track_id: i64,
+    pub field_name: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub wrote_tags: i64,
+    pub user_name: Option<String>,
 }
 
-pub fn iter_files<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, FileMetadata>> {
+/// Iterate the most recent metadata edits, newest first.
+pub fn iter_metadata_edits<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, limit: i64) -> Result<Iter<'i, 'a, MetadataEdit>> {
     let sql = r#"
         select
             id
-          , filename
-          , mtime
-          , streaminfo_channels
-          , streaminfo_bits_per_sample
-          , streaminfo_num_samples
-          , streaminfo_sample_rate
+          , edited_at
+          , track_id
+          , field_name
+          , old_value
+          , new_value
+          , wrote_tags
+          , user_name
         from
-          files
+          metadata_edits
         order by
-          filename asc;
+          id desc
+        limit
+          :limit;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    let decode_row = |statement: &Statement| Ok(FileMetadata {
+    statement.bind(1, limit)?;
+    let decode_row = |statement: &Statement| Ok(MetadataEdit {
         id: statement.read(0)?,
-        filename: statement.read(1)?,
-        mtime: statement.read(2)?,
-        streaminfo_channels: statement.read(3)?,
-        streaminfo_bits_per_sample: statement.read(4)?,
-        streaminfo_num_samples: statement.read(5)?,
-        streaminfo_sample_rate: statement.read(6)?,
+        edited_at: statement.read(1)?,
+        track_id: statement.read(2)?,
+        field_name: statement.read(3)?,
+        old_value: statement.read(4)?,
+        new_value: statement.read(5)?,
+        wrote_tags: statement.read(6)?,
+        user_name: statement.read(7)?,
     });
     let result = Iter { statement, decode_row };
     Ok(result)
 }
 
-/// Iterate all `(field_name, value)` pairs for the given file.
-pub fn iter_file_tags<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, file_id: i64) -> Result<Iter<'i, 'a, (String, String)>> {
+/// Yields tuples `(artist_id, canonical_artist_id, name, created_at)`.
+pub fn iter_artist_aliases<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, i64, String, String)>> {
     let sql = r#"
         select
-          field_name, value
+          artist_id, canonical_artist_id, name, created_at
         from
-          tags
-        where
-          file_id = :file_id
+          artist_aliases
         order by
-          -- We have to order by id, which is increasing with insert order, because some
-          -- tags can occur multiple times, and we have to preserve the order in which
-          -- we found them in the file.
-          id asc;
+          artist_id asc;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, file_id)?;
     let decode_row = |statement: &Statement| Ok((
         statement.read(0)?,
         statement.read(1)?,
+        statement.read(2)?,
+        statement.read(3)?,
 ));
     let result = Iter { statement, decode_row };
     Ok(result)
 }
 
-pub fn insert_album_thumbnail(tx: &mut Transaction, album_id: i64, file_id: i64, data: &[u8]) -> Result<()> {
+/// Merge an artist into the canonical artist, or change its canonical artist.
+pub fn insert_artist_alias(tx: &mut Transaction, artist_id: i64, canonical_artist_id: i64, name: &str, created_at: &str) -> Result<()> {
     let sql = r#"
-        insert into thumbnails (album_id, file_id, data)
-        values (:album_id, :file_id, :data)
-        on conflict (album_id) do update set data = :data;
+        insert into artist_aliases (artist_id, canonical_artist_id, name, created_at)
+        values (:artist_id, :canonical_artist_id, :name, :created_at)
+        on conflict (artist_id) do update
+        set canonical_artist_id = :canonical_artist_id, name = :name, created_at = :created_at;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, album_id)?;
-    statement.bind(2, file_id)?;
-    statement.bind(3, data)?;
+    statement.bind(1, artist_id)?;
+    statement.bind(2, canonical_artist_id)?;
+    statement.bind(3, name)?;
+    statement.bind(4, created_at)?;
     let result = match statement.next()? {
-        Row => panic!("Query 'insert_album_thumbnail' unexpectedly returned a row."),
+        Row => panic!("Query 'insert_artist_alias' unexpectedly returned a row."),
         Done => (),
     };
     Ok(result)
 }
 
-pub fn insert_album_loudness(tx: &mut Transaction, album_id: i64, file_id: i64, loudness: f64) -> Result<()> {
+/// When an artist that has aliases gets merged itself, its aliases follow it,
+/// so we never have to resolve chains of aliases.
+pub fn update_artist_alias_canonical(tx: &mut Transaction, old_canonical_artist_id: i64, new_canonical_artist_id: i64) -> Result<()> {
     let sql = r#"
-        insert into album_loudness (album_id, file_id, bs17704_loudness_lufs)
-        values (:album_id, :file_id, :loudness)
-        on conflict (album_id) do update set bs17704_loudness_lufs = :loudness;
+        update artist_aliases
+        set canonical_artist_id = :new_canonical_artist_id
+        where canonical_artist_id = :old_canonical_artist_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, album_id)?;
-    statement.bind(2, file_id)?;
-    statement.bind(3, loudness)?;
+    statement.bind(1, new_canonical_artist_id)?;
+    statement.bind(2, old_canonical_artist_id)?;
     let result = match statement.next()? {
-        Row => panic!("Query 'insert_album_loudness' unexpectedly returned a row."),
+        Row => panic!("Query 'update_artist_alias_canonical' unexpectedly returned a row."),
         Done => (),
     };
     Ok(result)
 }
 
-pub fn insert_track_loudness(tx: &mut Transaction, track_id: i64, file_id: i64, loudness: f64) -> Result<()> {
+pub fn delete_artist_alias(tx: &mut Transaction, artist_id: i64) -> Result<()> {
     let sql = r#"
-        insert into track_loudness (track_id, file_id, bs17704_loudness_lufs)
-        values (:track_id, :file_id, :loudness)
-        on conflict (track_id) do update set bs17704_loudness_lufs = :loudness;
+        delete from artist_aliases where artist_id = :artist_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, track_id)?;
-    statement.bind(2, file_id)?;
-    statement.bind(3, loudness)?;
+    statement.bind(1, artist_id)?;
     let result = match statement.next()? {
-        Row => panic!("Query 'insert_track_loudness' unexpectedly returned a row."),
+        Row => panic!("Query 'delete_artist_alias' unexpectedly returned a row."),
         Done => (),
     };
     Ok(result)
 }
 
-pub fn insert_track_waveform(tx: &mut Transaction, track_id: i64, file_id: i64, data: &[u8]) -> Result<()> {
+/// Attribute the listens of one album artist to another.
+pub fn update_listens_album_artist(tx: &mut Transaction, old_artist_id: i64, new_artist_id: i64) -> Result<()> {
     let sql = r#"
-        insert into waveforms (track_id, file_id, data)
-        values (:track_id, :file_id, :data)
-        on conflict (track_id) do update set data = :data;
+        update listens
+        set album_artist_id = :new_artist_id
+        where album_artist_id = :old_artist_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, track_id)?;
-    statement.bind(2, file_id)?;
-    statement.bind(3, data)?;
+    statement.bind(1, new_artist_id)?;
+    statement.bind(2, old_artist_id)?;
     let result = match statement.next()? {
-        Row => panic!("Query 'insert_track_waveform' unexpectedly returned a row."),
+        Row => panic!("Query 'update_listens_album_artist' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Attribute the listens of one album to another album artist, when we undo a
+/// merge and the album belongs to the alias again.
+pub fn update_listens_album_artist_for_album(tx: &mut Transaction, album_id: i64, old_artist_id: i64, new_artist_id: i64) -> Result<()> {
+    let sql = r#"
+        update listens
+        set album_artist_id = :new_artist_id
+        where album_id = :album_id and album_artist_id = :old_artist_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, new_artist_id)?;
+    statement.bind(2, album_id)?;
+    statement.bind(3, old_artist_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'update_listens_album_artist_for_album' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+/// Record that we looked up the fingerprint of the file.
+pub fn insert_acoustid_lookup(tx: &mut Transaction, file_id: i64, looked_up_at: &str) -> Result<()> {
+    let sql = r#"
+        insert into acoustid_lookups (file_id, looked_up_at)
+        values (:file_id, :looked_up_at)
+        on conflict (file_id) do update set looked_up_at = :looked_up_at;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, file_id)?;
+    statement.bind(2, looked_up_at)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_acoustid_lookup' unexpectedly returned a row."),
         Done => (),
     };
     Ok(result)
 }
 
+/// Return the ids of all files that we looked up already.
+pub fn iter_acoustid_lookups<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, i64>> {
+    let sql = r#"
+        select file_id from acoustid_lookups;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
 #[derive(Debug)]
-pub struct Listen<'a> {
-    pub started_at: &'a str,
+pub struct InsertAcoustidProposal<'a> {
     pub file_id: i64,
-    pub queue_id: i64,
-    pub track_id: i64,
-    pub album_id: i64,
-    pub album_artist_id: i64,
-    pub track_title: &'a str,
-    pub track_artist: &'a str,
-    pub album_title: &'a str,
-    pub album_artist: &'a str,
-    pub duration_seconds: i64,
-    pub track_number: i64,
-    pub disc_number: i64,
+    pub acoustid: &'a str,
+    pub score: f64,
+    pub musicbrainz_trackid: &'a str,
+    pub title: &'a str,
+    pub artist: &'a str,
+    pub album: &'a str,
+    pub musicbrainz_albumid: &'a str,
+    pub albumartist: &'a str,
+    pub musicbrainz_albumartistid: &'a str,
+    pub tracknumber: i64,
+    pub discnumber: i64,
+    pub originaldate: &'a str,
 }
 
-pub fn insert_listen_started(tx: &mut Transaction, listen: Listen) -> Result<i64> {
+/// Store the tags that we propose for a file, pending review.
+pub fn insert_acoustid_proposal(tx: &mut Transaction, proposal: InsertAcoustidProposal) -> Result<()> {
     let sql = r#"
-        insert into
-          listens
-          ( started_at
-          , file_id
-          , queue_id
-          , track_id
-          , album_id
-          , album_artist_id
-          , track_title
-          , track_artist
-          , album_title
-          , album_artist
-          , duration_seconds
-          , track_number
-          , disc_number
-          , source
-          )
+        insert into acoustid_proposals
+        ( file_id
+        , acoustid
+        , score
+        , musicbrainz_trackid
+        , title
+        , artist
+        , album
+        , musicbrainz_albumid
+        , albumartist
+        , musicbrainz_albumartistid
+        , tracknumber
+        , discnumber
+        , originaldate
+        , status
+        )
         values
-          ( :started_at
-          , :file_id
-          , :queue_id
-          , :track_id
-          , :album_id
-          , :album_artist_id
-          , :track_title
-          , :track_artist
-          , :album_title
-          , :album_artist
-          , :duration_seconds
-          , :track_number
-          , :disc_number
-          , 'musium'
-          )
-        returning
-          id;
+        ( :file_id
+        , :acoustid
+        , :score
+        , :musicbrainz_trackid
+        , :title
+        , :artist
+        , :album
+        , :musicbrainz_albumid
+        , :albumartist
+        , :musicbrainz_albumartistid
+        , :tracknumber
+        , :discnumber
+        , :originaldate
+        , 'pending'
+        )
+        on conflict (file_id) do nothing;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, proposal.file_id)?;
+    statement.bind(2, proposal.acoustid)?;
+    statement.bind(3, proposal.score)?;
+    statement.bind(4, proposal.musicbrainz_trackid)?;
+    statement.bind(5, proposal.title)?;
+    statement.bind(6, proposal.artist)?;
+    statement.bind(7, proposal.album)?;
+    statement.bind(8, proposal.musicbrainz_albumid)?;
+    statement.bind(9, proposal.albumartist)?;
+    statement.bind(10, proposal.musicbrainz_albumartistid)?;
+    statement.bind(11, proposal.tracknumber)?;
+    statement.bind(12, proposal.discnumber)?;
+    statement.bind(13, proposal.originaldate)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_acoustid_proposal' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub struct AcoustidProposal {
+    pub file_id: i64,
+    pub filename: String,
+    pub acoustid: String,
+    pub score: f64,
+    pub musicbrainz_trackid: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub musicbrainz_albumid: String,
+    pub albumartist: String,
+    pub musicbrainz_albumartistid: String,
+    pub tracknumber: i64,
+    pub discnumber: i64,
+    pub originaldate: String,
+    pub status: String,
+}
+
+/// Iterate the proposals that await review, ordered by filename.
+pub fn iter_acoustid_proposals_pending<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, AcoustidProposal>> {
+    let sql = r#"
+        select
+            acoustid_proposals.file_id
+          , files.filename
+          , acoustid_proposals.acoustid
+          , acoustid_proposals.score
+          , acoustid_proposals.musicbrainz_trackid
+          , acoustid_proposals.title
+          , acoustid_proposals.artist
+          , acoustid_proposals.album
+          , acoustid_proposals.musicbrainz_albumid
+          , acoustid_proposals.albumartist
+          , acoustid_proposals.musicbrainz_albumartistid
+          , acoustid_proposals.tracknumber
+          , acoustid_proposals.discnumber
+          , acoustid_proposals.originaldate
+          , acoustid_proposals.status
+        from
+          acoustid_proposals
+          inner join files on files.id = acoustid_proposals.file_id
+        where
+          acoustid_proposals.status = 'pending'
+        order by
+          files.filename asc;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok(AcoustidProposal {
+        file_id: statement.read(0)?,
+        filename: statement.read(1)?,
+        acoustid: statement.read(2)?,
+        score: statement.read(3)?,
+        musicbrainz_trackid: statement.read(4)?,
+        title: statement.read(5)?,
+        artist: statement.read(6)?,
+        album: statement.read(7)?,
+        musicbrainz_albumid: statement.read(8)?,
+        albumartist: statement.read(9)?,
+        musicbrainz_albumartistid: statement.read(10)?,
+        tracknumber: statement.read(11)?,
+        discnumber: statement.read(12)?,
+        originaldate: statement.read(13)?,
+        status: statement.read(14)?,
+    });
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+pub fn select_acoustid_proposal(tx: &mut Transaction, file_id: i64) -> Result<Option<AcoustidProposal>> {
+    let sql = r#"
+        select
+            acoustid_proposals.file_id
+          , files.filename
+          , acoustid_proposals.acoustid
+          , acoustid_proposals.score
+          , acoustid_proposals.musicbrainz_trackid
+          , acoustid_proposals.title
+          , acoustid_proposals.artist
+          , acoustid_proposals.album
+          , acoustid_proposals.musicbrainz_albumid
+          , acoustid_proposals.albumartist
+          , acoustid_proposals.musicbrainz_albumartistid
+          , acoustid_proposals.tracknumber
+          , acoustid_proposals.discnumber
+          , acoustid_proposals.originaldate
+          , acoustid_proposals.status
+        from
+          acoustid_proposals
+          inner join files on files.id = acoustid_proposals.file_id
+        where
+          acoustid_proposals.file_id = :file_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, listen.started_at)?;
-    statement.bind(2, listen.file_id)?;
-    statement.bind(3, listen.queue_id)?;
-    statement.bind(4, listen.track_id)?;
-    statement.bind(5, listen.album_id)?;
-    statement.bind(6, listen.album_artist_id)?;
-    statement.bind(7, listen.track_title)?;
-    statement.bind(8, listen.track_artist)?;
-    statement.bind(9, listen.album_title)?;
-    statement.bind(10, listen.album_artist)?;
-    statement.bind(11, listen.duration_seconds)?;
-    statement.bind(12, listen.track_number)?;
-    statement.bind(13, listen.disc_number)?;
-    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    statement.bind(1, file_id)?;
+    let decode_row = |statement: &Statement| Ok(AcoustidProposal {
+        file_id: statement.read(0)?,
+        filename: statement.read(1)?,
+        acoustid: statement.read(2)?,
+        score: statement.read(3)?,
+        musicbrainz_trackid: statement.read(4)?,
+        title: statement.read(5)?,
+        artist: statement.read(6)?,
+        album: statement.read(7)?,
+        musicbrainz_albumid: statement.read(8)?,
+        albumartist: statement.read(9)?,
+        musicbrainz_albumartistid: statement.read(10)?,
+        tracknumber: statement.read(11)?,
+        discnumber: statement.read(12)?,
+        originaldate: statement.read(13)?,
+        status: statement.read(14)?,
+    });
     let result = match statement.next()? {
-        Row => decode_row(statement)?,
-        Done => panic!("Query 'insert_listen_started' should return exactly one row."),
+        Row => Some(decode_row(statement)?),
+        Done => None,
     };
-    if statement.next()? != Done {
-        panic!("Query 'insert_listen_started' should return exactly one row.");
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_acoustid_proposal' should return at most one row.");
+        }
     }
     Ok(result)
 }
 
-pub fn update_listen_completed(tx: &mut Transaction, listen_id: i64, queue_id: i64, track_id: i64, completed_at: &str) -> Result<()> {
+/// Set the status of a proposal to 'accepted' or 'rejected'.
+pub fn update_acoustid_proposal_status(tx: &mut Transaction, file_id: i64, status: &str) -> Result<()> {
     let sql = r#"
-        update listens
-          set completed_at = :completed_at
-        where
-          id = :listen_id
-          and queue_id = :queue_id
-          and track_id = :track_id;
+        update acoustid_proposals set status = :status where file_id = :file_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, completed_at)?;
-    statement.bind(2, listen_id)?;
-    statement.bind(3, queue_id)?;
-    statement.bind(4, track_id)?;
+    statement.bind(1, status)?;
+    statement.bind(2, file_id)?;
     let result = match statement.next()? {
-        Row => panic!("Query 'update_listen_completed' unexpectedly returned a row."),
+        Row => panic!("Query 'update_acoustid_proposal_status' unexpectedly returned a row."),
         Done => (),
     };
     Ok(result)
 }
 
-pub fn select_album_loudness_lufs(tx: &mut Transaction, album_id: i64) -> Result<Option<f64>> {
+pub fn insert_or_replace_resume_position(tx: &mut Transaction, track_id: i64, position_ms: i64, saved_at: &str) -> Result<()> {
     let sql = r#"
-        select bs17704_loudness_lufs from album_loudness where album_id = :album_id;
+        insert or replace into
+          resume_positions (track_id, position_ms, saved_at)
+        values
+          (:track_id, :position_ms, :saved_at);
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, album_id)?;
-    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    statement.bind(1, track_id)?;
+    statement.bind(2, position_ms)?;
+    statement.bind(3, saved_at)?;
     let result = match statement.next()? {
-        Row => Some(decode_row(statement)?),
-        Done => None,
+        Row => panic!("Query 'insert_or_replace_resume_position' unexpectedly returned a row."),
+        Done => (),
     };
-    if result.is_some() {
-        if statement.next()? != Done {
-            panic!("Query 'select_album_loudness_lufs' should return at most one row.");
-        }
-    }
     Ok(result)
 }
 
-pub fn select_track_loudness_lufs(tx: &mut Transaction, track_id: i64) -> Result<Option<f64>> {
+pub fn delete_resume_position(tx: &mut Transaction, track_id: i64) -> Result<()> {
     let sql = r#"
-        select bs17704_loudness_lufs from track_loudness where track_id = :track_id;
+        delete from resume_positions where track_id = :track_id;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, track_id)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_resume_position' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
+}
+
+pub fn select_resume_position(tx: &mut Transaction, track_id: i64) -> Result<Option<i64>> {
+    let sql = r#"
+        select position_ms from resume_positions where track_id = :track_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
@@ -763,22 +5735,42 @@ pub fn select_track_loudness_lufs(tx: &mut Transaction, track_id: i64) -> Result
     };
     if result.is_some() {
         if statement.next()? != Done {
-            panic!("Query 'select_track_loudness_lufs' should return at most one row.");
+            panic!("Query 'select_resume_position' should return at most one row.");
         }
     }
     Ok(result)
 }
 
-pub fn select_track_waveform(tx: &mut Transaction, track_id: i64) -> Result<Option<Vec<u8>>> {
+/// Iterate all resume positions as (track_id, position_ms) pairs.
+pub fn iter_resume_positions<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, i64)>> {
     let sql = r#"
-        select data from waveforms where track_id = :track_id;
+        select track_id, position_ms from resume_positions;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, track_id)?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Return when the cached data of the artist expires, if we have data from the source.
+pub fn select_artist_enrichment_expiry(tx: &mut Transaction, artist_id: i64, source: &str) -> Result<Option<i64>> {
+    let sql = r#"
+        select expires_at_seconds from artist_enrichment where artist_id = :artist_id and source = :source;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, artist_id)?;
+    statement.bind(2, source)?;
     let decode_row = |statement: &Statement| Ok(statement.read(0)?);
     let result = match statement.next()? {
         Row => Some(decode_row(statement)?),
@@ -786,193 +5778,217 @@ pub fn select_track_waveform(tx: &mut Transaction, track_id: i64) -> Result<Opti
     };
     if result.is_some() {
         if statement.next()? != Done {
-            panic!("Query 'select_track_waveform' should return at most one row.");
+            panic!("Query 'select_artist_enrichment_expiry' should return at most one row.");
         }
     }
     Ok(result)
 }
 
-/// Return the sum of the sizes (in bytes) of all thumbnails.
-pub fn select_thumbnails_count_and_total_size(tx: &mut Transaction) -> Result<(i64, i64)> {
+/// Delete the cached data of the artist, including the similar artists and tags.
+pub fn delete_artist_enrichment(tx: &mut Transaction, artist_id: i64) -> Result<()> {
     let sql = r#"
-        select count(*), sum(length(data)) from thumbnails;
+        delete from artist_enrichment where artist_id = :artist_id;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    let decode_row = |statement: &Statement| Ok((
-        statement.read(0)?,
-        statement.read(1)?,
-));
+    statement.bind(1, artist_id)?;
     let result = match statement.next()? {
-        Row => decode_row(statement)?,
-        Done => panic!("Query 'select_thumbnails_count_and_total_size' should return exactly one row."),
+        Row => panic!("Query 'delete_artist_enrichment' unexpectedly returned a row."),
+        Done => (),
     };
-    if statement.next()? != Done {
-        panic!("Query 'select_thumbnails_count_and_total_size' should return exactly one row.");
-    }
     Ok(result)
 }
 
-#[derive(Debug)]
-pub struct Thumbnail {
-    pub album_id: i64,
-    pub data: Vec<u8>,
+pub fn insert_artist_enrichment(tx: &mut Transaction, artist_id: i64, source: &str, expires_at_seconds: i64) -> Result<()> {
+    let sql = r#"
+        insert into
+          artist_enrichment (artist_id, source, expires_at_seconds)
+        values
+          (:artist_id, :source, :expires_at_seconds);
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, artist_id)?;
+    statement.bind(2, source)?;
+    statement.bind(3, expires_at_seconds)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_artist_enrichment' unexpectedly returned a row."),
+        Done => (),
+    };
+    Ok(result)
 }
 
-pub fn iter_thumbnails<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, Thumbnail>> {
+pub fn insert_artist_enrichment_similar(tx: &mut Transaction, artist_id: i64, rank: i64, name: &str, mbid: Option<&str>, score: f64) -> Result<()> {
     let sql = r#"
-        select album_id, data from thumbnails;
+        insert into
+          artist_enrichment_similar (artist_id, rank, name, mbid, score)
+        values
+          (:artist_id, :rank, :name, :mbid, :score);
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    let decode_row = |statement: &Statement| Ok(Thumbnail {
-        album_id: statement.read(0)?,
-        data: statement.read(1)?,
-    });
-    let result = Iter { statement, decode_row };
+    statement.bind(1, artist_id)?;
+    statement.bind(2, rank)?;
+    statement.bind(3, name)?;
+    statement.bind(4, mbid)?;
+    statement.bind(5, score)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'insert_artist_enrichment_similar' unexpectedly returned a row."),
+        Done => (),
+    };
     Ok(result)
 }
 
-/// Return whether a thumbnail for the album exists (1 if it does, 0 otherwise).
-pub fn select_thumbnail_exists(tx: &mut Transaction, album_id: i64) -> Result<i64> {
+pub fn insert_artist_enrichment_tag(tx: &mut Transaction, artist_id: i64, rank: i64, tag: &str) -> Result<()> {
     let sql = r#"
-        select count(*) from thumbnails where album_id = :album_id;
+        insert into
+          artist_enrichment_tags (artist_id, rank, tag)
+        values
+          (:artist_id, :rank, :tag);
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, album_id)?;
-    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    statement.bind(1, artist_id)?;
+    statement.bind(2, rank)?;
+    statement.bind(3, tag)?;
     let result = match statement.next()? {
-        Row => decode_row(statement)?,
-        Done => panic!("Query 'select_thumbnail_exists' should return exactly one row."),
+        Row => panic!("Query 'insert_artist_enrichment_tag' unexpectedly returned a row."),
+        Done => (),
     };
-    if statement.next()? != Done {
-        panic!("Query 'select_thumbnail_exists' should return exactly one row.");
-    }
     Ok(result)
 }
 
-/// For every album, return the earliest listen in the listens table.
-///
-/// Yields tuples `(album_id, started_at_iso8601)`.
-pub fn iter_album_first_listens<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, String)>> {
+/// Iterate the cached similar artists as (name, mbid, score), most similar first.
+pub fn iter_artist_enrichment_similar<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, artist_id: i64) -> Result<Iter<'i, 'a, (String, Option<String>, f64)>> {
     let sql = r#"
-        select
-          -- We rely on the fact here that asciibetical sorting of ISO-8601 strings
-          -- with the same time zone offset is also chronological, and our listens all
-          -- have Z suffix (+00 UTC offset).
-          album_id, min(started_at)
-        from
-          listens
-        group by
-          album_id;
+        select name, mbid, score from artist_enrichment_similar where artist_id = :artist_id order by rank;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
+    statement.bind(1, artist_id)?;
     let decode_row = |statement: &Statement| Ok((
         statement.read(0)?,
         statement.read(1)?,
+        statement.read(2)?,
 ));
     let result = Iter { statement, decode_row };
     Ok(result)
 }
 
-/// Insert a rating for a given track.
-///
-/// When the `created_at` timestamp is not unique, this replaces the previous
-/// rating that was present for that timestamp. This might happen when the user
-/// edits the rating in quick succession; then we only store the last write.
-pub fn insert_or_replace_rating(tx: &mut Transaction, track_id: i64, created_at: &str, rating: i64) -> Result<()> {
+/// Iterate the cached tags of the artist, most applicable first.
+pub fn iter_artist_enrichment_tags<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>, artist_id: i64) -> Result<Iter<'i, 'a, String>> {
     let sql = r#"
-        insert or replace into
-          ratings (track_id, created_at, rating, source)
-        values
-          (:track_id, :created_at, :rating, 'musium');
+        select tag from artist_enrichment_tags where artist_id = :artist_id order by rank;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, track_id)?;
-    statement.bind(2, created_at)?;
-    statement.bind(3, rating)?;
+    statement.bind(1, artist_id)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Return a MusicBrainz id of an album artist that starts and ends like the
+/// pattern, for example 'f6f2326f-%-e235b25508e8'. Artist ids are made of the
+/// first and last 8 digits of the MusicBrainz id, so this finds the full id.
+pub fn select_album_artist_mbid(tx: &mut Transaction, pattern: &str) -> Result<Option<String>> {
+    let sql = r#"
+        select value from tags where field_name = 'musicbrainz_albumartistid' and value like :pattern limit 1;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    statement.bind(1, pattern)?;
+    let decode_row = |statement: &Statement| Ok(statement.read(0)?);
     let result = match statement.next()? {
-        Row => panic!("Query 'insert_or_replace_rating' unexpectedly returned a row."),
-        Done => (),
+        Row => Some(decode_row(statement)?),
+        Done => None,
     };
+    if result.is_some() {
+        if statement.next()? != Done {
+            panic!("Query 'select_album_artist_mbid' should return at most one row.");
+        }
+    }
     Ok(result)
 }
 
-/// Backfill a rating for a given track.
-///
-/// The timestamp must be unique on the second.
-pub fn insert_rating(tx: &mut Transaction, track_id: i64, created_at: &str, rating: i64, source: &str) -> Result<()> {
+/// Iterate all files as `(file_id, filename)`, in the order of their path.
+pub fn iter_files_for_verification<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, (i64, String)>> {
     let sql = r#"
-        insert into
-          ratings (track_id, created_at, rating, source)
+        select id, filename from files order by filename;
+        "#;
+    let statement = match tx.statements.entry(sql.as_ptr()) {
+        Occupied(entry) => entry.into_mut(),
+        Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
+    };
+    statement.reset()?;
+    let decode_row = |statement: &Statement| Ok((
+        statement.read(0)?,
+        statement.read(1)?,
+));
+    let result = Iter { statement, decode_row };
+    Ok(result)
+}
+
+/// Record an issue with a file, replacing an earlier one of the same kind.
+pub fn insert_file_issue(tx: &mut Transaction, file_id: i64, kind: &str, detail: &str, found_at: &str) -> Result<()> {
+    let sql = r#"
+        insert or replace into
+          file_issues (file_id, kind, detail, found_at)
         values
-          (:track_id, :created_at, :rating, :source);
+          (:file_id, :kind, :detail, :found_at);
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    statement.bind(1, track_id)?;
-    statement.bind(2, created_at)?;
-    statement.bind(3, rating)?;
-    statement.bind(4, source)?;
+    statement.bind(1, file_id)?;
+    statement.bind(2, kind)?;
+    statement.bind(3, detail)?;
+    statement.bind(4, found_at)?;
     let result = match statement.next()? {
-        Row => panic!("Query 'insert_rating' unexpectedly returned a row."),
+        Row => panic!("Query 'insert_file_issue' unexpectedly returned a row."),
         Done => (),
     };
     Ok(result)
 }
 
-#[derive(Debug)]
-pub struct TrackRating {
-    pub id: i64,
-    pub track_id: i64,
-    pub rating: i64,
-}
-
-pub fn iter_ratings<'i, 't, 'a>(tx: &'i mut Transaction<'t, 'a>) -> Result<Iter<'i, 'a, TrackRating>> {
+pub fn delete_file_issue(tx: &mut Transaction, file_id: i64, kind: &str) -> Result<()> {
     let sql = r#"
-        select
-            id
-          , track_id
-          , rating
-        from
-          ratings
-        order by
-          -- Order by ascending creation time to ensure we can clamp to rating ranges,
-          -- should we need to. We have an index on this expression.
-          cast(strftime('%s', created_at) as integer) asc;
+        delete from file_issues where file_id = :file_id and kind = :kind;
         "#;
     let statement = match tx.statements.entry(sql.as_ptr()) {
         Occupied(entry) => entry.into_mut(),
         Vacant(vacancy) => vacancy.insert(tx.connection.prepare(sql)?),
     };
     statement.reset()?;
-    let decode_row = |statement: &Statement| Ok(TrackRating {
-        id: statement.read(0)?,
-        track_id: statement.read(1)?,
-        rating: statement.read(2)?,
-    });
-    let result = Iter { statement, decode_row };
+    statement.bind(1, file_id)?;
+    statement.bind(2, kind)?;
+    let result = match statement.next()? {
+        Row => panic!("Query 'delete_file_issue' unexpectedly returned a row."),
+        Done => (),
+    };
     Ok(result)
 }
 
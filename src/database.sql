@@ -39,7 +39,7 @@ create table if not exists listens
 , disc_number      integer null
 
 -- Source of the listen. Should be either 'musium' if we produced the
--- listen, or 'listenbrainz' if we backfilled it from Listenbrainz.
+-- listen, or 'listenbrainz' or 'lastfm' if we backfilled it from an export.
 , source           string  not null
 
 -- ISO-8601 time with UTC offset at which we scrobbled the track to Last.fm.
@@ -58,6 +58,39 @@ create table if not exists listens
 create unique index if not exists ix_listens_unique_second
 on listens (cast(strftime('%s', started_at) as integer));
 
+-- The statistics aggregate listens per album artist, album, or track. Filtering
+-- on the time range uses the index above, these indexes serve the per-album
+-- and per-artist listings, and the play counts.
+create index if not exists ix_listens_album_artist_id on listens (album_artist_id);
+create index if not exists ix_listens_album_id on listens (album_id);
+create index if not exists ix_listens_track_id on listens (track_id);
+
+-- The client that enqueued the track of a listen, for listens that we produced.
+-- This is a name chosen by the client, such as 'kitchen' or 'kids-tablet'.
+-- Listens of tracks enqueued without a client name have no row here.
+create table if not exists listen_clients
+( listen_id integer primary key references listens (id)
+, client    string  not null
+);
+
+create index if not exists ix_listen_clients_client on listen_clients (client);
+
+-- Tracks that the user skipped before they completed. The listen for the track
+-- is in the listens table, without completion time.
+create table if not exists skips
+( id               integer primary key
+-- ISO-8601 time with UTC offset at which we skipped the track.
+, skipped_at       string  not null
+-- The listen that we skipped. NULL if the history thread missed the start.
+, listen_id        integer null     references listens (id)
+, queue_id         integer not null
+, track_id         integer not null
+-- Playback position in the track at the time of the skip.
+, position_seconds integer not null
+);
+
+create index if not exists ix_skips_track_id on skips (track_id);
+
 create table if not exists ratings
 ( id          integer primary key
 -- ISO-8601 time with UTC offset at which we rated the track.
@@ -77,6 +110,25 @@ create table if not exists ratings
 create unique index if not exists ix_ratings_unique_second
 on ratings (cast(strftime('%s', created_at) as integer));
 
+-- Ratings for albums, with the same structure and rating levels as the track
+-- ratings above. Like there, we don't enforce a foreign key relation.
+create table if not exists album_ratings
+( id          integer primary key
+, created_at  string  not null unique
+, album_id    integer not null
+, rating      integer not null check ((rating >= -1) and (rating <= 2))
+, source      string not null
+);
+
+-- Ratings for album artists, with the same structure as the album ratings.
+create table if not exists artist_ratings
+( id          integer primary key
+, created_at  string  not null unique
+, artist_id   integer not null
+, rating      integer not null check ((rating >= -1) and (rating <= 2))
+, source      string not null
+);
+
 create table if not exists files
 -- First an id, and properties about the file, but not its contents.
 -- We can use this to see if a file needs to be re-scanned. The mtime
@@ -94,6 +146,7 @@ create table if not exists files
 , streaminfo_num_samples         integer     null
 , streaminfo_sample_rate         integer not null
 );
+-- Schema version 2 adds more columns to this table, see add_file_identity below.
 
 create table if not exists tags
 ( id         integer primary key
@@ -136,8 +189,381 @@ create table if not exists thumbnails
 , file_id  integer not null references files (id) on delete cascade
 , data     blob    not null
 );
+
+-- The time at which an album first entered the library. There is deliberately
+-- no reference to the files table, such that the import time survives rescans
+-- of the files in the album, which delete and re-insert the file rows.
+create table if not exists album_imports
+( album_id    integer primary key
+-- ISO-8601 timestamp at which the album was first imported.
+, imported_at string  not null
+);
+
+-- Named playlists, created by the user.
+create table if not exists playlists
+( id          integer primary key
+, name        string  not null
+-- ISO-8601 timestamp at which the playlist was created.
+, created_at  string  not null
+-- For smart playlists, the query that selects the tracks, see smart_playlist.rs.
+-- NULL for static playlists, which have their tracks in playlist_entries.
+, query       string  null
+);
+
+-- The tracks in a playlist. A track can occur in a playlist multiple times, so
+-- entries have their own id. Like for ratings, the track id is not a foreign
+-- key, such that the entry survives a re-import of the track.
+create table if not exists playlist_entries
+( id          integer primary key
+, playlist_id integer not null references playlists (id) on delete cascade
+-- 0-based position of the entry in the playlist.
+, position    integer not null
+, track_id    integer not null
+);
+
+create index if not exists ix_playlist_entries_playlist_id
+on playlist_entries (playlist_id, position);
 -- @end ensure_schema_exists
 
+-- The schema version, see database_utils::migrate. It is 0 for a new database,
+-- and for databases created before we tracked the version.
+-- @query select_schema_version() ->1 i64
+select user_version from pragma_user_version;
+
+-- Schema version 2: columns that identify a file when it moves, so that a scan
+-- can update its path, instead of deleting it and scanning it as a new file.
+-- They are NULL for files scanned before version 2, until the next scan.
+-- @begin add_file_identity()
+-- Size of the file in bytes, and its inode number, as returned by 'stat'.
+alter table files add column size_bytes integer null;
+alter table files add column inode integer null;
+-- The MD5 of the unencoded audio, from the streaminfo block, in lowercase hex.
+-- NULL if the encoder did not compute it.
+alter table files add column streaminfo_md5 string null;
+-- @end add_file_identity
+
+-- Schema version 3: users, see auth.rs. Listens, ratings, and playlists of the
+-- default user have no user, that includes everything from before version 3.
+-- @begin add_users()
+-- The user whose token enqueued the track of a listen, for listens that we
+-- produced. Like for listen_clients, listens without a user have no row here.
+create table if not exists listen_users
+( listen_id integer primary key references listens (id)
+, user_name string  not null
+);
+create index if not exists ix_listen_users_user_name on listen_users (user_name);
+alter table ratings add column user_name string null;
+alter table album_ratings add column user_name string null;
+alter table artist_ratings add column user_name string null;
+alter table playlists add column user_name string null;
+-- Two users can rate a track in the same second, so the second only needs to
+-- be unique per user.
+drop index if exists ix_ratings_unique_second;
+create unique index if not exists ix_ratings_unique_user_second
+on ratings (coalesce(user_name, ''), cast(strftime('%s', created_at) as integer));
+-- @end add_users
+
+-- Schema version 4: the play queue, saved when the server shuts down, so it can
+-- resume where it left off when it starts again. Entries are either a track or
+-- a radio station. Only the first entry has a nonzero position.
+-- @begin add_saved_queue()
+create table if not exists saved_queue
+( position     integer primary key
+, track_id     integer null
+, station_id   integer null
+, position_ms  integer not null
+, client       string  null
+, user_name    string  null
+);
+-- @end add_saved_queue
+
+-- Schema version 5: a log of metadata edits made through the API, see
+-- metadata_edit.rs. Like for ratings, the file and track are not foreign keys,
+-- the log should outlive the files that were edited.
+-- @begin add_metadata_edits()
+create table if not exists metadata_edits
+( id          integer primary key
+-- ISO-8601 time with UTC offset at which the edit was made.
+, edited_at   string  not null
+, file_id     integer not null
+, track_id    integer not null
+-- The lowercase tag name, as in the tags table.
+, field_name  string  not null
+-- All values of the tag before the edit, separated by "; ", NULL if there were none.
+, old_value   string  null
+-- The new value, NULL if the edit removed the tag.
+, new_value   string  null
+-- 1 if the edit was also written to the file, 0 if only to the database.
+, wrote_tags  integer not null
+, user_name   string  null
+);
+-- @end add_metadata_edits
+
+-- Schema version 6: artists that were merged into another artist, see
+-- artist_alias.rs. When we build the index, albums of the alias belong to the
+-- canonical artist instead. Artist ids are derived from MusicBrainz ids, they
+-- are not foreign keys because artists only exist in the index.
+-- @begin add_artist_aliases()
+create table if not exists artist_aliases
+( artist_id           integer primary key
+, canonical_artist_id integer not null
+-- The name of the artist when it was merged, the index no longer has it.
+, name                string  not null
+-- ISO-8601 time with UTC offset at which the artists were merged.
+, created_at          string  not null
+);
+-- @end add_artist_aliases
+
+-- Schema version 7: fingerprint lookups for files that lack the tags we need,
+-- see acoustid.rs. Every file that we looked up has a row in acoustid_lookups,
+-- so we look up files only once. When AcoustID found a match, the tags that we
+-- propose for the file are in acoustid_proposals, for review. Like for other
+-- file data, rows of deleted files are removed by delete_orphaned_file_data.
+-- @begin add_acoustid()
+create table if not exists acoustid_lookups
+( file_id      integer primary key
+-- ISO-8601 time with UTC offset at which we looked up the file.
+, looked_up_at string  not null
+);
+create table if not exists acoustid_proposals
+( file_id                   integer primary key
+, acoustid                  string  not null
+-- How well the fingerprint matches, from 0.0 to 1.0, as reported by AcoustID.
+, score                     real    not null
+-- The proposed tags, named like the tags in the tags table.
+, musicbrainz_trackid       string  not null
+, title                     string  not null
+, artist                    string  not null
+, album                     string  not null
+, musicbrainz_albumid       string  not null
+, albumartist               string  not null
+, musicbrainz_albumartistid string  not null
+, tracknumber               integer not null
+, discnumber                integer not null
+, originaldate              string  not null
+-- One of 'pending', 'accepted', or 'rejected'.
+, status                    string  not null
+);
+-- @end add_acoustid
+
+-- Schema version 8: where playback of long tracks, such as DJ mixes and
+-- audiobooks, stopped before the end, so it can resume there when the track is
+-- enqueued again. A track has at most one position, its latest one. Like for
+-- ratings, the track is not a foreign key.
+-- @begin add_resume_positions()
+create table if not exists resume_positions
+( track_id     integer primary key
+, position_ms  integer not null
+-- ISO-8601 time with UTC offset at which playback stopped at this position.
+, saved_at     string  not null
+);
+-- @end add_resume_positions
+
+-- Schema version 9: the pre-gap of files, the audio before INDEX 01 of the first
+-- track in the cuesheet block of the flac file, see cuesheet.rs. Every scanned
+-- file has a row, files without a pre-gap have 0 pregap_samples. Files scanned
+-- before version 9 have no row until the next scan fills it in.
+-- @begin add_file_pregaps()
+create table if not exists file_pregaps
+( file_id        integer primary key references files (id) on delete cascade
+-- Number of samples per channel, at the sample rate of the file.
+, pregap_samples integer not null
+);
+-- @end add_file_pregaps
+
+-- Schema version 10: tracks and albums that shuffling should pass over, see
+-- user_data.rs. They stay in the library, and they play when enqueued
+-- explicitly. A row means that the item is excluded for the user, including
+-- the item again deletes the row. Like for ratings, the ids are not foreign keys.
+-- @begin add_exclusions()
+create table if not exists track_exclusions
+( track_id   integer not null
+-- ISO-8601 time with UTC offset at which the track was excluded.
+, created_at string  not null
+, user_name  string  null
+);
+create unique index if not exists ix_track_exclusions_unique
+on track_exclusions (track_id, coalesce(user_name, ''));
+create table if not exists album_exclusions
+( album_id   integer not null
+-- ISO-8601 time with UTC offset at which the album was excluded.
+, created_at string  not null
+, user_name  string  null
+);
+create unique index if not exists ix_album_exclusions_unique
+on album_exclusions (album_id, coalesce(user_name, ''));
+-- @end add_exclusions
+
+-- Similar artists and tags of album artists, fetched from Last.fm or
+-- ListenBrainz when `enrichment` is configured, see enrichment.rs. This is a
+-- cache: `artist_enrichment` has a row per artist that we fetched, also when
+-- fetching failed, and the other tables hold the results. Deleting the row
+-- deletes the results.
+-- @begin add_artist_enrichment()
+create table if not exists artist_enrichment
+( artist_id          integer primary key
+-- The service that we fetched from, 'lastfm' or 'listenbrainz'.
+, source             string  not null
+-- Seconds since the epoch after which we fetch again.
+, expires_at_seconds integer not null
+);
+create table if not exists artist_enrichment_similar
+( artist_id integer not null references artist_enrichment (artist_id) on delete cascade
+-- Position in the list of the service, most similar first.
+, rank      integer not null
+, name      string  not null
+-- The MusicBrainz id of the similar artist, if the service reports it.
+, mbid      string  null
+, score     real    not null
+);
+create index if not exists ix_artist_enrichment_similar_artist_id
+on artist_enrichment_similar (artist_id);
+create table if not exists artist_enrichment_tags
+( artist_id integer not null references artist_enrichment (artist_id) on delete cascade
+, rank      integer not null
+, tag       string  not null
+);
+create index if not exists ix_artist_enrichment_tags_artist_id
+on artist_enrichment_tags (artist_id);
+-- @end add_artist_enrichment
+
+-- Listening sessions. A listen that starts within half an hour after the
+-- previous listen of the same user ended, is part of the same session, see
+-- sessions.rs. Listens that are not yet assigned to a session have null.
+-- @begin add_listen_sessions()
+alter table listens add column session_id integer null;
+create index if not exists ix_listens_session_id on listens (session_id);
+-- @end add_listen_sessions
+
+-- The first listen of every track, album, and album artist, per user. We
+-- could find these in the listens, but that takes a scan of the full history,
+-- and we need them for the discovery statistics, see listens.rs.
+-- @begin add_first_listens()
+create table if not exists first_listens
+( kind       string  not null  -- One of 'track', 'album', or 'artist'.
+, item_id    integer not null
+, user_name  string  null
+, listen_id  integer not null references listens (id) on delete cascade
+, started_at string  not null
+);
+
+create unique index if not exists ix_first_listens_item
+on first_listens (kind, item_id, coalesce(user_name, ''));
+
+create index if not exists ix_first_listens_started_at
+on first_listens (cast(strftime('%s', started_at) as integer));
+
+insert into first_listens (kind, item_id, user_name, listen_id, started_at)
+select kind, item_id, user_name, id, min(started_at)
+from (
+  select 'track' as kind, track_id as item_id, user_name, id, started_at
+  from listens left join listen_users on listen_users.listen_id = listens.id
+  union all
+  select 'album', album_id, user_name, id, started_at
+  from listens left join listen_users on listen_users.listen_id = listens.id
+  union all
+  select 'artist', album_artist_id, user_name, id, started_at
+  from listens left join listen_users on listen_users.listen_id = listens.id
+)
+group by kind, item_id, user_name;
+-- @end add_first_listens
+
+-- How much of the track played, from 0.0 to 1.0, for listens that ended, see
+-- `ListenThreshold` in listens.rs. Listens from before we recorded this, and
+-- imported listens, have null.
+-- @begin add_listen_played_fraction()
+alter table listens add column played_fraction real null;
+-- @end add_listen_played_fraction
+
+-- The offset from UTC of the reporting time zone at the time of the listen, so
+-- day and hour statistics are in the time of day where the listen happened.
+-- Listens from before we recorded this have null, for those we use the local
+-- time of the server.
+-- @begin add_listen_utc_offset()
+alter table listens add column utc_offset_seconds integer null;
+-- @end add_listen_utc_offset
+
+-- The true peak of tracks and albums, in dBTP, see `TruePeakMeter` in
+-- loudness.rs. Loudness that we analyzed before we measured the peak has null,
+-- the next scan analyzes those tracks again.
+-- @begin add_loudness_true_peak()
+alter table track_loudness add column true_peak_dbtp real null;
+alter table album_loudness add column true_peak_dbtp real null;
+-- @end add_loudness_true_peak
+
+-- Problems with files that we found outside of a scan, see verify.rs. There is
+-- at most one issue of every kind per file. For now the only kind is 'verify',
+-- for files whose decoded audio does not match the MD5 in their streaminfo.
+-- @begin add_file_issues()
+create table if not exists file_issues
+( file_id  integer not null references files (id) on delete cascade
+, kind     string  not null
+, detail   string  not null
+, found_at string  not null
+);
+create unique index if not exists ix_file_issues_file_id_kind
+on file_issues (file_id, kind);
+-- @end add_file_issues
+
+-- Internet radio stations, that can be enqueued like tracks, and their listens.
+-- @begin add_radio_stations()
+create table if not exists radio_stations
+( id          integer primary key
+, name        string  not null
+, url         string  not null
+-- ISO-8601 timestamp at which the station was added.
+, created_at  string  not null
+);
+
+-- Listens of radio stations. A station plays many songs, we record one listen
+-- per title that the station announces in its stream. Radio listens do not
+-- refer to tracks in the library, so unlike listens from the library, they
+-- don't count towards statistics, and we don't scrobble them.
+create table if not exists radio_listens
+( id           integer primary key
+-- ISO-8601 time with UTC offset at which the title started playing.
+, started_at   string  not null
+-- ISO-8601 time with UTC offset at which the title stopped playing.
+-- NULL if it is still playing, or if playback stopped unexpectedly.
+, completed_at string  null     check (started_at < completed_at)
+, queue_id     integer not null
+-- References the radio_stations table, but like for listens, we keep the
+-- listen when the station is deleted, that's why we also store the name.
+, station_id   integer not null
+, station_name string  not null
+-- The title as the station announced it, often "Artist - Title". NULL when
+-- the station does not announce titles.
+, stream_title string  null
+);
+-- @end add_radio_stations
+
+-- Check the database for corruption. Yields a single "ok" row if all is well,
+-- or one row per problem otherwise.
+-- @query iter_integrity_check() ->* str
+select integrity_check from pragma_integrity_check;
+
+-- Rows that reference a row in another table that does not exist. There should
+-- be none, but the references were not enforced when foreign keys were off.
+-- @query iter_foreign_key_violations() ->* (str, i64, str)
+select "table", rowid, parent from pragma_foreign_key_check;
+
+-- Return the size of the database in bytes, not counting the WAL.
+-- @query select_database_size() ->1 i64
+select page_count * page_size from pragma_page_count, pragma_page_size;
+
+-- Return the auto_vacuum mode: 0 is none, 1 is full, 2 is incremental.
+-- @query select_auto_vacuum() ->1 i64
+select auto_vacuum from pragma_auto_vacuum;
+
+-- Take the write lock without changing anything, for the health check. The
+-- caller rolls back the transaction afterwards.
+-- @query probe_write()
+delete from playlists where false;
+
+-- Gather statistics about the tables and indexes, for the query planner.
+-- @query analyze()
+analyze;
+
 -- @query insert_file(metadata: InsertFile) ->1 i64
 insert into files
 ( filename
@@ -147,6 +573,9 @@ insert into files
 , streaminfo_bits_per_sample
 , streaminfo_num_samples
 , streaminfo_sample_rate
+, size_bytes
+, inode
+, streaminfo_md5
 )
 values
 ( :filename                   -- :str
@@ -156,6 +585,9 @@ values
 , :streaminfo_bits_per_sample -- :i64
 , :streaminfo_num_samples     -- :i64?
 , :streaminfo_sample_rate     -- :i64
+, :size_bytes                 -- :i64
+, :inode                      -- :i64
+, :streaminfo_md5             -- :str?
 )
 returning id;
 
@@ -170,6 +602,54 @@ insert into
 -- @query delete_file(file_id: i64)
 delete from files where id = :file_id;
 
+-- Files that were moved keep their id, so their loudness, waveform, and
+-- thumbnail remain valid, and their listens keep referring to them.
+-- @query update_file_path(file_id: i64, filename: str, inode: i64)
+update files
+set filename = :filename, inode = :inode
+where id = :file_id;
+
+-- Fill in the size and inode for files scanned before we recorded those.
+-- @query update_file_size_inode(file_id: i64, size_bytes: i64, inode: i64)
+update files
+set size_bytes = :size_bytes, inode = :inode
+where id = :file_id;
+
+-- @query iter_files_without_inode() ->* (i64, str)
+select id, filename from files where inode is null;
+
+-- @query insert_file_pregap(file_id: i64, pregap_samples: i64)
+insert or replace into
+  file_pregaps (file_id, pregap_samples)
+values
+  (:file_id, :pregap_samples);
+
+-- @query iter_files_without_pregap() ->* (i64, str)
+select id, filename from files where id not in (select file_id from file_pregaps);
+
+-- Iterate the files that have a pre-gap, for the index.
+--
+-- Yields tuples `(file_id, pregap_samples, sample_rate)`.
+-- @query iter_file_pregaps() ->* (i64, i64, i64)
+select
+  files.id, file_pregaps.pregap_samples, files.streaminfo_sample_rate
+from
+  file_pregaps, files
+where
+  files.id = file_pregaps.file_id
+  and file_pregaps.pregap_samples > 0;
+
+-- @query iter_file_identities() ->* FileIdentity
+select
+    id             -- :i64
+  , filename       -- :str
+  , mtime          -- :i64
+  , size_bytes     -- :i64?
+  , inode          -- :i64?
+  , streaminfo_md5 -- :str?
+from
+  files;
+
 -- @query iter_file_mtime() ->* FileMetadataSimple
 select
     id       -- :i64
@@ -185,6 +665,7 @@ select
     id                         -- :i64
   , filename                   -- :str
   , mtime                      -- :i64
+  , imported_at                -- :str
   , streaminfo_channels        -- :i64
   , streaminfo_bits_per_sample -- :i64
   , streaminfo_num_samples     -- :i64?
@@ -194,6 +675,16 @@ from
 order by
   filename asc;
 
+-- Iterate the audio properties and sizes of all files, for library statistics.
+-- @query iter_file_formats() ->* FileFormat
+select
+    id                         -- :i64
+  , streaminfo_bits_per_sample -- :i64
+  , streaminfo_sample_rate     -- :i64
+  , size_bytes                 -- :i64?
+from
+  files;
+
 -- Iterate all `(field_name, value)` pairs for the given file.
 -- @query iter_file_tags(file_id: i64) ->* (str, str)
 select
@@ -208,20 +699,31 @@ order by
   -- we found them in the file.
   id asc;
 
+-- Remove all values of a tag from a file, before inserting the edited value.
+-- @query delete_file_tag(file_id: i64, field_name: str)
+delete from tags where file_id = :file_id and field_name = :field_name;
+
+-- Record the new mtime, size, and inode of a file after we rewrote its tags,
+-- so the next scan does not consider it modified.
+-- @query update_file_mtime_size_inode(file_id: i64, mtime: i64, size_bytes: i64, inode: i64)
+update files
+set mtime = :mtime, size_bytes = :size_bytes, inode = :inode
+where id = :file_id;
+
 -- @query insert_album_thumbnail(album_id: i64, file_id: i64, data: bytes)
 insert into thumbnails (album_id, file_id, data)
 values (:album_id, :file_id, :data)
 on conflict (album_id) do update set data = :data;
 
--- @query insert_album_loudness(album_id: i64, file_id: i64, loudness: f64)
-insert into album_loudness (album_id, file_id, bs17704_loudness_lufs)
-values (:album_id, :file_id, :loudness)
-on conflict (album_id) do update set bs17704_loudness_lufs = :loudness;
+-- @query insert_album_loudness(album_id: i64, file_id: i64, loudness: f64, true_peak: f64)
+insert into album_loudness (album_id, file_id, bs17704_loudness_lufs, true_peak_dbtp)
+values (:album_id, :file_id, :loudness, :true_peak)
+on conflict (album_id) do update set bs17704_loudness_lufs = :loudness, true_peak_dbtp = :true_peak;
 
--- @query insert_track_loudness(track_id: i64, file_id: i64, loudness: f64)
-insert into track_loudness (track_id, file_id, bs17704_loudness_lufs)
-values (:track_id, :file_id, :loudness)
-on conflict (track_id) do update set bs17704_loudness_lufs = :loudness;
+-- @query insert_track_loudness(track_id: i64, file_id: i64, loudness: f64, true_peak: f64)
+insert into track_loudness (track_id, file_id, bs17704_loudness_lufs, true_peak_dbtp)
+values (:track_id, :file_id, :loudness, :true_peak)
+on conflict (track_id) do update set bs17704_loudness_lufs = :loudness, true_peak_dbtp = :true_peak;
 
 -- @query insert_track_waveform(track_id: i64, file_id: i64, data: bytes)
 insert into waveforms (track_id, file_id, data)
@@ -244,6 +746,7 @@ insert into
   , duration_seconds
   , track_number
   , disc_number
+  , utc_offset_seconds
   , source
   )
 values
@@ -260,37 +763,820 @@ values
   , :duration_seconds -- :i64
   , :track_number     -- :i64
   , :disc_number      -- :i64
+  , :utc_offset_seconds -- :i64
   , 'musium'
   )
 returning
   id;
 
+-- Mark the listen completed, after the track played for `played_seconds`.
 -- @query update_listen_completed(
 --   listen_id: i64,
 --   queue_id: i64,
 --   track_id: i64,
 --   completed_at: str,
+--   played_seconds: i64,
 -- )
 update listens
-  set completed_at = :completed_at
+  set
+    completed_at = :completed_at
+  , played_fraction = min(1.0, :played_seconds * 1.0 / max(duration_seconds, 1))
 where
   id = :listen_id
   and queue_id = :queue_id
   and track_id = :track_id;
 
+-- Iterate listens of the user that started in the interval [since, until),
+-- newest first, optionally only for one album, or one album artist. The user
+-- is NULL for the default user, see listen_users. Seconds are unique per
+-- listen, so the start second of the last row can serve as a cursor for the
+-- next page.
+-- @query iter_listens_page(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   album_id: i64?,
+--   album_artist_id: i64?,
+--   client: str?,
+--   user: str?,
+--   limit: i64,
+-- ) ->* ListenRow
+select
+    id                                                                 -- :i64
+  , cast(strftime('%s', started_at) as integer) as started_at_seconds -- :i64
+  , started_at                                                         -- :str
+  , completed_at                                                       -- :str?
+  , track_id                                                           -- :i64
+  , album_id                                                           -- :i64
+  , album_artist_id                                                    -- :i64
+  , track_title                                                        -- :str
+  , track_artist                                                       -- :str
+  , album_title                                                        -- :str
+  , album_artist                                                       -- :str
+  , duration_seconds                                                   -- :i64
+  , track_number                                                       -- :i64?
+  , disc_number                                                        -- :i64?
+  , source                                                             -- :str
+  , scrobbled_at                                                       -- :str?
+  , played_fraction                                                    -- :f64?
+  , ( select client
+      from listen_clients
+      where listen_clients.listen_id = listens.id
+    ) as client                                                        -- :str?
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and (:album_id is null or album_id = :album_id)
+  and (:album_artist_id is null or album_artist_id = :album_artist_id)
+  and (:client is null or id in (select listen_id from listen_clients where client = :client))
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+order by
+  cast(strftime('%s', started_at) as integer) desc
+limit
+  :limit;
+
+-- Return the number of listens in the interval [since, until), and the time
+-- spent listening. We only know the listening time for completed listens,
+-- which count with the full duration of the track.
+-- @query select_listen_totals(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   client: str?,
+--   user: str?,
+-- ) ->1 (i64, i64)
+select
+    count(*)
+  , coalesce(sum(case when completed_at is null then 0 else duration_seconds end), 0)
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and (:client is null or id in (select listen_id from listen_clients where client = :client))
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user;
+
+-- Return the number of listens in the interval [since, until) that played for
+-- at least the threshold, see `ListenThreshold` in listens.rs. The others are
+-- samples. Listens that are still playing do not count yet.
+-- @query select_counted_listen_count(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   client: str?,
+--   user: str?,
+--   threshold_percent: i64,
+--   threshold_seconds: i64,
+-- ) ->1 i64
+select
+  count(*)
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and (:client is null or id in (select listen_id from listen_clients where client = :client))
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+  and coalesce(
+    played_fraction * duration_seconds,
+    cast(strftime('%s', completed_at) as integer) - cast(strftime('%s', started_at) as integer),
+    0
+  ) >= min(duration_seconds * :threshold_percent / 100.0, :threshold_seconds);
+
+-- @query insert_listen_client(listen_id: i64, client: str)
+insert into listen_clients (listen_id, client) values (:listen_id, :client);
+
+-- @query insert_listen_user(listen_id: i64, user: str)
+insert into listen_users (listen_id, user_name) values (:listen_id, :user);
+
+-- Record how long a listen played, when the track was skipped.
+-- @query update_listen_played(listen_id: i64, played_seconds: i64)
+update listens
+  set played_fraction = min(1.0, :played_seconds * 1.0 / max(duration_seconds, 1))
+where
+  id = :listen_id;
+
+-- @query insert_skip(
+--   skipped_at: str,
+--   listen_id: i64?,
+--   queue_id: i64,
+--   track_id: i64,
+--   position_seconds: i64,
+-- )
+insert into
+  skips (skipped_at, listen_id, queue_id, track_id, position_seconds)
+values
+  (:skipped_at, :listen_id, :queue_id, :track_id, :position_seconds);
+
+-- @query select_skip_count(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   client: str?,
+--   user: str?,
+-- ) ->1 i64
+select
+  count(*)
+from
+  skips
+where
+  cast(strftime('%s', skipped_at) as integer) >= :since_seconds
+  and cast(strftime('%s', skipped_at) as integer) < :until_seconds
+  and (:client is null or listen_id in (select listen_id from listen_clients where client = :client))
+  and (select user_name from listen_users where listen_users.listen_id = skips.listen_id) is :user;
+
+-- Return the most skipped tracks in the interval [since, until), with the
+-- number of listens in the same interval, which includes the skipped ones.
+-- @query iter_top_skipped_tracks(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   client: str?,
+--   user: str?,
+--   limit: i64,
+-- ) ->* SkippedTrack
+select
+    track_id                                                  -- :i64
+  , count(*) as skip_count                                    -- :i64
+  , ( select count(*)
+      from listens
+      where
+        listens.track_id = skips.track_id
+        and cast(strftime('%s', listens.started_at) as integer) >= :since_seconds
+        and cast(strftime('%s', listens.started_at) as integer) < :until_seconds
+        and (:client is null or listens.id in (select listen_id from listen_clients where client = :client))
+        and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+    ) as listen_count                                         -- :i64
+from
+  skips
+where
+  cast(strftime('%s', skipped_at) as integer) >= :since_seconds
+  and cast(strftime('%s', skipped_at) as integer) < :until_seconds
+  and (:client is null or skips.listen_id in (select listen_id from listen_clients where client = :client))
+  and (select user_name from listen_users where listen_users.listen_id = skips.listen_id) is :user
+group by
+  track_id
+order by
+  skip_count desc, listen_count asc
+limit
+  :limit;
+
+-- @query iter_top_artists(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   client: str?,
+--   user: str?,
+--   limit: i64,
+-- ) ->* TopArtist
+select
+    album_artist_id                                                        -- :i64
+  , max(album_artist) as album_artist                                      -- :str
+  , count(*) as listen_count                                               -- :i64
+  , sum(case when completed_at is null then 0 else duration_seconds end)
+    as listen_seconds                                                      -- :i64
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and (:client is null or id in (select listen_id from listen_clients where client = :client))
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+group by
+  album_artist_id
+order by
+  listen_count desc, listen_seconds desc
+limit
+  :limit;
+
+-- @query iter_top_albums(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   client: str?,
+--   user: str?,
+--   limit: i64,
+-- ) ->* TopAlbum
+select
+    album_id                                                               -- :i64
+  , max(album_title) as album_title                                        -- :str
+  , max(album_artist) as album_artist                                      -- :str
+  , count(*) as listen_count                                               -- :i64
+  , sum(case when completed_at is null then 0 else duration_seconds end)
+    as listen_seconds                                                      -- :i64
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and (:client is null or id in (select listen_id from listen_clients where client = :client))
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+group by
+  album_id
+order by
+  listen_count desc, listen_seconds desc
+limit
+  :limit;
+
+-- @query iter_top_tracks(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   client: str?,
+--   user: str?,
+--   limit: i64,
+-- ) ->* TopTrack
+select
+    track_id                                                               -- :i64
+  , max(track_title) as track_title                                        -- :str
+  , max(track_artist) as track_artist                                      -- :str
+  , max(album_title) as album_title                                        -- :str
+  , count(*) as listen_count                                               -- :i64
+  , sum(case when completed_at is null then 0 else duration_seconds end)
+    as listen_seconds                                                      -- :i64
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and (:client is null or id in (select listen_id from listen_clients where client = :client))
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+group by
+  track_id
+order by
+  listen_count desc, listen_seconds desc
+limit
+  :limit;
+
+-- Count listens per day of the week (0 is Sunday) and hour of the day, in the
+-- local time of the listen, see `add_listen_utc_offset`.
+-- @query iter_listens_per_weekday_hour(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   client: str?,
+--   user: str?,
+-- ) ->* (i64, i64, i64)
+select
+    cast(strftime('%w', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) as integer) as weekday
+  , cast(strftime('%H', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) as integer) as hour
+  , count(*)
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and (:client is null or id in (select listen_id from listen_clients where client = :client))
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+group by
+  weekday, hour;
+
+-- Albums whose first listen ever by the user falls in the interval [since,
+-- until), by the number of listens in that interval.
+-- @query iter_album_discoveries(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   user: str?,
+--   limit: i64,
+-- ) ->* AlbumDiscovery
+select
+    album_id                                     -- :i64
+  , max(album_title) as album_title              -- :str
+  , max(album_artist) as album_artist            -- :str
+  , count(*) as listen_count                     -- :i64
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+  and album_id not in (
+    select album_id
+    from listens
+    where
+      cast(strftime('%s', started_at) as integer) < :since_seconds
+      and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+  )
+group by
+  album_id
+order by
+  listen_count desc
+limit
+  :limit;
+
+-- Count listens per year and album on the given day of the year, formatted as
+-- '%m-%d', in years before the given year. Days and years are in the local
+-- time of the listen. This needs a full table scan, because the index is on
+-- the timestamp, not on the day of the year.
+-- @query iter_listens_on_day_of_year(month_day: str, before_year: i64, user: str?) ->* DayOfYearAlbum
+select
+    cast(strftime('%Y', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) as integer) as year -- :i64
+  , album_id                                                          -- :i64
+  , max(album_title) as album_title                                   -- :str
+  , max(album_artist) as album_artist                                 -- :str
+  , count(*) as listen_count                                          -- :i64
+from
+  listens
+where
+  strftime('%m-%d', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) = :month_day
+  and cast(strftime('%Y', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) as integer) < :before_year
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+group by
+  year, album_id
+order by
+  year desc, listen_count desc;
+
+-- Iterate the listens of the user since the given time, grouped by album, and
+-- oldest first within an album, as `(album_id, track_id, started_at_seconds)`.
+-- For finding albums that the user listened to partway, see listens.rs.
+-- @query iter_album_listens_since(since_seconds: i64, user: str?) ->* (i64, i64, i64)
+select
+    album_id
+  , track_id
+  , cast(strftime('%s', started_at) as integer) as started_at_seconds
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+order by
+  album_id, started_at_seconds;
+
+-- Iterate all listens of the user, grouped by session, as
+-- `(track_id, album_artist_id, session_id)`. For finding tracks and artists
+-- that get played in the same session, see similar.rs. A listen that is not
+-- yet part of a session is a session of its own.
+-- @query iter_listens_for_similarity(user: str?) ->* (i64, i64, i64)
+select
+    track_id
+  , album_artist_id
+  , coalesce(session_id, -id) as session
+from
+  listens
+where
+  (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+order by
+  session;
+
+-- Iterate the listens that are not yet part of a session, oldest first, as
+-- (id, started_at_seconds, user_name).
+-- @query iter_listens_without_session() ->* (i64, i64, str?)
+select
+    id
+  , cast(strftime('%s', started_at) as integer)
+  , (select user_name from listen_users where listen_users.listen_id = listens.id)
+from
+  listens
+where
+  session_id is null
+order by
+  started_at;
+
+-- Return the session of the latest listen of the user that started before the
+-- given time, and when that listen ended, as (session_id, ended_at_seconds).
+-- Skipped and unfinished listens have no completion time, for those we take
+-- the start.
+-- @query select_previous_listen_session(started_at_seconds: i64, user: str?) ->? (i64?, i64)
+select
+    session_id
+  , cast(strftime('%s', coalesce(completed_at, started_at)) as integer)
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) < :started_at_seconds
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+order by
+  cast(strftime('%s', started_at) as integer) desc
+limit
+  1;
+
+-- @query select_max_listen_session_id() ->1 i64
+select coalesce(max(session_id), 0) from listens;
+
+-- @query update_listen_session(listen_id: i64, session_id: i64)
+update listens set session_id = :session_id where id = :listen_id;
+
+-- Iterate the sessions of the user with listens in the time range, as
+-- (started_at_seconds, ended_at_seconds, listen_count, start_hour), where the
+-- hour is in the local time of the listen.
+-- @query iter_listen_sessions(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   client: str?,
+--   user: str?,
+-- ) ->* (i64, i64, i64, i64)
+select
+    min(cast(strftime('%s', started_at) as integer))
+  , max(cast(strftime('%s', coalesce(completed_at, started_at)) as integer))
+  , count(*)
+  , cast(strftime('%H', min(started_at), coalesce(utc_offset_seconds || ' seconds', 'localtime')) as integer)
+from
+  listens
+where
+  session_id is not null
+  and cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and (:client is null or id in (select listen_id from listen_clients where client = :client))
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+group by
+  session_id;
+
+-- Record the listen as the first listen of its track, album, and album artist,
+-- for those that do not have one yet. Listens start in chronological order, so
+-- the first one we record is the earliest.
+-- @query insert_first_listen(listen_id: i64)
+insert or ignore into first_listens (kind, item_id, user_name, listen_id, started_at)
+with listen as (
+  select
+      id
+    , track_id
+    , album_id
+    , album_artist_id
+    , started_at
+    , (select user_name from listen_users where listen_users.listen_id = listens.id) as user_name
+  from
+    listens
+  where
+    id = :listen_id
+)
+select 'track', track_id, user_name, id, started_at from listen
+union all
+select 'album', album_id, user_name, id, started_at from listen
+union all
+select 'artist', album_artist_id, user_name, id, started_at from listen;
+
+-- Recompute the first listens from scratch. For after an import or a repair,
+-- where listens do not arrive in chronological order.
+-- @begin rebuild_first_listens()
+delete from first_listens;
+
+insert into first_listens (kind, item_id, user_name, listen_id, started_at)
+select kind, item_id, user_name, id, min(started_at)
+from (
+  select 'track' as kind, track_id as item_id, user_name, id, started_at
+  from listens left join listen_users on listen_users.listen_id = listens.id
+  union all
+  select 'album', album_id, user_name, id, started_at
+  from listens left join listen_users on listen_users.listen_id = listens.id
+  union all
+  select 'artist', album_artist_id, user_name, id, started_at
+  from listens left join listen_users on listen_users.listen_id = listens.id
+)
+group by kind, item_id, user_name;
+-- @end rebuild_first_listens
+
+-- Count the tracks, albums, and album artists that the user listened to for
+-- the first time in the interval [since, until).
+-- @query select_discovery_counts(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   user: str?,
+-- ) ->1 (i64, i64, i64)
+select
+    coalesce(sum(kind = 'track'), 0)
+  , coalesce(sum(kind = 'album'), 0)
+  , coalesce(sum(kind = 'artist'), 0)
+from
+  first_listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and user_name is :user;
+
+-- Count the first listens of the user in the interval [since, until) per
+-- month, as (month, tracks, albums, artists), oldest month first. Months are
+-- formatted as YYYY-MM, in the local time of the listen.
+-- @query iter_discoveries_per_month(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   user: str?,
+-- ) ->* (str, i64, i64, i64)
+select
+    strftime('%Y-%m', started_at, coalesce(
+      (select utc_offset_seconds from listens where listens.id = first_listens.listen_id) || ' seconds',
+      'localtime'
+    )) as month
+  , sum(kind = 'track')
+  , sum(kind = 'album')
+  , sum(kind = 'artist')
+from
+  first_listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and user_name is :user
+group by
+  month
+order by
+  month;
+
+-- Count the listens of the user in the interval [since, until) per month, as
+-- (month, listens), oldest month first, like `iter_discoveries_per_month`.
+-- @query iter_listens_per_month(
+--   since_seconds: i64,
+--   until_seconds: i64,
+--   user: str?,
+-- ) ->* (str, i64)
+select
+    strftime('%Y-%m', started_at, coalesce(utc_offset_seconds || ' seconds', 'localtime')) as month
+  , count(*)
+from
+  listens
+where
+  cast(strftime('%s', started_at) as integer) >= :since_seconds
+  and cast(strftime('%s', started_at) as integer) < :until_seconds
+  and (select user_name from listen_users where listen_users.listen_id = listens.id) is :user
+group by
+  month
+order by
+  month;
+
+-- Insert a listen imported from an export of an external service. Returns
+-- nothing when we already have a listen that started in the same second, for
+-- example because we produced the listen ourselves and scrobbled it, or
+-- because we imported the same export before.
+-- @query insert_listen_imported(listen: ImportedListen) ->? i64
+insert or ignore into
+  listens
+  ( started_at
+  , completed_at
+  , file_id
+  , track_id
+  , album_id
+  , album_artist_id
+  , track_title
+  , track_artist
+  , album_title
+  , album_artist
+  , duration_seconds
+  , track_number
+  , disc_number
+  , utc_offset_seconds
+  , source
+  )
+values
+  ( :started_at       -- :str
+  , :completed_at     -- :str
+  , :file_id          -- :i64
+  , :track_id         -- :i64
+  , :album_id         -- :i64
+  , :album_artist_id  -- :i64
+  , :track_title      -- :str
+  , :track_artist     -- :str
+  , :album_title      -- :str
+  , :album_artist     -- :str
+  , :duration_seconds -- :i64
+  , :track_number     -- :i64
+  , :disc_number      -- :i64
+  , :utc_offset_seconds -- :i64
+  , :source           -- :str
+  )
+returning
+  id;
+
+-- Iterate listens that are eligible for scrobbling to Last.fm, but that we
+-- have not scrobbled yet, oldest first, at most one batch of 50.
+--
+-- Last.fm does not accept tracks shorter than 30 seconds, and a track should
+-- be scrobbled after it played for the threshold, by default half its duration
+-- or four minutes, whichever comes first, see `ListenThreshold` in listens.rs.
+-- This includes listens that were skipped after the threshold. For listens
+-- from before we recorded how long they played, we go by the completion time.
+-- We only scrobble listens that started after `since_posix_seconds`, because
+-- Last.fm rejects scrobbles that are too old.
+-- The track MusicBrainz id comes from the tags of the file, if it is still
+-- there.
+-- @query iter_listens_to_scrobble(
+--   since_posix_seconds: i64,
+--   threshold_percent: i64,
+--   threshold_seconds: i64,
+-- ) ->* ListenToScrobble
+select
+    listens.id                                                         -- :i64
+  , cast(strftime('%s', started_at) as integer) as started_at_seconds -- :i64
+  , track_title                                                        -- :str
+  , track_artist                                                       -- :str
+  , album_title                                                        -- :str
+  , album_artist                                                       -- :str
+  , duration_seconds                                                   -- :i64
+  , track_number                                                       -- :i64?
+  , tags.value as track_mbid                                           -- :str?
+from
+  listens
+  left join tags
+    on tags.file_id = listens.file_id
+    and tags.field_name = 'musicbrainz_trackid'
+where
+  scrobbled_at is null
+  and source = 'musium'
+  and (completed_at is not null or played_fraction is not null)
+  and duration_seconds > 30
+  and coalesce(
+    played_fraction * duration_seconds,
+    cast(strftime('%s', completed_at) as integer) - cast(strftime('%s', started_at) as integer)
+  ) >= min(duration_seconds * :threshold_percent / 100.0, :threshold_seconds)
+  and cast(strftime('%s', started_at) as integer) > :since_posix_seconds
+  and listens.id not in (select listen_id from listen_users)
+order by
+  started_at asc
+limit
+  50;
+
+-- @query update_listen_scrobbled(listen_id: i64, scrobbled_at: str)
+update listens set scrobbled_at = :scrobbled_at where id = :listen_id;
+
+-- Iterate all listens for exporting, oldest first. The MusicBrainz ids come
+-- from the tags of the file, if it is still there.
+-- @query iter_listens_for_export() ->* ExportListen
+select
+    id                                                              -- :i64
+  , started_at                                                      -- :str
+  , completed_at                                                    -- :str?
+  , track_id                                                        -- :i64
+  , album_id                                                        -- :i64
+  , album_artist_id                                                 -- :i64
+  , track_title                                                     -- :str
+  , track_artist                                                    -- :str
+  , album_title                                                     -- :str
+  , album_artist                                                    -- :str
+  , duration_seconds                                                -- :i64
+  , track_number                                                    -- :i64?
+  , disc_number                                                     -- :i64?
+  , source                                                          -- :str
+  , ( select client
+      from listen_clients
+      where listen_clients.listen_id = listens.id
+    ) as client                                                     -- :str?
+  , scrobbled_at                                                    -- :str?
+  , ( select value
+      from tags
+      where tags.file_id = listens.file_id
+        and tags.field_name = 'musicbrainz_trackid'
+      limit 1
+    ) as recording_mbid                                             -- :str?
+  , ( select value
+      from tags
+      where tags.file_id = listens.file_id
+        and tags.field_name = 'musicbrainz_albumid'
+      limit 1
+    ) as release_mbid                                               -- :str?
+from
+  listens
+order by
+  started_at asc;
+
+-- Iterate all listens with the fields needed to find duplicates and orphans,
+-- ordered by start time.
+-- @query iter_listens_for_repair() ->* RepairListen
+select
+    id                                                                 -- :i64
+  , started_at                                                         -- :str
+  , cast(strftime('%s', started_at) as integer) as started_at_seconds -- :i64
+  , completed_at                                                       -- :str?
+  , track_id                                                           -- :i64
+  , duration_seconds                                                   -- :i64
+  , source                                                             -- :str
+  , scrobbled_at                                                       -- :str?
+  , exists (
+      select 1 from skips where skips.listen_id = listens.id
+    ) as is_skipped                                                    -- :i64
+from
+  listens
+order by
+  started_at_seconds asc, id asc;
+
+-- Fill the completion and scrobble time of a listen from a duplicate that we
+-- are about to delete, where the listen does not have them already.
+-- @query update_listen_merge(listen_id: i64, completed_at: str?, scrobbled_at: str?)
+update
+  listens
+set
+  completed_at = coalesce(completed_at, :completed_at),
+  scrobbled_at = coalesce(scrobbled_at, :scrobbled_at)
+where
+  id = :listen_id;
+
+-- @query update_listen_completed_at(listen_id: i64, completed_at: str)
+update listens set completed_at = :completed_at where id = :listen_id and completed_at is null;
+
+-- Move the client of a listen to the listen it is merged into. When both
+-- listens have a client, the client of the merged-into listen wins, and the
+-- other one needs to be deleted separately.
+-- @query update_listen_client_listen(old_listen_id: i64, new_listen_id: i64)
+update or ignore listen_clients set listen_id = :new_listen_id where listen_id = :old_listen_id;
+
+-- Like update_listen_client_listen, but for the user of the listen.
+-- @query update_listen_user_listen(old_listen_id: i64, new_listen_id: i64)
+update or ignore listen_users set listen_id = :new_listen_id where listen_id = :old_listen_id;
+
+-- @query update_skips_listen(old_listen_id: i64, new_listen_id: i64)
+update skips set listen_id = :new_listen_id where listen_id = :old_listen_id;
+
+-- @query delete_listen(listen_id: i64)
+delete from listens where id = :listen_id;
+
+-- @query delete_listen_client(listen_id: i64)
+delete from listen_clients where listen_id = :listen_id;
+
+-- @query delete_listen_user(listen_id: i64)
+delete from listen_users where listen_id = :listen_id;
+
+-- Iterate the files that have the given tag, with the tag value.
+-- @query iter_tag_values(field_name: str) ->* (i64, str)
+select file_id, value from tags where field_name = :field_name;
+
 -- @query select_album_loudness_lufs(album_id: i64) ->? f64
 select bs17704_loudness_lufs from album_loudness where album_id = :album_id;
 
 -- @query select_track_loudness_lufs(track_id: i64) ->? f64
 select bs17704_loudness_lufs from track_loudness where track_id = :track_id;
 
+-- @query select_track_true_peak_dbtp(track_id: i64) ->? f64
+select true_peak_dbtp from track_loudness where track_id = :track_id and true_peak_dbtp is not null;
+
+-- Return the loudness and true peak of the album.
+-- @query select_album_dynamics(album_id: i64) ->? (f64, f64?)
+select bs17704_loudness_lufs, true_peak_dbtp from album_loudness where album_id = :album_id;
+
+-- Return the loudness and true peak of the track.
+-- @query select_track_dynamics(track_id: i64) ->? (f64, f64?)
+select bs17704_loudness_lufs, true_peak_dbtp from track_loudness where track_id = :track_id;
+
+-- Iterate the loudness and true peak of all albums. This includes albums that
+-- are no longer in the library.
+-- @query iter_album_dynamics() ->* (i64, f64, f64?)
+select album_id, bs17704_loudness_lufs, true_peak_dbtp from album_loudness;
+
+-- Iterate the loudness and true peak of all tracks.
+-- @query iter_track_dynamics() ->* (i64, f64, f64?)
+select track_id, bs17704_loudness_lufs, true_peak_dbtp from track_loudness;
+
 -- @query select_track_waveform(track_id: i64) ->? bytes
 select data from waveforms where track_id = :track_id;
 
+-- Delete data derived from files that no longer exist. The foreign keys
+-- cascade deletes of files to these tables, but only when foreign keys are
+-- enabled, and in the past, not every connection enabled them.
+-- @begin delete_orphaned_file_data()
+delete from tags where file_id not in (select id from files);
+delete from track_loudness where file_id not in (select id from files);
+delete from album_loudness where file_id not in (select id from files);
+delete from waveforms where file_id not in (select id from files);
+delete from thumbnails where file_id not in (select id from files);
+delete from acoustid_lookups where file_id not in (select id from files);
+delete from acoustid_proposals where file_id not in (select id from files);
+-- @end delete_orphaned_file_data
+
 -- Return the sum of the sizes (in bytes) of all thumbnails.
 -- @query select_thumbnails_count_and_total_size() ->1 (i64, i64)
 select count(*), sum(length(data)) from thumbnails;
 
+-- Iterate the ids of all albums that have a thumbnail, also those that are no
+-- longer in the library.
+-- @query iter_thumbnail_album_ids() ->* i64
+select album_id from thumbnails;
+
+-- Delete the thumbnail of the album, if it has one.
+-- @query delete_thumbnail(album_id: i64)
+delete from thumbnails where album_id = :album_id;
+
+-- Delete all thumbnails, so the next thumbnail generation recreates them.
+-- @query delete_thumbnails()
+delete from thumbnails;
+
 -- @query iter_thumbnails() ->* Thumbnail
 select album_id /*: i64 */, data /* :bytes */ from thumbnails;
 
@@ -312,16 +1598,76 @@ from
 group by
   album_id;
 
+-- For every user and track, return the number of listens, and the most recent one.
+-- @query iter_track_play_stats() ->* TrackPlayStats
+select
+    track_id                          -- :i64
+  , count(*) as play_count            -- :i64
+  , max(started_at) as last_played_at -- :str
+  , ( select user_name
+      from listen_users
+      where listen_users.listen_id = listens.id
+    ) as user_name                    -- :str?
+from
+  listens
+group by
+  user_name, track_id;
+
+-- @query iter_album_play_stats() ->* AlbumPlayStats
+select
+    album_id                          -- :i64
+  , count(*) as play_count            -- :i64
+  , max(started_at) as last_played_at -- :str
+  , ( select user_name
+      from listen_users
+      where listen_users.listen_id = listens.id
+    ) as user_name                    -- :str?
+from
+  listens
+group by
+  user_name, album_id;
+
+-- @query iter_artist_play_stats() ->* ArtistPlayStats
+select
+    album_artist_id                   -- :i64
+  , count(*) as play_count            -- :i64
+  , max(started_at) as last_played_at -- :str
+  , ( select user_name
+      from listen_users
+      where listen_users.listen_id = listens.id
+    ) as user_name                    -- :str?
+from
+  listens
+group by
+  user_name, album_artist_id;
+
+-- Record the import time of an album, unless we have one for it already.
+-- @query insert_album_import(album_id: i64, imported_at: str)
+insert or ignore into
+  album_imports (album_id, imported_at)
+values
+  (:album_id, :imported_at);
+
+-- Return the import time of every album that was ever imported.
+--
+-- Yields tuples `(album_id, imported_at_iso8601)`.
+-- @query iter_album_imports() ->* (i64, str)
+select
+  album_id, imported_at
+from
+  album_imports;
+
 -- Insert a rating for a given track.
 --
--- When the `created_at` timestamp is not unique, this replaces the previous
--- rating that was present for that timestamp. This might happen when the user
--- edits the rating in quick succession; then we only store the last write.
--- @query insert_or_replace_rating(track_id: i64, created_at: str, rating: i64)
+-- When the `created_at` timestamp is not unique for the user, this replaces the
+-- previous rating that was present for that timestamp. This might happen when
+-- the user edits the rating in quick succession; then we only store the last
+-- write. The user is NULL for the default user.
+-- @query insert_or_replace_rating(track_id: i64, created_at: str, rating: i64, user: str?)
 insert or replace into
-  ratings (track_id, created_at, rating, source)
+  ratings (track_id, created_at, rating, source, user_name)
 values
-  (:track_id, :created_at, :rating, 'musium');
+  (:track_id, :created_at, :rating, 'musium', :user);
 
 -- Backfill a rating for a given track.
 --
@@ -334,12 +1680,478 @@ values
 
 -- @query iter_ratings() ->* TrackRating
 select
-    id       -- :i64
-  , track_id -- :i64
-  , rating   -- :i64
+    id        -- :i64
+  , track_id  -- :i64
+  , rating    -- :i64
+  , user_name -- :str?
 from
   ratings
 order by
   -- Order by ascending creation time to ensure we can clamp to rating ranges,
-  -- should we need to. We have an index on this expression.
+  -- should we need to.
   cast(strftime('%s', created_at) as integer) asc;
+
+-- Insert a rating for a given album.
+--
+-- Like `insert_or_replace_rating`, but for albums.
+-- @query insert_or_replace_album_rating(album_id: i64, created_at: str, rating: i64, user: str?)
+insert or replace into
+  album_ratings (album_id, created_at, rating, source, user_name)
+values
+  (:album_id, :created_at, :rating, 'musium', :user);
+
+-- @query iter_album_ratings() ->* AlbumRating
+select
+    id        -- :i64
+  , album_id  -- :i64
+  , rating    -- :i64
+  , user_name -- :str?
+from
+  album_ratings
+order by
+  created_at asc;
+
+-- Insert a rating for a given album artist.
+--
+-- Like `insert_or_replace_rating`, but for artists.
+-- @query insert_or_replace_artist_rating(artist_id: i64, created_at: str, rating: i64, user: str?)
+insert or replace into
+  artist_ratings (artist_id, created_at, rating, source, user_name)
+values
+  (:artist_id, :created_at, :rating, 'musium', :user);
+
+-- @query iter_artist_ratings() ->* ArtistRating
+select
+    id        -- :i64
+  , artist_id -- :i64
+  , rating    -- :i64
+  , user_name -- :str?
+from
+  artist_ratings
+order by
+  created_at asc;
+
+-- Exclude a track from shuffling for the user. Excluding it twice is a no-op.
+-- @query insert_track_exclusion(track_id: i64, created_at: str, user: str?)
+insert or ignore into
+  track_exclusions (track_id, created_at, user_name)
+values
+  (:track_id, :created_at, :user);
+
+-- @query delete_track_exclusion(track_id: i64, user: str?)
+delete from track_exclusions where track_id = :track_id and user_name is :user;
+
+-- @query iter_track_exclusions() ->* (i64, str?)
+select track_id, user_name from track_exclusions;
+
+-- Like `insert_track_exclusion`, but for albums.
+-- @query insert_album_exclusion(album_id: i64, created_at: str, user: str?)
+insert or ignore into
+  album_exclusions (album_id, created_at, user_name)
+values
+  (:album_id, :created_at, :user);
+
+-- @query delete_album_exclusion(album_id: i64, user: str?)
+delete from album_exclusions where album_id = :album_id and user_name is :user;
+
+-- @query iter_album_exclusions() ->* (i64, str?)
+select album_id, user_name from album_exclusions;
+
+-- @query insert_playlist(name: str, query: str?, created_at: str, user: str?) ->1 i64
+insert into playlists (name, query, created_at, user_name)
+values (:name, :query, :created_at, :user)
+returning id;
+
+-- @query update_playlist_name(playlist_id: i64, name: str)
+update playlists set name = :name where id = :playlist_id;
+
+-- Delete a playlist and its entries (through the cascade).
+-- @query delete_playlist(playlist_id: i64)
+delete from playlists where id = :playlist_id;
+
+-- @query update_playlist_query(playlist_id: i64, query: str)
+update playlists set query = :query where id = :playlist_id;
+
+-- @query select_playlist_name(playlist_id: i64) ->? str
+select name from playlists where id = :playlist_id;
+
+-- Return the playlist, if it exists and belongs to the user.
+-- @query select_playlist(playlist_id: i64, user: str?) ->? PlaylistHeader
+select
+    name  -- :str
+  , query -- :str?
+from
+  playlists
+where
+  id = :playlist_id
+  and user_name is :user;
+
+-- Iterate the playlists of the user, ordered by name.
+-- @query iter_playlists(user: str?) ->* Playlist
+select
+    playlists.id                               -- :i64
+  , playlists.name                             -- :str
+  , playlists.query                            -- :str?
+  , count(playlist_entries.id) as track_count  -- :i64
+from
+  playlists
+  left join playlist_entries on playlist_entries.playlist_id = playlists.id
+where
+  playlists.user_name is :user
+group by
+  playlists.id
+order by
+  playlists.name asc, playlists.id asc;
+
+-- Iterate the entries of a playlist, in playlist order.
+-- @query iter_playlist_entries(playlist_id: i64) ->* PlaylistEntry
+select
+    id       -- :i64
+  , track_id -- :i64
+from
+  playlist_entries
+where
+  playlist_id = :playlist_id
+order by
+  position asc, id asc;
+
+-- Return the position one past the last entry of the playlist.
+-- @query select_playlist_next_position(playlist_id: i64) ->1 i64
+select
+  coalesce(max(position) + 1, 0) as next_position -- :i64
+from
+  playlist_entries
+where
+  playlist_id = :playlist_id;
+
+-- @query insert_playlist_entry(playlist_id: i64, position: i64, track_id: i64) ->1 i64
+insert into
+  playlist_entries (playlist_id, position, track_id)
+values
+  (:playlist_id, :position, :track_id)
+returning id;
+
+-- @query update_playlist_entry_position(entry_id: i64, position: i64)
+update playlist_entries set position = :position where id = :entry_id;
+
+-- @query delete_playlist_entry(playlist_id: i64, entry_id: i64)
+delete from playlist_entries where playlist_id = :playlist_id and id = :entry_id;
+
+-- @query insert_radio_station(name: str, url: str, created_at: str) ->1 i64
+insert into radio_stations (name, url, created_at)
+values (:name, :url, :created_at)
+returning id;
+
+-- @query delete_radio_station(station_id: i64)
+delete from radio_stations where id = :station_id;
+
+-- @query select_radio_station(station_id: i64) ->? (str, str)
+select name, url from radio_stations where id = :station_id;
+
+-- Iterate all radio stations, ordered by name.
+-- @query iter_radio_stations() ->* RadioStation
+select
+    id   -- :i64
+  , name -- :str
+  , url  -- :str
+from
+  radio_stations
+order by
+  name asc, id asc;
+
+-- @query insert_radio_listen(
+--   started_at: str,
+--   queue_id: i64,
+--   station_id: i64,
+--   station_name: str,
+--   stream_title: str?,
+-- ) ->1 i64
+insert into
+  radio_listens (started_at, queue_id, station_id, station_name, stream_title)
+values
+  (:started_at, :queue_id, :station_id, :station_name, :stream_title)
+returning id;
+
+-- @query update_radio_listen_completed(listen_id: i64, completed_at: str)
+update radio_listens set completed_at = :completed_at where id = :listen_id;
+
+-- @query delete_saved_queue()
+delete from saved_queue;
+
+-- @query insert_saved_queue_entry(
+--   position: i64,
+--   track_id: i64?,
+--   station_id: i64?,
+--   position_ms: i64,
+--   client: str?,
+--   user: str?,
+-- )
+insert into
+  saved_queue (position, track_id, station_id, position_ms, client, user_name)
+values
+  (:position, :track_id, :station_id, :position_ms, :client, :user);
+
+-- Iterate the saved queue, in queue order. For radio stations, the name and
+-- url are NULL when the station was deleted in the meantime.
+-- @query iter_saved_queue() ->* SavedQueueEntry
+select
+    saved_queue.track_id     -- :i64?
+  , saved_queue.station_id   -- :i64?
+  , radio_stations.name      -- :str?
+  , radio_stations.url       -- :str?
+  , saved_queue.position_ms  -- :i64
+  , saved_queue.client       -- :str?
+  , saved_queue.user_name    -- :str?
+from
+  saved_queue
+  left join radio_stations on radio_stations.id = saved_queue.station_id
+order by
+  saved_queue.position asc;
+
+-- @query insert_metadata_edit(edit: InsertMetadataEdit)
+insert into metadata_edits
+( edited_at
+, file_id
+, track_id
+, field_name
+, old_value
+, new_value
+, wrote_tags
+, user_name
+)
+values
+( :edited_at  -- :str
+, :file_id    -- :i64
+, :track_id   -- :i64
+, :field_name -- :str
+, :old_value  -- :str?
+, :new_value  -- :str?
+, :wrote_tags -- :i64
+, :user_name  -- :str?
+);
+
+-- Iterate the most recent metadata edits, newest first.
+-- @query iter_metadata_edits(limit: i64) ->* MetadataEdit
+select
+    id         -- :i64
+  , edited_at  -- :str
+  , track_id   -- :i64
+  , field_name -- :str
+  , old_value  -- :str?
+  , new_value  -- :str?
+  , wrote_tags -- :i64
+  , user_name  -- :str?
+from
+  metadata_edits
+order by
+  id desc
+limit
+  :limit;
+
+-- Yields tuples `(artist_id, canonical_artist_id, name, created_at)`.
+-- @query iter_artist_aliases() ->* (i64, i64, str, str)
+select
+  artist_id, canonical_artist_id, name, created_at
+from
+  artist_aliases
+order by
+  artist_id asc;
+
+-- Merge an artist into the canonical artist, or change its canonical artist.
+-- @query insert_artist_alias(artist_id: i64, canonical_artist_id: i64, name: str, created_at: str)
+insert into artist_aliases (artist_id, canonical_artist_id, name, created_at)
+values (:artist_id, :canonical_artist_id, :name, :created_at)
+on conflict (artist_id) do update
+set canonical_artist_id = :canonical_artist_id, name = :name, created_at = :created_at;
+
+-- When an artist that has aliases gets merged itself, its aliases follow it,
+-- so we never have to resolve chains of aliases.
+-- @query update_artist_alias_canonical(old_canonical_artist_id: i64, new_canonical_artist_id: i64)
+update artist_aliases
+set canonical_artist_id = :new_canonical_artist_id
+where canonical_artist_id = :old_canonical_artist_id;
+
+-- @query delete_artist_alias(artist_id: i64)
+delete from artist_aliases where artist_id = :artist_id;
+
+-- Attribute the listens of one album artist to another.
+-- @query update_listens_album_artist(old_artist_id: i64, new_artist_id: i64)
+update listens
+set album_artist_id = :new_artist_id
+where album_artist_id = :old_artist_id;
+
+-- Attribute the listens of one album to another album artist, when we undo a
+-- merge and the album belongs to the alias again.
+-- @query update_listens_album_artist_for_album(album_id: i64, old_artist_id: i64, new_artist_id: i64)
+update listens
+set album_artist_id = :new_artist_id
+where album_id = :album_id and album_artist_id = :old_artist_id;
+
+-- Record that we looked up the fingerprint of the file.
+-- @query insert_acoustid_lookup(file_id: i64, looked_up_at: str)
+insert into acoustid_lookups (file_id, looked_up_at)
+values (:file_id, :looked_up_at)
+on conflict (file_id) do update set looked_up_at = :looked_up_at;
+
+-- Return the ids of all files that we looked up already.
+-- @query iter_acoustid_lookups() ->* i64
+select file_id from acoustid_lookups;
+
+-- Store the tags that we propose for a file, pending review.
+-- @query insert_acoustid_proposal(proposal: InsertAcoustidProposal)
+insert into acoustid_proposals
+( file_id
+, acoustid
+, score
+, musicbrainz_trackid
+, title
+, artist
+, album
+, musicbrainz_albumid
+, albumartist
+, musicbrainz_albumartistid
+, tracknumber
+, discnumber
+, originaldate
+, status
+)
+values
+( :file_id                   -- :i64
+, :acoustid                  -- :str
+, :score                     -- :f64
+, :musicbrainz_trackid       -- :str
+, :title                     -- :str
+, :artist                    -- :str
+, :album                     -- :str
+, :musicbrainz_albumid       -- :str
+, :albumartist               -- :str
+, :musicbrainz_albumartistid -- :str
+, :tracknumber               -- :i64
+, :discnumber                -- :i64
+, :originaldate              -- :str
+, 'pending'
+)
+on conflict (file_id) do nothing;
+
+-- Iterate the proposals that await review, ordered by filename.
+-- @query iter_acoustid_proposals_pending() ->* AcoustidProposal
+select
+    acoustid_proposals.file_id                   -- :i64
+  , files.filename                               -- :str
+  , acoustid_proposals.acoustid                  -- :str
+  , acoustid_proposals.score                     -- :f64
+  , acoustid_proposals.musicbrainz_trackid       -- :str
+  , acoustid_proposals.title                     -- :str
+  , acoustid_proposals.artist                    -- :str
+  , acoustid_proposals.album                     -- :str
+  , acoustid_proposals.musicbrainz_albumid       -- :str
+  , acoustid_proposals.albumartist               -- :str
+  , acoustid_proposals.musicbrainz_albumartistid -- :str
+  , acoustid_proposals.tracknumber               -- :i64
+  , acoustid_proposals.discnumber                -- :i64
+  , acoustid_proposals.originaldate              -- :str
+  , acoustid_proposals.status                    -- :str
+from
+  acoustid_proposals
+  inner join files on files.id = acoustid_proposals.file_id
+where
+  acoustid_proposals.status = 'pending'
+order by
+  files.filename asc;
+
+-- @query select_acoustid_proposal(file_id: i64) ->? AcoustidProposal
+select
+    acoustid_proposals.file_id                   -- :i64
+  , files.filename                               -- :str
+  , acoustid_proposals.acoustid                  -- :str
+  , acoustid_proposals.score                     -- :f64
+  , acoustid_proposals.musicbrainz_trackid       -- :str
+  , acoustid_proposals.title                     -- :str
+  , acoustid_proposals.artist                    -- :str
+  , acoustid_proposals.album                     -- :str
+  , acoustid_proposals.musicbrainz_albumid       -- :str
+  , acoustid_proposals.albumartist               -- :str
+  , acoustid_proposals.musicbrainz_albumartistid -- :str
+  , acoustid_proposals.tracknumber               -- :i64
+  , acoustid_proposals.discnumber                -- :i64
+  , acoustid_proposals.originaldate              -- :str
+  , acoustid_proposals.status                    -- :str
+from
+  acoustid_proposals
+  inner join files on files.id = acoustid_proposals.file_id
+where
+  acoustid_proposals.file_id = :file_id;
+
+-- Set the status of a proposal to 'accepted' or 'rejected'.
+-- @query update_acoustid_proposal_status(file_id: i64, status: str)
+update acoustid_proposals set status = :status where file_id = :file_id;
+
+-- @query insert_or_replace_resume_position(track_id: i64, position_ms: i64, saved_at: str)
+insert or replace into
+  resume_positions (track_id, position_ms, saved_at)
+values
+  (:track_id, :position_ms, :saved_at);
+
+-- @query delete_resume_position(track_id: i64)
+delete from resume_positions where track_id = :track_id;
+
+-- @query select_resume_position(track_id: i64) ->? i64
+select position_ms from resume_positions where track_id = :track_id;
+
+-- Iterate all resume positions as (track_id, position_ms) pairs.
+-- @query iter_resume_positions() ->* (i64, i64)
+select track_id, position_ms from resume_positions;
+
+-- Return when the cached data of the artist expires, if we have data from the source.
+-- @query select_artist_enrichment_expiry(artist_id: i64, source: str) ->? i64
+select expires_at_seconds from artist_enrichment where artist_id = :artist_id and source = :source;
+
+-- Delete the cached data of the artist, including the similar artists and tags.
+-- @query delete_artist_enrichment(artist_id: i64)
+delete from artist_enrichment where artist_id = :artist_id;
+
+-- @query insert_artist_enrichment(artist_id: i64, source: str, expires_at_seconds: i64)
+insert into
+  artist_enrichment (artist_id, source, expires_at_seconds)
+values
+  (:artist_id, :source, :expires_at_seconds);
+
+-- @query insert_artist_enrichment_similar(artist_id: i64, rank: i64, name: str, mbid: str?, score: f64)
+insert into
+  artist_enrichment_similar (artist_id, rank, name, mbid, score)
+values
+  (:artist_id, :rank, :name, :mbid, :score);
+
+-- @query insert_artist_enrichment_tag(artist_id: i64, rank: i64, tag: str)
+insert into
+  artist_enrichment_tags (artist_id, rank, tag)
+values
+  (:artist_id, :rank, :tag);
+
+-- Iterate the cached similar artists as (name, mbid, score), most similar first.
+-- @query iter_artist_enrichment_similar(artist_id: i64) ->* (str, str?, f64)
+select name, mbid, score from artist_enrichment_similar where artist_id = :artist_id order by rank;
+
+-- Iterate the cached tags of the artist, most applicable first.
+-- @query iter_artist_enrichment_tags(artist_id: i64) ->* str
+select tag from artist_enrichment_tags where artist_id = :artist_id order by rank;
+
+-- Return a MusicBrainz id of an album artist that starts and ends like the
+-- pattern, for example 'f6f2326f-%-e235b25508e8'. Artist ids are made of the
+-- first and last 8 digits of the MusicBrainz id, so this finds the full id.
+-- @query select_album_artist_mbid(pattern: str) ->? str
+select value from tags where field_name = 'musicbrainz_albumartistid' and value like :pattern limit 1;
+
+-- Iterate all files as `(file_id, filename)`, in the order of their path.
+-- @query iter_files_for_verification() ->* (i64, str)
+select id, filename from files order by filename;
+
+-- Record an issue with a file, replacing an earlier one of the same kind.
+-- @query insert_file_issue(file_id: i64, kind: str, detail: str, found_at: str)
+insert or replace into
+  file_issues (file_id, kind, detail, found_at)
+values
+  (:file_id, :kind, :detail, :found_at);
+
+-- @query delete_file_issue(file_id: i64, kind: str)
+delete from file_issues where file_id = :file_id and kind = :kind;
@@ -6,11 +6,90 @@
 // A copy of the License has been included in the root of the repository.
 
 //! Interaction with Musium's SQLite database.
+//!
+//! Multiple threads and processes use the database at the same time. The
+//! database is in WAL mode, so readers never block, but there can be only one
+//! writer at a time. These are the writers:
+//!
+//! * The history thread records listens, skips, and ratings.
+//! * The scrobbler thread marks listens as scrobbled.
+//! * The scanner writes files, tags, loudness, waveforms, and thumbnails.
+//!   It commits once per album, to keep its transactions short.
+//! * The http server threads write playlists.
+//! * The `import`, `import-listens`, and `repair-listens` commands, which may
+//!   run while the server is running.
+//!
+//! Other threads only read. Writers wait for each other for up to
+//! `BUSY_TIMEOUT_MS`. But a deferred transaction that reads before it writes
+//! fails right away when another writer committed in the meantime, so writers
+//! that can run concurrently with other writers retry, see `retry_busy`.
 
 use std::path::Path;
+use std::time::Duration;
+
+use crate::database as db;
+use crate::database::{Connection, Transaction};
+use crate::metrics;
 
 pub type Result<T> = sqlite::Result<T>;
 
+/// How long a connection waits for a lock held by another writer, before it
+/// fails with `SQLITE_BUSY`.
+const BUSY_TIMEOUT_MS: usize = 10_000;
+
+/// Number of attempts for a write that fails because the database is busy.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry of a busy write, this doubles after every attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Schema migrations, in order. Applying migration `i` brings the schema to version `i + 1`.
+///
+/// To change the schema, append a migration here, and never change existing
+/// ones. Databases that predate versioning have version 0, but they do have
+/// tables. That is fine, version 1 creates its tables only if they don't exist.
+const MIGRATIONS: [fn(&mut Transaction) -> Result<()>; 18] = [
+    // Version 1: the schema as it was when we started tracking versions.
+    db::ensure_schema_exists,
+    // Version 2: size, inode, and audio MD5 of files, to detect moves.
+    db::add_file_identity,
+    // Version 3: users, for listens, ratings, and playlists.
+    db::add_users,
+    // Version 4: the play queue, saved at shutdown.
+    db::add_saved_queue,
+    // Version 5: the log of metadata edits.
+    db::add_metadata_edits,
+    // Version 6: artist aliases, for merging artists.
+    db::add_artist_aliases,
+    // Version 7: fingerprint lookups and tag proposals from AcoustID.
+    db::add_acoustid,
+    // Version 8: resume positions of long tracks.
+    db::add_resume_positions,
+    // Version 9: pre-gaps of files, from their cuesheet.
+    db::add_file_pregaps,
+    // Version 10: tracks and albums excluded from shuffling.
+    db::add_exclusions,
+    // Version 11: the cache of similar artists and tags from Last.fm or ListenBrainz.
+    db::add_artist_enrichment,
+    // Version 12: listening sessions.
+    db::add_listen_sessions,
+    // Version 13: first listens, for discovery statistics.
+    db::add_first_listens,
+    // Version 14: how much of the track played per listen.
+    db::add_listen_played_fraction,
+    // Version 15: the UTC offset of the local time of the listen.
+    db::add_listen_utc_offset,
+    // Version 16: the true peak of tracks and albums.
+    db::add_loudness_true_peak,
+    // Version 17: issues with files, from verifying their audio.
+    db::add_file_issues,
+    // Version 18: internet radio stations and their listens.
+    db::add_radio_stations,
+];
+
+/// The schema version that this version of Musium understands.
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
 fn connect_internal<P: AsRef<Path>>(
     path: P,
     flags: sqlite::OpenFlags,
@@ -19,8 +98,7 @@ fn connect_internal<P: AsRef<Path>>(
     // different threads.
     let flags = flags.set_no_mutex();
     let mut connection = sqlite::Connection::open_with_flags(path, flags)?;
-    let timeout_ms = 10_000;
-    connection.set_busy_timeout(timeout_ms)?;
+    connection.set_busy_timeout(BUSY_TIMEOUT_MS)?;
     // Use the faster WAL mode, see https://www.sqlite.org/wal.html.
     connection.execute("PRAGMA journal_mode = WAL;")?;
     connection.execute("PRAGMA foreign_keys = ON;")?;
@@ -36,3 +114,291 @@ pub fn connect_read_write<P: AsRef<Path>>(path: P) -> Result<sqlite::Connection>
     let flags = sqlite::OpenFlags::new().set_read_write().set_create();
     connect_internal(path, flags)
 }
+
+/// Return whether the error is one where retrying the write may succeed.
+pub fn is_busy(err: &sqlite::Error) -> bool {
+    // Extended result codes share the low byte with their primary code.
+    const SQLITE_BUSY: isize = 5;
+    const SQLITE_LOCKED: isize = 6;
+    match err.code {
+        Some(code) => code & 0xff == SQLITE_BUSY || code & 0xff == SQLITE_LOCKED,
+        None => false,
+    }
+}
+
+/// Call `f` until it succeeds, retrying with backoff while the database is busy.
+///
+/// When `f` fails, it must leave no trace, so it is safe to call it again.
+pub fn retry_busy<T, F>(mut f: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(err) if is_busy(&err) && attempt < MAX_ATTEMPTS => {
+                metrics::count_sqlite_busy_retry();
+                std::thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Return the queue that we saved when we last shut down, and delete it.
+pub fn take_saved_queue(connection: &sqlite::Connection) -> Result<Vec<db::SavedQueueEntry>> {
+    let mut db = Connection::new(connection);
+    with_write_transaction(&mut db, |tx| {
+        let entries = db::iter_saved_queue(tx)?.collect::<Result<Vec<_>>>()?;
+        db::delete_saved_queue(tx)?;
+        Ok(entries)
+    })
+}
+
+/// Check that we can write to the database, without writing anything.
+///
+/// Like any write, this waits for other writers for up to `BUSY_TIMEOUT_MS`.
+pub fn check_writable(db: &mut Connection) -> Result<()> {
+    let mut tx = db.begin()?;
+    let result = db::probe_write(&mut tx);
+    tx.rollback()?;
+    result
+}
+
+/// Run `f` in a transaction and commit it, retrying while the database is busy.
+///
+/// When `f` fails, the transaction is rolled back, so the connection is ready
+/// for the next transaction.
+pub fn with_write_transaction<T, F>(db: &mut Connection, mut f: F) -> Result<T>
+where
+    F: FnMut(&mut Transaction) -> Result<T>,
+{
+    retry_busy(|| {
+        let mut tx = db.begin()?;
+        match f(&mut tx) {
+            Ok(result) => {
+                tx.commit()?;
+                Ok(result)
+            }
+            Err(err) => {
+                tx.rollback()?;
+                Err(err)
+            }
+        }
+    })
+}
+
+/// Bring the schema up to date, applying pending migrations in one transaction.
+///
+/// Returns an error without touching the database if the schema is newer than
+/// `SCHEMA_VERSION`, when the database was used by a newer version of Musium.
+pub fn migrate(connection: &sqlite::Connection) -> Result<()> {
+    let mut db = Connection::new(connection);
+    let mut tx = db.begin()?;
+
+    let result = migrate_internal(connection, &mut tx);
+    match result {
+        Ok(()) => tx.commit(),
+        Err(err) => {
+            tx.rollback()?;
+            Err(err)
+        }
+    }
+}
+
+fn migrate_internal(connection: &sqlite::Connection, tx: &mut Transaction) -> Result<()> {
+    let version = db::select_schema_version(tx)?;
+
+    if version > SCHEMA_VERSION {
+        let message = format!(
+            "Database schema version is {}, but this version of Musium supports \
+            at most version {}. Please upgrade Musium.",
+            version, SCHEMA_VERSION,
+        );
+        return Err(sqlite::Error { code: None, message: Some(message) });
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(tx)?;
+        // The version is part of the database header, so this is transactional
+        // too. Pragmas do not support parameters, hence the format.
+        connection.execute(format!("PRAGMA user_version = {};", i + 1))?;
+        info!("Migrated database schema to version {}.", i + 1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{migrate, take_saved_queue, SCHEMA_VERSION};
+    use crate::database as db;
+    use crate::database::Connection;
+
+    fn get_version(connection: &sqlite::Connection) -> i64 {
+        let mut db = Connection::new(connection);
+        let mut tx = db.begin().unwrap();
+        let version = db::select_schema_version(&mut tx).unwrap();
+        tx.commit().unwrap();
+        version
+    }
+
+    #[test]
+    fn migrate_applies_migrations_once() {
+        let connection = sqlite::open(":memory:").unwrap();
+        assert_eq!(get_version(&connection), 0);
+        migrate(&connection).unwrap();
+        assert_eq!(get_version(&connection), SCHEMA_VERSION);
+        migrate(&connection).unwrap();
+        assert_eq!(get_version(&connection), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn migrate_refuses_newer_schema() {
+        let connection = sqlite::open(":memory:").unwrap();
+        let newer = SCHEMA_VERSION + 1;
+        connection.execute(format!("PRAGMA user_version = {};", newer)).unwrap();
+        assert!(migrate(&connection).is_err());
+        assert_eq!(get_version(&connection), newer);
+        // The rollback means no tables were created either.
+        let mut db = Connection::new(&connection);
+        let mut tx = db.begin().unwrap();
+        assert!(db::iter_playlists(&mut tx, None).is_err());
+    }
+
+    #[test]
+    fn take_saved_queue_returns_entries_once() {
+        let connection = sqlite::open(":memory:").unwrap();
+        migrate(&connection).unwrap();
+        {
+            let mut db = Connection::new(&connection);
+            let mut tx = db.begin().unwrap();
+            db::insert_saved_queue_entry(&mut tx, 1, Some(42), None, 0, None, Some("alex")).unwrap();
+            db::insert_saved_queue_entry(&mut tx, 0, Some(7), None, 61_000, Some("webinterface"), None).unwrap();
+            db::insert_saved_queue_entry(&mut tx, 2, None, Some(3), 0, None, None).unwrap();
+            tx.commit().unwrap();
+        }
+
+        let entries = take_saved_queue(&connection).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].track_id, Some(7));
+        assert_eq!(entries[0].position_ms, 61_000);
+        assert_eq!(entries[0].client.as_deref(), Some("webinterface"));
+        assert_eq!(entries[1].user_name.as_deref(), Some("alex"));
+        // There is no station 3, so we can't restore it.
+        assert_eq!(entries[2].station_id, Some(3));
+        assert_eq!(entries[2].url, None);
+
+        assert!(take_saved_queue(&connection).unwrap().is_empty());
+    }
+
+    fn insert_file(tx: &mut db::Transaction, filename: &str) -> i64 {
+        let file = db::InsertFile {
+            filename: filename,
+            mtime: 1,
+            imported_at: "2023-06-01T12:00:00Z",
+            streaminfo_channels: 2,
+            streaminfo_bits_per_sample: 16,
+            streaminfo_num_samples: Some(44_100),
+            streaminfo_sample_rate: 44_100,
+            size_bytes: 1024,
+            inode: 7,
+            streaminfo_md5: Some("00ff"),
+        };
+        db::insert_file(tx, file).unwrap()
+    }
+
+    fn count_tags(tx: &mut db::Transaction, file_id: i64) -> usize {
+        db::iter_file_tags(tx, file_id).unwrap().count()
+    }
+
+    #[test]
+    fn insert_file_round_trips() {
+        let connection = sqlite::open(":memory:").unwrap();
+        migrate(&connection).unwrap();
+        let mut db = Connection::new(&connection);
+        let mut tx = db.begin().unwrap();
+        let file_id = insert_file(&mut tx, "/music/a.flac");
+        let files = db::iter_file_identities(&mut tx)
+            .unwrap()
+            .collect::<db::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].id, file_id);
+        assert_eq!(files[0].filename, "/music/a.flac");
+        assert_eq!(files[0].size_bytes, Some(1024));
+        assert_eq!(files[0].inode, Some(7));
+        assert_eq!(files[0].streaminfo_md5.as_deref(), Some("00ff"));
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn delete_file_cascades_to_tags() {
+        let connection = sqlite::open(":memory:").unwrap();
+        connection.execute("PRAGMA foreign_keys = ON;").unwrap();
+        migrate(&connection).unwrap();
+        let mut db = Connection::new(&connection);
+        let mut tx = db.begin().unwrap();
+        let file_id = insert_file(&mut tx, "/music/a.flac");
+        db::insert_tag(&mut tx, file_id, "title", "Apollo").unwrap();
+        assert_eq!(count_tags(&mut tx, file_id), 1);
+        db::delete_file(&mut tx, file_id).unwrap();
+        assert_eq!(count_tags(&mut tx, file_id), 0);
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn ratings_and_playlists_are_per_user() {
+        let connection = sqlite::open(":memory:").unwrap();
+        migrate(&connection).unwrap();
+        let mut db = Connection::new(&connection);
+        let mut tx = db.begin().unwrap();
+
+        // Ratings of different users in the same second don't replace each
+        // other, but a second rating by the same user does.
+        db::insert_or_replace_rating(&mut tx, 1, "2023-06-01T12:00:00.100Z", 2, None).unwrap();
+        db::insert_or_replace_rating(&mut tx, 1, "2023-06-01T12:00:00.200Z", -1, Some("alex")).unwrap();
+        db::insert_or_replace_rating(&mut tx, 1, "2023-06-01T12:00:00.300Z", 1, Some("alex")).unwrap();
+        let ratings = db::iter_ratings(&mut tx)
+            .unwrap()
+            .map(|r| r.map(|r| (r.user_name, r.rating)))
+            .collect::<db::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(ratings, [(None, 2), (Some("alex".to_string()), 1)]);
+
+        let mine = db::insert_playlist(&mut tx, "Mine", None, "2023-06-01T12:00:00Z", None).unwrap();
+        let theirs = db::insert_playlist(&mut tx, "Theirs", None, "2023-06-01T12:00:00Z", Some("alex")).unwrap();
+        let names = db::iter_playlists(&mut tx, None)
+            .unwrap()
+            .map(|p| p.map(|p| p.name))
+            .collect::<db::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(names, ["Mine"]);
+        assert!(db::select_playlist(&mut tx, mine, None).unwrap().is_some());
+        assert!(db::select_playlist(&mut tx, mine, Some("alex")).unwrap().is_none());
+        assert!(db::select_playlist(&mut tx, theirs, Some("alex")).unwrap().is_some());
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn delete_orphaned_file_data_deletes_tags_of_missing_files() {
+        // Without foreign keys, deleting the file leaves its tags behind.
+        let connection = sqlite::open(":memory:").unwrap();
+        migrate(&connection).unwrap();
+        let mut db = Connection::new(&connection);
+        let mut tx = db.begin().unwrap();
+        let file_id = insert_file(&mut tx, "/music/a.flac");
+        let other_id = insert_file(&mut tx, "/music/b.flac");
+        db::insert_tag(&mut tx, file_id, "title", "Apollo").unwrap();
+        db::insert_tag(&mut tx, other_id, "title", "Zeus").unwrap();
+        db::delete_file(&mut tx, file_id).unwrap();
+        assert_eq!(count_tags(&mut tx, file_id), 1);
+        db::delete_orphaned_file_data(&mut tx).unwrap();
+        assert_eq!(count_tags(&mut tx, file_id), 0);
+        assert_eq!(count_tags(&mut tx, other_id), 1);
+        tx.commit().unwrap();
+    }
+}
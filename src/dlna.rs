@@ -0,0 +1,915 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Exposing the library as a UPnP media server, for DLNA clients.
+//!
+//! Smart TVs and AV receivers find media servers with SSDP: they multicast a
+//! search, and servers respond with the url of their device description. From
+//! there, clients browse the library by calling the ContentDirectory service
+//! with SOAP. We present the library as artists, albums, and tracks, described
+//! in DIDL-Lite. Tracks stream from `/dlna/track`, like from the api, but
+//! without token, because DLNA clients have no way to present one.
+//!
+//! This module builds the documents and handles the control calls, `server.rs`
+//! serves them over http.
+
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::FromRawFd;
+use std::time::{Duration, Instant};
+
+use crate::md5;
+use crate::prim::{AlbumId, ArtistId, TrackId};
+use crate::string_utils::normalize_words;
+use crate::xspf::write_escaped;
+use crate::MetaIndex;
+
+const SSDP_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+
+const MEDIA_SERVER: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+/// How long clients may cache our announcements, in seconds.
+const MAX_AGE_SECONDS: u64 = 1800;
+
+/// DLNA flags for the streams: streaming transfer mode, DLNA 1.5.
+const DLNA_FLAGS: &str = "DLNA.ORG_FLAGS=01700000000000000000000000000000";
+
+/// The `contentFeatures.dlna.org` of a track, depending on whether we transcode.
+///
+/// For flac we support range requests (`OP=01`), transcoded streams are not
+/// seekable, and are converted (`CI=1`).
+pub fn content_features(transcoded: bool) -> String {
+    match transcoded {
+        false => format!("DLNA.ORG_OP=01;DLNA.ORG_CI=0;{}", DLNA_FLAGS),
+        true => format!("DLNA.ORG_PN=MP3;DLNA.ORG_OP=00;DLNA.ORG_CI=1;{}", DLNA_FLAGS),
+    }
+}
+
+/// Return a stable uuid for the server, derived from its name.
+///
+/// Clients remember servers by uuid, so it should not change across restarts.
+pub fn device_uuid(name: &str) -> String {
+    let h = md5::md5_hex(format!("musium dlna {}", name).as_bytes());
+    format!("{}-{}-{}-{}-{}", &h[0..8], &h[8..12], &h[12..16], &h[16..20], &h[20..32])
+}
+
+/// Write the device description, the document that SSDP points clients to.
+pub fn write_device_description<W: Write>(mut w: W, name: &str, uuid: &str) -> io::Result<()> {
+    write!(w, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+    write!(w, r#"<root xmlns="urn:schemas-upnp-org:device-1-0" xmlns:dlna="urn:schemas-dlna-org:device-1-0">"#)?;
+    write!(w, "<specVersion><major>1</major><minor>0</minor></specVersion>")?;
+    write!(w, "<device><deviceType>{}</deviceType>", MEDIA_SERVER)?;
+    write!(w, "<dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC>")?;
+    write!(w, "<friendlyName>")?;
+    write_escaped(&mut w, name)?;
+    write!(w, "</friendlyName>")?;
+    write!(w, "<manufacturer>Musium</manufacturer>")?;
+    write!(w, "<manufacturerURL>https://github.com/ruuda/musium</manufacturerURL>")?;
+    write!(w, "<modelName>Musium</modelName>")?;
+    write!(w, "<UDN>uuid:{}</UDN><serviceList>", uuid)?;
+    for (service_type, service) in [(CONTENT_DIRECTORY, "ContentDirectory"), (CONNECTION_MANAGER, "ConnectionManager")] {
+        write!(
+            w,
+            "<service><serviceType>{0}</serviceType>\
+            <serviceId>urn:upnp-org:serviceId:{1}</serviceId>\
+            <SCPDURL>/dlna/{1}.xml</SCPDURL>\
+            <controlURL>/dlna/control/{1}</controlURL>\
+            <eventSubURL>/dlna/event/{1}</eventSubURL></service>",
+            service_type, service,
+        )?;
+    }
+    write!(w, "</serviceList></device></root>")
+}
+
+/// Service description of the ContentDirectory, the actions we implement.
+pub const CONTENT_DIRECTORY_SCPD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+<specVersion><major>1</major><minor>0</minor></specVersion>
+<actionList>
+<action><name>Browse</name><argumentList>
+<argument><name>ObjectID</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_ObjectID</relatedStateVariable></argument>
+<argument><name>BrowseFlag</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_BrowseFlag</relatedStateVariable></argument>
+<argument><name>Filter</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Filter</relatedStateVariable></argument>
+<argument><name>StartingIndex</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Index</relatedStateVariable></argument>
+<argument><name>RequestedCount</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+<argument><name>SortCriteria</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_SortCriteria</relatedStateVariable></argument>
+<argument><name>Result</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Result</relatedStateVariable></argument>
+<argument><name>NumberReturned</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+<argument><name>TotalMatches</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+<argument><name>UpdateID</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_UpdateID</relatedStateVariable></argument>
+</argumentList></action>
+<action><name>Search</name><argumentList>
+<argument><name>ContainerID</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_ObjectID</relatedStateVariable></argument>
+<argument><name>SearchCriteria</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_SearchCriteria</relatedStateVariable></argument>
+<argument><name>Filter</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Filter</relatedStateVariable></argument>
+<argument><name>StartingIndex</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Index</relatedStateVariable></argument>
+<argument><name>RequestedCount</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+<argument><name>SortCriteria</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_SortCriteria</relatedStateVariable></argument>
+<argument><name>Result</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Result</relatedStateVariable></argument>
+<argument><name>NumberReturned</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+<argument><name>TotalMatches</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+<argument><name>UpdateID</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_UpdateID</relatedStateVariable></argument>
+</argumentList></action>
+<action><name>GetSearchCapabilities</name><argumentList>
+<argument><name>SearchCaps</name><direction>out</direction><relatedStateVariable>SearchCapabilities</relatedStateVariable></argument>
+</argumentList></action>
+<action><name>GetSortCapabilities</name><argumentList>
+<argument><name>SortCaps</name><direction>out</direction><relatedStateVariable>SortCapabilities</relatedStateVariable></argument>
+</argumentList></action>
+<action><name>GetSystemUpdateID</name><argumentList>
+<argument><name>Id</name><direction>out</direction><relatedStateVariable>SystemUpdateID</relatedStateVariable></argument>
+</argumentList></action>
+</actionList>
+<serviceStateTable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_ObjectID</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_Result</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_BrowseFlag</name><dataType>string</dataType><allowedValueList><allowedValue>BrowseMetadata</allowedValue><allowedValue>BrowseDirectChildren</allowedValue></allowedValueList></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_Filter</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_SortCriteria</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_SearchCriteria</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_Index</name><dataType>ui4</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_Count</name><dataType>ui4</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_UpdateID</name><dataType>ui4</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>SearchCapabilities</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>SortCapabilities</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="yes"><name>SystemUpdateID</name><dataType>ui4</dataType></stateVariable>
+</serviceStateTable>
+</scpd>
+"#;
+
+/// Service description of the ConnectionManager, which DLNA requires.
+pub const CONNECTION_MANAGER_SCPD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+<specVersion><major>1</major><minor>0</minor></specVersion>
+<actionList>
+<action><name>GetProtocolInfo</name><argumentList>
+<argument><name>Source</name><direction>out</direction><relatedStateVariable>SourceProtocolInfo</relatedStateVariable></argument>
+<argument><name>Sink</name><direction>out</direction><relatedStateVariable>SinkProtocolInfo</relatedStateVariable></argument>
+</argumentList></action>
+<action><name>GetCurrentConnectionIDs</name><argumentList>
+<argument><name>ConnectionIDs</name><direction>out</direction><relatedStateVariable>CurrentConnectionIDs</relatedStateVariable></argument>
+</argumentList></action>
+<action><name>GetCurrentConnectionInfo</name><argumentList>
+<argument><name>ConnectionID</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_ConnectionID</relatedStateVariable></argument>
+<argument><name>RcsID</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_RcsID</relatedStateVariable></argument>
+<argument><name>AVTransportID</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_AVTransportID</relatedStateVariable></argument>
+<argument><name>ProtocolInfo</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_ProtocolInfo</relatedStateVariable></argument>
+<argument><name>PeerConnectionManager</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_ConnectionManager</relatedStateVariable></argument>
+<argument><name>PeerConnectionID</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_ConnectionID</relatedStateVariable></argument>
+<argument><name>Direction</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Direction</relatedStateVariable></argument>
+<argument><name>Status</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_ConnectionStatus</relatedStateVariable></argument>
+</argumentList></action>
+</actionList>
+<serviceStateTable>
+<stateVariable sendEvents="yes"><name>SourceProtocolInfo</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="yes"><name>SinkProtocolInfo</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="yes"><name>CurrentConnectionIDs</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_ConnectionStatus</name><dataType>string</dataType><allowedValueList><allowedValue>OK</allowedValue><allowedValue>ContentFormatMismatch</allowedValue><allowedValue>InsufficientBandwidth</allowedValue><allowedValue>UnreliableChannel</allowedValue><allowedValue>Unknown</allowedValue></allowedValueList></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_ConnectionManager</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_Direction</name><dataType>string</dataType><allowedValueList><allowedValue>Input</allowedValue><allowedValue>Output</allowedValue></allowedValueList></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_ProtocolInfo</name><dataType>string</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_ConnectionID</name><dataType>i4</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_AVTransportID</name><dataType>i4</dataType></stateVariable>
+<stateVariable sendEvents="no"><name>A_ARG_TYPE_RcsID</name><dataType>i4</dataType></stateVariable>
+</serviceStateTable>
+</scpd>
+"#;
+
+/// A node in the tree that clients browse.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Object {
+    Root,
+    Artists,
+    Albums,
+    Artist(ArtistId),
+    Album(AlbumId),
+    Track(TrackId),
+}
+
+impl Object {
+    fn parse(id: &str) -> Option<Object> {
+        match id {
+            "0" => return Some(Object::Root),
+            "artists" => return Some(Object::Artists),
+            "albums" => return Some(Object::Albums),
+            _ => {}
+        }
+        match id.split_once(':')? {
+            ("artist", a) => ArtistId::parse(a).map(Object::Artist),
+            ("album", a) => AlbumId::parse(a).map(Object::Album),
+            ("track", t) => TrackId::parse(t).map(Object::Track),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> String {
+        match self {
+            Object::Root => "0".to_string(),
+            Object::Artists => "artists".to_string(),
+            Object::Albums => "albums".to_string(),
+            Object::Artist(id) => format!("artist:{}", id),
+            Object::Album(id) => format!("album:{}", id),
+            Object::Track(id) => format!("track:{}", id),
+        }
+    }
+
+    /// The container that we list the object in when browsing its metadata.
+    fn parent_id(&self) -> String {
+        match self {
+            Object::Root => "-1".to_string(),
+            Object::Artists | Object::Albums => "0".to_string(),
+            Object::Artist(..) => "artists".to_string(),
+            Object::Album(..) => "albums".to_string(),
+            Object::Track(id) => Object::Album(id.album_id()).id(),
+        }
+    }
+
+    /// Return whether the object exists in the index.
+    fn exists(&self, index: &dyn MetaIndex) -> bool {
+        match self {
+            Object::Root | Object::Artists | Object::Albums => true,
+            Object::Artist(id) => index.get_artist(*id).is_some(),
+            Object::Album(id) => index.get_album(*id).is_some(),
+            Object::Track(id) => index.get_track(*id).is_some(),
+        }
+    }
+
+    /// Return the children of a container, or nothing for a track.
+    fn children(&self, index: &dyn MetaIndex) -> Vec<Object> {
+        match self {
+            Object::Root => vec![Object::Artists, Object::Albums],
+            Object::Artists => {
+                let mut artists: Vec<_> = index.get_artists().iter().collect();
+                artists.sort_by_key(|kv| index.get_string(kv.artist.name_for_sort));
+                artists.iter().map(|kv| Object::Artist(kv.artist_id)).collect()
+            }
+            Object::Albums => {
+                let mut albums: Vec<_> = index.get_albums().iter().collect();
+                albums.sort_by_key(|kv| (index.get_string(kv.album.title), kv.album.original_release_date));
+                albums.iter().map(|kv| Object::Album(kv.album_id)).collect()
+            }
+            Object::Artist(id) => {
+                index.get_albums_by_artist(*id).iter().map(|(_, album_id)| Object::Album(*album_id)).collect()
+            }
+            Object::Album(id) => {
+                index.get_album_tracks(*id).iter().map(|kv| Object::Track(kv.track_id)).collect()
+            }
+            Object::Track(..) => Vec::new(),
+        }
+    }
+
+    /// Return the number of children, without building the list.
+    fn child_count(&self, index: &dyn MetaIndex) -> usize {
+        match self {
+            Object::Root => 2,
+            Object::Artists => index.get_artists().len(),
+            Object::Albums => index.get_albums().len(),
+            Object::Artist(id) => index.get_albums_by_artist(*id).len(),
+            Object::Album(id) => index.get_album_tracks(*id).len(),
+            Object::Track(..) => 0,
+        }
+    }
+}
+
+fn write_element<W: Write>(mut w: W, tag: &str, content: &str) -> io::Result<()> {
+    write!(w, "<{}>", tag)?;
+    write_escaped(&mut w, content)?;
+    write!(w, "</{}>", tag)
+}
+
+/// Write the DIDL-Lite description of one object.
+fn write_object<W: Write>(
+    mut w: W,
+    index: &dyn MetaIndex,
+    base_url: &str,
+    object: Object,
+    parent_id: &str,
+) -> io::Result<()> {
+    let track_id = match object {
+        Object::Track(id) => id,
+        _ => {
+            write!(
+                w,
+                r#"<container id="{}" parentID="{}" restricted="1" searchable="1" childCount="{}">"#,
+                object.id(), parent_id, object.child_count(index),
+            )?;
+            match object {
+                Object::Artist(id) => {
+                    let artist = index.get_artist(id).expect("Only existing objects get written.");
+                    write_element(&mut w, "dc:title", index.get_string(artist.name))?;
+                    write!(w, "<upnp:class>object.container.person.musicArtist</upnp:class>")?;
+                }
+                Object::Album(id) => {
+                    let album = index.get_album(id).expect("Only existing objects get written.");
+                    let date = album.original_release_date;
+                    write_element(&mut w, "dc:title", index.get_string(album.title))?;
+                    write_element(&mut w, "upnp:artist", index.get_string(album.artist))?;
+                    write_element(&mut w, "dc:creator", index.get_string(album.artist))?;
+                    write!(w, "<dc:date>{:04}-{:02}-{:02}</dc:date>", date.year, date.month.max(1), date.day.max(1))?;
+                    write!(w, "<upnp:albumArtURI>{}/dlna/cover/{}</upnp:albumArtURI>", base_url, id)?;
+                    write!(w, "<upnp:class>object.container.album.musicAlbum</upnp:class>")?;
+                }
+                Object::Artists => {
+                    write!(w, "<dc:title>Artists</dc:title><upnp:class>object.container</upnp:class>")?;
+                }
+                Object::Albums => {
+                    write!(w, "<dc:title>Albums</dc:title><upnp:class>object.container</upnp:class>")?;
+                }
+                _ => {
+                    write!(w, "<dc:title>Musium</dc:title><upnp:class>object.container</upnp:class>")?;
+                }
+            }
+            return write!(w, "</container>");
+        }
+    };
+
+    let track = index.get_track(track_id).expect("Only existing objects get written.");
+    let album_id = track_id.album_id();
+    let album = index.get_album(album_id).expect("Tracks belong to an album.");
+    write!(w, r#"<item id="{}" parentID="{}" restricted="1">"#, object.id(), parent_id)?;
+    write_element(&mut w, "dc:title", index.get_string(track.title))?;
+    write_element(&mut w, "dc:creator", index.get_string(track.artist))?;
+    write_element(&mut w, "upnp:artist", index.get_string(track.artist))?;
+    write_element(&mut w, "upnp:album", index.get_string(album.title))?;
+    write!(w, "<upnp:originalTrackNumber>{}</upnp:originalTrackNumber>", track_id.track_number())?;
+    write!(w, "<upnp:albumArtURI>{}/dlna/cover/{}</upnp:albumArtURI>", base_url, album_id)?;
+    write!(w, "<upnp:class>object.item.audioItem.musicTrack</upnp:class>")?;
+    let seconds = track.duration_seconds;
+    let duration = format!("{}:{:02}:{:02}.000", seconds / 3600, (seconds / 60) % 60, seconds % 60);
+    // Offer the flac, and an mp3 for clients that can't play flac.
+    write!(
+        w,
+        r#"<res protocolInfo="http-get:*:audio/flac:{}" duration="{}">{}/dlna/track/{}.flac</res>"#,
+        content_features(false), duration, base_url, track_id,
+    )?;
+    write!(
+        w,
+        r#"<res protocolInfo="http-get:*:audio/mpeg:{}" duration="{}">{}/dlna/track/{}.flac?format=mp3</res>"#,
+        content_features(true), duration, base_url, track_id,
+    )?;
+    write!(w, "</item>")
+}
+
+/// Write a DIDL-Lite document with the given objects, all in one parent.
+fn write_didl<W: Write>(
+    mut w: W,
+    index: &dyn MetaIndex,
+    base_url: &str,
+    objects: &[Object],
+    parent_id: Option<&str>,
+) -> io::Result<()> {
+    write!(
+        w,
+        r#"<DIDL-Lite xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/" xmlns:dlna="urn:schemas-dlna-org:metadata-1-0/">"#
+    )?;
+    for object in objects {
+        let parent_id = match parent_id {
+            Some(p) => p.to_string(),
+            None => object.parent_id(),
+        };
+        write_object(&mut w, index, base_url, *object, &parent_id)?;
+    }
+    write!(w, "</DIDL-Lite>")
+}
+
+/// An error that we report to the client as a SOAP fault.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Fault {
+    pub code: u32,
+    pub description: &'static str,
+}
+
+const INVALID_ACTION: Fault = Fault { code: 401, description: "Invalid Action" };
+const INVALID_ARGS: Fault = Fault { code: 402, description: "Invalid Args" };
+const NO_SUCH_OBJECT: Fault = Fault { code: 701, description: "No such object" };
+
+/// Write the SOAP envelope for a fault, sent with status 500.
+pub fn write_fault<W: Write>(mut w: W, fault: Fault) -> io::Result<()> {
+    write!(
+        w,
+        r#"<?xml version="1.0" encoding="utf-8"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0"><errorCode>{}</errorCode><errorDescription>{}</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>"#,
+        fault.code, fault.description,
+    )
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Extract the value of an argument from a SOAP request body.
+///
+/// Arguments are plain elements in the action element. Some clients add
+/// attributes with type information, we skip over those.
+fn get_argument(body: &str, name: &str) -> Option<String> {
+    let open = format!("<{}", name);
+    let mut from = 0;
+    while let Some(i) = body[from..].find(&open) {
+        let after_name = from + i + open.len();
+        from = after_name;
+        let rest = &body[after_name..];
+        match rest.chars().next() {
+            Some('>') | Some(' ') | Some('/') | Some('\t') | Some('\r') | Some('\n') => {}
+            // A different element that starts with the same name.
+            _ => continue,
+        }
+        let tag_end = rest.find('>')?;
+        if rest[..tag_end].ends_with('/') {
+            return Some(String::new());
+        }
+        let content = &rest[tag_end + 1..];
+        let close = format!("</{}>", name);
+        let end = content.find(&close)?;
+        return Some(unescape(&content[..end]));
+    }
+    None
+}
+
+fn get_u32_argument(body: &str, name: &str) -> Result<u32, Fault> {
+    match get_argument(body, name) {
+        None => Ok(0),
+        Some(v) if v.trim().is_empty() => Ok(0),
+        Some(v) => v.trim().parse().map_err(|_| INVALID_ARGS),
+    }
+}
+
+/// What a search is for, and the words to search for.
+#[derive(Debug, Eq, PartialEq)]
+enum SearchCriteria {
+    Artists(Vec<String>),
+    Albums(Vec<String>),
+    Tracks(Vec<String>),
+}
+
+/// Interpret UPnP search criteria, as far as we can answer them.
+///
+/// Clients send criteria such as `upnp:class derivedfrom "object.item.audioItem"
+/// and dc:title contains "blue"`. The class determines what we search for,
+/// the strings that the properties are compared against become the query for
+/// our regular search. We don't distinguish between properties.
+fn parse_search_criteria(criteria: &str) -> SearchCriteria {
+    let mut query = String::new();
+    let mut tokens: Vec<&str> = Vec::new();
+    let mut rest = criteria.trim();
+    let mut is_album = false;
+    let mut is_artist = false;
+    while !rest.is_empty() {
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            let value = &quoted[..end];
+            let n = tokens.len();
+            let property = if n >= 2 { tokens[n - 2] } else { "" };
+            if property == "upnp:class" {
+                is_album = is_album || value.starts_with("object.container.album");
+                is_artist = is_artist || value.starts_with("object.container.person");
+            } else {
+                query.push(' ');
+                query.push_str(value);
+            }
+            rest = quoted[end..].strip_prefix('"').unwrap_or("").trim_start();
+            tokens.clear();
+            continue;
+        }
+        let end = rest.find(|c: char| c.is_whitespace() || c == '"').unwrap_or(rest.len());
+        let token = &rest[..end];
+        tokens.push(token.trim_matches(|c| c == '(' || c == ')'));
+        rest = rest[end..].trim_start();
+    }
+
+    let mut words = Vec::new();
+    normalize_words(&query, &mut words);
+    match (is_album, is_artist) {
+        (true, _) => SearchCriteria::Albums(words),
+        (false, true) => SearchCriteria::Artists(words),
+        (false, false) => SearchCriteria::Tracks(words),
+    }
+}
+
+fn search(index: &dyn MetaIndex, max_edits: u32, criteria: &str) -> Vec<Object> {
+    match parse_search_criteria(criteria) {
+        SearchCriteria::Artists(words) if words.is_empty() => Object::Artists.children(index),
+        SearchCriteria::Albums(words) if words.is_empty() => Object::Albums.children(index),
+        SearchCriteria::Tracks(words) if words.is_empty() => {
+            index.get_tracks().iter().map(|kv| Object::Track(kv.track_id)).collect()
+        }
+        SearchCriteria::Artists(words) => {
+            let mut ids = Vec::new();
+            index.search_artist(&words[..], max_edits, &mut ids);
+            ids.into_iter().map(Object::Artist).collect()
+        }
+        SearchCriteria::Albums(words) => {
+            let mut ids = Vec::new();
+            index.search_album(&words[..], max_edits, &mut ids);
+            ids.into_iter().map(Object::Album).collect()
+        }
+        SearchCriteria::Tracks(words) => {
+            let mut ids = Vec::new();
+            index.search_track(&words[..], max_edits, &mut ids);
+            ids.into_iter().map(Object::Track).collect()
+        }
+    }
+}
+
+/// Return the page of objects that the client requested, a count of 0 means all.
+fn page(objects: &[Object], starting_index: u32, requested_count: u32) -> &[Object] {
+    let start = (starting_index as usize).min(objects.len());
+    let end = match requested_count {
+        0 => objects.len(),
+        n => start.saturating_add(n as usize).min(objects.len()),
+    };
+    &objects[start..end]
+}
+
+/// The output arguments of an action.
+type Outputs = Vec<(&'static str, String)>;
+
+/// The system update id changes when the library changes.
+///
+/// We don't track changes, but a rescan that changes the library usually
+/// changes the number of tracks, which is enough for clients to refresh.
+fn system_update_id(index: &dyn MetaIndex) -> String {
+    (index.len() as u32).to_string()
+}
+
+fn browse_or_search(
+    index: &dyn MetaIndex,
+    max_edits: u32,
+    base_url: &str,
+    action: &str,
+    body: &str,
+) -> Result<Outputs, Fault> {
+    let starting_index = get_u32_argument(body, "StartingIndex")?;
+    let requested_count = get_u32_argument(body, "RequestedCount")?;
+
+    let (objects, parent_id) = if action == "Search" {
+        let criteria = get_argument(body, "SearchCriteria").unwrap_or_default();
+        (search(index, max_edits, &criteria), None)
+    } else {
+        let object_id = get_argument(body, "ObjectID").ok_or(INVALID_ARGS)?;
+        let object = Object::parse(object_id.trim()).ok_or(NO_SUCH_OBJECT)?;
+        if !object.exists(index) {
+            return Err(NO_SUCH_OBJECT);
+        }
+        match get_argument(body, "BrowseFlag").as_deref() {
+            Some("BrowseMetadata") => (vec![object], None),
+            Some("BrowseDirectChildren") => (object.children(index), Some(object.id())),
+            _ => return Err(INVALID_ARGS),
+        }
+    };
+
+    let selected = page(&objects, starting_index, requested_count);
+    let mut didl = Vec::new();
+    write_didl(&mut didl, index, base_url, selected, parent_id.as_deref())
+        .expect("Writing to a Vec does not fail.");
+
+    Ok(vec![
+        ("Result", String::from_utf8(didl).expect("We only write valid UTF-8.")),
+        ("NumberReturned", selected.len().to_string()),
+        ("TotalMatches", objects.len().to_string()),
+        ("UpdateID", system_update_id(index)),
+    ])
+}
+
+/// Handle a SOAP call to one of the services.
+///
+/// The `soap_action` is the value of the `SOAPACTION` header, which names the
+/// service type and action. Returns the response envelope, or a fault.
+pub fn handle_control(
+    index: &dyn MetaIndex,
+    max_edits: u32,
+    base_url: &str,
+    soap_action: &str,
+    body: &str,
+) -> Result<String, Fault> {
+    let (service_type, action) = soap_action.trim().trim_matches('"').split_once('#').ok_or(INVALID_ACTION)?;
+
+    let outputs = match (service_type, action) {
+        (CONTENT_DIRECTORY, "Browse") | (CONTENT_DIRECTORY, "Search") => {
+            browse_or_search(index, max_edits, base_url, action, body)?
+        }
+        (CONTENT_DIRECTORY, "GetSearchCapabilities") => {
+            vec![("SearchCaps", "dc:title,dc:creator,upnp:artist,upnp:album,upnp:class".to_string())]
+        }
+        (CONTENT_DIRECTORY, "GetSortCapabilities") => vec![("SortCaps", String::new())],
+        (CONTENT_DIRECTORY, "GetSystemUpdateID") => vec![("Id", system_update_id(index))],
+        (CONNECTION_MANAGER, "GetProtocolInfo") => vec![
+            ("Source", "http-get:*:audio/flac:*,http-get:*:audio/mpeg:*".to_string()),
+            ("Sink", String::new()),
+        ],
+        (CONNECTION_MANAGER, "GetCurrentConnectionIDs") => vec![("ConnectionIDs", "0".to_string())],
+        (CONNECTION_MANAGER, "GetCurrentConnectionInfo") => vec![
+            ("RcsID", "-1".to_string()),
+            ("AVTransportID", "-1".to_string()),
+            ("ProtocolInfo", String::new()),
+            ("PeerConnectionManager", String::new()),
+            ("PeerConnectionID", "-1".to_string()),
+            ("Direction", "Output".to_string()),
+            ("Status", "OK".to_string()),
+        ],
+        _ => return Err(INVALID_ACTION),
+    };
+
+    let mut w = Vec::new();
+    write!(
+        w,
+        r#"<?xml version="1.0" encoding="utf-8"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:{}Response xmlns:u="{}">"#,
+        action, service_type,
+    ).expect("Writing to a Vec does not fail.");
+    for (name, value) in outputs {
+        write_element(&mut w, name, &value).expect("Writing to a Vec does not fail.");
+    }
+    write!(w, "</u:{}Response></s:Body></s:Envelope>", action).expect("Writing to a Vec does not fail.");
+    Ok(String::from_utf8(w).expect("We only write valid UTF-8."))
+}
+
+/// The notification types that we announce, and respond to searches for.
+fn targets(uuid: &str) -> [String; 5] {
+    [
+        "upnp:rootdevice".to_string(),
+        format!("uuid:{}", uuid),
+        MEDIA_SERVER.to_string(),
+        CONTENT_DIRECTORY.to_string(),
+        CONNECTION_MANAGER.to_string(),
+    ]
+}
+
+fn unique_service_name(uuid: &str, target: &str) -> String {
+    if target.starts_with("uuid:") {
+        target.to_string()
+    } else {
+        format!("uuid:{}::{}", uuid, target)
+    }
+}
+
+/// Return the search target of an SSDP search request, if it is one.
+fn parse_search_request(packet: &str) -> Option<&str> {
+    let mut lines = packet.lines();
+    if lines.next()?.trim() != "M-SEARCH * HTTP/1.1" {
+        return None;
+    }
+    let mut is_discover = false;
+    let mut search_target = None;
+    for line in lines {
+        let (key, value) = match line.split_once(':') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        match &key.trim().to_ascii_uppercase()[..] {
+            "MAN" => is_discover = value.trim() == "\"ssdp:discover\"",
+            "ST" => search_target = Some(value.trim()),
+            _ => {}
+        }
+    }
+    if is_discover { search_target } else { None }
+}
+
+/// Answers SSDP searches, and announces the server periodically.
+pub struct Ssdp {
+    socket: UdpSocket,
+    uuid: String,
+    port: u16,
+    scheme: &'static str,
+}
+
+impl Ssdp {
+    /// Join the SSDP multicast group, to receive searches.
+    ///
+    /// Other UPnP software on the host listens on the same port, so we set
+    /// `SO_REUSEADDR`, which we can only do before binding.
+    pub fn bind(uuid: String, port: u16, scheme: &'static str) -> io::Result<Ssdp> {
+        let socket = unsafe {
+            let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Take ownership right away, so we close the fd on error.
+            let socket = UdpSocket::from_raw_fd(fd);
+            let enable: libc::c_int = 1;
+            let result = libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_REUSEADDR,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let addr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: SSDP_PORT.to_be(),
+                sin_addr: libc::in_addr { s_addr: libc::INADDR_ANY },
+                sin_zero: [0; 8],
+            };
+            let result = libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            );
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            socket
+        };
+        socket.join_multicast_v4(&SSDP_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+        // Announcements should not leave the local network.
+        socket.set_multicast_ttl_v4(2)?;
+
+        let ssdp = Ssdp {
+            socket: socket,
+            uuid: uuid,
+            port: port,
+            scheme: scheme,
+        };
+        Ok(ssdp)
+    }
+
+    /// The url of the device description, as reachable from `local_ip`.
+    fn location(&self, local_ip: IpAddr) -> String {
+        format!("{}://{}/dlna/description.xml", self.scheme, SocketAddr::new(local_ip, self.port))
+    }
+
+    fn search_response(&self, target: &str, location: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\n\
+            CACHE-CONTROL: max-age={}\r\n\
+            EXT:\r\n\
+            LOCATION: {}\r\n\
+            SERVER: Linux UPnP/1.0 Musium\r\n\
+            ST: {}\r\n\
+            USN: {}\r\n\
+            \r\n",
+            MAX_AGE_SECONDS, location, target, unique_service_name(&self.uuid, target),
+        )
+    }
+
+    fn notify(&self, target: &str, location: &str) -> String {
+        format!(
+            "NOTIFY * HTTP/1.1\r\n\
+            HOST: {}:{}\r\n\
+            CACHE-CONTROL: max-age={}\r\n\
+            LOCATION: {}\r\n\
+            NT: {}\r\n\
+            NTS: ssdp:alive\r\n\
+            SERVER: Linux UPnP/1.0 Musium\r\n\
+            USN: {}\r\n\
+            \r\n",
+            SSDP_ADDR, SSDP_PORT, MAX_AGE_SECONDS, location, target, unique_service_name(&self.uuid, target),
+        )
+    }
+
+    /// Announce all targets to the multicast group.
+    fn announce(&self) -> io::Result<()> {
+        let group = SocketAddr::V4(SocketAddrV4::new(SSDP_ADDR, SSDP_PORT));
+        let location = self.location(local_ip_towards(group)?);
+        for target in targets(&self.uuid).iter() {
+            self.socket.send_to(self.notify(target, &location).as_bytes(), group)?;
+        }
+        Ok(())
+    }
+
+    fn respond(&self, search_target: &str, source: SocketAddr) -> io::Result<()> {
+        let location = self.location(local_ip_towards(source)?);
+        for target in targets(&self.uuid).iter() {
+            if search_target == "ssdp:all" || search_target == target {
+                self.socket.send_to(self.search_response(target, &location).as_bytes(), source)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Respond to searches and re-announce before announcements expire.
+    pub fn serve(&self) -> io::Result<()> {
+        let announce_interval = Duration::from_secs(MAX_AGE_SECONDS / 3);
+        let mut next_announce = Instant::now();
+        let mut buffer = [0_u8; 2048];
+        loop {
+            let now = Instant::now();
+            if now >= next_announce {
+                // When the network is down, we try again next time.
+                if let Err(err) = self.announce() {
+                    warn!("Failed to send SSDP announcement: {:?}", err);
+                }
+                next_announce = now + announce_interval;
+            }
+            let timeout = next_announce.saturating_duration_since(Instant::now());
+            self.socket.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+
+            let (len, source) = match self.socket.recv_from(&mut buffer) {
+                Ok(r) => r,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => continue,
+                Err(err) => return Err(err),
+            };
+            let packet = String::from_utf8_lossy(&buffer[..len]);
+            if let Some(search_target) = parse_search_request(&packet) {
+                if let Err(err) = self.respond(search_target, source) {
+                    warn!("Failed to respond to SSDP search from {}: {:?}", source, err);
+                }
+            }
+        }
+    }
+}
+
+/// Return the address of the interface that we would reach `dest` over.
+fn local_ip_towards(dest: SocketAddr) -> io::Result<IpAddr> {
+    // Connecting a udp socket sends nothing, but it does pick a route.
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(dest)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        device_uuid, get_argument, handle_control, parse_search_criteria, parse_search_request,
+        Object, SearchCriteria, CONTENT_DIRECTORY, NO_SUCH_OBJECT,
+    };
+    use crate::prim::{AlbumId, TrackId};
+    use crate::MemoryMetaIndex;
+
+    #[test]
+    fn device_uuid_is_stable_and_well_formed() {
+        let uuid = device_uuid("Musium");
+        assert_eq!(uuid, device_uuid("Musium"));
+        assert_ne!(uuid, device_uuid("Living room"));
+        let lens: Vec<usize> = uuid.split('-').map(|part| part.len()).collect();
+        assert_eq!(lens, vec![8, 4, 4, 4, 12]);
+    }
+
+    #[test]
+    fn object_ids_roundtrip() {
+        let objects = [
+            Object::Root,
+            Object::Artists,
+            Object::Albums,
+            Object::Album(AlbumId(0xa)),
+            Object::Track(TrackId(0xa01)),
+        ];
+        for object in objects.iter() {
+            assert_eq!(Object::parse(&object.id()), Some(*object));
+        }
+        assert_eq!(Object::parse("album:zz"), None);
+        assert_eq!(Object::parse("1"), None);
+    }
+
+    #[test]
+    fn get_argument_handles_attributes_and_escapes() {
+        let body = r#"<u:Browse xmlns:u="urn:x"><ObjectIDs>x</ObjectIDs><ObjectID xmlns:dt="urn:dt" dt:dt="string">album:1</ObjectID><Filter/><SortCriteria></SortCriteria><SearchCriteria>dc:title contains &quot;a&amp;b&quot;</SearchCriteria></u:Browse>"#;
+        assert_eq!(get_argument(body, "ObjectID").as_deref(), Some("album:1"));
+        assert_eq!(get_argument(body, "Filter").as_deref(), Some(""));
+        assert_eq!(get_argument(body, "SortCriteria").as_deref(), Some(""));
+        assert_eq!(get_argument(body, "SearchCriteria").as_deref(), Some(r#"dc:title contains "a&b""#));
+        assert_eq!(get_argument(body, "StartingIndex"), None);
+    }
+
+    #[test]
+    fn parse_search_criteria_uses_class_and_strings() {
+        let words = |ws: &[&str]| ws.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            parse_search_criteria(r#"(upnp:class derivedfrom "object.container.album.musicAlbum" and dc:title contains "Blue Train")"#),
+            SearchCriteria::Albums(words(&["blue", "train"])),
+        );
+        assert_eq!(
+            parse_search_criteria(r#"upnp:class = "object.container.person.musicArtist" and dc:title contains "coltrane""#),
+            SearchCriteria::Artists(words(&["coltrane"])),
+        );
+        assert_eq!(
+            parse_search_criteria(r#"upnp:class derivedfrom "object.item.audioItem" and (dc:title contains "giant" or upnp:artist contains "steps")"#),
+            SearchCriteria::Tracks(words(&["giant", "steps"])),
+        );
+        assert_eq!(parse_search_criteria("*"), SearchCriteria::Tracks(Vec::new()));
+    }
+
+    #[test]
+    fn parse_search_request_extracts_target() {
+        let search = "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: ssdp:all\r\n\r\n";
+        assert_eq!(parse_search_request(search), Some("ssdp:all"));
+        let notify = "NOTIFY * HTTP/1.1\r\nNT: upnp:rootdevice\r\n\r\n";
+        assert_eq!(parse_search_request(notify), None);
+    }
+
+    #[test]
+    fn browse_root_lists_containers() {
+        let index = MemoryMetaIndex::new_empty();
+        let action = format!("\"{}#Browse\"", CONTENT_DIRECTORY);
+        let body = "<ObjectID>0</ObjectID><BrowseFlag>BrowseDirectChildren</BrowseFlag><StartingIndex>0</StartingIndex><RequestedCount>10</RequestedCount>";
+        let response = handle_control(&index, 1, "http://musium", &action, body).unwrap();
+        assert!(response.contains("<NumberReturned>2</NumberReturned>"));
+        assert!(response.contains("&lt;container id=&quot;artists&quot; parentID=&quot;0&quot;"));
+
+        let body = "<ObjectID>album:0000000000000a</ObjectID><BrowseFlag>BrowseMetadata</BrowseFlag>";
+        assert_eq!(handle_control(&index, 1, "http://musium", &action, body), Err(NO_SUCH_OBJECT));
+    }
+}
@@ -0,0 +1,241 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Loudness, true peak, and dynamic range of tracks and albums.
+//!
+//! The loudness analysis stores the integrated loudness and the true peak of
+//! every track and album, see loudness.rs. From those we compute the dynamic
+//! range as the peak to loudness ratio (PLR): the true peak minus the loudness.
+//! A master that was limited hard, "brickwalled", has a low ratio, so this is
+//! a way to compare different masters of the same album.
+
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use crate::database as db;
+use crate::database::Transaction;
+use crate::prim::{AlbumId, TrackId};
+use crate::MetaIndex;
+
+/// The loudness and true peak of a track or album.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Dynamics {
+    /// Integrated loudness in LUFS.
+    pub loudness: f64,
+
+    /// True peak in dBTP, `None` when the loudness was analyzed before we
+    /// measured the peak.
+    pub true_peak: Option<f64>,
+}
+
+impl Dynamics {
+    /// Return the peak to loudness ratio in dB, higher is more dynamic.
+    pub fn dynamic_range(&self) -> Option<f64> {
+        self.true_peak.map(|peak| peak - self.loudness)
+    }
+}
+
+/// Order in which the dynamics listings return their items.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DynamicsSort {
+    /// By dynamic range, most dynamic first. This is the default.
+    DynamicRange,
+    /// By loudness, quietest first.
+    Loudness,
+    /// By true peak, lowest first.
+    TruePeak,
+}
+
+impl FromStr for DynamicsSort {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<DynamicsSort, &'static str> {
+        match s {
+            "dynamic_range" => Ok(DynamicsSort::DynamicRange),
+            "loudness" => Ok(DynamicsSort::Loudness),
+            "true_peak" => Ok(DynamicsSort::TruePeak),
+            _ => Err("Invalid sort order, must be one of dynamic_range, loudness, true_peak."),
+        }
+    }
+}
+
+/// The `sort`, `offset`, and `limit` parameters of a dynamics listing.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DynamicsParams {
+    pub sort: DynamicsSort,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl DynamicsParams {
+    /// Parse the parameters from the query string of a request.
+    ///
+    /// All parameters are optional, by default we list everything, most
+    /// dynamic first.
+    pub fn parse(raw_query: &str) -> Result<DynamicsParams, &'static str> {
+        let mut params = DynamicsParams {
+            sort: DynamicsSort::DynamicRange,
+            offset: 0,
+            limit: None,
+        };
+
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "sort" => params.sort = DynamicsSort::from_str(v.as_ref())?,
+                "offset" => match usize::from_str(v.as_ref()) {
+                    Ok(n) => params.offset = n,
+                    Err(_) => return Err("Invalid offset, must be a non-negative integer."),
+                }
+                "limit" => match usize::from_str(v.as_ref()) {
+                    Ok(n) => params.limit = Some(n),
+                    Err(_) => return Err("Invalid limit, must be a non-negative integer."),
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(params)
+    }
+
+    /// Sort the items, and return the page selected by the offset and limit.
+    fn sort_and_page<T>(&self, mut items: Vec<(T, Dynamics)>) -> Vec<(T, Dynamics)> {
+        // Items without a value go last. The sort is stable, and the items
+        // come ordered by id, so ties are broken by id.
+        let key = |d: &Dynamics| match self.sort {
+            DynamicsSort::DynamicRange => d.dynamic_range().map(|x| -x),
+            DynamicsSort::Loudness => Some(d.loudness),
+            DynamicsSort::TruePeak => d.true_peak,
+        };
+        items.sort_by(|(_, a), (_, b)| match (key(a), key(b)) {
+            (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        });
+
+        let begin = self.offset.min(items.len());
+        let end = match self.limit {
+            Some(n) => begin.saturating_add(n).min(items.len()),
+            None => items.len(),
+        };
+        items.truncate(end);
+        items.drain(..begin);
+        items
+    }
+}
+
+/// Return the page of albums in the index selected by the parameters.
+pub fn list_album_dynamics(
+    tx: &mut Transaction,
+    index: &dyn MetaIndex,
+    params: &DynamicsParams,
+) -> db::Result<Vec<(AlbumId, Dynamics)>> {
+    let mut albums = Vec::new();
+    for row in db::iter_album_dynamics(tx)? {
+        let (album_id, loudness, true_peak) = row?;
+        let album_id = AlbumId(album_id as u64);
+        // The table keeps albums that are no longer in the library.
+        if index.get_album(album_id).is_some() {
+            albums.push((album_id, Dynamics { loudness: loudness, true_peak: true_peak }));
+        }
+    }
+    Ok(params.sort_and_page(albums))
+}
+
+/// Return the page of tracks in the index selected by the parameters.
+pub fn list_track_dynamics(
+    tx: &mut Transaction,
+    index: &dyn MetaIndex,
+    params: &DynamicsParams,
+) -> db::Result<Vec<(TrackId, Dynamics)>> {
+    let mut tracks = Vec::new();
+    for row in db::iter_track_dynamics(tx)? {
+        let (track_id, loudness, true_peak) = row?;
+        let track_id = TrackId(track_id as u64);
+        if index.get_track(track_id).is_some() {
+            tracks.push((track_id, Dynamics { loudness: loudness, true_peak: true_peak }));
+        }
+    }
+    Ok(params.sort_and_page(tracks))
+}
+
+/// The dynamics of an album and of its tracks.
+pub struct AlbumDynamics {
+    /// `None` when the album has not been analyzed yet.
+    pub album: Option<Dynamics>,
+
+    /// The tracks in album order, `None` for tracks not analyzed yet.
+    pub tracks: Vec<(TrackId, Option<Dynamics>)>,
+}
+
+/// Return the dynamics of the album and its tracks.
+pub fn get_album_dynamics(
+    tx: &mut Transaction,
+    index: &dyn MetaIndex,
+    album_id: AlbumId,
+) -> db::Result<AlbumDynamics> {
+    let to_dynamics = |(loudness, true_peak)| Dynamics { loudness: loudness, true_peak: true_peak };
+    let album = db::select_album_dynamics(tx, album_id.0 as i64)?.map(to_dynamics);
+    let mut tracks = Vec::new();
+    for kv in index.get_album_tracks(album_id) {
+        let track = db::select_track_dynamics(tx, kv.track_id.0 as i64)?.map(to_dynamics);
+        tracks.push((kv.track_id, track));
+    }
+    let result = AlbumDynamics {
+        album: album,
+        tracks: tracks,
+    };
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Dynamics, DynamicsParams, DynamicsSort};
+
+    fn dynamics(loudness: f64, true_peak: Option<f64>) -> Dynamics {
+        Dynamics { loudness: loudness, true_peak: true_peak }
+    }
+
+    #[test]
+    fn dynamics_params_parse_sort_and_page() {
+        let params = DynamicsParams::parse("").unwrap();
+        assert_eq!(params.sort, DynamicsSort::DynamicRange);
+        assert_eq!(params.limit, None);
+
+        let params = DynamicsParams::parse("sort=true_peak&offset=5&limit=10").unwrap();
+        assert_eq!(params.sort, DynamicsSort::TruePeak);
+        assert_eq!(params.offset, 5);
+        assert_eq!(params.limit, Some(10));
+
+        assert!(DynamicsParams::parse("sort=name").is_err());
+        assert!(DynamicsParams::parse("limit=-1").is_err());
+    }
+
+    #[test]
+    fn sort_and_page_puts_unknown_values_last() {
+        let items = vec![
+            (1, dynamics(-6.0, Some(0.0))),
+            (2, dynamics(-14.0, None)),
+            (3, dynamics(-12.0, Some(-1.0))),
+            (4, dynamics(-8.0, Some(-0.5))),
+        ];
+
+        let mut params = DynamicsParams::parse("").unwrap();
+        let ids: Vec<_> = params.sort_and_page(items.clone()).into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![3, 4, 1, 2]);
+
+        params.sort = DynamicsSort::Loudness;
+        let ids: Vec<_> = params.sort_and_page(items.clone()).into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![2, 3, 4, 1]);
+
+        params.sort = DynamicsSort::TruePeak;
+        params.offset = 1;
+        params.limit = Some(2);
+        let ids: Vec<_> = params.sort_and_page(items).into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![4, 1]);
+    }
+}
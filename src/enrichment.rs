@@ -0,0 +1,475 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Similar artists and artist tags from Last.fm or ListenBrainz.
+//!
+//! The similar artists that we find in the listens, see `similar.rs`, need a
+//! good amount of listening history. For libraries that don't have that yet,
+//! when `enrichment` is configured, we also ask an external service, and blend
+//! the similar artists that it reports and that are in the library into our
+//! own. Last.fm looks up artists by name. ListenBrainz works with MusicBrainz
+//! ids, which we find in the tags of the files, and because ListenBrainz has
+//! no tags, we take those from MusicBrainz.
+//!
+//! Responses are cached in the database, so we ask at most once a month per
+//! artist, or once a day when asking failed. We talk to the services through
+//! `curl`, like for scrobbling.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use chrono::Utc;
+use serde_json::Value;
+
+use crate::build::parse_uuid;
+use crate::database::{self as db, Connection, Transaction};
+use crate::database_utils;
+use crate::error::{Error, Result};
+use crate::prim::{ArtistId, TrackId};
+use crate::user_data::UserData;
+use crate::MetaIndex;
+
+const LASTFM_API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+const LISTENBRAINZ_SIMILAR_URL: &str = "https://labs.api.listenbrainz.org/similar-artists/json";
+const MUSICBRAINZ_ARTIST_URL: &str = "https://musicbrainz.org/ws/2/artist/";
+
+/// The similarity dataset of ListenBrainz that we use, the one that its own
+/// website uses for the similar artists of an artist page.
+const LISTENBRAINZ_ALGORITHM: &str =
+    "session_based_days_7500_session_300_contribution_5_threshold_10_limit_100_filter_True_skip_30";
+
+/// How long we keep a response before we ask again.
+const CACHE_TTL_SECONDS: i64 = 30 * 24 * 3600;
+
+/// How long to wait before we ask again after asking failed.
+const FAILURE_TTL_SECONDS: i64 = 24 * 3600;
+
+/// We keep at most this many tags per artist, the long tail is mostly noise.
+const MAX_TAGS: usize = 10;
+
+/// The service to fetch similar artists and tags from, configured with `enrichment`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Source {
+    LastFm,
+    ListenBrainz,
+}
+
+impl Source {
+    /// The name of the source, as in the config, and in the database.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Source::LastFm => "lastfm",
+            Source::ListenBrainz => "listenbrainz",
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Source, &'static str> {
+        match s {
+            "lastfm" => Ok(Source::LastFm),
+            "listenbrainz" => Ok(Source::ListenBrainz),
+            _ => Err("Invalid enrichment value, must be 'lastfm' or 'listenbrainz'."),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SimilarArtist {
+    pub name: String,
+    /// The MusicBrainz id, if the service reports it.
+    pub mbid: Option<String>,
+    /// How similar the artist is, from 0.0 to 1.0.
+    pub score: f64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ArtistInfo {
+    /// Similar artists, most similar first.
+    pub similar: Vec<SimilarArtist>,
+    /// Tags, such as genres, most applicable first.
+    pub tags: Vec<String>,
+}
+
+/// Make a GET request, return the response.
+fn get_json(url: &str) -> Result<Value> {
+    let mut curl = Command::new("curl")
+        .args(["--silent", "--show-error"])
+        .args(["--max-time", "10"])
+        // MusicBrainz rejects requests without a meaningful user agent.
+        .args(["--user-agent", concat!("Musium/", env!("CARGO_PKG_VERSION"))])
+        // Read the url from stdin, so the API key does not show up in the
+        // process list.
+        .args(["--config", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::CommandError("Failed to spawn 'curl'.", e))?;
+
+    {
+        let stdin = curl.stdin.as_mut().expect("Stdin is piped.");
+        writeln!(stdin, "url = {:?}", url)
+            .map_err(|e| Error::CommandError("Failed to write to 'curl'.", e))?;
+    }
+
+    let output = curl
+        .wait_with_output()
+        .map_err(|e| Error::CommandError("Failed to wait for 'curl'.", e))?;
+
+    if !output.status.success() {
+        return Err(Error::EnrichmentError(format!(
+            "curl exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )));
+    }
+
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::EnrichmentError(format!("Invalid response: {}", e)))
+}
+
+/// Parse the response of Last.fm's `artist.getSimilar`.
+fn parse_lastfm_similar(response: &Value) -> Option<Vec<SimilarArtist>> {
+    let artists = response.pointer("/similarartists/artist")?.as_array()?;
+    let result = artists
+        .iter()
+        .filter_map(|artist| Some(SimilarArtist {
+            name: artist.get("name")?.as_str()?.to_string(),
+            // Last.fm reports an empty string for artists without mbid.
+            mbid: artist
+                .get("mbid")
+                .and_then(|m| m.as_str())
+                .filter(|m| !m.is_empty())
+                .map(|m| m.to_string()),
+            // The match is a string, like "0.812345".
+            score: artist.get("match")?.as_str()?.parse().ok()?,
+        }))
+        .collect();
+    Some(result)
+}
+
+/// Parse the response of Last.fm's `artist.getTopTags`.
+fn parse_lastfm_tags(response: &Value) -> Option<Vec<String>> {
+    let tags = response.pointer("/toptags/tag")?.as_array()?;
+    let result = tags
+        .iter()
+        .filter_map(|tag| Some(tag.get("name")?.as_str()?.to_lowercase()))
+        .take(MAX_TAGS)
+        .collect();
+    Some(result)
+}
+
+/// Parse the response of the ListenBrainz similar artists endpoint.
+///
+/// Scores are counts of co-listens, we scale them so the most similar artist
+/// has score 1.0.
+fn parse_listenbrainz_similar(response: &Value) -> Option<Vec<SimilarArtist>> {
+    let artists = response.as_array()?;
+    let mut result: Vec<SimilarArtist> = artists
+        .iter()
+        .filter_map(|artist| Some(SimilarArtist {
+            name: artist.get("name")?.as_str()?.to_string(),
+            mbid: Some(artist.get("artist_mbid")?.as_str()?.to_string()),
+            score: artist.get("score")?.as_f64()?,
+        }))
+        .collect();
+    let max_score = result.iter().map(|a| a.score).fold(0.0, f64::max);
+    if max_score > 0.0 {
+        for artist in result.iter_mut() {
+            artist.score /= max_score;
+        }
+    }
+    Some(result)
+}
+
+/// Parse the tags from a MusicBrainz artist lookup with `inc=tags`.
+fn parse_musicbrainz_tags(response: &Value) -> Option<Vec<String>> {
+    let tags = response.get("tags")?.as_array()?;
+    let mut counted: Vec<(i64, String)> = tags
+        .iter()
+        .filter_map(|tag| Some((tag.get("count")?.as_i64()?, tag.get("name")?.as_str()?.to_lowercase())))
+        .collect();
+    // Most votes first, ties alphabetically.
+    counted.sort_by(|(count_a, name_a), (count_b, name_b)| count_b.cmp(count_a).then(name_a.cmp(name_b)));
+    Some(counted.into_iter().map(|(_, name)| name).take(MAX_TAGS).collect())
+}
+
+fn fetch_lastfm(api_key: &str, artist_name: &str) -> Result<ArtistInfo> {
+    let url = |method: &str| {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("method", method)
+            .append_pair("artist", artist_name)
+            .append_pair("autocorrect", "1")
+            .append_pair("api_key", api_key)
+            .append_pair("format", "json")
+            .finish();
+        format!("{}?{}", LASTFM_API_URL, query)
+    };
+
+    let similar = get_json(&url("artist.getSimilar"))?;
+    // Error 6 means that Last.fm does not know the artist. That is not a
+    // failure, there is simply nothing to know.
+    match similar.get("error").and_then(|e| e.as_i64()) {
+        Some(6) => return Ok(ArtistInfo::default()),
+        Some(code) => return Err(Error::EnrichmentError(format!(
+            "Last.fm error {}: {}",
+            code,
+            similar.get("message").and_then(|m| m.as_str()).unwrap_or(""),
+        ))),
+        None => {}
+    }
+    let tags = get_json(&url("artist.getTopTags"))?;
+
+    Ok(ArtistInfo {
+        similar: parse_lastfm_similar(&similar)
+            .ok_or_else(|| Error::EnrichmentError("Unexpected similar artists response.".to_string()))?,
+        tags: parse_lastfm_tags(&tags).unwrap_or_default(),
+    })
+}
+
+fn fetch_listenbrainz(artist_mbid: &str) -> Result<ArtistInfo> {
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("artist_mbids", artist_mbid)
+        .append_pair("algorithm", LISTENBRAINZ_ALGORITHM)
+        .finish();
+    let similar = get_json(&format!("{}?{}", LISTENBRAINZ_SIMILAR_URL, query))?;
+    let tags = get_json(&format!("{}{}?inc=tags&fmt=json", MUSICBRAINZ_ARTIST_URL, artist_mbid))?;
+
+    Ok(ArtistInfo {
+        similar: parse_listenbrainz_similar(&similar)
+            .ok_or_else(|| Error::EnrichmentError("Unexpected similar artists response.".to_string()))?,
+        tags: parse_musicbrainz_tags(&tags).unwrap_or_default(),
+    })
+}
+
+/// Return the MusicBrainz id of the album artist, from the tags of the files.
+fn get_artist_mbid(tx: &mut Transaction, artist_id: ArtistId) -> db::Result<Option<String>> {
+    let hex = artist_id.to_string();
+    let pattern = format!("{}-%-{}", &hex[..8], &hex[8..]);
+    db::select_album_artist_mbid(tx, &pattern)
+}
+
+fn load_cached(tx: &mut Transaction, artist_id: ArtistId) -> db::Result<ArtistInfo> {
+    let id = artist_id.0 as i64;
+    let mut info = ArtistInfo::default();
+    for row in db::iter_artist_enrichment_similar(tx, id)? {
+        let (name, mbid, score) = row?;
+        info.similar.push(SimilarArtist { name: name, mbid: mbid, score: score });
+    }
+    for tag in db::iter_artist_enrichment_tags(tx, id)? {
+        info.tags.push(tag?);
+    }
+    Ok(info)
+}
+
+fn store(tx: &mut Transaction, artist_id: ArtistId, source: Source, info: &ArtistInfo, ttl_seconds: i64) -> db::Result<()> {
+    let id = artist_id.0 as i64;
+    let expires_at = Utc::now().timestamp() + ttl_seconds;
+    db::delete_artist_enrichment(tx, id)?;
+    db::insert_artist_enrichment(tx, id, source.name(), expires_at)?;
+    for (i, artist) in info.similar.iter().enumerate() {
+        db::insert_artist_enrichment_similar(tx, id, i as i64, &artist.name, artist.mbid.as_deref(), artist.score)?;
+    }
+    for (i, tag) in info.tags.iter().enumerate() {
+        db::insert_artist_enrichment_tag(tx, id, i as i64, tag)?;
+    }
+    Ok(())
+}
+
+/// Return the similar artists and tags of the artist, from the cache if we can.
+///
+/// When the cache has nothing, or it expired, we ask the service. When that
+/// fails, we log it, and return nothing, the recommendations then are only
+/// those from our own listens.
+pub fn get_artist_info(
+    db: &mut Connection,
+    source: Source,
+    lastfm_api_key: Option<&str>,
+    artist_id: ArtistId,
+    artist_name: &str,
+) -> Result<ArtistInfo> {
+    let now = Utc::now().timestamp();
+    let mut tx = db.begin()?;
+    let expires_at = db::select_artist_enrichment_expiry(&mut tx, artist_id.0 as i64, source.name())?;
+    if expires_at.map_or(false, |t| t > now) {
+        let info = load_cached(&mut tx, artist_id)?;
+        tx.commit()?;
+        return Ok(info);
+    }
+    let mbid = match source {
+        Source::ListenBrainz => get_artist_mbid(&mut tx, artist_id)?,
+        Source::LastFm => None,
+    };
+    tx.commit()?;
+
+    let fetched = match (source, lastfm_api_key, mbid) {
+        (Source::LastFm, Some(api_key), _) => fetch_lastfm(api_key, artist_name),
+        (Source::ListenBrainz, _, Some(mbid)) => fetch_listenbrainz(&mbid),
+        // Without id there is nothing to ask. The id may show up after a scan,
+        // so we don't cache this for long.
+        _ => Err(Error::EnrichmentError("The artist has no MusicBrainz id.".to_string())),
+    };
+
+    let (info, ttl_seconds) = match fetched {
+        Ok(info) => (info, CACHE_TTL_SECONDS),
+        Err(err) => {
+            warn!("Failed to fetch similar artists of {} from {}: {:?}", artist_name, source.name(), err);
+            (ArtistInfo::default(), FAILURE_TTL_SECONDS)
+        }
+    };
+
+    database_utils::with_write_transaction(db, |tx| {
+        store(tx, artist_id, source, &info, ttl_seconds)?;
+        Ok(())
+    })?;
+
+    Ok(info)
+}
+
+/// Return the artists in the library that the external similar artists refer to.
+///
+/// We match by MusicBrainz id where we can, and by name otherwise. Artists
+/// that are not in the library are left out, the order is preserved.
+pub fn match_artists(index: &dyn MetaIndex, similar: &[SimilarArtist]) -> Vec<ArtistId> {
+    let by_name: HashMap<String, ArtistId> = index
+        .get_artists()
+        .iter()
+        .map(|kv| (index.get_string(kv.artist.name).to_lowercase(), kv.artist_id))
+        .collect();
+
+    let mut result = Vec::new();
+    for artist in similar {
+        let by_mbid = artist
+            .mbid
+            .as_ref()
+            .and_then(|mbid| parse_uuid(mbid))
+            .map(ArtistId)
+            .filter(|id| index.get_artist(*id).is_some());
+        let id = by_mbid.or_else(|| by_name.get(&artist.name.to_lowercase()).copied());
+        if let Some(id) = id {
+            if !result.contains(&id) {
+                result.push(id);
+            }
+        }
+    }
+    result
+}
+
+/// Append the external similar artists after our own, leaving out duplicates.
+///
+/// Similar artists from our own listens come first, they are personal.
+pub fn blend(local: Vec<ArtistId>, external: &[ArtistId], exclude: ArtistId) -> Vec<ArtistId> {
+    let mut seen: HashSet<ArtistId> = local.iter().cloned().collect();
+    let mut result = local;
+    for &id in external {
+        if id != exclude && seen.insert(id) {
+            result.push(id);
+        }
+    }
+    result
+}
+
+/// Append one track of every external similar artist after our own tracks.
+///
+/// For every artist, we pick the track that the user played most, or its
+/// first track when it was never played.
+pub fn blend_tracks(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    local: Vec<TrackId>,
+    external: &[ArtistId],
+) -> Vec<TrackId> {
+    let mut result = local;
+    for &artist_id in external {
+        let tracks = index
+            .get_albums_by_artist(artist_id)
+            .iter()
+            .flat_map(|&(_, album_id)| index.get_album_tracks(album_id).iter())
+            .map(|kv| kv.track_id);
+        // Reverse the play count, so on ties, `min_by_key` picks the first track.
+        let best = tracks.min_by_key(|t| std::cmp::Reverse(user_data.get_track_play_count(*t)));
+        if let Some(track_id) = best {
+            if !result.contains(&track_id) {
+                result.push(track_id);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{blend, parse_lastfm_similar, parse_listenbrainz_similar, parse_musicbrainz_tags, SimilarArtist};
+    use crate::prim::ArtistId;
+
+    #[test]
+    fn parse_lastfm_similar_reads_names_mbids_and_scores() {
+        let response = serde_json::json!({
+            "similarartists": {
+                "artist": [
+                    { "name": "Amiina", "mbid": "e5c3a2f9-8bc6-4dd2-a5a5-0d2a3ba63347", "match": "1" },
+                    { "name": "Jónsi", "mbid": "", "match": "0.52" },
+                ],
+                "@attr": { "artist": "Sigur Rós" },
+            }
+        });
+        assert_eq!(
+            parse_lastfm_similar(&response),
+            Some(vec![
+                SimilarArtist {
+                    name: "Amiina".to_string(),
+                    mbid: Some("e5c3a2f9-8bc6-4dd2-a5a5-0d2a3ba63347".to_string()),
+                    score: 1.0,
+                },
+                SimilarArtist { name: "Jónsi".to_string(), mbid: None, score: 0.52 },
+            ]),
+        );
+        assert_eq!(parse_lastfm_similar(&serde_json::json!({ "error": 6 })), None);
+    }
+
+    #[test]
+    fn parse_listenbrainz_similar_scales_scores() {
+        let response = serde_json::json!([
+            { "artist_mbid": "e5c3a2f9-8bc6-4dd2-a5a5-0d2a3ba63347", "name": "Amiina", "score": 400 },
+            { "artist_mbid": "4a7c1b2e-5f3d-4e8a-9c1b-2e5f3d4e8a9c", "name": "Jónsi", "score": 100 },
+        ]);
+        let similar = parse_listenbrainz_similar(&response).unwrap();
+        assert_eq!(similar[0].score, 1.0);
+        assert_eq!(similar[1].score, 0.25);
+    }
+
+    #[test]
+    fn parse_musicbrainz_tags_orders_by_count() {
+        let response = serde_json::json!({
+            "id": "f6f2326f-6b25-4170-b89d-e235b25508e8",
+            "tags": [
+                { "count": 1, "name": "icelandic" },
+                { "count": 5, "name": "Post-Rock" },
+                { "count": 1, "name": "ambient" },
+            ],
+        });
+        assert_eq!(
+            parse_musicbrainz_tags(&response),
+            Some(vec!["post-rock".to_string(), "ambient".to_string(), "icelandic".to_string()]),
+        );
+    }
+
+    #[test]
+    fn blend_appends_external_artists_after_local_ones() {
+        let local = vec![ArtistId(2), ArtistId(3)];
+        let external = [ArtistId(3), ArtistId(1), ArtistId(4)];
+        assert_eq!(
+            blend(local, &external, ArtistId(1)),
+            vec![ArtistId(2), ArtistId(3), ArtistId(4)],
+        );
+    }
+}
@@ -14,6 +14,9 @@ pub enum Error {
     /// Error in config file on a given line.
     InvalidConfig(usize, &'static str),
 
+    /// Error in a config override from the given environment variable.
+    InvalidConfigEnv(String, &'static str),
+
     /// A key is missing in the config.
     IncompleteConfig(&'static str),
 
@@ -26,6 +29,30 @@ pub enum Error {
     /// An FLAC file at a given location could not be read.
     FormatError(PathBuf, claxon::Error),
 
+    /// The Last.fm API returned an error, or a response we could not parse.
+    LastFmError(String),
+
+    /// Fingerprinting a file failed, or the AcoustID API returned an error.
+    AcoustIdError(String),
+
+    /// Fetching similar artists or tags from Last.fm or ListenBrainz failed.
+    EnrichmentError(String),
+
+    /// Posting to a webhook failed.
+    WebhookError(String),
+
+    /// A request to the API of a running server failed.
+    ClientError(String),
+
+    /// A radio station could not be streamed from.
+    RadioError(String),
+
+    /// The path to write a backup of the database to cannot be used.
+    InvalidBackupPath(&'static str),
+
+    /// Database maintenance was requested while it was already running.
+    MaintenanceRunning,
+
     /// Interaction with the SQLite database failed.
     DatabaseError(sqlite::Error),
 }
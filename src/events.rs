@@ -0,0 +1,279 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Pushing state changes to clients as server-sent events.
+//!
+//! The player, the history thread, and the scanner publish events to the
+//! [`EventBus`]. Every client that connects to `/api/events` gets its own
+//! thread that writes the events to the socket, see `docs/api.md` for the
+//! events and their payloads.
+//!
+//! We format every event once, when it is published. Subscribers that fall
+//! behind are disconnected rather than blocking the publisher. Browsers
+//! reconnect automatically, and after reconnecting, clients should refetch the
+//! state they display, as they may have missed events in between.
+
+use std::io;
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::player::{Millibel, QueueId};
+use crate::prim::TrackId;
+use crate::scan;
+use crate::serialization;
+
+/// How many events can be in flight to a subscriber before we disconnect it.
+const SUBSCRIBER_BUFFER_LEN: usize = 64;
+
+/// How long to wait for an event before sending a comment to keep the
+/// connection open. This is also how quickly we notice that a client is gone.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A state change that clients may want to know about.
+pub enum Event {
+    /// Tracks were added to or removed from the queue, or it was reordered.
+    QueueChanged,
+
+    /// Playback of the queued track started.
+    TrackStarted { queue_id: QueueId, track_id: TrackId },
+
+    /// Playback of the queued track completed.
+    TrackCompleted { queue_id: QueueId, track_id: TrackId },
+
+    /// The queued track was skipped at the given position.
+    TrackSkipped { queue_id: QueueId, track_id: TrackId, position_seconds: u32 },
+
+    /// Playback of the queued track failed, for example because a read from
+    /// the file timed out, and the player moved on to the next track.
+    TrackFailed { queue_id: QueueId, track_id: TrackId, message: String },
+
+    /// Playback of a radio station started, or the station announced a new title.
+    RadioTitleChanged { queue_id: QueueId, title: Option<String> },
+
+    /// The player could not play the radio station, for example because it
+    /// could not be reached, and it moved on to the next entry.
+    RadioFailed { queue_id: QueueId, message: String },
+
+    /// The user paused playback.
+    PlaybackPaused,
+
+    /// Playback continues after a pause.
+    PlaybackResumed,
+
+    /// The playback volume changed.
+    VolumeChanged { volume: Millibel },
+
+    /// A library scan made progress.
+    ScanStatus { status: scan::Status },
+
+    /// A scan completed and the new index is available.
+    LibraryUpdated,
+}
+
+impl Event {
+    /// The name of the event, used as the server-sent event type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::QueueChanged => "queue_changed",
+            Event::TrackStarted { .. } => "track_started",
+            Event::TrackCompleted { .. } => "track_completed",
+            Event::TrackSkipped { .. } => "track_skipped",
+            Event::TrackFailed { .. } => "track_failed",
+            Event::RadioTitleChanged { .. } => "radio_title_changed",
+            Event::RadioFailed { .. } => "radio_failed",
+            Event::PlaybackPaused => "playback_paused",
+            Event::PlaybackResumed => "playback_resumed",
+            Event::VolumeChanged { .. } => "volume_changed",
+            Event::ScanStatus { .. } => "scan_status",
+            Event::LibraryUpdated => "library_updated",
+        }
+    }
+
+    /// Write the json payload of the event.
+    pub fn write_json<W: Write>(&self, mut w: W) -> io::Result<()> {
+        match self {
+            Event::QueueChanged
+            | Event::PlaybackPaused
+            | Event::PlaybackResumed
+            | Event::LibraryUpdated => write!(w, "{{}}"),
+            Event::TrackStarted { queue_id, track_id }
+            | Event::TrackCompleted { queue_id, track_id } => write!(
+                w,
+                r#"{{"queue_id":"{}","track_id":"{}"}}"#,
+                queue_id, track_id,
+            ),
+            Event::TrackSkipped { queue_id, track_id, position_seconds } => write!(
+                w,
+                r#"{{"queue_id":"{}","track_id":"{}","position_seconds":{}}}"#,
+                queue_id, track_id, position_seconds,
+            ),
+            Event::TrackFailed { queue_id, track_id, message } => {
+                write!(w, r#"{{"queue_id":"{}","track_id":"{}","message":"#, queue_id, track_id)?;
+                serde_json::to_writer(&mut w, message)?;
+                write!(w, "}}")
+            }
+            Event::RadioTitleChanged { queue_id, title } => {
+                write!(w, r#"{{"queue_id":"{}","title":"#, queue_id)?;
+                serde_json::to_writer(&mut w, title)?;
+                write!(w, "}}")
+            }
+            Event::RadioFailed { queue_id, message } => {
+                write!(w, r#"{{"queue_id":"{}","message":"#, queue_id)?;
+                serde_json::to_writer(&mut w, message)?;
+                write!(w, "}}")
+            }
+            Event::VolumeChanged { volume } => serialization::write_volume_json(w, *volume),
+            Event::ScanStatus { status } => serialization::write_scan_status_json(w, Some(*status)),
+        }
+    }
+
+    /// Format the event as a server-sent event message.
+    fn to_message(&self) -> Vec<u8> {
+        let mut message = Vec::new();
+        write!(message, "event: {}\ndata: ", self.name()).unwrap();
+        // The json payload never contains newlines, so it fits on one data line.
+        self.write_json(&mut message).unwrap();
+        message.extend_from_slice(b"\n\n");
+        message
+    }
+}
+
+/// Fans out events to all connected subscribers.
+pub struct EventBus {
+    subscribers: Mutex<Vec<SyncSender<Arc<[u8]>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Send the event to all subscribers, and drop the ones that can't keep up.
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let message: Arc<[u8]> = event.to_message().into();
+        // Dropping the sender of a subscriber that fell behind makes its thread
+        // exit after it wrote the events that are still buffered. The client
+        // then reconnects, and starts from a fresh state.
+        subscribers.retain(|sender| match sender.try_send(message.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(..)) => false,
+            Err(TrySendError::Disconnected(..)) => false,
+        });
+    }
+
+    /// Subscribe to events, return the receiving end.
+    pub fn subscribe(&self) -> Receiver<Arc<[u8]>> {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BUFFER_LEN);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+/// Write events to the client until it disconnects, or until it falls behind.
+///
+/// The writer is the raw socket, so this writes the response headers too.
+pub fn stream_events<W: Write>(mut w: W, events: Receiver<Arc<[u8]>>) -> io::Result<()> {
+    // We don't know the length of the body, and we don't use chunked encoding
+    // either, so the end of the body is when we close the connection.
+    write!(
+        w,
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: close\r\n\
+        \r\n"
+    )?;
+    // Ask the browser to reconnect quickly, the default is a few seconds.
+    write!(w, "retry: 1000\n\n")?;
+    w.flush()?;
+
+    loop {
+        match events.recv_timeout(KEEPALIVE_INTERVAL) {
+            Ok(message) => w.write_all(&message)?,
+            // Lines that start with a colon are comments, clients ignore them.
+            Err(RecvTimeoutError::Timeout) => w.write_all(b": keepalive\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        w.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, EventBus};
+    use crate::player::{Millibel, QueueId};
+    use crate::prim::TrackId;
+
+    #[test]
+    fn event_formats_as_server_sent_event() {
+        let event = Event::TrackSkipped {
+            queue_id: QueueId(3),
+            track_id: TrackId(0x0000_0000_0001_0102),
+            position_seconds: 42,
+        };
+        let message = String::from_utf8(event.to_message()).unwrap();
+        assert_eq!(
+            message,
+            "event: track_skipped\n\
+            data: {\"queue_id\":\"0000000000000003\",\"track_id\":\"0000000000010102\",\"position_seconds\":42}\n\n",
+        );
+    }
+
+    #[test]
+    fn event_track_failed_escapes_message() {
+        let event = Event::TrackFailed {
+            queue_id: QueueId(3),
+            track_id: TrackId(0x0000_0000_0001_0102),
+            message: "No data after 20 seconds for \"01.flac\".".to_string(),
+        };
+        let mut json = Vec::new();
+        event.write_json(&mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            r#"{"queue_id":"0000000000000003","track_id":"0000000000010102","message":"No data after 20 seconds for \"01.flac\"."}"#,
+        );
+    }
+
+    #[test]
+    fn event_radio_failed_has_queue_id_and_message() {
+        let event = Event::RadioFailed {
+            queue_id: QueueId(3),
+            message: "IoError(\"curl exited\")".to_string(),
+        };
+        let message = String::from_utf8(event.to_message()).unwrap();
+        assert_eq!(
+            message,
+            "event: radio_failed\n\
+            data: {\"queue_id\":\"0000000000000003\",\"message\":\"IoError(\\\"curl exited\\\")\"}\n\n",
+        );
+    }
+
+    #[test]
+    fn event_bus_drops_subscribers_that_fall_behind() {
+        let bus = EventBus::new();
+        let slow = bus.subscribe();
+        let fast = bus.subscribe();
+        for _ in 0..super::SUBSCRIBER_BUFFER_LEN {
+            bus.publish(Event::QueueChanged);
+            fast.recv().unwrap();
+        }
+        // The slow subscriber's buffer is full now, so this event drops it.
+        bus.publish(Event::VolumeChanged { volume: Millibel(-1500) });
+        assert!(fast.recv().is_ok());
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        // The slow subscriber still gets the events that were buffered.
+        assert_eq!(slow.iter().count(), super::SUBSCRIBER_BUFFER_LEN);
+    }
+}
@@ -18,6 +18,11 @@
 //!   and execute the pre-play right away.
 //! * All events get processed in order. If playback resumes while we are
 //!   executing the post-idle command, that is not an issue.
+//!
+//! When the pre-playback program turns on an amplifier, the amplifier may need
+//! a moment before it passes audio. The exec thread then waits for the
+//! configured pre-playback delay before it lets playback continue, but only
+//! when the output was idle, not when playback resumes within the idle timeout.
 
 use std::path::Path;
 use std::sync::Arc;
@@ -42,12 +47,15 @@ pub enum QueueEvent {
     EndPlayback(Instant),
 }
 
-fn execute_program_with_timeout(exe_path: &Path, stage_name: &'static str) {
-    println!("Executing {} program {} ...", stage_name, exe_path.to_string_lossy());
-    let mut proc = match Command::new(exe_path).spawn() {
+/// Run the program with the given arguments, and kill it if it runs for too long.
+///
+/// The stage name is only used in log messages.
+pub fn execute_program_with_timeout(exe_path: &Path, args: &[&str], stage_name: &'static str) {
+    info!("Executing {} program {} ...", stage_name, exe_path.to_string_lossy());
+    let mut proc = match Command::new(exe_path).args(args).spawn() {
         Ok(proc) => proc,
         Err(err) => {
-            println!(
+            warn!(
                 "Failed to spawn {} program {}: {}",
                 stage_name,
                 exe_path.to_string_lossy(),
@@ -62,19 +70,23 @@ fn execute_program_with_timeout(exe_path: &Path, stage_name: &'static str) {
     // is little more we can do then anyway.
     match proc.wait_timeout(Duration::from_secs(30)) {
         Ok(Some(_status)) => {
-            println!("The {} program exited.", stage_name);
+            info!("The {} program exited.", stage_name);
         }
         Ok(None) => {
-            println!("The {} program did not exit within 30 seconds, killing it ...", stage_name);
+            warn!("The {} program did not exit within 30 seconds, killing it ...", stage_name);
             let _ignored_result = proc.kill();
         }
         Err(err) => {
-            println!("Failed to wait for the {} program: {}", stage_name, err);
+            warn!("Failed to wait for the {} program: {}", stage_name, err);
         }
     }
 }
 
 pub fn main(config: &Config, events: Receiver<QueueEvent>) -> ! {
+    // Whether the post-idle program did not run since the last playback, so
+    // the output is still on. At startup, we assume it's off.
+    let mut is_output_active = false;
+
     // Wait for playback to start.
     let mut start_event = events.recv().expect("QueueEvent sender should run indefinitely.");
     loop {
@@ -84,8 +96,14 @@ pub fn main(config: &Config, events: Receiver<QueueEvent>) -> ! {
         };
 
         if let Some(exe) = config.exec_pre_playback_path.as_ref() {
-            execute_program_with_timeout(exe, "pre-playback");
+            execute_program_with_timeout(exe, &[], "pre-playback");
+        }
+
+        if !is_output_active && config.pre_playback_delay_ms > 0 {
+            info!("Waiting {} ms for the output to become active ...", config.pre_playback_delay_ms);
+            std::thread::sleep(Duration::from_millis(config.pre_playback_delay_ms));
         }
+        is_output_active = true;
 
         // Signal to the playback thread that it can continue.
         *is_running_condvar.0.lock().unwrap() = false;
@@ -112,8 +130,9 @@ pub fn main(config: &Config, events: Receiver<QueueEvent>) -> ! {
         // If we get here, then we waited for the full timeout, and playback did
         // not resume, which means we are idle now.
         if let Some(exe) = config.exec_post_idle_path.as_ref() {
-            execute_program_with_timeout(exe, "post-idle");
+            execute_program_with_timeout(exe, &[], "post-idle");
         }
+        is_output_active = false;
 
         // Wait for playback to start again.
         start_event = events.recv().expect("QueueEvent sender should run indefinitely.");
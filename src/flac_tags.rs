@@ -0,0 +1,295 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Rewriting the Vorbis comments of flac files, for metadata edits.
+//!
+//! Claxon only reads flac files, so we parse the metadata blocks ourselves,
+//! see <https://xiph.org/flac/format.html#metadata_block>. When the new comment
+//! block fits in the space of the old one plus the padding, we overwrite the
+//! metadata in place, so the audio data is not touched. Otherwise, we write a
+//! new file next to the original, with some padding for future edits, and
+//! rename it over the original.
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const BLOCK_PADDING: u8 = 1;
+const BLOCK_VORBIS_COMMENT: u8 = 4;
+
+/// Padding that we add when we have to rewrite the file anyway.
+const NEW_PADDING_LEN: usize = 4096;
+
+/// Set `field_name` to `value`, or remove it when `value` is `None`.
+#[derive(Debug)]
+pub struct TagUpdate<'a> {
+    pub field_name: &'a str,
+    pub value: Option<&'a str>,
+}
+
+struct Block {
+    block_type: u8,
+    data: Vec<u8>,
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Read the metadata blocks, leave the reader at the start of the audio data.
+fn read_blocks<R: Read>(r: &mut R) -> io::Result<Vec<Block>> {
+    let mut magic = [0_u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != b"fLaC" {
+        return Err(invalid("Not a flac file."));
+    }
+
+    let mut blocks = Vec::new();
+    loop {
+        let mut header = [0_u8; 4];
+        r.read_exact(&mut header)?;
+        let is_last = header[0] & 0x80 != 0;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let mut data = vec![0_u8; len];
+        r.read_exact(&mut data)?;
+        blocks.push(Block { block_type: header[0] & 0x7f, data });
+        if is_last {
+            return Ok(blocks);
+        }
+    }
+}
+
+fn metadata_len(blocks: &[Block]) -> usize {
+    4 + blocks.iter().map(|b| 4 + b.data.len()).sum::<usize>()
+}
+
+fn serialize_blocks(blocks: &[Block]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(metadata_len(blocks));
+    out.extend_from_slice(b"fLaC");
+    for (i, block) in blocks.iter().enumerate() {
+        if block.data.len() >= 1 << 24 {
+            return Err(invalid("Metadata block too large."));
+        }
+        let len = (block.data.len() as u32).to_be_bytes();
+        let is_last = if i + 1 == blocks.len() { 0x80 } else { 0 };
+        out.extend_from_slice(&[block.block_type | is_last, len[1], len[2], len[3]]);
+        out.extend_from_slice(&block.data);
+    }
+    Ok(out)
+}
+
+fn read_u32_le(data: &[u8], offset: &mut usize) -> io::Result<u32> {
+    let bytes = data
+        .get(*offset..*offset + 4)
+        .ok_or_else(|| invalid("Vorbis comment block is truncated."))?;
+    *offset += 4;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_bytes<'a>(data: &'a [u8], offset: &mut usize) -> io::Result<&'a [u8]> {
+    let len = read_u32_le(data, offset)? as usize;
+    let bytes = data
+        .get(*offset..*offset + len)
+        .ok_or_else(|| invalid("Vorbis comment block is truncated."))?;
+    *offset += len;
+    Ok(bytes)
+}
+
+/// Parse a Vorbis comment block into the vendor string and the comments.
+fn parse_comments(data: &[u8]) -> io::Result<(Vec<u8>, Vec<Vec<u8>>)> {
+    let mut offset = 0;
+    let vendor = read_bytes(data, &mut offset)?.to_vec();
+    let n = read_u32_le(data, &mut offset)?;
+    let mut comments = Vec::new();
+    for _ in 0..n {
+        comments.push(read_bytes(data, &mut offset)?.to_vec());
+    }
+    Ok((vendor, comments))
+}
+
+fn serialize_comments(vendor: &[u8], comments: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    out.extend_from_slice(vendor);
+    out.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        out.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        out.extend_from_slice(comment);
+    }
+    out
+}
+
+/// Return whether the `NAME=value` comment has the given field name.
+fn has_field_name(comment: &[u8], field_name: &str) -> bool {
+    match comment.iter().position(|&b| b == b'=') {
+        Some(i) => comment[..i].eq_ignore_ascii_case(field_name.as_bytes()),
+        None => false,
+    }
+}
+
+/// Apply the updates to the comments.
+///
+/// A field that occurs already keeps its position, the value replaces the
+/// first occurrence, and other occurrences are removed. New fields go at the
+/// end, with an uppercase name, like most taggers write them.
+fn update_comments(comments: &mut Vec<Vec<u8>>, updates: &[TagUpdate]) {
+    for update in updates {
+        let new_comment = update.value.map(|value| {
+            format!("{}={}", update.field_name.to_ascii_uppercase(), value).into_bytes()
+        });
+        let first = comments.iter().position(|c| has_field_name(c, update.field_name));
+        let mut i = 0;
+        comments.retain(|c| {
+            let keep = Some(i) == first || !has_field_name(c, update.field_name);
+            i += 1;
+            keep
+        });
+        match (first, new_comment) {
+            (Some(i), Some(comment)) => comments[i] = comment,
+            (Some(i), None) => { comments.remove(i); }
+            (None, Some(comment)) => comments.push(comment),
+            (None, None) => {}
+        }
+    }
+}
+
+/// Replace the comment block, and resize the padding to keep the metadata the
+/// same size if possible. Returns whether the size stayed the same.
+fn replace_comments(blocks: &mut Vec<Block>, new_data: Vec<u8>) -> bool {
+    let old_len = metadata_len(blocks);
+
+    match blocks.iter().position(|b| b.block_type == BLOCK_VORBIS_COMMENT) {
+        Some(i) => blocks[i].data = new_data,
+        // The streaminfo block must be first, put the comments after it.
+        None => blocks.insert(1, Block { block_type: BLOCK_VORBIS_COMMENT, data: new_data }),
+    }
+
+    let new_len = metadata_len(blocks);
+    let padding = blocks.iter().position(|b| b.block_type == BLOCK_PADDING);
+    match padding {
+        Some(i) if new_len <= old_len + blocks[i].data.len() => {
+            // Shrink the padding, or grow it when the comments got shorter.
+            let padding_len = old_len + blocks[i].data.len() - new_len;
+            blocks[i].data = vec![0; padding_len];
+            true
+        }
+        Some(i) if new_len == old_len + blocks[i].data.len() + 4 => {
+            // The comments take exactly the padding and its header.
+            blocks.remove(i);
+            true
+        }
+        None if new_len == old_len => true,
+        None if new_len + 4 <= old_len => {
+            blocks.push(Block { block_type: BLOCK_PADDING, data: vec![0; old_len - new_len - 4] });
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Rewrite the Vorbis comments of the flac file at `path`.
+pub fn write_tags(path: &Path, updates: &[TagUpdate]) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let mut blocks = read_blocks(&mut io::BufReader::new(&mut file))?;
+    let old_len = metadata_len(&blocks);
+
+    let (vendor, mut comments) = match blocks.iter().find(|b| b.block_type == BLOCK_VORBIS_COMMENT) {
+        Some(block) => parse_comments(&block.data)?,
+        None => (b"Musium".to_vec(), Vec::new()),
+    };
+    update_comments(&mut comments, updates);
+
+    if replace_comments(&mut blocks, serialize_comments(&vendor, &comments)) {
+        let metadata = serialize_blocks(&blocks)?;
+        debug_assert_eq!(metadata.len(), old_len);
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&metadata)?;
+        return file.sync_all();
+    }
+
+    // The metadata grew beyond the padding. Write a new file with padding,
+    // and copy the audio data over.
+    blocks.retain(|b| b.block_type != BLOCK_PADDING);
+    blocks.push(Block { block_type: BLOCK_PADDING, data: vec![0; NEW_PADDING_LEN] });
+    let metadata = serialize_blocks(&blocks)?;
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".musium-tmp");
+    let tmp_path = Path::new(&tmp_path);
+
+    let result = (|| {
+        let mut out = fs::File::create(tmp_path)?;
+        out.set_permissions(file.metadata()?.permissions())?;
+        out.write_all(&metadata)?;
+        file.seek(SeekFrom::Start(old_len as u64))?;
+        io::copy(&mut file, &mut out)?;
+        out.sync_all()?;
+        fs::rename(tmp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(tmp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        parse_comments, read_blocks, replace_comments, serialize_blocks, serialize_comments,
+        update_comments, Block, TagUpdate, BLOCK_PADDING, BLOCK_VORBIS_COMMENT,
+    };
+
+    fn comments(strs: &[&str]) -> Vec<Vec<u8>> {
+        strs.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn update_comments_replaces_in_place_and_appends() {
+        let mut cs = comments(&["TITLE=Old", "Genre=Rock", "ARTIST=A", "GENRE=Pop"]);
+        update_comments(&mut cs, &[
+            TagUpdate { field_name: "title", value: Some("New") },
+            TagUpdate { field_name: "genre", value: Some("Jazz") },
+            TagUpdate { field_name: "originaldate", value: Some("1999") },
+        ]);
+        assert_eq!(cs, comments(&["TITLE=New", "GENRE=Jazz", "ARTIST=A", "ORIGINALDATE=1999"]));
+
+        update_comments(&mut cs, &[TagUpdate { field_name: "genre", value: None }]);
+        assert_eq!(cs, comments(&["TITLE=New", "ARTIST=A", "ORIGINALDATE=1999"]));
+    }
+
+    #[test]
+    fn comments_roundtrip() {
+        let cs = comments(&["TITLE=Hoppípolla", "ARTIST=Sigur Rós"]);
+        let data = serialize_comments(b"reference libFLAC 1.4.2", &cs);
+        let (vendor, parsed) = parse_comments(&data).unwrap();
+        assert_eq!(vendor, b"reference libFLAC 1.4.2");
+        assert_eq!(parsed, cs);
+        assert!(parse_comments(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn replace_comments_takes_space_from_padding() {
+        let mut blocks = vec![
+            Block { block_type: 0, data: vec![0; 34] },
+            Block { block_type: BLOCK_VORBIS_COMMENT, data: vec![1; 20] },
+            Block { block_type: BLOCK_PADDING, data: vec![0; 10] },
+        ];
+        let metadata = serialize_blocks(&blocks).unwrap();
+        assert_eq!(read_blocks(&mut &metadata[..]).unwrap().len(), 3);
+
+        // Growing by the size of the padding uses the padding and its header.
+        assert!(replace_comments(&mut blocks, vec![1; 24]));
+        assert_eq!(blocks[2].data.len(), 6);
+        assert!(replace_comments(&mut blocks, vec![1; 34]));
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(serialize_blocks(&blocks).unwrap().len(), metadata.len());
+
+        // Beyond that, the file has to grow.
+        assert!(!replace_comments(&mut blocks, vec![1; 35]));
+    }
+}
@@ -0,0 +1,316 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Digital volume: a single gain stage with a lookahead soft limiter.
+//!
+//! By default we set the volume with the mixer of the audio card, which can't
+//! go above full scale, so a quiet track that loudness normalization would
+//! boost stays too quiet. With digital volume, the playback thread applies the
+//! volume, the loudness normalization, and the 6 dB that the high-pass filter
+//! takes off for headroom, as one gain. Positive gains can push peaks past full
+//! scale, and the limiter turns those down smoothly before they get there.
+//!
+//! To see peaks coming, the limiter delays the audio by its lookahead, 2 ms.
+//! The delay line persists across calls, so a peak at the start of one period
+//! is turned down in time in the period before it. When the format changes, we
+//! drop the last 2 ms of the old format, the device restarts there anyway.
+
+use std::collections::VecDeque;
+
+use crate::player::{Format, Millibel};
+
+/// The high-pass filter halves the amplitude for headroom, we give it back.
+const FILTER_HEADROOM_MILLIBEL: i16 = 602;
+
+/// The limiter keeps peaks below -1 dBFS.
+const CEILING: f32 = 0.891;
+
+/// How far ahead the limiter looks, it turns the gain down over this time.
+const LOOKAHEAD_MS: u32 = 2;
+
+/// After a peak, the limiter lets the gain recover at this rate.
+const RELEASE_DB_PER_SECOND: f32 = 10.0;
+
+/// The gain that the playback thread applies, for the player status.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GainStatus {
+    /// Volume plus loudness normalization plus filter headroom.
+    pub gain: Millibel,
+
+    /// How much the limiter turned the gain down, 0.0 when it is inactive.
+    pub limiter_reduction_db: f32,
+}
+
+/// Convert a sample to a float in the range [-1.0, 1.0).
+fn read_sample(bytes: &[u8]) -> f32 {
+    match bytes.len() {
+        2 => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32_768.0,
+        // Put the 24 bits in the high bytes of an i32 to get the sign right.
+        3 => i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as f32 / 2_147_483_648.0,
+        n => panic!("Unsupported: {} bits per sample. Please re-index.", n * 8),
+    }
+}
+
+/// Write a float in the range [-1.0, 1.0) as sample, clip when out of range.
+fn write_sample(x: f32, out: &mut [u8]) {
+    match out.len() {
+        2 => {
+            let y = (x * 32_768.0).round().clamp(-32_768.0, 32_767.0) as i16;
+            out.copy_from_slice(&y.to_le_bytes());
+        }
+        3 => {
+            let y = (x * 8_388_608.0).round().clamp(-8_388_608.0, 8_388_607.0) as i32;
+            out.copy_from_slice(&y.to_le_bytes()[..3]);
+        }
+        n => panic!("Unsupported: {} bits per sample. Please re-index.", n * 8),
+    }
+}
+
+pub struct GainStage {
+    /// The format that the limiter state below belongs to.
+    format: Format,
+
+    /// Number of frames that the limiter looks ahead.
+    lookahead: usize,
+
+    /// Factor by which the held gain may increase per frame.
+    release: f32,
+
+    /// The linear gain of the previous frame, we ramp from there to the new gain.
+    gain: f32,
+
+    /// The lowest gain needed in the lookahead window, recovering slowly.
+    held: f32,
+
+    /// The last `lookahead` frames, with the volume applied, not written yet.
+    delay: VecDeque<[f32; 2]>,
+
+    /// Sliding window minimum of the gain that frames need to stay below the
+    /// ceiling, as `(frame index, gain)`. Covers the frame that leaves the
+    /// delay line, and the ones in it.
+    window: VecDeque<(u64, f32)>,
+
+    /// The number of frames that entered the delay line so far.
+    frames_in: u64,
+
+    /// The last `lookahead` held gains, and their sum.
+    ///
+    /// The limiter gain is their average, which ramps down over the lookahead
+    /// window, so it reaches the held gain by the time the peak plays.
+    recent: VecDeque<f32>,
+    recent_sum: f64,
+
+    /// The status after the last call to `process`.
+    status: Option<GainStatus>,
+}
+
+impl GainStage {
+    pub fn new() -> GainStage {
+        let mut stage = GainStage {
+            format: Format::default(),
+            lookahead: 0,
+            release: 1.0,
+            gain: 0.0,
+            held: 1.0,
+            delay: VecDeque::new(),
+            window: VecDeque::new(),
+            frames_in: 0,
+            recent: VecDeque::new(),
+            recent_sum: 0.0,
+            status: None,
+        };
+        stage.set_format(Format::default());
+        stage
+    }
+
+    /// Reset the limiter for a new format.
+    ///
+    /// This is the only place where we allocate, `process` runs on the
+    /// playback thread, and only moves frames through these buffers.
+    fn set_format(&mut self, format: Format) {
+        let rate = format.sample_rate.0;
+        self.format = format;
+        self.lookahead = (rate * LOOKAHEAD_MS / 1000).max(1) as usize;
+        self.release = 10.0_f32.powf(RELEASE_DB_PER_SECOND / 20.0 / rate as f32);
+        self.held = 1.0;
+        self.delay.clear();
+        self.delay.resize(self.lookahead, [0.0, 0.0]);
+        self.window.clear();
+        self.window.reserve(self.lookahead + 1);
+        self.frames_in = 0;
+        self.recent.clear();
+        self.recent.resize(self.lookahead, 1.0);
+        self.recent_sum = self.lookahead as f64;
+    }
+
+    /// Return the status after the last call to `process`.
+    pub fn status(&self) -> Option<GainStatus> {
+        self.status
+    }
+
+    /// Apply the gain to the samples in `src`, write the result to `dst`.
+    ///
+    /// The output lags the input by the lookahead of the limiter. The `volume`
+    /// is relative to full scale, it includes loudness normalization.
+    pub fn process(&mut self, format: Format, volume: Millibel, src: &[u8], dst: &mut [u8]) {
+        if format != self.format {
+            self.set_format(format);
+        }
+
+        let bytes_per_sample = format.bits_per_sample as usize / 8;
+        let bytes_per_frame = 2 * bytes_per_sample;
+        let n = dst.len().min(src.len()) / bytes_per_frame;
+        let lookahead = self.lookahead as u64;
+
+        let gain_mb = volume.0.saturating_add(FILTER_HEADROOM_MILLIBEL);
+        let target_gain = 10.0_f32.powf(gain_mb as f32 / 2000.0);
+        // Ramp to a new volume over the frames that we write, to avoid clicks.
+        // On the first call there is nothing to ramp from.
+        let start_gain = if self.gain > 0.0 { self.gain } else { target_gain };
+
+        let mut min_limiter_gain = 1.0_f32;
+
+        for i in 0..n {
+            let t = (i + 1) as f32 / n as f32;
+            let gain = start_gain + (target_gain - start_gain) * t;
+            let frame_src = &src[i * bytes_per_frame..(i + 1) * bytes_per_frame];
+            let frame = [
+                read_sample(&frame_src[..bytes_per_sample]) * gain,
+                read_sample(&frame_src[bytes_per_sample..]) * gain,
+            ];
+
+            // The gain that the entering frame needs to stay below the ceiling.
+            let peak = frame[0].abs().max(frame[1].abs());
+            let required = if peak > CEILING { CEILING / peak } else { 1.0 };
+            while self.window.back().map_or(false, |&(_, r)| r >= required) {
+                self.window.pop_back();
+            }
+            self.window.push_back((self.frames_in, required));
+            while self.window.front().map_or(false, |&(j, _)| j + lookahead < self.frames_in) {
+                self.window.pop_front();
+            }
+            self.frames_in += 1;
+
+            let needed = self.window[0].1;
+            self.held = needed.min((self.held * self.release).min(1.0));
+            let oldest = self.recent.pop_front().unwrap_or(1.0);
+            self.recent.push_back(self.held);
+            self.recent_sum += self.held as f64 - oldest as f64;
+            let limiter_gain = (self.recent_sum / self.lookahead as f64).min(1.0) as f32;
+            min_limiter_gain = min_limiter_gain.min(limiter_gain);
+
+            let out = self.delay.pop_front().unwrap_or([0.0, 0.0]);
+            self.delay.push_back(frame);
+
+            let frame_dst = &mut dst[i * bytes_per_frame..(i + 1) * bytes_per_frame];
+            write_sample(out[0] * limiter_gain, &mut frame_dst[..bytes_per_sample]);
+            write_sample(out[1] * limiter_gain, &mut frame_dst[bytes_per_sample..]);
+        }
+
+        self.gain = target_gain;
+        self.status = Some(GainStatus {
+            gain: Millibel(gain_mb),
+            limiter_reduction_db: -20.0 * min_limiter_gain.log10(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::player::{Format, Millibel};
+    use crate::prim::Hertz;
+    use super::{GainStage, CEILING};
+
+    fn format() -> Format {
+        Format {
+            sample_rate: Hertz(48_000),
+            bits_per_sample: 16,
+        }
+    }
+
+    fn to_bytes(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Vec<i16> {
+        bytes.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+    }
+
+    /// Frames of delay at 48 kHz.
+    const DELAY: usize = 96;
+
+    #[test]
+    fn gain_stage_applies_gain_below_the_ceiling() {
+        let mut stage = GainStage::new();
+        let samples: Vec<i16> = (0..2 * 200).map(|i| (i as i16 - 200) * 10).collect();
+        let src = to_bytes(&samples);
+        let mut dst = vec![0; src.len()];
+        // -6.02 dB of volume cancels the filter headroom.
+        stage.process(format(), Millibel(-602), &src, &mut dst);
+        let out = from_bytes(&dst);
+        assert!(out[..2 * DELAY].iter().all(|x| *x == 0));
+        assert_eq!(&out[2 * DELAY..], &samples[..2 * (200 - DELAY)]);
+
+        let status = stage.status().unwrap();
+        assert_eq!(status.gain, Millibel(0));
+        assert_eq!(status.limiter_reduction_db, 0.0);
+    }
+
+    #[test]
+    fn gain_stage_limits_peaks_before_they_play() {
+        let mut stage = GainStage::new();
+        // Quiet samples with a single loud peak, with 6 dB of gain on top of
+        // the headroom, the peak would clip.
+        let mut samples = vec![1000_i16; 2 * 1000];
+        samples[2 * 500] = 20_000;
+        samples[2 * 500 + 1] = -20_000;
+        let src = to_bytes(&samples);
+        let mut dst = vec![0; src.len()];
+        // Process in chunks, like the playback thread does.
+        for (src_chunk, dst_chunk) in src.chunks(4 * 128).zip(dst.chunks_mut(4 * 128)) {
+            stage.process(format(), Millibel(0), src_chunk, dst_chunk);
+        }
+        let out = from_bytes(&dst);
+
+        let ceiling = (CEILING * 32_768.0) as i16;
+        assert!(out.iter().all(|x| x.abs() <= ceiling + 1), "Output exceeds the ceiling.");
+        let peak = out[2 * (500 + DELAY)];
+        assert!(peak >= ceiling - 200, "Got {}.", peak);
+        // Well before the peak, the gain is not affected.
+        assert!((out[2 * DELAY] - 2000).abs() <= 2, "Got {}.", out[2 * DELAY]);
+        // After the peak, the gain recovers slowly, so the limiter is still active.
+        assert!(stage.status().unwrap().limiter_reduction_db > 2.0);
+    }
+
+    #[test]
+    fn gain_stage_limits_peaks_across_calls() {
+        let mut stage = GainStage::new();
+        // A peak that starts at the end of one call, and gets louder at the
+        // start of the next one, where the first call can't see it.
+        let mut samples = vec![1000_i16; 2 * 512];
+        for i in 2 * 126..2 * 128 {
+            samples[i] = 16_000;
+        }
+        for i in 2 * 128..2 * 131 {
+            samples[i] = 30_000;
+        }
+        let src = to_bytes(&samples);
+        let mut dst = vec![0; src.len()];
+        for (src_chunk, dst_chunk) in src.chunks(4 * 128).zip(dst.chunks_mut(4 * 128)) {
+            stage.process(format(), Millibel(0), src_chunk, dst_chunk);
+        }
+        let out = from_bytes(&dst);
+
+        let ceiling = (CEILING * 32_768.0) as i16;
+        assert!(
+            out.iter().all(|x| x.abs() <= ceiling + 1),
+            "Output exceeds the ceiling: {:?}.", out.iter().max(),
+        );
+        let peak = out[2 * (128 + DELAY)];
+        assert!(peak >= ceiling - 200, "Got {}.", peak);
+    }
+}
@@ -0,0 +1,167 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! The library generation, for clients that keep a copy of the library.
+//!
+//! The webinterface can work offline (see `app/sw.js`), and then it shows the
+//! album list from its cache. To tell whether that copy is stale, a client can
+//! compare the generation from `/api/sync` against the one it saw when it
+//! fetched the list.
+//!
+//! The album list includes ratings and play counts, so the generation changes
+//! with those too, not only when a scan changes the library. The version of
+//! the user data starts over at every start, so the generation includes the
+//! start time of the server as well. After a restart, clients fetch the list
+//! once more than strictly needed, but they never keep a stale copy.
+
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{MemoryMetaIndex, MetaIndex};
+
+/// The 64-bit FNV-1a hash.
+///
+/// Unlike `DefaultHasher`, it does not change between Rust versions, so the
+/// generation only changes when the library does.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Fnv1a {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Return a fingerprint of everything in the index that clients can see.
+pub fn fingerprint<I: MetaIndex>(index: &I) -> u64 {
+    let mut h = Fnv1a::new();
+    for kv in index.get_tracks() {
+        kv.track_id.hash(&mut h);
+        kv.track.file_id.hash(&mut h);
+        index.get_string(kv.track.title).hash(&mut h);
+        index.get_string(kv.track.artist).hash(&mut h);
+        kv.track.duration_seconds.hash(&mut h);
+        kv.track.loudness.map(|l| l.0.get()).hash(&mut h);
+    }
+    for kv in index.get_albums() {
+        kv.album_id.hash(&mut h);
+        index.get_album_artists(kv.album.artist_ids).hash(&mut h);
+        index.get_string(kv.album.title).hash(&mut h);
+        index.get_string(kv.album.artist).hash(&mut h);
+        let date = kv.album.original_release_date;
+        (date.year, date.month, date.day).hash(&mut h);
+        kv.album.loudness.map(|l| l.0.get()).hash(&mut h);
+        kv.album.first_seen.posix_seconds_utc.hash(&mut h);
+    }
+    for kv in index.get_artists() {
+        kv.artist_id.hash(&mut h);
+        index.get_string(kv.artist.name).hash(&mut h);
+        index.get_string(kv.artist.name_for_sort).hash(&mut h);
+    }
+    h.finish()
+}
+
+/// Remembers the fingerprint of the latest index, so we hash every index once.
+///
+/// We hold on to the index weakly, an old index should not stay in memory just
+/// because we computed its fingerprint.
+pub struct GenerationCache {
+    latest: Mutex<Option<(Weak<MemoryMetaIndex>, u64)>>,
+    started_at: u64,
+}
+
+impl GenerationCache {
+    pub fn new() -> GenerationCache {
+        GenerationCache {
+            latest: Mutex::new(None),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Return the fingerprint of the index, see `fingerprint()`.
+    pub fn library(&self, index: &Arc<MemoryMetaIndex>) -> u64 {
+        let mut latest = self.latest.lock().unwrap();
+        match latest.as_ref() {
+            Some((weak, fingerprint)) if Weak::ptr_eq(weak, &Arc::downgrade(index)) => *fingerprint,
+            _ => {
+                let result = fingerprint(&**index);
+                *latest = Some((Arc::downgrade(index), result));
+                result
+            }
+        }
+    }
+
+    /// Return the generation for `/api/sync`.
+    pub fn get(&self, index: &Arc<MemoryMetaIndex>, user_data_version: u64) -> String {
+        format!("{:016x}-{:x}-{}", self.library(index), self.started_at, user_data_version)
+    }
+}
+
+impl Default for GenerationCache {
+    fn default() -> GenerationCache {
+        GenerationCache::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::hash::Hasher;
+    use std::sync::Arc;
+
+    use super::{Fnv1a, GenerationCache};
+    use crate::MemoryMetaIndex;
+
+    #[test]
+    fn fnv1a_matches_reference_values() {
+        let mut h = Fnv1a::new();
+        assert_eq!(h.finish(), 0xcbf2_9ce4_8422_2325);
+        h.write(b"a");
+        assert_eq!(h.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn generation_cache_recomputes_for_new_index() {
+        let cache = GenerationCache::new();
+        let index = Arc::new(MemoryMetaIndex::new_empty());
+        let library = cache.library(&index);
+        assert_eq!(cache.library(&index), library);
+
+        // A new index with the same content has the same fingerprint.
+        let other = Arc::new(MemoryMetaIndex::new_empty());
+        assert_eq!(cache.library(&other), library);
+        assert!(Arc::ptr_eq(
+            &cache.latest.lock().unwrap().as_ref().unwrap().0.upgrade().unwrap(),
+            &other,
+        ));
+    }
+
+    #[test]
+    fn generation_changes_with_user_data() {
+        let cache = GenerationCache::new();
+        let index = Arc::new(MemoryMetaIndex::new_empty());
+        let generation = cache.get(&index, 0);
+        assert!(generation.starts_with(&format!("{:016x}-", cache.library(&index))));
+        assert_eq!(cache.get(&index, 0), generation);
+        assert_ne!(cache.get(&index, 1), generation);
+    }
+}
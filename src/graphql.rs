@@ -0,0 +1,1121 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A read-only GraphQL endpoint over the index, queue, playlists, and listens.
+//!
+//! The REST endpoints return fixed shapes, so a client that wants an album
+//! with its tracks and their play counts and a thumbnail hash needs several
+//! round trips. With GraphQL, the client names the fields it needs, and we
+//! resolve them in one go. We implement the subset of the query language that
+//! such clients need: a single query operation with fields, aliases,
+//! arguments, and variables. Fragments, directives, mutations, and
+//! introspection are not supported. See `docs/graphql.md` for the schema.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::database as db;
+use crate::database::Connection;
+use crate::listens::{self, ListenParams};
+use crate::listing::{self, ListParams, SortOrder};
+use crate::player::{Source, TrackSnapshot};
+use crate::prim::{Album, AlbumId, ArtistId, Instant, Track, TrackId};
+use crate::shuffle::Prng;
+use crate::smart_playlist::Query as SmartQuery;
+use crate::thumb_cache::ThumbCache;
+use crate::user_data::UserData;
+use crate::MetaIndex;
+
+/// Queries nest, but not arbitrarily deep, this bounds the recursion.
+const MAX_DEPTH: u32 = 12;
+
+/// A value in a query document, or of a variable.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    String(String),
+    /// A reference to a variable, `$name`. Does not occur in variable values.
+    Variable(String),
+}
+
+/// A field in a selection set, with its arguments and subselection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Field {
+    pub alias: Option<String>,
+    pub name: String,
+    pub arguments: Vec<(String, Value)>,
+    pub selection: Vec<Field>,
+}
+
+impl Field {
+    /// The key of the field in the response.
+    fn response_key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// A parsed query operation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Query {
+    /// Declared variables, with their default value, if any.
+    pub variables: Vec<(String, Option<Value>)>,
+    pub selection: Vec<Field>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Punct(u8),
+    Name(String),
+    Int(i64),
+    String(String),
+    Spread,
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let bytes = src.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            // Commas are insignificant in GraphQL, like whitespace.
+            b' ' | b'\t' | b'\n' | b'\r' | b',' => i += 1,
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'{' | b'}' | b'(' | b')' | b'[' | b']' | b':' | b'$' | b'!' | b'=' | b'@' => {
+                tokens.push(Token::Punct(bytes[i]));
+                i += 1;
+            }
+            b'.' if src[i..].starts_with("...") => {
+                tokens.push(Token::Spread);
+                i += 3;
+            }
+            b'"' => {
+                if src[i..].starts_with(r#"""""#) {
+                    return Err("Block strings are not supported.".to_string());
+                }
+                let (s, len) = tokenize_string(&src[i..])?;
+                tokens.push(Token::String(s));
+                i += len;
+            }
+            b'-' | b'0'..=b'9' => {
+                let begin = i;
+                i += 1;
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                if i < bytes.len() && matches!(bytes[i], b'.' | b'e' | b'E') {
+                    return Err("Float values are not supported.".to_string());
+                }
+                match i64::from_str(&src[begin..i]) {
+                    Ok(n) => tokens.push(Token::Int(n)),
+                    Err(_) => return Err(format!("Invalid integer '{}'.", &src[begin..i])),
+                }
+            }
+            b'_' | b'a'..=b'z' | b'A'..=b'Z' => {
+                let begin = i;
+                while i < bytes.len() && (bytes[i] == b'_' || bytes[i].is_ascii_alphanumeric()) {
+                    i += 1;
+                }
+                tokens.push(Token::Name(src[begin..i].to_string()));
+            }
+            _ => {
+                let ch = src[i..].chars().next().unwrap();
+                return Err(format!("Unexpected character '{}'.", ch));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Read a string literal at the start of `src`, return it and its length.
+fn tokenize_string(src: &str) -> Result<(String, usize), String> {
+    let mut result = String::new();
+    let mut chars = src.char_indices().skip(1);
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '"' => return Ok((result, i + 1)),
+            '\n' => break,
+            '\\' => match chars.next() {
+                Some((_, '"')) => result.push('"'),
+                Some((_, '\\')) => result.push('\\'),
+                Some((_, '/')) => result.push('/'),
+                Some((_, 'n')) => result.push('\n'),
+                Some((_, 't')) => result.push('\t'),
+                Some((_, 'r')) => result.push('\r'),
+                Some((_, 'u')) => {
+                    let hex: String = (0..4).filter_map(|_| chars.next()).map(|(_, c)| c).collect();
+                    match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                        Some(c) => result.push(c),
+                        None => return Err("Invalid unicode escape in string.".to_string()),
+                    }
+                }
+                _ => return Err("Invalid escape sequence in string.".to_string()),
+            },
+            _ => result.push(ch),
+        }
+    }
+    Err("Unterminated string.".to_string())
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_punct(&self, ch: u8) -> bool {
+        self.peek() == Some(&Token::Punct(ch))
+    }
+
+    fn expect_punct(&mut self, ch: u8) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Punct(c)) if c == ch => Ok(()),
+            _ => Err(format!("Expected '{}'.", ch as char)),
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(name),
+            _ => Err("Expected a name.".to_string()),
+        }
+    }
+
+    fn parse_query(&mut self) -> Result<Query, String> {
+        let mut variables = Vec::new();
+
+        match self.peek() {
+            Some(Token::Punct(b'{')) => {}
+            Some(Token::Name(keyword)) if keyword == "query" => {
+                self.pos += 1;
+                // The operation name is optional, and we have no use for it.
+                if let Some(Token::Name(..)) = self.peek() {
+                    self.pos += 1;
+                }
+                if self.is_punct(b'(') {
+                    variables = self.parse_variable_definitions()?;
+                }
+            }
+            Some(Token::Name(keyword)) if keyword == "mutation" || keyword == "subscription" => {
+                return Err("Only queries are supported.".to_string());
+            }
+            _ => return Err("Expected a query.".to_string()),
+        }
+
+        let selection = self.parse_selection_set(0)?;
+
+        if self.peek().is_some() {
+            return Err("Expected a single operation.".to_string());
+        }
+
+        let query = Query {
+            variables: variables,
+            selection: selection,
+        };
+        Ok(query)
+    }
+
+    fn parse_variable_definitions(&mut self) -> Result<Vec<(String, Option<Value>)>, String> {
+        let mut variables = Vec::new();
+        self.expect_punct(b'(')?;
+        while !self.is_punct(b')') {
+            self.expect_punct(b'$')?;
+            let name = self.expect_name()?;
+            self.expect_punct(b':')?;
+            // We check the values when we use them, so we only need to skip
+            // over the type.
+            self.skip_type()?;
+            let default = if self.is_punct(b'=') {
+                self.pos += 1;
+                Some(self.parse_value()?)
+            } else {
+                None
+            };
+            variables.push((name, default));
+        }
+        self.expect_punct(b')')?;
+        Ok(variables)
+    }
+
+    fn skip_type(&mut self) -> Result<(), String> {
+        if self.is_punct(b'[') {
+            self.pos += 1;
+            self.skip_type()?;
+            self.expect_punct(b']')?;
+        } else {
+            self.expect_name()?;
+        }
+        if self.is_punct(b'!') {
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn parse_selection_set(&mut self, depth: u32) -> Result<Vec<Field>, String> {
+        if depth > MAX_DEPTH {
+            return Err("Query is nested too deeply.".to_string());
+        }
+        let mut fields = Vec::new();
+        self.expect_punct(b'{')?;
+        while !self.is_punct(b'}') {
+            fields.push(self.parse_field(depth)?);
+        }
+        self.expect_punct(b'}')?;
+        if fields.is_empty() {
+            return Err("Selection set must not be empty.".to_string());
+        }
+        Ok(fields)
+    }
+
+    fn parse_field(&mut self, depth: u32) -> Result<Field, String> {
+        if let Some(Token::Spread) = self.peek() {
+            return Err("Fragments are not supported.".to_string());
+        }
+        let mut alias = None;
+        let mut name = self.expect_name()?;
+        if self.is_punct(b':') {
+            self.pos += 1;
+            alias = Some(name);
+            name = self.expect_name()?;
+        }
+
+        let mut arguments = Vec::new();
+        if self.is_punct(b'(') {
+            self.pos += 1;
+            while !self.is_punct(b')') {
+                let arg_name = self.expect_name()?;
+                self.expect_punct(b':')?;
+                arguments.push((arg_name, self.parse_value()?));
+            }
+            self.expect_punct(b')')?;
+        }
+
+        if self.is_punct(b'@') {
+            return Err("Directives are not supported.".to_string());
+        }
+
+        let selection = if self.is_punct(b'{') {
+            self.parse_selection_set(depth + 1)?
+        } else {
+            Vec::new()
+        };
+
+        let field = Field {
+            alias: alias,
+            name: name,
+            arguments: arguments,
+            selection: selection,
+        };
+        Ok(field)
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String> {
+        match self.next() {
+            Some(Token::Punct(b'$')) => Ok(Value::Variable(self.expect_name()?)),
+            Some(Token::Int(n)) => Ok(Value::Int(n)),
+            Some(Token::String(s)) => Ok(Value::String(s)),
+            Some(Token::Name(name)) => match name.as_ref() {
+                "null" => Ok(Value::Null),
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                // Enum values, we treat them like strings.
+                _ => Ok(Value::String(name)),
+            },
+            Some(Token::Punct(b'[')) | Some(Token::Punct(b'{')) => {
+                Err("List and object values are not supported.".to_string())
+            }
+            _ => Err("Expected a value.".to_string()),
+        }
+    }
+}
+
+/// Parse a query document that contains a single query operation.
+pub fn parse(src: &str) -> Result<Query, String> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+    };
+    parser.parse_query()
+}
+
+/// Convert the value of a variable from the request to a query value.
+fn value_from_json(value: &serde_json::Value) -> Result<Value, String> {
+    match value {
+        serde_json::Value::Null => Ok(Value::Null),
+        serde_json::Value::Bool(b) => Ok(Value::Bool(*b)),
+        serde_json::Value::String(s) => Ok(Value::String(s.clone())),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Ok(Value::Int(i)),
+            None => Err("Variables must be integers, not floats.".to_string()),
+        },
+        _ => Err("List and object variables are not supported.".to_string()),
+    }
+}
+
+/// A query to execute, and the values of its variables.
+pub struct Request {
+    pub query: Query,
+    pub variables: HashMap<String, Value>,
+}
+
+fn build_request(query: &str, variables: Option<&serde_json::Value>) -> Result<Request, String> {
+    let mut values = HashMap::new();
+    match variables {
+        None | Some(serde_json::Value::Null) => {}
+        Some(serde_json::Value::Object(vars)) => {
+            for (name, value) in vars.iter() {
+                values.insert(name.clone(), value_from_json(value)?);
+            }
+        }
+        Some(..) => return Err("Variables must be an object.".to_string()),
+    }
+    let request = Request {
+        query: parse(query)?,
+        variables: values,
+    };
+    Ok(request)
+}
+
+/// Parse a POST body of the form `{"query": "...", "variables": {...}}`.
+pub fn parse_json_request(body: &str) -> Result<Request, String> {
+    let body: serde_json::Value = serde_json::from_str(body)
+        .map_err(|_| "Request body must be a json object.".to_string())?;
+    match body.get("query") {
+        Some(serde_json::Value::String(query)) => build_request(query, body.get("variables")),
+        _ => Err("Request body must contain a 'query' string.".to_string()),
+    }
+}
+
+/// Parse the `query` and `variables` url parameters of a GET request.
+pub fn parse_get_request(query: Option<String>, variables: Option<String>) -> Result<Request, String> {
+    let query = query.ok_or("Missing 'query' parameter.")?;
+    let variables: Option<serde_json::Value> = match variables {
+        Some(vars) => Some(
+            serde_json::from_str(&vars)
+                .map_err(|_| "The 'variables' parameter must be a json object.".to_string())?
+        ),
+        None => None,
+    };
+    build_request(&query, variables.as_ref())
+}
+
+/// A resolved value, the `data` part of the response.
+///
+/// Unlike a `serde_json::Value`, objects keep their fields in the order of
+/// the query, as the GraphQL spec requires.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f32),
+    String(String),
+    List(Vec<Output>),
+    Object(Vec<(String, Output)>),
+}
+
+impl Output {
+    fn string(s: &str) -> Output {
+        Output::String(s.to_string())
+    }
+
+    fn optional_string(s: Option<&str>) -> Output {
+        match s {
+            Some(s) => Output::String(s.to_string()),
+            None => Output::Null,
+        }
+    }
+
+    fn optional_instant(t: Option<Instant>) -> Output {
+        match t {
+            Some(t) => Output::String(t.format_iso8601()),
+            None => Output::Null,
+        }
+    }
+
+    pub fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Output::Null => write!(w, "null"),
+            Output::Bool(b) => write!(w, "{}", b),
+            Output::Int(i) => write!(w, "{}", i),
+            Output::Float(x) => write!(w, "{:.03}", x),
+            Output::String(s) => serde_json::to_writer(w, s).map_err(io::Error::from),
+            Output::List(xs) => {
+                write!(w, "[")?;
+                let mut first = true;
+                for x in xs {
+                    if !first { write!(w, ",")?; }
+                    x.write_json(w)?;
+                    first = false;
+                }
+                write!(w, "]")
+            }
+            Output::Object(fields) => {
+                write!(w, "{{")?;
+                let mut first = true;
+                for (key, x) in fields {
+                    if !first { write!(w, ",")?; }
+                    serde_json::to_writer(&mut *w, key)?;
+                    write!(w, ":")?;
+                    x.write_json(w)?;
+                    first = false;
+                }
+                write!(w, "}}")
+            }
+        }
+    }
+}
+
+/// Write the response body for the result of `execute`.
+///
+/// We resolve the entire query or nothing, so on error, `data` is null.
+pub fn write_response<W: Write>(mut w: W, result: &Result<Output, String>) -> io::Result<()> {
+    match result {
+        Ok(data) => {
+            write!(w, r#"{{"data":"#)?;
+            data.write_json(&mut w)?;
+            write!(w, "}}")
+        }
+        Err(message) => {
+            write!(w, r#"{{"data":null,"errors":[{{"message":"#)?;
+            serde_json::to_writer(&mut w, message)?;
+            write!(w, "}}]}}")
+        }
+    }
+}
+
+/// Everything that a query can read from.
+pub struct Context<'a, 'db> {
+    pub index: &'a dyn MetaIndex,
+    /// The user making the request, `None` for the default user.
+    pub user: Option<&'a str>,
+    /// The user data of that user.
+    pub user_data: &'a UserData,
+    pub thumb_cache: &'a ThumbCache,
+    pub queue: &'a [TrackSnapshot],
+    pub db: &'a mut Connection<'db>,
+}
+
+/// A playlist, and its track count if we know it without evaluating it.
+struct PlaylistInfo {
+    id: i64,
+    name: String,
+    query: Option<String>,
+    track_count: Option<i64>,
+}
+
+struct Executor<'c, 'a, 'db> {
+    ctx: &'c mut Context<'a, 'db>,
+    variables: HashMap<String, Value>,
+}
+
+/// Execute the query against the context.
+///
+/// The `variables` are the values that the request provided, declared
+/// variables that are absent take their default value.
+pub fn execute(
+    ctx: &mut Context,
+    query: &Query,
+    mut variables: HashMap<String, Value>,
+) -> Result<Output, String> {
+    for (name, default) in query.variables.iter() {
+        if let Some(value) = default {
+            variables.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+    }
+    let mut executor = Executor {
+        ctx: ctx,
+        variables: variables,
+    };
+    executor.object("Query", &query.selection, |ex, field| ex.query_field(field))
+}
+
+fn check_arguments(field: &Field, allowed: &[&str]) -> Result<(), String> {
+    for (name, _) in field.arguments.iter() {
+        if !allowed.contains(&name.as_ref()) {
+            return Err(format!("Unknown argument '{}' on field '{}'.", name, field.name));
+        }
+    }
+    Ok(())
+}
+
+fn db_error(err: sqlite::Error) -> String {
+    error!("Error while resolving GraphQL query: {:?}", err);
+    "Database error.".to_string()
+}
+
+impl<'c, 'a, 'db> Executor<'c, 'a, 'db> {
+    /// Resolve the selection on an object, `resolve` resolves one field.
+    ///
+    /// The resolver returns `None` for fields that the type does not have.
+    fn object<F>(&mut self, type_name: &str, selection: &[Field], mut resolve: F) -> Result<Output, String>
+    where
+        F: FnMut(&mut Self, &Field) -> Result<Option<Output>, String>,
+    {
+        if selection.is_empty() {
+            return Err(format!("Fields of type '{}' must have a selection of subfields.", type_name));
+        }
+        let mut result = Vec::with_capacity(selection.len());
+        for field in selection {
+            let value = if field.name == "__typename" {
+                Output::string(type_name)
+            } else {
+                match resolve(self, field)? {
+                    Some(value) => value,
+                    None => return Err(format!(
+                        "Cannot query field '{}' on type '{}'.", field.name, type_name,
+                    )),
+                }
+            };
+            let is_scalar = !matches!(value, Output::Null | Output::List(..) | Output::Object(..));
+            if is_scalar && !field.selection.is_empty() {
+                return Err(format!("Field '{}' is a scalar and cannot have a selection.", field.name));
+            }
+            result.push((field.response_key().to_string(), value));
+        }
+        Ok(Output::Object(result))
+    }
+
+    /// Return the value of an argument, with variables substituted.
+    fn argument(&self, field: &Field, name: &str) -> Option<Value> {
+        let value = field.arguments.iter().find(|(k, _)| k == name).map(|(_, v)| v)?;
+        match value {
+            Value::Variable(var) => self.variables.get(var).cloned(),
+            v => Some(v.clone()),
+        }
+    }
+
+    fn string_argument(&self, field: &Field, name: &str) -> Result<Option<String>, String> {
+        match self.argument(field, name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(..) => Err(format!("Argument '{}' must be a string.", name)),
+        }
+    }
+
+    fn int_argument(&self, field: &Field, name: &str) -> Result<Option<i64>, String> {
+        match self.argument(field, name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::Int(i)) => Ok(Some(i)),
+            Some(..) => Err(format!("Argument '{}' must be an integer.", name)),
+        }
+    }
+
+    fn required_argument<T>(&self, field: &Field, name: &str, value: Option<T>) -> Result<T, String> {
+        value.ok_or_else(|| format!("Field '{}' needs argument '{}'.", field.name, name))
+    }
+
+    /// Parse the `sort`, `offset`, and `limit` arguments of a listing field.
+    fn list_params(&self, field: &Field) -> Result<ListParams, String> {
+        check_arguments(field, &["sort", "offset", "limit"])?;
+        let mut params = ListParams {
+            sort: SortOrder::Id,
+            offset: 0,
+            limit: None,
+        };
+        if let Some(sort) = self.string_argument(field, "sort")? {
+            params.sort = SortOrder::from_str(&sort.to_lowercase())?;
+        }
+        if let Some(n) = self.int_argument(field, "offset")? {
+            params.offset = usize::try_from(n).map_err(|_| "Offset must not be negative.")?;
+        }
+        if let Some(n) = self.int_argument(field, "limit")? {
+            params.limit = Some(usize::try_from(n).map_err(|_| "Limit must not be negative.")?);
+        }
+        Ok(params)
+    }
+
+    fn list<T, F>(&mut self, items: &[T], mut resolve: F) -> Result<Output, String>
+    where
+        F: FnMut(&mut Self, &T) -> Result<Output, String>,
+    {
+        let mut result = Vec::with_capacity(items.len());
+        for item in items {
+            result.push(resolve(self, item)?);
+        }
+        Ok(Output::List(result))
+    }
+
+    fn query_field(&mut self, field: &Field) -> Result<Option<Output>, String> {
+        let index = self.ctx.index;
+        let user_data = self.ctx.user_data;
+        let sel = &field.selection[..];
+
+        let result = match field.name.as_ref() {
+            "album" => {
+                check_arguments(field, &["id"])?;
+                let id = self.string_argument(field, "id")?;
+                let id = self.required_argument(field, "id", id)?;
+                let album_id = AlbumId::parse(&id).ok_or("Invalid album id.")?;
+                match index.get_album(album_id) {
+                    Some(album) => self.album(sel, album_id, album)?,
+                    None => Output::Null,
+                }
+            }
+            "albums" => {
+                let params = self.list_params(field)?;
+                let albums = listing::list_albums(index, user_data, &params);
+                self.list(&albums, |ex, &album_id| {
+                    ex.album(sel, album_id, index.get_album(album_id).unwrap())
+                })?
+            }
+            "artist" => {
+                check_arguments(field, &["id"])?;
+                let id = self.string_argument(field, "id")?;
+                let id = self.required_argument(field, "id", id)?;
+                let artist_id = ArtistId::parse(&id).ok_or("Invalid artist id.")?;
+                match index.get_artist(artist_id) {
+                    Some(..) => self.artist(sel, artist_id)?,
+                    None => Output::Null,
+                }
+            }
+            "artists" => {
+                let params = self.list_params(field)?;
+                let artists = listing::list_artists(index, user_data, &params);
+                self.list(&artists, |ex, &artist_id| ex.artist(sel, artist_id))?
+            }
+            "track" => {
+                check_arguments(field, &["id"])?;
+                let id = self.string_argument(field, "id")?;
+                let id = self.required_argument(field, "id", id)?;
+                let track_id = TrackId::parse(&id).ok_or("Invalid track id.")?;
+                match index.get_track(track_id) {
+                    Some(track) => self.track(sel, track_id, track)?,
+                    None => Output::Null,
+                }
+            }
+            "tracks" => {
+                let params = self.list_params(field)?;
+                let tracks = listing::list_tracks(index, user_data, &params);
+                self.list(&tracks, |ex, &track_id| {
+                    ex.track(sel, track_id, index.get_track(track_id).unwrap())
+                })?
+            }
+            "queue" => {
+                check_arguments(field, &[])?;
+                let queue = self.ctx.queue;
+                self.list(queue, |ex, entry| ex.queue_entry(sel, entry))?
+            }
+            "playlist" => {
+                check_arguments(field, &["id"])?;
+                let id = self.int_argument(field, "id")?;
+                let id = self.required_argument(field, "id", id)?;
+                let user = self.ctx.user;
+                let header = self
+                    .ctx
+                    .db
+                    .begin()
+                    .and_then(|mut tx| {
+                        let header = db::select_playlist(&mut tx, id, user)?;
+                        tx.commit()?;
+                        Ok(header)
+                    })
+                    .map_err(db_error)?;
+                match header {
+                    Some(header) => {
+                        let playlist = PlaylistInfo {
+                            id: id,
+                            name: header.name,
+                            query: header.query,
+                            track_count: None,
+                        };
+                        self.playlist(sel, &playlist)?
+                    }
+                    None => Output::Null,
+                }
+            }
+            "playlists" => {
+                check_arguments(field, &[])?;
+                let user = self.ctx.user;
+                let playlists = self
+                    .ctx
+                    .db
+                    .begin()
+                    .and_then(|mut tx| {
+                        let mut result = Vec::new();
+                        for playlist in db::iter_playlists(&mut tx, user)? {
+                            let playlist = playlist?;
+                            // The count in the database is the number of
+                            // entries, smart playlists have none of those.
+                            let track_count = match playlist.query {
+                                None => Some(playlist.track_count),
+                                Some(..) => None,
+                            };
+                            result.push(PlaylistInfo {
+                                id: playlist.id,
+                                name: playlist.name,
+                                query: playlist.query,
+                                track_count: track_count,
+                            });
+                        }
+                        tx.commit()?;
+                        Ok(result)
+                    })
+                    .map_err(db_error)?;
+                self.list(&playlists, |ex, playlist| ex.playlist(sel, playlist))?
+            }
+            "listens" => {
+                check_arguments(field, &["limit", "cursor", "album", "artist"])?;
+                let mut params = ListenParams {
+                    since: None,
+                    until: None,
+                    artist: None,
+                    album: None,
+                    client: None,
+                    limit: 100,
+                    cursor: self.int_argument(field, "cursor")?,
+                };
+                if let Some(n) = self.int_argument(field, "limit")? {
+                    if !(1..=1000).contains(&n) {
+                        return Err("Invalid limit, must be an integer from 1 to 1000.".to_string());
+                    }
+                    params.limit = n as usize;
+                }
+                if let Some(id) = self.string_argument(field, "album")? {
+                    params.album = Some(AlbumId::parse(&id).ok_or("Invalid album id.")?);
+                }
+                if let Some(id) = self.string_argument(field, "artist")? {
+                    params.artist = Some(ArtistId::parse(&id).ok_or("Invalid artist id.")?);
+                }
+                let user = self.ctx.user;
+                let (rows, next_cursor) = self
+                    .ctx
+                    .db
+                    .begin()
+                    .and_then(|mut tx| {
+                        let page = listens::get_page(&mut tx, &params, user)?;
+                        tx.commit()?;
+                        Ok(page)
+                    })
+                    .map_err(db_error)?;
+                self.object("ListenPage", sel, |ex, f| {
+                    let result = match f.name.as_ref() {
+                        "listens" => ex.list(&rows, |ex, row| ex.listen(&f.selection, row))?,
+                        "next_cursor" => next_cursor.map_or(Output::Null, Output::Int),
+                        _ => return Ok(None),
+                    };
+                    Ok(Some(result))
+                })?
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(result))
+    }
+
+    fn album(&mut self, sel: &[Field], album_id: AlbumId, album: &Album) -> Result<Output, String> {
+        let index = self.ctx.index;
+        let user_data = self.ctx.user_data;
+        let thumb_cache = self.ctx.thumb_cache;
+        self.object("Album", sel, |ex, f| {
+            let result = match f.name.as_ref() {
+                "id" => Output::String(album_id.to_string()),
+                "title" => Output::string(index.get_string(album.title)),
+                "artist" => Output::string(index.get_string(album.artist)),
+                "artists" => {
+                    let artist_ids = index.get_album_artists(album.artist_ids);
+                    ex.list(artist_ids, |ex, &artist_id| ex.artist(&f.selection, artist_id))?
+                }
+                "release_date" => Output::String(album.original_release_date.to_string()),
+                "first_seen" => Output::String(album.first_seen.format_iso8601()),
+                "rating" => Output::Int(user_data.get_album_rating(album_id) as i64),
+                "play_count" => Output::Int(user_data.get_album_play_count(album_id) as i64),
+                "last_played" => Output::optional_instant(user_data.get_album_last_played(album_id)),
+                // Same as the etag of the thumbnail, so a client can request
+                // `/api/thumb/:id?v=:thumb_hash` and cache it forever.
+                "thumb_hash" => match thumb_cache.get(album_id) {
+                    Some(img) => Output::string(&crate::md5::md5_hex(img)[..16]),
+                    None => Output::Null,
+                },
+                "tracks" => {
+                    let tracks = index.get_album_tracks(album_id);
+                    ex.list(tracks, |ex, kv| ex.track(&f.selection, kv.track_id, &kv.track))?
+                }
+                _ => return Ok(None),
+            };
+            Ok(Some(result))
+        })
+    }
+
+    fn artist(&mut self, sel: &[Field], artist_id: ArtistId) -> Result<Output, String> {
+        let index = self.ctx.index;
+        let user_data = self.ctx.user_data;
+        let artist = match index.get_artist(artist_id) {
+            Some(artist) => artist,
+            None => return Ok(Output::Null),
+        };
+        self.object("Artist", sel, |ex, f| {
+            let result = match f.name.as_ref() {
+                "id" => Output::String(artist_id.to_string()),
+                "name" => Output::string(index.get_string(artist.name)),
+                "sort_name" => Output::string(index.get_string(artist.name_for_sort)),
+                "rating" => Output::Int(user_data.get_artist_rating(artist_id) as i64),
+                "play_count" => Output::Int(user_data.get_artist_play_count(artist_id) as i64),
+                "last_played" => Output::optional_instant(user_data.get_artist_last_played(artist_id)),
+                "albums" => {
+                    let albums = index.get_albums_by_artist(artist_id);
+                    ex.list(albums, |ex, &(_, album_id)| {
+                        ex.album(&f.selection, album_id, index.get_album(album_id).unwrap())
+                    })?
+                }
+                _ => return Ok(None),
+            };
+            Ok(Some(result))
+        })
+    }
+
+    fn track(&mut self, sel: &[Field], track_id: TrackId, track: &Track) -> Result<Output, String> {
+        let index = self.ctx.index;
+        let user_data = self.ctx.user_data;
+        self.object("Track", sel, |ex, f| {
+            let result = match f.name.as_ref() {
+                "id" => Output::String(track_id.to_string()),
+                "title" => Output::string(index.get_string(track.title)),
+                "artist" => Output::string(index.get_string(track.artist)),
+                "album" => {
+                    let album_id = track_id.album_id();
+                    match index.get_album(album_id) {
+                        Some(album) => ex.album(&f.selection, album_id, album)?,
+                        None => Output::Null,
+                    }
+                }
+                "disc_number" => Output::Int(track_id.disc_number() as i64),
+                "track_number" => Output::Int(track_id.track_number() as i64),
+                "duration_seconds" => Output::Int(track.duration_seconds as i64),
+                "rating" => Output::Int(user_data.get_track_rating(track_id) as i64),
+                "play_count" => Output::Int(user_data.get_track_play_count(track_id) as i64),
+                "last_played" => Output::optional_instant(user_data.get_track_last_played(track_id)),
+                _ => return Ok(None),
+            };
+            Ok(Some(result))
+        })
+    }
+
+    fn queue_entry(&mut self, sel: &[Field], entry: &TrackSnapshot) -> Result<Output, String> {
+        let index = self.ctx.index;
+        let (track_id, station) = match &entry.source {
+            Source::Track(track_id) => (Some(*track_id), None),
+            Source::Radio(station) => (None, Some(station)),
+        };
+        self.object("QueueEntry", sel, |ex, f| {
+            let result = match f.name.as_ref() {
+                "queue_id" => Output::String(entry.queue_id.to_string()),
+                "track" => match track_id.and_then(|id| index.get_track(id).map(|t| (id, t))) {
+                    Some((id, track)) => ex.track(&f.selection, id, track)?,
+                    None => Output::Null,
+                },
+                "radio_station_id" => station.map_or(Output::Null, |s| Output::Int(s.id)),
+                "station" => Output::optional_string(station.map(|s| &s.name[..])),
+                "stream_title" => Output::optional_string(entry.stream_title.as_deref()),
+                "position_seconds" => Output::Float(entry.position_ms as f32 * 1e-3),
+                "buffered_seconds" => Output::Float(entry.buffered_ms as f32 * 1e-3),
+                "is_buffering" => Output::Bool(entry.is_buffering),
+                _ => return Ok(None),
+            };
+            Ok(Some(result))
+        })
+    }
+
+    /// Return the tracks of a playlist, evaluating it if it is a smart one.
+    fn playlist_tracks(&mut self, playlist: &PlaylistInfo) -> Result<Vec<TrackId>, String> {
+        if let Some(query_str) = playlist.query.as_ref() {
+            // Queries are validated before we store them, this can only fail
+            // if the query language changed in an incompatible way.
+            let query = SmartQuery::from_str(query_str)
+                .map_err(|msg| format!("Invalid smart playlist query: {}", msg))?;
+            let now = Instant { posix_seconds_utc: chrono::Utc::now().timestamp() };
+            let mut rng = Prng::new();
+            return Ok(query.evaluate(self.ctx.index, self.ctx.user_data, now, &mut rng));
+        }
+
+        self.ctx
+            .db
+            .begin()
+            .and_then(|mut tx| {
+                let mut result = Vec::new();
+                for entry in db::iter_playlist_entries(&mut tx, playlist.id)? {
+                    result.push(TrackId(entry?.track_id as u64));
+                }
+                tx.commit()?;
+                Ok(result)
+            })
+            .map_err(db_error)
+    }
+
+    fn playlist(&mut self, sel: &[Field], playlist: &PlaylistInfo) -> Result<Output, String> {
+        let index = self.ctx.index;
+        self.object("Playlist", sel, |ex, f| {
+            let result = match f.name.as_ref() {
+                "id" => Output::Int(playlist.id),
+                "name" => Output::string(&playlist.name),
+                "query" => Output::optional_string(playlist.query.as_deref()),
+                "track_count" => match playlist.track_count {
+                    Some(n) => Output::Int(n),
+                    None => Output::Int(ex.playlist_tracks(playlist)?.len() as i64),
+                },
+                "tracks" => {
+                    // Entries may refer to tracks that are no longer in the
+                    // library, we leave those out.
+                    let tracks = ex.playlist_tracks(playlist)?;
+                    let mut result = Vec::with_capacity(tracks.len());
+                    for track_id in tracks {
+                        if let Some(track) = index.get_track(track_id) {
+                            result.push(ex.track(&f.selection, track_id, track)?);
+                        }
+                    }
+                    Output::List(result)
+                }
+                _ => return Ok(None),
+            };
+            Ok(Some(result))
+        })
+    }
+
+    fn listen(&mut self, sel: &[Field], row: &db::ListenRow) -> Result<Output, String> {
+        let index = self.ctx.index;
+        let track_id = TrackId(row.track_id as u64);
+        self.object("Listen", sel, |ex, f| {
+            let result = match f.name.as_ref() {
+                "id" => Output::Int(row.id),
+                "started_at" => Output::string(&row.started_at),
+                "completed_at" => Output::optional_string(row.completed_at.as_deref()),
+                "track_title" => Output::string(&row.track_title),
+                "track_artist" => Output::string(&row.track_artist),
+                "album_title" => Output::string(&row.album_title),
+                "album_artist" => Output::string(&row.album_artist),
+                "duration_seconds" => Output::Int(row.duration_seconds),
+                "source" => Output::string(&row.source),
+                "scrobbled_at" => Output::optional_string(row.scrobbled_at.as_deref()),
+                "client" => Output::optional_string(row.client.as_deref()),
+                // The track may have left the library since the listen.
+                "track" => match index.get_track(track_id) {
+                    Some(track) => ex.track(&f.selection, track_id, track)?,
+                    None => Output::Null,
+                },
+                _ => return Ok(None),
+            };
+            Ok(Some(result))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, write_response, Field, Output, Value};
+
+    fn field(name: &str, selection: Vec<Field>) -> Field {
+        Field {
+            alias: None,
+            name: name.to_string(),
+            arguments: Vec::new(),
+            selection: selection,
+        }
+    }
+
+    #[test]
+    fn parse_handles_shorthand_query() {
+        let query = parse("{ queue { queue_id track { title } } }").unwrap();
+        assert!(query.variables.is_empty());
+        assert_eq!(
+            query.selection,
+            vec![field("queue", vec![
+                field("queue_id", vec![]),
+                field("track", vec![field("title", vec![])]),
+            ])],
+        );
+    }
+
+    #[test]
+    fn parse_handles_aliases_arguments_and_variables() {
+        let src = r#"
+            query Album($id: String!, $n: Int = 10) {
+              # The album, and what played recently.
+              a: album(id: $id) { title }
+              listens(limit: $n, album: "1234", cursor: -5) { next_cursor }
+            }
+        "#;
+        let query = parse(src).unwrap();
+        assert_eq!(
+            query.variables,
+            vec![("id".to_string(), None), ("n".to_string(), Some(Value::Int(10)))],
+        );
+        let album = &query.selection[0];
+        assert_eq!(album.alias.as_deref(), Some("a"));
+        assert_eq!(album.name, "album");
+        assert_eq!(album.arguments, vec![("id".to_string(), Value::Variable("id".to_string()))]);
+        let listens = &query.selection[1];
+        assert_eq!(
+            listens.arguments,
+            vec![
+                ("limit".to_string(), Value::Variable("n".to_string())),
+                ("album".to_string(), Value::String("1234".to_string())),
+                ("cursor".to_string(), Value::Int(-5)),
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_rejects_unsupported_syntax() {
+        assert!(parse("mutation { rate }").is_err());
+        assert!(parse("{ album { ...AlbumFields } }").is_err());
+        assert!(parse("{ album @skip(if: true) { title } }").is_err());
+        assert!(parse("{ albums(limit: 1.5) { title } }").is_err());
+        assert!(parse("{ queue { } }").is_err());
+        assert!(parse("{ queue } { queue }").is_err());
+        assert!(parse(r#"{ album(id: "unterminated) { title } }"#).is_err());
+    }
+
+    #[test]
+    fn parse_unescapes_strings() {
+        let query = parse(r#"{ album(id: "a\"bé") { title } }"#).unwrap();
+        assert_eq!(
+            query.selection[0].arguments,
+            vec![("id".to_string(), Value::String("a\"b\u{e9}".to_string()))],
+        );
+    }
+
+    #[test]
+    fn write_response_preserves_field_order() {
+        let data = Output::Object(vec![
+            ("z".to_string(), Output::List(vec![Output::Int(1), Output::Null])),
+            ("a".to_string(), Output::String("\"q\"".to_string())),
+        ]);
+        let mut out = Vec::new();
+        write_response(&mut out, &Ok(data)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"data":{"z":[1,null],"a":"\"q\""}}"#,
+        );
+
+        let mut out = Vec::new();
+        write_response(&mut out, &Err("Invalid album id.".to_string())).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"data":null,"errors":[{"message":"Invalid album id."}]}"#,
+        );
+    }
+}
@@ -0,0 +1,294 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A minimal gzip encoder, for compressing http responses.
+//!
+//! We find matches with hash chains and encode them in a single block with
+//! the fixed Huffman code of DEFLATE. Dynamic Huffman codes would save maybe
+//! another 10–20%, but json is so repetitive that the matches matter most.
+//! See also RFC 1951 for DEFLATE and RFC 1952 for the gzip container.
+
+use crate::zip::crc32_update;
+
+/// DEFLATE can refer back at most this many bytes.
+const WINDOW_SIZE: usize = 32 * 1024;
+
+const MIN_MATCH_LEN: usize = 3;
+const MAX_MATCH_LEN: usize = 258;
+
+/// How many earlier positions with the same hash we try before giving up.
+const MAX_CHAIN_LEN: usize = 64;
+
+const HASH_BITS: u32 = 15;
+
+/// Marker for "no position" in the hash chains.
+const NONE: u32 = u32::MAX;
+
+/// Base length for length codes 257 through 285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+
+/// Number of extra bits for length codes 257 through 285.
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distance for distance codes 0 through 29.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+/// Number of extra bits for distance codes 0 through 29.
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// Writes values least significant bit first, as DEFLATE packs them.
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    n_bits: u32,
+}
+
+impl BitWriter {
+    fn write_bits(&mut self, value: u32, n_bits: u32) {
+        self.acc |= (value as u64) << self.n_bits;
+        self.n_bits += n_bits;
+        while self.n_bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.n_bits -= 8;
+        }
+    }
+
+    /// Write a Huffman code, those are packed most significant bit first.
+    fn write_code(&mut self, code: u32, n_bits: u32) {
+        let reversed = code.reverse_bits() >> (32 - n_bits);
+        self.write_bits(reversed, n_bits);
+    }
+
+    fn flush(&mut self) {
+        if self.n_bits > 0 {
+            self.out.push(self.acc as u8);
+            self.acc = 0;
+            self.n_bits = 0;
+        }
+    }
+
+    /// Write a literal/length symbol in the fixed Huffman code.
+    fn write_symbol(&mut self, symbol: u16) {
+        let s = symbol as u32;
+        match symbol {
+            0..=143 => self.write_code(0x30 + s, 8),
+            144..=255 => self.write_code(0x190 + s - 144, 9),
+            256..=279 => self.write_code(s - 256, 7),
+            _ => self.write_code(0xc0 + s - 280, 8),
+        }
+    }
+
+    fn write_match(&mut self, len: usize, dist: usize) {
+        // The tables are short, a linear scan is fine.
+        let li = LENGTH_BASE.iter().rposition(|&b| b as usize <= len).unwrap();
+        self.write_symbol(257 + li as u16);
+        self.write_bits((len - LENGTH_BASE[li] as usize) as u32, LENGTH_EXTRA[li] as u32);
+
+        let di = DIST_BASE.iter().rposition(|&b| b as usize <= dist).unwrap();
+        self.write_code(di as u32, 5);
+        self.write_bits((dist - DIST_BASE[di] as usize) as u32, DIST_EXTRA[di] as u32);
+    }
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let x = (data[i] as u32) | (data[i + 1] as u32) << 8 | (data[i + 2] as u32) << 16;
+    (x.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Return the raw DEFLATE stream for `data`.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter {
+        out: Vec::with_capacity(data.len() / 4 + 16),
+        acc: 0,
+        n_bits: 0,
+    };
+
+    // A single block that is the final block, with the fixed Huffman code.
+    w.write_bits(1, 1);
+    w.write_bits(1, 2);
+
+    // For every hash, the most recent position where it occurred, and for
+    // every position in the window, the previous one with the same hash.
+    let mut head = vec![NONE; 1 << HASH_BITS];
+    let mut prev = vec![NONE; WINDOW_SIZE];
+
+    let insert = |head: &mut Vec<u32>, prev: &mut Vec<u32>, i: usize| {
+        if i + MIN_MATCH_LEN <= data.len() {
+            let h = hash(data, i);
+            prev[i % WINDOW_SIZE] = head[h];
+            head[h] = i as u32;
+        }
+    };
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+
+        if i + MIN_MATCH_LEN <= data.len() {
+            let max_len = MAX_MATCH_LEN.min(data.len() - i);
+            let mut candidate = head[hash(data, i)];
+            let mut chain_len = 0;
+            while candidate != NONE && chain_len < MAX_CHAIN_LEN {
+                let j = candidate as usize;
+                if i - j > WINDOW_SIZE - 1 {
+                    break;
+                }
+                let len = data[j..j + max_len]
+                    .iter()
+                    .zip(&data[i..i + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_dist = i - j;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                let next = prev[j % WINDOW_SIZE];
+                // Entries in `prev` get overwritten when the window moves on,
+                // positions must strictly decrease along a valid chain.
+                if next == NONE || next as usize >= j {
+                    break;
+                }
+                candidate = next;
+                chain_len += 1;
+            }
+        }
+
+        if best_len >= MIN_MATCH_LEN {
+            w.write_match(best_len, best_dist);
+            for k in i..i + best_len {
+                insert(&mut head, &mut prev, k);
+            }
+            i += best_len;
+        } else {
+            w.write_symbol(data[i] as u16);
+            insert(&mut head, &mut prev, i);
+            i += 1;
+        }
+    }
+
+    // End of block.
+    w.write_symbol(256);
+    w.flush();
+    w.out
+}
+
+/// Return `data` compressed in the gzip format.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // Magic, compression method 8 (DEFLATE), no flags, no modification time,
+    // no extra flags, and 255 for unknown operating system.
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&crc32_update(0, data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress, deflate};
+
+    /// Decode a DEFLATE stream that uses only the fixed Huffman code.
+    ///
+    /// This is just enough of an inflater to check that the encoder round
+    /// trips, it is not meant for untrusted input.
+    fn inflate_fixed(input: &[u8]) -> Vec<u8> {
+        let mut pos = 0;
+        let mut read_bits = |n: u32| -> u32 {
+            let mut v = 0;
+            for k in 0..n {
+                let bit = (input[pos / 8] >> (pos % 8)) & 1;
+                v |= (bit as u32) << k;
+                pos += 1;
+            }
+            v
+        };
+        assert_eq!(read_bits(1), 1, "Expected a final block.");
+        assert_eq!(read_bits(2), 1, "Expected a fixed Huffman block.");
+
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            // Read the code most significant bit first, the lengths of the
+            // fixed code tell us when we have a complete code.
+            let mut code = 0;
+            let mut n = 0;
+            let symbol = loop {
+                code = (code << 1) | read_bits(1);
+                n += 1;
+                match (n, code) {
+                    (7, 0..=0x17) => break code + 256,
+                    (8, 0x30..=0xbf) => break code - 0x30,
+                    (8, 0xc0..=0xc7) => break code - 0xc0 + 280,
+                    (9, 0x190..=0x1ff) => break code - 0x190 + 144,
+                    _ => assert!(n < 9, "Invalid code."),
+                }
+            };
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => return out,
+                _ => {
+                    let li = symbol as usize - 257;
+                    let len = super::LENGTH_BASE[li] as usize
+                        + read_bits(super::LENGTH_EXTRA[li] as u32) as usize;
+                    let di = read_bits(5).reverse_bits() >> 27;
+                    let dist = super::DIST_BASE[di as usize] as usize
+                        + read_bits(super::DIST_EXTRA[di as usize] as u32) as usize;
+                    for _ in 0..len {
+                        out.push(out[out.len() - dist]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deflate_round_trips() {
+        let json = br#"[{"id":"a1","title":"One"},{"id":"a2","title":"Two"}]"#.repeat(500);
+        let inputs: [&[u8]; 5] = [
+            b"",
+            b"a",
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            "Ünïcödé, and bytes above 143 \u{ff}".as_bytes(),
+            &json[..],
+        ];
+        for input in inputs.iter() {
+            assert_eq!(&inflate_fixed(&deflate(input))[..], *input);
+        }
+    }
+
+    #[test]
+    fn deflate_compresses_repetitive_json() {
+        let json = br#"{"id":"1a2b3c4d","title":"Some Album","artist":"Some Artist","release_date":"2023-01-01"},"#.repeat(1000);
+        let compressed = deflate(&json);
+        assert!(compressed.len() * 20 < json.len());
+    }
+
+    #[test]
+    fn compress_writes_gzip_header_and_trailer() {
+        let gz = compress(b"123456789");
+        assert_eq!(&gz[..4], &[0x1f, 0x8b, 8, 0]);
+        // The trailer has the CRC-32 and the length of the uncompressed data.
+        assert_eq!(&gz[gz.len() - 8..gz.len() - 4], &0xcbf4_3926_u32.to_le_bytes());
+        assert_eq!(&gz[gz.len() - 4..], &9_u32.to_le_bytes());
+    }
+}
@@ -7,117 +7,831 @@
 
 //! Logging of historical playback events.
 
+use std::collections::{HashMap, VecDeque};
 use std::path::Path;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use chrono::{SecondsFormat, Utc};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
 
 use crate::database_utils;
 use crate::database as db;
-use crate::database::{Connection, Listen, Result};
+use crate::database::{Connection, Listen, Result, Transaction};
+use crate::events::{Event, EventBus};
+use crate::listens::ListenThreshold;
 use crate::mvar::Var;
-use crate::player::QueueId;
-use crate::{MetaIndex, MemoryMetaIndex, TrackId};
-use crate::user_data::{Rating, UserData};
+use crate::player::{QueueId, SavedQueueEntry, Source};
+use crate::prim::Instant;
+use crate::radio;
+use crate::scrobble::ScrobbleEvent;
+use crate::sessions;
+use crate::status_hook::Status;
+use crate::{AlbumId, ArtistId, MetaIndex, MemoryMetaIndex, TrackId};
+use crate::user_data::{Rating, UserDataSet};
+use crate::webhook::{EventType, WebhookEvent};
+
+/// When the database stays busy, try the buffered events again after this long.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reload the play counts from the database this often, to pick up listens
+/// that were added by other means than playback, such as an import.
+const PLAY_STATS_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Don't remember a resume position before this many milliseconds into a track,
+/// starting over is not much of a loss then.
+const RESUME_MIN_POSITION_MS: u64 = 30_000;
+
+/// When playback stops less than this many milliseconds before the end of a
+/// track, we consider it finished, and forget its resume position.
+const RESUME_END_MARGIN_MS: u64 = 30_000;
+
+/// The maximum number of events to hold in memory while the database is busy.
+/// When the buffer is full, we drop the oldest event.
+const MAX_BUFFERED_EVENTS: usize = 1000;
 
 /// Changes in the playback state or library to be recorded.
 pub enum PlaybackEvent {
-    Started(QueueId, TrackId),
-    Completed(QueueId, TrackId),
+    /// Playback of the queue entry started, with the name of the client that
+    /// enqueued it, if it provided one, and the user who enqueued it, `None`
+    /// for the default user.
+    Started(QueueId, TrackId, Option<String>, Option<String>),
+
+    /// The track played to the end, which is at the given position.
+    Completed(QueueId, TrackId, u32),
+
+    /// The user skipped the track before it completed, at the given position.
+    Skipped(QueueId, TrackId, u32),
+
+    /// The player could not play the track to the end, with the reason.
+    Failed(QueueId, TrackId, String),
+
     QueueEnded,
 
+    /// The user paused playback. The current entry stays in the queue, and
+    /// its listen is still pending.
+    Paused,
+
+    /// Playback continues after a pause.
+    Resumed,
+
+    /// Playback of the radio station started, with the title it announced.
+    RadioStarted(QueueId, Arc<radio::Station>, Option<String>),
+
+    /// The radio station announced a new title.
+    RadioTitleChanged(QueueId, Option<String>),
+
+    /// Playback of the radio station stopped, because it was skipped, or
+    /// because the stream ended.
+    RadioEnded(QueueId),
+
+    /// The player could not play the radio station, with the reason.
+    RadioFailed(QueueId, String),
+
     /// The user modified the rating for the given track.
     Rated {
         track_id: TrackId,
         rating: Rating,
+        user: Option<String>,
+    },
+
+    /// The user modified the rating for the given album.
+    AlbumRated {
+        album_id: AlbumId,
+        rating: Rating,
+        user: Option<String>,
+    },
+
+    /// The user modified the rating for the given album artist.
+    ArtistRated {
+        artist_id: ArtistId,
+        rating: Rating,
+        user: Option<String>,
+    },
+
+    /// The server is shutting down, save the queue, and confirm on `done`.
+    ShutDown {
+        queue: Vec<SavedQueueEntry>,
+        done: SyncSender<()>,
     },
 }
 
-/// Main for the thread that logs historical playback events.
-pub fn main(
-    db_path: &Path,
-    index_var: Var<MemoryMetaIndex>,
-    user_data: Arc<Mutex<UserData>>,
-    events: Receiver<PlaybackEvent>,
+/// Whether the history thread is keeping up with recording events.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct HistoryStatus {
+    /// Events that could not be recorded yet, waiting for a retry.
+    pub buffered_events: usize,
+
+    /// Events that were lost since startup, because the buffer overflowed, or
+    /// because writing them failed with a non-transient error.
+    pub dropped_events: u64,
+}
+
+impl HistoryStatus {
+    /// Return whether all events so far have been recorded.
+    pub fn is_healthy(&self) -> bool {
+        self.buffered_events == 0 && self.dropped_events == 0
+    }
+}
+
+/// Remember where playback of a long track stopped, so it can resume there.
+///
+/// When playback stopped near the end, the track is finished, and we forget
+/// the position instead. Tracks shorter than `min_duration_seconds` have no
+/// resume position, 0 disables resume positions entirely.
+fn update_resume_position(
+    tx: &mut Transaction,
+    index: &dyn MetaIndex,
+    min_duration_seconds: u64,
+    now_str: &str,
+    track_id: TrackId,
+    position_ms: u64,
 ) -> Result<()> {
-    let connection = database_utils::connect_read_write(db_path)?;
-    let mut db = Connection::new(&connection);
+    if min_duration_seconds == 0 || position_ms < RESUME_MIN_POSITION_MS {
+        return Ok(());
+    }
+    let duration_seconds = match index.get_track(track_id) {
+        Some(track) => track.duration_seconds as u64,
+        None => return Ok(()),
+    };
+    if duration_seconds < min_duration_seconds {
+        return Ok(());
+    }
+    if position_ms + RESUME_END_MARGIN_MS >= duration_seconds * 1000 {
+        db::delete_resume_position(tx, track_id.0 as i64)
+    } else {
+        db::insert_or_replace_resume_position(tx, track_id.0 as i64, position_ms as i64, now_str)
+    }
+}
+
+/// State of the history thread, shared between events.
+struct Recorder<'a> {
+    connection: &'a sqlite::Connection,
+    db: Connection<'a>,
+    index_var: Var<MemoryMetaIndex>,
+    user_data: Arc<Mutex<UserDataSet>>,
+    scrobble_events: Option<SyncSender<ScrobbleEvent>>,
+    webhook_events: Option<SyncSender<WebhookEvent>>,
+    status_events: Option<SyncSender<Status>>,
+    event_bus: Arc<EventBus>,
+
+    /// Remember resume positions of tracks at least this long, 0 to disable.
+    resume_min_duration_seconds: u64,
+
+    /// How much of a track needs to play for its listen to count.
+    listen_threshold: ListenThreshold,
+
+    /// Listens that started but did not yet complete, keyed by queue id.
+    ///
+    /// The value is the id of the listen in the database. Keying by queue id
+    /// rather than tracking only the last listen, means that interleaved events
+    /// (e.g. when a track is skipped right after it started) still update the
+    /// right row.
+    pending_listens: HashMap<QueueId, i64>,
+
+    /// Radio listens that did not yet complete, keyed by queue id.
+    ///
+    /// Next to the listen id, we keep the station, to record the listen for
+    /// the next title.
+    pending_radio_listens: HashMap<QueueId, (i64, Arc<radio::Station>)>,
+}
+
+impl<'a> Recorder<'a> {
+    fn scrobble(&self, event: ScrobbleEvent) {
+        if let Some(sender) = self.scrobble_events.as_ref() {
+            if sender.send(event).is_err() {
+                warn!("Scrobbler thread is gone, not forwarding event.");
+            }
+        }
+    }
+
+    fn notify_webhooks(
+        &self,
+        now_str: &str,
+        queue_id: QueueId,
+        track_id: TrackId,
+        event_type: EventType,
+    ) {
+        let sender = match self.webhook_events.as_ref() {
+            Some(s) => s,
+            None => return,
+        };
+        let event = WebhookEvent {
+            event_type: event_type,
+            at: now_str.to_string(),
+            queue_id: queue_id,
+            track_id: track_id,
+        };
+        // Unlike the scrobbler, we don't wait for the webhook thread when it
+        // falls behind, a slow webhook should not hold up recording listens.
+        match sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(..)) => {
+                warn!("Webhook thread is behind, dropping event.");
+            }
+            Err(TrySendError::Disconnected(..)) => {
+                warn!("Webhook thread is gone, not forwarding event.");
+            }
+        }
+    }
+
+    fn notify_status(&self, status: Status) {
+        let sender = match self.status_events.as_ref() {
+            Some(s) => s,
+            None => return,
+        };
+        // Like for webhooks, a slow status change program should not hold
+        // up recording listens.
+        match sender.try_send(status) {
+            Ok(()) => {}
+            Err(TrySendError::Full(..)) => {
+                warn!("Status thread is behind, dropping status change.");
+            }
+            Err(TrySendError::Disconnected(..)) => {
+                warn!("Status thread is gone, not forwarding status change.");
+            }
+        }
+    }
+
+    fn handle_started(
+        &mut self,
+        now: DateTime<Utc>,
+        now_str: &str,
+        queue_id: QueueId,
+        track_id: TrackId,
+        client: Option<&str>,
+        user: Option<&str>,
+    ) -> Result<()> {
+        if self.pending_listens.contains_key(&queue_id) {
+            warn!(
+                "Queue entry {}, track {}, started twice, ignoring.",
+                queue_id, track_id,
+            );
+            return Ok(());
+        }
+
+        let index = self.index_var.get();
+        let track = match index.get_track(track_id) {
+            Some(t) => t,
+            None => {
+                // The index may have been replaced by a rescan after the track
+                // was enqueued.
+                warn!(
+                    "Queue entry {}, track {}, started but the track is not in the index.",
+                    queue_id, track_id,
+                );
+                return Ok(());
+            }
+        };
+        let album = index.get_album(track_id.album_id()).unwrap();
+        let album_artists = index.get_album_artists(album.artist_ids);
+        let listen = Listen {
+            started_at: now_str,
+            file_id: track.file_id.0,
+            queue_id: queue_id.0 as i64,
+            track_id: track_id.0 as i64,
+            album_id: track_id.album_id().0 as i64,
+            // We record only the first album artist, to keep the
+            // structure of the table simple.
+            album_artist_id: album_artists[0].0 as i64,
+            track_title: index.get_string(track.title),
+            album_title: index.get_string(album.title),
+            track_artist: index.get_string(track.artist),
+            album_artist: index.get_string(album.artist),
+            duration_seconds: track.duration_seconds as i64,
+            track_number: track_id.track_number() as i64,
+            disc_number: track_id.disc_number() as i64,
+            utc_offset_seconds: Local::now().offset().local_minus_utc() as i64,
+        };
+        let mut tx = self.db.begin()?;
+        let listen_id = db::insert_listen_started(&mut tx, listen)?;
+        if let Some(name) = client {
+            db::insert_listen_client(&mut tx, listen_id, name)?;
+        }
+        if let Some(name) = user {
+            db::insert_listen_user(&mut tx, listen_id, name)?;
+        }
+        db::insert_first_listen(&mut tx, listen_id)?;
+        sessions::assign_sessions(&mut tx)?;
+        tx.commit()?;
+        self.pending_listens.insert(queue_id, listen_id);
+
+        let started_at = Instant { posix_seconds_utc: now.timestamp() };
+        self.user_data
+            .lock()
+            .unwrap()
+            .get_mut(user)
+            .add_listen(track_id, album_artists[0], started_at);
+
+        Ok(())
+    }
+
+    fn handle_completed(
+        &mut self,
+        now_str: &str,
+        queue_id: QueueId,
+        track_id: TrackId,
+        position_seconds: u32,
+    ) -> Result<()> {
+        let listen_id = match self.pending_listens.get(&queue_id) {
+            Some(id) => *id,
+            None => {
+                warn!(
+                    "Queue entry {}, track {}, completed before starting, ignoring.",
+                    queue_id, track_id,
+                );
+                return Ok(());
+            }
+        };
+        let mut tx = self.db.begin()?;
+        db::update_listen_completed(
+            &mut tx,
+            listen_id,
+            queue_id.0 as i64,
+            track_id.0 as i64,
+            now_str,
+            position_seconds as i64,
+        )?;
+        // The track played to the end, so there is nothing to resume.
+        db::delete_resume_position(&mut tx, track_id.0 as i64)?;
+        tx.commit()?;
+        self.pending_listens.remove(&queue_id);
+        Ok(())
+    }
+
+    fn handle_skipped(
+        &mut self,
+        now_str: &str,
+        queue_id: QueueId,
+        track_id: TrackId,
+        position_seconds: u32,
+    ) -> Result<()> {
+        let listen_id = self.pending_listens.get(&queue_id).copied();
+        let mut tx = self.db.begin()?;
+        if let Some(id) = listen_id {
+            db::update_listen_played(&mut tx, id, position_seconds as i64)?;
+        }
+        db::insert_skip(
+            &mut tx,
+            now_str,
+            listen_id,
+            queue_id.0 as i64,
+            track_id.0 as i64,
+            position_seconds as i64,
+        )?;
+        update_resume_position(
+            &mut tx,
+            &*self.index_var.get(),
+            self.resume_min_duration_seconds,
+            now_str,
+            track_id,
+            position_seconds as u64 * 1000,
+        )?;
+        tx.commit()?;
+
+        // The listen of a skipped track remains without completion time.
+        self.pending_listens.remove(&queue_id);
+        if listen_id.is_none() {
+            warn!(
+                "Queue entry {}, track {}, skipped before starting.",
+                queue_id, track_id,
+            );
+        }
+        Ok(())
+    }
+
+    /// Return whether a listen of the track that played this long counts.
+    fn is_counted(&self, track_id: TrackId, played_seconds: u32) -> bool {
+        match self.index_var.get().get_track(track_id) {
+            Some(track) => self.listen_threshold.is_counted(track.duration_seconds as u32, played_seconds),
+            None => false,
+        }
+    }
+
+    fn handle_failed(&mut self, queue_id: QueueId, track_id: TrackId, message: &str) {
+        error!(
+            "Queue entry {}, track {}, failed to play, skipping: {}",
+            queue_id, track_id, message,
+        );
+        // Like for a skip, the listen remains without completion time, but
+        // the user did not choose to skip, so we don't record a skip.
+        self.pending_listens.remove(&queue_id);
+    }
+
+    fn handle_radio_started(
+        &mut self,
+        now_str: &str,
+        queue_id: QueueId,
+        station: &Arc<radio::Station>,
+        title: Option<&str>,
+    ) -> Result<()> {
+        let mut tx = self.db.begin()?;
+        // A title change ends the listen of the previous title.
+        if let Some((listen_id, _)) = self.pending_radio_listens.get(&queue_id) {
+            db::update_radio_listen_completed(&mut tx, *listen_id, now_str)?;
+        }
+        let listen_id = db::insert_radio_listen(
+            &mut tx,
+            now_str,
+            queue_id.0 as i64,
+            station.id,
+            &station.name,
+            title,
+        )?;
+        tx.commit()?;
+        self.pending_radio_listens.insert(queue_id, (listen_id, station.clone()));
+        Ok(())
+    }
+
+    fn handle_radio_ended(&mut self, now_str: &str, queue_id: QueueId) -> Result<()> {
+        let listen_id = match self.pending_radio_listens.get(&queue_id) {
+            Some((id, _)) => *id,
+            None => {
+                warn!("Queue entry {}, radio, ended before starting, ignoring.", queue_id);
+                return Ok(());
+            }
+        };
+        let mut tx = self.db.begin()?;
+        db::update_radio_listen_completed(&mut tx, listen_id, now_str)?;
+        tx.commit()?;
+        self.pending_radio_listens.remove(&queue_id);
+        Ok(())
+    }
+
+    fn handle_radio_failed(&mut self, now_str: &str, queue_id: QueueId, message: &str) -> Result<()> {
+        error!("Queue entry {}, radio, failed to play, skipping: {}", queue_id, message);
+        // A stream that failed to open never started, so it has no listen to end.
+        match self.pending_radio_listens.contains_key(&queue_id) {
+            true => self.handle_radio_ended(now_str, queue_id),
+            false => Ok(()),
+        }
+    }
+
+    fn handle_queue_ended(&mut self, now_str: &str) -> Result<()> {
+        // When the queue ends, flush the WAL. This is not really
+        // needed, but I back up my database with rsync once in a
+        // while, and I like to have everything in one file instead
+        // of having to sync the WAL as well. We checkpoint after
+        // the queue ends, before the post-playback program runs.
+        self.connection.execute("PRAGMA wal_checkpoint(PASSIVE);")?;
+
+        // When the queue ends, nothing is playing, so any listens that are
+        // still pending will never complete.
+        if !self.pending_listens.is_empty() {
+            warn!(
+                "Queue ended with {} listens that did not complete.",
+                self.pending_listens.len(),
+            );
+            self.pending_listens.clear();
+        }
+
+        // A radio listen can still be pending when playback stopped because
+        // we are shutting down. The station stopped for us, so it ends now.
+        if !self.pending_radio_listens.is_empty() {
+            let mut tx = self.db.begin()?;
+            for (listen_id, _) in self.pending_radio_listens.values() {
+                db::update_radio_listen_completed(&mut tx, *listen_id, now_str)?;
+            }
+            tx.commit()?;
+            self.pending_radio_listens.clear();
+        }
+
+        Ok(())
+    }
 
-    let mut last_listen_id = None;
+    fn handle_shut_down(&mut self, now_str: &str, queue: &[SavedQueueEntry]) -> Result<()> {
+        let mut tx = self.db.begin()?;
+        db::delete_saved_queue(&mut tx)?;
+        // Only the current track has a position. The saved queue resumes it
+        // when the server starts again, but if it gets dequeued, the track can
+        // still resume when it is enqueued later.
+        if let Some(SavedQueueEntry { source: Source::Track(track_id), position_ms, .. }) = queue.first() {
+            update_resume_position(
+                &mut tx,
+                &*self.index_var.get(),
+                self.resume_min_duration_seconds,
+                now_str,
+                *track_id,
+                *position_ms,
+            )?;
+        }
+        for (i, entry) in queue.iter().enumerate() {
+            let (track_id, station_id) = match &entry.source {
+                Source::Track(track_id) => (Some(track_id.0 as i64), None),
+                Source::Radio(station) => (None, Some(station.id)),
+            };
+            db::insert_saved_queue_entry(
+                &mut tx,
+                i as i64,
+                track_id,
+                station_id,
+                entry.position_ms as i64,
+                entry.client.as_deref(),
+                entry.user.as_deref(),
+            )?;
+        }
+        tx.commit()?;
+
+        // Like when the queue ends, leave everything in the main database file.
+        self.connection.execute("PRAGMA wal_checkpoint(PASSIVE);")?;
+
+        Ok(())
+    }
+
+    fn reload_play_stats(&mut self) -> Result<()> {
+        let mut tx = self.db.begin()?;
+        let result = self.user_data.lock().unwrap().reload_play_stats(&mut tx);
+        match result {
+            Ok(()) => tx.commit(),
+            Err(err) => {
+                tx.rollback()?;
+                Err(err)
+            }
+        }
+    }
 
-    for event in events {
-        let now = Utc::now();
+    /// Record the event that happened at `now`.
+    ///
+    /// When this fails, nothing about the event has been recorded, so it is
+    /// safe to try again.
+    fn handle_event(&mut self, now: DateTime<Utc>, event: &PlaybackEvent) -> Result<()> {
         let use_zulu_suffix = true;
         let now_str = now.to_rfc3339_opts(SecondsFormat::Millis, use_zulu_suffix);
 
-        match event {
-            PlaybackEvent::Started(queue_id, track_id) => {
-                let index = index_var.get();
-                let track = index.get_track(track_id).unwrap();
-                let album = index.get_album(track_id.album_id()).unwrap();
-                let album_artists = index.get_album_artists(album.artist_ids);
-                let listen = Listen {
-                    started_at: &now_str[..],
-                    file_id: track.file_id.0,
-                    queue_id: queue_id.0 as i64,
-                    track_id: track_id.0 as i64,
-                    album_id: track_id.album_id().0 as i64,
-                    // We record only the first album artist, to keep the
-                    // structure of the table simple.
-                    album_artist_id: album_artists[0].0 as i64,
-                    track_title: index.get_string(track.title),
-                    album_title: index.get_string(album.title),
-                    track_artist: index.get_string(track.artist),
-                    album_artist: index.get_string(album.artist),
-                    duration_seconds: track.duration_seconds as i64,
-                    track_number: track_id.track_number() as i64,
-                    disc_number: track_id.disc_number() as i64,
-                };
-                let mut tx = db.begin()?;
-                let result = db::insert_listen_started(&mut tx, listen)?;
-                tx.commit()?;
-                last_listen_id = Some(result);
-            }
-            PlaybackEvent::Completed(queue_id, track_id) => {
-                if let Some(listen_id) = last_listen_id {
-                    let mut tx = db.begin()?;
-                    db::update_listen_completed(
-                        &mut tx,
-                        listen_id,
-                        queue_id.0 as i64,
-                        track_id.0 as i64,
-                        &now_str[..],
-                    )?;
-                    tx.commit()?;
-                } else {
-                    panic!(
-                        "Completed queue entry {}, track {}, before starting.",
-                        queue_id, track_id,
-                    );
+        let result = self.handle_event_inner(now, &now_str, event);
+
+        if result.is_err() {
+            // If the error happened halfway through a transaction, roll it
+            // back, otherwise the next `BEGIN` fails too. If there is no
+            // transaction, rolling back fails, which is fine.
+            let _ = self.connection.execute("ROLLBACK;");
+        }
+
+        result
+    }
+
+    /// Record the event, retrying with backoff while the database is busy.
+    fn handle_event_with_retries(&mut self, now: DateTime<Utc>, event: &PlaybackEvent) -> Result<()> {
+        database_utils::retry_busy(|| self.handle_event(now, event))
+    }
+
+    fn handle_event_inner(
+        &mut self,
+        now: DateTime<Utc>,
+        now_str: &str,
+        event: &PlaybackEvent,
+    ) -> Result<()> {
+        // We notify the status thread before touching the database, so the
+        // status is current even when the database is busy. If we retry the
+        // event later, the status thread ignores the repeated status.
+        match *event {
+            PlaybackEvent::Started(..) | PlaybackEvent::RadioStarted(..) => self.notify_status(Status::Playing),
+            PlaybackEvent::Failed(..) | PlaybackEvent::RadioFailed(..) => self.notify_status(Status::Error),
+            PlaybackEvent::QueueEnded => self.notify_status(Status::Idle),
+            PlaybackEvent::Paused => self.notify_status(Status::Paused),
+            PlaybackEvent::Resumed => self.notify_status(Status::Playing),
+            _ => {}
+        }
+
+        match *event {
+            PlaybackEvent::Started(queue_id, track_id, ref client, ref user) => {
+                self.handle_started(
+                    now,
+                    now_str,
+                    queue_id,
+                    track_id,
+                    client.as_deref(),
+                    user.as_deref(),
+                )?;
+                // The Last.fm account belongs to the default user.
+                if user.is_none() {
+                    self.scrobble(ScrobbleEvent::NowPlaying(track_id));
+                }
+                let event_type = EventType::Started { client: client.clone() };
+                self.notify_webhooks(now_str, queue_id, track_id, event_type);
+                self.event_bus.publish(Event::TrackStarted { queue_id, track_id });
+            }
+            PlaybackEvent::Completed(queue_id, track_id, position_seconds) => {
+                self.handle_completed(now_str, queue_id, track_id, position_seconds)?;
+                self.scrobble(ScrobbleEvent::ListenCompleted);
+                self.notify_webhooks(now_str, queue_id, track_id, EventType::Completed);
+                self.event_bus.publish(Event::TrackCompleted { queue_id, track_id });
+            }
+            PlaybackEvent::Skipped(queue_id, track_id, position_seconds) => {
+                self.handle_skipped(now_str, queue_id, track_id, position_seconds)?;
+                // A track that played long enough before the skip still gets
+                // scrobbled.
+                if self.is_counted(track_id, position_seconds) {
+                    self.scrobble(ScrobbleEvent::ListenCompleted);
                 }
+                let event_type = EventType::Skipped { position_seconds: position_seconds };
+                self.notify_webhooks(now_str, queue_id, track_id, event_type);
+                self.event_bus.publish(Event::TrackSkipped { queue_id, track_id, position_seconds });
+            }
+            PlaybackEvent::Failed(queue_id, track_id, ref message) => {
+                self.handle_failed(queue_id, track_id, message);
+                let message = message.clone();
+                self.event_bus.publish(Event::TrackFailed { queue_id, track_id, message });
             }
             PlaybackEvent::QueueEnded => {
-                // When the queue ends, flush the WAL. This is not really
-                // needed, but I back up my database with rsync once in a
-                // while, and I like to have everything in one file instead
-                // of having to sync the WAL as well. We checkpoint after
-                // the queue ends, before the post-playback program runs.
-                connection.execute("PRAGMA wal_checkpoint(PASSIVE);")?;
-            }
-            PlaybackEvent::Rated { track_id, rating } => {
-                let mut tx = db.begin()?;
+                self.handle_queue_ended(now_str)?;
+            }
+            // There is nothing to record, only the status changes.
+            PlaybackEvent::Paused | PlaybackEvent::Resumed => {}
+            PlaybackEvent::RadioStarted(queue_id, ref station, ref title) => {
+                self.handle_radio_started(now_str, queue_id, station, title.as_deref())?;
+                let title = title.clone();
+                self.event_bus.publish(Event::RadioTitleChanged { queue_id, title });
+            }
+            PlaybackEvent::RadioTitleChanged(queue_id, ref title) => {
+                let station = match self.pending_radio_listens.get(&queue_id) {
+                    Some((_, station)) => station.clone(),
+                    None => {
+                        warn!("Queue entry {}, radio, changed title before starting.", queue_id);
+                        return Ok(());
+                    }
+                };
+                self.handle_radio_started(now_str, queue_id, &station, title.as_deref())?;
+                let title = title.clone();
+                self.event_bus.publish(Event::RadioTitleChanged { queue_id, title });
+            }
+            PlaybackEvent::RadioEnded(queue_id) => {
+                self.handle_radio_ended(now_str, queue_id)?;
+            }
+            PlaybackEvent::RadioFailed(queue_id, ref message) => {
+                self.handle_radio_failed(now_str, queue_id, message)?;
+                let message = message.clone();
+                self.event_bus.publish(Event::RadioFailed { queue_id, message });
+            }
+            PlaybackEvent::ShutDown { ref queue, ref done } => {
+                self.handle_shut_down(now_str, queue)?;
+                // The server may have given up on waiting already.
+                let _ = done.send(());
+            }
+            PlaybackEvent::Rated { track_id, rating, ref user } => {
+                let mut tx = self.db.begin()?;
                 db::insert_or_replace_rating(
                     &mut tx,
                     track_id.0 as i64,
-                    &now_str,
+                    now_str,
+                    rating as i64,
+                    user.as_deref(),
+                )?;
+                tx.commit()?;
+                let was_loved = {
+                    let mut user_data = self.user_data.lock().unwrap();
+                    let user_data = user_data.get_mut(user.as_deref());
+                    let was_loved = user_data.get_track_rating(track_id) == Rating::Love;
+                    user_data.set_track_rating(track_id, rating);
+                    was_loved
+                };
+                let is_loved = rating == Rating::Love;
+                if was_loved != is_loved && user.is_none() {
+                    self.scrobble(ScrobbleEvent::Loved(track_id, is_loved));
+                }
+            }
+            PlaybackEvent::AlbumRated { album_id, rating, ref user } => {
+                let mut tx = self.db.begin()?;
+                db::insert_or_replace_album_rating(
+                    &mut tx,
+                    album_id.0 as i64,
+                    now_str,
+                    rating as i64,
+                    user.as_deref(),
+                )?;
+                tx.commit()?;
+                self.user_data
+                    .lock()
+                    .unwrap()
+                    .get_mut(user.as_deref())
+                    .set_album_rating(album_id, rating);
+            }
+            PlaybackEvent::ArtistRated { artist_id, rating, ref user } => {
+                let mut tx = self.db.begin()?;
+                db::insert_or_replace_artist_rating(
+                    &mut tx,
+                    artist_id.0 as i64,
+                    now_str,
                     rating as i64,
+                    user.as_deref(),
                 )?;
                 tx.commit()?;
-                user_data.lock().unwrap().set_track_rating(track_id, rating);
+                self.user_data
+                    .lock()
+                    .unwrap()
+                    .get_mut(user.as_deref())
+                    .set_artist_rating(artist_id, rating);
             }
         }
+
+        Ok(())
+    }
+}
+
+/// Main for the thread that logs historical playback events.
+///
+/// When scrobbling is enabled, events are forwarded to the scrobbler after
+/// they have been recorded in the database. The same holds for webhooks, and
+/// for events pushed to clients through the event bus.
+///
+/// Failing to record one event does not stop the thread. When the database is
+/// busy, we retry with backoff, and if it stays busy, we buffer events in
+/// memory and try again later. Other errors are logged, and the event is
+/// dropped. `status` reflects whether events are being buffered or dropped.
+/// Events that arrive out of order, twice, or not at all, are logged as
+/// warnings and otherwise ignored.
+pub fn main(
+    db_path: &Path,
+    index_var: Var<MemoryMetaIndex>,
+    user_data: Arc<Mutex<UserDataSet>>,
+    events: Receiver<PlaybackEvent>,
+    scrobble_events: Option<SyncSender<ScrobbleEvent>>,
+    webhook_events: Option<SyncSender<WebhookEvent>>,
+    status_events: Option<SyncSender<Status>>,
+    event_bus: Arc<EventBus>,
+    status: Arc<Mutex<HistoryStatus>>,
+    resume_min_duration_seconds: u64,
+    listen_threshold: ListenThreshold,
+) -> Result<()> {
+    let connection = database_utils::connect_read_write(db_path)?;
+    let mut recorder = Recorder {
+        connection: &connection,
+        db: Connection::new(&connection),
+        index_var: index_var,
+        user_data: user_data,
+        scrobble_events: scrobble_events,
+        webhook_events: webhook_events,
+        status_events: status_events,
+        event_bus: event_bus,
+        resume_min_duration_seconds: resume_min_duration_seconds,
+        listen_threshold: listen_threshold,
+        pending_listens: HashMap::new(),
+        pending_radio_listens: HashMap::new(),
+    };
+
+    // Listens from before sessions existed, or that were imported while we
+    // were not running, don't have a session yet.
+    match database_utils::with_write_transaction(&mut recorder.db, sessions::assign_sessions) {
+        Ok(0) => {}
+        Ok(n) => info!("Assigned {} listens to listening sessions.", n),
+        Err(err) => error!("Error while assigning listening sessions: {:?}", err),
     }
 
-    Ok(())
+    // Events that we failed to record because the database was busy, with
+    // the time at which they happened, oldest first.
+    let mut buffer: VecDeque<(DateTime<Utc>, PlaybackEvent)> = VecDeque::new();
+    let mut dropped_events = 0;
+
+    let mut next_refresh = std::time::Instant::now() + PLAY_STATS_REFRESH_INTERVAL;
+
+    loop {
+        let now = std::time::Instant::now();
+        if now >= next_refresh {
+            if let Err(err) = recorder.reload_play_stats() {
+                error!("Error while reloading play counts: {:?}", err);
+            }
+            next_refresh = now + PLAY_STATS_REFRESH_INTERVAL;
+        }
+
+        let mut timeout = next_refresh.saturating_duration_since(now);
+        if !buffer.is_empty() {
+            timeout = timeout.min(RETRY_INTERVAL);
+        }
+
+        let received = match events.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+
+        if let Some(event) = received {
+            if buffer.len() == MAX_BUFFERED_EVENTS {
+                error!("Too many events buffered, dropping the oldest one.");
+                buffer.pop_front();
+                dropped_events += 1;
+            }
+            buffer.push_back((Utc::now(), event));
+        }
+
+        // Record events in the order in which they happened. When the
+        // database is still busy, leave the rest for the next attempt.
+        while let Some((now, event)) = buffer.front() {
+            match recorder.handle_event_with_retries(*now, event) {
+                Ok(()) => {}
+                Err(err) if database_utils::is_busy(&err) => {
+                    warn!("Database is busy, will retry recording playback events: {:?}", err);
+                    break;
+                }
+                Err(err) => {
+                    error!("Error while recording playback event, dropping it: {:?}", err);
+                    dropped_events += 1;
+                }
+            }
+            buffer.pop_front();
+        }
+
+        let mut status = status.lock().unwrap();
+        status.buffered_events = buffer.len();
+        status.dropped_events = dropped_events;
+    }
 }
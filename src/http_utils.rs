@@ -0,0 +1,290 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Range requests, conditional requests, and content negotiation.
+
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An inclusive range of bytes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// The part of a resource that a request asks for.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RangeRequest {
+    /// No range, or one that we don't support: respond with everything.
+    Full,
+
+    /// Respond with 206 Partial Content, for this range.
+    Partial(ByteRange),
+
+    /// The range lies outside of the resource, respond with 416.
+    Unsatisfiable,
+}
+
+/// Interpret the value of the `Range` header, for a resource of length `len`.
+///
+/// We only support a single range. Browsers don't request multiple ranges for
+/// media, and per RFC 7233 we are free to ignore a `Range` header that we don't
+/// support, and respond with the full resource.
+pub fn parse_range(header: Option<&str>, len: u64) -> RangeRequest {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return RangeRequest::Full,
+    };
+    let (start_str, end_str) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return RangeRequest::Full,
+    };
+
+    let range = match (start_str.parse::<u64>(), end_str.parse::<u64>()) {
+        // A range like "bytes=100-199".
+        (Ok(start), Ok(end)) if start <= end => ByteRange {
+            start: start,
+            end: end.min(len.saturating_sub(1)),
+        },
+        // A range like "bytes=100-", until the end.
+        (Ok(start), Err(..)) if end_str.is_empty() => ByteRange {
+            start: start,
+            end: len.saturating_sub(1),
+        },
+        // A suffix range like "bytes=-500", the last 500 bytes.
+        (Err(..), Ok(suffix_len)) if start_str.is_empty() => {
+            if suffix_len == 0 {
+                return RangeRequest::Unsatisfiable;
+            }
+            ByteRange {
+                start: len.saturating_sub(suffix_len),
+                end: len.saturating_sub(1),
+            }
+        }
+        _ => return RangeRequest::Full,
+    };
+
+    if len == 0 || range.start >= len {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(range)
+    }
+}
+
+/// Return whether the value of an `If-None-Match` header matches the etag.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    // For If-None-Match, the comparison is weak, so we ignore a W/ prefix.
+    let strip_weak = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_string();
+    let etag = strip_weak(etag);
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || strip_weak(tag) == etag)
+}
+
+/// Return an etag for a file, based on its modification time and size.
+///
+/// This is what most web servers do. It changes when the file is replaced,
+/// even when the new file happens to have the same size.
+pub fn file_etag(metadata: &fs::Metadata) -> String {
+    let mtime_secs = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!(r#""{:x}-{:x}""#, mtime_secs, metadata.len())
+}
+
+/// Format a time for the `Last-Modified` header.
+pub fn format_http_date(time: SystemTime) -> String {
+    let time: chrono::DateTime<chrono::Utc> = time.into();
+    // The format from https://tools.ietf.org/html/rfc7231#section-7.1.1.1.
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Format a `Content-Disposition` value for downloading a file.
+///
+/// Header values must be ascii, so for names with other characters we add the
+/// UTF-8 name in the `filename*` parameter from RFC 6266, and give an ascii
+/// approximation in `filename` for clients that don't understand it.
+pub fn content_disposition_attachment(file_name: &str) -> String {
+    let is_plain = |ch: char| ch.is_ascii() && !ch.is_ascii_control() && ch != '"' && ch != '\\';
+    let fallback: String = file_name
+        .chars()
+        .map(|ch| if is_plain(ch) { ch } else { '_' })
+        .collect();
+    if fallback == file_name {
+        return format!(r#"attachment; filename="{}""#, file_name);
+    }
+
+    let mut encoded = String::with_capacity(file_name.len() * 3);
+    for byte in file_name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    format!(r#"attachment; filename="{}"; filename*=UTF-8''{}"#, fallback, encoded)
+}
+
+/// A `Content-Encoding` that we can respond with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl ContentEncoding {
+    /// The name of the encoding in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "identity",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Brotli => "br",
+        }
+    }
+
+    /// The suffix of a precompressed file in this encoding, such as `.gz`.
+    pub fn file_suffix(&self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "",
+            ContentEncoding::Gzip => ".gz",
+            ContentEncoding::Brotli => ".br",
+        }
+    }
+}
+
+/// Return whether the value of an `Accept-Encoding` header allows `coding`.
+///
+/// A coding is acceptable when it is listed, or when `*` is listed and the
+/// coding is not, unless the q-value is zero.
+pub fn accepts_encoding(accept_encoding: Option<&str>, coding: &str) -> bool {
+    let mut wildcard = false;
+    for item in accept_encoding.unwrap_or("").split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let q = parts
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding) {
+            return q > 0.0;
+        }
+        if name == "*" {
+            wildcard = q > 0.0;
+        }
+    }
+    wildcard
+}
+
+/// Pick the first of `available` that the client accepts.
+///
+/// The order of `available` is our preference, we ignore the client's
+/// q-values other than zero, like most servers do.
+pub fn negotiate_encoding(
+    accept_encoding: Option<&str>,
+    available: &[ContentEncoding],
+) -> ContentEncoding {
+    available
+        .iter()
+        .cloned()
+        .find(|e| accepts_encoding(accept_encoding, e.as_str()))
+        .unwrap_or(ContentEncoding::Identity)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{accepts_encoding, content_disposition_attachment, etag_matches, format_http_date, negotiate_encoding, parse_range};
+    use super::{ByteRange, ContentEncoding, RangeRequest};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn partial(start: u64, end: u64) -> RangeRequest {
+        RangeRequest::Partial(ByteRange { start, end })
+    }
+
+    #[test]
+    fn parse_range_handles_single_ranges() {
+        assert_eq!(parse_range(None, 1000), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes=0-99"), 1000), partial(0, 99));
+        assert_eq!(parse_range(Some("bytes=900-"), 1000), partial(900, 999));
+        assert_eq!(parse_range(Some("bytes=-100"), 1000), partial(900, 999));
+        assert_eq!(parse_range(Some("bytes=-5000"), 1000), partial(0, 999));
+        // An end beyond the resource gets clamped.
+        assert_eq!(parse_range(Some("bytes=500-5000"), 1000), partial(500, 999));
+    }
+
+    #[test]
+    fn parse_range_rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range(Some("bytes=1000-"), 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-0"), 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-"), 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn parse_range_ignores_unsupported_ranges() {
+        assert_eq!(parse_range(Some("bytes=0-99,200-299"), 1000), RangeRequest::Full);
+        assert_eq!(parse_range(Some("items=0-99"), 1000), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes=99-0"), 1000), RangeRequest::Full);
+        assert_eq!(parse_range(Some("bytes=x-y"), 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn etag_matches_handles_lists_and_weak_tags() {
+        assert!(etag_matches(r#""abc""#, r#""abc""#));
+        assert!(etag_matches(r#""xyz", W/"abc""#, r#""abc""#));
+        assert!(etag_matches("*", r#""abc""#));
+        assert!(!etag_matches(r#""abcd""#, r#""abc""#));
+    }
+
+    #[test]
+    fn format_http_date_uses_imf_fixdate() {
+        let t = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(format_http_date(t), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+
+    #[test]
+    fn content_disposition_attachment_encodes_non_ascii_names() {
+        assert_eq!(
+            content_disposition_attachment("Air - Moon Safari (1998).zip"),
+            r#"attachment; filename="Air - Moon Safari (1998).zip""#,
+        );
+        assert_eq!(
+            content_disposition_attachment("Sigur Rós.zip"),
+            r#"attachment; filename="Sigur R_s.zip"; filename*=UTF-8''Sigur%20R%C3%B3s.zip"#,
+        );
+    }
+
+    #[test]
+    fn accepts_encoding_handles_q_values_and_wildcards() {
+        assert!(accepts_encoding(Some("gzip, deflate, br"), "gzip"));
+        assert!(accepts_encoding(Some("br;q=1.0, GZIP;q=0.5"), "gzip"));
+        assert!(!accepts_encoding(Some("gzip;q=0, *"), "gzip"));
+        assert!(accepts_encoding(Some("*"), "br"));
+        assert!(!accepts_encoding(Some("deflate"), "gzip"));
+        assert!(!accepts_encoding(None, "gzip"));
+    }
+
+    #[test]
+    fn negotiate_encoding_prefers_our_order() {
+        let all = [ContentEncoding::Brotli, ContentEncoding::Gzip];
+        assert_eq!(negotiate_encoding(Some("gzip, br"), &all), ContentEncoding::Brotli);
+        assert_eq!(negotiate_encoding(Some("gzip, br"), &all[1..]), ContentEncoding::Gzip);
+        assert_eq!(negotiate_encoding(Some("deflate"), &all), ContentEncoding::Identity);
+        assert_eq!(negotiate_encoding(None, &all), ContentEncoding::Identity);
+    }
+}
@@ -24,40 +24,106 @@ extern crate libc;
 extern crate serde_json;
 extern crate unicode_normalization;
 extern crate bs1770;
+#[macro_use]
+extern crate tracing;
 
 mod album_table;
 mod build;
+mod cuesheet;
 mod exec_pre_post;
 mod filter;
+mod gain;
+mod gzip;
 mod loudness;
+mod md5;
+mod pipe;
+mod read_timeout;
+mod resample;
 mod search;
 mod waveform;
 mod word_index;
-
+mod zip;
+
+pub mod acoustid;
+pub mod album_download;
+pub mod artist_alias;
+pub mod assets;
+pub mod auth;
+pub mod backup;
+pub mod browser_output;
+pub mod cast;
+pub mod client;
 pub mod config;
+pub mod ctl;
 pub mod database;
 pub mod database_utils;
+pub mod dbus;
+pub mod dlna;
+pub mod dynamics;
+pub mod enrichment;
 pub mod error;
+pub mod events;
+pub mod flac_tags;
+pub mod generation;
+pub mod graphql;
 pub mod history;
+pub mod http_utils;
+pub mod library_stats;
+pub mod library_view;
+pub mod listen_export;
+pub mod listen_import;
+pub mod limits;
+pub mod listen_repair;
+pub mod listing;
+pub mod log;
+pub mod listens;
+pub mod m3u;
+pub mod maintenance;
+pub mod metadata_edit;
+pub mod metrics;
+pub mod mpd;
+pub mod mpris;
 pub mod mvar;
+pub mod openapi;
 pub mod playback;
 pub mod player;
+pub mod playlist;
 pub mod prim;
+pub mod proxy;
+pub mod radio;
+pub mod reload;
 pub mod scan;
+pub mod scrobble;
 pub mod serialization;
 pub mod server;
+pub mod sessions;
 pub mod shuffle;
+pub mod shutdown;
+pub mod similar;
+pub mod smart_playlist;
+pub mod snapcast;
+pub mod status_hook;
 pub mod string_utils;
+pub mod subsonic;
 pub mod systemd;
 pub mod thumb_cache;
 pub mod thumb_gen;
+pub mod tls;
+pub mod transcode;
+pub mod tui;
+pub mod typeahead;
 pub mod user_data;
+pub mod verify;
+pub mod webhook;
+pub mod xspf;
+
+use std::mem;
 
-use crate::build::{AlbumArtistsDeduper, BuildMetaIndex, BuildError};
+use crate::build::{AlbumArtistsDeduper, AlbumIdentity, BuildMetaIndex, BuildError};
 use crate::error::{Error, Result};
 use crate::prim::{ArtistId, Artist, AlbumArtistsRef, AlbumId, Album, TrackId, Track, Lufs, StringRef, FilenameRef};
 use crate::prim::{ArtistWithId, AlbumWithId, TrackWithId};
-use crate::string_utils::StringDeduper;
+use crate::string_utils::{StringArena, StringDeduper};
 use crate::word_index::MemoryWordIndex;
 
 pub trait MetaIndex {
@@ -87,6 +153,19 @@ pub trait MetaIndex {
     /// Return all tracks that are part of the album.
     fn get_album_tracks(&self, id: AlbumId) -> &[TrackWithId];
 
+    /// Return the subtitle of a disc of the album, if it has one.
+    ///
+    /// Subtitles come from the `discsubtitle` tag, box sets often name their discs.
+    fn get_disc_subtitle(&self, id: AlbumId, disc_number: u8) -> Option<StringRef>;
+
+    /// Return the pre-gap of the track in milliseconds, 0 if it has none.
+    ///
+    /// This is the audio before INDEX 01 in the cuesheet of the file, see `cuesheet.rs`.
+    fn get_pregap_ms(&self, id: TrackId) -> u32;
+
+    /// Return the sum of the track durations of the album, in seconds.
+    fn get_album_duration_seconds(&self, id: AlbumId) -> u32;
+
     /// Return all tracks, ordered by id.
     fn get_tracks(&self) -> &[TrackWithId];
 
@@ -107,6 +186,9 @@ pub trait MetaIndex {
     /// array of (artist id, album id) pairs.
     fn get_albums_by_artist(&self, _: ArtistId) -> &[(ArtistId, AlbumId)];
 
+    /// Return the sum of the album durations of the artist, in seconds.
+    fn get_artist_duration_seconds(&self, id: ArtistId) -> u64;
+
     /// Return all (artist id, album id) pairs.
     ///
     /// The resulting index is sorted by artist id first, and then by ascending
@@ -114,10 +196,12 @@ pub trait MetaIndex {
     fn get_album_ids_ordered_by_artist(&self) -> &[(ArtistId, AlbumId)];
 
     /// Search for artists where the word occurs in the name.
-    fn search_artist(&self, words: &[String], into: &mut Vec<ArtistId>);
+    ///
+    /// Tolerates up to `max_edits` typos in the query, see `search::search_fuzzy`.
+    fn search_artist(&self, words: &[String], max_edits: u32, into: &mut Vec<ArtistId>);
 
     /// Search for albums where the word occurs in the title or artist.
-    fn search_album(&self, words: &[String], into: &mut Vec<AlbumId>);
+    fn search_album(&self, words: &[String], max_edits: u32, into: &mut Vec<AlbumId>);
 
     /// Search for tracks where the word occurs in the title or track artist.
     ///
@@ -125,7 +209,7 @@ pub trait MetaIndex {
     /// part of the album artist. That is, this search will not turn up all
     /// tracks by an artist, only those for which `search_album` would not
     /// already find the entire album.
-    fn search_track(&self, words: &[String], into: &mut Vec<TrackId>);
+    fn search_track(&self, words: &[String], max_edits: u32, into: &mut Vec<TrackId>);
 }
 
 /// Indices into a sorted array based on the most significant byte of an id.
@@ -181,6 +265,111 @@ impl Bookmarks {
     }
 }
 
+/// Total playtime of albums and album artists.
+///
+/// We sum the track durations once when we build the index, so listing albums
+/// or artists with their playtime does not need to visit every track.
+pub struct Durations {
+    // Per album, the sum of its track durations, ordered by album id.
+    albums: Vec<(AlbumId, u32)>,
+
+    // Per album artist, the sum of its album durations, ordered by artist id.
+    artists: Vec<(ArtistId, u64)>,
+}
+
+impl Durations {
+    /// Sum the durations of `tracks`, which must be ordered by id.
+    ///
+    /// The `albums_by_artist` pairs must be ordered by artist id, as returned
+    /// by `MetaIndex::get_album_ids_ordered_by_artist`.
+    pub fn new(tracks: &[TrackWithId], albums_by_artist: &[(ArtistId, AlbumId)]) -> Durations {
+        let mut albums: Vec<(AlbumId, u32)> = Vec::new();
+        for kv in tracks {
+            let album_id = kv.track_id.album_id();
+            let duration = kv.track.duration_seconds as u32;
+            match albums.last_mut() {
+                Some((id, total)) if *id == album_id => *total += duration,
+                _ => albums.push((album_id, duration)),
+            }
+        }
+
+        let mut artists: Vec<(ArtistId, u64)> = Vec::new();
+        for &(artist_id, album_id) in albums_by_artist {
+            let duration = lookup(&albums, album_id) as u64;
+            match artists.last_mut() {
+                Some((id, total)) if *id == artist_id => *total += duration,
+                _ => artists.push((artist_id, duration)),
+            }
+        }
+
+        Durations {
+            albums: albums,
+            artists: artists,
+        }
+    }
+
+    pub fn new_empty() -> Durations {
+        Durations::new(&[], &[])
+    }
+
+    /// Return the total duration of the album, or 0 if it does not exist.
+    pub fn get_album(&self, id: AlbumId) -> u32 {
+        lookup(&self.albums, id)
+    }
+
+    /// Return the total duration of the artist, or 0 if it does not exist.
+    pub fn get_artist(&self, id: ArtistId) -> u64 {
+        lookup(&self.artists, id)
+    }
+
+    /// Return the number of bytes of heap memory used.
+    pub fn size_bytes(&self) -> usize {
+        vec_bytes(&self.albums) + vec_bytes(&self.artists)
+    }
+}
+
+/// Return the number of bytes of heap memory that the vector uses.
+fn vec_bytes<T>(xs: &Vec<T>) -> usize {
+    xs.capacity() * mem::size_of::<T>()
+}
+
+/// Look up the value for `key` in a slice of pairs ordered by key.
+fn lookup<K: Ord, V: Copy + Default>(xs: &[(K, V)], key: K) -> V {
+    match xs.binary_search_by(|(k, _)| k.cmp(&key)) {
+        Ok(i) => xs[i].1,
+        Err(_) => V::default(),
+    }
+}
+
+/// Bytes of heap memory used by the parts of a `MemoryMetaIndex`.
+#[derive(Debug)]
+pub struct MemoryUsage {
+    pub tracks: usize,
+    pub albums: usize,
+    pub artists: usize,
+    /// The album artists of albums, and the albums by artist.
+    pub album_artists: usize,
+    pub strings: usize,
+    pub filenames: usize,
+    /// The word indexes for search.
+    pub words: usize,
+    /// Bookmarks, disc subtitles, and durations.
+    pub other: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.tracks
+            + self.albums
+            + self.artists
+            + self.album_artists
+            + self.strings
+            + self.filenames
+            + self.words
+            + self.other
+    }
+}
+
 pub struct MemoryMetaIndex {
     artists: Vec<ArtistWithId>,
     albums: Vec<AlbumWithId>,
@@ -195,10 +384,18 @@ pub struct MemoryMetaIndex {
     track_bookmarks: Bookmarks,
     albums_by_artist_bookmarks: Bookmarks,
 
-    strings: Vec<String>,
-    filenames: Vec<String>,
+    strings: StringArena,
+    filenames: StringArena,
     album_artists: Vec<ArtistId>,
 
+    // Disc subtitles, keyed on the id of track 0 of the disc, ordered by it.
+    disc_subtitles: Vec<(TrackId, StringRef)>,
+
+    // Pre-gaps in milliseconds, for the few tracks that have one, ordered by track id.
+    pregaps: Vec<(TrackId, u32)>,
+
+    durations: Durations,
+
     // TODO: Don't make these pub, this is just for debug printing stats.
     pub words_artist: MemoryWordIndex<ArtistId>,
     pub words_album: MemoryWordIndex<AlbumId>,
@@ -249,7 +446,7 @@ impl MemoryMetaIndex {
         let mut tracks: Vec<TrackWithId> = Vec::with_capacity(builder.tracks.len());
         let mut album_artists = AlbumArtistsDeduper::new();
         let mut strings = StringDeduper::new();
-        let mut filenames = Vec::new();
+        let mut filenames = StringArena::new();
 
         for (id, track) in builder.tracks.iter() {
             let (id, mut track) = (*id, track.clone());
@@ -261,8 +458,9 @@ impl MemoryMetaIndex {
             track.artist = StringRef(
                 strings.insert(builder.strings.get(track.artist.0))
             );
-            filenames.push(builder.filenames[track.filename.0 as usize].clone());
-            track.filename = FilenameRef(filenames.len() as u32 - 1);
+            track.filename = FilenameRef(
+                filenames.push(&builder.filenames[track.filename.0 as usize])
+            );
 
             tracks.push(TrackWithId { track_id: id, track });
         }
@@ -300,6 +498,13 @@ impl MemoryMetaIndex {
                     .cloned()
             );
 
+            // When files change, we re-import them, but that does not make the
+            // album new. If we recorded an import time for the album before,
+            // then that is when it entered the library.
+            if let Some(imported_at) = builder.album_imports.get(&id) {
+                album.first_seen = album.first_seen.min(*imported_at);
+            }
+
             // We may have edited a file after listening to it (for example to
             // fix some tags). In that case the mtime will be recent, and that
             // will mess with the “first seen” field. But if we have listens for
@@ -325,13 +530,25 @@ impl MemoryMetaIndex {
             artists.push(ArtistWithId { artist_id: id, artist });
         }
 
+        let disc_subtitles = builder
+            .disc_subtitles
+            .iter()
+            .map(|(&(album_id, disc_number), subtitle)| (
+                TrackId::new(album_id, disc_number, 0),
+                StringRef(strings.insert(builder.strings.get(subtitle.0))),
+            ))
+            .collect();
+
         strings.upgrade_quotes();
+        filenames.shrink_to_fit();
 
         let albums_by_artist = build_albums_by_artist_index(
             &albums[..],
             &album_artists,
         );
 
+        let durations = Durations::new(&tracks, &albums_by_artist);
+
         MemoryMetaIndex {
             artist_bookmarks: Bookmarks::new(artists.iter().map(|p| p.artist_id.0)),
             album_bookmarks: Bookmarks::new(albums.iter().map(|p| p.album_id.for_bookmark())),
@@ -341,9 +558,12 @@ impl MemoryMetaIndex {
             albums: albums,
             tracks: tracks,
             albums_by_artist: albums_by_artist,
-            strings: strings.into_vec(),
+            strings: strings.into_arena(),
             filenames: filenames,
             album_artists: album_artists.into_vec(),
+            disc_subtitles: disc_subtitles,
+            pregaps: builder.pregaps.iter().map(|(&k, &v)| (k, v)).collect(),
+            durations: durations,
             words_artist: MemoryWordIndex::new(&builder.words_artist),
             words_album: MemoryWordIndex::new(&builder.words_album),
             words_track: MemoryWordIndex::new(&builder.words_track),
@@ -365,8 +585,11 @@ impl MemoryMetaIndex {
             tracks: Vec::new(),
             albums_by_artist: Vec::new(),
             album_artists: Vec::new(),
-            strings: Vec::new(),
-            filenames: Vec::new(),
+            disc_subtitles: Vec::new(),
+            pregaps: Vec::new(),
+            durations: Durations::new_empty(),
+            strings: StringArena::new(),
+            filenames: StringArena::new(),
             words_artist: MemoryWordIndex::new(std::iter::empty()),
             words_album: MemoryWordIndex::new(std::iter::empty()),
             words_track: MemoryWordIndex::new(std::iter::empty()),
@@ -378,10 +601,15 @@ impl MemoryMetaIndex {
     /// Also returns the intermediate builder. It contains any issues
     /// discovered, and the mtimes per album, which can be used to check if any
     /// thumbnails need updating.
-    pub fn from_database(tx: &mut database::Transaction) -> Result<(MemoryMetaIndex, BuildMetaIndex)> {
-        let mut builder = BuildMetaIndex::new();
+    pub fn from_database(
+        tx: &mut database::Transaction,
+        album_identity: AlbumIdentity,
+    ) -> Result<(MemoryMetaIndex, BuildMetaIndex)> {
+        let mut builder = BuildMetaIndex::new(album_identity);
         let mut tasks = Vec::new();
 
+        builder.insert_artist_aliases(tx)?;
+
         for file in database::iter_files(tx)? {
             match builder.insert_meta(file?) {
                 Ok(task) => tasks.push(task),
@@ -391,19 +619,43 @@ impl MemoryMetaIndex {
         }
 
         for task in tasks {
+            let file = (task.file_id(), task.filename());
             match builder.insert_full(tx, task) {
                 Ok(()) => continue,
                 Err(BuildError::DbError(err)) => return Err(Error::from(err)),
-                Err(BuildError::FileFailed) => continue,
+                Err(BuildError::FileFailed) => builder.files_missing_tags.push(file),
             }
         }
 
+        builder.finish_artist_aliases();
         builder.insert_first_listens(tx)?;
+        builder.insert_album_imports(tx)?;
+        builder.insert_pregaps(tx)?;
 
         let memory_index = MemoryMetaIndex::new(&builder);
 
         Ok((memory_index, builder))
     }
+
+    /// Return how much memory the parts of the index use.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let bookmarks_bytes = 4 * mem::size_of::<[u32; 257]>();
+        MemoryUsage {
+            tracks: vec_bytes(&self.tracks),
+            albums: vec_bytes(&self.albums),
+            artists: vec_bytes(&self.artists),
+            album_artists: vec_bytes(&self.album_artists) + vec_bytes(&self.albums_by_artist),
+            strings: self.strings.size_bytes(),
+            filenames: self.filenames.size_bytes(),
+            words: self.words_artist.size().total_bytes()
+                + self.words_album.size().total_bytes()
+                + self.words_track.size().total_bytes(),
+            other: bookmarks_bytes
+                + vec_bytes(&self.disc_subtitles)
+                + vec_bytes(&self.pregaps)
+                + self.durations.size_bytes(),
+        }
+    }
 }
 
 impl MetaIndex for MemoryMetaIndex {
@@ -414,12 +666,12 @@ impl MetaIndex for MemoryMetaIndex {
 
     #[inline]
     fn get_string(&self, sr: StringRef) -> &str {
-        &self.strings[sr.0 as usize]
+        self.strings.get(sr.0)
     }
 
     #[inline]
     fn get_filename(&self, sr: FilenameRef) -> &str {
-        &self.filenames[sr.0 as usize]
+        self.filenames.get(sr.0)
     }
 
     #[inline]
@@ -479,6 +731,26 @@ impl MetaIndex for MemoryMetaIndex {
         &slice[begin..end]
     }
 
+    fn get_disc_subtitle(&self, id: AlbumId, disc_number: u8) -> Option<StringRef> {
+        let tid = TrackId::new(id, disc_number, 0);
+        self.disc_subtitles
+            .binary_search_by_key(&tid, |&(k, _)| k)
+            .ok()
+            .map(|i| self.disc_subtitles[i].1)
+    }
+
+    fn get_pregap_ms(&self, id: TrackId) -> u32 {
+        self.pregaps
+            .binary_search_by_key(&id, |&(k, _)| k)
+            .map(|i| self.pregaps[i].1)
+            .unwrap_or(0)
+    }
+
+    #[inline]
+    fn get_album_duration_seconds(&self, id: AlbumId) -> u32 {
+        self.durations.get_album(id)
+    }
+
     #[inline]
     fn get_tracks(&self) -> &[TrackWithId] {
         &self.tracks
@@ -540,20 +812,25 @@ impl MetaIndex for MemoryMetaIndex {
         &candidates[..end]
     }
 
+    #[inline]
+    fn get_artist_duration_seconds(&self, id: ArtistId) -> u64 {
+        self.durations.get_artist(id)
+    }
+
     #[inline]
     fn get_album_ids_ordered_by_artist(&self) -> &[(ArtistId, AlbumId)] {
         &self.albums_by_artist[..]
     }
 
-    fn search_artist(&self, words: &[String], into: &mut Vec<ArtistId>) {
-        search::search(&self.words_artist, words, into);
+    fn search_artist(&self, words: &[String], max_edits: u32, into: &mut Vec<ArtistId>) {
+        search::search_fuzzy(&self.words_artist, words, max_edits, into);
     }
 
-    fn search_album(&self, words: &[String], into: &mut Vec<AlbumId>) {
-        search::search(&self.words_album, words, into);
+    fn search_album(&self, words: &[String], max_edits: u32, into: &mut Vec<AlbumId>) {
+        search::search_fuzzy(&self.words_album, words, max_edits, into);
     }
 
-    fn search_track(&self, words: &[String], into: &mut Vec<TrackId>) {
-        search::search(&self.words_track, words, into);
+    fn search_track(&self, words: &[String], max_edits: u32, into: &mut Vec<TrackId>) {
+        search::search_fuzzy(&self.words_track, words, max_edits, into);
     }
 }
@@ -0,0 +1,149 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Statistics about the library as a whole, for `/api/stats/library`.
+//!
+//! Counts and playtime come from the index. The audio properties and sizes of
+//! the files are not part of the index, we read them from the `files` table,
+//! and the genres from the tags. We only count files that are in the index we
+//! are given, so for a library view, the statistics cover what the user sees.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::path::Path;
+
+use crate::database::{self as db, Transaction};
+use crate::MetaIndex;
+
+#[derive(Debug, Default)]
+pub struct LibraryStats {
+    pub tracks: usize,
+    pub albums: usize,
+    pub artists: usize,
+    pub duration_seconds: u64,
+    pub size_bytes: u64,
+
+    /// Number of tracks per file extension, in lowercase.
+    pub formats: Vec<(String, u64)>,
+
+    /// Number of tracks per sample rate in Hz.
+    pub sample_rates: Vec<(i64, u64)>,
+
+    /// Number of tracks per bit depth.
+    pub bits_per_sample: Vec<(i64, u64)>,
+
+    /// Number of albums per year of original release, ordered by year.
+    pub years: Vec<(u16, u64)>,
+
+    /// Number of tracks per genre tag, most common genre first.
+    pub genres: Vec<(String, u64)>,
+}
+
+/// Return the counts, ordered by descending count, and by key for equal counts.
+fn sorted_by_count<K: Ord>(counts: HashMap<K, u64>) -> Vec<(K, u64)> {
+    let mut result: Vec<(K, u64)> = counts.into_iter().collect();
+    result.sort_by(|(k1, n1), (k2, n2)| n2.cmp(n1).then(k1.cmp(k2)));
+    result
+}
+
+fn increment<K: Eq + Hash>(counts: &mut HashMap<K, u64>, key: K) {
+    *counts.entry(key).or_insert(0) += 1;
+}
+
+/// Return the file extension in lowercase, or an empty string if there is none.
+fn format_of(filename: &str) -> String {
+    Path::new(filename)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+pub fn compute(tx: &mut Transaction, index: &dyn MetaIndex) -> db::Result<LibraryStats> {
+    let mut files = HashMap::new();
+    for row in db::iter_file_formats(tx)? {
+        let file = row?;
+        files.insert(file.id, file);
+    }
+
+    let mut genres_by_file: HashMap<i64, Vec<String>> = HashMap::new();
+    for row in db::iter_tag_values(tx, "genre")? {
+        let (file_id, genre) = row?;
+        let genre = genre.trim();
+        if !genre.is_empty() {
+            genres_by_file.entry(file_id).or_default().push(genre.to_string());
+        }
+    }
+
+    let mut stats = LibraryStats {
+        tracks: index.get_tracks().len(),
+        albums: index.get_albums().len(),
+        artists: index.get_artists().len(),
+        ..LibraryStats::default()
+    };
+
+    let mut formats = HashMap::new();
+    let mut sample_rates = HashMap::new();
+    let mut bits_per_sample = HashMap::new();
+    let mut years = HashMap::new();
+    let mut genres = HashMap::new();
+
+    for kv in index.get_tracks() {
+        let track = &kv.track;
+        stats.duration_seconds += track.duration_seconds as u64;
+        increment(&mut formats, format_of(index.get_filename(track.filename)));
+
+        if let Some(file) = files.get(&track.file_id.0) {
+            stats.size_bytes += file.size_bytes.unwrap_or(0) as u64;
+            increment(&mut sample_rates, file.streaminfo_sample_rate);
+            increment(&mut bits_per_sample, file.streaminfo_bits_per_sample);
+        }
+
+        for genre in genres_by_file.get(&track.file_id.0).into_iter().flatten() {
+            increment(&mut genres, genre.clone());
+        }
+    }
+
+    for kv in index.get_albums() {
+        increment(&mut years, kv.album.original_release_date.year);
+    }
+
+    stats.formats = sorted_by_count(formats);
+    stats.sample_rates = sorted_by_count(sample_rates);
+    stats.bits_per_sample = sorted_by_count(bits_per_sample);
+    stats.genres = sorted_by_count(genres);
+
+    let mut years: Vec<(u16, u64)> = years.into_iter().collect();
+    years.sort();
+    stats.years = years;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_of, sorted_by_count};
+    use std::collections::HashMap;
+
+    #[test]
+    fn sorted_by_count_puts_most_common_first() {
+        let mut counts = HashMap::new();
+        counts.insert("Rock", 3);
+        counts.insert("Ambient", 1);
+        counts.insert("Post-rock", 3);
+        assert_eq!(
+            sorted_by_count(counts),
+            vec![("Post-rock", 3), ("Rock", 3), ("Ambient", 1)],
+        );
+    }
+
+    #[test]
+    fn format_of_returns_lowercase_extension() {
+        assert_eq!(format_of("/music/Takk/01 Takk.FLAC"), "flac");
+        assert_eq!(format_of("/music/Takk/01 Takk.mp3"), "mp3");
+        assert_eq!(format_of("/music/Takk/README"), "");
+    }
+}
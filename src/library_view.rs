@@ -0,0 +1,503 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Library views, which hide part of the library from a user.
+//!
+//! A view consists of the `library_view =` rules for a user in the config, for
+//! example to keep the music of the parents out of sight of the kids. The
+//! server reads the library for such a user through a `LibraryView`, which
+//! implements `MetaIndex` with the hidden tracks left out. That way browsing,
+//! searching, random albums, and smart playlists all respect the view, without
+//! each of them having to know about it.
+//!
+//! Genres and advisories are not part of the index, we read them from the tags
+//! in the database when we build a view. The views of an index don't change,
+//! so we build them once per index, see `ViewCache`.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::auth;
+use crate::config::Config;
+use crate::database as db;
+use crate::database::Connection;
+use crate::database_utils;
+use crate::prim::{AlbumArtistsRef, AlbumId, ArtistId, FilenameRef, StringRef, TrackId};
+use crate::prim::{Album, AlbumWithId, Artist, ArtistWithId, Track, TrackWithId};
+use crate::{Durations, MemoryMetaIndex, MetaIndex};
+
+/// One way in which a view hides tracks.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Rule {
+    /// Hide tracks with this genre tag, compared case-insensitively.
+    ExcludeGenre(String),
+
+    /// Hide albums where a track has the iTunes advisory for explicit content.
+    ExcludeExplicit,
+
+    /// Hide tracks in this directory.
+    ExcludePath(PathBuf),
+
+    /// Hide tracks outside of this directory.
+    ///
+    /// With multiple of these rules, tracks in any of the directories are visible.
+    OnlyPath(PathBuf),
+}
+
+/// A rule that applies to the view of one user, a `library_view =` line.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ViewRule {
+    pub user: String,
+    pub rule: Rule,
+}
+
+impl FromStr for ViewRule {
+    type Err = &'static str;
+
+    /// Parse a rule from the form `user rule [argument]`.
+    ///
+    /// The argument is the rest of the line, so genres and paths can contain
+    /// spaces.
+    fn from_str(s: &str) -> std::result::Result<ViewRule, &'static str> {
+        let (user, rest) = s.trim().split_once(char::is_whitespace).unwrap_or((s.trim(), ""));
+        let rest = rest.trim_start();
+        let (kind, argument) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let argument = argument.trim();
+
+        if !auth::is_valid_user(user) {
+            return Err("Invalid library_view user, must be 1 to 64 letters, digits, '-', or '_'.");
+        }
+
+        let rule = match (kind, argument) {
+            ("exclude_explicit", "") => Rule::ExcludeExplicit,
+            ("exclude_genre", genre) if !genre.is_empty() => Rule::ExcludeGenre(genre.to_string()),
+            ("exclude_path", path) if !path.is_empty() => Rule::ExcludePath(PathBuf::from(path)),
+            ("only_path", path) if !path.is_empty() => Rule::OnlyPath(PathBuf::from(path)),
+            ("exclude_explicit" | "exclude_genre" | "exclude_path" | "only_path", _) => {
+                return Err("Invalid library_view value, expected 'user rule [argument]', \
+                    where only exclude_explicit has no argument.");
+            }
+            _ => {
+                return Err("Invalid library_view rule, must be one of \
+                    exclude_genre, exclude_explicit, exclude_path, or only_path.");
+            }
+        };
+
+        let result = ViewRule {
+            user: user.to_string(),
+            rule: rule,
+        };
+        Ok(result)
+    }
+}
+
+/// The tags that rules can match on, by file id.
+#[derive(Default)]
+pub struct FileTags {
+    genres: HashMap<i64, Vec<String>>,
+    explicit: HashSet<i64>,
+}
+
+impl FileTags {
+    /// Read the genre and advisory tags from the database.
+    pub fn load(tx: &mut db::Transaction) -> db::Result<FileTags> {
+        let mut result = FileTags::default();
+
+        for row in db::iter_tag_values(tx, "genre")? {
+            let (file_id, genre) = row?;
+            result.genres.entry(file_id).or_default().push(genre);
+        }
+
+        // The tag that iTunes and Picard use, 1 is explicit, 2 is clean.
+        for row in db::iter_tag_values(tx, "itunesadvisory")? {
+            let (file_id, advisory) = row?;
+            if advisory.trim() == "1" {
+                result.explicit.insert(file_id);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// Return whether any of the rules hides the track, apart from `ExcludeExplicit`,
+/// which applies to the album as a whole.
+fn hides_track(rules: &[(&Rule, PathBuf)], filename: &Path, genres: &[String]) -> bool {
+    let mut has_only_path = false;
+    let mut in_only_path = false;
+
+    for (rule, path) in rules {
+        match rule {
+            Rule::ExcludeExplicit => continue,
+            Rule::ExcludeGenre(excluded) => {
+                if genres.iter().any(|g| g.trim().eq_ignore_ascii_case(excluded.trim())) {
+                    return true;
+                }
+            }
+            Rule::ExcludePath(..) => {
+                if filename.starts_with(path) {
+                    return true;
+                }
+            }
+            Rule::OnlyPath(..) => {
+                has_only_path = true;
+                in_only_path = in_only_path || filename.starts_with(path);
+            }
+        }
+    }
+
+    has_only_path && !in_only_path
+}
+
+/// The part of an index that a user may see.
+pub struct LibraryView {
+    index: Arc<MemoryMetaIndex>,
+    tracks: Vec<TrackWithId>,
+    albums: Vec<AlbumWithId>,
+    artists: Vec<ArtistWithId>,
+    albums_by_artist: Vec<(ArtistId, AlbumId)>,
+    durations: Durations,
+}
+
+impl LibraryView {
+    /// Apply the rules to the index.
+    ///
+    /// Relative paths in rules are relative to the library path. Albums and
+    /// artists are visible when at least one of their tracks is.
+    pub fn new(
+        index: Arc<MemoryMetaIndex>,
+        rules: &[&Rule],
+        tags: &FileTags,
+        library_path: &Path,
+    ) -> LibraryView {
+        let rules: Vec<(&Rule, PathBuf)> = rules
+            .iter()
+            .map(|&rule| match rule {
+                Rule::ExcludePath(p) | Rule::OnlyPath(p) => (rule, library_path.join(p)),
+                _ => (rule, PathBuf::new()),
+            })
+            .collect();
+
+        let mut explicit_albums = HashSet::new();
+        if rules.iter().any(|(rule, _)| *rule == &Rule::ExcludeExplicit) {
+            for kv in index.get_tracks() {
+                if tags.explicit.contains(&kv.track.file_id.0) {
+                    explicit_albums.insert(kv.track_id.album_id());
+                }
+            }
+        }
+
+        let no_genres = Vec::new();
+        let mut tracks = Vec::new();
+        let mut visible_albums = HashSet::new();
+        for kv in index.get_tracks() {
+            if explicit_albums.contains(&kv.track_id.album_id()) {
+                continue;
+            }
+            let filename = Path::new(index.get_filename(kv.track.filename));
+            let genres = tags.genres.get(&kv.track.file_id.0).unwrap_or(&no_genres);
+            if hides_track(&rules, filename, genres) {
+                continue;
+            }
+            visible_albums.insert(kv.track_id.album_id());
+            tracks.push(TrackWithId {
+                track_id: kv.track_id,
+                track: kv.track.clone(),
+            });
+        }
+
+        let albums: Vec<AlbumWithId> = index
+            .get_albums()
+            .iter()
+            .filter(|kv| visible_albums.contains(&kv.album_id))
+            .map(|kv| AlbumWithId {
+                album_id: kv.album_id,
+                album: kv.album.clone(),
+            })
+            .collect();
+
+        let albums_by_artist: Vec<(ArtistId, AlbumId)> = index
+            .get_album_ids_ordered_by_artist()
+            .iter()
+            .filter(|(_artist_id, album_id)| visible_albums.contains(album_id))
+            .cloned()
+            .collect();
+
+        let visible_artists: HashSet<ArtistId> = albums_by_artist
+            .iter()
+            .map(|&(artist_id, _album_id)| artist_id)
+            .collect();
+        let artists: Vec<ArtistWithId> = index
+            .get_artists()
+            .iter()
+            .filter(|kv| visible_artists.contains(&kv.artist_id))
+            .map(|kv| ArtistWithId {
+                artist_id: kv.artist_id,
+                artist: kv.artist.clone(),
+            })
+            .collect();
+
+        // Hidden tracks do not count towards the playtime of the album.
+        let durations = Durations::new(&tracks, &albums_by_artist);
+
+        LibraryView {
+            index: index,
+            tracks: tracks,
+            albums: albums,
+            artists: artists,
+            albums_by_artist: albums_by_artist,
+            durations: durations,
+        }
+    }
+
+    /// A view that hides everything, for when we cannot apply the rules.
+    pub fn new_empty(index: Arc<MemoryMetaIndex>) -> LibraryView {
+        LibraryView {
+            index: index,
+            tracks: Vec::new(),
+            albums: Vec::new(),
+            artists: Vec::new(),
+            albums_by_artist: Vec::new(),
+            durations: Durations::new_empty(),
+        }
+    }
+}
+
+impl MetaIndex for LibraryView {
+    fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    fn get_string(&self, sr: StringRef) -> &str {
+        self.index.get_string(sr)
+    }
+
+    fn get_filename(&self, sr: FilenameRef) -> &str {
+        self.index.get_filename(sr)
+    }
+
+    fn get_track(&self, id: TrackId) -> Option<&Track> {
+        self.tracks
+            .binary_search_by_key(&id, |kv| kv.track_id)
+            .ok()
+            .map(|i| &self.tracks[i].track)
+    }
+
+    fn get_album(&self, id: AlbumId) -> Option<&Album> {
+        self.albums
+            .binary_search_by_key(&id, |kv| kv.album_id)
+            .ok()
+            .map(|i| &self.albums[i].album)
+    }
+
+    fn get_album_artists(&self, range: AlbumArtistsRef) -> &[ArtistId] {
+        self.index.get_album_artists(range)
+    }
+
+    fn get_album_tracks(&self, id: AlbumId) -> &[TrackWithId] {
+        // Tracks are ordered by id, and the album id is the most significant
+        // part of the track id, so the tracks of an album are adjacent.
+        let begin = self.tracks.partition_point(|kv| kv.track_id.album_id() < id);
+        let end = self.tracks.partition_point(|kv| kv.track_id.album_id() <= id);
+        &self.tracks[begin..end]
+    }
+
+    fn get_disc_subtitle(&self, id: AlbumId, disc_number: u8) -> Option<StringRef> {
+        self.index.get_disc_subtitle(id, disc_number)
+    }
+
+    fn get_pregap_ms(&self, id: TrackId) -> u32 {
+        self.index.get_pregap_ms(id)
+    }
+
+    fn get_album_duration_seconds(&self, id: AlbumId) -> u32 {
+        self.durations.get_album(id)
+    }
+
+    fn get_tracks(&self) -> &[TrackWithId] {
+        &self.tracks
+    }
+
+    fn get_albums(&self) -> &[AlbumWithId] {
+        &self.albums
+    }
+
+    fn get_artists(&self) -> &[ArtistWithId] {
+        &self.artists
+    }
+
+    fn get_artist(&self, id: ArtistId) -> Option<&Artist> {
+        self.artists
+            .binary_search_by_key(&id, |kv| kv.artist_id)
+            .ok()
+            .map(|i| &self.artists[i].artist)
+    }
+
+    fn get_albums_by_artist(&self, artist_id: ArtistId) -> &[(ArtistId, AlbumId)] {
+        let begin = self.albums_by_artist.partition_point(|&(a, _)| a < artist_id);
+        let end = self.albums_by_artist.partition_point(|&(a, _)| a <= artist_id);
+        &self.albums_by_artist[begin..end]
+    }
+
+    fn get_artist_duration_seconds(&self, id: ArtistId) -> u64 {
+        self.durations.get_artist(id)
+    }
+
+    fn get_album_ids_ordered_by_artist(&self) -> &[(ArtistId, AlbumId)] {
+        &self.albums_by_artist
+    }
+
+    fn search_artist(&self, words: &[String], max_edits: u32, into: &mut Vec<ArtistId>) {
+        let mut found = Vec::new();
+        self.index.search_artist(words, max_edits, &mut found);
+        into.extend(found.into_iter().filter(|&id| self.get_artist(id).is_some()));
+    }
+
+    fn search_album(&self, words: &[String], max_edits: u32, into: &mut Vec<AlbumId>) {
+        let mut found = Vec::new();
+        self.index.search_album(words, max_edits, &mut found);
+        into.extend(found.into_iter().filter(|&id| self.get_album(id).is_some()));
+    }
+
+    fn search_track(&self, words: &[String], max_edits: u32, into: &mut Vec<TrackId>) {
+        let mut found = Vec::new();
+        self.index.search_track(words, max_edits, &mut found);
+        into.extend(found.into_iter().filter(|&id| self.get_track(id).is_some()));
+    }
+}
+
+/// Remembers the views for the latest index, so we build every view once.
+///
+/// Like `GenerationCache`, we hold on to the index weakly.
+pub struct ViewCache {
+    rules: Vec<ViewRule>,
+    library_path: PathBuf,
+    db_path: PathBuf,
+    latest: Mutex<Option<(Weak<MemoryMetaIndex>, HashMap<String, Arc<LibraryView>>)>>,
+}
+
+impl ViewCache {
+    pub fn new(config: &Config) -> ViewCache {
+        ViewCache {
+            rules: config.library_views.clone(),
+            library_path: config.library_path.clone(),
+            db_path: config.db_path.clone(),
+            latest: Mutex::new(None),
+        }
+    }
+
+    /// Return whether the user sees only part of the library.
+    pub fn has_view(&self, user: Option<&str>) -> bool {
+        match user {
+            None => false,
+            Some(name) => self.rules.iter().any(|r| r.user == name),
+        }
+    }
+
+    /// Return the view of the user, or `None` if the user sees the full library.
+    pub fn get(&self, index: &Arc<MemoryMetaIndex>, user: Option<&str>) -> Option<Arc<LibraryView>> {
+        let name = match user {
+            Some(name) if self.has_view(user) => name,
+            _ => return None,
+        };
+
+        let mut latest = self.latest.lock().unwrap();
+        match latest.as_ref() {
+            Some((weak, _)) if Weak::ptr_eq(weak, &Arc::downgrade(index)) => {}
+            _ => *latest = Some((Arc::downgrade(index), HashMap::new())),
+        }
+        let views = &mut latest.as_mut().expect("We just set it.").1;
+        if let Some(view) = views.get(name) {
+            return Some(view.clone());
+        }
+
+        let rules: Vec<&Rule> = self.rules.iter().filter(|r| r.user == name).map(|r| &r.rule).collect();
+        let tags = database_utils::connect_readonly(&self.db_path).and_then(|connection| {
+            let mut db = Connection::new(&connection);
+            let mut tx = db.begin()?;
+            let tags = FileTags::load(&mut tx)?;
+            tx.commit()?;
+            Ok(tags)
+        });
+        let view = match tags {
+            Ok(tags) => LibraryView::new(index.clone(), &rules, &tags, &self.library_path),
+            // When we can't tell what to hide, we hide everything. We don't
+            // cache that, so the next request tries again.
+            Err(err) => {
+                error!("Error while loading tags for the library view of {}: {:?}", name, err);
+                return Some(Arc::new(LibraryView::new_empty(index.clone())));
+            }
+        };
+        let view = Arc::new(view);
+        views.insert(name.to_string(), view.clone());
+        Some(view)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use super::{hides_track, FileTags, LibraryView, Rule, ViewRule};
+    use crate::{MemoryMetaIndex, MetaIndex};
+
+    #[test]
+    fn view_rule_parses_rules_with_arguments() {
+        let rule = ViewRule::from_str("kids exclude_genre  Death Metal ").unwrap();
+        assert_eq!(rule.user, "kids");
+        assert_eq!(rule.rule, Rule::ExcludeGenre("Death Metal".to_string()));
+
+        let rule = ViewRule::from_str("kids exclude_explicit").unwrap();
+        assert_eq!(rule.rule, Rule::ExcludeExplicit);
+
+        let rule = ViewRule::from_str("kids only_path Kids/Audiobooks").unwrap();
+        assert_eq!(rule.rule, Rule::OnlyPath(PathBuf::from("Kids/Audiobooks")));
+
+        assert!(ViewRule::from_str("kids").is_err());
+        assert!(ViewRule::from_str("kids exclude_genre").is_err());
+        assert!(ViewRule::from_str("kids exclude_explicit yes").is_err());
+        assert!(ViewRule::from_str("kids exclude_mood sad").is_err());
+        assert!(ViewRule::from_str("kid$ exclude_explicit").is_err());
+    }
+
+    #[test]
+    fn hides_track_applies_genre_and_path_rules() {
+        let genre = Rule::ExcludeGenre("metal".to_string());
+        let exclude = Rule::ExcludePath(PathBuf::from("Parents"));
+        let only = Rule::OnlyPath(PathBuf::from("Kids"));
+        let rules = [
+            (&genre, PathBuf::new()),
+            (&exclude, PathBuf::from("/music/Parents")),
+        ];
+        let none: Vec<String> = Vec::new();
+        let metal = vec!["Rock".to_string(), "Metal".to_string()];
+
+        assert!(!hides_track(&rules, Path::new("/music/Kids/a.flac"), &none));
+        assert!(hides_track(&rules, Path::new("/music/Kids/a.flac"), &metal));
+        assert!(hides_track(&rules, Path::new("/music/Parents/a.flac"), &none));
+        // Paths match on whole components, not on string prefixes.
+        assert!(!hides_track(&rules, Path::new("/music/Parents2/a.flac"), &none));
+
+        let rules = [(&only, PathBuf::from("/music/Kids"))];
+        assert!(!hides_track(&rules, Path::new("/music/Kids/a.flac"), &none));
+        assert!(hides_track(&rules, Path::new("/music/Parents/a.flac"), &none));
+    }
+
+    #[test]
+    fn library_view_of_empty_index_is_empty() {
+        let index = Arc::new(MemoryMetaIndex::new_empty());
+        let rule = Rule::ExcludeExplicit;
+        let view = LibraryView::new(index, &[&rule], &FileTags::default(), Path::new("/music"));
+        assert_eq!(view.len(), 0);
+        assert!(view.get_albums().is_empty());
+        assert!(view.get_album_ids_ordered_by_artist().is_empty());
+    }
+}
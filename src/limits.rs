@@ -0,0 +1,221 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Rate limiting and request body limits for the HTTP server.
+//!
+//! The server handles requests on a small pool of threads, and the player
+//! shares the machine with it. A client that sends requests in a tight loop,
+//! or that uploads a huge body, should not be able to take all of that.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Limit for request bodies in general: login forms, Subsonic parameters, and
+/// GraphQL queries are all small.
+pub const MAX_BODY_LEN: u64 = 256 * 1024;
+
+/// Limit for uploads, and for the track ids of a bulk enqueue. A playlist or
+/// list of track ids with many thousands of entries still fits comfortably.
+pub const MAX_UPLOAD_LEN: u64 = 4 * 1024 * 1024;
+
+/// When we track more clients than this, we forget the ones that are idle.
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+/// Return the maximum body length for a request to /«p0»/«p1»/«p2».
+pub fn max_body_len(p0: Option<&str>, p1: Option<&str>, p2: Option<&str>) -> u64 {
+    match (p0, p1, p2) {
+        (Some("api"), Some("playlists"), Some("import")) => MAX_UPLOAD_LEN,
+        (Some("api"), Some("queue"), Some("tracks")) => MAX_UPLOAD_LEN,
+        _ => MAX_BODY_LEN,
+    }
+}
+
+/// The party that a request counts against.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Client {
+    /// A request with a valid token, identified by the name of the token.
+    Token(String),
+    /// A request without valid token, identified by the address of the client.
+    Ip(IpAddr),
+}
+
+/// Requests that a client can still make, see `RateLimiter`.
+struct Bucket {
+    requests: f64,
+    updated_at: Instant,
+}
+
+/// Token bucket rate limiter, with one bucket per client.
+///
+/// Every client can make a burst of up to a period worth of requests, after
+/// that requests are admitted at the configured rate.
+pub struct RateLimiter {
+    /// Requests per period, also the size of a burst.
+    requests_per_period: f64,
+    period_seconds: f64,
+    buckets: Mutex<HashMap<Client, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32) -> RateLimiter {
+        RateLimiter::with_period(requests_per_minute, 60)
+    }
+
+    /// Limit to `requests_per_hour`, for actions that are rarer than requests.
+    pub fn per_hour(requests_per_hour: u32) -> RateLimiter {
+        RateLimiter::with_period(requests_per_hour, 3600)
+    }
+
+    fn with_period(requests_per_period: u32, period_seconds: u32) -> RateLimiter {
+        RateLimiter {
+            requests_per_period: requests_per_period as f64,
+            period_seconds: period_seconds as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated_at).as_secs_f64();
+        let refilled = bucket.requests + elapsed * self.requests_per_period / self.period_seconds;
+        refilled.min(self.requests_per_period)
+    }
+
+    /// Count a request by the client at time `now`.
+    ///
+    /// Returns `Err` with the number of seconds until the client can make a
+    /// new request, if it exceeded its rate.
+    pub fn check(&self, client: Client, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&client) {
+            // A client with a full bucket is indistinguishable from one that we
+            // have never seen, so we can drop it without changing behavior.
+            let full = self.requests_per_period;
+            buckets.retain(|_, bucket| self.refill(bucket, now) < full);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            requests: self.requests_per_period,
+            updated_at: now,
+        });
+        bucket.requests = self.refill(bucket, now);
+        bucket.updated_at = now;
+
+        if bucket.requests >= 1.0 {
+            bucket.requests -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - bucket.requests;
+            let retry_after = missing * self.period_seconds / self.requests_per_period;
+            Err(retry_after.ceil() as u64)
+        }
+    }
+}
+
+/// Counts of requests that we rejected, for the status endpoint.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct LimitStatus {
+    /// Requests that got a 429 response because the client exceeded its rate.
+    pub rate_limited_requests: u64,
+
+    /// Requests that got a 413 response because the body was too large.
+    pub oversized_requests: u64,
+}
+
+/// Counters behind `LimitStatus`, shared between the server threads.
+#[derive(Default)]
+pub struct LimitCounters {
+    rate_limited_requests: AtomicU64,
+    oversized_requests: AtomicU64,
+}
+
+impl LimitCounters {
+    pub fn count_rate_limited(&self) {
+        self.rate_limited_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_oversized(&self) {
+        self.oversized_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_status(&self) -> LimitStatus {
+        LimitStatus {
+            rate_limited_requests: self.rate_limited_requests.load(Ordering::Relaxed),
+            oversized_requests: self.oversized_requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    use super::{max_body_len, Client, RateLimiter, MAX_BODY_LEN, MAX_UPLOAD_LEN};
+
+    #[test]
+    fn rate_limiter_allows_burst_then_refills() {
+        let limiter = RateLimiter::new(60);
+        let client = Client::Token("laptop".to_string());
+        let t0 = Instant::now();
+
+        for _ in 0..60 {
+            assert_eq!(limiter.check(client.clone(), t0), Ok(()));
+        }
+        assert_eq!(limiter.check(client.clone(), t0), Err(1));
+
+        // Other clients have their own budget.
+        let other = Client::Ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(limiter.check(other, t0), Ok(()));
+
+        // At 60 requests per minute, we get one request back per second.
+        let t1 = t0 + Duration::from_millis(1500);
+        assert_eq!(limiter.check(client.clone(), t1), Ok(()));
+        assert_eq!(limiter.check(client.clone(), t1), Err(1));
+    }
+
+    #[test]
+    fn rate_limiter_per_hour_refills_slowly() {
+        let limiter = RateLimiter::per_hour(10);
+        let client = Client::Token("guest".to_string());
+        let t0 = Instant::now();
+
+        for _ in 0..10 {
+            assert_eq!(limiter.check(client.clone(), t0), Ok(()));
+        }
+        assert_eq!(limiter.check(client.clone(), t0), Err(360));
+
+        let t1 = t0 + Duration::from_secs(360);
+        assert_eq!(limiter.check(client.clone(), t1), Ok(()));
+    }
+
+    #[test]
+    fn rate_limiter_forgets_idle_clients() {
+        let limiter = RateLimiter::new(600);
+        let t0 = Instant::now();
+        for i in 0..super::MAX_TRACKED_CLIENTS as u32 {
+            let ip = IpAddr::V4(Ipv4Addr::from(i));
+            assert_eq!(limiter.check(Client::Ip(ip), t0), Ok(()));
+        }
+        let t1 = t0 + Duration::from_secs(60);
+        let new_client = Client::Token("phone".to_string());
+        assert_eq!(limiter.check(new_client, t1), Ok(()));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn max_body_len_allows_large_playlist_imports_and_bulk_enqueues_only() {
+        assert_eq!(max_body_len(Some("api"), Some("playlists"), Some("import")), MAX_UPLOAD_LEN);
+        assert_eq!(max_body_len(Some("api"), Some("queue"), Some("tracks")), MAX_UPLOAD_LEN);
+        assert_eq!(max_body_len(Some("api"), Some("queue"), Some("shuffle")), MAX_BODY_LEN);
+        assert_eq!(max_body_len(Some("api"), Some("login"), None), MAX_BODY_LEN);
+        assert_eq!(max_body_len(Some("rest"), Some("createPlaylist"), None), MAX_BODY_LEN);
+    }
+}
@@ -0,0 +1,257 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Exporting the full listening history, as newline-delimited json or csv.
+//!
+//! The listens table is the most valuable data in the database, it cannot be
+//! recreated by a rescan. The export contains all columns, so it can serve as a
+//! backup, and as input for other tools.
+//!
+//! Exports can be large, so we write them as we read the rows. For the http
+//! endpoint, a separate thread produces the export into a pipe, and the
+//! response body reads from it.
+
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::database as db;
+use crate::database::{Connection, ExportListen, Transaction};
+use crate::database_utils;
+use crate::error::{Error, Result};
+use crate::pipe::{self, PipeReader};
+use crate::prim::{AlbumId, ArtistId, TrackId};
+
+/// The columns of the csv export, and the keys of the json export.
+const FIELDS: [&str; 18] = [
+    "id",
+    "started_at",
+    "completed_at",
+    "track_id",
+    "album_id",
+    "album_artist_id",
+    "track_title",
+    "track_artist",
+    "album_title",
+    "album_artist",
+    "duration_seconds",
+    "track_number",
+    "disc_number",
+    "source",
+    "client",
+    "scrobbled_at",
+    "recording_mbid",
+    "release_mbid",
+];
+
+/// The format to export listens in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// One json object per line.
+    Json,
+    /// Comma-separated values with a header, see RFC 4180.
+    Csv,
+}
+
+impl Format {
+    /// Parse the `format` query parameter, `json` or `csv`.
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    /// Pick the format based on the extension of the file name.
+    pub fn from_file_name(file_name: &str) -> Format {
+        if file_name.to_ascii_lowercase().ends_with(".csv") {
+            Format::Csv
+        } else {
+            Format::Json
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/x-ndjson",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Format::Json => "listens.ndjson",
+            Format::Csv => "listens.csv",
+        }
+    }
+}
+
+/// The values of the listen, formatted as strings, in the order of `FIELDS`.
+fn values(listen: &ExportListen) -> [Option<String>; 18] {
+    let opt_i64 = |x: Option<i64>| x.map(|n| n.to_string());
+    [
+        Some(listen.id.to_string()),
+        Some(listen.started_at.clone()),
+        listen.completed_at.clone(),
+        Some(TrackId(listen.track_id as u64).to_string()),
+        Some(AlbumId(listen.album_id as u64).to_string()),
+        Some(ArtistId(listen.album_artist_id as u64).to_string()),
+        Some(listen.track_title.clone()),
+        Some(listen.track_artist.clone()),
+        Some(listen.album_title.clone()),
+        Some(listen.album_artist.clone()),
+        Some(listen.duration_seconds.to_string()),
+        opt_i64(listen.track_number),
+        opt_i64(listen.disc_number),
+        Some(listen.source.clone()),
+        listen.client.clone(),
+        listen.scrobbled_at.clone(),
+        listen.recording_mbid.clone(),
+        listen.release_mbid.clone(),
+    ]
+}
+
+/// Write a csv field, quoted if needed.
+fn write_csv_field<W: Write>(mut w: W, value: &str) -> io::Result<()> {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        write!(w, "\"{}\"", value.replace('"', "\"\""))
+    } else {
+        write!(w, "{}", value)
+    }
+}
+
+fn write_csv_listen<W: Write>(mut w: W, listen: &ExportListen) -> io::Result<()> {
+    for (i, value) in values(listen).iter().enumerate() {
+        if i > 0 { write!(w, ",")?; }
+        // Null becomes the empty string, csv has no way to distinguish them.
+        write_csv_field(&mut w, value.as_deref().unwrap_or(""))?;
+    }
+    write!(w, "\r\n")
+}
+
+fn write_json_listen<W: Write>(mut w: W, listen: &ExportListen) -> io::Result<()> {
+    write!(w, "{{")?;
+    for (i, (key, value)) in FIELDS.iter().zip(values(listen).iter()).enumerate() {
+        if i > 0 { write!(w, ",")?; }
+        write!(w, r#""{}":"#, key)?;
+        match (*key, value) {
+            // Numbers are numbers, ids are strings, like in the api.
+            ("id", Some(v))
+            | ("duration_seconds", Some(v))
+            | ("track_number", Some(v))
+            | ("disc_number", Some(v)) => write!(w, "{}", v)?,
+            (_, v) => serde_json::to_writer(&mut w, v)?,
+        }
+    }
+    writeln!(w, "}}")
+}
+
+/// Write all listens, oldest first. Returns the number of listens written.
+pub fn write_listens<W: Write>(tx: &mut Transaction, format: Format, mut w: W) -> Result<u64> {
+    if format == Format::Csv {
+        write!(w, "{}\r\n", FIELDS.join(","))?;
+    }
+
+    let mut n = 0;
+    for opt_listen in db::iter_listens_for_export(tx)? {
+        let listen = opt_listen?;
+        match format {
+            Format::Json => write_json_listen(&mut w, &listen)?,
+            Format::Csv => write_csv_listen(&mut w, &listen)?,
+        }
+        n += 1;
+    }
+
+    w.flush()?;
+    Ok(n)
+}
+
+/// Export all listens on a background thread, return the reader for the export.
+///
+/// The thread opens its own read-only connection, so the export reads from a
+/// consistent snapshot, and does not hold up other requests.
+pub fn spawn_export(db_path: PathBuf, format: Format) -> PipeReader {
+    let (writer, reader) = pipe::pipe();
+
+    let builder = std::thread::Builder::new();
+    builder
+        .name("listen_export".into())
+        .spawn(move || {
+            let result = database_utils::connect_readonly(&db_path)
+                .map_err(Error::from)
+                .and_then(|connection| {
+                    let mut db = Connection::new(&connection);
+                    let mut tx = db.begin()?;
+                    let n = write_listens(&mut tx, format, writer)?;
+                    tx.commit()?;
+                    Ok(n)
+                });
+            // When this fails, the response is truncated. We can't change the
+            // status code any more at this point.
+            if let Err(err) = result {
+                error!("Error while exporting listens: {:?}", err);
+            }
+        })
+        .expect("Failed to spawn listen export thread.");
+
+    reader
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_csv_listen, write_json_listen};
+    use crate::database::ExportListen;
+
+    fn example_listen() -> ExportListen {
+        ExportListen {
+            id: 42,
+            started_at: "2023-06-01T18:00:00.000Z".to_string(),
+            completed_at: Some("2023-06-01T18:03:20.000Z".to_string()),
+            track_id: 0x0000_0000_0001_0102,
+            album_id: 0x0000_0000_0001,
+            album_artist_id: 0x0000_0000_0000_0007,
+            track_title: "Intro, \"Part 1\"".to_string(),
+            track_artist: "Artist".to_string(),
+            album_title: "Album".to_string(),
+            album_artist: "Artist".to_string(),
+            duration_seconds: 200,
+            track_number: Some(2),
+            disc_number: None,
+            source: "musium".to_string(),
+            client: None,
+            scrobbled_at: None,
+            recording_mbid: Some("b8d7e1f4-0000-4000-8000-000000000000".to_string()),
+            release_mbid: None,
+        }
+    }
+
+    #[test]
+    fn write_csv_listen_quotes_fields() {
+        let mut out = Vec::new();
+        write_csv_listen(&mut out, &example_listen()).unwrap();
+        let line = String::from_utf8(out).unwrap();
+        assert!(line.starts_with("42,2023-06-01T18:00:00.000Z,2023-06-01T18:03:20.000Z,"));
+        assert!(line.contains(r#","Intro, ""Part 1""",Artist,Album,Artist,200,2,,musium,,,b8d7e1f4"#));
+        assert!(line.ends_with(",\r\n"));
+    }
+
+    #[test]
+    fn write_json_listen_writes_one_line() {
+        let mut out = Vec::new();
+        write_json_listen(&mut out, &example_listen()).unwrap();
+        let line = String::from_utf8(out).unwrap();
+        assert!(line.ends_with("}\n"));
+        assert_eq!(line.matches('\n').count(), 1);
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["id"], 42);
+        assert_eq!(value["track_title"], "Intro, \"Part 1\"");
+        assert_eq!(value["disc_number"], serde_json::Value::Null);
+        assert_eq!(value["track_number"], 2);
+        assert_eq!(value["release_mbid"], serde_json::Value::Null);
+    }
+}
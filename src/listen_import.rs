@@ -0,0 +1,489 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Importing listening history from Last.fm and ListenBrainz exports.
+//!
+//! Listens are matched against the library, by MusicBrainz recording id when
+//! the export has one, and otherwise by title, artist, and album. Only matched
+//! listens are imported, because a listen needs a track id.
+
+use std::collections::HashMap;
+use std::mem;
+
+use chrono::NaiveDateTime;
+use serde_json::Value;
+
+use crate::database as db;
+use crate::database::Transaction;
+use crate::prim::Instant;
+use crate::string_utils::{equals_normalized, normalize_words};
+use crate::{MetaIndex, TrackId};
+
+/// The kind of export that we import from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// A CSV file with Last.fm scrobbles.
+    ///
+    /// Last.fm does not offer an export itself; we support the files produced
+    /// by lastfm.ghan.nl/export (with a header row) and by
+    /// benjaminbenben.com/lastfm-to-csv (artist, album, track, date).
+    LastFmCsv,
+
+    /// The JSON responses of `user.getRecentTracks`, one or more pages.
+    LastFmJson,
+
+    /// A ListenBrainz export, either a JSON array or one listen per line.
+    ListenBrainz,
+}
+
+impl Format {
+    /// Guess the format from the file name and its contents.
+    pub fn detect(file_name: &str, src: &str) -> Format {
+        if file_name.to_ascii_lowercase().ends_with(".csv") {
+            Format::LastFmCsv
+        } else if src.contains("\"listened_at\"") {
+            Format::ListenBrainz
+        } else {
+            Format::LastFmJson
+        }
+    }
+
+    /// The value for the `source` column of listens imported from this format.
+    pub fn source(&self) -> &'static str {
+        match self {
+            Format::LastFmCsv => "lastfm",
+            Format::LastFmJson => "lastfm",
+            Format::ListenBrainz => "listenbrainz",
+        }
+    }
+}
+
+/// A listen as recorded by an external service.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ExternalListen {
+    pub listened_at: Instant,
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+    pub recording_mbid: Option<String>,
+}
+
+/// Split CSV into records of fields, following the quoting rules of RFC 4180.
+fn parse_csv(src: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = src.trim_start_matches('\u{feff}').chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match (in_quotes, ch) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => in_quotes = false,
+            (true, _) => field.push(ch),
+            (false, '"') => in_quotes = true,
+            (false, ',') => record.push(mem::take(&mut field)),
+            (false, '\r') => continue,
+            (false, '\n') => {
+                record.push(mem::take(&mut field));
+                records.push(mem::take(&mut record));
+            }
+            (false, _) => field.push(ch),
+        }
+    }
+
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    // Skip blank lines.
+    records.retain(|r| !(r.len() == 1 && r[0].is_empty()));
+    records
+}
+
+/// Parse a date as formatted by the Last.fm CSV exporters, in UTC.
+fn parse_lastfm_date(date: &str) -> Option<Instant> {
+    let formats = ["%d %b %Y %H:%M", "%d %b %Y, %H:%M"];
+    formats
+        .iter()
+        .filter_map(|f| NaiveDateTime::parse_from_str(date.trim(), f).ok())
+        .map(|t| Instant { posix_seconds_utc: t.timestamp() })
+        .next()
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    if s.is_empty() { None } else { Some(s.to_string()) }
+}
+
+fn parse_lastfm_csv(src: &str) -> Result<Vec<ExternalListen>, String> {
+    let records = parse_csv(src);
+
+    let header: Option<&Vec<String>> = records
+        .first()
+        .filter(|r| r.iter().any(|col| col == "artist"));
+    let column = |names: &[&str]| -> Option<usize> {
+        header?.iter().position(|col| names.contains(&&col[..]))
+    };
+
+    let (rows, artist, album, title, uts, date, mbid) = match header {
+        Some(..) => (
+            &records[1..],
+            column(&["artist"]).ok_or("Header has no 'artist' column.")?,
+            column(&["album"]),
+            column(&["track", "title"]).ok_or("Header has no 'track' column.")?,
+            column(&["uts"]),
+            column(&["utc_time", "date"]),
+            column(&["track_mbid"]),
+        ),
+        None => (&records[..], 0, Some(1), 2, None, Some(3), None),
+    };
+
+    let mut result = Vec::with_capacity(rows.len());
+    for (i, row) in rows.iter().enumerate() {
+        let get = |col: usize| -> Result<&str, String> {
+            match row.get(col) {
+                Some(value) => Ok(&value[..]),
+                None => Err(format!("Record {} has only {} columns.", i + 1, row.len())),
+            }
+        };
+        let listened_at = match (uts, date) {
+            (Some(col), _) => get(col)?.parse().ok().map(|t| Instant { posix_seconds_utc: t }),
+            (None, Some(col)) => parse_lastfm_date(get(col)?),
+            (None, None) => return Err("Header has no 'uts' or 'utc_time' column.".to_string()),
+        };
+        let listened_at = match listened_at {
+            Some(t) => t,
+            None => return Err(format!("Record {} has an invalid date.", i + 1)),
+        };
+        let listen = ExternalListen {
+            listened_at: listened_at,
+            artist: get(artist)?.to_string(),
+            title: get(title)?.to_string(),
+            album: match album {
+                Some(col) => non_empty(get(col)?),
+                None => None,
+            },
+            recording_mbid: match mbid {
+                Some(col) => non_empty(get(col)?),
+                None => None,
+            },
+        };
+        result.push(listen);
+    }
+
+    Ok(result)
+}
+
+/// Return the name in a Last.fm JSON value, which can be a plain string, or an
+/// object with the name in `#text` or `name`.
+fn lastfm_json_text(v: &Value) -> Option<&str> {
+    match v {
+        Value::String(s) => Some(s.as_str()),
+        _ => v.get("#text").or_else(|| v.get("name"))?.as_str(),
+    }
+}
+
+fn parse_lastfm_json_track(v: &Value) -> Option<ExternalListen> {
+    // The track that is playing right now has no date, we skip it.
+    let uts: i64 = match v.get("date")?.get("uts")? {
+        Value::String(s) => s.parse().ok()?,
+        other => other.as_i64()?,
+    };
+    let listen = ExternalListen {
+        listened_at: Instant { posix_seconds_utc: uts },
+        artist: lastfm_json_text(v.get("artist")?)?.to_string(),
+        title: v.get("name")?.as_str()?.to_string(),
+        album: v.get("album").and_then(lastfm_json_text).and_then(non_empty),
+        recording_mbid: v.get("mbid").and_then(|m| m.as_str()).and_then(non_empty),
+    };
+    Some(listen)
+}
+
+/// Collect the tracks from a `user.getRecentTracks` response, or from an array
+/// of responses, or from an array of tracks.
+fn collect_lastfm_json(v: &Value, into: &mut Vec<ExternalListen>) {
+    match v {
+        Value::Array(xs) => {
+            for x in xs {
+                collect_lastfm_json(x, into);
+            }
+        }
+        Value::Object(obj) => match obj.get("recenttracks") {
+            Some(page) => {
+                if let Some(tracks) = page.get("track") {
+                    collect_lastfm_json(tracks, into);
+                }
+            }
+            None => into.extend(parse_lastfm_json_track(v)),
+        },
+        _ => {}
+    }
+}
+
+fn parse_listenbrainz_listen(v: &Value) -> Option<ExternalListen> {
+    let meta = v.get("track_metadata")?;
+    // Newer exports include the mapping that ListenBrainz made, older ones
+    // may have the id that the submitter included.
+    let mbid = meta
+        .get("mbid_mapping")
+        .and_then(|m| m.get("recording_mbid"))
+        .or_else(|| meta.get("additional_info")?.get("recording_mbid"))
+        .and_then(|m| m.as_str())
+        .and_then(non_empty);
+    let listen = ExternalListen {
+        listened_at: Instant { posix_seconds_utc: v.get("listened_at")?.as_i64()? },
+        artist: meta.get("artist_name")?.as_str()?.to_string(),
+        title: meta.get("track_name")?.as_str()?.to_string(),
+        album: meta.get("release_name").and_then(|a| a.as_str()).and_then(non_empty),
+        recording_mbid: mbid,
+    };
+    Some(listen)
+}
+
+fn parse_listenbrainz(src: &str) -> Result<Vec<ExternalListen>, String> {
+    let src = src.trim_start_matches('\u{feff}').trim_start();
+    let mut result = Vec::new();
+
+    if src.starts_with('[') {
+        let listens: Vec<Value> = serde_json::from_str(src)
+            .map_err(|err| format!("Invalid JSON: {}", err))?;
+        result.extend(listens.iter().filter_map(parse_listenbrainz_listen));
+    } else {
+        // The JSON Lines format, one listen per line.
+        for (lineno, line) in src.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let listen: Value = serde_json::from_str(line)
+                .map_err(|err| format!("Invalid JSON on line {}: {}", lineno + 1, err))?;
+            result.extend(parse_listenbrainz_listen(&listen));
+        }
+    }
+
+    Ok(result)
+}
+
+/// Parse the listens in an export.
+///
+/// Entries that lack a time, artist, or title are skipped.
+pub fn parse(format: Format, src: &str) -> Result<Vec<ExternalListen>, String> {
+    match format {
+        Format::LastFmCsv => parse_lastfm_csv(src),
+        Format::LastFmJson => {
+            let value: Value = serde_json::from_str(src.trim_start_matches('\u{feff}'))
+                .map_err(|err| format!("Invalid JSON: {}", err))?;
+            let mut result = Vec::new();
+            collect_lastfm_json(&value, &mut result);
+            Ok(result)
+        }
+        Format::ListenBrainz => parse_listenbrainz(src),
+    }
+}
+
+/// Matches external listens against tracks in the library.
+pub struct Matcher<'a> {
+    index: &'a dyn MetaIndex,
+    by_mbid: HashMap<String, TrackId>,
+}
+
+impl<'a> Matcher<'a> {
+    /// Build a matcher, with the recording ids from the tags in the database.
+    pub fn new(tx: &mut Transaction, index: &'a dyn MetaIndex) -> db::Result<Matcher<'a>> {
+        let mut by_file_id = HashMap::new();
+        for kv in index.get_tracks() {
+            by_file_id.insert(kv.track.file_id.0, kv.track_id);
+        }
+
+        // Despite its name, the MUSICBRAINZ_TRACKID tag that Picard writes
+        // holds the recording id, which is what Last.fm and ListenBrainz use.
+        let mut by_mbid = HashMap::new();
+        for row in db::iter_tag_values(tx, "musicbrainz_trackid")? {
+            let (file_id, mbid) = row?;
+            if let Some(&track_id) = by_file_id.get(&file_id) {
+                by_mbid.insert(mbid, track_id);
+            }
+        }
+
+        let result = Matcher {
+            index: index,
+            by_mbid: by_mbid,
+        };
+        Ok(result)
+    }
+
+    /// Find the track for the listen.
+    ///
+    /// When the title and artist match tracks on several albums, we prefer the
+    /// one on the album of the listen, but if none of them match the album, we
+    /// still take the first one: it is the same song on a different release.
+    pub fn find(&self, listen: &ExternalListen) -> Option<TrackId> {
+        if let Some(mbid) = listen.recording_mbid.as_ref() {
+            if let Some(&track_id) = self.by_mbid.get(mbid) {
+                return Some(track_id);
+            }
+        }
+
+        let mut words = Vec::new();
+        normalize_words(&listen.title, &mut words);
+        let mut candidates = Vec::new();
+        // We want exact matches here, so do not tolerate typos.
+        self.index.search_track(&words[..], 0, &mut candidates);
+
+        let mut best = None;
+
+        for track_id in candidates {
+            let track = self.index.get_track(track_id).expect("Search result should be in index.");
+            let title_ok = equals_normalized(self.index.get_string(track.title), &listen.title);
+            let artist_ok = equals_normalized(self.index.get_string(track.artist), &listen.artist);
+            if !(title_ok && artist_ok) {
+                continue;
+            }
+            let album = self.index.get_album(track_id.album_id()).expect("Track album should be in index.");
+            let album_ok = match listen.album.as_ref() {
+                Some(title) => equals_normalized(self.index.get_string(album.title), title),
+                None => false,
+            };
+            if album_ok {
+                return Some(track_id);
+            }
+            best = best.or(Some(track_id));
+        }
+
+        best
+    }
+}
+
+/// Record the listen of the track in the database, return the listen id.
+///
+/// The `source` is for example `lastfm` for an import, or `subsonic` for a
+/// listen reported by a Subsonic client. Returns `None` if we already had a
+/// listen that started at the same second.
+pub fn insert_listen(
+    tx: &mut Transaction,
+    index: &dyn MetaIndex,
+    source: &str,
+    listened_at: Instant,
+    track_id: TrackId,
+) -> db::Result<Option<i64>> {
+    let track = index.get_track(track_id).expect("Matched track should be in index.");
+    let album = index.get_album(track_id.album_id()).expect("Track album should be in index.");
+    let album_artists = index.get_album_artists(album.artist_ids);
+
+    // External sources only report when the listen started, we assume that
+    // the track played to completion. The completion time must be later than
+    // the start.
+    let completed_at = Instant {
+        posix_seconds_utc: listened_at.posix_seconds_utc + (track.duration_seconds as i64).max(1),
+    };
+
+    let row = db::ImportedListen {
+        started_at: &listened_at.format_iso8601(),
+        completed_at: &completed_at.format_iso8601(),
+        file_id: track.file_id.0,
+        track_id: track_id.0 as i64,
+        album_id: track_id.album_id().0 as i64,
+        // We record only the first album artist, like the history thread.
+        album_artist_id: album_artists[0].0 as i64,
+        track_title: index.get_string(track.title),
+        track_artist: index.get_string(track.artist),
+        album_title: index.get_string(album.title),
+        album_artist: index.get_string(album.artist),
+        duration_seconds: track.duration_seconds as i64,
+        track_number: track_id.track_number() as i64,
+        disc_number: track_id.disc_number() as i64,
+        // Exports record the time in UTC, so we assume that the listen
+        // happened in the reporting time zone.
+        utc_offset_seconds: listened_at.local_utc_offset_seconds(),
+        source: source,
+    };
+
+    db::insert_listen_imported(tx, row)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, parse_csv, ExternalListen, Format};
+    use crate::prim::Instant;
+
+    #[test]
+    fn parse_csv_handles_quotes() {
+        let src = "a,\"b, \"\"c\"\"\"\r\n\nd,\"e\nf\"\n";
+        assert_eq!(
+            parse_csv(src),
+            vec![
+                vec!["a".to_string(), "b, \"c\"".to_string()],
+                vec!["d".to_string(), "e\nf".to_string()],
+            ],
+        );
+    }
+
+    #[test]
+    fn parse_lastfm_csv_without_header() {
+        let src = "Muse,Absolution,Hysteria,31 Jan 2021 12:34\n";
+        let listens = parse(Format::LastFmCsv, src).unwrap();
+        assert_eq!(
+            listens,
+            vec![ExternalListen {
+                listened_at: Instant { posix_seconds_utc: 1612096440 },
+                artist: "Muse".to_string(),
+                title: "Hysteria".to_string(),
+                album: Some("Absolution".to_string()),
+                recording_mbid: None,
+            }],
+        );
+    }
+
+    #[test]
+    fn parse_lastfm_csv_with_header() {
+        let src = "\
+uts,utc_time,artist,artist_mbid,album,album_mbid,track,track_mbid
+1612096440,\"31 Jan 2021, 12:34\",Muse,,,,Hysteria,abc-123
+";
+        let listens = parse(Format::LastFmCsv, src).unwrap();
+        assert_eq!(
+            listens,
+            vec![ExternalListen {
+                listened_at: Instant { posix_seconds_utc: 1612096440 },
+                artist: "Muse".to_string(),
+                title: "Hysteria".to_string(),
+                album: None,
+                recording_mbid: Some("abc-123".to_string()),
+            }],
+        );
+    }
+
+    #[test]
+    fn parse_lastfm_json_skips_now_playing() {
+        let src = r##"[{"recenttracks": {"track": [
+            {"artist": {"#text": "Muse"}, "name": "Hysteria", "mbid": "",
+             "album": {"#text": "Absolution"}, "@attr": {"nowplaying": "true"}},
+            {"artist": {"#text": "Muse"}, "name": "Hysteria", "mbid": "",
+             "album": {"#text": "Absolution"}, "date": {"uts": "1612096440"}}
+        ]}}]"##;
+        let listens = parse(Format::LastFmJson, src).unwrap();
+        assert_eq!(listens.len(), 1);
+        assert_eq!(listens[0].listened_at.posix_seconds_utc, 1612096440);
+        assert_eq!(listens[0].album, Some("Absolution".to_string()));
+    }
+
+    #[test]
+    fn parse_listenbrainz_json_lines() {
+        let src = r#"
+{"listened_at": 1612096440, "track_metadata": {"artist_name": "Muse", "track_name": "Hysteria", "release_name": "Absolution", "mbid_mapping": {"recording_mbid": "abc-123"}}}
+{"listened_at": 1612096740, "track_metadata": {"artist_name": "Muse", "track_name": "Time Is Running Out"}}
+"#;
+        assert_eq!(Format::detect("listens.jsonl", src), Format::ListenBrainz);
+        let listens = parse(Format::ListenBrainz, src).unwrap();
+        assert_eq!(listens.len(), 2);
+        assert_eq!(listens[0].recording_mbid, Some("abc-123".to_string()));
+        assert_eq!(listens[1].album, None);
+    }
+}
@@ -0,0 +1,293 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Finding and repairing damaged listens in the `listens` table.
+//!
+//! There are two kinds of damage that we can repair:
+//!
+//! * Duplicates: the same track started twice within a few seconds. This
+//!   happens when an import overlaps with listens that we recorded ourselves,
+//!   but the service recorded a slightly different start time, so the unique
+//!   index on the second of `started_at` does not catch them.
+//! * Orphans: listens that started, but never got a completion time, because
+//!   Musium was stopped or crashed before the history thread recorded it.
+//!
+//! Repairing happens in two steps: first we plan the changes, which does not
+//! touch the database, and then we apply them. This enables a dry run.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+
+use crate::database as db;
+use crate::database::{RepairListen, Transaction};
+
+/// Listens of the same track that start at most this many seconds apart are duplicates.
+pub const DUPLICATE_WINDOW_SECONDS: i64 = 5;
+
+/// Merge a duplicate listen into the listen that we keep.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Merge {
+    pub keep_id: i64,
+    pub drop_id: i64,
+    pub track_id: i64,
+    pub started_at: String,
+    /// Completion time to copy from the duplicate, if the kept listen has none.
+    pub completed_at: Option<String>,
+    /// Scrobble time to copy from the duplicate, if the kept listen has none.
+    pub scrobbled_at: Option<String>,
+}
+
+/// Set the completion time of an orphaned listen.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Completion {
+    pub listen_id: i64,
+    pub started_at: String,
+    pub completed_at: String,
+}
+
+/// Return whether time `t` is strictly after `reference`, both RFC 3339.
+///
+/// The table has check constraints that require this of the completion and
+/// scrobble times. Returns false if either time fails to parse.
+fn is_after(t: &str, reference: &str) -> bool {
+    match (DateTime::parse_from_rfc3339(t), DateTime::parse_from_rfc3339(reference)) {
+        (Ok(t), Ok(reference)) => t > reference,
+        _ => false,
+    }
+}
+
+/// Return whether we would rather keep listen `a` than listen `b`.
+///
+/// Listens that we recorded ourselves have more details than imported ones,
+/// such as the queue id and file id. Otherwise, the oldest listen wins.
+fn is_preferred(a: &RepairListen, b: &RepairListen) -> bool {
+    match (a.source == "musium", b.source == "musium") {
+        (true, false) => true,
+        (false, true) => false,
+        _ => a.id < b.id,
+    }
+}
+
+/// Merge listen `drop` into listen `keep`, in memory, and return the merge.
+fn merge(keep: &mut RepairListen, drop: &RepairListen) -> Merge {
+    let mut result = Merge {
+        keep_id: keep.id,
+        drop_id: drop.id,
+        track_id: keep.track_id,
+        started_at: drop.started_at.clone(),
+        completed_at: None,
+        scrobbled_at: None,
+    };
+
+    if let (None, Some(t)) = (&keep.completed_at, &drop.completed_at) {
+        if is_after(t, &keep.started_at) {
+            result.completed_at = Some(t.clone());
+            keep.completed_at = Some(t.clone());
+        }
+    }
+    if let (None, Some(t)) = (&keep.scrobbled_at, &drop.scrobbled_at) {
+        if is_after(t, &keep.started_at) {
+            result.scrobbled_at = Some(t.clone());
+            keep.scrobbled_at = Some(t.clone());
+        }
+    }
+
+    // Skips of the duplicate move to the listen that we keep.
+    keep.is_skipped = keep.is_skipped.max(drop.is_skipped);
+
+    result
+}
+
+/// Find duplicate listens, merge them in memory, and remove them from `listens`.
+///
+/// The listens must be ordered by start time. Returns the merges to apply to
+/// the database, in the order in which they must be applied.
+pub fn find_duplicates(listens: &mut Vec<RepairListen>) -> Vec<Merge> {
+    let mut merges = Vec::new();
+    let mut dropped = vec![false; listens.len()];
+
+    // For every track, the index of its most recent listen that we keep.
+    let mut last_listen: HashMap<i64, usize> = HashMap::new();
+
+    for i in 0..listens.len() {
+        let track_id = listens[i].track_id;
+        if let Some(&j) = last_listen.get(&track_id) {
+            let delta = listens[i].started_at_seconds - listens[j].started_at_seconds;
+            if delta <= DUPLICATE_WINDOW_SECONDS {
+                // We have j < i, so we can split between them to borrow both.
+                let (before, after) = listens.split_at_mut(i);
+                let (keep, drop, keep_index, drop_index) = if is_preferred(&after[0], &before[j]) {
+                    (&mut after[0], &before[j], i, j)
+                } else {
+                    (&mut before[j], &after[0], j, i)
+                };
+                merges.push(merge(keep, drop));
+                dropped[drop_index] = true;
+                last_listen.insert(track_id, keep_index);
+                continue;
+            }
+        }
+        last_listen.insert(track_id, i);
+    }
+
+    let mut i = 0;
+    listens.retain(|_| {
+        let keep = !dropped[i];
+        i += 1;
+        keep
+    });
+
+    merges
+}
+
+/// Find listens that Musium recorded, that did not complete, and were not skipped.
+///
+/// We only fill in the completion time when playback could have completed:
+/// when the next listen that Musium recorded started after the track would
+/// have ended. If it started earlier, playback was cut short, and the listen
+/// remains incomplete. Listens that could still be playing at `now_seconds`
+/// are left alone. The listens must be ordered by start time.
+pub fn find_orphans(listens: &[RepairListen], now_seconds: i64) -> Vec<Completion> {
+    let mut result = Vec::new();
+    let mut next_start = now_seconds;
+
+    // Walk backwards, so we know when the next listen started.
+    for listen in listens.iter().rev() {
+        if listen.source != "musium" {
+            continue;
+        }
+
+        let end = listen.started_at_seconds + listen.duration_seconds;
+        let is_orphan = listen.completed_at.is_none()
+            && listen.is_skipped == 0
+            && listen.duration_seconds > 0
+            && end <= next_start;
+        next_start = listen.started_at_seconds;
+
+        if !is_orphan {
+            continue;
+        }
+
+        let started_at = match DateTime::parse_from_rfc3339(&listen.started_at) {
+            Ok(t) => t.with_timezone(&Utc),
+            Err(..) => continue,
+        };
+        let completed_at = started_at + Duration::seconds(listen.duration_seconds);
+        let use_zulu_suffix = true;
+        result.push(Completion {
+            listen_id: listen.id,
+            started_at: listen.started_at.clone(),
+            completed_at: completed_at.to_rfc3339_opts(SecondsFormat::Millis, use_zulu_suffix),
+        });
+    }
+
+    result.reverse();
+    result
+}
+
+/// Load all listens, ordered by start time.
+pub fn load_listens(tx: &mut Transaction) -> db::Result<Vec<RepairListen>> {
+    db::iter_listens_for_repair(tx)?.collect()
+}
+
+/// Apply the merges and completions to the database.
+pub fn apply(
+    tx: &mut Transaction,
+    merges: &[Merge],
+    completions: &[Completion],
+) -> db::Result<()> {
+    for m in merges {
+        db::update_listen_merge(
+            tx,
+            m.keep_id,
+            m.completed_at.as_deref(),
+            m.scrobbled_at.as_deref(),
+        )?;
+        db::update_listen_client_listen(tx, m.drop_id, m.keep_id)?;
+        db::delete_listen_client(tx, m.drop_id)?;
+        db::update_listen_user_listen(tx, m.drop_id, m.keep_id)?;
+        db::delete_listen_user(tx, m.drop_id)?;
+        db::update_skips_listen(tx, m.drop_id, m.keep_id)?;
+        db::delete_listen(tx, m.drop_id)?;
+    }
+
+    // Deleting a listen also deletes it as a first listen, and then we may
+    // not have one for its track any more.
+    if !merges.is_empty() {
+        db::rebuild_first_listens(tx)?;
+    }
+
+    for c in completions {
+        db::update_listen_completed_at(tx, c.listen_id, &c.completed_at)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_duplicates, find_orphans};
+    use crate::database::RepairListen;
+
+    fn listen(id: i64, started_at_seconds: i64, track_id: i64, source: &str) -> RepairListen {
+        let started_at = chrono::NaiveDateTime::from_timestamp(started_at_seconds, 0);
+        RepairListen {
+            id: id,
+            started_at: format!("{}Z", started_at.format("%Y-%m-%dT%H:%M:%S%.3f")),
+            started_at_seconds: started_at_seconds,
+            completed_at: None,
+            track_id: track_id,
+            duration_seconds: 100,
+            source: source.to_string(),
+            scrobbled_at: None,
+            is_skipped: 0,
+        }
+    }
+
+    #[test]
+    fn find_duplicates_prefers_own_listens() {
+        let mut imported = listen(2, 1_000_001, 7, "lastfm");
+        imported.completed_at = Some("1970-01-12T13:48:21.000Z".to_string());
+        let mut listens = vec![
+            listen(1, 1_000_000, 7, "musium"),
+            imported,
+            // Same track, but much later, this is a real second listen.
+            listen(3, 1_000_200, 7, "musium"),
+            // A different track at the same time is not a duplicate.
+            listen(4, 1_000_200, 8, "musium"),
+        ];
+        let merges = find_duplicates(&mut listens);
+        assert_eq!(merges.len(), 1);
+        assert_eq!(merges[0].keep_id, 1);
+        assert_eq!(merges[0].drop_id, 2);
+        assert_eq!(merges[0].completed_at.as_deref(), Some("1970-01-12T13:48:21.000Z"));
+        assert_eq!(listens.iter().map(|l| l.id).collect::<Vec<_>>(), vec![1, 3, 4]);
+        assert!(listens[0].completed_at.is_some());
+    }
+
+    #[test]
+    fn find_orphans_skips_interrupted_and_skipped_listens() {
+        let mut skipped = listen(3, 1_000_250, 9, "musium");
+        skipped.is_skipped = 1;
+        let listens = vec![
+            // Next listen starts after this one would have ended, an orphan.
+            listen(1, 1_000_000, 7, "musium"),
+            // Next listen starts halfway, playback was interrupted.
+            listen(2, 1_000_200, 8, "musium"),
+            skipped,
+            // May still be playing.
+            listen(4, 1_000_600, 10, "musium"),
+            // Imported listens are not ours to complete.
+            listen(5, 1_000_700, 11, "lastfm"),
+        ];
+        let completions = find_orphans(&listens, 1_000_650);
+        assert_eq!(completions.len(), 1);
+        assert_eq!(completions[0].listen_id, 1);
+        assert_eq!(completions[0].completed_at, "1970-01-12T13:48:20.000Z");
+    }
+}
@@ -0,0 +1,851 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Querying the listening history in the `listens` table.
+//!
+//! Listens are returned newest first. Rather than an offset, pages are
+//! addressed with a cursor, so that listens which get added while a client is
+//! paging through the history do not shift the pages.
+//!
+//! This module also computes statistics over the listens in a time range, such
+//! as the most played artists, albums, and tracks, and the summaries for the
+//! home page: what we listened to on this day in earlier years, the yearly
+//! rewind, weekly and monthly charts, discoveries, and the albums that we
+//! listened to partway.
+
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, Local, NaiveDate, TimeZone};
+
+use crate::database as db;
+use crate::database::Transaction;
+use crate::prim::{AlbumId, ArtistId, Instant, TrackId};
+use crate::MetaIndex;
+
+/// Albums that we last listened to longer ago than this are not in progress.
+const IN_PROGRESS_WINDOW_SECONDS: i64 = 90 * 24 * 3600;
+
+/// Listens of an album this close before its last listen, are the same sitting.
+const SITTING_SECONDS: i64 = 6 * 3600;
+
+/// Parse an RFC 3339 timestamp, or a date, which means midnight UTC.
+pub fn parse_time(s: &str) -> Option<Instant> {
+    if let Some(t) = Instant::from_iso8601(s) {
+        return Some(t);
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+    let t = Instant { posix_seconds_utc: date.and_hms(0, 0, 0).timestamp() };
+    Some(t)
+}
+
+/// Parse a client name, as chosen by the client that enqueued a track.
+///
+/// Client names are non-empty and at most 64 bytes, surrounding whitespace is
+/// not part of the name.
+pub fn parse_client(s: &str) -> Option<String> {
+    let name = s.trim();
+    if name.is_empty() || name.len() > 64 {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// How much of a track needs to play for its listen to count.
+///
+/// A listen counts when the track played for at least `percent` of its
+/// duration, or for `seconds`, whichever comes first. Listens that fall short
+/// are samples: we keep them, but they are not scrobbled, and the statistics
+/// report them separately.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ListenThreshold {
+    pub percent: u32,
+    pub seconds: u32,
+}
+
+impl ListenThreshold {
+    /// Return whether a listen that played for `played_seconds` counts.
+    pub fn is_counted(&self, duration_seconds: u32, played_seconds: u32) -> bool {
+        let min_seconds = (duration_seconds as u64 * self.percent as u64 / 100).min(self.seconds as u64);
+        played_seconds as u64 >= min_seconds
+    }
+}
+
+/// The parameters of a listens request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ListenParams {
+    /// Only include listens that started at or after this instant.
+    pub since: Option<Instant>,
+    /// Only include listens that started before this instant.
+    pub until: Option<Instant>,
+    /// Only include listens of albums by this (first) album artist.
+    pub artist: Option<ArtistId>,
+    /// Only include listens of this album.
+    pub album: Option<AlbumId>,
+    /// Only include listens of tracks enqueued by this client.
+    pub client: Option<String>,
+    /// The maximum number of listens to return.
+    pub limit: usize,
+    /// Continue after the listen that started at this second.
+    pub cursor: Option<i64>,
+}
+
+impl ListenParams {
+    /// Parse the `since`, `until`, `artist`, `album`, `client`, `limit`, and
+    /// `cursor` parameters.
+    ///
+    /// All parameters are optional, by default we return 100 listens.
+    pub fn parse(raw_query: &str) -> Result<ListenParams, &'static str> {
+        let mut params = ListenParams {
+            since: None,
+            until: None,
+            artist: None,
+            album: None,
+            client: None,
+            limit: 100,
+            cursor: None,
+        };
+
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "since" => match parse_time(v.as_ref()) {
+                    Some(t) => params.since = Some(t),
+                    None => return Err("Invalid since, must be an RFC 3339 timestamp or a date."),
+                }
+                "until" => match parse_time(v.as_ref()) {
+                    Some(t) => params.until = Some(t),
+                    None => return Err("Invalid until, must be an RFC 3339 timestamp or a date."),
+                }
+                "artist" => match ArtistId::parse(v.as_ref()) {
+                    Some(id) => params.artist = Some(id),
+                    None => return Err("Invalid artist id."),
+                }
+                "album" => match AlbumId::parse(v.as_ref()) {
+                    Some(id) => params.album = Some(id),
+                    None => return Err("Invalid album id."),
+                }
+                "client" => match parse_client(v.as_ref()) {
+                    Some(name) => params.client = Some(name),
+                    None => return Err("Invalid client, must be a name of 1 to 64 bytes."),
+                }
+                "limit" => match usize::from_str(v.as_ref()) {
+                    Ok(n) if (1..=1000).contains(&n) => params.limit = n,
+                    _ => return Err("Invalid limit, must be an integer from 1 to 1000."),
+                }
+                "cursor" => match i64::from_str(v.as_ref()) {
+                    Ok(c) => params.cursor = Some(c),
+                    Err(_) => return Err("Invalid cursor."),
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+/// Return one page of listens of the user, and the cursor for the next page, if any.
+pub fn get_page(
+    tx: &mut Transaction,
+    params: &ListenParams,
+    user: Option<&str>,
+) -> db::Result<(Vec<db::ListenRow>, Option<i64>)> {
+    let since = params.since.map(|t| t.posix_seconds_utc).unwrap_or(i64::MIN);
+    let until = params.until.map(|t| t.posix_seconds_utc).unwrap_or(i64::MAX);
+    // The cursor is the start of the last listen on the previous page, this
+    // page continues with the listens before it.
+    let until = match params.cursor {
+        Some(cursor) => until.min(cursor),
+        None => until,
+    };
+
+    // Fetch one more than requested, to know whether there is a next page.
+    let mut listens = db::iter_listens_page(
+        tx,
+        since,
+        until,
+        params.album.map(|id| id.0 as i64),
+        params.artist.map(|id| id.0 as i64),
+        params.client.as_deref(),
+        user,
+        params.limit as i64 + 1,
+    )?.collect::<db::Result<Vec<db::ListenRow>>>()?;
+
+    let next_cursor = if listens.len() > params.limit {
+        listens.truncate(params.limit);
+        listens.last().map(|listen| listen.started_at_seconds)
+    } else {
+        None
+    };
+
+    Ok((listens, next_cursor))
+}
+
+/// The parameters of a listening statistics request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatsParams {
+    /// Only include listens that started at or after this instant.
+    pub since: Option<Instant>,
+    /// Only include listens that started before this instant.
+    pub until: Option<Instant>,
+    /// Only include listens of tracks enqueued by this client.
+    pub client: Option<String>,
+    /// The number of entries in a top list.
+    pub limit: usize,
+}
+
+impl StatsParams {
+    /// Parse the `since`, `until`, `client`, and `limit` parameters.
+    ///
+    /// All parameters are optional, by default the statistics cover all
+    /// listens, and top lists have 10 entries.
+    pub fn parse(raw_query: &str) -> Result<StatsParams, &'static str> {
+        let mut params = StatsParams {
+            since: None,
+            until: None,
+            client: None,
+            limit: 10,
+        };
+
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "since" => match parse_time(v.as_ref()) {
+                    Some(t) => params.since = Some(t),
+                    None => return Err("Invalid since, must be an RFC 3339 timestamp or a date."),
+                }
+                "until" => match parse_time(v.as_ref()) {
+                    Some(t) => params.until = Some(t),
+                    None => return Err("Invalid until, must be an RFC 3339 timestamp or a date."),
+                }
+                "client" => match parse_client(v.as_ref()) {
+                    Some(name) => params.client = Some(name),
+                    None => return Err("Invalid client, must be a name of 1 to 64 bytes."),
+                }
+                "limit" => match usize::from_str(v.as_ref()) {
+                    Ok(n) if (1..=100).contains(&n) => params.limit = n,
+                    _ => return Err("Invalid limit, must be an integer from 1 to 100."),
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(params)
+    }
+
+    /// Return the time range as posix seconds, including the start, excluding the end.
+    pub fn range(&self) -> (i64, i64) {
+        (
+            self.since.map(|t| t.posix_seconds_utc).unwrap_or(i64::MIN),
+            self.until.map(|t| t.posix_seconds_utc).unwrap_or(i64::MAX),
+        )
+    }
+}
+
+/// Count listens of the user per day of the week and hour of the day.
+///
+/// The outer index is the day of the week, where 0 is Sunday, the inner index
+/// is the hour, both in the local time of the listen.
+pub fn get_weekday_hour_counts(
+    tx: &mut Transaction,
+    params: &StatsParams,
+    user: Option<&str>,
+) -> db::Result<[[i64; 24]; 7]> {
+    let (since, until) = params.range();
+    let mut result = [[0; 24]; 7];
+    let client = params.client.as_deref();
+    for row in db::iter_listens_per_weekday_hour(tx, since, until, client, user)? {
+        let (weekday, hour, count) = row?;
+        result[weekday as usize][hour as usize] = count;
+    }
+    Ok(result)
+}
+
+/// Return the current year, in the reporting time zone.
+pub fn current_year() -> i32 {
+    Local::now().year()
+}
+
+/// Return today's date, in the reporting time zone.
+pub fn today() -> NaiveDate {
+    Local::now().naive_local().date()
+}
+
+/// Return the posix time of the start of the day, in the reporting time zone.
+fn local_midnight_seconds(date: NaiveDate) -> i64 {
+    let midnight = date.and_hms(0, 0, 0);
+    match Local.from_local_datetime(&midnight).earliest() {
+        Some(t) => t.timestamp(),
+        // Midnight does not exist when daylight saving time starts at
+        // midnight, then we are off by an hour, which is fine for our purpose.
+        None => midnight.timestamp(),
+    }
+}
+
+/// The albums listened to in one year, on the same day of the year.
+pub struct OnThisDay {
+    pub year: i64,
+    pub albums: Vec<db::DayOfYearAlbum>,
+}
+
+/// Return the albums the user listened to on the day of the year of `date`, in
+/// earlier years, most recent year first, with at most `albums_per_year` per year.
+pub fn get_on_this_day(
+    tx: &mut Transaction,
+    date: NaiveDate,
+    albums_per_year: usize,
+    user: Option<&str>,
+) -> db::Result<Vec<OnThisDay>> {
+    let month_day = date.format("%m-%d").to_string();
+    let mut result: Vec<OnThisDay> = Vec::new();
+
+    // Rows are ordered by year, and then by listen count.
+    for row in db::iter_listens_on_day_of_year(tx, &month_day, date.year() as i64, user)? {
+        let album = row?;
+        match result.last_mut() {
+            Some(day) if day.year == album.year => {
+                if day.albums.len() < albums_per_year {
+                    day.albums.push(album);
+                }
+            }
+            _ => result.push(OnThisDay {
+                year: album.year,
+                albums: vec![album],
+            }),
+        }
+    }
+
+    Ok(result)
+}
+
+/// A summary of a year of listening.
+pub struct Rewind {
+    pub year: i32,
+    pub listen_count: i64,
+    pub listen_seconds: i64,
+    pub most_played_album: Option<db::TopAlbum>,
+    /// Albums that we listened to for the first time this year, most played first.
+    pub discoveries: Vec<db::AlbumDiscovery>,
+}
+
+/// Summarize the listens of the user in the given year, in the reporting time zone.
+pub fn get_rewind(tx: &mut Transaction, year: i32, user: Option<&str>) -> db::Result<Rewind> {
+    let since = local_midnight_seconds(NaiveDate::from_ymd(year, 1, 1));
+    let until = local_midnight_seconds(NaiveDate::from_ymd(year + 1, 1, 1));
+
+    let (listen_count, listen_seconds) = db::select_listen_totals(tx, since, until, None, user)?;
+    let most_played_album = db::iter_top_albums(tx, since, until, None, user, 1)?.next().transpose()?;
+    let discoveries = db::iter_album_discoveries(tx, since, until, user, 10)?
+        .collect::<db::Result<Vec<db::AlbumDiscovery>>>()?;
+
+    let result = Rewind {
+        year: year,
+        listen_count: listen_count,
+        listen_seconds: listen_seconds,
+        most_played_album: most_played_album,
+        discoveries: discoveries,
+    };
+    Ok(result)
+}
+
+/// The length of the period that a chart covers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ChartPeriod {
+    /// A week, from Monday up to and including Sunday.
+    Week,
+    /// A calendar month.
+    Month,
+}
+
+impl ChartPeriod {
+    pub fn name(self) -> &'static str {
+        match self {
+            ChartPeriod::Week => "week",
+            ChartPeriod::Month => "month",
+        }
+    }
+
+    /// Return the first day of the period that contains `date`, and the first
+    /// day of the period after it.
+    pub fn bounds(self, date: NaiveDate) -> (NaiveDate, NaiveDate) {
+        match self {
+            ChartPeriod::Week => {
+                let start = date - Duration::days(date.weekday().num_days_from_monday() as i64);
+                (start, start + Duration::days(7))
+            }
+            ChartPeriod::Month => {
+                let start = NaiveDate::from_ymd(date.year(), date.month(), 1);
+                let end = match date.month() {
+                    12 => NaiveDate::from_ymd(date.year() + 1, 1, 1),
+                    m => NaiveDate::from_ymd(date.year(), m + 1, 1),
+                };
+                (start, end)
+            }
+        }
+    }
+}
+
+/// The parameters of a chart request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChartParams {
+    pub period: ChartPeriod,
+    /// A day in the period of the chart.
+    pub date: NaiveDate,
+    /// The number of entries in the chart.
+    pub limit: usize,
+}
+
+impl ChartParams {
+    /// Parse the `period`, `date`, and `limit` parameters.
+    ///
+    /// By default the chart is for the current week, with 20 entries.
+    pub fn parse(raw_query: &str) -> Result<ChartParams, &'static str> {
+        let mut params = ChartParams {
+            period: ChartPeriod::Week,
+            date: today(),
+            limit: 20,
+        };
+
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "period" => match v.as_ref() {
+                    "week" => params.period = ChartPeriod::Week,
+                    "month" => params.period = ChartPeriod::Month,
+                    _ => return Err("Invalid period, must be 'week' or 'month'."),
+                }
+                "date" => match NaiveDate::parse_from_str(v.as_ref(), "%Y-%m-%d") {
+                    Ok(date) => params.date = date,
+                    Err(_) => return Err("Invalid date, must be formatted as YYYY-MM-DD."),
+                }
+                "limit" => match usize::from_str(v.as_ref()) {
+                    Ok(n) if (1..=100).contains(&n) => params.limit = n,
+                    _ => return Err("Invalid limit, must be an integer from 1 to 100."),
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+/// An entry in a chart, with its rank in the chart of the previous period.
+pub struct ChartEntry<T> {
+    /// The rank, starting at 1.
+    pub rank: u32,
+    /// The rank in the previous chart, `None` if the item was not in it.
+    pub previous_rank: Option<u32>,
+    pub item: T,
+}
+
+/// The most played artists, albums, or tracks in a week or month.
+pub struct Chart<T> {
+    pub period: ChartPeriod,
+    /// The first day of the period.
+    pub start: NaiveDate,
+    /// The first day after the period.
+    pub end: NaiveDate,
+    pub entries: Vec<ChartEntry<T>>,
+}
+
+/// Return the time ranges of the chart and of the chart before it, as posix seconds.
+fn chart_ranges(params: &ChartParams) -> ((NaiveDate, NaiveDate), (i64, i64), (i64, i64)) {
+    let (start, end) = params.period.bounds(params.date);
+    let (prev_start, _) = params.period.bounds(start.pred());
+    let current = (local_midnight_seconds(start), local_midnight_seconds(end));
+    let previous = (local_midnight_seconds(prev_start), current.0);
+    ((start, end), current, previous)
+}
+
+/// Rank the items, most played first, against the ids of the previous chart.
+fn rank_chart<T>(items: Vec<T>, previous_ids: &[i64], id: impl Fn(&T) -> i64) -> Vec<ChartEntry<T>> {
+    items
+        .into_iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let item_id = id(&item);
+            ChartEntry {
+                rank: i as u32 + 1,
+                previous_rank: previous_ids.iter().position(|&p| p == item_id).map(|j| j as u32 + 1),
+                item: item,
+            }
+        })
+        .collect()
+}
+
+/// Return the chart of the most played album artists of the user.
+pub fn get_artist_chart(
+    tx: &mut Transaction,
+    params: &ChartParams,
+    user: Option<&str>,
+) -> db::Result<Chart<db::TopArtist>> {
+    let ((start, end), (since, until), (prev_since, prev_until)) = chart_ranges(params);
+    let limit = params.limit as i64;
+    let items = db::iter_top_artists(tx, since, until, None, user, limit)?.collect::<db::Result<Vec<_>>>()?;
+    let mut previous_ids = Vec::new();
+    for artist in db::iter_top_artists(tx, prev_since, prev_until, None, user, limit)? {
+        previous_ids.push(artist?.album_artist_id);
+    }
+    let result = Chart {
+        period: params.period,
+        start: start,
+        end: end,
+        entries: rank_chart(items, &previous_ids, |artist| artist.album_artist_id),
+    };
+    Ok(result)
+}
+
+/// Return the chart of the most played albums of the user.
+pub fn get_album_chart(
+    tx: &mut Transaction,
+    params: &ChartParams,
+    user: Option<&str>,
+) -> db::Result<Chart<db::TopAlbum>> {
+    let ((start, end), (since, until), (prev_since, prev_until)) = chart_ranges(params);
+    let limit = params.limit as i64;
+    let items = db::iter_top_albums(tx, since, until, None, user, limit)?.collect::<db::Result<Vec<_>>>()?;
+    let mut previous_ids = Vec::new();
+    for album in db::iter_top_albums(tx, prev_since, prev_until, None, user, limit)? {
+        previous_ids.push(album?.album_id);
+    }
+    let result = Chart {
+        period: params.period,
+        start: start,
+        end: end,
+        entries: rank_chart(items, &previous_ids, |album| album.album_id),
+    };
+    Ok(result)
+}
+
+/// Return the chart of the most played tracks of the user.
+pub fn get_track_chart(
+    tx: &mut Transaction,
+    params: &ChartParams,
+    user: Option<&str>,
+) -> db::Result<Chart<db::TopTrack>> {
+    let ((start, end), (since, until), (prev_since, prev_until)) = chart_ranges(params);
+    let limit = params.limit as i64;
+    let items = db::iter_top_tracks(tx, since, until, None, user, limit)?.collect::<db::Result<Vec<_>>>()?;
+    let mut previous_ids = Vec::new();
+    for track in db::iter_top_tracks(tx, prev_since, prev_until, None, user, limit)? {
+        previous_ids.push(track?.track_id);
+    }
+    let result = Chart {
+        period: params.period,
+        start: start,
+        end: end,
+        entries: rank_chart(items, &previous_ids, |track| track.track_id),
+    };
+    Ok(result)
+}
+
+/// What the user listened to for the first time in a time range.
+pub struct Discoveries {
+    pub tracks: i64,
+    pub albums: i64,
+    pub artists: i64,
+    /// The new albums, most played first.
+    pub top_albums: Vec<db::AlbumDiscovery>,
+}
+
+/// Return what the user listened to for the first time in the requested range.
+pub fn get_discoveries(
+    tx: &mut Transaction,
+    params: &StatsParams,
+    user: Option<&str>,
+) -> db::Result<Discoveries> {
+    let (since, until) = params.range();
+    let (tracks, albums, artists) = db::select_discovery_counts(tx, since, until, user)?;
+    let top_albums = db::iter_album_discoveries(tx, since, until, user, params.limit as i64)?
+        .collect::<db::Result<Vec<_>>>()?;
+    let result = Discoveries {
+        tracks: tracks,
+        albums: albums,
+        artists: artists,
+        top_albums: top_albums,
+    };
+    Ok(result)
+}
+
+/// The number of listens and first listens in one month.
+#[derive(Debug, Eq, PartialEq)]
+pub struct DiscoveryMonth {
+    /// The month, formatted as YYYY-MM.
+    pub month: String,
+    pub listens: i64,
+    pub new_tracks: i64,
+    pub new_albums: i64,
+    pub new_artists: i64,
+}
+
+/// Combine listens per month with first listens per month, both oldest first.
+///
+/// Every first listen is a listen, so every month with first listens is in
+/// `listens`, but not the other way around.
+fn merge_discovery_months(
+    listens: Vec<(String, i64)>,
+    discoveries: Vec<(String, i64, i64, i64)>,
+) -> Vec<DiscoveryMonth> {
+    let mut discoveries = discoveries.into_iter().peekable();
+    let mut result = Vec::with_capacity(listens.len());
+    for (month, listen_count) in listens {
+        let (tracks, albums, artists) = match discoveries.peek() {
+            Some((m, ..)) if *m == month => {
+                let (_, tracks, albums, artists) = discoveries.next().unwrap();
+                (tracks, albums, artists)
+            }
+            _ => (0, 0, 0),
+        };
+        result.push(DiscoveryMonth {
+            month: month,
+            listens: listen_count,
+            new_tracks: tracks,
+            new_albums: albums,
+            new_artists: artists,
+        });
+    }
+    result
+}
+
+/// Return the listens and first listens of the user per month, oldest first.
+pub fn get_discovery_rate(
+    tx: &mut Transaction,
+    params: &StatsParams,
+    user: Option<&str>,
+) -> db::Result<Vec<DiscoveryMonth>> {
+    let (since, until) = params.range();
+    let listens = db::iter_listens_per_month(tx, since, until, user)?.collect::<db::Result<Vec<_>>>()?;
+    let discoveries = db::iter_discoveries_per_month(tx, since, until, user)?.collect::<db::Result<Vec<_>>>()?;
+    Ok(merge_discovery_months(listens, discoveries))
+}
+
+/// An album that we listened to partway, see `get_albums_in_progress`.
+pub struct AlbumInProgress {
+    pub album_id: AlbumId,
+    /// The track after the one that we listened to last, where to continue.
+    pub next_track_id: TrackId,
+    /// The number of different tracks of the album that we listened to in the last sitting.
+    pub tracks_listened: u32,
+    /// When the most recent listen of the album started.
+    pub last_listened: Instant,
+}
+
+/// Return where to continue the album, given its listens, oldest first.
+///
+/// We consider the album in progress if in the last sitting, we listened to at
+/// least two of its tracks, so we were listening to the album, not to a single
+/// track that came up in a shuffle. If the last listen was the final track, we
+/// finished the album. Returns the next track, and the number of tracks that
+/// we listened to in the sitting.
+fn find_next_track(
+    listens: &[(TrackId, i64)],
+    album_tracks: &[TrackId],
+) -> Option<(TrackId, u32)> {
+    let &(last_track_id, last_started_at) = listens.last()?;
+
+    let mut sitting: Vec<TrackId> = listens
+        .iter()
+        .filter(|(_, started_at)| *started_at >= last_started_at - SITTING_SECONDS)
+        .map(|(track_id, _)| *track_id)
+        .collect();
+    sitting.sort();
+    sitting.dedup();
+    if sitting.len() < 2 {
+        return None;
+    }
+
+    let i = album_tracks.iter().position(|t| *t == last_track_id)?;
+    let next_track_id = *album_tracks.get(i + 1)?;
+    Some((next_track_id, sitting.len() as u32))
+}
+
+/// Return the albums that the user listened to partway, most recent first.
+///
+/// Only albums that the user listened to in the past 90 days qualify, and we
+/// return at most `limit` of them.
+pub fn get_albums_in_progress(
+    tx: &mut Transaction,
+    index: &dyn MetaIndex,
+    user: Option<&str>,
+    limit: usize,
+) -> db::Result<Vec<AlbumInProgress>> {
+    let since = Local::now().timestamp() - IN_PROGRESS_WINDOW_SECONDS;
+    let mut result = Vec::new();
+
+    // Rows are ordered by album, so we handle one album at a time.
+    let mut album_id = None;
+    let mut listens: Vec<(TrackId, i64)> = Vec::new();
+    let mut push_album = |album_id: AlbumId, listens: &[(TrackId, i64)]| {
+        let album_tracks: Vec<TrackId> = index
+            .get_album_tracks(album_id)
+            .iter()
+            .map(|kv| kv.track_id)
+            .collect();
+        if let Some((next_track_id, tracks_listened)) = find_next_track(listens, &album_tracks) {
+            let (_, last_started_at) = listens[listens.len() - 1];
+            result.push(AlbumInProgress {
+                album_id: album_id,
+                next_track_id: next_track_id,
+                tracks_listened: tracks_listened,
+                last_listened: Instant { posix_seconds_utc: last_started_at },
+            });
+        }
+    };
+
+    for row in db::iter_album_listens_since(tx, since, user)? {
+        let (row_album_id, track_id, started_at) = row?;
+        let row_album_id = AlbumId(row_album_id as u64);
+        if album_id != Some(row_album_id) {
+            if let Some(id) = album_id {
+                push_album(id, &listens);
+            }
+            album_id = Some(row_album_id);
+            listens.clear();
+        }
+        listens.push((TrackId(track_id as u64), started_at));
+    }
+    if let Some(id) = album_id {
+        push_album(id, &listens);
+    }
+
+    result.sort_by_key(|album| std::cmp::Reverse(album.last_listened));
+    result.truncate(limit);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDate;
+
+    use super::{find_next_track, merge_discovery_months, parse_time, rank_chart};
+    use super::{ChartParams, ChartPeriod, DiscoveryMonth, ListenParams, ListenThreshold, StatsParams};
+    use crate::prim::{AlbumId, Instant, TrackId};
+
+    #[test]
+    fn parse_time_accepts_timestamps_and_dates() {
+        let t = Instant { posix_seconds_utc: 1612051200 };
+        assert_eq!(parse_time("2021-01-31"), Some(t));
+        assert_eq!(parse_time("2021-01-31T00:00:00Z"), Some(t));
+        assert_eq!(parse_time("2021-01-31T01:00:00+01:00"), Some(t));
+        assert_eq!(parse_time("yesterday"), None);
+    }
+
+    #[test]
+    fn listen_params_can_be_parsed() {
+        let params = ListenParams::parse("").unwrap();
+        assert_eq!(params.limit, 100);
+        assert_eq!(params.cursor, None);
+
+        let params = ListenParams::parse("album=0000000000001&limit=5&cursor=1612051200").unwrap();
+        assert_eq!(params.album, Some(AlbumId(1)));
+        assert_eq!(params.limit, 5);
+        assert_eq!(params.cursor, Some(1612051200));
+
+        assert!(ListenParams::parse("limit=0").is_err());
+        assert!(ListenParams::parse("since=last-week").is_err());
+        assert!(ListenParams::parse("artist=Muse").is_err());
+
+        let params = ListenParams::parse("client=+kids-tablet+").unwrap();
+        assert_eq!(params.client.as_deref(), Some("kids-tablet"));
+        assert!(ListenParams::parse("client=").is_err());
+    }
+
+    #[test]
+    fn stats_params_default_to_all_time() {
+        let params = StatsParams::parse("").unwrap();
+        assert_eq!(params.range(), (i64::MIN, i64::MAX));
+        assert_eq!(params.limit, 10);
+
+        let params = StatsParams::parse("since=2021-01-31&limit=3").unwrap();
+        assert_eq!(params.range(), (1612051200, i64::MAX));
+        assert_eq!(params.limit, 3);
+
+        assert!(StatsParams::parse("limit=1000").is_err());
+    }
+
+    #[test]
+    fn find_next_track_continues_after_the_last_listen() {
+        let tracks = [TrackId(0x1001), TrackId(0x1002), TrackId(0x1003)];
+        let listens = [(TrackId(0x1001), 1000), (TrackId(0x1002), 1300)];
+        assert_eq!(find_next_track(&listens, &tracks), Some((TrackId(0x1003), 2)));
+    }
+
+    #[test]
+    fn find_next_track_ignores_finished_albums_and_single_tracks() {
+        let tracks = [TrackId(0x1001), TrackId(0x1002), TrackId(0x1003)];
+
+        // We listened to the album to the end.
+        let listens = [(TrackId(0x1002), 1000), (TrackId(0x1003), 1300)];
+        assert_eq!(find_next_track(&listens, &tracks), None);
+
+        // A single track, for example from a shuffle, is not listening to the album.
+        let listens = [(TrackId(0x1001), 1000), (TrackId(0x1001), 5000)];
+        assert_eq!(find_next_track(&listens, &tracks), None);
+
+        // Listens from an earlier sitting don't count towards the last one.
+        let listens = [(TrackId(0x1001), 1000), (TrackId(0x1002), 1000 + 7 * 3600)];
+        assert_eq!(find_next_track(&listens, &tracks), None);
+    }
+
+    #[test]
+    fn chart_period_bounds_cover_the_date() {
+        let date = NaiveDate::from_ymd(2021, 12, 30);
+        let week = ChartPeriod::Week.bounds(date);
+        assert_eq!(week, (NaiveDate::from_ymd(2021, 12, 27), NaiveDate::from_ymd(2022, 1, 3)));
+        let month = ChartPeriod::Month.bounds(date);
+        assert_eq!(month, (NaiveDate::from_ymd(2021, 12, 1), NaiveDate::from_ymd(2022, 1, 1)));
+
+        // A Monday starts its own week.
+        let monday = NaiveDate::from_ymd(2021, 12, 27);
+        assert_eq!(ChartPeriod::Week.bounds(monday).0, monday);
+    }
+
+    #[test]
+    fn chart_params_can_be_parsed() {
+        let params = ChartParams::parse("period=month&date=2021-01-31&limit=5").unwrap();
+        assert_eq!(params.period, ChartPeriod::Month);
+        assert_eq!(params.date, NaiveDate::from_ymd(2021, 1, 31));
+        assert_eq!(params.limit, 5);
+
+        assert_eq!(ChartParams::parse("").unwrap().period, ChartPeriod::Week);
+        assert!(ChartParams::parse("period=year").is_err());
+        assert!(ChartParams::parse("date=yesterday").is_err());
+    }
+
+    #[test]
+    fn rank_chart_finds_previous_ranks() {
+        let entries = rank_chart(vec![7, 3, 5], &[3, 9, 7], |&id| id);
+        let ranks: Vec<_> = entries.iter().map(|e| (e.item, e.rank, e.previous_rank)).collect();
+        assert_eq!(ranks, vec![(7, 1, Some(3)), (3, 2, Some(1)), (5, 3, None)]);
+    }
+
+    #[test]
+    fn merge_discovery_months_fills_in_months_without_discoveries() {
+        let listens = vec![("2021-01".to_string(), 10), ("2021-02".to_string(), 4), ("2021-03".to_string(), 7)];
+        let discoveries = vec![("2021-01".to_string(), 10, 2, 1), ("2021-03".to_string(), 3, 1, 0)];
+        let months = merge_discovery_months(listens, discoveries);
+        let expected = DiscoveryMonth {
+            month: "2021-02".to_string(),
+            listens: 4,
+            new_tracks: 0,
+            new_albums: 0,
+            new_artists: 0,
+        };
+        assert_eq!(months.len(), 3);
+        assert_eq!(months[0].new_tracks, 10);
+        assert_eq!(months[1], expected);
+        assert_eq!(months[2].new_albums, 1);
+    }
+
+    #[test]
+    fn listen_threshold_takes_whichever_comes_first() {
+        let threshold = ListenThreshold { percent: 50, seconds: 240 };
+        assert!(threshold.is_counted(200, 100));
+        assert!(!threshold.is_counted(200, 99));
+        // For long tracks, four minutes is enough.
+        assert!(threshold.is_counted(3600, 240));
+        assert!(!threshold.is_counted(3600, 10));
+    }
+}
@@ -0,0 +1,375 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Pagination and sorting for the album, artist, and track listings.
+//!
+//! For a large library, the full listings are several megabytes of json.
+//! Clients on slow connections can use the `offset` and `limit` query
+//! parameters to fetch a page at a time, and `sort` to have the server do the
+//! sorting, rather than downloading the full catalog and sorting it locally.
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use nanorand::Rng;
+
+use crate::prim::{AlbumId, ArtistId, TrackId};
+use crate::shuffle::Prng;
+use crate::user_data::{Rating, UserData};
+use crate::MetaIndex;
+
+/// Order in which a listing endpoint returns its items.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SortOrder {
+    /// By id, the order of the index itself. This is the default.
+    Id,
+    /// Alphabetically, by title for albums and tracks, by sort name for artists.
+    Name,
+    /// By original release date, oldest first. Artists sort by their first album.
+    ReleaseDate,
+    /// By the time the album entered the library, newest first. Artists sort
+    /// by their most recently added album.
+    RecentlyAdded,
+    /// By the user's rating, highest first.
+    Rating,
+    /// By the number of listens, most played first. Artists count the listens
+    /// of albums of which they are the first album artist.
+    MostPlayed,
+}
+
+impl FromStr for SortOrder {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<SortOrder, &'static str> {
+        match s {
+            "id" => Ok(SortOrder::Id),
+            "name" => Ok(SortOrder::Name),
+            "release_date" => Ok(SortOrder::ReleaseDate),
+            "recently_added" => Ok(SortOrder::RecentlyAdded),
+            "rating" => Ok(SortOrder::Rating),
+            "most_played" => Ok(SortOrder::MostPlayed),
+            _ => Err("Invalid sort order, must be one of id, name, release_date, recently_added, rating, most_played."),
+        }
+    }
+}
+
+/// The `sort`, `offset`, and `limit` parameters of a listing request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ListParams {
+    pub sort: SortOrder,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl ListParams {
+    /// Parse the listing parameters from the query string of a request.
+    ///
+    /// All parameters are optional, by default we list everything by id.
+    /// Unknown parameters are ignored.
+    pub fn parse(raw_query: &str) -> Result<ListParams, &'static str> {
+        let mut params = ListParams {
+            sort: SortOrder::Id,
+            offset: 0,
+            limit: None,
+        };
+
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "sort" => params.sort = SortOrder::from_str(v.as_ref())?,
+                "offset" => match usize::from_str(v.as_ref()) {
+                    Ok(n) => params.offset = n,
+                    Err(_) => return Err("Invalid offset, must be a non-negative integer."),
+                }
+                "limit" => match usize::from_str(v.as_ref()) {
+                    Ok(n) => params.limit = Some(n),
+                    Err(_) => return Err("Invalid limit, must be a non-negative integer."),
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(params)
+    }
+
+    /// Return the part of `xs` selected by the offset and limit.
+    pub fn page<'a, T>(&self, xs: &'a [T]) -> &'a [T] {
+        let begin = self.offset.min(xs.len());
+        let end = match self.limit {
+            Some(n) => begin.saturating_add(n).min(xs.len()),
+            None => xs.len(),
+        };
+        &xs[begin..end]
+    }
+}
+
+/// Return the page of album ids selected by the parameters.
+pub fn list_albums(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    params: &ListParams,
+) -> Vec<AlbumId> {
+    let mut albums: Vec<_> = index.get_albums().iter().collect();
+
+    // Note, the sorts are stable, and the albums start out ordered by id, so
+    // ties are broken by id, which keeps pagination deterministic.
+    match params.sort {
+        SortOrder::Id => {}
+        SortOrder::Name => albums.sort_by_cached_key(|kv| {
+            index.get_string(kv.album.title).to_lowercase()
+        }),
+        SortOrder::ReleaseDate => albums.sort_by_key(|kv| kv.album.original_release_date),
+        SortOrder::RecentlyAdded => albums.sort_by_key(|kv| Reverse(kv.album.first_seen)),
+        SortOrder::Rating => albums.sort_by_key(|kv| Reverse(user_data.get_album_rating(kv.album_id))),
+        SortOrder::MostPlayed => albums.sort_by_key(|kv| Reverse(user_data.get_album_play_count(kv.album_id))),
+    }
+
+    params.page(&albums[..]).iter().map(|kv| kv.album_id).collect()
+}
+
+/// Return the page of albums that the user played, most recently played first.
+///
+/// The sort order in the parameters is ignored, only the page applies.
+pub fn recently_played_albums(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    params: &ListParams,
+) -> Vec<AlbumId> {
+    let mut albums: Vec<_> = index
+        .get_albums()
+        .iter()
+        .filter_map(|kv| Some((user_data.get_album_last_played(kv.album_id)?, kv.album_id)))
+        .collect();
+
+    albums.sort_by_key(|&(last_played, _)| Reverse(last_played));
+
+    params.page(&albums[..]).iter().map(|&(_, album_id)| album_id).collect()
+}
+
+/// Return the page of artist ids selected by the parameters.
+pub fn list_artists(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    params: &ListParams,
+) -> Vec<ArtistId> {
+    let mut artists: Vec<_> = index.get_artists().iter().collect();
+
+    match params.sort {
+        SortOrder::Id => {}
+        SortOrder::Name => artists.sort_by_cached_key(|kv| {
+            index.get_string(kv.artist.name_for_sort).to_lowercase()
+        }),
+        SortOrder::ReleaseDate => artists.sort_by_cached_key(|kv| {
+            // Albums by artist are ordered by release date, so the first one
+            // is the earliest.
+            index
+                .get_albums_by_artist(kv.artist_id)
+                .first()
+                .and_then(|&(_, album_id)| index.get_album(album_id))
+                .map(|album| album.original_release_date)
+        }),
+        SortOrder::RecentlyAdded => artists.sort_by_cached_key(|kv| {
+            let last_added = index
+                .get_albums_by_artist(kv.artist_id)
+                .iter()
+                .filter_map(|&(_, album_id)| index.get_album(album_id))
+                .map(|album| album.first_seen)
+                .max();
+            Reverse(last_added)
+        }),
+        SortOrder::Rating => artists.sort_by_key(|kv| Reverse(user_data.get_artist_rating(kv.artist_id))),
+        SortOrder::MostPlayed => artists.sort_by_key(|kv| Reverse(user_data.get_artist_play_count(kv.artist_id))),
+    }
+
+    params.page(&artists[..]).iter().map(|kv| kv.artist_id).collect()
+}
+
+/// Return the page of track ids selected by the parameters.
+pub fn list_tracks(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    params: &ListParams,
+) -> Vec<TrackId> {
+    // Fast path: in id order, we don't need to materialize the full listing.
+    if params.sort == SortOrder::Id {
+        return params.page(index.get_tracks()).iter().map(|kv| kv.track_id).collect();
+    }
+
+    let mut tracks: Vec<_> = index.get_tracks().iter().collect();
+
+    // The track id has the album id as prefix, so for sorts that only look at
+    // the album, tracks of the same album stay together, in album order.
+    match params.sort {
+        SortOrder::Id => unreachable!("Handled by the fast path above."),
+        SortOrder::Name => tracks.sort_by_cached_key(|kv| {
+            index.get_string(kv.track.title).to_lowercase()
+        }),
+        SortOrder::ReleaseDate => tracks.sort_by_cached_key(|kv| {
+            index
+                .get_album(kv.track_id.album_id())
+                .map(|album| album.original_release_date)
+        }),
+        SortOrder::RecentlyAdded => tracks.sort_by_cached_key(|kv| {
+            let first_seen = index
+                .get_album(kv.track_id.album_id())
+                .map(|album| album.first_seen);
+            Reverse(first_seen)
+        }),
+        SortOrder::Rating => tracks.sort_by_key(|kv| Reverse(user_data.get_track_rating(kv.track_id))),
+        SortOrder::MostPlayed => tracks.sort_by_key(|kv| Reverse(user_data.get_track_play_count(kv.track_id))),
+    }
+
+    params.page(&tracks[..]).iter().map(|kv| kv.track_id).collect()
+}
+
+/// The parameters of a random album request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RandomParams {
+    /// The number of albums to pick.
+    pub count: usize,
+    /// Only pick albums released in the decade that starts at this year.
+    pub decade: Option<u16>,
+    /// Only pick albums that have no listens.
+    pub never_played: bool,
+    /// Only pick albums rated at least this.
+    pub min_rating: Option<Rating>,
+}
+
+impl RandomParams {
+    /// Parse the `count`, `decade`, `never_played`, and `min_rating` parameters.
+    ///
+    /// All parameters are optional, by default we pick 6 albums.
+    pub fn parse(raw_query: &str) -> Result<RandomParams, &'static str> {
+        let mut params = RandomParams {
+            count: 6,
+            decade: None,
+            never_played: false,
+            min_rating: None,
+        };
+
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            match k.as_ref() {
+                "count" => match usize::from_str(v.as_ref()) {
+                    Ok(n) if n <= 100 => params.count = n,
+                    _ => return Err("Invalid count, must be an integer of at most 100."),
+                }
+                "decade" => match u16::from_str(v.as_ref()) {
+                    Ok(y) if y % 10 == 0 => params.decade = Some(y),
+                    _ => return Err("Invalid decade, must be a year that is a multiple of 10."),
+                }
+                "never_played" => match v.as_ref() {
+                    "true" => params.never_played = true,
+                    "false" => params.never_played = false,
+                    _ => return Err("Invalid never_played, must be 'true' or 'false'."),
+                }
+                "min_rating" => match i64::from_str(v.as_ref()) {
+                    Ok(r) => params.min_rating = Some(Rating::try_from(r)?),
+                    Err(_) => return Err("Invalid min_rating, must be an integer."),
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+/// Pick random albums that match the parameters.
+///
+/// Albums in `played` are excluded if `never_played` is set, and albums that the
+/// user excluded from shuffling are never picked. Every album is
+/// picked at most once, so if fewer albums match than requested, the result
+/// contains all matching albums, in random order.
+pub fn random_albums(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    params: &RandomParams,
+    played: &HashSet<AlbumId>,
+    rng: &mut Prng,
+) -> Vec<AlbumId> {
+    let mut candidates: Vec<AlbumId> = index
+        .get_albums()
+        .iter()
+        .filter(|kv| match params.decade {
+            Some(y) => kv.album.original_release_date.year / 10 == y / 10,
+            None => true,
+        })
+        .filter(|kv| !(params.never_played && played.contains(&kv.album_id)))
+        .filter(|kv| !user_data.is_album_excluded(kv.album_id))
+        .filter(|kv| match params.min_rating {
+            Some(r) => user_data.get_album_rating(kv.album_id) >= r,
+            None => true,
+        })
+        .map(|kv| kv.album_id)
+        .collect();
+
+    // A partial Fisher-Yates shuffle, we only need the first `count` elements.
+    let n = params.count.min(candidates.len());
+    for i in 0..n {
+        let j = rng.generate_range(i..candidates.len());
+        candidates.swap(i, j);
+    }
+    candidates.truncate(n);
+    candidates
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ListParams, RandomParams, Rating, SortOrder};
+
+    #[test]
+    fn list_params_default_to_everything_by_id() {
+        let params = ListParams::parse("").unwrap();
+        assert_eq!(params.sort, SortOrder::Id);
+        assert_eq!(params.offset, 0);
+        assert_eq!(params.limit, None);
+    }
+
+    #[test]
+    fn list_params_can_be_parsed() {
+        let params = ListParams::parse("sort=release_date&offset=20&limit=10&q=x").unwrap();
+        assert_eq!(params.sort, SortOrder::ReleaseDate);
+        assert_eq!(params.offset, 20);
+        assert_eq!(params.limit, Some(10));
+
+        assert_eq!(ListParams::parse("sort=most_played").unwrap().sort, SortOrder::MostPlayed);
+        assert!(ListParams::parse("sort=loudness").is_err());
+        assert!(ListParams::parse("offset=-1").is_err());
+        assert!(ListParams::parse("limit=ten").is_err());
+    }
+
+    #[test]
+    fn list_params_page_clamps_to_bounds() {
+        let xs = [1, 2, 3, 4, 5];
+        let page = |q| ListParams::parse(q).unwrap().page(&xs[..]).to_vec();
+        assert_eq!(page(""), vec![1, 2, 3, 4, 5]);
+        assert_eq!(page("limit=2"), vec![1, 2]);
+        assert_eq!(page("offset=3"), vec![4, 5]);
+        assert_eq!(page("offset=3&limit=10"), vec![4, 5]);
+        assert_eq!(page("offset=10&limit=2"), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn random_params_can_be_parsed() {
+        let params = RandomParams::parse("").unwrap();
+        assert_eq!(params.count, 6);
+        assert_eq!(params.decade, None);
+        assert!(!params.never_played);
+
+        let params = RandomParams::parse("count=3&decade=1970&never_played=true").unwrap();
+        assert_eq!(params.count, 3);
+        assert_eq!(params.decade, Some(1970));
+        assert!(params.never_played);
+
+        assert!(RandomParams::parse("count=1000").is_err());
+        assert!(RandomParams::parse("decade=1975").is_err());
+        assert!(RandomParams::parse("never_played=yes").is_err());
+        assert!(RandomParams::parse("min_rating=3").is_err());
+        assert_eq!(RandomParams::parse("min_rating=1").unwrap().min_rating, Some(Rating::Like));
+    }
+}
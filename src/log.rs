@@ -0,0 +1,250 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Setting up `tracing` with a level per subsystem, as text or as json.
+//!
+//! We log with the `tracing` macros, `info!`, `warn!`, and so on, the target
+//! is the module that logs, such as `musium::scan`. We call that module the
+//! subsystem. The `log_level` setting sets the levels as a default level and
+//! `subsystem=level` overrides, for example `info,player=debug`, and we turn
+//! that into an `EnvFilter`. The `MUSIUM_LOG` environment variable takes
+//! precedence over the config file, so it is easy to debug a single run.
+//!
+//! Messages can carry fields, `warn!(album_id = %id, "...")`. In text output
+//! they follow the message as `key=value`, with `log_format = json` every
+//! message is a json object on a line of its own, with the fields as keys,
+//! which journald and Loki can index.
+//!
+//! Messages go to stderr, with the level in the text format, so systemd
+//! assigns the right priority. Output of commands that the user runs directly,
+//! such as `musium count`, is not logging, it goes to stdout as before.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use tracing_subscriber::layer::{Layer, Layered, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt as tracing_fmt, reload, EnvFilter, Registry};
+
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Level, &'static str> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err("Invalid log level, must be one of 'error', 'warn', 'info', or 'debug'."),
+        }
+    }
+}
+
+/// The level per subsystem, parsed from for example `info,scan=debug`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Filter {
+    default: Level,
+    subsystems: Vec<(String, Level)>,
+}
+
+impl Filter {
+    pub const fn new() -> Filter {
+        Filter {
+            default: Level::Info,
+            subsystems: Vec::new(),
+        }
+    }
+
+    /// Return the most verbose level that we log for the subsystem.
+    pub fn level(&self, subsystem: &str) -> Level {
+        match self.subsystems.iter().find(|(name, _)| name == subsystem) {
+            Some((_, level)) => *level,
+            None => self.default,
+        }
+    }
+
+    /// Return the filter as `EnvFilter` directives, the subsystems become targets in our crate.
+    fn to_directives(&self) -> String {
+        let mut directives = self.default.name().to_string();
+        for (subsystem, level) in &self.subsystems {
+            directives.push_str(&format!(",musium::{}={}", subsystem, level.name()));
+        }
+        directives
+    }
+
+    fn to_env_filter(&self) -> EnvFilter {
+        EnvFilter::try_new(self.to_directives()).expect("Filter only holds valid directives.")
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter::new()
+    }
+}
+
+impl FromStr for Filter {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Filter, &'static str> {
+        let mut filter = Filter::new();
+        for part in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some((subsystem, level)) => {
+                    let level = Level::from_str(level.trim())?;
+                    let subsystem = subsystem.trim();
+                    // The subsystem is a module path, anything else would not
+                    // be a valid target directive.
+                    let is_module_path = !subsystem.is_empty() && subsystem
+                        .split("::")
+                        .all(|m| !m.is_empty() && m.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_'));
+                    if !is_module_path {
+                        return Err("Invalid log subsystem, must be a module such as 'scan' or 'player'.");
+                    }
+                    filter.subsystems.push((subsystem.to_string(), level));
+                }
+                None => filter.default = Level::from_str(part)?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.default.name())?;
+        for (subsystem, level) in &self.subsystems {
+            write!(f, ",{}={}", subsystem, level.name())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Format, &'static str> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err("Invalid log_format value, must be 'text' or 'json'."),
+        }
+    }
+}
+
+type FilteredRegistry = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+type OutputLayer = Box<dyn Layer<FilteredRegistry> + Send + Sync>;
+
+/// Handles to swap the filter and the output when the config is reloaded.
+struct Handles {
+    filter: reload::Handle<EnvFilter, Registry>,
+    output: reload::Handle<OutputLayer, FilteredRegistry>,
+}
+
+static HANDLES: Mutex<Option<Handles>> = Mutex::new(None);
+
+fn new_output_layer(format: Format) -> OutputLayer {
+    // Systemd and journald add the time already, and stderr is not a terminal
+    // there, so we print neither the time nor colors.
+    let layer = tracing_fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(false)
+        .without_time();
+    match format {
+        Format::Text => layer.boxed(),
+        Format::Json => layer.json().flatten_event(true).boxed(),
+    }
+}
+
+/// Set the filter and format for all subsequent messages.
+///
+/// The first call installs the global subscriber, later calls, when the config
+/// is reloaded, replace the filter and format in place. Before the first call,
+/// there is no subscriber, and messages go nowhere.
+pub fn init(filter: Filter, format: Format) {
+    let mut handles = HANDLES.lock().unwrap();
+    match handles.as_ref() {
+        Some(handles) => {
+            // Reloading only fails when the subscriber is gone, then there is
+            // nothing to log to anyway.
+            let _ = handles.filter.reload(filter.to_env_filter());
+            let _ = handles.output.reload(new_output_layer(format));
+        }
+        None => {
+            let (filter_layer, filter_handle) = reload::Layer::new(filter.to_env_filter());
+            let (output_layer, output_handle) = reload::Layer::new(new_output_layer(format));
+            let result = tracing_subscriber::registry()
+                .with(filter_layer)
+                .with(output_layer)
+                .try_init();
+            if result.is_ok() {
+                *handles = Some(Handles {
+                    filter: filter_handle,
+                    output: output_handle,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::{Filter, Level};
+
+    #[test]
+    fn filter_parses_default_and_subsystem_levels() {
+        let filter = Filter::from_str("warn, scan=debug,player=error").unwrap();
+        assert_eq!(filter.level("scan"), Level::Debug);
+        assert_eq!(filter.level("player"), Level::Error);
+        assert_eq!(filter.level("server"), Level::Warn);
+        assert_eq!(filter.to_string(), "warn,scan=debug,player=error");
+
+        assert_eq!(Filter::from_str("").unwrap().level("scan"), Level::Info);
+        assert_eq!(Filter::from_str("scan=debug").unwrap().level("server"), Level::Info);
+        assert!(Filter::from_str("loud").is_err());
+        assert!(Filter::from_str("scan=loud").is_err());
+        assert!(Filter::from_str("=debug").is_err());
+        assert!(Filter::from_str("scan[x]=debug").is_err());
+    }
+
+    #[test]
+    fn filter_targets_modules_of_the_crate() {
+        let filter = Filter::from_str("warn,scan=debug,server::tls=error").unwrap();
+        assert_eq!(filter.to_directives(), "warn,musium::scan=debug,musium::server::tls=error");
+        // The directives must be valid, or building the filter panics.
+        let _ = filter.to_env_filter();
+    }
+}
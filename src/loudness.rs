@@ -5,7 +5,7 @@
 // you may not use this file except in compliance with the License.
 // A copy of the License has been included in the root of the repository.
 
-//! Computation of track and album loudness, and track waveforms.
+//! Computation of track and album loudness, true peak, and track waveforms.
 
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::{SyncSender, Receiver, sync_channel};
@@ -23,6 +23,77 @@ use crate::scan::Status;
 use crate::waveform::Waveform;
 use crate::{MetaIndex, MemoryMetaIndex};
 
+/// Coefficients of the 4x oversampling filter of ITU-R BS.1770-4, Annex 2.
+///
+/// Every phase produces one of the four interpolated samples per input sample.
+const TRUE_PEAK_FILTER: [[f32; 12]; 4] = [
+    [
+        0.0017089843750, 0.0109863281250, -0.0196533203125, 0.0332031250000,
+        -0.0594482421875, 0.1373291015625, 0.9721679687500, -0.1022949218750,
+        0.0476074218750, -0.0266113281250, 0.0148925781250, -0.0083007812500,
+    ],
+    [
+        -0.0291748046875, 0.0292968750000, -0.0517578125000, 0.0891113281250,
+        -0.1665039062500, 0.4650878906250, 0.7797851562500, -0.2003173828125,
+        0.1015625000000, -0.0582275390625, 0.0330810546875, -0.0189208984375,
+    ],
+    [
+        -0.0189208984375, 0.0330810546875, -0.0582275390625, 0.1015625000000,
+        -0.2003173828125, 0.7797851562500, 0.4650878906250, -0.1665039062500,
+        0.0891113281250, -0.0517578125000, 0.0292968750000, -0.0291748046875,
+    ],
+    [
+        -0.0083007812500, 0.0148925781250, -0.0266113281250, 0.0476074218750,
+        -0.1022949218750, 0.9721679687500, 0.1373291015625, -0.0594482421875,
+        0.0332031250000, -0.0196533203125, 0.0109863281250, 0.0017089843750,
+    ],
+];
+
+/// Measures the true peak of one channel, the peak after 4x oversampling.
+///
+/// When the peak of the waveform falls between two samples, the sample peak
+/// is lower than the peak that the DAC outputs. A master that is limited to a
+/// sample peak of 0 dBFS can therefore still clip.
+pub struct TruePeakMeter {
+    /// The most recent samples, the most recent one first.
+    history: [f32; 12],
+
+    /// The true peak so far, as linear amplitude, where 1.0 is full scale.
+    peak: f32,
+}
+
+impl TruePeakMeter {
+    pub fn new() -> TruePeakMeter {
+        TruePeakMeter {
+            history: [0.0; 12],
+            peak: 0.0,
+        }
+    }
+
+    /// Feed input samples, in the range -1.0 to 1.0, into the meter.
+    pub fn push<I: Iterator<Item = f32>>(&mut self, samples: I) {
+        for sample in samples {
+            self.history.copy_within(0..11, 1);
+            self.history[0] = sample;
+            for phase in TRUE_PEAK_FILTER.iter() {
+                let y: f32 = phase.iter().zip(self.history.iter()).map(|(h, x)| h * x).sum();
+                self.peak = self.peak.max(y.abs());
+            }
+        }
+    }
+
+    /// Return the true peak as linear amplitude, where 1.0 is full scale.
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+}
+
+/// Convert a linear amplitude into decibels relative to full scale.
+fn amplitude_to_db(amplitude: f32) -> f64 {
+    // Silence has no finite level, we put it at -100 dB.
+    20.0 * (amplitude as f64).max(1e-5).log10()
+}
+
 /// Tracks the state of loudness analysis for one album.
 struct AlbumTask {
     album_id: AlbumId,
@@ -36,6 +107,9 @@ struct AlbumTask {
     /// Results of tracks that we have analyzed, in no particular order.
     tracks_done: Vec<[ChannelLoudnessMeter; 2]>,
 
+    /// The highest true peak of the tracks that we have analyzed.
+    true_peak: f32,
+
     /// The total number of tracks in this album.
     num_tracks: usize,
 }
@@ -73,6 +147,7 @@ impl AlbumTask {
             album_id: self.album_id,
             file_id: self.file_id,
             loudness: bs1770::gated_mean(channel0.as_ref()),
+            true_peak: self.true_peak,
         }).unwrap();
     }
 }
@@ -80,6 +155,7 @@ impl AlbumTask {
 struct TrackResult {
     album_id: AlbumId,
     meters: [ChannelLoudnessMeter; 2],
+    true_peak: f32,
 }
 
 /// Tracks the state of loudness analysis for one track.
@@ -120,6 +196,7 @@ impl TrackTask {
             ChannelLoudnessMeter::new(streaminfo.sample_rate),
             ChannelLoudnessMeter::new(streaminfo.sample_rate),
         ];
+        let mut peak_meters = [TruePeakMeter::new(), TruePeakMeter::new()];
 
         let mut blocks = reader.blocks();
         let mut buffer = Vec::new();
@@ -131,6 +208,9 @@ impl TrackTask {
             for (ch, meter) in meters.iter_mut().enumerate() {
                 meter.push(block.channel(ch as u32).iter().map(|s| *s as f32 * normalizer));
             }
+            for (ch, meter) in peak_meters.iter_mut().enumerate() {
+                meter.push(block.channel(ch as u32).iter().map(|s| *s as f32 * normalizer));
+            }
             buffer = block.into_buffer();
         }
 
@@ -139,17 +219,20 @@ impl TrackTask {
             meters[0].as_100ms_windows(),
             meters[1].as_100ms_windows(),
         );
+        let true_peak = peak_meters[0].peak().max(peak_meters[1].peak());
 
         inserts.send(Insert::Track {
             track_id: self.track_id,
             file_id: self.file_id,
             loudness: bs1770::gated_mean(zipped.as_ref()),
+            true_peak: true_peak,
             waveform: Waveform::from_meters(&meters),
         }).unwrap();
 
         let result = TrackResult {
             album_id: self.track_id.album_id(),
             meters: meters,
+            true_peak: true_peak,
         };
 
         Ok(result)
@@ -176,12 +259,14 @@ enum Insert {
         track_id: TrackId,
         file_id: FileId,
         loudness: bs1770::Power,
+        true_peak: f32,
         waveform: Waveform,
     },
     Album {
         album_id: AlbumId,
         file_id: FileId,
         loudness: bs1770::Power,
+        true_peak: f32,
     }
 }
 
@@ -207,12 +292,24 @@ fn process_inserts(
 
     for insert in inserts {
         match insert {
-            Insert::Track { track_id, file_id, loudness, waveform } => {
-                db::insert_track_loudness(&mut tx, track_id.0 as i64, file_id.0, loudness.loudness_lkfs() as f64)?;
+            Insert::Track { track_id, file_id, loudness, true_peak, waveform } => {
+                db::insert_track_loudness(
+                    &mut tx,
+                    track_id.0 as i64,
+                    file_id.0,
+                    loudness.loudness_lkfs() as f64,
+                    amplitude_to_db(true_peak),
+                )?;
                 db::insert_track_waveform(&mut tx, track_id.0 as i64, file_id.0, waveform.as_bytes())?;
             }
-            Insert::Album { album_id, file_id, loudness } => {
-                db::insert_album_loudness(&mut tx, album_id.0 as i64, file_id.0, loudness.loudness_lkfs() as f64)?;
+            Insert::Album { album_id, file_id, loudness, true_peak } => {
+                db::insert_album_loudness(
+                    &mut tx,
+                    album_id.0 as i64,
+                    file_id.0,
+                    loudness.loudness_lkfs() as f64,
+                    amplitude_to_db(true_peak),
+                )?;
                 tx.commit()?;
                 tx = db.begin()?;
             }
@@ -288,6 +385,7 @@ impl<'a> TaskQueue<'a> {
                 .expect("Album contains at least one track."),
             tracks_pending: tracks.iter().map(|kv| kv.track_id).collect(),
             tracks_done: Vec::with_capacity(tracks.len()),
+            true_peak: 0.0,
             num_tracks: tracks.len(),
         };
         self.status.albums_to_process_loudness += 1;
@@ -331,16 +429,23 @@ impl<'a> TaskQueue<'a> {
                     self.push_task_album(album_id);
                     continue 'albums
                 }
+
+                // Loudness from before we measured the true peak lacks it.
+                if db::select_track_true_peak_dbtp(tx, track_id.0 as i64)?.is_none() {
+                    self.push_task_album(album_id);
+                    continue 'albums
+                }
             }
         }
 
         Ok(())
     }
 
-    fn finish_track(&mut self, album_id: AlbumId, meters: [ChannelLoudnessMeter; 2]) {
+    fn finish_track(&mut self, album_id: AlbumId, meters: [ChannelLoudnessMeter; 2], true_peak: f32) {
         for album_task in self.tasks.iter_mut().rev() {
             if album_task.album_id == album_id {
                 album_task.tracks_done.push(meters);
+                album_task.true_peak = album_task.true_peak.max(true_peak);
                 return;
             }
         }
@@ -352,7 +457,7 @@ impl<'a> TaskQueue<'a> {
     fn get_next_task(&mut self, prev_result: TaskResult) -> Option<Task> {
         match prev_result {
             TaskResult::Track(track_result) => {
-                self.finish_track(track_result.album_id, track_result.meters);
+                self.finish_track(track_result.album_id, track_result.meters, track_result.true_peak);
                 self.status.tracks_processed_loudness += 1;
                 self.status_sender.send(*self.status).unwrap();
             }
@@ -497,3 +602,31 @@ impl<'a> TaskQueue<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{amplitude_to_db, TruePeakMeter};
+
+    #[test]
+    fn true_peak_meter_finds_peak_between_samples() {
+        // A full scale sine at a quarter of the sample rate, with its peaks
+        // exactly halfway between the samples. The samples are all ±0.707.
+        let samples = (0..1000).map(|i| {
+            let t = i as f32 * std::f32::consts::FRAC_PI_2 + std::f32::consts::FRAC_PI_4;
+            t.sin()
+        });
+        let mut meter = TruePeakMeter::new();
+        meter.push(samples);
+        assert!(meter.peak() > 0.97, "Peak {} should be close to 1.0.", meter.peak());
+        assert!(meter.peak() < 1.03, "Peak {} should be close to 1.0.", meter.peak());
+    }
+
+    #[test]
+    fn true_peak_meter_reports_silence_as_zero() {
+        let mut meter = TruePeakMeter::new();
+        meter.push(std::iter::repeat(0.0).take(100));
+        assert_eq!(meter.peak(), 0.0);
+        assert_eq!(amplitude_to_db(meter.peak()), -100.0);
+        assert_eq!(amplitude_to_db(1.0), 0.0);
+    }
+}
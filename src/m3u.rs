@@ -0,0 +1,291 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2021 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Reading and writing playlists in the (extended) M3U format.
+//!
+//! We always write M3U8, which is M3U encoded as UTF-8. When reading, entries
+//! are resolved against the index: first by path, then by the trailing path
+//! components (for files that moved, or playlists made on a different machine),
+//! and finally by the artist and title in the `#EXTINF` line.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use crate::prim::TrackId;
+use crate::string_utils::{equals_normalized, normalize_words};
+use crate::MetaIndex;
+
+/// Metadata from an `#EXTINF:<duration>,<artist> - <title>` line.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ExtInf {
+    /// Duration in seconds, if known. M3U uses -1 for unknown.
+    pub duration_seconds: Option<u32>,
+    pub artist: Option<String>,
+    pub title: String,
+}
+
+/// A single entry in an M3U file.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Entry {
+    /// The path as it occurs in the file, possibly relative.
+    pub path: String,
+    pub info: Option<ExtInf>,
+}
+
+fn parse_extinf(line: &str) -> Option<ExtInf> {
+    let (duration, name) = line.split_once(',')?;
+    // The duration can be followed by attributes (`-1 tvg-id="..."`), which
+    // we ignore.
+    let duration = duration.split_whitespace().next()?;
+    let duration_seconds = match duration.parse::<f64>() {
+        Ok(d) if d >= 0.0 => Some(d.round() as u32),
+        _ => None,
+    };
+    let (artist, title) = match name.split_once(" - ") {
+        Some((artist, title)) => (Some(artist.trim().to_string()), title.trim()),
+        None => (None, name.trim()),
+    };
+    let result = ExtInf {
+        duration_seconds,
+        artist,
+        title: title.to_string(),
+    };
+    Some(result)
+}
+
+/// Parse an M3U or extended M3U file.
+///
+/// Unknown directives and comments are ignored.
+pub fn parse(src: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut info = None;
+
+    // Strip the byte order mark, if there is one.
+    let src = src.strip_prefix('\u{feff}').unwrap_or(src);
+
+    for line in src.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            info = parse_extinf(extinf);
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let entry = Entry {
+            path: line.to_string(),
+            info: info.take(),
+        };
+        entries.push(entry);
+    }
+
+    entries
+}
+
+/// Split a path on both forward and backward slashes.
+///
+/// We might be importing a playlist that was written on Windows.
+fn path_components(path: &str) -> Vec<&str> {
+    path.split(|ch| ch == '/' || ch == '\\').filter(|c| !c.is_empty()).collect()
+}
+
+/// Resolves playlist entries to tracks in the index.
+pub struct Resolver<'a> {
+    index: &'a dyn MetaIndex,
+    by_path: HashMap<&'a str, TrackId>,
+    by_file_name: HashMap<&'a str, Vec<TrackId>>,
+    max_edits: u32,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(index: &'a dyn MetaIndex, max_edits: u32) -> Resolver<'a> {
+        let mut by_path = HashMap::new();
+        let mut by_file_name = HashMap::new();
+        for kv in index.get_tracks() {
+            let path = index.get_filename(kv.track.filename);
+            by_path.insert(path, kv.track_id);
+            if let Some(file_name) = path_components(path).last() {
+                by_file_name.entry(*file_name).or_insert_with(Vec::new).push(kv.track_id);
+            }
+        }
+        Resolver {
+            index,
+            by_path,
+            by_file_name,
+            max_edits,
+        }
+    }
+
+    /// Find the track that shares the most trailing path components with the
+    /// entry. Returns `None` when there is no unique best match.
+    fn resolve_suffix(&self, path: &str) -> Option<TrackId> {
+        let components = path_components(path);
+        let candidates = self.by_file_name.get(components.last()?)?;
+
+        let mut best = None;
+        let mut best_len = 0;
+        let mut is_tie = false;
+
+        for &track_id in candidates {
+            let track = self.index.get_track(track_id).unwrap();
+            let candidate = path_components(self.index.get_filename(track.filename));
+            let len = candidate
+                .iter()
+                .rev()
+                .zip(components.iter().rev())
+                .take_while(|(x, y)| x == y)
+                .count();
+            if len > best_len {
+                best = Some(track_id);
+                best_len = len;
+                is_tie = false;
+            } else if len == best_len {
+                is_tie = true;
+            }
+        }
+
+        if is_tie { None } else { best }
+    }
+
+    /// Find the track by artist and title, tolerating typos.
+    fn resolve_info(&self, info: &ExtInf) -> Option<TrackId> {
+        let mut words = Vec::new();
+        normalize_words(&info.title, &mut words);
+        let mut candidates = Vec::new();
+        self.index.search_track(&words[..], self.max_edits, &mut candidates);
+
+        let mut best = None;
+        let mut best_delta = u32::MAX;
+
+        for track_id in candidates {
+            let track = self.index.get_track(track_id).unwrap();
+            let is_match = match info.artist.as_ref() {
+                Some(artist) => equals_normalized(self.index.get_string(track.artist), artist),
+                // Without artist we only trust an exact title match.
+                None => equals_normalized(self.index.get_string(track.title), &info.title),
+            };
+            if !is_match {
+                continue;
+            }
+
+            // Prefer the track closest in duration, and reject tracks that are
+            // clearly a different recording.
+            let delta = match info.duration_seconds {
+                Some(d) => (d as i64 - track.duration_seconds as i64).unsigned_abs() as u32,
+                None => 0,
+            };
+            if delta <= 5 && delta < best_delta {
+                best = Some(track_id);
+                best_delta = delta;
+            }
+        }
+
+        best
+    }
+
+    /// Resolve an entry to a track.
+    ///
+    /// Relative paths are resolved against `base_dir` if it is provided.
+    pub fn resolve(&self, entry: &Entry, base_dir: Option<&Path>) -> Option<TrackId> {
+        let path = match base_dir {
+            Some(dir) => dir.join(&entry.path).to_string_lossy().into_owned(),
+            None => entry.path.clone(),
+        };
+
+        if let Some(&track_id) = self.by_path.get(&path[..]) {
+            return Some(track_id);
+        }
+        if let Some(track_id) = self.resolve_suffix(&entry.path) {
+            return Some(track_id);
+        }
+        match entry.info.as_ref() {
+            Some(info) => self.resolve_info(info),
+            None => None,
+        }
+    }
+}
+
+/// Write the tracks as an extended M3U8 playlist.
+///
+/// Entries contain the full path of the file, so the playlist is usable by
+/// other players on the machine where the library lives.
+pub fn write_m3u8<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    tracks: &[TrackId],
+) -> io::Result<()> {
+    writeln!(w, "#EXTM3U")?;
+    for &track_id in tracks {
+        let track = match index.get_track(track_id) {
+            Some(t) => t,
+            None => continue,
+        };
+        writeln!(
+            w,
+            "#EXTINF:{},{} - {}",
+            track.duration_seconds,
+            index.get_string(track.artist),
+            index.get_string(track.title),
+        )?;
+        writeln!(w, "{}", index.get_filename(track.filename))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, path_components, Entry, ExtInf};
+
+    #[test]
+    fn parse_handles_extended_m3u() {
+        let src = "\u{feff}#EXTM3U\n\
+            #EXTINF:251,Daði Freyr - Think About Things\r\n\
+            /music/Daði Freyr/01 Think About Things.flac\n\
+            \n\
+            # A comment.\n\
+            relative/track.flac\n\
+            #EXTINF:-1,No Artist\n\
+            C:\\Music\\track.flac\n";
+        let entries = parse(src);
+        assert_eq!(
+            entries,
+            vec![
+                Entry {
+                    path: "/music/Daði Freyr/01 Think About Things.flac".to_string(),
+                    info: Some(ExtInf {
+                        duration_seconds: Some(251),
+                        artist: Some("Daði Freyr".to_string()),
+                        title: "Think About Things".to_string(),
+                    }),
+                },
+                Entry {
+                    path: "relative/track.flac".to_string(),
+                    info: None,
+                },
+                Entry {
+                    path: "C:\\Music\\track.flac".to_string(),
+                    info: Some(ExtInf {
+                        duration_seconds: None,
+                        artist: None,
+                        title: "No Artist".to_string(),
+                    }),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn path_components_splits_on_both_slashes() {
+        assert_eq!(path_components("/a/b\\c.flac"), ["a", "b", "c.flac"]);
+        assert_eq!(path_components("C:\\Music\\x.flac"), ["C:", "Music", "x.flac"]);
+    }
+}
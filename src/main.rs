@@ -13,6 +13,8 @@ extern crate crossbeam;
 extern crate musium;
 extern crate serde_json;
 extern crate tiny_http;
+#[macro_use]
+extern crate tracing;
 extern crate url;
 extern crate walkdir;
 
@@ -20,22 +22,44 @@ use std::env;
 use std::fs;
 use std::io::{BufRead, Write};
 use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::str::FromStr;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 
+use musium::backup;
+use musium::client::Client;
+use musium::config;
 use musium::config::Config;
+use musium::ctl::{self, CtlCommand};
 use musium::database;
 use musium::database_utils;
-use musium::error::Result;
+use musium::error::{Error, Result};
+use musium::events::EventBus;
+use musium::listen_export;
+use musium::listen_import;
+use musium::listen_repair;
+use musium::log;
+use musium::maintenance;
 use musium::mvar::MVar;
+use musium::playback;
+use musium::player::QueueId;
+use musium::reload;
+use musium::scan;
 use musium::server::{MetaServer, serve};
-use musium::string_utils::normalize_words;
+use musium::sessions;
+use musium::shutdown;
+use musium::string_utils::{equals_normalized, normalize_words};
 use musium::thumb_cache::ThumbCache;
-use musium::user_data::UserData;
+use musium::thumb_gen;
+use musium::tui;
+use musium::user_data::UserDataSet;
+use musium::verify;
 use musium::{MetaIndex, MemoryMetaIndex};
 
-fn make_index(tx: &mut database::Transaction) -> Result<MemoryMetaIndex> {
-    let (index, builder) = MemoryMetaIndex::from_database(tx)?;
+fn make_index(config: &Config, tx: &mut database::Transaction) -> Result<MemoryMetaIndex> {
+    let (index, builder) = MemoryMetaIndex::from_database(tx, config.album_identity)?;
 
     for issue in &builder.issues {
         println!("{}\n", issue);
@@ -111,20 +135,11 @@ fn make_index(tx: &mut database::Transaction) -> Result<MemoryMetaIndex> {
     println!("Artist word index: {}", index.words_artist.size());
     println!("Album word index:  {}", index.words_album.size());
     println!("Track word index:  {}", index.words_track.size());
+    println!("Index memory:      {:4} kB", index.memory_usage().total() / 1000);
 
     Ok(index)
 }
 
-/// Return whether the two strings are equal after extracting normalized words.
-fn equals_normalized(x1: &str, x2: &str) -> bool {
-    // TODO: Figure out a faster way to do this.
-    let mut w1 = Vec::new();
-    let mut w2 = Vec::new();
-    normalize_words(x1, &mut w1);
-    normalize_words(x2, &mut w2);
-    w1 == w2
-}
-
 fn match_listens(
     index: &MemoryMetaIndex,
     in_path: String,
@@ -157,7 +172,8 @@ fn match_listens(
         normalize_words(track_title, &mut words);
         normalize_words(artist_name, &mut words);
         // TODO: Add a way to turn off prefix search for the last word.
-        index.search_track(&words[..], &mut tracks);
+        // We want exact matches here, so do not tolerate typos.
+        index.search_track(&words[..], 0, &mut tracks);
 
         let mut found = false;
 
@@ -199,6 +215,88 @@ fn match_listens(
     Ok(())
 }
 
+fn import_playlist(
+    config: &Config,
+    index: &MemoryMetaIndex,
+    in_path: String,
+) -> Result<()> {
+    let path = Path::new(&in_path);
+    let src = fs::read_to_string(path)?;
+    let name = match path.file_stem() {
+        Some(stem) => stem.to_string_lossy().into_owned(),
+        None => in_path.clone(),
+    };
+
+    // Relative paths in the playlist are relative to the playlist file.
+    let base_dir = path.parent();
+    let resolver = musium::m3u::Resolver::new(index, config.search_max_edits);
+    let mut tracks = Vec::new();
+
+    for entry in musium::m3u::parse(&src) {
+        match resolver.resolve(&entry, base_dir) {
+            Some(track_id) => tracks.push(track_id),
+            None => println!("MISSING: {}", entry.path),
+        }
+    }
+
+    let now = chrono::Utc::now();
+    let use_zulu_suffix = true;
+    let now_str = now.to_rfc3339_opts(chrono::SecondsFormat::Millis, use_zulu_suffix);
+
+    let conn = database_utils::connect_read_write(&config.db_path)?;
+    database_utils::migrate(&conn)?;
+    let mut db = database::Connection::new(&conn);
+    let mut tx = db.begin()?;
+    // Playlists imported from the command line belong to the default user.
+    let user = None;
+    let playlist_id = musium::playlist::create_with_tracks(&mut tx, &name, &now_str, user, &tracks[..])?;
+    tx.commit()?;
+
+    println!(
+        "Imported {} tracks into playlist {} (id {}).",
+        tracks.len(), name, playlist_id,
+    );
+
+    Ok(())
+}
+
+/// Print scan status updates in place, until the sender is dropped.
+fn print_scan_status(rx: mpsc::Receiver<scan::Status>) {
+    let stdout = io::stdout();
+    let mut lock = stdout.lock();
+
+    write!(lock, "\n\n\n\n\n").unwrap();
+
+    for status in rx {
+        // Move the cursor up a line, and clear that line. We need to clear
+        // it, because "convert" sometimes prints warnings. We could swallow
+        // its stderr, but this allows the warning to at least be visible
+        // very briefly.
+        let up_clear = "\x1b[F\x1b[K";
+        write!(lock, "{0}{0}{0}{0}{0}{0}{1}", up_clear, status).unwrap();
+        lock.flush().unwrap();
+    }
+}
+
+/// Print verification progress in place, until the sender is dropped.
+fn print_verify_status(rx: mpsc::Receiver<scan::Status>) {
+    let stdout = io::stdout();
+    let mut lock = stdout.lock();
+
+    writeln!(lock).unwrap();
+
+    for status in rx {
+        write!(
+            lock,
+            "\x1b[F\x1b[KVerifying: {} of {} files, {} failed\n",
+            status.files_verified,
+            status.files_to_verify,
+            status.files_failed_verification,
+        ).unwrap();
+        lock.flush().unwrap();
+    }
+}
+
 fn run_scan(config: &Config) -> Result<()> {
     // Running a scan requires an index var that the scan can update. When
     // triggered from the server this updates the servers index, but when we
@@ -209,131 +307,606 @@ fn run_scan(config: &Config) -> Result<()> {
     let index_var = Arc::new(MVar::new(Arc::new(dummy_index)));
     let thumb_cache_var = Arc::new(MVar::new(Arc::new(dummy_thumb_cache)));
 
-    let (scan_thread, rx) = musium::scan::run_scan_in_thread(
+    let (scan_thread, rx) = scan::run_scan_in_thread(
         config,
         index_var,
         thumb_cache_var,
     );
 
-    {
-        let stdout = io::stdout();
-        let mut lock = stdout.lock();
+    print_scan_status(rx);
 
-        write!(lock, "\n\n\n\n\n").unwrap();
+    // The unwrap unwraps the join, not the scan's result.
+    scan_thread.join().unwrap()
+}
 
-        for status in rx {
-            // Move the cursor up a line, and clear that line. We need to clear
-            // it, because "convert" sometimes prints warnings. We could swallow
-            // its stderr, but this allows the warning to at least be visible
-            // very briefly.
-            let up_clear = "\x1b[F\x1b[K";
-            write!(lock, "{0}{0}{0}{0}{0}{0}{1}", up_clear, status).unwrap();
-            lock.flush().unwrap();
+fn import_listens(
+    config: &Config,
+    index: &MemoryMetaIndex,
+    in_paths: Vec<String>,
+) -> Result<()> {
+    let conn = database_utils::connect_read_write(&config.db_path)?;
+    database_utils::migrate(&conn)?;
+    let mut db = database::Connection::new(&conn);
+    let mut tx = db.begin()?;
+    let matcher = listen_import::Matcher::new(&mut tx, index)?;
+
+    let mut total = 0_u32;
+    let mut imported = 0_u32;
+    let mut duplicate = 0_u32;
+    let mut unmatched = 0_u32;
+
+    for in_path in in_paths {
+        let src = fs::read_to_string(&in_path)?;
+        let format = listen_import::Format::detect(&in_path, &src);
+        let listens = match listen_import::parse(format, &src) {
+            Ok(listens) => listens,
+            Err(msg) => {
+                eprintln!("Failed to parse {}: {}", in_path, msg);
+                process::exit(1);
+            }
+        };
+        println!("Read {} listens from {} ({:?}).", listens.len(), in_path, format);
+
+        for listen in &listens {
+            match matcher.find(listen) {
+                Some(track_id) => {
+                    let source = format.source();
+                    let inserted = listen_import::insert_listen(&mut tx, index, source, listen.listened_at, track_id)?;
+                    if inserted.is_some() {
+                        imported += 1;
+                    } else {
+                        duplicate += 1;
+                    }
+                }
+                None => {
+                    println!(
+                        "UNMATCHED: at {} listened {} by {} from {}",
+                        listen.listened_at.format_iso8601(),
+                        listen.title,
+                        listen.artist,
+                        listen.album.as_deref().unwrap_or("unknown album"),
+                    );
+                    unmatched += 1;
+                }
+            }
+            total += 1;
         }
     }
 
-    // The unwrap unwraps the join, not the scan's result.
-    scan_thread.join().unwrap()
+    sessions::assign_sessions(&mut tx)?;
+    database::rebuild_first_listens(&mut tx)?;
+    tx.commit()?;
+
+    println!(
+        "Imported {} out of {} listens. {} were present already, {} did not match a track.",
+        imported, total, duplicate, unmatched,
+    );
+
+    Ok(())
+}
+
+fn repair_listens(config: &Config, dry_run: bool) -> Result<()> {
+    let conn = database_utils::connect_read_write(&config.db_path)?;
+    database_utils::migrate(&conn)?;
+    let mut db = database::Connection::new(&conn);
+    let mut tx = db.begin()?;
+
+    let mut listens = listen_repair::load_listens(&mut tx)?;
+    let total = listens.len();
+    let merges = listen_repair::find_duplicates(&mut listens);
+    let now_seconds = chrono::Utc::now().timestamp();
+    let completions = listen_repair::find_orphans(&listens, now_seconds);
+
+    for m in &merges {
+        println!(
+            "DUPLICATE: listen {} of track {} at {}, merge into listen {}",
+            m.drop_id, m.track_id, m.started_at, m.keep_id,
+        );
+    }
+    for c in &completions {
+        println!(
+            "ORPHAN: listen {} at {}, set completed at {}",
+            c.listen_id, c.started_at, c.completed_at,
+        );
+    }
+
+    if dry_run {
+        tx.rollback()?;
+        println!(
+            "Checked {} listens. Would merge {} duplicates and complete {} orphans.",
+            total, merges.len(), completions.len(),
+        );
+    } else {
+        listen_repair::apply(&mut tx, &merges, &completions)?;
+        tx.commit()?;
+        println!(
+            "Checked {} listens. Merged {} duplicates and completed {} orphans.",
+            total, merges.len(), completions.len(),
+        );
+    }
+
+    Ok(())
+}
+
+fn export_listens(config: &Config, out_path: String) -> Result<()> {
+    let conn = database_utils::connect_readonly(&config.db_path)?;
+    let mut db = database::Connection::new(&conn);
+    let mut tx = db.begin()?;
+
+    let format = listen_export::Format::from_file_name(&out_path);
+    let f = fs::File::create(&out_path)?;
+    let n = listen_export::write_listens(&mut tx, format, io::BufWriter::new(f))?;
+    tx.commit()?;
+
+    println!("Exported {} listens to {}.", n, out_path);
+    Ok(())
+}
+
+/// Delete all thumbnails and generate them again, without scanning.
+fn regenerate_thumbs(config: &Config) -> Result<()> {
+    let conn = database_utils::connect_read_write(&config.db_path)?;
+    database_utils::migrate(&conn)?;
+    let mut db = database::Connection::new(&conn);
+    let mut tx = db.begin()?;
+    let (index, _builder) = MemoryMetaIndex::from_database(&mut tx, config.album_identity)?;
+    database::delete_thumbnails(&mut tx)?;
+    tx.commit()?;
+
+    let (mut sender, rx) = mpsc::sync_channel(15);
+    let db_path = config.db_path.clone();
+    let thumb_thread = std::thread::Builder::new()
+        .name("thumb_gen".to_string())
+        .spawn(move || {
+            let mut status = scan::Status::new();
+            thumb_gen::generate_thumbnails(&index, &db_path, &mut status, &mut sender)
+        })
+        .expect("Failed to spawn thumbnail generation thread.");
+
+    print_scan_status(rx);
+
+    // The unwrap unwraps the join, not the generation's result.
+    thumb_thread.join().unwrap()
+}
+
+/// Print library statistics: the size of the index, loudness, and thumbnails.
+fn print_stats(config: &Config) -> Result<()> {
+    let conn = database_utils::connect_readonly(&config.db_path)?;
+    let mut db = database::Connection::new(&conn);
+    let mut tx = db.begin()?;
+
+    // Building the index prints the number of artists, albums, and tracks,
+    // and the loudness distribution.
+    let index = make_index(config, &mut tx)?;
+    let total_seconds: u64 = index
+        .get_tracks()
+        .iter()
+        .map(|kv| kv.track.duration_seconds as u64)
+        .sum();
+    let (thumb_count, thumb_bytes) = database::select_thumbnails_count_and_total_size(&mut tx)?;
+    tx.commit()?;
+
+    println!(
+        "\nTotal duration: {} days, {} hours, {} minutes.",
+        total_seconds / (24 * 3600),
+        (total_seconds / 3600) % 24,
+        (total_seconds / 60) % 60,
+    );
+    println!(
+        "Thumbnails: {} ({:.1} MiB).",
+        thumb_count,
+        thumb_bytes as f64 / (1024.0 * 1024.0),
+    );
+
+    Ok(())
 }
 
 fn print_usage() {
     println!("\
 Usage:
 
-  musium scan musium.conf
   musium serve musium.conf
+  musium scan musium.conf
+  musium check musium.conf
+  musium stats musium.conf
+  musium regenerate-thumbs musium.conf
   musium match musium.conf listenbrainz.tsv matched.tsv
+  musium import musium.conf playlist.m3u8
+  musium import-listens musium.conf export.csv [export.json ...]
+  musium repair-listens musium.conf [--dry-run]
+  musium export-listens musium.conf listens.ndjson|listens.csv
+  musium backup musium.conf backup.sqlite3
+  musium maintenance musium.conf
+  musium verify musium.conf
+  musium tui [http://localhost:8233]
+  musium ctl play|pause|next|prev|status
+  musium ctl queue <query>
+
+All commands other than serve, tui, and ctl work on the database directly, they do not
+start the server or the player, so they can run from cron jobs and scripts.
+Commands that only read the database can run while the server is running.
+
+SERVE
+
+  Start the server. Requires running a scan first for serving an up-to-date
+  library.
 
 SCAN
 
   Update the file database, generate album art thumbnails.
 
-SERVE
+CHECK
 
-  Start the server. Requires running a scan first for serving an up-to-date
-  library.
+  Validate the configuration file, including overrides from MUSIUM_<KEY>
+  environment variables, and check that the paths it refers to exist and that
+  the audio output is present. Exits with a nonzero status if the
+  configuration is invalid. Also available as 'musium config check'.
+
+STATS
+
+  Print the number of artists, albums, and tracks in the library, the
+  loudness distribution, the total duration, and the size of the thumbnails.
+
+REGENERATE-THUMBS
+
+  Delete all album art thumbnails, and generate them again from the files,
+  without scanning the library. Useful after changing how thumbnails are made.
 
 MATCH
 
-  Match listens (see process_listens.py) to tracks.");
+  Match listens (see process_listens.py) to tracks.
+
+IMPORT
+
+  Import an M3U or M3U8 playlist into the database. The playlist is named
+  after the file.
+
+IMPORT-LISTENS
+
+  Import listening history from a Last.fm CSV or JSON export, or from a
+  ListenBrainz export, into the database. Listens that do not match a track in
+  the library are reported, and not imported.
+
+REPAIR-LISTENS
+
+  Merge duplicate listens, such as those from an import that overlaps with
+  listens that Musium recorded, and set the completion time of listens that
+  were left incomplete by a crash. With --dry-run, only report the changes.
+
+EXPORT-LISTENS
+
+  Export the full listening history, oldest first. The output is CSV when the
+  file name ends in .csv, and newline-delimited JSON otherwise.
+
+BACKUP
+
+  Write a consistent copy of the database to a new file. This is safe to do
+  while the server is running.
+
+MAINTENANCE
+
+  Check the integrity of the database, update statistics for the query
+  planner, and release unused space to the file system.
+
+VERIFY
+
+  Decode every file in the database, and compare its audio against the MD5 in
+  its streaminfo block, to detect files that were damaged on disk. Failures
+  are printed, and recorded in the database. Exits with a nonzero status if
+  any file failed.
+
+TUI
+
+  Browse the library and control the queue of a running server from the
+  terminal. The url defaults to MUSIUM_URL, or http://localhost:8233. When the
+  server requires authentication, set MUSIUM_TOKEN to an API token.
+
+CTL
+
+  Control a running server from scripts and keybindings. 'next' skips the
+  current track, 'prev' restarts it or plays the previous track, 'pause' pauses
+  playback and 'play' continues it, 'status' prints what is playing, and
+  'queue' enqueues the best match for the query: the first matching track, or
+  else the first matching album. 'pause' exits with a nonzero status when the
+  queue is empty. Like tui, this uses MUSIUM_URL and MUSIUM_TOKEN.");
+}
+
+/// A subcommand and its arguments, other than the config file.
+enum Command {
+    Serve,
+    Scan,
+    Check,
+    Stats,
+    RegenerateThumbs,
+    Match { in_path: String, out_path: String },
+    Import { in_path: String },
+    ImportListens { in_paths: Vec<String> },
+    RepairListens { dry_run: bool },
+    ExportListens { out_path: String },
+    Backup { out_path: String },
+    Maintenance,
+    Verify,
+}
+
+impl Command {
+    /// Parse the command line, without the program name, into the command
+    /// and the path of the config file.
+    fn parse(args: &[String]) -> Option<(Command, &str)> {
+        let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        let (cmd, config_path, rest) = match args[..] {
+            // `config check` predates the `check` command, we still accept it.
+            ["config", "check", config_path] => return Some((Command::Check, config_path)),
+            [cmd, config_path, ref rest @ ..] => (cmd, config_path, rest),
+            _ => return None,
+        };
+        let owned = |s: &str| s.to_string();
+        let command = match (cmd, rest) {
+            ("serve", []) => Command::Serve,
+            ("scan", []) => Command::Scan,
+            ("check", []) => Command::Check,
+            ("stats", []) => Command::Stats,
+            ("regenerate-thumbs", []) => Command::RegenerateThumbs,
+            ("match", [in_path, out_path]) => Command::Match {
+                in_path: owned(in_path),
+                out_path: owned(out_path),
+            },
+            ("import", [in_path]) => Command::Import { in_path: owned(in_path) },
+            ("import-listens", in_paths) if !in_paths.is_empty() => Command::ImportListens {
+                in_paths: in_paths.iter().map(|p| owned(p)).collect(),
+            },
+            ("repair-listens", []) => Command::RepairListens { dry_run: false },
+            ("repair-listens", ["--dry-run"]) => Command::RepairListens { dry_run: true },
+            ("export-listens", [out_path]) => Command::ExportListens { out_path: owned(out_path) },
+            ("backup", [out_path]) => Command::Backup { out_path: owned(out_path) },
+            ("maintenance", []) => Command::Maintenance,
+            ("verify", []) => Command::Verify,
+            _ => return None,
+        };
+        Some((command, config_path))
+    }
+}
+
+/// Set up logging from the config, or from `MUSIUM_LOG` if it is set.
+fn init_log(config: &Config) {
+    let filter = match env::var("MUSIUM_LOG") {
+        Ok(value) => match log::Filter::from_str(&value) {
+            Ok(filter) => filter,
+            Err(msg) => {
+                eprintln!("Invalid MUSIUM_LOG value: {}", msg);
+                process::exit(1);
+            }
+        },
+        Err(..) => config.log_level.clone(),
+    };
+    log::init(filter, config.log_format);
+}
+
+/// Make the configured time zone the local time zone of the process.
+///
+/// Both chrono and SQLite read `TZ`, so this sets the time zone for statistics
+/// and for the offsets that we record with listens. This must happen before we
+/// start any threads.
+fn init_timezone(config: &Config) {
+    if let Some(tz) = &config.timezone {
+        if !Path::new("/usr/share/zoneinfo").join(tz).is_file() {
+            warn!("Time zone {} is not in /usr/share/zoneinfo, times may be in UTC.", tz);
+        }
+        env::set_var("TZ", tz);
+    }
+}
+
+/// Load the config, or print why it is invalid and exit.
+fn load_config_or_exit(config_fname: &str) -> Config {
+    let path = Path::new(config_fname);
+    match Config::load(path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", config::describe_error(path, &err));
+            process::exit(1);
+        }
+    }
+}
+
+/// Validate the config without starting anything, for `musium check`.
+fn check_config(config_fname: &str) -> ! {
+    let config = load_config_or_exit(config_fname);
+    println!("Configuration:\n{}\n", config);
+
+    let mut problems = config.check_paths();
+    if let Err(msg) = playback::check_audio_output(&config) {
+        problems.push(msg.to_string());
+    }
+
+    if problems.is_empty() {
+        println!("The configuration is valid.");
+        process::exit(0);
+    }
+
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    process::exit(1);
 }
 
-fn load_config(config_fname: &str) -> Result<Config> {
-    let f = fs::File::open(config_fname)?;
-    let buf_reader = io::BufReader::new(f);
-    let lines: io::Result<Vec<String>> = buf_reader.lines().collect();
-    Config::parse(lines?.iter())
+/// Load the index, for commands that need to match against the library.
+fn load_index(config: &Config) -> Result<MemoryMetaIndex> {
+    let conn = database_utils::connect_readonly(&config.db_path)?;
+    let mut db = database::Connection::new(&conn);
+    let mut tx = db.begin()?;
+    let index = make_index(config, &mut tx)?;
+    tx.commit()?;
+    Ok(index)
+}
+
+fn serve_forever(config: Config, config_path: &str) -> Result<()> {
+    // The server reloads the config (and the TLS certificate) on SIGHUP, and
+    // shuts down gracefully on SIGTERM and SIGINT. For that, the signals must
+    // be blocked before we spawn any threads.
+    reload::block_sighup();
+    shutdown::block_signals();
+
+    // Newer versions of Musium may change the schema, migrate it before we
+    // load anything from the database. The queue that we saved when we last
+    // shut down, we take out, so that after a crash we don't restore an
+    // outdated queue.
+    let saved_queue = {
+        let conn = database_utils::connect_read_write(&config.db_path)?;
+        database_utils::migrate(&conn)?;
+        database_utils::take_saved_queue(&conn)?
+    };
+
+    let conn = database_utils::connect_readonly(&config.db_path)?;
+    let mut db = database::Connection::new(&conn);
+    let mut tx = db.begin()?;
+
+    let index = make_index(&config, &mut tx)?;
+    let arc_index = Arc::new(index);
+    let index_var = Arc::new(MVar::new(arc_index));
+    info!("Index loaded.");
+
+    info!("Loading user data ...");
+    let user_data = UserDataSet::load_from_database(&mut tx)?;
+    let user_data_arc = Arc::new(Mutex::new(user_data));
+
+    info!("Loading cover art thumbnails ...");
+    let thumb_cache = ThumbCache::load_from_database(&mut tx)?;
+    info!("Thumb cache size: {}", thumb_cache.size());
+    let arc_thumb_cache = Arc::new(thumb_cache);
+    let thumb_cache_var = Arc::new(MVar::new(arc_thumb_cache));
+
+    tx.commit()?;
+    std::mem::drop(db);
+    std::mem::drop(conn);
+
+    if let Some(hours) = config.maintenance_interval_hours {
+        let interval = std::time::Duration::from_secs(hours * 3600);
+        maintenance::spawn_scheduled(config.db_path.clone(), interval);
+    }
+
+    info!("Starting server on {}.", config.listen);
+    let event_bus = Arc::new(EventBus::new());
+    let player = musium::player::Player::new(
+        index_var.clone(),
+        user_data_arc.clone(),
+        event_bus.clone(),
+        &config,
+    );
+    player.restore_queue(&index_var.get(), saved_queue);
+
+    // Every player gets its own range of queue ids, so the history thread can
+    // tell their listens apart.
+    let extra_players = config
+        .players
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let first_queue_id = QueueId((i as u64 + 1) << 48);
+            (name.clone(), player.new_remote(&config, first_queue_id))
+        })
+        .collect();
+
+    let listen = config.listen.clone();
+    let service = MetaServer::new(
+        config,
+        PathBuf::from(config_path),
+        index_var,
+        thumb_cache_var,
+        user_data_arc,
+        player,
+        extra_players,
+        event_bus,
+    );
+    serve(&listen, Arc::new(service));
 }
 
 fn main() -> Result<()> {
-    if env::args().len() < 3 {
-        print_usage();
-        process::exit(1);
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    // The terminal interface talks to a running server, it does not need a
+    // config file.
+    if args.first().map(|a| a.as_str()) == Some("tui") && args.len() <= 2 {
+        let client = Client::from_env(args.get(1).map(|a| a.as_str()));
+        return tui::main(client);
+    }
+
+    if args.first().map(|a| a.as_str()) == Some("ctl") {
+        let ctl_args: Vec<&str> = args[1..].iter().map(|a| a.as_str()).collect();
+        let cmd = match CtlCommand::parse(&ctl_args) {
+            Some(cmd) => cmd,
+            None => {
+                print_usage();
+                process::exit(1);
+            }
+        };
+        match ctl::main(&Client::from_env(None), cmd) {
+            Err(Error::ClientError(msg)) => {
+                eprintln!("{}", msg);
+                process::exit(1);
+            }
+            result => return result,
+        }
     }
 
-    let cmd = env::args().nth(1).unwrap();
-    let config_path = env::args().nth(2).unwrap();
-    let config = load_config(&config_path)?;
+    let (command, config_path) = match Command::parse(&args) {
+        Some(parsed) => parsed,
+        None => {
+            print_usage();
+            process::exit(1);
+        }
+    };
+
+    if let Command::Check = command {
+        check_config(config_path);
+    }
+
+    let config = load_config_or_exit(config_path);
+    init_log(&config);
+    init_timezone(&config);
+    thumb_gen::set_max_threads(config.thumbnail_threads);
     println!("Configuration:\n{}\n", config);
 
-    match &cmd[..] {
-        "serve" => {
-            let config_clone = config.clone();
-
-            let conn = database_utils::connect_readonly(&config.db_path)?;
-            let mut db = database::Connection::new(&conn);
-            let mut tx = db.begin()?;
-
-            let index = make_index(&mut tx)?;
-            let arc_index = Arc::new(index);
-            let index_var = Arc::new(MVar::new(arc_index));
-            println!("Index loaded.");
-
-            println!("Loading user data ...");
-            let user_data = UserData::load_from_database(&mut tx)?;
-            let user_data_arc = Arc::new(Mutex::new(user_data));
-
-            println!("Loading cover art thumbnails ...");
-            let thumb_cache = ThumbCache::load_from_database(&mut tx)?;
-            println!("Thumb cache size: {}", thumb_cache.size());
-            let arc_thumb_cache = Arc::new(thumb_cache);
-            let thumb_cache_var = Arc::new(MVar::new(arc_thumb_cache));
-
-            tx.commit()?;
-            std::mem::drop(db);
-            std::mem::drop(conn);
-
-            println!("Starting server on {}.", config.listen);
-            let player = musium::player::Player::new(
-                index_var.clone(),
-                user_data_arc.clone(),
-                &config,
-            );
-            let service = MetaServer::new(
-                config_clone,
-                index_var,
-                thumb_cache_var,
-                user_data_arc,
-                player,
-            );
-            serve(&config.listen, Arc::new(service));
+    match command {
+        Command::Serve => serve_forever(config, config_path),
+        Command::Scan => run_scan(&config),
+        Command::Check => unreachable!("The check command exits before we get here."),
+        Command::Stats => print_stats(&config),
+        Command::RegenerateThumbs => regenerate_thumbs(&config),
+        Command::Match { in_path, out_path } => {
+            let index = load_index(&config)?;
+            match_listens(&index, in_path, out_path)
+        }
+        Command::Import { in_path } => {
+            let index = load_index(&config)?;
+            import_playlist(&config, &index, in_path)
+        }
+        Command::ImportListens { in_paths } => {
+            let index = load_index(&config)?;
+            import_listens(&config, &index, in_paths)
         }
-        "scan" => {
-            run_scan(&config)?;
+        Command::RepairListens { dry_run } => repair_listens(&config, dry_run),
+        Command::ExportListens { out_path } => export_listens(&config, out_path),
+        Command::Backup { out_path } => {
+            backup::backup(&config.db_path, Path::new(&out_path))?;
+            println!("Backed up the database to {}.", out_path);
             Ok(())
         }
-        "match" => {
-            let in_path = env::args().nth(3).unwrap();
-            let out_path = env::args().nth(4).unwrap();
-            let conn = database_utils::connect_readonly(&config.db_path)?;
-            let mut db = database::Connection::new(&conn);
-            let mut tx = db.begin()?;
-            let index = make_index(&mut tx)?;
-            match_listens(&index, in_path, out_path)
+        Command::Maintenance => {
+            let report = maintenance::run(&config.db_path)?;
+            maintenance::print_report(&report);
+            if !report.is_ok() {
+                process::exit(1);
+            }
+            Ok(())
         }
-        _ => {
-            print_usage();
-            process::exit(1);
+        Command::Verify => {
+            let (verify_thread, rx) = verify::run_verify_in_thread(config.db_path.clone());
+            print_verify_status(rx);
+            // The unwrap unwraps the join, not the verification's result.
+            let report = verify_thread.join().unwrap()?;
+            verify::print_report(&report);
+            if !report.is_ok() {
+                process::exit(1);
+            }
+            Ok(())
         }
     }
 }
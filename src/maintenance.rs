@@ -0,0 +1,158 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Database maintenance: integrity and foreign key check, statistics, and vacuum.
+//!
+//! Rescans replace thumbnails and waveforms, and SQLite does not return the
+//! pages that the old ones occupied to the file system. Maintenance puts the
+//! database in incremental auto-vacuum mode, so we can release those pages
+//! without rewriting the entire database every time.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::database as db;
+use crate::database::Connection;
+use crate::database_utils;
+use crate::error::{Error, Result};
+
+/// Value of `PRAGMA auto_vacuum` for incremental mode.
+const AUTO_VACUUM_INCREMENTAL: i64 = 2;
+
+/// Set while maintenance is running, so we don't run it twice concurrently.
+static IS_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The outcome of a maintenance run.
+#[derive(Debug)]
+pub struct Report {
+    /// Problems found by the integrity check, empty if there are none.
+    pub integrity_errors: Vec<String>,
+
+    /// Whether we rewrote the database entirely, to switch it to incremental
+    /// auto-vacuum mode. This happens only once.
+    pub full_vacuum: bool,
+
+    pub size_before_bytes: i64,
+    pub size_after_bytes: i64,
+    pub duration: Duration,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.integrity_errors.is_empty()
+    }
+}
+
+fn run_internal(connection: &sqlite::Connection) -> Result<Report> {
+    let start = std::time::Instant::now();
+    let mut db = Connection::new(connection);
+
+    let mut tx = db.begin()?;
+    let mut integrity_errors: Vec<String> = db::iter_integrity_check(&mut tx)?
+        .filter(|row| row.as_ref().map(|msg| msg != "ok").unwrap_or(true))
+        .collect::<db::Result<Vec<String>>>()?;
+    for row in db::iter_foreign_key_violations(&mut tx)? {
+        let (table, rowid, parent) = row?;
+        integrity_errors.push(format!(
+            "Row {} in {} references a missing row in {}.",
+            rowid, table, parent,
+        ));
+    }
+    let size_before_bytes = db::select_database_size(&mut tx)?;
+    let auto_vacuum = db::select_auto_vacuum(&mut tx)?;
+    tx.commit()?;
+
+    let mut report = Report {
+        integrity_errors: integrity_errors,
+        full_vacuum: false,
+        size_before_bytes: size_before_bytes,
+        size_after_bytes: size_before_bytes,
+        duration: Duration::from_secs(0),
+    };
+
+    // Rewriting a corrupt database could make things worse, leave it alone.
+    if !report.is_ok() {
+        report.duration = start.elapsed();
+        return Ok(report);
+    }
+
+    database_utils::with_write_transaction(&mut db, db::analyze)?;
+
+    // Vacuum cannot run inside a transaction, so we execute these directly.
+    // Changing the auto-vacuum mode only takes effect after a full vacuum.
+    if auto_vacuum == AUTO_VACUUM_INCREMENTAL {
+        connection.execute("PRAGMA incremental_vacuum;")?;
+    } else {
+        connection.execute("PRAGMA auto_vacuum = INCREMENTAL;")?;
+        connection.execute("VACUUM;")?;
+        report.full_vacuum = true;
+    }
+
+    // Move the changes out of the WAL, so the file actually shrinks.
+    connection.execute("PRAGMA wal_checkpoint(TRUNCATE);")?;
+
+    let mut tx = db.begin()?;
+    report.size_after_bytes = db::select_database_size(&mut tx)?;
+    tx.commit()?;
+
+    report.duration = start.elapsed();
+    Ok(report)
+}
+
+/// Check the integrity of the database, update statistics, and vacuum.
+///
+/// Returns an error if maintenance is already running.
+pub fn run(db_path: &Path) -> Result<Report> {
+    if IS_RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(Error::MaintenanceRunning);
+    }
+    let result = database_utils::connect_read_write(db_path)
+        .map_err(Error::from)
+        .and_then(|connection| run_internal(&connection));
+    IS_RUNNING.store(false, Ordering::SeqCst);
+    result
+}
+
+/// Print the report in a human-readable form.
+pub fn print_report(report: &Report) {
+    if report.is_ok() {
+        println!("Integrity check found no problems.");
+    } else {
+        println!("Integrity check found {} problems:", report.integrity_errors.len());
+        for error in &report.integrity_errors {
+            println!("  {}", error);
+        }
+        println!("Skipped vacuum, restore the database from a backup.");
+        return;
+    }
+    if report.full_vacuum {
+        println!("Switched the database to incremental vacuum, this was a one-time full vacuum.");
+    }
+    println!(
+        "Database size went from {:.1} MB to {:.1} MB in {:.1} seconds.",
+        report.size_before_bytes as f64 * 1e-6,
+        report.size_after_bytes as f64 * 1e-6,
+        report.duration.as_secs_f64(),
+    );
+}
+
+/// Spawn a thread that runs maintenance every `interval`.
+pub fn spawn_scheduled(db_path: PathBuf, interval: Duration) {
+    let builder = std::thread::Builder::new();
+    builder
+        .name("maintenance".into())
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            info!("Running scheduled database maintenance ...");
+            match run(&db_path) {
+                Ok(report) => print_report(&report),
+                Err(err) => error!("Database maintenance failed: {:?}", err),
+            }
+        })
+        .expect("Failed to spawn maintenance thread.");
+}
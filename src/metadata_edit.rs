@@ -0,0 +1,309 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Editing the metadata of tracks and albums through the API.
+//!
+//! An edit replaces tags in the database, and then we rebuild the index from
+//! the database, like after a scan. Optionally, we also write the new tags into
+//! the files, see `flac_tags.rs`. Without that, an edit lasts until the file
+//! changes on disk, and a scan reads its tags again. Every change is recorded
+//! in the `metadata_edits` table.
+//!
+//! Edits never touch the MusicBrainz ids, disc numbers, or track numbers, so
+//! the track and album ids stay the same, and so do the ratings and listens
+//! that refer to them.
+
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::build::parse_date;
+use crate::database::{self as db, Transaction};
+use crate::error::Result;
+use crate::flac_tags::{self, TagUpdate};
+use crate::prim::{AlbumId, TrackId};
+use crate::{MemoryMetaIndex, MetaIndex};
+
+/// The tags that can be edited.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    OriginalDate,
+    Genre,
+}
+
+impl Field {
+    /// The lowercase tag name, as stored in the database.
+    pub fn tag_name(self) -> &'static str {
+        match self {
+            Field::Title => "title",
+            Field::Artist => "artist",
+            Field::Album => "album",
+            Field::AlbumArtist => "albumartist",
+            Field::OriginalDate => "originaldate",
+            Field::Genre => "genre",
+        }
+    }
+}
+
+/// What an edit applies to: a single track, or all tracks of an album.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Target {
+    Track(TrackId),
+    Album(AlbumId),
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Edit {
+    pub field: Field,
+    /// The new value, `None` to remove the tag.
+    pub value: Option<String>,
+}
+
+/// Parse the edits from the query string of an edit request.
+///
+/// For tracks, `title`, `artist`, and `genre` can be set. For albums, `title`
+/// and `artist` refer to the album title and album artist, and additionally
+/// `year` (the original release date) can be set. The `dry_run` and
+/// `write_tags` parameters are not edits, we skip them here.
+pub fn parse_edits(target: Target, raw_query: &str) -> std::result::Result<Vec<Edit>, &'static str> {
+    let mut edits = Vec::new();
+
+    for (key, value) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+        let field = match (target, key.as_ref()) {
+            (_, "dry_run" | "write_tags") => continue,
+            (Target::Track(..), "title") => Field::Title,
+            (Target::Track(..), "artist") => Field::Artist,
+            (Target::Album(..), "title") => Field::Album,
+            (Target::Album(..), "artist") => Field::AlbumArtist,
+            (Target::Album(..), "year") => Field::OriginalDate,
+            (_, "genre") => Field::Genre,
+            _ => return Err("Unknown metadata field."),
+        };
+        let value = value.trim();
+        let value = match field {
+            // An empty genre removes it, the other tags are required.
+            Field::Genre if value.is_empty() => None,
+            _ if value.is_empty() => return Err("Metadata values must not be empty."),
+            Field::OriginalDate if parse_date(value).is_none() => {
+                return Err("Invalid year, expected YYYY, YYYY-MM, or YYYY-MM-DD.")
+            }
+            _ => Some(value.to_string()),
+        };
+        if edits.iter().any(|e: &Edit| e.field == field) {
+            return Err("Metadata fields can be set only once.");
+        }
+        edits.push(Edit { field, value });
+    }
+
+    if edits.is_empty() {
+        return Err("No metadata to change.");
+    }
+
+    Ok(edits)
+}
+
+/// A change to a tag of one file.
+#[derive(Debug)]
+pub struct Change {
+    pub track_id: TrackId,
+    pub file_id: i64,
+    pub filename: String,
+    pub field: Field,
+    /// All current values of the tag, joined with "; ", if there are any.
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+/// Return the changes that applying the edits would make.
+///
+/// Returns `None` if the target does not exist. Edits that would set a tag to
+/// the value it already has produce no change.
+pub fn plan(
+    tx: &mut Transaction,
+    index: &MemoryMetaIndex,
+    target: Target,
+    edits: &[Edit],
+) -> db::Result<Option<Vec<Change>>> {
+    let tracks = match target {
+        Target::Track(track_id) => match index.get_track(track_id) {
+            Some(track) => vec![(track_id, track)],
+            None => return Ok(None),
+        },
+        Target::Album(album_id) => match index.get_album(album_id) {
+            Some(_) => index
+                .get_album_tracks(album_id)
+                .iter()
+                .map(|kv| (kv.track_id, &kv.track))
+                .collect(),
+            None => return Ok(None),
+        },
+    };
+
+    let mut changes = Vec::new();
+
+    for (track_id, track) in tracks {
+        let mut current: HashMap<String, Vec<String>> = HashMap::new();
+        for row in db::iter_file_tags(tx, track.file_id.0)? {
+            let (field_name, value) = row?;
+            current.entry(field_name).or_default().push(value);
+        }
+
+        for edit in edits {
+            let old_values = current.remove(edit.field.tag_name()).unwrap_or_default();
+            let is_unchanged = match &edit.value {
+                Some(v) => old_values.len() == 1 && &old_values[0] == v,
+                None => old_values.is_empty(),
+            };
+            if is_unchanged {
+                continue;
+            }
+            changes.push(Change {
+                track_id,
+                file_id: track.file_id.0,
+                filename: index.get_filename(track.filename).to_string(),
+                field: edit.field,
+                old_value: match old_values.len() {
+                    0 => None,
+                    _ => Some(old_values.join("; ")),
+                },
+                new_value: edit.value.clone(),
+            });
+        }
+    }
+
+    Ok(Some(changes))
+}
+
+/// The size and identity of a file after we rewrote its tags.
+pub struct WrittenFile {
+    pub file_id: i64,
+    pub mtime: i64,
+    pub size_bytes: i64,
+    pub inode: i64,
+}
+
+/// Write the changes into the Vorbis comments of the files.
+///
+/// When writing fails halfway, the files that were written already keep their
+/// new tags, and the next scan picks them up because their mtime changed.
+pub fn write_files(changes: &[Change]) -> Result<Vec<WrittenFile>> {
+    let mut written = Vec::new();
+
+    // The changes are grouped per track, so changes to one file are adjacent.
+    for file_changes in group_by_file(changes) {
+        let updates: Vec<TagUpdate> = file_changes
+            .iter()
+            .map(|c| TagUpdate {
+                field_name: c.field.tag_name(),
+                value: c.new_value.as_deref(),
+            })
+            .collect();
+        let path = Path::new(&file_changes[0].filename);
+        flac_tags::write_tags(path, &updates)?;
+
+        let metadata = path.metadata()?;
+        written.push(WrittenFile {
+            file_id: file_changes[0].file_id,
+            mtime: metadata.mtime(),
+            size_bytes: metadata.len() as i64,
+            inode: metadata.ino() as i64,
+        });
+    }
+
+    Ok(written)
+}
+
+/// Split the changes into runs that apply to the same file.
+fn group_by_file(changes: &[Change]) -> Vec<&[Change]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for i in 1..=changes.len() {
+        if i == changes.len() || changes[i].file_id != changes[start].file_id {
+            groups.push(&changes[start..i]);
+            start = i;
+        }
+    }
+    groups
+}
+
+/// Store the changes in the database, and record them in the edit log.
+pub fn apply(
+    tx: &mut Transaction,
+    changes: &[Change],
+    written: &[WrittenFile],
+    edited_at: &str,
+    user: Option<&str>,
+) -> db::Result<()> {
+    for change in changes {
+        let field_name = change.field.tag_name();
+        db::delete_file_tag(tx, change.file_id, field_name)?;
+        if let Some(value) = change.new_value.as_ref() {
+            db::insert_tag(tx, change.file_id, field_name, value)?;
+        }
+        let wrote_tags = written.iter().any(|w| w.file_id == change.file_id);
+        db::insert_metadata_edit(tx, db::InsertMetadataEdit {
+            edited_at,
+            file_id: change.file_id,
+            track_id: change.track_id.0 as i64,
+            field_name,
+            old_value: change.old_value.as_deref(),
+            new_value: change.new_value.as_deref(),
+            wrote_tags: wrote_tags as i64,
+            user_name: user,
+        })?;
+    }
+
+    // The files changed on disk, but their tags are what we now have in the
+    // database, so a scan should not consider them modified.
+    for file in written {
+        db::update_file_mtime_size_inode(tx, file.file_id, file.mtime, file.size_bytes, file.inode)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_edits, Edit, Field, Target};
+    use crate::prim::{AlbumId, TrackId};
+
+    #[test]
+    fn parse_edits_maps_fields_per_target() {
+        let track = Target::Track(TrackId(1));
+        let album = Target::Album(AlbumId(1));
+        assert_eq!(
+            parse_edits(track, "title=Hopp%C3%ADpolla&genre=&dry_run=true"),
+            Ok(vec![
+                Edit { field: Field::Title, value: Some("Hoppípolla".to_string()) },
+                Edit { field: Field::Genre, value: None },
+            ]),
+        );
+        assert_eq!(
+            parse_edits(album, "title=Takk&artist=Sigur+R%C3%B3s&year=2005"),
+            Ok(vec![
+                Edit { field: Field::Album, value: Some("Takk".to_string()) },
+                Edit { field: Field::AlbumArtist, value: Some("Sigur Rós".to_string()) },
+                Edit { field: Field::OriginalDate, value: Some("2005".to_string()) },
+            ]),
+        );
+    }
+
+    #[test]
+    fn parse_edits_rejects_invalid_edits() {
+        let track = Target::Track(TrackId(1));
+        let album = Target::Album(AlbumId(1));
+        assert!(parse_edits(track, "year=2005").is_err());
+        assert!(parse_edits(album, "year=05").is_err());
+        assert!(parse_edits(track, "title=+").is_err());
+        assert!(parse_edits(track, "title=a&title=b").is_err());
+        assert!(parse_edits(track, "write_tags=true").is_err());
+    }
+}
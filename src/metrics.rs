@@ -0,0 +1,218 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Counters and gauges for Prometheus, served at `/metrics`.
+//!
+//! The things we count happen all over the place: in the playback thread, in
+//! the scanner, and in the database helpers. Threading a handle through all of
+//! those would touch many signatures for little benefit, so the counters are
+//! process-wide statics. Gauges that we can read at any time, such as the queue
+//! length, are not stored here, the server passes them in when it renders.
+//!
+//! The output is the Prometheus text format, see
+//! <https://prometheus.io/docs/instrumenting/exposition_formats/>.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::limits::LimitStatus;
+use crate::scan::ScanStage;
+
+static TRACKS_PLAYED: AtomicU64 = AtomicU64::new(0);
+static AUDIO_UNDERRUNS: AtomicU64 = AtomicU64::new(0);
+static DECODE_UNDERRUNS: AtomicU64 = AtomicU64::new(0);
+static THUMBNAIL_FAILURES: AtomicU64 = AtomicU64::new(0);
+static SQLITE_BUSY_RETRIES: AtomicU64 = AtomicU64::new(0);
+static HTTP_REQUEST_DURATION: Histogram = Histogram::new();
+
+/// Duration of every stage of the most recent scan.
+static SCAN_STAGE_SECONDS: Mutex<BTreeMap<ScanStage, f64>> = Mutex::new(BTreeMap::new());
+
+/// Upper bounds of the buckets of the request duration histogram, in seconds.
+///
+/// Most requests take well under a millisecond, but album downloads and
+/// transcoded tracks are streamed, and those take as long as the transfer.
+const BUCKETS: [f64; 11] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0];
+
+/// A histogram with the buckets in `BUCKETS`.
+struct Histogram {
+    /// The number of observations per bucket, not cumulative.
+    ///
+    /// The last element counts observations above the largest bound.
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Histogram {
+            counts: [ZERO; BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let i = BUCKETS.iter().position(|&le| seconds <= le).unwrap_or(BUCKETS.len());
+        self.counts[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn write<W: Write>(&self, mut w: W, name: &str) -> io::Result<()> {
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            match BUCKETS.get(i) {
+                Some(le) => writeln!(w, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative)?,
+                None => writeln!(w, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative)?,
+            }
+        }
+        let sum_seconds = self.sum_micros.load(Ordering::Relaxed) as f64 * 1e-6;
+        writeln!(w, "{}_sum {:.06}", name, sum_seconds)?;
+        writeln!(w, "{}_count {}", name, cumulative)
+    }
+}
+
+/// Count a track that played to the end.
+pub fn count_track_played() {
+    TRACKS_PLAYED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a buffer underrun of the audio device.
+pub fn count_audio_underrun() {
+    AUDIO_UNDERRUNS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a time that playback ran out of decoded audio while more was queued.
+pub fn count_decode_underrun() {
+    DECODE_UNDERRUNS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count an album for which we failed to generate a thumbnail.
+pub fn count_thumbnail_failure() {
+    THUMBNAIL_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a database operation that we retry because the database was busy.
+pub fn count_sqlite_busy_retry() {
+    SQLITE_BUSY_RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record the time it took to handle an HTTP request.
+pub fn observe_http_request(duration: Duration) {
+    HTTP_REQUEST_DURATION.observe(duration);
+}
+
+/// Record the time that a scan spent in the given stage.
+pub fn observe_scan_stage(stage: ScanStage, duration: Duration) {
+    SCAN_STAGE_SECONDS.lock().unwrap().insert(stage, duration.as_secs_f64());
+}
+
+/// Return the name of the stage as we use it in the `stage` label.
+fn stage_label(stage: ScanStage) -> &'static str {
+    match stage {
+        ScanStage::Discovering => "discovering",
+        ScanStage::PreProcessingMetadata => "preprocessing_metadata",
+        ScanStage::ExtractingMetadata => "extracting_metadata",
+        ScanStage::IndexingMetadata => "indexing_metadata",
+        ScanStage::Fingerprinting => "fingerprinting",
+        ScanStage::PreProcessingLoudness => "preprocessing_loudness",
+        ScanStage::AnalyzingLoudness => "analyzing_loudness",
+        ScanStage::PreProcessingThumbnails => "preprocessing_thumbnails",
+        ScanStage::GeneratingThumbnails => "generating_thumbnails",
+        ScanStage::LoadingThumbnails => "loading_thumbnails",
+        ScanStage::Verifying => "verifying",
+        ScanStage::Done => "done",
+    }
+}
+
+fn write_counter_value<W: Write>(mut w: W, name: &str, help: &str, value: u64) -> io::Result<()> {
+    writeln!(w, "# HELP {} {}", name, help)?;
+    writeln!(w, "# TYPE {} counter", name)?;
+    writeln!(w, "{} {}", name, value)
+}
+
+fn write_counter<W: Write>(w: W, name: &str, help: &str, counter: &AtomicU64) -> io::Result<()> {
+    write_counter_value(w, name, help, counter.load(Ordering::Relaxed))
+}
+
+/// Write all metrics in the Prometheus text format.
+///
+/// The limit counters live in the server rather than in a static here, so
+/// the caller passes them in.
+pub fn write_metrics<W: Write>(mut w: W, queue_len: usize, limits: LimitStatus) -> io::Result<()> {
+    write_counter(&mut w, "musium_tracks_played_total", "Tracks that played to the end.", &TRACKS_PLAYED)?;
+    write_counter(&mut w, "musium_audio_underruns_total", "Buffer underruns of the audio device.", &AUDIO_UNDERRUNS)?;
+    write_counter(&mut w, "musium_decode_underruns_total", "Times that playback ran out of decoded audio.", &DECODE_UNDERRUNS)?;
+    write_counter(&mut w, "musium_thumbnail_failures_total", "Albums for which thumbnail generation failed.", &THUMBNAIL_FAILURES)?;
+    write_counter(&mut w, "musium_sqlite_busy_retries_total", "Database operations retried because the database was busy.", &SQLITE_BUSY_RETRIES)?;
+    write_counter_value(&mut w, "musium_http_rate_limited_requests_total", "Requests rejected with 429 because the client exceeded its rate.", limits.rate_limited_requests)?;
+    write_counter_value(&mut w, "musium_http_oversized_requests_total", "Requests rejected with 413 because the body was too large.", limits.oversized_requests)?;
+
+    writeln!(w, "# HELP musium_queue_length Tracks and radio stations in the play queue.")?;
+    writeln!(w, "# TYPE musium_queue_length gauge")?;
+    writeln!(w, "musium_queue_length {}", queue_len)?;
+
+    writeln!(w, "# HELP musium_scan_stage_duration_seconds Time that the most recent scan spent per stage.")?;
+    writeln!(w, "# TYPE musium_scan_stage_duration_seconds gauge")?;
+    for (&stage, seconds) in SCAN_STAGE_SECONDS.lock().unwrap().iter() {
+        writeln!(w, "musium_scan_stage_duration_seconds{{stage=\"{}\"}} {:.03}", stage_label(stage), seconds)?;
+    }
+
+    let name = "musium_http_request_duration_seconds";
+    writeln!(w, "# HELP {} Time to handle an HTTP request, including sending the response.", name)?;
+    writeln!(w, "# TYPE {} histogram", name)?;
+    HTTP_REQUEST_DURATION.write(&mut w, name)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{write_metrics, Histogram};
+    use crate::limits::LimitStatus;
+
+    #[test]
+    fn histogram_writes_cumulative_buckets() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(60));
+
+        let mut out = Vec::new();
+        histogram.write(&mut out, "t").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "t_bucket{le=\"0.001\"} 1");
+        assert_eq!(lines[1], "t_bucket{le=\"0.005\"} 1");
+        assert_eq!(lines[3], "t_bucket{le=\"0.025\"} 2");
+        assert_eq!(lines[10], "t_bucket{le=\"30\"} 2");
+        assert_eq!(lines[11], "t_bucket{le=\"+Inf\"} 3");
+        assert_eq!(lines[12], "t_sum 60.020500");
+        assert_eq!(lines[13], "t_count 3");
+    }
+
+    #[test]
+    fn write_metrics_includes_limit_counters() {
+        let limits = LimitStatus {
+            rate_limited_requests: 3,
+            oversized_requests: 1,
+        };
+        let mut out = Vec::new();
+        write_metrics(&mut out, 2, limits).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\nmusium_http_rate_limited_requests_total 3\n"));
+        assert!(out.contains("\nmusium_http_oversized_requests_total 1\n"));
+        assert!(out.contains("\nmusium_queue_length 2\n"));
+    }
+}
@@ -0,0 +1,1340 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A server for the MPD protocol, for existing MPD clients.
+//!
+//! MPD, the Music Player Daemon, has a line-based protocol over TCP that many
+//! clients speak, such as ncmpcpp on the terminal and MALP on Android, see
+//! <https://mpd.readthedocs.io/en/latest/protocol.html>. We map its commands
+//! onto the player and the index. Songs are identified by their path relative
+//! to the library, and the queue is MPD's "current playlist". Musium plays
+//! whenever the queue is not empty, unless paused, and consumes tracks as it
+//! plays them, so there is no stop or repeat.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+use crate::auth::Scope;
+use crate::config::Config;
+use crate::mvar::Var;
+use crate::player::{Millibel, PlaybackState, Player, QueueId, Source, TrackSnapshot};
+use crate::prim::{Track, TrackId};
+use crate::{MemoryMetaIndex, MetaIndex};
+
+/// The protocol version that we claim to speak, clients adapt to it.
+const PROTOCOL_VERSION: &str = "0.23.0";
+
+/// Lines longer than this are not commands that we support.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+/// The name that enqueued tracks record as their client.
+const CLIENT_NAME: &str = "mpd";
+
+// Error codes, see `src/protocol/Ack.hxx` in MPD.
+const ACK_ERROR_ARG: u32 = 2;
+const ACK_ERROR_PASSWORD: u32 = 3;
+const ACK_ERROR_PERMISSION: u32 = 4;
+const ACK_ERROR_UNKNOWN: u32 = 5;
+const ACK_ERROR_NO_EXIST: u32 = 50;
+const ACK_ERROR_PLAYER_SYNC: u32 = 55;
+
+/// The commands that we support, and the scope they require.
+///
+/// Commands without scope are allowed before the client sent a password.
+const COMMANDS: &[(&str, Option<Scope>)] = &[
+    ("add", Some(Scope::Queue)),
+    ("addid", Some(Scope::Queue)),
+    ("clear", Some(Scope::Queue)),
+    ("clearerror", Some(Scope::Read)),
+    ("close", None),
+    ("commands", None),
+    ("count", Some(Scope::Read)),
+    ("currentsong", Some(Scope::Read)),
+    ("decoders", Some(Scope::Read)),
+    ("delete", Some(Scope::Queue)),
+    ("deleteid", Some(Scope::Queue)),
+    ("find", Some(Scope::Read)),
+    ("findadd", Some(Scope::Queue)),
+    ("idle", Some(Scope::Read)),
+    ("list", Some(Scope::Read)),
+    ("listall", Some(Scope::Read)),
+    ("listallinfo", Some(Scope::Read)),
+    ("listplaylists", Some(Scope::Read)),
+    ("lsinfo", Some(Scope::Read)),
+    ("next", Some(Scope::Queue)),
+    ("noidle", Some(Scope::Read)),
+    ("notcommands", None),
+    ("outputs", Some(Scope::Read)),
+    ("password", None),
+    ("pause", Some(Scope::Queue)),
+    ("ping", None),
+    ("play", Some(Scope::Queue)),
+    ("playid", Some(Scope::Queue)),
+    ("playlistid", Some(Scope::Read)),
+    ("playlistinfo", Some(Scope::Read)),
+    ("plchanges", Some(Scope::Read)),
+    ("plchangesposid", Some(Scope::Read)),
+    ("previous", Some(Scope::Queue)),
+    ("replay_gain_status", Some(Scope::Read)),
+    ("search", Some(Scope::Read)),
+    ("searchadd", Some(Scope::Queue)),
+    ("seekcur", Some(Scope::Queue)),
+    ("seekid", Some(Scope::Queue)),
+    ("setvol", Some(Scope::Queue)),
+    ("shuffle", Some(Scope::Queue)),
+    ("stats", Some(Scope::Read)),
+    ("status", Some(Scope::Read)),
+    ("tagtypes", Some(Scope::Read)),
+    ("urlhandlers", Some(Scope::Read)),
+    ("volume", Some(Scope::Queue)),
+];
+
+/// A command failed, MPD calls the error response an acknowledgement.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Ack {
+    code: u32,
+    message: &'static str,
+}
+
+impl Ack {
+    fn new(code: u32, message: &'static str) -> Ack {
+        Ack {
+            code: code,
+            message: message,
+        }
+    }
+
+    fn arg(message: &'static str) -> Ack {
+        Ack::new(ACK_ERROR_ARG, message)
+    }
+
+    fn no_exist(message: &'static str) -> Ack {
+        Ack::new(ACK_ERROR_NO_EXIST, message)
+    }
+
+    fn write(&self, out: &mut Vec<u8>, list_num: usize, command: &str) {
+        writeln!(out, "ACK [{}@{}] {{{}}} {}", self.code, list_num, command, self.message).unwrap();
+    }
+}
+
+type Result<T> = std::result::Result<T, Ack>;
+
+/// Split a command line into its arguments.
+///
+/// Arguments are separated by spaces, or enclosed in double quotes, in which
+/// case a backslash escapes the next character.
+fn parse_arguments(line: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|ch| ch.is_ascii_whitespace()) {
+            chars.next();
+        }
+        let mut arg = String::new();
+        match chars.next() {
+            None => return Ok(args),
+            Some('"') => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(ch) => arg.push(ch),
+                        None => return Err(Ack::arg("Missing closing quote.")),
+                    },
+                    Some(ch) => arg.push(ch),
+                    None => return Err(Ack::arg("Missing closing quote.")),
+                }
+            },
+            Some(ch) => {
+                arg.push(ch);
+                while let Some(ch) = chars.peek().filter(|ch| !ch.is_ascii_whitespace()) {
+                    arg.push(*ch);
+                    chars.next();
+                }
+            }
+        }
+        args.push(arg);
+    }
+}
+
+/// A start position, and an exclusive end position, if there is one.
+type Range = (usize, Option<usize>);
+
+/// Parse a position like `3`, or a range like `3:7` or `3:`.
+fn parse_range(arg: &str) -> Result<Range> {
+    let invalid = || Ack::arg("Invalid position or range.");
+    match arg.split_once(':') {
+        None => {
+            let pos = usize::from_str(arg).map_err(|_| invalid())?;
+            Ok((pos, Some(pos + 1)))
+        }
+        Some((start, "")) => Ok((usize::from_str(start).map_err(|_| invalid())?, None)),
+        Some((start, end)) => {
+            let start = usize::from_str(start).map_err(|_| invalid())?;
+            let end = usize::from_str(end).map_err(|_| invalid())?;
+            Ok((start, Some(end)))
+        }
+    }
+}
+
+/// Parse the time of a seek in seconds, relative to `position_ms` when it has a sign.
+fn parse_seek_time(time: &str, position_ms: u64) -> Result<u64> {
+    let seconds = f64::from_str(time)
+        .ok()
+        .filter(|s| s.is_finite())
+        .ok_or_else(|| Ack::arg("Invalid time."))?;
+    let target_ms = match time.starts_with('+') || time.starts_with('-') {
+        true => position_ms as f64 + seconds * 1000.0,
+        false => seconds * 1000.0,
+    };
+    Ok(target_ms.max(0.0) as u64)
+}
+
+/// MPD volume goes from 0 to 100, we map that linearly onto -60 dB to 0 dB.
+fn volume_to_percent(volume: Millibel) -> i32 {
+    ((volume.0 as i32 + 6000) / 60).clamp(0, 100)
+}
+
+fn percent_to_volume(percent: i32) -> Millibel {
+    Millibel((percent.clamp(0, 100) * 60 - 6000) as i16)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Tag {
+    Artist,
+    AlbumArtist,
+    Album,
+    Title,
+    Track,
+    Disc,
+    Date,
+    File,
+    /// Matches any of the textual tags.
+    Any,
+}
+
+/// The tags that we report, in the order that `tagtypes` lists them.
+const TAGS: [Tag; 7] = [
+    Tag::Artist,
+    Tag::AlbumArtist,
+    Tag::Album,
+    Tag::Title,
+    Tag::Track,
+    Tag::Disc,
+    Tag::Date,
+];
+
+impl FromStr for Tag {
+    type Err = Ack;
+
+    /// Parse a tag name, which is case-insensitive.
+    fn from_str(s: &str) -> Result<Tag> {
+        match &s.to_ascii_lowercase()[..] {
+            "artist" => Ok(Tag::Artist),
+            "albumartist" => Ok(Tag::AlbumArtist),
+            "album" => Ok(Tag::Album),
+            "title" => Ok(Tag::Title),
+            "track" => Ok(Tag::Track),
+            "disc" => Ok(Tag::Disc),
+            "date" => Ok(Tag::Date),
+            "file" => Ok(Tag::File),
+            "any" => Ok(Tag::Any),
+            _ => Err(Ack::arg("Unsupported tag.")),
+        }
+    }
+}
+
+impl Tag {
+    fn name(&self) -> &'static str {
+        match self {
+            Tag::Artist => "Artist",
+            Tag::AlbumArtist => "AlbumArtist",
+            Tag::Album => "Album",
+            Tag::Title => "Title",
+            Tag::Track => "Track",
+            Tag::Disc => "Disc",
+            Tag::Date => "Date",
+            Tag::File => "file",
+            Tag::Any => "any",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Contains,
+    StartsWith,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Condition {
+    Tag { tag: Tag, op: Operator, value: String },
+    /// The file is in this directory, or in one of its subdirectories.
+    Base(String),
+}
+
+/// Parse a quoted value in a filter expression, return it and the remainder.
+fn parse_quoted(s: &str) -> Result<(String, &str)> {
+    let mut chars = s.char_indices();
+    let quote = match chars.next() {
+        Some((_, q)) if q == '\'' || q == '"' => q,
+        _ => return Err(Ack::arg("Expected quoted value in filter expression.")),
+    };
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some((_, '\\')) => match chars.next() {
+                Some((_, ch)) => value.push(ch),
+                None => break,
+            },
+            Some((i, ch)) if ch == quote => return Ok((value, &s[i + 1..])),
+            Some((_, ch)) => value.push(ch),
+            None => break,
+        }
+    }
+    Err(Ack::arg("Missing closing quote in filter expression."))
+}
+
+/// Parse a filter expression such as `((artist == 'A') AND (album == 'B'))`.
+///
+/// Appends the conditions, which must all hold, and returns the remainder.
+fn parse_expression<'a>(s: &'a str, into: &mut Vec<Condition>) -> Result<&'a str> {
+    let s = match s.trim_start().strip_prefix('(') {
+        Some(rest) => rest.trim_start(),
+        None => return Err(Ack::arg("Expected '(' in filter expression.")),
+    };
+
+    if s.starts_with('(') {
+        let mut rest = parse_expression(s, into)?;
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(')') {
+                return Ok(after);
+            }
+            rest = match rest.strip_prefix("AND") {
+                Some(after) => parse_expression(after, into)?,
+                None => return Err(Ack::arg("Only AND is supported in filter expressions.")),
+            };
+        }
+    }
+
+    let word_end = s
+        .find(|ch: char| ch.is_whitespace() || ch == '\'' || ch == '"')
+        .unwrap_or(s.len());
+    let (word, rest) = s.split_at(word_end);
+    let rest = if word == "base" {
+        let (value, rest) = parse_quoted(rest.trim_start())?;
+        into.push(Condition::Base(value));
+        rest
+    } else {
+        let tag = Tag::from_str(word)?;
+        let rest = rest.trim_start();
+        let op_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let op = match &rest[..op_end] {
+            "==" => Operator::Equal,
+            "!=" => Operator::NotEqual,
+            "contains" => Operator::Contains,
+            "starts_with" => Operator::StartsWith,
+            _ => return Err(Ack::arg("Unsupported operator in filter expression.")),
+        };
+        let (value, rest) = parse_quoted(rest[op_end..].trim_start())?;
+        into.push(Condition::Tag { tag: tag, op: op, value: value });
+        rest
+    };
+
+    match rest.trim_start().strip_prefix(')') {
+        Some(after) => Ok(after),
+        None => Err(Ack::arg("Expected ')' in filter expression.")),
+    }
+}
+
+/// Parse the filter of `find`, `search`, `list`, and `count`.
+///
+/// Clients send either a filter expression, or pairs of tag and value, which
+/// is the older syntax. For those, `op` is the operator to use.
+fn parse_filter(args: &[String], op: Operator) -> Result<Vec<Condition>> {
+    let mut conditions = Vec::new();
+    match args {
+        [] => {}
+        [expression] if expression.starts_with('(') => {
+            let rest = parse_expression(expression, &mut conditions)?;
+            if !rest.trim().is_empty() {
+                return Err(Ack::arg("Unexpected input after filter expression."));
+            }
+        }
+        pairs if pairs.len() % 2 == 0 => {
+            for pair in pairs.chunks(2) {
+                let condition = match &pair[0].to_ascii_lowercase()[..] {
+                    "base" => Condition::Base(pair[1].clone()),
+                    tag => Condition::Tag {
+                        tag: Tag::from_str(tag)?,
+                        op: op,
+                        value: pair[1].clone(),
+                    },
+                };
+                conditions.push(condition);
+            }
+        }
+        _ => return Err(Ack::arg("Expected a filter expression, or pairs of tag and value.")),
+    }
+    Ok(conditions)
+}
+
+/// Split off trailing `sort`, `window`, and `group` arguments.
+///
+/// Returns the remaining arguments, the window, and the group tags. We don't
+/// sort, results are in library order, which is by album.
+fn split_modifiers(args: &[String]) -> Result<(&[String], Option<Range>, Vec<Tag>)> {
+    let mut args = args;
+    let mut window = None;
+    let mut groups = Vec::new();
+    while args.len() >= 2 {
+        let (rest, modifier) = args.split_at(args.len() - 2);
+        match &modifier[0][..] {
+            "sort" => {}
+            "window" => window = Some(parse_range(&modifier[1])?),
+            "group" => groups.insert(0, Tag::from_str(&modifier[1])?),
+            _ => break,
+        }
+        args = rest;
+    }
+    Ok((args, window, groups))
+}
+
+/// Return whether the file is in the directory, or one of its subdirectories.
+fn is_in_directory(file: &str, dir: &str) -> bool {
+    dir.is_empty() || (file.starts_with(dir) && file[dir.len()..].starts_with('/'))
+}
+
+/// Return the subsystems that an event from the event bus affects.
+fn get_subsystems(message: &[u8]) -> &'static [&'static str] {
+    let name = message
+        .strip_prefix(b"event: ")
+        .and_then(|m| m.split(|b| *b == b'\n').next())
+        .unwrap_or(b"");
+    match name {
+        b"queue_changed" => &["playlist"],
+        // Tracks leave the queue when they complete or get skipped, so these
+        // change the playlist too.
+        b"track_started" | b"track_completed" | b"track_skipped" => &["player", "playlist"],
+        b"radio_title_changed" | b"radio_failed" => &["player", "playlist"],
+        b"volume_changed" => &["mixer"],
+        b"scan_status" => &["update"],
+        b"library_updated" => &["database", "update"],
+        _ => &[],
+    }
+}
+
+/// The metadata of a track, as MPD clients see it.
+struct Song<'a> {
+    track_id: TrackId,
+    track: &'a Track,
+    file: &'a str,
+    title: &'a str,
+    artist: &'a str,
+    album: &'a str,
+    album_artist: &'a str,
+    year: u16,
+}
+
+impl<'a> Song<'a> {
+    fn get_values(&self, tag: Tag) -> Vec<String> {
+        match tag {
+            Tag::Artist => vec![self.artist.to_string()],
+            Tag::AlbumArtist => vec![self.album_artist.to_string()],
+            Tag::Album => vec![self.album.to_string()],
+            Tag::Title => vec![self.title.to_string()],
+            Tag::Track => vec![self.track_id.track_number().to_string()],
+            Tag::Disc => vec![self.track_id.disc_number().to_string()],
+            Tag::Date => vec![self.year.to_string()],
+            Tag::File => vec![self.file.to_string()],
+            Tag::Any => [Tag::Artist, Tag::AlbumArtist, Tag::Album, Tag::Title, Tag::File]
+                .iter()
+                .flat_map(|t| self.get_values(*t))
+                .collect(),
+        }
+    }
+
+    /// Return whether the song satisfies the condition.
+    ///
+    /// `find` compares case-sensitively, `search` folds case.
+    fn matches(&self, condition: &Condition, fold_case: bool) -> bool {
+        let (tag, op, expected) = match condition {
+            Condition::Base(dir) => return is_in_directory(self.file, dir.trim_matches('/')),
+            Condition::Tag { tag, op, value } => (*tag, *op, value),
+        };
+        let fold = |s: &str| if fold_case { s.to_lowercase() } else { s.to_string() };
+        let expected = fold(expected);
+        let values = self.get_values(tag);
+        let mut values = values.iter().map(|v| fold(v));
+        match op {
+            Operator::Equal => values.any(|v| v == expected),
+            Operator::NotEqual => values.all(|v| v != expected),
+            Operator::Contains => values.any(|v| v.contains(&expected)),
+            Operator::StartsWith => values.any(|v| v.starts_with(&expected)),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>, position: Option<(usize, QueueId)>) {
+        writeln!(out, "file: {}", self.file).unwrap();
+        writeln!(out, "Title: {}", self.title).unwrap();
+        writeln!(out, "Artist: {}", self.artist).unwrap();
+        writeln!(out, "Album: {}", self.album).unwrap();
+        writeln!(out, "AlbumArtist: {}", self.album_artist).unwrap();
+        writeln!(out, "Track: {}", self.track_id.track_number()).unwrap();
+        writeln!(out, "Disc: {}", self.track_id.disc_number()).unwrap();
+        writeln!(out, "Date: {}", self.year).unwrap();
+        writeln!(out, "Time: {}", self.track.duration_seconds).unwrap();
+        writeln!(out, "duration: {}", self.track.duration_seconds).unwrap();
+        if let Some((pos, queue_id)) = position {
+            writeln!(out, "Pos: {}", pos).unwrap();
+            writeln!(out, "Id: {}", queue_id.0).unwrap();
+        }
+    }
+}
+
+/// The parts of the server that MPD commands act on.
+pub struct Context<'a> {
+    pub config: &'a Config,
+    pub index_var: &'a Var<MemoryMetaIndex>,
+    pub player: &'a Player,
+}
+
+/// Input for the connection loop.
+enum Input {
+    /// The client sent a line.
+    Line(String),
+    /// A subsystem changed, for clients that are waiting in `idle`.
+    Changed(&'static str),
+    /// The client closed the connection.
+    Closed,
+}
+
+/// The state of a single client connection.
+struct Session<'a> {
+    ctx: &'a Context<'a>,
+
+    /// The scope of the password that the client sent, if any.
+    scope: Option<Scope>,
+
+    /// The user of the password, tracks that the client enqueues count as
+    /// listens of this user. `None` for the default user.
+    user: Option<String>,
+
+    /// Incremented on every change to the queue, so clients know to refetch.
+    playlist_version: u32,
+
+    /// Subsystems that changed since the client last waited in `idle`.
+    changed: BTreeSet<&'static str>,
+}
+
+impl<'a> Session<'a> {
+    fn check_permission(&self, command: &str) -> Result<()> {
+        match COMMANDS.iter().find(|(name, _)| *name == command) {
+            None => Err(Ack::new(ACK_ERROR_UNKNOWN, "Unknown command.")),
+            Some((_, None)) => Ok(()),
+            Some((_, Some(required))) => match self.scope {
+                Some(scope) if scope >= *required => Ok(()),
+                _ => Err(Ack::new(ACK_ERROR_PERMISSION, "Permission denied, send a password with sufficient scope.")),
+            },
+        }
+    }
+
+    /// If any of the subsystems changed, report and forget them.
+    ///
+    /// An empty set of subsystems means all of them.
+    fn take_changes(&mut self, subsystems: &BTreeSet<String>, out: &mut Vec<u8>) -> bool {
+        let changed: Vec<&'static str> = self
+            .changed
+            .iter()
+            .filter(|s| subsystems.is_empty() || subsystems.contains(**s))
+            .cloned()
+            .collect();
+        if changed.is_empty() {
+            return false;
+        }
+        for subsystem in changed {
+            self.changed.remove(subsystem);
+            writeln!(out, "changed: {}", subsystem).unwrap();
+        }
+        writeln!(out, "OK").unwrap();
+        true
+    }
+
+    fn get_song<'b>(&self, index: &'b MemoryMetaIndex, track_id: TrackId, track: &'b Track) -> Song<'b> {
+        let album = index.get_album(track_id.album_id()).expect("Track's album should be in the index.");
+        let filename = index.get_filename(track.filename);
+        let file = Path::new(filename)
+            .strip_prefix(&self.ctx.config.library_path)
+            .ok()
+            .and_then(|p| p.to_str())
+            .unwrap_or(filename);
+        Song {
+            track_id: track_id,
+            track: track,
+            file: file,
+            title: index.get_string(track.title),
+            artist: index.get_string(track.artist),
+            album: index.get_string(album.title),
+            album_artist: index.get_string(album.artist),
+            year: album.original_release_date.year,
+        }
+    }
+
+    /// Write the queued tracks in the range, with their positions.
+    fn write_queue(&self, tracks: &[TrackSnapshot], range: Range, out: &mut Vec<u8>) -> Result<()> {
+        let (start, end) = range;
+        let end = end.unwrap_or(tracks.len()).min(tracks.len());
+        if start > 0 && start >= tracks.len() {
+            return Err(Ack::arg("Bad song index."));
+        }
+        let index = &*self.ctx.index_var.get();
+        for (pos, t) in tracks.iter().enumerate().take(end).skip(start) {
+            match &t.source {
+                Source::Track(track_id) => {
+                    // After a rescan, a queued track may no longer be in the index.
+                    if let Some(track) = index.get_track(*track_id) {
+                        self.get_song(index, *track_id, track).write(out, Some((pos, t.queue_id)));
+                    }
+                }
+                // Like MPD does for streams, the name is the station, and the
+                // title is what the station announced.
+                Source::Radio(station) => {
+                    writeln!(out, "file: {}", station.url).unwrap();
+                    writeln!(out, "Name: {}", station.name).unwrap();
+                    if let Some(title) = t.stream_title.as_ref() {
+                        writeln!(out, "Title: {}", title).unwrap();
+                    }
+                    writeln!(out, "Pos: {}", pos).unwrap();
+                    writeln!(out, "Id: {}", t.queue_id.0).unwrap();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the position of the queued track.
+    fn find_queue_position(&self, tracks: &[TrackSnapshot], id: &str) -> Result<usize> {
+        let queue_id = u64::from_str(id).map_err(|_| Ack::arg("Invalid song id."))?;
+        tracks
+            .iter()
+            .position(|t| t.queue_id == QueueId(queue_id))
+            .ok_or_else(|| Ack::no_exist("No such song."))
+    }
+
+    /// Remove the queued track at the position.
+    fn remove_at(&self, tracks: &[TrackSnapshot], pos: usize) {
+        // The current track cannot be dequeued, but it can be skipped.
+        if pos == 0 {
+            self.ctx.player.skip();
+        } else {
+            self.ctx.player.dequeue(tracks[pos].queue_id);
+        }
+    }
+
+    /// Skip to the queued track at the position, by removing the ones before.
+    fn play_at(&self, tracks: &[TrackSnapshot], pos: usize) -> Result<()> {
+        if pos >= tracks.len() {
+            return Err(Ack::arg("Bad song index."));
+        }
+        for t in &tracks[1..pos.max(1)] {
+            self.ctx.player.dequeue(t.queue_id);
+        }
+        if pos > 0 {
+            self.ctx.player.skip();
+        }
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<()> {
+        if self.ctx.player.is_casting() {
+            return Err(Ack::new(ACK_ERROR_PLAYER_SYNC, "Can't pause while casting."));
+        }
+        // With nothing to pause, there is nothing to do, like MPD when stopped.
+        self.ctx.player.pause();
+        Ok(())
+    }
+
+    fn seek(&self, position_ms: u64) -> Result<()> {
+        if self.ctx.player.is_casting() {
+            return Err(Ack::new(ACK_ERROR_PLAYER_SYNC, "Can't seek while casting."));
+        }
+        match self.ctx.player.seek(position_ms) {
+            true => Ok(()),
+            false => Err(Ack::new(ACK_ERROR_PLAYER_SYNC, "Not playing a track.")),
+        }
+    }
+
+    fn set_volume_percent(&self, percent: i32) {
+        let current = self.ctx.player.get_volume();
+        let target = percent_to_volume(percent);
+        self.ctx.player.change_volume(Millibel(target.0 - current.0));
+    }
+
+    /// Write or enqueue the songs that match the filter.
+    fn find(&self, args: &[String], fold_case: bool, enqueue: bool, out: &mut Vec<u8>) -> Result<()> {
+        let (args, window, _groups) = split_modifiers(args)?;
+        let op = if fold_case { Operator::Contains } else { Operator::Equal };
+        let conditions = parse_filter(args, op)?;
+        let (start, end) = window.unwrap_or((0, None));
+        let index = &*self.ctx.index_var.get();
+        let songs = index
+            .get_tracks()
+            .iter()
+            .map(|kv| self.get_song(index, kv.track_id, &kv.track))
+            .filter(|song| conditions.iter().all(|c| song.matches(c, fold_case)))
+            .skip(start)
+            .take(end.map_or(usize::MAX, |end| end.saturating_sub(start)));
+        for song in songs {
+            if enqueue {
+                self.ctx.player.enqueue(index, song.track_id, Some(CLIENT_NAME), self.user.as_deref());
+            } else {
+                song.write(out, None);
+            }
+        }
+        Ok(())
+    }
+
+    /// List the distinct values of a tag, optionally grouped by other tags.
+    fn list(&self, args: &[String], out: &mut Vec<u8>) -> Result<()> {
+        let (args, _window, groups) = split_modifiers(args)?;
+        let tag = match args.first() {
+            Some(tag) => Tag::from_str(tag)?,
+            None => return Err(Ack::arg("Expected a tag to list.")),
+        };
+        if tag == Tag::Any {
+            return Err(Ack::arg("Cannot list 'any'."));
+        }
+        let conditions = match &args[1..] {
+            // In the oldest syntax, `list album X` lists the albums of artist X.
+            [artist] if tag == Tag::Album && !artist.starts_with('(') => vec![Condition::Tag {
+                tag: Tag::Artist,
+                op: Operator::Equal,
+                value: artist.clone(),
+            }],
+            filter => parse_filter(filter, Operator::Equal)?,
+        };
+
+        let index = &*self.ctx.index_var.get();
+        let mut values = BTreeSet::new();
+        for kv in index.get_tracks() {
+            let song = self.get_song(index, kv.track_id, &kv.track);
+            if conditions.iter().all(|c| song.matches(c, false)) {
+                let group_values: Vec<String> = groups.iter().map(|g| song.get_values(*g).remove(0)).collect();
+                values.insert((group_values, song.get_values(tag).remove(0)));
+            }
+        }
+
+        let mut previous_groups = None;
+        for (group_values, value) in &values {
+            if previous_groups != Some(group_values) {
+                for (group, group_value) in groups.iter().zip(group_values) {
+                    writeln!(out, "{}: {}", group.name(), group_value).unwrap();
+                }
+                previous_groups = Some(group_values);
+            }
+            writeln!(out, "{}: {}", tag.name(), value).unwrap();
+        }
+        Ok(())
+    }
+
+    /// List the contents of a directory, with `recursive` all the way down.
+    fn list_directory(&self, args: &[String], recursive: bool, info: bool, out: &mut Vec<u8>) -> Result<()> {
+        let dir = args.first().map_or("", |d| d.trim_matches('/'));
+        let index = &*self.ctx.index_var.get();
+        let mut directories = BTreeSet::new();
+        let mut files = Vec::new();
+        let mut found = dir.is_empty();
+
+        for kv in index.get_tracks() {
+            let song = self.get_song(index, kv.track_id, &kv.track);
+            if song.file == dir {
+                // Listing a file lists only that file.
+                song.write(out, None);
+                return Ok(());
+            }
+            if !is_in_directory(song.file, dir) {
+                continue;
+            }
+            found = true;
+            let file = song.file;
+            let rest_start = if dir.is_empty() { 0 } else { dir.len() + 1 };
+            let mut subdirs = file[rest_start..].match_indices('/').map(move |(i, _)| &file[..rest_start + i]);
+            if recursive {
+                directories.extend(subdirs);
+                files.push(song);
+            } else {
+                match subdirs.next() {
+                    Some(subdir) => {
+                        directories.insert(subdir);
+                    }
+                    None => files.push(song),
+                }
+            }
+        }
+
+        if !found {
+            return Err(Ack::no_exist("No such directory."));
+        }
+        for directory in directories {
+            writeln!(out, "directory: {}", directory).unwrap();
+        }
+        for song in files {
+            if info {
+                song.write(out, None);
+            } else {
+                writeln!(out, "file: {}", song.file).unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    /// Enqueue the file, or all files in the directory.
+    fn add(&self, uri: &str) -> Result<Option<QueueId>> {
+        let uri = uri.trim_matches('/');
+        let index = &*self.ctx.index_var.get();
+        let mut tracks = Vec::new();
+        for kv in index.get_tracks() {
+            let song = self.get_song(index, kv.track_id, &kv.track);
+            if song.file == uri || is_in_directory(song.file, uri) {
+                tracks.push(kv.track_id);
+            }
+        }
+        // Adding the root directory adds the entire library, enqueue it in one go.
+        let shuffle_tracks = false;
+        let user = self.user.as_deref();
+        let queue_ids = self.ctx.player.enqueue_many(index, &tracks, Some(CLIENT_NAME), user, shuffle_tracks);
+        match queue_ids.last() {
+            None => Err(Ack::no_exist("No such song or directory.")),
+            Some(&queue_id) => Ok(Some(queue_id)),
+        }
+    }
+
+    fn write_status(&self, out: &mut Vec<u8>) {
+        let now_playing = self.ctx.player.get_now_playing();
+        let queue = self.ctx.player.get_queue();
+        let volume = volume_to_percent(self.ctx.player.get_volume());
+        writeln!(out, "volume: {}", volume).unwrap();
+        writeln!(out, "repeat: 0").unwrap();
+        writeln!(out, "random: 0").unwrap();
+        writeln!(out, "single: 0").unwrap();
+        writeln!(out, "consume: 1").unwrap();
+        writeln!(out, "playlist: {}", self.playlist_version).unwrap();
+        writeln!(out, "playlistlength: {}", queue.tracks.len()).unwrap();
+        let state = match now_playing.state {
+            PlaybackState::Stopped => "stop",
+            PlaybackState::Paused => "pause",
+            _ => "play",
+        };
+        writeln!(out, "state: {}", state).unwrap();
+
+        let index = &*self.ctx.index_var.get();
+        if let Some(current) = queue.tracks.first() {
+            let duration = current
+                .source
+                .track_id()
+                .and_then(|track_id| index.get_track(track_id))
+                .map_or(0, |t| t.duration_seconds);
+            writeln!(out, "song: 0").unwrap();
+            writeln!(out, "songid: {}", current.queue_id.0).unwrap();
+            writeln!(out, "time: {}:{}", current.position_ms / 1000, duration).unwrap();
+            writeln!(out, "elapsed: {}.{:03}", current.position_ms / 1000, current.position_ms % 1000).unwrap();
+            writeln!(out, "duration: {}", duration).unwrap();
+        }
+        if let Some(next) = queue.tracks.get(1) {
+            writeln!(out, "nextsong: 1").unwrap();
+            writeln!(out, "nextsongid: {}", next.queue_id.0).unwrap();
+        }
+    }
+
+    /// Execute a command and write its response, except for the final `OK`.
+    fn execute(&mut self, command: &str, args: &[String], out: &mut Vec<u8>) -> Result<()> {
+        self.check_permission(command)?;
+
+        match (command, args) {
+            ("ping", []) | ("clearerror", []) | ("noidle", []) => {}
+            ("password", [secret]) => {
+                let views = &self.ctx.config.library_views;
+                match self.ctx.config.api_tokens.iter().find(|t| t.matches(secret)) {
+                    // We don't restrict the MPD commands to a library view,
+                    // so users with a view can't use MPD at all.
+                    Some(token) if views.iter().any(|v| Some(&v.user) == token.user.as_ref()) => {
+                        return Err(Ack::new(ACK_ERROR_PERMISSION, "The user of this password has a library view, which MPD does not support."));
+                    }
+                    Some(token) => {
+                        self.scope = Some(token.scope);
+                        self.user = token.user.clone();
+                    }
+                    None => return Err(Ack::new(ACK_ERROR_PASSWORD, "Incorrect password.")),
+                }
+            }
+            ("commands", []) | ("notcommands", []) => {
+                let want_allowed = command == "commands";
+                for (name, _) in COMMANDS {
+                    if self.check_permission(name).is_ok() == want_allowed {
+                        writeln!(out, "command: {}", name).unwrap();
+                    }
+                }
+            }
+            ("tagtypes", []) => {
+                for tag in TAGS.iter() {
+                    writeln!(out, "tagtype: {}", tag.name()).unwrap();
+                }
+            }
+            // Clients may ask for fewer tags, we always send all of them.
+            ("tagtypes", _) => {}
+            ("urlhandlers", []) | ("decoders", []) | ("listplaylists", []) => {}
+            ("outputs", []) => {
+                writeln!(out, "outputid: 0").unwrap();
+                match self.ctx.config.audio_device.as_ref() {
+                    Some(device) if self.ctx.config.snapcast_fifo.is_none() => {
+                        writeln!(out, "outputname: {}", device).unwrap();
+                        writeln!(out, "plugin: alsa").unwrap();
+                    }
+                    _ => {
+                        writeln!(out, "outputname: Snapcast").unwrap();
+                        writeln!(out, "plugin: fifo").unwrap();
+                    }
+                }
+                writeln!(out, "outputenabled: 1").unwrap();
+            }
+            ("replay_gain_status", []) => {
+                // Musium always normalizes loudness, see docs/loudness.md.
+                writeln!(out, "replay_gain_mode: auto").unwrap();
+            }
+            ("status", []) => self.write_status(out),
+            ("stats", []) => {
+                let index = &*self.ctx.index_var.get();
+                let playtime: u64 = index.get_tracks().iter().map(|kv| kv.track.duration_seconds as u64).sum();
+                writeln!(out, "artists: {}", index.get_artists().len()).unwrap();
+                writeln!(out, "albums: {}", index.get_albums().len()).unwrap();
+                writeln!(out, "songs: {}", index.get_tracks().len()).unwrap();
+                writeln!(out, "db_playtime: {}", playtime).unwrap();
+            }
+            ("currentsong", []) => {
+                let queue = self.ctx.player.get_queue();
+                self.write_queue(&queue.tracks, (0, Some(1)), out)?;
+            }
+            ("playlistinfo", []) => {
+                let queue = self.ctx.player.get_queue();
+                self.write_queue(&queue.tracks, (0, None), out)?;
+            }
+            ("playlistinfo", [range]) => {
+                let queue = self.ctx.player.get_queue();
+                self.write_queue(&queue.tracks, parse_range(range)?, out)?;
+            }
+            ("playlistid", []) => {
+                let queue = self.ctx.player.get_queue();
+                self.write_queue(&queue.tracks, (0, None), out)?;
+            }
+            ("playlistid", [id]) => {
+                let queue = self.ctx.player.get_queue();
+                let pos = self.find_queue_position(&queue.tracks, id)?;
+                self.write_queue(&queue.tracks, (pos, Some(pos + 1)), out)?;
+            }
+            // We don't track what changed in which version, so when anything
+            // changed, the client gets the full queue.
+            ("plchanges", [version, ..]) | ("plchangesposid", [version, ..]) => {
+                if u32::from_str(version).ok() != Some(self.playlist_version) {
+                    let queue = self.ctx.player.get_queue();
+                    if command == "plchanges" {
+                        self.write_queue(&queue.tracks, (0, None), out)?;
+                    } else {
+                        for (pos, t) in queue.tracks.iter().enumerate() {
+                            writeln!(out, "cpos: {}\nId: {}", pos, t.queue_id.0).unwrap();
+                        }
+                    }
+                }
+            }
+            ("add", [uri]) => {
+                self.add(uri)?;
+            }
+            ("addid", [uri]) => {
+                let uri = uri.trim_matches('/');
+                let index = &*self.ctx.index_var.get();
+                let track_id = index
+                    .get_tracks()
+                    .iter()
+                    .find(|kv| self.get_song(index, kv.track_id, &kv.track).file == uri)
+                    .map(|kv| kv.track_id)
+                    .ok_or_else(|| Ack::no_exist("No such song."))?;
+                let queue_id = self.ctx.player.enqueue(index, track_id, Some(CLIENT_NAME), self.user.as_deref());
+                writeln!(out, "Id: {}", queue_id.0).unwrap();
+            }
+            ("addid", [_, _]) => return Err(Ack::arg("Musium can only add to the end of the queue.")),
+            ("delete", [range]) => {
+                let queue = self.ctx.player.get_queue();
+                let (start, end) = parse_range(range)?;
+                let end = end.unwrap_or(queue.tracks.len());
+                if start >= end || end > queue.tracks.len() {
+                    return Err(Ack::arg("Bad song index."));
+                }
+                for pos in start..end {
+                    self.remove_at(&queue.tracks, pos);
+                }
+            }
+            ("deleteid", [id]) => {
+                let queue = self.ctx.player.get_queue();
+                let pos = self.find_queue_position(&queue.tracks, id)?;
+                self.remove_at(&queue.tracks, pos);
+            }
+            ("clear", []) => self.ctx.player.clear_queue(),
+            ("shuffle", []) => {
+                let index = &*self.ctx.index_var.get();
+                self.ctx.player.shuffle(index);
+            }
+            ("next", []) => {
+                self.ctx.player.skip();
+            }
+            ("previous", []) => {
+                self.ctx.player.previous();
+            }
+            // Musium plays whenever the queue is not empty, so playing only
+            // has to undo a pause.
+            ("play", []) | ("playid", []) => {
+                self.ctx.player.play();
+            }
+            ("play", [pos]) => {
+                let pos = usize::from_str(pos).map_err(|_| Ack::arg("Invalid song position."))?;
+                let queue = self.ctx.player.get_queue();
+                self.play_at(&queue.tracks, pos)?;
+                self.ctx.player.play();
+            }
+            ("playid", [id]) => {
+                let queue = self.ctx.player.get_queue();
+                let pos = self.find_queue_position(&queue.tracks, id)?;
+                self.play_at(&queue.tracks, pos)?;
+                self.ctx.player.play();
+            }
+            ("pause", [state]) if state == "1" => self.pause()?,
+            ("pause", [state]) if state == "0" => {
+                self.ctx.player.play();
+            }
+            ("pause", []) => match self.ctx.player.get_now_playing().state {
+                PlaybackState::Paused => {
+                    self.ctx.player.play();
+                }
+                _ => self.pause()?,
+            },
+            ("pause", [_]) => return Err(Ack::arg("Expected 0 or 1.")),
+            ("seekcur", [time]) => {
+                let queue = self.ctx.player.get_queue();
+                let position_ms = queue.tracks.first().map_or(0, |t| t.position_ms);
+                self.seek(parse_seek_time(time, position_ms)?)?;
+            }
+            ("seekid", [id, time]) => {
+                let queue = self.ctx.player.get_queue();
+                let pos = self.find_queue_position(&queue.tracks, id)?;
+                if pos != 0 {
+                    return Err(Ack::arg("Musium can only seek in the current song."));
+                }
+                self.seek(parse_seek_time(time, queue.tracks[0].position_ms)?)?;
+            }
+            ("setvol", [percent]) => {
+                let percent = i32::from_str(percent).map_err(|_| Ack::arg("Invalid volume."))?;
+                self.set_volume_percent(percent);
+            }
+            ("volume", [delta]) => {
+                let delta = i32::from_str(delta).map_err(|_| Ack::arg("Invalid volume change."))?;
+                let current = volume_to_percent(self.ctx.player.get_volume());
+                self.set_volume_percent(current + delta);
+            }
+            ("lsinfo", _) => self.list_directory(args, false, true, out)?,
+            ("listall", _) => self.list_directory(args, true, false, out)?,
+            ("listallinfo", _) => self.list_directory(args, true, true, out)?,
+            ("find", _) => self.find(args, false, false, out)?,
+            ("search", _) => self.find(args, true, false, out)?,
+            ("findadd", _) => self.find(args, false, true, out)?,
+            ("searchadd", _) => self.find(args, true, true, out)?,
+            ("list", _) => self.list(args, out)?,
+            ("count", _) => {
+                let conditions = parse_filter(args, Operator::Equal)?;
+                let index = &*self.ctx.index_var.get();
+                let (mut songs, mut playtime) = (0_u64, 0_u64);
+                for kv in index.get_tracks() {
+                    let song = self.get_song(index, kv.track_id, &kv.track);
+                    if conditions.iter().all(|c| song.matches(c, false)) {
+                        songs += 1;
+                        playtime += kv.track.duration_seconds as u64;
+                    }
+                }
+                writeln!(out, "songs: {}\nplaytime: {}", songs, playtime).unwrap();
+            }
+            _ => return Err(Ack::arg("Wrong number of arguments.")),
+        }
+
+        Ok(())
+    }
+}
+
+/// Read lines from the client, and send them to the connection loop.
+fn spawn_reader(stream: TcpStream, sender: Sender<Input>) {
+    std::thread::Builder::new()
+        .name("mpd_reader".into())
+        .spawn(move || {
+            let mut reader = BufReader::new(stream);
+            loop {
+                let mut line = String::new();
+                match reader.by_ref().take(MAX_LINE_LEN).read_line(&mut line) {
+                    // A line without newline is cut off by the length limit, or
+                    // by the end of the input. Either way, we are done.
+                    Ok(..) if !line.ends_with('\n') => break,
+                    Ok(..) => {
+                        let line = line.trim_end_matches(&['\r', '\n'][..]).to_string();
+                        if sender.send(Input::Line(line)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(..) => break,
+                }
+            }
+            let _ = sender.send(Input::Closed);
+        })
+        .expect("Failed to spawn MPD reader thread.");
+}
+
+/// Forward events from the event bus to the connection loop as subsystems.
+fn spawn_event_forwarder(events: Receiver<Arc<[u8]>>, sender: Sender<Input>) {
+    std::thread::Builder::new()
+        .name("mpd_events".into())
+        .spawn(move || {
+            // This thread exits at the first event after the connection closed.
+            for message in events.iter() {
+                for subsystem in get_subsystems(&message) {
+                    if sender.send(Input::Changed(subsystem)).is_err() {
+                        return;
+                    }
+                }
+            }
+        })
+        .expect("Failed to spawn MPD event thread.");
+}
+
+/// Serve an MPD client until it disconnects.
+pub fn serve_client(ctx: &Context, mut stream: TcpStream, events: Receiver<Arc<[u8]>>) -> io::Result<()> {
+    let (sender, inputs) = mpsc::channel();
+    spawn_reader(stream.try_clone()?, sender.clone());
+    spawn_event_forwarder(events, sender);
+
+    let result = run_session(ctx, &mut stream, inputs);
+
+    // The reader thread holds a clone of the socket, so dropping ours would
+    // not close the connection. Shutting down also makes the reader exit.
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+fn run_session(ctx: &Context, writer: &mut TcpStream, inputs: Receiver<Input>) -> io::Result<()> {
+    writeln!(writer, "OK MPD {}", PROTOCOL_VERSION)?;
+
+    let mut session = Session {
+        ctx: ctx,
+        scope: if ctx.config.unauthenticated { Some(Scope::Full) } else { None },
+        user: None,
+        playlist_version: 1,
+        changed: BTreeSet::new(),
+    };
+
+    // When the client waits in `idle`, the subsystems it is interested in.
+    let mut idle: Option<BTreeSet<String>> = None;
+
+    // Inside a command list, whether to respond `list_OK`, and the commands.
+    let mut command_list: Option<(bool, Vec<Vec<String>>)> = None;
+
+    for input in inputs.iter() {
+        let mut out = Vec::new();
+        let line = match input {
+            Input::Closed => return Ok(()),
+            Input::Changed(subsystem) => {
+                if subsystem == "playlist" {
+                    session.playlist_version += 1;
+                }
+                session.changed.insert(subsystem);
+                if let Some(subsystems) = idle.as_ref() {
+                    if session.take_changes(subsystems, &mut out) {
+                        idle = None;
+                    }
+                }
+                writer.write_all(&out)?;
+                continue;
+            }
+            Input::Line(line) => line,
+        };
+
+        if idle.is_some() {
+            // While idle, the only command that clients may send is noidle.
+            if line.trim() != "noidle" {
+                return Ok(());
+            }
+            idle = None;
+            writer.write_all(b"OK\n")?;
+            continue;
+        }
+
+        let args = match parse_arguments(&line) {
+            Ok(args) => args,
+            Err(ack) => {
+                ack.write(&mut out, 0, "");
+                writer.write_all(&out)?;
+                continue;
+            }
+        };
+        let command = match args.first() {
+            Some(command) => command.as_str(),
+            None => {
+                Ack::new(ACK_ERROR_UNKNOWN, "No command given.").write(&mut out, 0, "");
+                writer.write_all(&out)?;
+                continue;
+            }
+        };
+
+        if let Some((list_ok, commands)) = command_list.as_mut() {
+            if command != "command_list_end" {
+                commands.push(args);
+                continue;
+            }
+            let list_ok = *list_ok;
+            let commands = std::mem::take(commands);
+            command_list = None;
+            let mut failed = false;
+            for (i, args) in commands.iter().enumerate() {
+                match session.execute(&args[0], &args[1..], &mut out) {
+                    Ok(()) if list_ok => out.extend_from_slice(b"list_OK\n"),
+                    Ok(()) => {}
+                    Err(ack) => {
+                        ack.write(&mut out, i, &args[0]);
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+            if !failed {
+                out.extend_from_slice(b"OK\n");
+            }
+            writer.write_all(&out)?;
+            continue;
+        }
+
+        match command {
+            "close" => return Ok(()),
+            "command_list_begin" => command_list = Some((false, Vec::new())),
+            "command_list_ok_begin" => command_list = Some((true, Vec::new())),
+            "idle" => match session.check_permission(command) {
+                Ok(()) => {
+                    let subsystems = args[1..].iter().map(|s| s.to_ascii_lowercase()).collect();
+                    if !session.take_changes(&subsystems, &mut out) {
+                        idle = Some(subsystems);
+                    }
+                }
+                Err(ack) => ack.write(&mut out, 0, command),
+            },
+            // Outside of idle, noidle is ignored, without response.
+            "noidle" => {}
+            _ => match session.execute(command, &args[1..], &mut out) {
+                Ok(()) => out.extend_from_slice(b"OK\n"),
+                Err(ack) => ack.write(&mut out, 0, command),
+            },
+        }
+        writer.write_all(&out)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{get_subsystems, parse_arguments, parse_filter, parse_range, parse_seek_time};
+    use super::{percent_to_volume, volume_to_percent};
+    use super::{Condition, Operator, Tag};
+    use crate::player::Millibel;
+
+    #[test]
+    fn parse_arguments_handles_quotes_and_escapes() {
+        assert_eq!(parse_arguments("status").unwrap(), vec!["status"]);
+        assert_eq!(
+            parse_arguments(r#"find  artist "AC/DC \"live\"" album x"#).unwrap(),
+            vec!["find", "artist", "AC/DC \"live\"", "album", "x"],
+        );
+        assert_eq!(parse_arguments(r#"add "a\\b""#).unwrap(), vec!["add", "a\\b"]);
+        assert!(parse_arguments(r#"add "unterminated"#).is_err());
+    }
+
+    #[test]
+    fn parse_range_accepts_position_and_ranges() {
+        assert_eq!(parse_range("3"), Ok((3, Some(4))));
+        assert_eq!(parse_range("3:7"), Ok((3, Some(7))));
+        assert_eq!(parse_range("3:"), Ok((3, None)));
+        assert!(parse_range("x").is_err());
+    }
+
+    #[test]
+    fn parse_seek_time_is_relative_with_a_sign() {
+        assert_eq!(parse_seek_time("12.5", 60_000), Ok(12_500));
+        assert_eq!(parse_seek_time("+10", 60_000), Ok(70_000));
+        assert_eq!(parse_seek_time("-10", 60_000), Ok(50_000));
+        assert_eq!(parse_seek_time("-90", 60_000), Ok(0));
+        assert!(parse_seek_time("x", 60_000).is_err());
+        assert!(parse_seek_time("inf", 60_000).is_err());
+    }
+
+    #[test]
+    fn parse_filter_accepts_expressions() {
+        let args = vec![r#"((artist == 'Sigur Rós') AND (album contains "()") AND (base 'a/b'))"#.to_string()];
+        assert_eq!(
+            parse_filter(&args, Operator::Equal).unwrap(),
+            vec![
+                Condition::Tag { tag: Tag::Artist, op: Operator::Equal, value: "Sigur Rós".to_string() },
+                Condition::Tag { tag: Tag::Album, op: Operator::Contains, value: "()".to_string() },
+                Condition::Base("a/b".to_string()),
+            ],
+        );
+        let args = vec!["(Title != 'it\\'s')".to_string()];
+        assert_eq!(
+            parse_filter(&args, Operator::Equal).unwrap(),
+            vec![Condition::Tag { tag: Tag::Title, op: Operator::NotEqual, value: "it's".to_string() }],
+        );
+        assert!(parse_filter(&["((artist == 'a') OR (artist == 'b'))".to_string()], Operator::Equal).is_err());
+        assert!(parse_filter(&["(genre == 'Jazz')".to_string()], Operator::Equal).is_err());
+    }
+
+    #[test]
+    fn parse_filter_accepts_tag_value_pairs() {
+        let args = vec!["Artist".to_string(), "Bonobo".to_string(), "base".to_string(), "B".to_string()];
+        assert_eq!(
+            parse_filter(&args, Operator::Contains).unwrap(),
+            vec![
+                Condition::Tag { tag: Tag::Artist, op: Operator::Contains, value: "Bonobo".to_string() },
+                Condition::Base("B".to_string()),
+            ],
+        );
+        assert!(parse_filter(&args[..3], Operator::Equal).is_err());
+    }
+
+    #[test]
+    fn volume_maps_onto_percentage() {
+        assert_eq!(volume_to_percent(Millibel(0)), 100);
+        assert_eq!(volume_to_percent(Millibel(600)), 100);
+        assert_eq!(volume_to_percent(Millibel(-3000)), 50);
+        assert_eq!(volume_to_percent(Millibel(-6000)), 0);
+        assert_eq!(percent_to_volume(50), Millibel(-3000));
+        assert_eq!(percent_to_volume(120), Millibel(0));
+    }
+
+    #[test]
+    fn get_subsystems_maps_events() {
+        assert_eq!(get_subsystems(b"event: queue_changed\ndata: {}\n\n"), &["playlist"]);
+        assert_eq!(get_subsystems(b"event: volume_changed\ndata: {}\n\n"), &["mixer"]);
+        assert!(get_subsystems(b"event: unknown\ndata: {}\n\n").is_empty());
+    }
+}
@@ -0,0 +1,535 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! MPRIS, so desktop media controls can show and control playback.
+//!
+//! We expose `org.mpris.MediaPlayer2` on the session or system bus, see
+//! <https://specifications.freedesktop.org/mpris-spec/latest/>. Musium plays
+//! whenever the queue is not empty, unless it is paused. `Play`, `Pause`, and
+//! `PlayPause` pause and continue, `Seek` and `SetPosition` jump in the current
+//! track, `Next` skips it, `Previous` goes back, and the volume can be set.
+//! There is no stopping and no opening of urls, those methods have no effect.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+
+use crate::dbus::{self, Connection, Message, MessageSender, MessageType, Value};
+use crate::mvar::Var;
+use crate::player::{Millibel, PlaybackState, Player, QueueId, Source, TrackSnapshot};
+use crate::prim::AlbumId;
+use crate::radio;
+use crate::{MemoryMetaIndex, MetaIndex};
+
+pub const BUS_NAME: &str = "org.mpris.MediaPlayer2.musium";
+
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const IFACE_ROOT: &str = "org.mpris.MediaPlayer2";
+const IFACE_PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+const IFACE_PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+const IFACE_INTROSPECTABLE: &str = "org.freedesktop.DBus.Introspectable";
+const IFACE_PEER: &str = "org.freedesktop.DBus.Peer";
+
+/// The track id to report when nothing is playing, defined by the spec.
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg name="xml_data" type="s" direction="out"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="property_name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <method name="Set">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="property_name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="in"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface_name" type="s"/>
+      <arg name="changed_properties" type="a{sv}"/>
+      <arg name="invalidated_properties" type="as"/>
+    </signal>
+  </interface>
+  <interface name="org.mpris.MediaPlayer2">
+    <method name="Raise"/>
+    <method name="Quit"/>
+    <property name="CanQuit" type="b" access="read"/>
+    <property name="CanRaise" type="b" access="read"/>
+    <property name="HasTrackList" type="b" access="read"/>
+    <property name="Identity" type="s" access="read"/>
+    <property name="SupportedUriSchemes" type="as" access="read"/>
+    <property name="SupportedMimeTypes" type="as" access="read"/>
+  </interface>
+  <interface name="org.mpris.MediaPlayer2.Player">
+    <method name="Next"/>
+    <method name="Previous"/>
+    <method name="Pause"/>
+    <method name="PlayPause"/>
+    <method name="Stop"/>
+    <method name="Play"/>
+    <method name="Seek"><arg name="Offset" type="x" direction="in"/></method>
+    <method name="SetPosition">
+      <arg name="TrackId" type="o" direction="in"/>
+      <arg name="Position" type="x" direction="in"/>
+    </method>
+    <method name="OpenUri"><arg name="Uri" type="s" direction="in"/></method>
+    <signal name="Seeked"><arg name="Position" type="x"/></signal>
+    <property name="PlaybackStatus" type="s" access="read"/>
+    <property name="LoopStatus" type="s" access="read"/>
+    <property name="Rate" type="d" access="read"/>
+    <property name="Shuffle" type="b" access="read"/>
+    <property name="Metadata" type="a{sv}" access="read"/>
+    <property name="Volume" type="d" access="readwrite"/>
+    <property name="Position" type="x" access="read"/>
+    <property name="MinimumRate" type="d" access="read"/>
+    <property name="MaximumRate" type="d" access="read"/>
+    <property name="CanGoNext" type="b" access="read"/>
+    <property name="CanGoPrevious" type="b" access="read"/>
+    <property name="CanPlay" type="b" access="read"/>
+    <property name="CanPause" type="b" access="read"/>
+    <property name="CanSeek" type="b" access="read"/>
+    <property name="CanControl" type="b" access="read"/>
+  </interface>
+</node>
+"#;
+
+/// Return the MPRIS track id of the queue entry.
+///
+/// The same track can be in the queue twice, so we identify it by its queue id
+/// rather than by its track id.
+fn get_track_path(queue_id: QueueId) -> String {
+    format!("/nl/ruuda/musium/queue/{}", queue_id)
+}
+
+/// Convert the volume to the linear scale that MPRIS uses.
+///
+/// The spec leaves the curve to the player, we pick the amplitude factor, so
+/// 1.0 is 0 dB. Musium allows a bit of gain, so it can exceed 1.0.
+fn volume_to_linear(volume: Millibel) -> f64 {
+    10.0_f64.powf(volume.0 as f64 / 2000.0)
+}
+
+fn linear_to_volume(linear: f64) -> Millibel {
+    // The player clamps the volume to its range, below -60 dB is silent anyway.
+    if linear > 0.001 {
+        Millibel((2000.0 * linear.log10()).round().min(i16::MAX as f64) as i16)
+    } else {
+        Millibel(-6000)
+    }
+}
+
+/// The cover art of the current album, extracted to a file.
+///
+/// Desktop widgets load the art url themselves, and they can't authenticate
+/// to the http api, so we extract the cover into the runtime directory. We
+/// only keep the cover of the album that is playing.
+#[derive(Default)]
+pub struct ArtCache {
+    current: Mutex<Option<(AlbumId, Option<String>)>>,
+}
+
+impl ArtCache {
+    fn get_dir() -> PathBuf {
+        env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir)
+            .join("musium")
+    }
+
+    /// Return the `file://` url of the album's cover, extracting it if needed.
+    fn get_url(&self, index: &MemoryMetaIndex, album_id: AlbumId) -> Option<String> {
+        let mut current = self.current.lock().unwrap();
+        match current.as_ref() {
+            Some((id, url)) if *id == album_id => return url.clone(),
+            _ => {}
+        }
+        let url = match ArtCache::extract(index, album_id) {
+            Ok(url) => url,
+            Err(err) => {
+                warn!("Failed to extract cover art for MPRIS: {:?}", err);
+                None
+            }
+        };
+        *current = Some((album_id, url.clone()));
+        url
+    }
+
+    fn extract(index: &MemoryMetaIndex, album_id: AlbumId) -> io::Result<Option<String>> {
+        let tracks = index.get_album_tracks(album_id);
+        let track = match tracks.first() {
+            Some(t) => &t.track,
+            None => return Ok(None),
+        };
+        let opts = claxon::FlacReaderOptions {
+            metadata_only: true,
+            read_picture: claxon::ReadPicture::CoverAsVec,
+            read_vorbis_comment: false,
+        };
+        let reader = match claxon::FlacReader::open_ext(index.get_filename(track.filename), opts) {
+            Ok(r) => r,
+            Err(..) => return Ok(None),
+        };
+        let cover = match reader.into_pictures().pop() {
+            Some(cover) => cover,
+            None => return Ok(None),
+        };
+        let extension = match &cover.mime_type[..] {
+            "image/jpeg" => "jpg",
+            "image/png" => "png",
+            _ => "img",
+        };
+
+        let dir = ArtCache::get_dir();
+        fs::create_dir_all(&dir)?;
+
+        // Remove the cover of the previous album.
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let is_cover = path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("cover-"));
+            if is_cover {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let path = dir.join(format!("cover-{}.{}", album_id, extension));
+        fs::write(&path, cover.into_vec())?;
+        Ok(Some(format!("file://{}", path.to_string_lossy())))
+    }
+}
+
+/// The parts of the server that MPRIS reads and controls.
+pub struct Context<'a> {
+    pub index_var: &'a Var<MemoryMetaIndex>,
+    pub player: &'a Player,
+    pub art: &'a ArtCache,
+}
+
+fn get_metadata(ctx: &Context, current: Option<&TrackSnapshot>) -> Value {
+    let index = &*ctx.index_var.get();
+    let no_track = || Value::dict(vec![("mpris:trackid", Value::ObjectPath(NO_TRACK.to_string()))]);
+    let snapshot = match current {
+        Some(c) => c,
+        None => return no_track(),
+    };
+    let track_id = match &snapshot.source {
+        Source::Track(track_id) => *track_id,
+        Source::Radio(station) => return get_radio_metadata(snapshot, station),
+    };
+    let track = match index.get_track(track_id) {
+        Some(t) => t,
+        None => return no_track(),
+    };
+    let album_id = track_id.album_id();
+    let album = index.get_album(album_id).expect("Track's album should be in the index.");
+
+    let mut metadata = vec![
+        ("mpris:trackid", Value::ObjectPath(get_track_path(snapshot.queue_id))),
+        ("mpris:length", Value::Int64(track.duration_seconds as i64 * 1_000_000)),
+        ("xesam:title", Value::Str(index.get_string(track.title).to_string())),
+        ("xesam:artist", Value::strings(vec![index.get_string(track.artist).to_string()])),
+        ("xesam:album", Value::Str(index.get_string(album.title).to_string())),
+        ("xesam:albumArtist", Value::strings(vec![index.get_string(album.artist).to_string()])),
+        ("xesam:trackNumber", Value::Int32(track_id.track_number() as i32)),
+        ("xesam:discNumber", Value::Int32(track_id.disc_number() as i32)),
+        ("xesam:contentCreated", Value::Str(album.original_release_date.year.to_string())),
+    ];
+    if let Some(url) = ctx.art.get_url(index, album_id) {
+        metadata.push(("mpris:artUrl", Value::Str(url)));
+    }
+    Value::dict(metadata)
+}
+
+/// Return the metadata of a radio station, which has no length.
+fn get_radio_metadata(snapshot: &TrackSnapshot, station: &radio::Station) -> Value {
+    let title = snapshot.stream_title.as_ref().unwrap_or(&station.name);
+    let metadata = vec![
+        ("mpris:trackid", Value::ObjectPath(get_track_path(snapshot.queue_id))),
+        ("xesam:title", Value::Str(title.clone())),
+        ("xesam:album", Value::Str(station.name.clone())),
+        ("xesam:url", Value::Str(station.url.clone())),
+    ];
+    Value::dict(metadata)
+}
+
+/// The current track, as far as seeking is concerned. Times are in microseconds.
+struct SeekTarget {
+    track_path: String,
+    position_us: i64,
+    length_us: i64,
+}
+
+/// Return the current track, if we can seek in it.
+///
+/// Radio stations are live, and a cast device or browser plays on its own,
+/// we can't seek in those.
+fn get_seek_target(ctx: &Context) -> Option<SeekTarget> {
+    if ctx.player.is_casting() {
+        return None;
+    }
+    let current = ctx.player.get_now_playing().current?;
+    let track_id = current.source.track_id()?;
+    let index = &*ctx.index_var.get();
+    let track = index.get_track(track_id)?;
+    let target = SeekTarget {
+        track_path: get_track_path(current.queue_id),
+        position_us: current.position_ms as i64 * 1000,
+        length_us: track.duration_seconds as i64 * 1_000_000,
+    };
+    Some(target)
+}
+
+/// Jump to the position in the current track, if it is within the track.
+fn seek_to(ctx: &Context, target: &SeekTarget, position_us: i64) {
+    if position_us >= 0 && position_us <= target.length_us {
+        ctx.player.seek(position_us as u64 / 1000);
+    }
+}
+
+fn get_root_properties() -> Vec<(&'static str, Value)> {
+    vec![
+        ("CanQuit", Value::Bool(false)),
+        ("CanRaise", Value::Bool(false)),
+        ("HasTrackList", Value::Bool(false)),
+        ("Identity", Value::Str("Musium".to_string())),
+        ("SupportedUriSchemes", Value::strings(Vec::new())),
+        ("SupportedMimeTypes", Value::strings(Vec::new())),
+    ]
+}
+
+fn get_player_properties(ctx: &Context) -> Vec<(&'static str, Value)> {
+    let now_playing = ctx.player.get_now_playing();
+    let status = match now_playing.state {
+        PlaybackState::Stopped => "Stopped",
+        PlaybackState::Paused => "Paused",
+        PlaybackState::Buffering | PlaybackState::Playing => "Playing",
+    };
+    let position_us = now_playing.current.as_ref().map_or(0, |c| c.position_ms as i64 * 1000);
+    let is_casting = ctx.player.is_casting();
+    let has_current = now_playing.current.is_some();
+    let is_track = matches!(&now_playing.current, Some(c) if c.source.track_id().is_some());
+    vec![
+        ("PlaybackStatus", Value::Str(status.to_string())),
+        ("LoopStatus", Value::Str("None".to_string())),
+        ("Rate", Value::Double(1.0)),
+        ("Shuffle", Value::Bool(false)),
+        ("Metadata", get_metadata(ctx, now_playing.current.as_ref())),
+        ("Volume", Value::Double(volume_to_linear(now_playing.volume))),
+        ("Position", Value::Int64(position_us)),
+        ("MinimumRate", Value::Double(1.0)),
+        ("MaximumRate", Value::Double(1.0)),
+        ("CanGoNext", Value::Bool(now_playing.current.is_some())),
+        // Even with an empty queue, we can go back to what played last.
+        ("CanGoPrevious", Value::Bool(true)),
+        ("CanPlay", Value::Bool(has_current)),
+        ("CanPause", Value::Bool(has_current && !is_casting)),
+        ("CanSeek", Value::Bool(is_track && !is_casting)),
+        ("CanControl", Value::Bool(true)),
+    ]
+}
+
+fn get_properties(ctx: &Context, interface: &str) -> Option<Vec<(&'static str, Value)>> {
+    match interface {
+        IFACE_ROOT => Some(get_root_properties()),
+        IFACE_PLAYER => Some(get_player_properties(ctx)),
+        _ => None,
+    }
+}
+
+fn handle_properties_call(ctx: &Context, call: &Message) -> Message {
+    let args: Vec<&str> = call.body.iter().filter_map(|v| v.as_str()).collect();
+    let member = call.member.as_deref().unwrap_or("");
+    let interface = args.first().copied().unwrap_or("");
+    let properties = match get_properties(ctx, interface) {
+        Some(properties) => properties,
+        None => return Message::error(call, "org.freedesktop.DBus.Error.UnknownInterface", "No such interface."),
+    };
+    match (member, &args[..]) {
+        ("GetAll", [_]) => Message::method_return(call, vec![Value::dict(properties)]),
+        ("Get", [_, name]) => match properties.into_iter().find(|(k, _)| k == name) {
+            Some((_, value)) => Message::method_return(call, vec![Value::Variant(Box::new(value))]),
+            None => Message::error(call, "org.freedesktop.DBus.Error.UnknownProperty", "No such property."),
+        },
+        ("Set", [IFACE_PLAYER, "Volume"]) => match call.body.get(2) {
+            Some(Value::Variant(v)) => match **v {
+                Value::Double(linear) => {
+                    let target = linear_to_volume(linear);
+                    let current = ctx.player.get_volume();
+                    ctx.player.change_volume(Millibel(target.0.saturating_sub(current.0)));
+                    Message::method_return(call, Vec::new())
+                }
+                _ => Message::error(call, "org.freedesktop.DBus.Error.InvalidArgs", "Volume must be a double."),
+            },
+            _ => Message::error(call, "org.freedesktop.DBus.Error.InvalidArgs", "Expected a variant."),
+        },
+        ("Set", [_, _]) => Message::error(call, "org.freedesktop.DBus.Error.PropertyReadOnly", "Property is read-only."),
+        _ => Message::error(call, "org.freedesktop.DBus.Error.InvalidArgs", "Invalid arguments."),
+    }
+}
+
+fn handle_call(ctx: &Context, call: &Message) -> Message {
+    if call.path.as_deref() != Some(OBJECT_PATH) {
+        return Message::error(call, "org.freedesktop.DBus.Error.UnknownObject", "No such object.");
+    }
+    let interface = call.interface.as_deref().unwrap_or("");
+    let member = call.member.as_deref().unwrap_or("");
+    match (interface, member) {
+        (IFACE_PROPERTIES, _) => handle_properties_call(ctx, call),
+        (IFACE_INTROSPECTABLE, "Introspect") => {
+            Message::method_return(call, vec![Value::Str(INTROSPECTION.to_string())])
+        }
+        (IFACE_PEER, "Ping") => Message::method_return(call, Vec::new()),
+        (IFACE_ROOT, "Raise") | (IFACE_ROOT, "Quit") => Message::method_return(call, Vec::new()),
+        (IFACE_PLAYER, "Next") => {
+            ctx.player.skip();
+            Message::method_return(call, Vec::new())
+        }
+        (IFACE_PLAYER, "Previous") => {
+            ctx.player.previous();
+            Message::method_return(call, Vec::new())
+        }
+        (IFACE_PLAYER, "Pause") => {
+            ctx.player.pause();
+            Message::method_return(call, Vec::new())
+        }
+        (IFACE_PLAYER, "Play") => {
+            ctx.player.play();
+            Message::method_return(call, Vec::new())
+        }
+        (IFACE_PLAYER, "PlayPause") => {
+            if !ctx.player.play() {
+                ctx.player.pause();
+            }
+            Message::method_return(call, Vec::new())
+        }
+        (IFACE_PLAYER, "Seek") => match call.body.first() {
+            Some(Value::Int64(offset_us)) => {
+                if let Some(target) = get_seek_target(ctx) {
+                    let position_us = target.position_us.saturating_add(*offset_us);
+                    // The spec says that seeking past the end goes to the next
+                    // track, and seeking before the start goes to the start.
+                    match position_us > target.length_us {
+                        true => { ctx.player.skip(); }
+                        false => seek_to(ctx, &target, position_us.max(0)),
+                    }
+                }
+                Message::method_return(call, Vec::new())
+            }
+            _ => Message::error(call, "org.freedesktop.DBus.Error.InvalidArgs", "Offset must be an int64."),
+        },
+        (IFACE_PLAYER, "SetPosition") => match &call.body[..] {
+            [Value::ObjectPath(track_path), Value::Int64(position_us)] => {
+                // A position for a track that is no longer current is stale,
+                // the spec says to ignore it.
+                if let Some(target) = get_seek_target(ctx) {
+                    if *track_path == target.track_path {
+                        seek_to(ctx, &target, *position_us);
+                    }
+                }
+                Message::method_return(call, Vec::new())
+            }
+            _ => Message::error(call, "org.freedesktop.DBus.Error.InvalidArgs", "Expected a track id and a position."),
+        },
+        // Musium plays whenever the queue is not empty, there is no stopped
+        // state to go to, and we only play what is in the library.
+        (IFACE_PLAYER, "Stop") | (IFACE_PLAYER, "OpenUri") => Message::method_return(call, Vec::new()),
+        _ => Message::error(call, "org.freedesktop.DBus.Error.UnknownMethod", "No such method."),
+    }
+}
+
+/// Connect to the bus and claim the MPRIS name.
+pub fn connect(bus: dbus::Bus) -> io::Result<Connection> {
+    let mut connection = Connection::connect(bus)?;
+    connection.request_name(BUS_NAME)?;
+    Ok(connection)
+}
+
+/// Answer method calls until the connection fails.
+pub fn serve_calls(ctx: &Context, mut connection: Connection) -> io::Result<()> {
+    let sender = connection.sender();
+    loop {
+        let message = connection.read_message()?;
+        // Signals, such as NameAcquired, and replies are of no interest.
+        if message.message_type != MessageType::MethodCall {
+            continue;
+        }
+        let mut reply = handle_call(ctx, &message);
+        if message.flags & dbus::FLAG_NO_REPLY_EXPECTED == 0 {
+            sender.send(&mut reply)?;
+        }
+        // Clients extrapolate the position from the rate, they don't poll it,
+        // so when it jumps, we have to tell them.
+        let is_seek = message.interface.as_deref() == Some(IFACE_PLAYER)
+            && matches!(message.member.as_deref(), Some("Seek") | Some("SetPosition"));
+        if is_seek {
+            let position_us = get_seek_target(ctx).map_or(0, |target| target.position_us);
+            let mut signal = Message::signal(OBJECT_PATH, IFACE_PLAYER, "Seeked", vec![Value::Int64(position_us)]);
+            sender.send(&mut signal)?;
+        }
+    }
+}
+
+/// Emit `PropertiesChanged` for the player properties that events change.
+///
+/// Returns when the event bus drops the subscription.
+pub fn emit_changes(ctx: &Context, sender: &MessageSender, events: Receiver<Arc<[u8]>>) -> io::Result<()> {
+    let mut last = get_player_properties(ctx);
+    for _event in events {
+        let properties = get_player_properties(ctx);
+        let changed: Vec<(&str, Value)> = properties
+            .iter()
+            .zip(last.iter())
+            // The spec says not to signal position changes, they are implied
+            // by the playback rate.
+            .filter(|((name, value), (_, last_value))| *name != "Position" && value != last_value)
+            .map(|((name, value), _)| (*name, value.clone()))
+            .collect();
+        if !changed.is_empty() {
+            let mut signal = Message::signal(
+                OBJECT_PATH,
+                IFACE_PROPERTIES,
+                "PropertiesChanged",
+                vec![Value::Str(IFACE_PLAYER.to_string()), Value::dict(changed), Value::strings(Vec::new())],
+            );
+            sender.send(&mut signal)?;
+        }
+        last = properties;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{linear_to_volume, volume_to_linear};
+    use crate::player::Millibel;
+
+    #[test]
+    fn volume_roundtrips_through_linear() {
+        assert_eq!(volume_to_linear(Millibel(0)), 1.0);
+        assert!((volume_to_linear(Millibel(-2000)) - 0.1).abs() < 1e-9);
+        for mb in [-6000, -2500, -600, -1, 0, 300] {
+            assert_eq!(linear_to_volume(volume_to_linear(Millibel(mb))), Millibel(mb));
+        }
+        assert_eq!(linear_to_volume(0.0), Millibel(-6000));
+        assert_eq!(linear_to_volume(-1.0), Millibel(-6000));
+    }
+}
@@ -0,0 +1,1509 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Description of the REST API as an OpenAPI 3 document.
+//!
+//! The json of the API is written by hand in `serialization.rs`, so there are
+//! no types that we could derive a schema from. Instead, this module describes
+//! every endpoint, its parameters, and the shape of its response, and
+//! `server.rs` serves the document at `/api/openapi.json`, so client authors
+//! can generate bindings. The scope that an endpoint needs is not written
+//! down here, we take it from `auth::required_scope`, which the server uses to
+//! authorize requests.
+
+use serde_json::{json, Map, Value};
+use tiny_http::Method::{self, Delete, Get, Post, Put};
+
+use crate::auth::{self, Scope};
+
+/// The shape of a json value in a request or response.
+pub enum Schema {
+    Boolean,
+    Integer,
+    Number,
+    String,
+    /// A string in a known format, such as `date-time`.
+    Format(&'static str),
+    Nullable(&'static Schema),
+    Array(&'static Schema),
+    /// An object with all of these keys.
+    Object(&'static [(&'static str, Schema)]),
+    OneOf(&'static [Schema]),
+    /// A named schema from `SCHEMAS`.
+    Ref(&'static str),
+}
+
+/// Where a parameter goes in the request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Location {
+    Path,
+    Query,
+}
+
+pub struct Param {
+    pub name: &'static str,
+    pub location: Location,
+    pub required: bool,
+    pub schema: Schema,
+    pub description: &'static str,
+}
+
+const fn path(name: &'static str, schema: Schema, description: &'static str) -> Param {
+    Param { name: name, location: Location::Path, required: true, schema: schema, description: description }
+}
+
+const fn query(name: &'static str, schema: Schema, description: &'static str) -> Param {
+    Param { name: name, location: Location::Query, required: false, schema: schema, description: description }
+}
+
+const fn required_query(name: &'static str, schema: Schema, description: &'static str) -> Param {
+    Param { name: name, location: Location::Query, required: true, schema: schema, description: description }
+}
+
+/// The body of a request or response.
+pub enum Body {
+    /// No body.
+    Empty,
+    /// A json value.
+    Json(Schema),
+    /// Content of a different type, such as audio, images, or playlist files.
+    Media(&'static str),
+}
+
+pub struct Endpoint {
+    pub method: Method,
+    /// The path, with `{name}` for path parameters.
+    pub path: &'static str,
+    pub summary: &'static str,
+    pub params: &'static [Param],
+    pub request: Body,
+    /// The status code of a successful response.
+    pub status: u16,
+    pub response: Body,
+}
+
+const ALBUM_ID: Param = path("album_id", Schema::String, "Album id, 13 hexadecimal digits.");
+const ARTIST_ID: Param = path("artist_id", Schema::String, "Album artist id, 16 hexadecimal digits.");
+const METADATA_DRY_RUN: Param = query("dry_run", Schema::Boolean, "When true, only report the changes.");
+const METADATA_WRITE_TAGS: Param = query("write_tags", Schema::Boolean, "When true, also write the changes to the files.");
+const TRACK_ID: Param = path("track_id", Schema::String, "Track id, 16 hexadecimal digits.");
+const PLAYLIST_ID: Param = path("playlist_id", Schema::Integer, "Playlist id.");
+const CLIENT: Param = query("client", Schema::String, "Name of the client that enqueues, recorded with the listen.");
+const PLAYER: Param = query("player", Schema::String, "Name of the player, the default player when absent.");
+const SINCE: Param = query("since", Schema::String, "Only include listens from this RFC 3339 timestamp or date.");
+const UNTIL: Param = query("until", Schema::String, "Only include listens before this RFC 3339 timestamp or date.");
+const STATS_LIMIT: Param = query("limit", Schema::Integer, "Length of the list, from 1 to 100, 10 by default.");
+const STATS_CLIENT: Param = query("client", Schema::String, "Only include listens of tracks enqueued by this client.");
+const SHUFFLE_NEW: Param = query("shuffle", Schema::Boolean, "Shuffle the new entries, `false` by default.");
+const FORMAT: Param = query("format", Schema::String, "Transcode to `opus` or `mp3`.");
+const BITRATE: Param = query("bitrate", Schema::Integer, "Bitrate in kbps for `format`, from 32 to 320.");
+const PROFILE: Param = query("profile", Schema::String, "Name of a transcode profile from the config file.");
+
+const LISTING: &[Param] = &[
+    query("sort", Schema::String, "One of id, name, release_date, recently_added, rating, most_played."),
+    query("offset", Schema::Integer, "Number of items to skip."),
+    query("limit", Schema::Integer, "Maximum number of items to return."),
+];
+
+const DYNAMICS_LISTING: &[Param] = &[
+    path("kind", Schema::String, "Either `albums` or `tracks`."),
+    query("sort", Schema::String, "One of dynamic_range (the default), loudness, true_peak."),
+    query("offset", Schema::Integer, "Number of items to skip."),
+    query("limit", Schema::Integer, "Maximum number of items to return."),
+];
+
+/// Ranges `[begin, end)` of a string that match the query, in UTF-16 code units.
+const HIGHLIGHTS: Schema = Schema::Array(&Schema::Array(&Schema::Integer));
+
+const RATING: (&str, Schema) = ("rating", Schema::Integer);
+const PLAY_COUNT: (&str, Schema) = ("play_count", Schema::Integer);
+const LAST_PLAYED: (&str, Schema) = ("last_played", Schema::Nullable(&Schema::Format("date-time")));
+
+/// The named schemas, under `components/schemas` in the document.
+pub const SCHEMAS: &[(&str, Schema)] = &[
+    ("BriefAlbum", Schema::Object(&[
+        ("id", Schema::String),
+        ("title", Schema::String),
+        ("artist_ids", Schema::Array(&Schema::String)),
+        ("artist", Schema::String),
+        ("release_date", Schema::String),
+        ("first_seen", Schema::Format("date-time")),
+        ("duration_seconds", Schema::Integer),
+        RATING, PLAY_COUNT, LAST_PLAYED,
+    ])),
+    ("Album", Schema::Object(&[
+        ("title", Schema::String),
+        ("artist_ids", Schema::Array(&Schema::String)),
+        ("artist", Schema::String),
+        ("release_date", Schema::String),
+        ("duration_seconds", Schema::Integer),
+        RATING, PLAY_COUNT, LAST_PLAYED,
+        ("excluded", Schema::Boolean),
+        ("tracks", Schema::Array(&Schema::Ref("AlbumTrack"))),
+        ("discs", Schema::Array(&Schema::Object(&[
+            ("disc_number", Schema::Integer),
+            ("subtitle", Schema::Nullable(&Schema::String)),
+            ("track_count", Schema::Integer),
+            ("duration_seconds", Schema::Integer),
+        ]))),
+        ("disc_count", Schema::Integer),
+    ])),
+    ("AlbumTrack", Schema::Object(&[
+        ("id", Schema::String),
+        ("disc_number", Schema::Integer),
+        ("track_number", Schema::Integer),
+        ("title", Schema::String),
+        ("artist", Schema::String),
+        ("duration_seconds", Schema::Integer),
+        ("pregap_seconds", Schema::Number),
+        RATING, PLAY_COUNT, LAST_PLAYED,
+        ("excluded", Schema::Boolean),
+    ])),
+    ("Artist", Schema::Object(&[
+        ("id", Schema::String),
+        ("name", Schema::String),
+        ("sort_name", Schema::String),
+        ("duration_seconds", Schema::Integer),
+        RATING, PLAY_COUNT, LAST_PLAYED,
+    ])),
+    ("ArtistDetails", Schema::Object(&[
+        ("name", Schema::String),
+        ("sort_name", Schema::String),
+        ("tags", Schema::Array(&Schema::String)),
+        ("duration_seconds", Schema::Integer),
+        RATING, PLAY_COUNT, LAST_PLAYED,
+        ("albums", Schema::Array(&Schema::Ref("BriefAlbum"))),
+    ])),
+    ("Track", Schema::Object(&[
+        ("id", Schema::String),
+        ("title", Schema::String),
+        ("album_id", Schema::String),
+        ("album", Schema::String),
+        ("artist", Schema::String),
+    ])),
+    ("SearchResults", Schema::Object(&[
+        ("artists", Schema::Array(&Schema::Object(&[
+            ("id", Schema::String),
+            ("name", Schema::String),
+            ("albums", Schema::Array(&Schema::String)),
+        ]))),
+        ("albums", Schema::Array(&Schema::Object(&[
+            ("id", Schema::String),
+            ("title", Schema::String),
+            ("artist", Schema::String),
+            ("release_date", Schema::String),
+        ]))),
+        ("tracks", Schema::Array(&Schema::Ref("Track"))),
+    ])),
+    ("TypeaheadResults", Schema::Object(&[
+        ("artists", Schema::Array(&Schema::Object(&[
+            ("id", Schema::String),
+            ("name", Schema::String),
+            ("name_highlights", HIGHLIGHTS),
+        ]))),
+        ("albums", Schema::Array(&Schema::Object(&[
+            ("id", Schema::String),
+            ("title", Schema::String),
+            ("artist", Schema::String),
+            ("release_date", Schema::String),
+            ("title_highlights", HIGHLIGHTS),
+            ("artist_highlights", HIGHLIGHTS),
+        ]))),
+        ("tracks", Schema::Array(&Schema::Object(&[
+            ("id", Schema::String),
+            ("title", Schema::String),
+            ("album_id", Schema::String),
+            ("album", Schema::String),
+            ("artist", Schema::String),
+            ("title_highlights", HIGHLIGHTS),
+            ("artist_highlights", HIGHLIGHTS),
+        ]))),
+    ])),
+    ("Stats", Schema::Object(&[
+        ("tracks", Schema::Integer),
+        ("albums", Schema::Integer),
+        ("artists", Schema::Integer),
+        ("memory_bytes", Schema::Object(&[
+            ("tracks", Schema::Integer),
+            ("albums", Schema::Integer),
+            ("artists", Schema::Integer),
+            ("album_artists", Schema::Integer),
+            ("strings", Schema::Integer),
+            ("filenames", Schema::Integer),
+            ("words", Schema::Integer),
+            ("other", Schema::Integer),
+            ("total", Schema::Integer),
+        ])),
+    ])),
+    ("LibraryStats", Schema::Object(&[
+        ("tracks", Schema::Integer),
+        ("albums", Schema::Integer),
+        ("artists", Schema::Integer),
+        ("duration_seconds", Schema::Integer),
+        ("size_bytes", Schema::Integer),
+        ("formats", Schema::Array(&Schema::Object(&[
+            ("format", Schema::String),
+            ("tracks", Schema::Integer),
+        ]))),
+        ("sample_rates", Schema::Array(&Schema::Object(&[
+            ("sample_rate", Schema::Integer),
+            ("tracks", Schema::Integer),
+        ]))),
+        ("bits_per_sample", Schema::Array(&Schema::Object(&[
+            ("bits_per_sample", Schema::Integer),
+            ("tracks", Schema::Integer),
+        ]))),
+        ("years", Schema::Array(&Schema::Object(&[
+            ("year", Schema::Integer),
+            ("albums", Schema::Integer),
+        ]))),
+        ("genres", Schema::Array(&Schema::Object(&[
+            ("genre", Schema::String),
+            ("tracks", Schema::Integer),
+        ]))),
+    ])),
+    ("Sync", Schema::Object(&[
+        ("generation", Schema::String),
+        ("tracks", Schema::Integer),
+        ("albums", Schema::Integer),
+        ("artists", Schema::Integer),
+    ])),
+    ("QueueEntry", Schema::OneOf(&[Schema::Ref("QueuedTrack"), Schema::Ref("QueuedRadio")])),
+    ("QueuedTrack", Schema::Object(&[
+        ("queue_id", Schema::String),
+        ("track_id", Schema::String),
+        ("title", Schema::String),
+        ("album_id", Schema::String),
+        ("album_artist_ids", Schema::Array(&Schema::String)),
+        ("album", Schema::String),
+        ("artist", Schema::String),
+        ("release_date", Schema::String),
+        ("duration_seconds", Schema::Integer),
+        ("pregap_seconds", Schema::Number),
+        ("rating", Schema::Integer),
+        ("resume_offer_seconds", Schema::Nullable(&Schema::Number)),
+        ("position_seconds", Schema::Number),
+        ("buffered_seconds", Schema::Number),
+        ("is_buffering", Schema::Boolean),
+    ])),
+    ("QueuedRadio", Schema::Object(&[
+        ("queue_id", Schema::String),
+        ("radio_station_id", Schema::Integer),
+        ("station", Schema::String),
+        ("stream_title", Schema::Nullable(&Schema::String)),
+        ("position_seconds", Schema::Number),
+        ("buffered_seconds", Schema::Number),
+        ("is_buffering", Schema::Boolean),
+    ])),
+    ("Player", Schema::Object(&[
+        ("state", Schema::String),
+        ("queue_length", Schema::Integer),
+        ("volume_db", Schema::Number),
+        ("current", Schema::Nullable(&Schema::Ref("QueueEntry"))),
+        ("output", Schema::Nullable(&Schema::Ref("OutputFormat"))),
+        ("gain", Schema::Nullable(&Schema::Ref("Gain"))),
+    ])),
+    ("Gain", Schema::Object(&[
+        ("applied_db", Schema::Number),
+        ("limiter_reduction_db", Schema::Number),
+    ])),
+    ("OutputFormat", Schema::Object(&[
+        ("sample_rate_hz", Schema::Integer),
+        ("bits_per_sample", Schema::Integer),
+        ("bit_perfect", Schema::Boolean),
+    ])),
+    ("PlayerSummary", Schema::Object(&[
+        ("name", Schema::String),
+        ("state", Schema::String),
+        ("queue_length", Schema::Integer),
+        ("volume_db", Schema::Number),
+    ])),
+    ("Volume", Schema::Object(&[
+        ("volume_db", Schema::Number),
+    ])),
+    ("Playlist", Schema::Object(&[
+        ("id", Schema::Integer),
+        ("name", Schema::String),
+        ("query", Schema::Nullable(&Schema::String)),
+        ("track_count", Schema::Nullable(&Schema::Integer)),
+    ])),
+    ("PlaylistDetails", Schema::Object(&[
+        ("id", Schema::Integer),
+        ("name", Schema::String),
+        ("query", Schema::Nullable(&Schema::String)),
+        ("tracks", Schema::Array(&Schema::Object(&[
+            ("entry_id", Schema::Nullable(&Schema::Integer)),
+            ("id", Schema::String),
+            ("title", Schema::String),
+            ("album_id", Schema::String),
+            ("album", Schema::String),
+            ("artist", Schema::String),
+            ("duration_seconds", Schema::Integer),
+        ]))),
+    ])),
+    ("PlaylistImport", Schema::Object(&[
+        ("id", Schema::Integer),
+        ("track_count", Schema::Integer),
+        ("missing", Schema::Array(&Schema::String)),
+    ])),
+    ("Created", Schema::Object(&[
+        ("id", Schema::Integer),
+    ])),
+    ("RadioStation", Schema::Object(&[
+        ("id", Schema::Integer),
+        ("name", Schema::String),
+        ("url", Schema::String),
+    ])),
+    ("ListenPage", Schema::Object(&[
+        ("listens", Schema::Array(&Schema::Ref("Listen"))),
+        ("next_cursor", Schema::Nullable(&Schema::String)),
+    ])),
+    ("Listen", Schema::Object(&[
+        ("id", Schema::Integer),
+        ("track_id", Schema::String),
+        ("album_id", Schema::String),
+        ("album_artist_id", Schema::String),
+        ("started_at", Schema::Format("date-time")),
+        ("completed_at", Schema::Nullable(&Schema::Format("date-time"))),
+        ("track_title", Schema::String),
+        ("track_artist", Schema::String),
+        ("album_title", Schema::String),
+        ("album_artist", Schema::String),
+        ("duration_seconds", Schema::Integer),
+        ("track_number", Schema::Nullable(&Schema::Integer)),
+        ("disc_number", Schema::Nullable(&Schema::Integer)),
+        ("source", Schema::String),
+        ("scrobbled_at", Schema::Nullable(&Schema::Format("date-time"))),
+        ("played_fraction", Schema::Nullable(&Schema::Number)),
+        ("client", Schema::Nullable(&Schema::String)),
+    ])),
+    ("TopArtist", Schema::Object(&[
+        ("id", Schema::String),
+        ("name", Schema::String),
+        ("listens", Schema::Integer),
+        ("seconds", Schema::Integer),
+    ])),
+    ("TopAlbum", Schema::Object(&[
+        ("id", Schema::String),
+        ("title", Schema::String),
+        ("artist", Schema::String),
+        ("listens", Schema::Integer),
+        ("seconds", Schema::Integer),
+    ])),
+    ("TopTrack", Schema::Object(&[
+        ("id", Schema::String),
+        ("title", Schema::String),
+        ("artist", Schema::String),
+        ("album", Schema::String),
+        ("listens", Schema::Integer),
+        ("seconds", Schema::Integer),
+    ])),
+    ("ListenTotals", Schema::Object(&[
+        ("listens", Schema::Integer),
+        ("counted", Schema::Integer),
+        ("seconds", Schema::Integer),
+        ("skips", Schema::Integer),
+    ])),
+    ("SkippedTrack", Schema::Object(&[
+        ("id", Schema::String),
+        ("title", Schema::String),
+        ("artist", Schema::String),
+        ("album", Schema::String),
+        ("skips", Schema::Integer),
+        ("listens", Schema::Integer),
+    ])),
+    ("AlbumListens", Schema::Object(&[
+        ("id", Schema::String),
+        ("title", Schema::String),
+        ("artist", Schema::String),
+        ("listens", Schema::Integer),
+    ])),
+    ("OnThisDay", Schema::Object(&[
+        ("year", Schema::Integer),
+        ("albums", Schema::Array(&Schema::Ref("AlbumListens"))),
+    ])),
+    ("AlbumInProgress", Schema::Object(&[
+        ("album", Schema::Ref("BriefAlbum")),
+        ("next_track_id", Schema::String),
+        ("tracks_listened", Schema::Integer),
+        ("last_listen_at", Schema::String),
+    ])),
+    ("Rewind", Schema::Object(&[
+        ("year", Schema::Integer),
+        ("listens", Schema::Integer),
+        ("seconds", Schema::Integer),
+        ("most_played_album", Schema::Nullable(&Schema::Ref("TopAlbum"))),
+        ("discoveries", Schema::Array(&Schema::Ref("AlbumListens"))),
+    ])),
+    ("Chart", Schema::Object(&[
+        ("period", Schema::String),
+        ("start", Schema::Format("date")),
+        ("end", Schema::Format("date")),
+        ("entries", Schema::Array(&Schema::Ref("ChartEntry"))),
+    ])),
+    ("ChartEntry", Schema::Object(&[
+        ("rank", Schema::Integer),
+        ("previous_rank", Schema::Nullable(&Schema::Integer)),
+        ("change", Schema::Nullable(&Schema::Integer)),
+        ("artist", Schema::Ref("TopArtist")),
+        ("album", Schema::Ref("TopAlbum")),
+        ("track", Schema::Ref("TopTrack")),
+    ])),
+    ("DynamicsEntry", Schema::Object(&[
+        ("id", Schema::String),
+        ("album_id", Schema::String),
+        ("title", Schema::String),
+        ("artist", Schema::String),
+        ("release_date", Schema::String),
+        ("loudness", Schema::Number),
+        ("true_peak", Schema::Nullable(&Schema::Number)),
+        ("dynamic_range", Schema::Nullable(&Schema::Number)),
+    ])),
+    ("AlbumDynamics", Schema::Object(&[
+        ("id", Schema::String),
+        ("loudness", Schema::Nullable(&Schema::Number)),
+        ("true_peak", Schema::Nullable(&Schema::Number)),
+        ("dynamic_range", Schema::Nullable(&Schema::Number)),
+        ("tracks", Schema::Array(&Schema::Ref("TrackDynamics"))),
+    ])),
+    ("TrackDynamics", Schema::Object(&[
+        ("id", Schema::String),
+        ("disc_number", Schema::Integer),
+        ("track_number", Schema::Integer),
+        ("title", Schema::String),
+        ("loudness", Schema::Nullable(&Schema::Number)),
+        ("true_peak", Schema::Nullable(&Schema::Number)),
+        ("dynamic_range", Schema::Nullable(&Schema::Number)),
+    ])),
+    ("Discoveries", Schema::Object(&[
+        ("tracks", Schema::Integer),
+        ("albums", Schema::Integer),
+        ("artists", Schema::Integer),
+        ("top_albums", Schema::Array(&Schema::Ref("AlbumListens"))),
+    ])),
+    ("DiscoveryMonth", Schema::Object(&[
+        ("month", Schema::String),
+        ("listens", Schema::Integer),
+        ("new_tracks", Schema::Integer),
+        ("new_albums", Schema::Integer),
+        ("new_artists", Schema::Integer),
+        ("rate", Schema::Number),
+    ])),
+    ("SessionStats", Schema::Object(&[
+        ("sessions", Schema::Integer),
+        ("average_seconds", Schema::Integer),
+        ("average_listens", Schema::Number),
+        ("typical_start_hour", Schema::Nullable(&Schema::Integer)),
+    ])),
+    ("CastDevice", Schema::Object(&[
+        ("id", Schema::String),
+        ("name", Schema::String),
+        ("model", Schema::String),
+        ("address", Schema::String),
+    ])),
+    ("CastStatus", Schema::Object(&[
+        ("device", Schema::Nullable(&Schema::Ref("CastDevice"))),
+    ])),
+    ("BrowserOutput", Schema::Object(&[
+        ("session", Schema::Integer),
+        ("queue_id", Schema::Nullable(&Schema::String)),
+        ("url", Schema::Nullable(&Schema::String)),
+        ("start_seconds", Schema::Number),
+        ("level", Schema::Nullable(&Schema::Number)),
+    ])),
+    ("Zone", Schema::Object(&[
+        ("id", Schema::String),
+        ("name", Schema::String),
+        ("connected", Schema::Boolean),
+        ("volume_percent", Schema::Integer),
+        ("muted", Schema::Boolean),
+    ])),
+    ("Status", Schema::Object(&[
+        ("history", Schema::Object(&[
+            ("healthy", Schema::Boolean),
+            ("buffered_events", Schema::Integer),
+            ("dropped_events", Schema::Integer),
+        ])),
+        ("http", Schema::Object(&[
+            ("rate_limited_requests", Schema::Integer),
+            ("oversized_requests", Schema::Integer),
+        ])),
+    ])),
+    ("ScanStatus", Schema::Object(&[
+        ("stage", Schema::String),
+        ("files_discovered", Schema::Integer),
+        ("files_moved", Schema::Integer),
+        ("files_to_process_metadata", Schema::Integer),
+        ("files_processed_metadata", Schema::Integer),
+        ("files_to_fingerprint", Schema::Integer),
+        ("files_fingerprinted", Schema::Integer),
+        ("tracks_to_process_loudness", Schema::Integer),
+        ("tracks_processed_loudness", Schema::Integer),
+        ("albums_to_process_loudness", Schema::Integer),
+        ("albums_processed_loudness", Schema::Integer),
+        ("files_to_process_thumbnails", Schema::Integer),
+        ("files_processed_thumbnails", Schema::Integer),
+    ])),
+    ("MaintenanceReport", Schema::Object(&[
+        ("integrity_ok", Schema::Boolean),
+        ("integrity_errors", Schema::Array(&Schema::String)),
+        ("full_vacuum", Schema::Boolean),
+        ("size_before_bytes", Schema::Integer),
+        ("size_after_bytes", Schema::Integer),
+        ("duration_seconds", Schema::Number),
+    ])),
+    ("Rating", Schema::Object(&[
+        ("track_id", Schema::String),
+        ("rating", Schema::Integer),
+    ])),
+    ("SkipVote", Schema::Object(&[
+        ("queue_id", Schema::String),
+        ("votes", Schema::Integer),
+        ("votes_needed", Schema::Integer),
+        ("skipped", Schema::Boolean),
+    ])),
+    ("MetadataChanges", Schema::Object(&[
+        ("dry_run", Schema::Boolean),
+        ("files_written", Schema::Integer),
+        ("changes", Schema::Array(&Schema::Object(&[
+            ("track_id", Schema::String),
+            ("field", Schema::String),
+            ("old", Schema::Nullable(&Schema::String)),
+            ("new", Schema::Nullable(&Schema::String)),
+        ]))),
+    ])),
+    ("MetadataEdit", Schema::Object(&[
+        ("id", Schema::Integer),
+        ("edited_at", Schema::Format("date-time")),
+        ("track_id", Schema::String),
+        ("field", Schema::String),
+        ("old", Schema::Nullable(&Schema::String)),
+        ("new", Schema::Nullable(&Schema::String)),
+        ("wrote_tags", Schema::Boolean),
+        ("user", Schema::Nullable(&Schema::String)),
+    ])),
+    ("ArtistAlias", Schema::Object(&[
+        ("id", Schema::String),
+        ("name", Schema::String),
+        ("canonical_id", Schema::String),
+        ("canonical_name", Schema::Nullable(&Schema::String)),
+        ("created_at", Schema::Format("date-time")),
+    ])),
+    ("AcoustidProposal", Schema::Object(&[
+        ("file_id", Schema::Integer),
+        ("filename", Schema::String),
+        ("acoustid", Schema::String),
+        ("score", Schema::Number),
+        ("tags", Schema::Object(&[
+            ("title", Schema::String),
+            ("artist", Schema::String),
+            ("album", Schema::String),
+            ("albumartist", Schema::String),
+            ("originaldate", Schema::String),
+            ("musicbrainz_trackid", Schema::String),
+            ("musicbrainz_albumid", Schema::String),
+            ("musicbrainz_albumartistid", Schema::String),
+            ("tracknumber", Schema::Integer),
+            ("discnumber", Schema::Integer),
+        ])),
+        ("status", Schema::String),
+    ])),
+    ("GraphqlRequest", Schema::Object(&[
+        ("query", Schema::String),
+        ("variables", Schema::Nullable(&Schema::Object(&[]))),
+    ])),
+];
+
+const QUEUE: Body = Body::Json(Schema::Array(&Schema::Ref("QueueEntry")));
+
+/// All endpoints under `/api`, in the order of the API docs.
+pub const ENDPOINTS: &[Endpoint] = &[
+    Endpoint {
+        method: Post, path: "/api/login", summary: "Set the token cookie for the webinterface.",
+        params: &[], request: Body::Media("application/x-www-form-urlencoded"),
+        status: 303, response: Body::Empty,
+    },
+    Endpoint {
+        method: Post, path: "/api/logout", summary: "Clear the token cookie.",
+        params: &[], request: Body::Empty, status: 303, response: Body::Empty,
+    },
+    Endpoint {
+        method: Get, path: "/api/track/{track_id}.flac", summary: "Stream the track, optionally transcoded.",
+        params: &[TRACK_ID, FORMAT, BITRATE, PROFILE], request: Body::Empty,
+        status: 200, response: Body::Media("audio/flac"),
+    },
+    Endpoint {
+        method: Get, path: "/api/album/{album_id}", summary: "Album details and tracks.",
+        params: &[ALBUM_ID], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("Album")),
+    },
+    Endpoint {
+        method: Get, path: "/api/album/{album_id}/download", summary: "Zip archive of the album.",
+        params: &[ALBUM_ID, FORMAT, BITRATE, PROFILE], request: Body::Empty,
+        status: 200, response: Body::Media("application/zip"),
+    },
+    Endpoint {
+        method: Get, path: "/api/album/{album_id}/dynamics", summary: "Loudness and dynamics of the album and its tracks.",
+        params: &[ALBUM_ID], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("AlbumDynamics")),
+    },
+    Endpoint {
+        method: Get, path: "/api/albums", summary: "List albums.",
+        params: LISTING, request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("BriefAlbum"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/albums/recent", summary: "Albums most recently added to the library.",
+        params: LISTING, request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("BriefAlbum"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/albums/recently-played", summary: "Albums most recently played.",
+        params: LISTING, request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("BriefAlbum"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/albums/in-progress", summary: "Albums listened to partway, and where to continue.",
+        params: &[query("limit", Schema::Integer, "Number of albums, 10 by default.")],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("AlbumInProgress"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/albums/random", summary: "Randomly picked albums.",
+        params: &[
+            query("count", Schema::Integer, "Number of albums, 6 by default, at most 100."),
+            query("decade", Schema::Integer, "Only albums released in the decade that starts at this year."),
+            query("never_played", Schema::Boolean, "Only albums without listens."),
+            query("min_rating", Schema::Integer, "Only albums rated at least this."),
+        ],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("BriefAlbum"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/artists", summary: "List album artists.",
+        params: LISTING, request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("Artist"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/tracks", summary: "List tracks.",
+        params: LISTING, request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("Track"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/dynamics/{kind}", summary: "List albums or tracks by loudness and dynamics.",
+        params: DYNAMICS_LISTING, request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("DynamicsEntry"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/artist/{artist_id}", summary: "Artist details and albums.",
+        params: &[ARTIST_ID], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("ArtistDetails")),
+    },
+    Endpoint {
+        method: Get, path: "/api/artist/{artist_id}/similar", summary: "Artists played in the same sessions.",
+        params: &[ARTIST_ID, query("limit", Schema::Integer, "Number of artists, 10 by default.")],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("Artist"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/track/{track_id}/similar", summary: "Tracks played in the same sessions.",
+        params: &[TRACK_ID, query("limit", Schema::Integer, "Number of tracks, 10 by default.")],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("Track"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/cover/{album_id}", summary: "Cover art in original resolution.",
+        params: &[ALBUM_ID], request: Body::Empty,
+        status: 200, response: Body::Media("image/*"),
+    },
+    Endpoint {
+        method: Get, path: "/api/thumb/{album_id}", summary: "Downsampled cover art.",
+        params: &[ALBUM_ID, query("v", Schema::String, "Hash of the thumbnail, makes the response cacheable forever.")],
+        request: Body::Empty,
+        status: 200, response: Body::Media("image/jpeg"),
+    },
+    Endpoint {
+        method: Get, path: "/api/waveform/{track_id}", summary: "Waveform of the track.",
+        params: &[TRACK_ID], request: Body::Empty,
+        status: 200, response: Body::Media("image/svg+xml"),
+    },
+    Endpoint {
+        method: Get, path: "/api/search", summary: "Search artists, albums, and tracks.",
+        params: &[required_query("q", Schema::String, "The search query.")], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("SearchResults")),
+    },
+    Endpoint {
+        method: Get, path: "/api/typeahead", summary: "Search as you type, with a few results per category.",
+        params: &[
+            required_query("q", Schema::String, "The search query, the last word may be incomplete."),
+            query("limit", Schema::Integer, "Results per category, from 1 to 25, 5 by default."),
+        ],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("TypeaheadResults")),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats", summary: "Library statistics.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("Stats")),
+    },
+    Endpoint {
+        method: Get, path: "/api/sync", summary: "Library generation, changes when the album list changes.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("Sync")),
+    },
+    Endpoint {
+        method: Get, path: "/api/favorites", summary: "Loved tracks.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("Track"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/queue", summary: "The play queue, the first entry is playing.",
+        params: &[
+            query("offset", Schema::Integer, "Number of entries to skip."),
+            query("limit", Schema::Integer, "Maximum number of entries to return."),
+            PLAYER,
+        ],
+        request: Body::Empty, status: 200, response: QUEUE,
+    },
+    Endpoint {
+        method: Get, path: "/api/queue/m3u8", summary: "The play queue as M3U8 playlist.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Media("audio/x-mpegurl"),
+    },
+    Endpoint {
+        method: Get, path: "/api/queue/xspf", summary: "The play queue as XSPF playlist.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Media("application/xspf+xml"),
+    },
+    Endpoint {
+        method: Put, path: "/api/queue/{track_id}", summary: "Enqueue a track, returns its queue id.",
+        params: &[TRACK_ID, CLIENT, PLAYER], request: Body::Empty,
+        status: 201, response: Body::Json(Schema::String),
+    },
+    Endpoint {
+        method: Post, path: "/api/queue/tracks", summary: "Enqueue many tracks at once, returns their queue ids.",
+        params: &[CLIENT, SHUFFLE_NEW, PLAYER], request: Body::Json(Schema::Array(&Schema::String)),
+        status: 201, response: Body::Json(Schema::Array(&Schema::String)),
+    },
+    Endpoint {
+        method: Post, path: "/api/queue/library", summary: "Enqueue every track in the library, returns their queue ids.",
+        params: &[CLIENT, SHUFFLE_NEW, PLAYER], request: Body::Empty,
+        status: 201, response: Body::Json(Schema::Array(&Schema::String)),
+    },
+    Endpoint {
+        method: Delete, path: "/api/queue/{queue_id}", summary: "Remove an entry from the queue.",
+        params: &[path("queue_id", Schema::String, "Queue id of the entry."), PLAYER], request: Body::Empty,
+        status: 200, response: Body::Empty,
+    },
+    Endpoint {
+        method: Post, path: "/api/queue/{queue_id}/resume", summary: "Resume the entry at the saved position of its track.",
+        params: &[path("queue_id", Schema::String, "Queue id of the entry."), PLAYER], request: Body::Empty,
+        status: 200, response: Body::Empty,
+    },
+    Endpoint {
+        method: Post, path: "/api/queue/shuffle", summary: "Shuffle the queue.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: QUEUE,
+    },
+    Endpoint {
+        method: Post, path: "/api/queue/clear", summary: "Clear the queue, except for the current track.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: QUEUE,
+    },
+    Endpoint {
+        method: Post, path: "/api/queue/skip", summary: "Skip the current track.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: QUEUE,
+    },
+    Endpoint {
+        method: Post, path: "/api/queue/previous", summary: "Restart the current track, or play the previous one.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: QUEUE,
+    },
+    Endpoint {
+        method: Post, path: "/api/queue/handoff", summary: "Continue the queue on another player.",
+        params: &[
+            required_query("to", Schema::String, "Name of the player to continue on."),
+            PLAYER,
+        ],
+        request: Body::Empty, status: 200, response: QUEUE,
+    },
+    Endpoint {
+        method: Post, path: "/api/queue/vote-skip", summary: "Vote to skip the current track, in party mode.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("SkipVote")),
+    },
+    Endpoint {
+        method: Post, path: "/api/queue/love", summary: "Toggle the current track between loved and neutral.",
+        params: &[PLAYER], request: Body::Empty, status: 202, response: Body::Json(Schema::Ref("Rating")),
+    },
+    Endpoint {
+        method: Get, path: "/api/playlists", summary: "List playlists.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("Playlist"))),
+    },
+    Endpoint {
+        method: Post, path: "/api/playlists", summary: "Create a playlist, a smart one with a query.",
+        params: &[
+            required_query("name", Schema::String, "Name of the playlist."),
+            query("query", Schema::String, "Smart playlist query."),
+        ],
+        request: Body::Empty,
+        status: 201, response: Body::Json(Schema::Ref("Created")),
+    },
+    Endpoint {
+        method: Get, path: "/api/playlist/{playlist_id}", summary: "Playlist details and tracks.",
+        params: &[PLAYLIST_ID], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("PlaylistDetails")),
+    },
+    Endpoint {
+        method: Get, path: "/api/playlist/{playlist_id}/m3u8", summary: "The playlist as M3U8 file.",
+        params: &[PLAYLIST_ID], request: Body::Empty,
+        status: 200, response: Body::Media("audio/x-mpegurl"),
+    },
+    Endpoint {
+        method: Get, path: "/api/playlist/{playlist_id}/xspf", summary: "The playlist as XSPF file.",
+        params: &[PLAYLIST_ID], request: Body::Empty,
+        status: 200, response: Body::Media("application/xspf+xml"),
+    },
+    Endpoint {
+        method: Post, path: "/api/playlists/import", summary: "Create a playlist from an M3U file.",
+        params: &[required_query("name", Schema::String, "Name of the playlist.")],
+        request: Body::Media("audio/x-mpegurl"),
+        status: 201, response: Body::Json(Schema::Ref("PlaylistImport")),
+    },
+    Endpoint {
+        method: Put, path: "/api/playlist/{playlist_id}/name", summary: "Rename the playlist.",
+        params: &[PLAYLIST_ID, required_query("name", Schema::String, "New name.")],
+        request: Body::Empty, status: 204, response: Body::Empty,
+    },
+    Endpoint {
+        method: Put, path: "/api/playlist/{playlist_id}/query", summary: "Replace the query of a smart playlist.",
+        params: &[PLAYLIST_ID, required_query("query", Schema::String, "New query.")],
+        request: Body::Empty, status: 204, response: Body::Empty,
+    },
+    Endpoint {
+        method: Delete, path: "/api/playlist/{playlist_id}", summary: "Delete the playlist.",
+        params: &[PLAYLIST_ID], request: Body::Empty, status: 204, response: Body::Empty,
+    },
+    Endpoint {
+        method: Put, path: "/api/playlist/{playlist_id}/track/{track_id}", summary: "Append a track.",
+        params: &[PLAYLIST_ID, TRACK_ID], request: Body::Empty, status: 204, response: Body::Empty,
+    },
+    Endpoint {
+        method: Delete, path: "/api/playlist/{playlist_id}/entry/{entry_id}", summary: "Remove an entry.",
+        params: &[PLAYLIST_ID, path("entry_id", Schema::Integer, "Entry id.")],
+        request: Body::Empty, status: 204, response: Body::Empty,
+    },
+    Endpoint {
+        method: Post, path: "/api/playlist/{playlist_id}/move/{entry_id}", summary: "Move an entry.",
+        params: &[
+            PLAYLIST_ID,
+            path("entry_id", Schema::Integer, "Entry id."),
+            required_query("to", Schema::Integer, "The 0-based position to move to."),
+        ],
+        request: Body::Empty, status: 204, response: Body::Empty,
+    },
+    Endpoint {
+        method: Post, path: "/api/playlist/{playlist_id}/enqueue", summary: "Enqueue all tracks of the playlist.",
+        params: &[PLAYLIST_ID, CLIENT, PLAYER], request: Body::Empty, status: 200, response: QUEUE,
+    },
+    Endpoint {
+        method: Get, path: "/api/radio", summary: "List radio stations.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("RadioStation"))),
+    },
+    Endpoint {
+        method: Post, path: "/api/radio", summary: "Add a radio station.",
+        params: &[
+            required_query("name", Schema::String, "Name of the station."),
+            required_query("url", Schema::String, "Url of the stream."),
+        ],
+        request: Body::Empty,
+        status: 201, response: Body::Json(Schema::Ref("Created")),
+    },
+    Endpoint {
+        method: Delete, path: "/api/radio/{station_id}", summary: "Delete a radio station.",
+        params: &[path("station_id", Schema::Integer, "Station id.")],
+        request: Body::Empty, status: 200, response: Body::Empty,
+    },
+    Endpoint {
+        method: Post, path: "/api/radio/{station_id}/enqueue", summary: "Enqueue a radio station.",
+        params: &[path("station_id", Schema::Integer, "Station id."), CLIENT, PLAYER], request: Body::Empty,
+        status: 201, response: Body::Json(Schema::String),
+    },
+    Endpoint {
+        method: Get, path: "/api/listens", summary: "Listening history, newest first.",
+        params: &[
+            SINCE,
+            UNTIL,
+            query("artist", Schema::String, "Only listens of this album artist."),
+            query("album", Schema::String, "Only listens of this album."),
+            query("client", Schema::String, "Only listens of tracks enqueued by this client."),
+            query("limit", Schema::Integer, "Number of listens, from 1 to 1000, 100 by default."),
+            query("cursor", Schema::String, "The `next_cursor` of the previous page."),
+        ],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("ListenPage")),
+    },
+    Endpoint {
+        method: Get, path: "/api/listens/export", summary: "The full listening history as download.",
+        params: &[query("format", Schema::String, "`json` (the default) or `csv`.")],
+        request: Body::Empty,
+        status: 200, response: Body::Media("application/x-ndjson"),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/library", summary: "Counts, playtime, and audio formats of the library.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("LibraryStats")),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/artists", summary: "Most played album artists.",
+        params: &[SINCE, UNTIL, STATS_CLIENT, STATS_LIMIT], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("TopArtist"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/albums", summary: "Most played albums.",
+        params: &[SINCE, UNTIL, STATS_CLIENT, STATS_LIMIT], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("TopAlbum"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/tracks", summary: "Most played tracks.",
+        params: &[SINCE, UNTIL, STATS_CLIENT, STATS_LIMIT], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("TopTrack"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/totals", summary: "Number of listens, listening time, and skips.",
+        params: &[SINCE, UNTIL, STATS_CLIENT], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("ListenTotals")),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/skips", summary: "Most skipped tracks.",
+        params: &[SINCE, UNTIL, STATS_CLIENT, STATS_LIMIT], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("SkippedTrack"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/hours", summary: "Listens per day of the week and hour.",
+        params: &[SINCE, UNTIL, STATS_CLIENT], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Array(&Schema::Integer))),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/sessions", summary: "Listening session length and start time.",
+        params: &[SINCE, UNTIL, STATS_CLIENT], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("SessionStats")),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/discoveries", summary: "Tracks, albums, and artists listened to for the first time.",
+        params: &[SINCE, UNTIL, STATS_LIMIT], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("Discoveries")),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/discovery-rate", summary: "Listens and first listens per month.",
+        params: &[SINCE, UNTIL], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("DiscoveryMonth"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/on-this-day", summary: "Albums listened to on this day in earlier years.",
+        params: &[query("date", Schema::Format("date"), "The day, today by default.")],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("OnThisDay"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/rewind/{year}", summary: "Summary of a year of listening.",
+        params: &[path("year", Schema::Integer, "The year.")], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("Rewind")),
+    },
+    Endpoint {
+        method: Get, path: "/api/stats/charts/{kind}", summary: "Weekly or monthly chart, with movement since the previous one.",
+        params: &[
+            path("kind", Schema::String, "One of `artists`, `albums`, or `tracks`."),
+            query("period", Schema::String, "Either `week` or `month`, `week` by default."),
+            query("date", Schema::Format("date"), "A day in the period, today by default."),
+            query("limit", Schema::Integer, "The number of entries, from 1 to 100, 20 by default."),
+        ],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("Chart")),
+    },
+    Endpoint {
+        method: Get, path: "/api/players", summary: "The players, with their state.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("PlayerSummary"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/player", summary: "What is playing now.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("Player")),
+    },
+    Endpoint {
+        method: Post, path: "/api/player/pause", summary: "Pause playback.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("Player")),
+    },
+    Endpoint {
+        method: Post, path: "/api/player/play", summary: "Continue playback after a pause.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("Player")),
+    },
+    Endpoint {
+        method: Post, path: "/api/player/seek", summary: "Jump to a position in the current track.",
+        params: &[
+            required_query("position", Schema::Number, "Position in seconds from the start of the track."),
+            PLAYER,
+        ],
+        request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("Player")),
+    },
+    Endpoint {
+        method: Get, path: "/api/volume", summary: "The current volume.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("Volume")),
+    },
+    Endpoint {
+        method: Post, path: "/api/volume/up", summary: "Increase the volume by 1 dB.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("Volume")),
+    },
+    Endpoint {
+        method: Post, path: "/api/volume/down", summary: "Decrease the volume by 1 dB.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("Volume")),
+    },
+    Endpoint {
+        method: Get, path: "/api/cast", summary: "The cast device that Musium plays on.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("CastStatus")),
+    },
+    Endpoint {
+        method: Get, path: "/api/cast/devices", summary: "Discover cast devices.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("CastDevice"))),
+    },
+    Endpoint {
+        method: Put, path: "/api/cast/{device_id}", summary: "Play on a cast device.",
+        params: &[path("device_id", Schema::String, "Id of the device."), PLAYER], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("CastStatus")),
+    },
+    Endpoint {
+        method: Delete, path: "/api/cast", summary: "Play on the audio card again.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("CastStatus")),
+    },
+    Endpoint {
+        method: Put, path: "/api/browser", summary: "Play in the browser that makes the request.",
+        params: &[PLAYER], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("BrowserOutput")),
+    },
+    Endpoint {
+        method: Post, path: "/api/browser/{session}/progress", summary: "Report browser playback progress.",
+        params: &[
+            path("session", Schema::Integer, "Session returned when browser playback started."),
+            query("queue_id", Schema::String, "Queue id of the entry that plays, if any."),
+            query("position_ms", Schema::Integer, "Playback position in the entry."),
+            PLAYER,
+        ],
+        request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("BrowserOutput")),
+    },
+    Endpoint {
+        method: Post, path: "/api/browser/{session}/ended", summary: "Report that the browser played an entry to the end.",
+        params: &[
+            path("session", Schema::Integer, "Session returned when browser playback started."),
+            query("queue_id", Schema::String, "Queue id of the entry that ended."),
+            PLAYER,
+        ],
+        request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("BrowserOutput")),
+    },
+    Endpoint {
+        method: Post, path: "/api/browser/{session}/failed", summary: "Report that the browser failed to play an entry.",
+        params: &[
+            path("session", Schema::Integer, "Session returned when browser playback started."),
+            query("queue_id", Schema::String, "Queue id of the entry that failed."),
+            PLAYER,
+        ],
+        request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("BrowserOutput")),
+    },
+    Endpoint {
+        method: Delete, path: "/api/browser/{session}", summary: "Play on the audio card again.",
+        params: &[path("session", Schema::Integer, "Session returned when browser playback started."), PLAYER],
+        request: Body::Empty, status: 200, response: Body::Empty,
+    },
+    Endpoint {
+        method: Get, path: "/api/zones", summary: "Snapcast zones.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("Zone"))),
+    },
+    Endpoint {
+        method: Put, path: "/api/zone/{zone_id}/volume/{percent}", summary: "Set the volume of a zone.",
+        params: &[
+            path("zone_id", Schema::String, "Id of the zone."),
+            path("percent", Schema::Integer, "Volume from 0 to 100."),
+        ],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("Zone"))),
+    },
+    Endpoint {
+        method: Put, path: "/api/track/{track_id}/rating/{n}", summary: "Rate a track.",
+        params: &[TRACK_ID, path("n", Schema::Integer, "Rating from -1 to 2.")],
+        request: Body::Empty, status: 202, response: Body::Empty,
+    },
+    Endpoint {
+        method: Put, path: "/api/album/{album_id}/rating/{n}", summary: "Rate an album.",
+        params: &[ALBUM_ID, path("n", Schema::Integer, "Rating from -1 to 2.")],
+        request: Body::Empty, status: 202, response: Body::Empty,
+    },
+    Endpoint {
+        method: Put, path: "/api/artist/{artist_id}/rating/{n}", summary: "Rate an album artist.",
+        params: &[ARTIST_ID, path("n", Schema::Integer, "Rating from -1 to 2.")],
+        request: Body::Empty, status: 202, response: Body::Empty,
+    },
+    Endpoint {
+        method: Delete, path: "/api/track/{track_id}/rating", summary: "Reset the rating of a track.",
+        params: &[TRACK_ID], request: Body::Empty, status: 202, response: Body::Empty,
+    },
+    Endpoint {
+        method: Delete, path: "/api/album/{album_id}/rating", summary: "Reset the rating of an album.",
+        params: &[ALBUM_ID], request: Body::Empty, status: 202, response: Body::Empty,
+    },
+    Endpoint {
+        method: Delete, path: "/api/artist/{artist_id}/rating", summary: "Reset the rating of an album artist.",
+        params: &[ARTIST_ID], request: Body::Empty, status: 202, response: Body::Empty,
+    },
+    Endpoint {
+        method: Put, path: "/api/track/{track_id}/exclusion", summary: "Exclude a track from shuffling.",
+        params: &[TRACK_ID], request: Body::Empty, status: 204, response: Body::Empty,
+    },
+    Endpoint {
+        method: Put, path: "/api/album/{album_id}/exclusion", summary: "Exclude an album from shuffling.",
+        params: &[ALBUM_ID], request: Body::Empty, status: 204, response: Body::Empty,
+    },
+    Endpoint {
+        method: Delete, path: "/api/track/{track_id}/exclusion", summary: "Include a track in shuffling again.",
+        params: &[TRACK_ID], request: Body::Empty, status: 204, response: Body::Empty,
+    },
+    Endpoint {
+        method: Delete, path: "/api/album/{album_id}/exclusion", summary: "Include an album in shuffling again.",
+        params: &[ALBUM_ID], request: Body::Empty, status: 204, response: Body::Empty,
+    },
+    Endpoint {
+        method: Put, path: "/api/track/{track_id}/metadata", summary: "Edit the tags of a track.",
+        params: &[
+            TRACK_ID,
+            query("title", Schema::String, "New track title."),
+            query("artist", Schema::String, "New track artist."),
+            query("genre", Schema::String, "New genre, empty to remove it."),
+            METADATA_DRY_RUN,
+            METADATA_WRITE_TAGS,
+        ],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("MetadataChanges")),
+    },
+    Endpoint {
+        method: Put, path: "/api/album/{album_id}/metadata", summary: "Edit the tags of all tracks of an album.",
+        params: &[
+            ALBUM_ID,
+            query("title", Schema::String, "New album title."),
+            query("artist", Schema::String, "New album artist."),
+            query("year", Schema::String, "New original release date, YYYY, YYYY-MM, or YYYY-MM-DD."),
+            query("genre", Schema::String, "New genre, empty to remove it."),
+            METADATA_DRY_RUN,
+            METADATA_WRITE_TAGS,
+        ],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("MetadataChanges")),
+    },
+    Endpoint {
+        method: Get, path: "/api/metadata/edits", summary: "The most recent metadata edits, newest first.",
+        params: &[query("limit", Schema::Integer, "Number of edits, from 1 to 1000, 100 by default.")],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("MetadataEdit"))),
+    },
+    Endpoint {
+        method: Post, path: "/api/artist/{artist_id}/merge/{canonical_id}",
+        summary: "Merge an album artist into another, making it an alias.",
+        params: &[
+            ARTIST_ID,
+            path("canonical_id", Schema::String, "Id of the artist to merge into."),
+        ],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Object(&[
+            ("id", Schema::String),
+            ("canonical_id", Schema::String),
+        ])),
+    },
+    Endpoint {
+        method: Delete, path: "/api/artist/{artist_id}/merge", summary: "Undo the merge of an album artist.",
+        params: &[ARTIST_ID], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("ArtistAlias"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/artists/aliases", summary: "Album artists that were merged into another.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("ArtistAlias"))),
+    },
+    Endpoint {
+        method: Get, path: "/api/acoustid/proposals", summary: "Pending tag proposals for untagged files.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Array(&Schema::Ref("AcoustidProposal"))),
+    },
+    Endpoint {
+        method: Post, path: "/api/acoustid/{file_id}/accept", summary: "Apply the tags proposed for a file.",
+        params: &[
+            path("file_id", Schema::Integer, "Id of the file."),
+            METADATA_WRITE_TAGS,
+        ],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("AcoustidProposal")),
+    },
+    Endpoint {
+        method: Post, path: "/api/acoustid/{file_id}/reject", summary: "Discard the tags proposed for a file.",
+        params: &[path("file_id", Schema::Integer, "Id of the file.")],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("AcoustidProposal")),
+    },
+    Endpoint {
+        method: Get, path: "/api/status", summary: "Health of the background threads.",
+        params: &[], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("Status")),
+    },
+    Endpoint {
+        method: Get, path: "/api/events", summary: "Subscribe to state changes as server-sent events.",
+        params: &[], request: Body::Empty, status: 200, response: Body::Media("text/event-stream"),
+    },
+    Endpoint {
+        method: Get, path: "/api/scan/status", summary: "Status of the current scan.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Nullable(&Schema::Ref("ScanStatus"))),
+    },
+    Endpoint {
+        method: Post, path: "/api/scan/start", summary: "Start a scan of the library.",
+        params: &[], request: Body::Empty, status: 200, response: Body::Json(Schema::Ref("ScanStatus")),
+    },
+    Endpoint {
+        method: Get, path: "/api/backup", summary: "Snapshot of the database.",
+        params: &[], request: Body::Empty, status: 200, response: Body::Media("application/vnd.sqlite3"),
+    },
+    Endpoint {
+        method: Post, path: "/api/maintenance", summary: "Check and compact the database.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Ref("MaintenanceReport")),
+    },
+    Endpoint {
+        method: Post, path: "/api/config/reload", summary: "Reload the settings that can change at runtime.",
+        params: &[], request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Object(&[("changed", Schema::Array(&Schema::String))])),
+    },
+    Endpoint {
+        method: Post, path: "/api/graphql", summary: "Execute a GraphQL query, when enabled.",
+        params: &[], request: Body::Json(Schema::Ref("GraphqlRequest")),
+        status: 200, response: Body::Json(Schema::Object(&[])),
+    },
+    Endpoint {
+        method: Get, path: "/api/graphql", summary: "Execute a GraphQL query passed in the url, when enabled.",
+        params: &[
+            required_query("query", Schema::String, "The GraphQL query."),
+            query("variables", Schema::String, "Values of the variables as json object."),
+        ],
+        request: Body::Empty,
+        status: 200, response: Body::Json(Schema::Object(&[])),
+    },
+    Endpoint {
+        method: Get, path: "/api/openapi.json", summary: "This document.",
+        params: &[], request: Body::Empty, status: 200, response: Body::Json(Schema::Object(&[])),
+    },
+];
+
+impl Schema {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Schema::Boolean => json!({"type": "boolean"}),
+            Schema::Integer => json!({"type": "integer"}),
+            Schema::Number => json!({"type": "number"}),
+            Schema::String => json!({"type": "string"}),
+            Schema::Format(format) => json!({"type": "string", "format": format}),
+            // A $ref can't have siblings in OpenAPI 3.0, so we wrap it.
+            Schema::Nullable(Schema::Ref(name)) => json!({
+                "allOf": [Schema::Ref(name).to_json()],
+                "nullable": true,
+            }),
+            Schema::Nullable(inner) => {
+                let mut result = inner.to_json();
+                result["nullable"] = json!(true);
+                result
+            }
+            Schema::Array(items) => json!({"type": "array", "items": items.to_json()}),
+            Schema::Object(fields) => {
+                let mut properties = Map::new();
+                for (name, schema) in fields.iter() {
+                    properties.insert(name.to_string(), schema.to_json());
+                }
+                let required: Vec<&str> = fields.iter().map(|(name, _)| *name).collect();
+                let mut result = json!({"type": "object", "properties": properties});
+                // An empty object means one with unspecified contents.
+                if !required.is_empty() {
+                    result["required"] = json!(required);
+                }
+                result
+            }
+            Schema::OneOf(options) => {
+                let options: Vec<Value> = options.iter().map(|s| s.to_json()).collect();
+                json!({"oneOf": options})
+            }
+            Schema::Ref(name) => json!({"$ref": format!("#/components/schemas/{}", name)}),
+        }
+    }
+}
+
+fn body_to_json(body: &Body) -> Option<Value> {
+    match body {
+        Body::Empty => None,
+        Body::Json(schema) => Some(json!({"application/json": {"schema": schema.to_json()}})),
+        Body::Media(media_type) => {
+            let mut content = Map::new();
+            content.insert(media_type.to_string(), json!({}));
+            Some(Value::Object(content))
+        }
+    }
+}
+
+/// Return the scope that `auth::required_scope` demands for the endpoint.
+///
+/// The path parameters are left in as `{name}`, the scope only depends on the
+/// fixed parts of the path.
+pub fn endpoint_scope(endpoint: &Endpoint) -> Scope {
+    let mut parts = endpoint.path.trim_start_matches("/api/").split('/');
+    let name = parts.next().unwrap_or("");
+    let arg1 = parts.next();
+    let arg2 = parts.next();
+    auth::required_scope(&endpoint.method, name, arg1, arg2)
+}
+
+impl Endpoint {
+    fn to_json(&self) -> Value {
+        let params: Vec<Value> = self.params.iter().map(|param| json!({
+            "name": param.name,
+            "in": match param.location {
+                Location::Path => "path",
+                Location::Query => "query",
+            },
+            "required": param.required,
+            "description": param.description,
+            "schema": param.schema.to_json(),
+        })).collect();
+
+        let mut response = json!({"description": self.summary});
+        if let Some(content) = body_to_json(&self.response) {
+            response["content"] = content;
+        }
+        let mut responses = Map::new();
+        responses.insert(self.status.to_string(), response);
+
+        let scope = match endpoint_scope(self) {
+            Scope::Read => "read",
+            Scope::Queue => "queue",
+            Scope::Full => "full",
+        };
+
+        let mut operation = json!({
+            "summary": self.summary,
+            "parameters": params,
+            "responses": responses,
+            "x-musium-scope": scope,
+        });
+        if let Some(content) = body_to_json(&self.request) {
+            operation["requestBody"] = json!({"required": true, "content": content});
+        }
+        // Logging in is how the cookie gets set in the first place.
+        if self.path == "/api/login" {
+            operation["security"] = json!([]);
+        }
+        operation
+    }
+}
+
+/// Build the OpenAPI document for all endpoints.
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for endpoint in ENDPOINTS {
+        let method = endpoint.method.as_str().to_ascii_lowercase();
+        let path = paths.entry(endpoint.path.to_string()).or_insert_with(|| json!({}));
+        path[method] = endpoint.to_json();
+    }
+
+    let mut schemas = Map::new();
+    for (name, schema) in SCHEMAS {
+        schemas.insert(name.to_string(), schema.to_json());
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Musium",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Every operation requires a token with at least the scope in x-musium-scope.",
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": {"type": "http", "scheme": "bearer"},
+                "cookie": {"type": "apiKey", "in": "cookie", "name": "musium_token"},
+            },
+        },
+        "security": [{"bearer": []}, {"cookie": []}],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{endpoint_scope, Location, Schema, ENDPOINTS, SCHEMAS};
+    use crate::auth::Scope;
+    use std::collections::HashSet;
+
+    #[test]
+    fn endpoints_are_unique_and_declare_their_path_parameters() {
+        let mut seen = HashSet::new();
+        for endpoint in ENDPOINTS {
+            assert!(seen.insert((endpoint.method.as_str(), endpoint.path)), "{}", endpoint.path);
+
+            let in_path: HashSet<&str> = endpoint
+                .path
+                .split(['{', '}'])
+                .skip(1)
+                .step_by(2)
+                .collect();
+            let declared: HashSet<&str> = endpoint
+                .params
+                .iter()
+                .filter(|param| param.location == Location::Path)
+                .map(|param| param.name)
+                .collect();
+            assert_eq!(in_path, declared, "{}", endpoint.path);
+        }
+    }
+
+    #[test]
+    fn schema_references_exist() {
+        fn check(schema: &Schema, names: &HashSet<&str>) {
+            match schema {
+                Schema::Ref(name) => assert!(names.contains(name), "{}", name),
+                Schema::Nullable(inner) | Schema::Array(inner) => check(inner, names),
+                Schema::Object(fields) => fields.iter().for_each(|(_, s)| check(s, names)),
+                Schema::OneOf(options) => options.iter().for_each(|s| check(s, names)),
+                _ => {}
+            }
+        }
+        let names: HashSet<&str> = SCHEMAS.iter().map(|(name, _)| *name).collect();
+        for (_, schema) in SCHEMAS {
+            check(schema, &names);
+        }
+        for endpoint in ENDPOINTS {
+            for body in [&endpoint.request, &endpoint.response] {
+                if let super::Body::Json(schema) = body {
+                    check(schema, &names);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn endpoint_scope_matches_authorization() {
+        let scope_of = |method: &str, path: &str| {
+            let endpoint = ENDPOINTS
+                .iter()
+                .find(|e| e.method.as_str() == method && e.path == path)
+                .unwrap();
+            endpoint_scope(endpoint)
+        };
+        assert_eq!(scope_of("GET", "/api/album/{album_id}"), Scope::Read);
+        assert_eq!(scope_of("GET", "/api/backup"), Scope::Full);
+        assert_eq!(scope_of("PUT", "/api/queue/{track_id}"), Scope::Queue);
+        assert_eq!(scope_of("POST", "/api/queue/love"), Scope::Full);
+        assert_eq!(scope_of("POST", "/api/playlist/{playlist_id}/enqueue"), Scope::Queue);
+        assert_eq!(scope_of("POST", "/api/graphql"), Scope::Read);
+    }
+}
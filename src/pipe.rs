@@ -0,0 +1,86 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A pipe for streaming a response that a background thread produces.
+//!
+//! Tiny_http reads the response body from a `Read`, but exports and archives
+//! are most naturally produced by writing into a `Write`. A thread writes into
+//! the write end, and the response body reads from the read end.
+
+use std::io;
+use std::io::{Read, Write};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::mpsc;
+
+/// Write end of a pipe, that sends the data in chunks to the read end.
+pub struct PipeWriter {
+    sender: SyncSender<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+/// Send chunks of about this many bytes through the pipe.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        self.sender
+            .send(chunk)
+            // When the receiver is gone, the client closed the connection.
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Pipe reader is gone."))
+    }
+}
+
+/// Read end of a pipe, it returns end of file when the writer is gone.
+pub struct PipeReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: io::Cursor<Vec<u8>>,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.receiver.recv() {
+                Ok(chunk) => self.chunk = io::Cursor::new(chunk),
+                Err(..) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Create a pipe.
+///
+/// The writer does not flush on drop, call `flush` before dropping it.
+pub fn pipe() -> (PipeWriter, PipeReader) {
+    // Allow a few chunks in flight, so producing the data and writing it to
+    // the socket can overlap.
+    let (sender, receiver) = mpsc::sync_channel(4);
+    let writer = PipeWriter {
+        sender: sender,
+        buffer: Vec::with_capacity(CHUNK_SIZE),
+    };
+    let reader = PipeReader {
+        receiver: receiver,
+        chunk: io::Cursor::new(Vec::new()),
+    };
+    (writer, reader)
+}
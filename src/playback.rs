@@ -21,9 +21,12 @@ use libc;
 
 use crate::config::Config;
 use crate::exec_pre_post::QueueEvent;
+use crate::gain::GainStage;
 use crate::history::PlaybackEvent;
-use crate::player::{Format, Millibel, PlayerState};
+use crate::metrics;
+use crate::player::{Format, Millibel, OutputFormat, PlayerState};
 use crate::prim::Hertz;
+use crate::snapcast;
 
 type Result<T> = result::Result<T, alsa::Error>;
 
@@ -55,7 +58,7 @@ fn print_available_cards() -> Result<()> {
     Ok(())
 }
 
-fn open_device(card_name: &str) -> Result<(alsa::PCM, alsa::Mixer)> {
+fn find_card_index(card_name: &str) -> Result<i32> {
     let cards = alsa::card::Iter::new();
     let mut opt_card_index = None;
 
@@ -66,16 +69,18 @@ fn open_device(card_name: &str) -> Result<(alsa::PCM, alsa::Mixer)> {
         }
     }
 
-    let card_index = match opt_card_index {
-        Some(i) => i,
+    match opt_card_index {
+        Some(i) => Ok(i),
         None => {
             println!("Could not find a card with name '{}'.", card_name);
             println!("Valid options:\n");
             print_available_cards()?;
             std::process::exit(1);
         }
-    };
+    }
+}
 
+fn open_pcm(card_index: i32, bit_perfect: bool) -> Result<alsa::PCM> {
     // Select the card by index (":{}") to get direct access to the hardware,
     // play back stereo on the front two speakers. Adding "plug:" in front makes
     // Alsa take care of conversions where needed. This is bad on the one hand,
@@ -85,23 +90,52 @@ fn open_device(card_name: &str) -> Result<(alsa::PCM, alsa::Mixer)> {
     // silence, and I don't feel like doing that right now. Even when selecting
     // "front" without "plug", the minimum number of channels is 4, even though
     // https://alsa-project.org/wiki/DeviceNames claims that for "front" we
-    // would get stereo.
-    let device = format!("plug:front:{}", card_index);
+    // would get stereo. In bit-perfect mode we do open "hw", and we only use
+    // it when it supports the format as-is, see `open_output`.
+    let device = match bit_perfect {
+        true => format!("hw:{}", card_index),
+        false => format!("plug:front:{}", card_index),
+    };
     let non_block = false;
-    let pcm = match alsa::PCM::new(&device, alsa::Direction::Playback, non_block) {
-        Ok(pcm) => pcm,
+    match alsa::PCM::new(&device, alsa::Direction::Playback, non_block) {
+        Ok(pcm) => Ok(pcm),
         Err(error) if error.errno() == alsa::nix::errno::Errno::EBUSY => {
-            println!("Could not open audio interface for exclusive access, it is already use.");
-            return Err(error);
+            error!("Could not open audio interface for exclusive access, it is already use.");
+            Err(error)
         }
-        Err(error) => return Err(error),
-    };
+        Err(error) => Err(error),
+    }
+}
 
+fn open_mixer(card_index: i32) -> Result<alsa::Mixer> {
     let device = format!("hw:{}", card_index);
     let non_block = false;
-    let mixer = alsa::Mixer::new(&device, non_block)?;
+    alsa::Mixer::new(&device, non_block)
+}
 
-    Ok((pcm, mixer))
+/// Check that the configured audio output exists, for the health check.
+///
+/// This does not open the device, because the playback thread holds it for
+/// exclusive access while it plays.
+pub fn check_audio_output(config: &Config) -> result::Result<(), &'static str> {
+    let card_name = match (&config.snapcast_fifo, &config.audio_device) {
+        (Some(fifo_path), _) => return match fifo_path.exists() {
+            true => Ok(()),
+            false => Err("The Snapcast fifo does not exist."),
+        },
+        (None, Some(card_name)) => card_name,
+        (None, None) => unreachable!("Config requires an audio device when Snapcast is not used."),
+    };
+    for res_card in alsa::card::Iter::new() {
+        let is_match = match res_card {
+            Ok(card) => card.get_name().map(|name| &name == card_name).unwrap_or(false),
+            Err(..) => false,
+        };
+        if is_match {
+            return Ok(());
+        }
+    }
+    Err("The audio device was not found.")
 }
 
 fn get_volume_control<'a>(mixer: &'a alsa::Mixer, name: &str) -> Option<alsa::mixer::Selem<'a>> {
@@ -112,8 +146,8 @@ fn get_volume_control<'a>(mixer: &'a alsa::Mixer, name: &str) -> Option<alsa::mi
     Some(selem)
 }
 
-fn set_format(pcm: &alsa::PCM, format: Format) -> Result<()> {
-    let sample_format = match format.bits_per_sample {
+fn get_sample_format(format: Format) -> alsa::pcm::Format {
+    match format.bits_per_sample {
         16 => alsa::pcm::Format::S16LE,
         // Note the "3" in the format here: this means that every sample is 3
         // bytes. The regular S24LE format uses 4 bytes per sample, with the
@@ -123,7 +157,25 @@ fn set_format(pcm: &alsa::PCM, format: Format) -> Result<()> {
         // They could still occur here if the index is outdated, but that is not
         // something that deserves special error handling, just crash it.
         n  => panic!("Unsupported: {} bits per sample. Please re-index.", n),
+    }
+}
+
+/// Return whether the device can play the format without any conversion.
+fn supports_format(pcm: &alsa::PCM, format: Format) -> bool {
+    let hwp = match alsa::pcm::HwParams::any(pcm) {
+        Ok(hwp) => hwp,
+        Err(..) => return false,
     };
+    // These only restrict the configuration space, we don't apply it.
+    hwp.set_channels(2).is_ok()
+        && hwp.set_format(get_sample_format(format)).is_ok()
+        && hwp.set_access(alsa::pcm::Access::MMapInterleaved).is_ok()
+        && hwp.set_rate(format.sample_rate.0, alsa::ValueOr::Nearest).is_ok()
+        && hwp.get_rate().ok() == Some(format.sample_rate.0)
+}
+
+fn set_format(pcm: &alsa::PCM, format: Format) -> Result<()> {
+    let sample_format = get_sample_format(format);
 
     {
         let hwp = alsa::pcm::HwParams::any(pcm)?;
@@ -158,6 +210,40 @@ fn set_format(pcm: &alsa::PCM, format: Format) -> Result<()> {
     Ok(())
 }
 
+/// Open the PCM of the card and set it to the format.
+///
+/// In bit-perfect mode we open the hardware device directly, but when it can't
+/// play the format as-is, we fall back to the "plug" device, which converts.
+fn open_output(card_index: i32, format: Format, bit_perfect: bool) -> Result<(alsa::PCM, OutputFormat)> {
+    if bit_perfect {
+        let pcm = open_pcm(card_index, true)?;
+        if supports_format(&pcm, format) {
+            set_format(&pcm, format)?;
+            let output_format = OutputFormat {
+                format: format,
+                bit_perfect: true,
+            };
+            return Ok((pcm, output_format));
+        }
+        warn!(
+            "The audio device does not support {} bits at {} without conversion, \
+            playing through the plug device.",
+            format.bits_per_sample, format.sample_rate,
+        );
+        // The hardware device is exclusive, release it before we open the
+        // plug device on top of it.
+        mem::drop(pcm);
+    }
+
+    let pcm = open_pcm(card_index, false)?;
+    set_format(&pcm, format)?;
+    let output_format = OutputFormat {
+        format: format,
+        bit_perfect: false,
+    };
+    Ok((pcm, output_format))
+}
+
 enum WriteResult {
     /// We performed a state transition, but did not write; try again.
     Continue,
@@ -182,6 +268,7 @@ fn write_samples(
     pcm: &alsa::PCM,
     current_format: Format,
     io: &mut alsa::pcm::IO<u8>,
+    gain_stage: &mut Option<GainStage>,
     player: &mut PlayerState,
 ) -> Result<WriteResult> {
     use alsa::pcm::State;
@@ -196,7 +283,8 @@ fn write_samples(
     let n_available = match pcm.avail_update() {
         Ok(n) => n,
         Err(err) => {
-            println!("DEBUG: write_samples was in error, the error was {:?}.", err);
+            warn!("Audio device underrun, recovering: {:?}", err);
+            metrics::count_audio_underrun();
             // Previously we used try_recover here, but all it does is call
             // prepare, which we would do anyway below. See [1].
             // [1]: https://git.alsa-project.org/?p=alsa-lib.git;a=blob;f=src/pcm/pcm.c;
@@ -206,6 +294,7 @@ fn write_samples(
     } as usize;
 
     if n_available > 0 {
+        let volume = player.target_volume_full_scale();
         n_consumed = match player.peek_mut() {
             Some(ref block) if current_format != block.format() => {
                 // Next block has a different sample rate or bit depth, finish
@@ -216,23 +305,48 @@ fn write_samples(
             }
             Some(block) => {
                 let num_channels = 2;
-                let samples_written = num_channels * io.mmap(n_available, |dst| {
+                let frames_written = io.mmap(n_available, |dst| {
                     let src = block.slice();
                     let n = dst.len().min(src.len());
-                    dst[..n].copy_from_slice(&src[..n]);
+                    match (gain_stage.as_mut(), volume) {
+                        // With digital volume, we apply the gain here. The
+                        // limiter keeps its own lookahead across calls.
+                        (Some(stage), Some(volume)) => stage.process(current_format, volume, &src[..n], &mut dst[..n]),
+                        _ => dst[..n].copy_from_slice(&src[..n]),
+                    }
                     // We have to return the number of frames (count independent
                     // of the number of channels), but we have bytes.
                     n / (num_channels * current_format.bits_per_sample as usize / 8)
-                // TODO: This can apparently cause Error("snd_pcm_mmap_commit", Sys(EPIPE).
-                // How to handle it?
-                })?;
-                samples_written
+                });
+                match frames_written {
+                    Ok(n) => num_channels * n,
+                    // The commit fails with EPIPE when the device ran dry while
+                    // we were writing. Nothing was consumed, so we recover the
+                    // device, and write the same samples again in the next
+                    // iteration.
+                    Err(err) => {
+                        warn!("Audio device underrun while writing, recovering: {:?}", err);
+                        metrics::count_audio_underrun();
+                        pcm.try_recover(err, true)?;
+                        0
+                    }
+                }
+            }
+            None => {
+                // The device is playing, there are tracks in the queue, but
+                // none of it is decoded, so the decoder fell behind.
+                if pcm.state() == State::Running {
+                    player.report_starved();
+                }
+                0
             }
-            None => 0,
         };
 
         if n_consumed > 0 {
             player.consume(n_consumed);
+            if let Some(stage) = gain_stage {
+                player.set_gain_status(stage.status());
+            }
         } else if player.is_queue_empty() {
             // The queue is empty, play what is still there, then stop.
             pcm.drain()?;
@@ -327,13 +441,13 @@ fn ensure_buffers_full(
     device: &alsa::PCM,
     format: Format,
     io: &mut alsa::pcm::IO<u8>,
+    gain_stage: &mut Option<GainStage>,
     player: &mut PlayerState,
 ) -> FillResult {
     loop {
-        match write_samples(device, format, io, player) {
+        match write_samples(device, format, io, gain_stage, player) {
             Err(err) => {
-                println!("Error while writing samples: {:?}", err);
-                println!("Resuming ...");
+                error!("Error while writing samples, resuming: {:?}", err);
                 continue
             }
             Ok(WriteResult::Continue) => continue,
@@ -352,76 +466,135 @@ fn ensure_buffers_full(
 fn play_queue(
     card_name: &str,
     volume_name: &str,
+    bit_perfect: bool,
+    digital_volume: bool,
     state_mutex: &Mutex<PlayerState>,
     decode_thread: &Thread,
 ) {
-    let (device, mixer) = open_device(card_name).expect("TODO: Failed to open device.");
-    let vc = get_volume_control(&mixer, volume_name).expect("TODO: Failed to get volume control.");
-    let mut fds = device.get().expect("TODO: Failed to get fds from device.");
+    let card_index = find_card_index(card_name).expect("TODO: Failed to find card.");
+    let mixer = open_mixer(card_index).expect("TODO: Failed to open mixer.");
+
+    // In bit-perfect mode we don't touch the volume control, the volume is up
+    // to the amplifier. This also means no loudness normalization. With
+    // digital volume, we leave the volume control alone too, and apply the
+    // volume to the samples.
+    let vc = match bit_perfect || digital_volume {
+        true => None,
+        false => Some(
+            get_volume_control(&mixer, volume_name).expect("TODO: Failed to get volume control.")
+        ),
+    };
+    let mut gain_stage = match digital_volume {
+        true => Some(GainStage::new()),
+        false => None,
+    };
 
     let mut volume = None;
     let mut format = Format {
         sample_rate: Hertz(44_100),
         bits_per_sample: 16,
     };
-    if let Err(err) = set_format(&device, format) {
-        panic!("Failed to set format for device {} to format {:?}: {:?}",
-            card_name, format, err,
-        );
-    }
-
-    // There is also "direct mode" that works with mmaps, but it is not
-    // supported by the kernel on ARM, and I want to run this on a Raspberry Pi,
-    // so for simplicity I will use the mode that is supported everywhere.
-    let mut io = device.io_bytes();
 
+    // On a format change we reopen the device, because in bit-perfect mode,
+    // whether we can use the hardware device depends on the format.
     loop {
-        let (result, target_volume, needs_decode) = {
-            let mut state = state_mutex.lock().unwrap();
-            let result = ensure_buffers_full(
-                &device,
-                format,
-                &mut io,
-                &mut state
-            );
-
-            (
-                result,
-                state.target_volume_full_scale(),
-                state.needs_decode(),
-            )
+        let (device, output_format) = match open_output(card_index, format, bit_perfect) {
+            Ok(result) => result,
+            Err(err) => panic!(
+                "Failed to open device {} with format {:?}: {:?}",
+                card_name, format, err,
+            ),
         };
-
-        if needs_decode {
-            decode_thread.unpark();
-        }
-
-        if volume != target_volume {
-            if let Some(Millibel(v)) = target_volume {
-                println!("Changing volume to {:.1} dB", v as f32 * 0.01);
-                vc.set_playback_db_all(alsa::mixer::MilliBel(v as i64), alsa::Round::Floor)
-                    .expect("Failed to set volume. TODO: Make fn return Alsa error?");
-                volume = target_volume;
+        state_mutex.lock().unwrap().set_output_format(Some(output_format));
+        let mut fds = device.get().expect("TODO: Failed to get fds from device.");
+
+        // There is also "direct mode" that works with mmaps, but it is not
+        // supported by the kernel on ARM, and I want to run this on a Raspberry Pi,
+        // so for simplicity I will use the mode that is supported everywhere.
+        let mut io = device.io_bytes();
+
+        let new_format = loop {
+            let (result, target_volume, needs_decode) = {
+                let mut state = state_mutex.lock().unwrap();
+
+                // When we start casting, the cast device takes over playback, and
+                // we release the audio card.
+                if state.is_casting() {
+                    return;
+                }
+
+                // When we are shutting down, stop after the fade out. Discard
+                // what is still in the device buffer, and leave the mixer at the
+                // volume it had before the fade, for whatever plays next.
+                if state.is_faded_out() {
+                    if let Err(err) = device.drop() {
+                        warn!("Failed to stop the audio device: {:?}", err);
+                    }
+                    if let (Some(vc), Some(Millibel(v))) = (&vc, state.volume_full_scale()) {
+                        let _ = vc.set_playback_db_all(alsa::mixer::MilliBel(v as i64), alsa::Round::Floor);
+                    }
+                    return;
+                }
+
+                // When the user pauses, we release the audio card like when the
+                // queue ends. What is still in the device buffer did not play,
+                // so the current track continues before it on resume.
+                if state.is_paused() {
+                    let unplayed_frames = device.delay().unwrap_or(0).max(0) as u64;
+                    if let Err(err) = device.drop() {
+                        warn!("Failed to stop the audio device: {:?}", err);
+                    }
+                    state.hold_for_pause(unplayed_frames * 1000 / format.sample_rate.0 as u64);
+                    decode_thread.unpark();
+                    return;
+                }
+
+                let result = ensure_buffers_full(
+                    &device,
+                    format,
+                    &mut io,
+                    &mut gain_stage,
+                    &mut state
+                );
+
+                (
+                    result,
+                    state.target_volume_full_scale(),
+                    state.needs_decode(),
+                )
+            };
+
+            if needs_decode {
+                decode_thread.unpark();
             }
-        }
 
-        match result {
-            FillResult::QueueEmpty => return,
-            FillResult::Yield => {
-                // If we are in this loop, then we are already playing, so for
-                // the sake of being responsive to songs starting, we don't have
-                // to have a low timeout here. But for volume changes we might.
-                let max_sleep_ms = 15;
-                alsa::poll::poll(&mut fds, max_sleep_ms).expect("TODO: Failed to wait for events.");
+            if let Some(vc) = &vc {
+                if volume != target_volume {
+                    if let Some(Millibel(v)) = target_volume {
+                        debug!("Changing volume to {:.1} dB", v as f32 * 0.01);
+                        vc.set_playback_db_all(alsa::mixer::MilliBel(v as i64), alsa::Round::Floor)
+                            .expect("Failed to set volume. TODO: Make fn return Alsa error?");
+                        volume = target_volume;
+                    }
+                }
             }
-            FillResult::ChangeFormat(new_format) => {
-                mem::drop(io);
-                set_format(&device, new_format).expect("TODO: Failed to set format.");
-                println!("Changed format to {:?}", new_format);
-                format = new_format;
-                io = device.io_bytes();
+
+            match result {
+                FillResult::QueueEmpty => return,
+                FillResult::Yield => {
+                    // If we are in this loop, then we are already playing, so for
+                    // the sake of being responsive to songs starting, we don't have
+                    // to have a low timeout here. But for volume changes we might.
+                    let max_sleep_ms = 15;
+                    alsa::poll::poll(&mut fds, max_sleep_ms).expect("TODO: Failed to wait for events.");
+                }
+                FillResult::ChangeFormat(new_format) => break new_format,
             }
-        }
+        };
+
+        mem::drop(io);
+        debug!("Changing format to {:?}", new_format);
+        format = new_format;
     }
 }
 
@@ -444,11 +617,13 @@ fn try_increase_thread_priority() {
     // Pipewire and Pulseaudio use by default.
     let new_nice = unsafe { libc::nice(-11) };
     if new_nice == -1 {
-        println!("Playback thread likely failed to set niceness.");
-        println!("Consider using setrlimit, granting CAP_SYS_NICE, \
-                  or setting LimitNICE=-11:-11 when using systemd.");
+        warn!(
+            "Playback thread likely failed to set niceness. \
+            Consider using setrlimit, granting CAP_SYS_NICE, \
+            or setting LimitNICE=-11:-11 when using systemd."
+        );
     } else {
-        println!("Playback thread new niceness: {}", new_nice);
+        info!("Playback thread new niceness: {}", new_nice);
     }
 
     let sched_policy = libc::SCHED_RR;
@@ -465,14 +640,14 @@ fn try_increase_thread_priority() {
         )
     };
     match sched_retval {
-        0 => println!(
+        0 => info!(
             "Playback thread is now SCHED_RR (high priority)."
         ),
-        libc::EPERM => println!(
+        libc::EPERM => warn!(
             "Playback thread was not allowed to change its scheduling \
              policy to SCHED_RR. Consider granting CAP_SYS_NICE."
         ),
-        _ => println!(
+        _ => warn!(
             "An unknown error occurred when setting playback thread \
              scheduling policy: {}.",
              sched_retval,
@@ -506,7 +681,11 @@ pub fn main(
     loop {
         let has_audio = {
             let state = state_mutex.lock().unwrap();
-            !state.is_queue_empty()
+            state.has_audio_card()
+                && !state.is_queue_empty()
+                && !state.is_casting()
+                && !state.is_faded_out()
+                && !state.is_paused()
         };
         if has_audio {
             // We are resuming playback now from an idle state. Let the exec
@@ -518,30 +697,52 @@ pub fn main(
                 .expect("Exec thread runs indefinitely, sending does not fail.");
 
             // If a pre-playback program is configured, we should wait for it to
-            // finish, but only up to 10 seconds. The exec thread will set
-            // is_running to false and wake us with the condvar.
-            if config.exec_pre_playback_path.is_some() {
+            // finish, but only up to 10 seconds, plus the pre-playback delay.
+            // The exec thread will set is_running to false and wake us with
+            // the condvar.
+            if config.exec_pre_playback_path.is_some() || config.pre_playback_delay_ms > 0 {
+                let timeout = Duration::from_secs(10) + Duration::from_millis(config.pre_playback_delay_ms);
                 let _ignored_guard = is_running_condvar.1.wait_timeout_while(
                     is_running_condvar.0.lock().unwrap(),
-                    Duration::from_secs(10),
+                    timeout,
                     |&mut is_running| is_running,
                 ).unwrap();
             }
 
-            println!("Starting playback ...");
-            play_queue(
-                &config.audio_device,
-                &config.audio_volume_control,
-                &state_mutex,
-                decode_thread,
-            );
-            println!("Playback done, sleeping ...");
+            info!("Starting playback ...");
+            match (&config.snapcast_fifo, &config.audio_device, &config.audio_volume_control) {
+                (Some(fifo_path), _, _) => snapcast::play_queue(
+                    fifo_path,
+                    config.snapcast_sample_rate,
+                    &state_mutex,
+                    decode_thread,
+                ),
+                (None, Some(card_name), Some(volume_name)) => play_queue(
+                    card_name,
+                    volume_name,
+                    config.bit_perfect,
+                    config.digital_volume,
+                    &state_mutex,
+                    decode_thread,
+                ),
+                _ => unreachable!("Config requires an audio device when Snapcast is not used."),
+            }
+            info!("Playback done, sleeping ...");
+            let is_paused = {
+                let mut state = state_mutex.lock().unwrap();
+                state.set_output_format(None);
+                state.set_gain_status(None);
+                state.is_paused() && !state.is_queue_empty()
+            };
 
             // Inform the history thread that the queue ended, so it can
-            // checkpoint the WAL.
-            history_events
-                .send(PlaybackEvent::QueueEnded)
-                .expect("History thread runs indefinitely, sending does not fail.");
+            // checkpoint the WAL. After a pause the queue did not end, and the
+            // listen of the current track is still pending.
+            if !is_paused {
+                history_events
+                    .send(PlaybackEvent::QueueEnded)
+                    .expect("History thread runs indefinitely, sending does not fail.");
+            }
 
             // Signal the exec thread to start the idle timeout and execute the
             // post-idle program afterwards.
@@ -7,32 +7,70 @@
 
 //! Ensures that the right samples are queued for playback.
 
+use std::collections::VecDeque;
 use std::fmt;
-use std::fs;
+use std::io::Read;
 use std::mem;
 use std::sync::mpsc::SyncSender;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use claxon;
 use claxon::metadata::StreamInfo;
 
+use crate::browser_output;
+use crate::cast;
 use crate::config::Config;
-use crate::error::Error;
-use crate::exec_pre_post;
+use crate::database as db;
+use crate::events::{Event, EventBus};
+use crate::exec_pre_post::{self, QueueEvent};
 use crate::filter::StateVariableFilter;
-use crate::history::PlaybackEvent;
+use crate::gain::GainStatus;
+use crate::history::{HistoryStatus, PlaybackEvent};
 use crate::history;
-use crate::mvar::Var;
+use crate::limits::Client;
+use crate::metrics;
+use crate::mvar::{MVar, Var};
 use crate::playback;
 use crate::prim::Hertz;
+use crate::radio;
+use crate::read_timeout;
+use crate::resample::{self, Resampler, SampleRatePolicy};
+use crate::scrobble::{Credentials, ScrobbleEvent};
+use crate::scrobble;
+use crate::webhook;
 use crate::shuffle;
-use crate::user_data::{Rating, UserData};
-use crate::{AlbumId, Lufs, MetaIndex, MemoryMetaIndex, TrackId};
+use crate::status_hook;
+use crate::user_data::{Rating, UserDataSet};
+use crate::{AlbumId, ArtistId, Lufs, MetaIndex, MemoryMetaIndex, TrackId};
 
-type FlacReader = claxon::FlacReader<fs::File>;
+type FlacReader = claxon::FlacReader<read_timeout::TimeoutReader>;
+
+/// How long a read from a track may take before we give up on the track.
+///
+/// When the library is on a network mount and the mount stalls, reads block
+/// indefinitely. After this timeout, we skip the track instead.
+const READ_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How long it takes to fade out playback when the server shuts down.
+const FADE_OUT_DURATION: Duration = Duration::from_secs(2);
+
+/// How much softer playback is at the end of the fade out, in millibel.
+const FADE_OUT_MILLIBEL: u64 = 6000;
+
+/// How many played queue entries we remember for going back.
+const HISTORY_LEN: usize = 50;
+
+/// Going back after the current track played this long restarts it.
+///
+/// Before that, going back plays the previous track, like in other players.
+const PREVIOUS_RESTART_MS: u64 = 3_000;
+
+/// The name of the player that plays on the audio card, see `config.players`.
+pub const DEFAULT_PLAYER: &str = "default";
 
 /// A unique identifier for a queued track.
 ///
@@ -55,6 +93,23 @@ impl QueueId {
     }
 }
 
+/// What a queue entry plays, a track from the library, or a radio station.
+#[derive(Clone, Debug)]
+pub enum Source {
+    Track(TrackId),
+    Radio(Arc<radio::Station>),
+}
+
+impl Source {
+    /// Return the track id, if this is a track from the library.
+    pub fn track_id(&self) -> Option<TrackId> {
+        match self {
+            Source::Track(track_id) => Some(*track_id),
+            Source::Radio(..) => None,
+        }
+    }
+}
+
 /// A dimensionless number expressed on a logarithmic scale.
 ///
 /// The representation is millibel, or in other words, this is a decibel as
@@ -85,6 +140,17 @@ impl Default for Format {
     }
 }
 
+/// The format that the audio card plays at, as negotiated with the hardware.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct OutputFormat {
+    pub format: Format,
+
+    /// Whether the samples go to the hardware unchanged, see `bit_perfect` in
+    /// the configuration docs. This is false when the hardware does not
+    /// support the format of the track, then Alsa converts it.
+    pub bit_perfect: bool,
+}
+
 /// A block of interleaved samples, queued for playback.
 pub struct Block {
     /// The samples, interleaved left, right.
@@ -147,20 +213,50 @@ impl Block {
     }
 }
 
-/// Holds high-pass filters, one for each channel.
+/// Processes decoded samples before they go into a block.
+///
+/// This holds high-pass filters, one for each channel, and converts the samples
+/// to the output format of the sample rate policy.
 struct Filters {
     /// One filter per channel.
     filters: [StateVariableFilter; 2],
 
+    /// In bit-perfect mode, samples pass through unchanged.
+    bypass: bool,
+
     /// The current sample format.
     format: Format,
 
     /// The cutoff frequency.
     cutoff: Hertz,
+
+    sample_rate_policy: SampleRatePolicy,
+    fixed_sample_rate: Hertz,
+
+    /// For the album policy, the album that we decode, and its highest format.
+    album_format: Option<(AlbumId, Option<Format>)>,
+
+    /// The format of the samples that we produce.
+    output_format: Format,
+
+    /// Converts from the current format to the output format.
+    resampler: Resampler,
+}
+
+/// Append a sample in little endian with the given number of bits.
+#[inline]
+fn write_sample(out: &mut Vec<u8>, x: i32, bits_per_sample: u32) {
+    let bytes = x.to_le_bytes();
+    out.extend_from_slice(&bytes[..bits_per_sample as usize / 8]);
 }
 
 impl Filters {
-    pub fn new(cutoff: Hertz) -> Self {
+    pub fn new(
+        cutoff: Hertz,
+        bypass: bool,
+        sample_rate_policy: SampleRatePolicy,
+        fixed_sample_rate: Hertz,
+    ) -> Self {
         // A q of sqrt(2) leads to the flattest possible pass-band.
         let q = 2.0_f64.sqrt();
 
@@ -174,18 +270,50 @@ impl Filters {
         );
         Self {
             filters: [filter.clone(), filter],
+            bypass: bypass,
             format: Format {
                 sample_rate: Hertz(44_100),
                 bits_per_sample: 16,
             },
             cutoff,
+            sample_rate_policy: sample_rate_policy,
+            fixed_sample_rate: fixed_sample_rate,
+            album_format: None,
+            output_format: Format::default(),
+            resampler: Resampler::new(sample_rate, sample_rate),
+        }
+    }
+
+    /// Set the album of the track that we are about to decode, `None` for radio.
+    ///
+    /// For the album policy, this looks up the highest format of the album,
+    /// unless it is the album of the previous track.
+    pub fn set_album(&mut self, index: &dyn MetaIndex, album_id: Option<AlbumId>) {
+        if self.sample_rate_policy != SampleRatePolicy::Album {
+            return;
         }
+        self.album_format = match (album_id, self.album_format) {
+            (None, _) => None,
+            (Some(id), Some((prev_id, format))) if id == prev_id => Some((id, format)),
+            (Some(id), _) => Some((id, resample::get_album_format(index, id))),
+        };
     }
 
     /// Update the filter parameters to work for a new format, if the format changed.
     ///
     /// Also clear the state if the format changed.
     pub fn set_format(&mut self, format: &Format) {
+        let output_format = resample::get_output_format(
+            self.sample_rate_policy,
+            self.fixed_sample_rate,
+            *format,
+            self.album_format.and_then(|(_, f)| f),
+        );
+        if *format != self.format || output_format != self.output_format {
+            self.resampler = Resampler::new(format.sample_rate, output_format.sample_rate);
+        }
+        self.output_format = output_format;
+
         // If the bit depth changes, we should reset the filter state, otherwise
         // we get incorrect past feeding into our output. We could instead
         // scale the state variables to keep the output continuous, but as a
@@ -206,14 +334,62 @@ impl Filters {
         self.format = *format;
     }
 
+    /// Return the format of the samples that `process` produces.
+    pub fn output_format(&self) -> Format {
+        self.output_format
+    }
+
     /// Feed one sample for both channels, return high-passed result.
     #[inline]
     pub fn tick(&mut self, left: i32, right: i32) -> (i32, i32) {
+        if self.bypass {
+            return (left, right);
+        }
         (
             self.filters[0].tick_highpass_clip(left, self.format.bits_per_sample),
             self.filters[1].tick_highpass_clip(right, self.format.bits_per_sample),
         )
     }
+
+    /// Filter the samples, append them to `out` in the output format.
+    pub fn process<I: Iterator<Item = (i32, i32)>>(&mut self, samples: I, out: &mut Vec<u8>) {
+        let in_bits = self.format.bits_per_sample;
+        let out_bits = self.output_format.bits_per_sample;
+
+        // Without resampling, we only need to pad the bit depth, which is
+        // lossless, so with the default policy, the samples are unchanged.
+        if self.format.sample_rate == self.output_format.sample_rate {
+            let shift = out_bits - in_bits;
+            for (l, r) in samples {
+                let (l, r) = self.tick(l, r);
+                write_sample(out, l << shift, out_bits);
+                write_sample(out, r << shift, out_bits);
+            }
+            return;
+        }
+
+        // The resampler works on floats in the range [-1.0, 1.0).
+        let scale_in = 1.0 / (1_i64 << (in_bits - 1)) as f32;
+        let scale_out = (1_i64 << (out_bits - 1)) as f32;
+        let frames: Vec<[f32; 2]> = samples
+            .map(|(l, r)| {
+                let (l, r) = self.tick(l, r);
+                [l as f32 * scale_in, r as f32 * scale_in]
+            })
+            .collect();
+        self.resampler.process(frames.into_iter(), |frame| {
+            for x in frame.iter() {
+                let sample = (x * scale_out).round().clamp(-scale_out, scale_out - 1.0) as i32;
+                write_sample(out, sample, out_bits);
+            }
+        });
+    }
+}
+
+/// A decoder that can be resumed.
+pub enum Reader {
+    Flac(FlacReader),
+    Radio(radio::RadioReader),
 }
 
 /// The decoding state of a queued track.
@@ -221,7 +397,9 @@ pub enum Decode {
     /// No decode started yet.
     NotStarted,
     /// Track partially decoded, can be resumed.
-    Partial(FlacReader),
+    ///
+    /// For radio stations, decoding never completes, unless the stream ends.
+    Partial(Reader),
     /// Decode in progress, the decoder thread has the reader for now.
     Running,
     /// Decoding is complete.
@@ -232,8 +410,14 @@ pub struct QueuedTrack {
     /// A unique identifier for this particular queuement of the track.
     pub queue_id: QueueId,
 
-    /// Track id of the track to be played.
-    pub track_id: TrackId,
+    /// The track or radio station to be played.
+    pub source: Source,
+
+    /// Name of the client that enqueued the track, if it provided one.
+    pub client: Option<String>,
+
+    /// The user who enqueued the track, `None` for the default user.
+    pub user: Option<String>,
 
     /// Perceived track loudness in Loudness Units Full Scale.
     track_loudness: Lufs,
@@ -241,8 +425,9 @@ pub struct QueuedTrack {
     /// Perceived album loudness in Loudness Units Full Scale.
     album_loudness: Lufs,
 
-    /// Decoded blocks of audio data.
-    blocks: Vec<Block>,
+    /// Decoded blocks of audio data, a ring buffer that the decoder appends
+    /// to, and that playback consumes from the front.
+    blocks: VecDeque<Block>,
 
     /// Number of samples already sent to the audio card.
     ///
@@ -257,29 +442,79 @@ pub struct QueuedTrack {
 
     /// Decoder for this track.
     decode: Decode,
+
+    /// Whether playback of this track started, on the audio card or cast device.
+    started: bool,
+
+    /// The playback position reported by the cast device, when casting.
+    cast_position_ms: Option<u64>,
+
+    /// For radio stations, the title that the station announced for what is playing now.
+    stream_title: Option<String>,
+
+    /// For radio stations, the titles that the decoder saw, but that did not
+    /// play yet, with the sample count at which they start.
+    pending_titles: VecDeque<(u64, Option<String>)>,
+
+    /// Number of samples decoded so far.
+    samples_decoded: u64,
+
+    /// Position to start playback at, for a track restored from the saved queue.
+    ///
+    /// We can't seek in the file, so we decode from the start, and drop the
+    /// samples before this position.
+    resume_at_ms: u64,
+
+    /// Why decoding failed, if it did, for example because a read timed out.
+    ///
+    /// We play what we decoded before the error, and then skip the rest.
+    error: Option<String>,
 }
 
 impl QueuedTrack {
     pub fn new(
         queue_id: QueueId,
-        track_id: TrackId,
+        source: Source,
+        client: Option<String>,
+        user: Option<String>,
         track_loudness: Lufs,
         album_loudness: Lufs,
     ) -> QueuedTrack {
         QueuedTrack {
             queue_id: queue_id,
-            track_id: track_id,
+            source: source,
+            client: client,
+            user: user,
             track_loudness: track_loudness,
             album_loudness: album_loudness,
-            blocks: Vec::new(),
+            blocks: VecDeque::new(),
             samples_played: 0,
             sample_rate: None,
             decode: Decode::NotStarted,
+            started: false,
+            cast_position_ms: None,
+            stream_title: None,
+            pending_titles: VecDeque::new(),
+            samples_decoded: 0,
+            resume_at_ms: 0,
+            error: None,
         }
     }
 
-    pub fn album_id(&self) -> AlbumId {
-        self.track_id.album_id()
+    /// Return the album of the track, or `None` for radio stations.
+    pub fn album_id(&self) -> Option<AlbumId> {
+        self.source.track_id().map(|t| t.album_id())
+    }
+
+    /// Clear the decoded audio, to start decoding from the beginning.
+    fn reset_decode(&mut self) {
+        // A running decode result will simply be dropped once the decode
+        // thread finishes the task.
+        self.decode = Decode::NotStarted;
+        self.blocks.clear();
+        self.pending_titles.clear();
+        self.samples_decoded = 0;
+        self.error = None;
     }
 
     /// Return the duration of the unconsumed samples in milliseconds.
@@ -289,6 +524,9 @@ impl QueuedTrack {
 
     /// Return the duration of the consumed samples in milliseconds.
     pub fn position_ms(&self) -> u64 {
+        if let Some(position_ms) = self.cast_position_ms {
+            return position_ms;
+        }
         match self.sample_rate {
             // Multiply by 1000 to go from seconds to milliseconds, divide by 2
             // because there are 2 channels. We need to work with u64 here, because
@@ -307,15 +545,75 @@ impl QueuedTrack {
     pub fn size_bytes(&self) -> usize {
         self.blocks.iter().map(|b| b.size_bytes()).sum()
     }
+
+    /// Return how many samples to drop before `resume_at_ms`.
+    fn samples_to_skip(&self, sample_rate: Hertz) -> u64 {
+        // See `position_ms` for the factor 500. Round down to a whole frame,
+        // so left and right don't swap.
+        let resume_at_samples = self.resume_at_ms * sample_rate.0 as u64 / 500;
+        resume_at_samples.saturating_sub(self.samples_played) & !1
+    }
+
+    /// Return a snapshot of the playback state of this track.
+    fn snapshot(&self) -> TrackSnapshot {
+        TrackSnapshot {
+            queue_id: self.queue_id,
+            source: self.source.clone(),
+            stream_title: self.stream_title.clone(),
+            // After a pause or a jump, decoding starts over, and the samples
+            // before `resume_at_ms` only count as played once the decoder
+            // dropped them, but we are at that position already.
+            position_ms: match self.started {
+                true => self.position_ms().max(self.resume_at_ms),
+                false => self.position_ms(),
+            },
+            buffered_ms: self.duration_ms(),
+            is_buffering: matches!(self.decode, Decode::Running),
+            resume_at_ms: self.resume_at_ms,
+            resume_offer_ms: None,
+        }
+    }
+}
+
+/// A queue entry that played, so we can play it again when going back.
+struct PlayedEntry {
+    source: Source,
+    client: Option<String>,
+    user: Option<String>,
+    track_loudness: Lufs,
+    album_loudness: Lufs,
+}
+
+impl PlayedEntry {
+    fn new(queued_track: &QueuedTrack) -> PlayedEntry {
+        PlayedEntry {
+            source: queued_track.source.clone(),
+            client: queued_track.client.clone(),
+            user: queued_track.user.clone(),
+            track_loudness: queued_track.track_loudness,
+            album_loudness: queued_track.album_loudness,
+        }
+    }
+
+    fn into_queued_track(self, queue_id: QueueId) -> QueuedTrack {
+        QueuedTrack::new(
+            queue_id,
+            self.source,
+            self.client,
+            self.user,
+            self.track_loudness,
+            self.album_loudness,
+        )
+    }
 }
 
 /// A task to be executed by the decoder thread.
 enum DecodeTask {
     /// Continue decoding with the given reader.
-    Continue(QueueId, FlacReader),
+    Continue(QueueId, Reader),
 
-    /// Start decoding a new track.
-    Start(QueueId, TrackId),
+    /// Start decoding a new track or radio stream.
+    Start(QueueId, Source),
 }
 
 /// The result of a decode task.
@@ -325,39 +623,28 @@ enum DecodeTask {
 pub struct DecodeResult {
     queue_id: QueueId,
     block: Block,
-    reader: Option<FlacReader>,
+    reader: Option<Reader>,
+
+    /// For radio stations, the title that the station announced last.
+    stream_title: Option<String>,
+
+    /// For files, why decoding stopped before the end of the file, if it did.
+    error: Option<String>,
 }
 
-/// Open a file, and `fadvise` that we will read it entirely.
+/// The number of bytes to decode from a radio stream at a time.
 ///
-/// In the decoder we might sometimes open a file, then decode it partially
-/// (because our buffer is full), and then resume decoding only a long time
-/// later. Possibly a disk will spin down. Tell the kernel that we are going to
-/// want the entire thing, so we can later finish decoding without having to
-/// spin the disk up again (for this particular file).
+/// Radio streams arrive in real time, so unlike for files, we can't decode
+/// ahead. Instead, we hand out the samples every second, so playback can
+/// start quickly, and the decoder keeps up with the stream.
+const RADIO_CHUNK_BYTES: usize = 44_100 * 4;
+
+/// Open a file for decoding, with reads that time out after `READ_TIMEOUT`.
 ///
-/// A related scenario that sometimes happens is that the disk is spun down, but
-/// a part of a file is still cached in the page cache. Then if we play it,
-/// playback starts immediately, but then gets stuck half-way because the
-/// decoder is waiting for the rest of the file. There too, it can help to tell
-/// the kernel early that we will need the entire thing. (Though probably it’s
-/// still too late, because decoding is fast, so we would have hit the blocking
-/// IO anyway within a few seconds.)
-fn open_with_readahead(fname: &str) -> crate::error::Result<FlacReader> {
-    use std::os::unix::io::AsRawFd;
-    let file = fs::File::open(fname)?;
-    let fd = file.as_raw_fd();
-    let offset = 0;
-    let len = file.metadata()?.len() as libc::off64_t;
-    unsafe {
-        let _ = libc::posix_fadvise64(fd, offset, len, libc::POSIX_FADV_SEQUENTIAL);
-        let _ = libc::posix_fadvise64(fd, offset, len, libc::POSIX_FADV_WILLNEED);
-    }
-    let reader = match FlacReader::new(file) {
-        Ok(r) => r,
-        Err(err) => return Err(Error::FormatError(fname.into(), err)),
-    };
-    Ok(reader)
+/// The file is read on a background thread, see `read_timeout` for why, and
+/// for the read-ahead hints that we give the kernel.
+fn open_flac(fname: &str) -> claxon::Result<FlacReader> {
+    FlacReader::new(read_timeout::open(fname, READ_TIMEOUT))
 }
 
 impl DecodeTask {
@@ -369,15 +656,29 @@ impl DecodeTask {
         stop_after_bytes: usize,
     ) -> DecodeResult {
         match self {
-            DecodeTask::Continue(qid, reader) => {
+            DecodeTask::Continue(qid, Reader::Flac(reader)) => {
                 DecodeTask::decode(qid, reader, filters, stop_after_bytes)
             }
-            DecodeTask::Start(qid, track_id) => {
+            DecodeTask::Continue(qid, Reader::Radio(reader)) => {
+                DecodeTask::decode_radio(qid, reader, filters)
+            }
+            DecodeTask::Start(qid, Source::Track(track_id)) => {
                 DecodeTask::start(index, qid, track_id, filters, stop_after_bytes)
             }
+            DecodeTask::Start(qid, Source::Radio(station)) => {
+                DecodeTask::start_radio(index, qid, &station, filters)
+            }
         }
     }
 
+    /// Return whether this task decodes a radio stream.
+    fn is_radio(&self) -> bool {
+        matches!(
+            self,
+            DecodeTask::Continue(_, Reader::Radio(..)) | DecodeTask::Start(_, Source::Radio(..))
+        )
+    }
+
     fn start(
         index: &dyn MetaIndex,
         queue_id: QueueId,
@@ -391,16 +692,19 @@ impl DecodeTask {
         };
         let fname = index.get_filename(track.filename);
         // TODO: Add a proper way to do logging.
-        println!("Opening {:?} for decode.", fname);
+        debug!("Opening {:?} for decode.", fname);
+        filters.set_album(index, Some(track_id.album_id()));
 
-        let reader = match open_with_readahead(fname) {
+        let reader = match open_flac(fname) {
             Ok(r) => r,
             Err(err) => {
-                println!("Error in {:?}: {:?}", fname, err);
+                error!("Error in {:?}: {:?}", fname, err);
                 return DecodeResult {
                     queue_id: queue_id,
                     block: Block::new(Format::default(), Vec::new()),
                     reader: None,
+                    stream_title: None,
+                    error: Some(err.to_string()),
                 };
             }
         };
@@ -408,6 +712,78 @@ impl DecodeTask {
         DecodeTask::decode(queue_id, reader, filters, stop_after_bytes)
     }
 
+    fn start_radio(
+        index: &dyn MetaIndex,
+        queue_id: QueueId,
+        station: &radio::Station,
+        filters: &mut Filters,
+    ) -> DecodeResult {
+        debug!("Opening {} for decode.", station.url);
+        filters.set_album(index, None);
+
+        let reader = match radio::open_stream(station) {
+            Ok(r) => r,
+            Err(err) => {
+                error!("Error in {}: {:?}", station.url, err);
+                return DecodeResult {
+                    queue_id: queue_id,
+                    block: Block::new(Format::default(), Vec::new()),
+                    reader: None,
+                    stream_title: None,
+                    // Our error has no `Display` yet, the debug format is
+                    // still more useful than nothing.
+                    error: Some(format!("{:?}", err)),
+                };
+            }
+        };
+
+        DecodeTask::decode_radio(queue_id, reader, filters)
+    }
+
+    /// Decode one chunk of a radio stream, block until it arrived.
+    fn decode_radio(queue_id: QueueId, mut reader: radio::RadioReader, filters: &mut Filters) -> DecodeResult {
+        let format = Format {
+            sample_rate: radio::SAMPLE_RATE,
+            bits_per_sample: 16,
+        };
+        filters.set_format(&format);
+
+        let mut out = vec![0_u8; RADIO_CHUNK_BYTES];
+        let mut len = 0;
+        let mut is_done = false;
+        while len < out.len() {
+            match reader.read(&mut out[len..]) {
+                Ok(0) => {
+                    is_done = true;
+                    break;
+                }
+                Ok(n) => len += n,
+                Err(err) => {
+                    error!("Error while decoding radio stream: {:?}", err);
+                    is_done = true;
+                    break;
+                }
+            }
+        }
+
+        // Drop a trailing partial sample, if the stream ended halfway.
+        out.truncate(len - len % 4);
+        let samples = out.chunks_exact(4).map(|frame| (
+            i16::from_le_bytes([frame[0], frame[1]]) as i32,
+            i16::from_le_bytes([frame[2], frame[3]]) as i32,
+        ));
+        let mut block_bytes = Vec::with_capacity(out.len());
+        filters.process(samples, &mut block_bytes);
+
+        DecodeResult {
+            queue_id: queue_id,
+            block: Block::new(filters.output_format(), block_bytes),
+            stream_title: reader.stream_title(),
+            reader: if is_done { None } else { Some(Reader::Radio(reader)) },
+            error: None,
+        }
+    }
+
     fn decode(queue_id: QueueId, reader: FlacReader, filters: &mut Filters, stop_after_bytes: usize) -> DecodeResult {
         let streaminfo = reader.streaminfo();
         match streaminfo.bits_per_sample {
@@ -438,6 +814,7 @@ impl DecodeTask {
         let max_samples_per_frame = streaminfo.max_block_size as usize * 2;
         let max_bytes_per_frame = max_samples_per_frame * 2;
         let mut is_done = false;
+        let mut error = None;
         let mut out = Vec::with_capacity(stop_after_bytes + max_bytes_per_frame);
 
         {
@@ -453,21 +830,20 @@ impl DecodeTask {
                         break
                     }
                     Ok(Some(b)) => b,
-                    Err(err) => panic!("TODO: Handle decode error: {:?}", err),
+                    Err(err) => {
+                        // This can be a corrupt file, but also a read error,
+                        // for example when a network mount is gone for longer
+                        // than the decode-ahead buffer covers. We can't resume
+                        // the reader after an error, so play what we have, and
+                        // continue with the next track.
+                        error!("Error while decoding, skipping the rest of the track: {:?}", err);
+                        error = Some(err.to_string());
+                        is_done = true;
+                        break
+                    }
                 };
 
-                for (l, r) in frame.stereo_samples() {
-                    let (l, r) = filters.tick(l, r);
-
-                    // Encode the samples in little endian.
-                    let bytes: [u8; 4] = [
-                        ((l >> 0) & 0xff) as u8,
-                        ((l >> 8) & 0xff) as u8,
-                        ((r >> 0) & 0xff) as u8,
-                        ((r >> 8) & 0xff) as u8,
-                    ];
-                    out.extend_from_slice(&bytes[..]);
-                }
+                filters.process(frame.stereo_samples(), &mut out);
 
                 buffer = frame.into_buffer();
             }
@@ -475,11 +851,13 @@ impl DecodeTask {
 
         out.shrink_to_fit();
 
-        let block = Block::new(format, out);
+        let block = Block::new(filters.output_format(), out);
         DecodeResult {
             queue_id: queue_id,
             block: block,
-            reader: if is_done { None } else { Some(reader) }
+            reader: if is_done { None } else { Some(Reader::Flac(reader)) },
+            stream_title: None,
+            error: error,
         }
     }
 
@@ -504,6 +882,7 @@ impl DecodeTask {
         let max_samples_per_frame = streaminfo.max_block_size as usize * 2;
         let max_bytes_per_frame = max_samples_per_frame * 3;
         let mut is_done = false;
+        let mut error = None;
         let mut out = Vec::with_capacity(stop_after_bytes + max_bytes_per_frame);
 
         {
@@ -519,37 +898,65 @@ impl DecodeTask {
                         break
                     }
                     Ok(Some(b)) => b,
-                    Err(err) => panic!("TODO: Handle decode error: {:?}", err),
+                    Err(err) => {
+                        // This can be a corrupt file, but also a read error,
+                        // for example when a network mount is gone for longer
+                        // than the decode-ahead buffer covers. We can't resume
+                        // the reader after an error, so play what we have, and
+                        // continue with the next track.
+                        error!("Error while decoding, skipping the rest of the track: {:?}", err);
+                        error = Some(err.to_string());
+                        is_done = true;
+                        break
+                    }
                 };
 
-                for (l, r) in frame.stereo_samples() {
-                    let (l, r) = filters.tick(l, r);
-
-                    // Encode the samples in little endian.
-                    let bytes: [u8; 6] = [
-                        ((l >>  0) & 0xff) as u8,
-                        ((l >>  8) & 0xff) as u8,
-                        ((l >> 16) & 0xff) as u8,
-                        ((r >>  0) & 0xff) as u8,
-                        ((r >>  8) & 0xff) as u8,
-                        ((r >> 16) & 0xff) as u8,
-                    ];
-                    out.extend_from_slice(&bytes[..]);
-                }
+                filters.process(frame.stereo_samples(), &mut out);
 
                 buffer = frame.into_buffer();
             }
         }
 
-        let block = Block::new(format, out);
+        let block = Block::new(filters.output_format(), out);
         DecodeResult {
             queue_id: queue_id,
             block: block,
-            reader: if is_done { None } else { Some(reader) }
+            reader: if is_done { None } else { Some(Reader::Flac(reader)) },
+            stream_title: None,
+            error: error,
         }
     }
 }
 
+/// The outcome of a vote to skip the current track, see [`PlayerState::vote_skip`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SkipVote {
+    /// The entry that the vote was for.
+    pub queue_id: QueueId,
+
+    /// The number of different clients that voted to skip the entry.
+    pub votes: u32,
+
+    /// The number of votes it takes to skip.
+    pub votes_needed: u32,
+
+    /// Whether this vote tipped the balance, and the entry got skipped.
+    pub skipped: bool,
+}
+
+/// Where the queue plays, when it does not play on the audio card.
+#[derive(Clone, Debug)]
+pub enum RemoteOutput {
+    /// A cast device, see `cast.rs`.
+    Cast(cast::Device),
+
+    /// A browser tab of the web app, see `browser_output.rs`.
+    Browser {
+        /// When the browser last reported its progress.
+        reported_at: Instant,
+    },
+}
+
 pub struct PlayerState {
     /// Counter that assigns queue ids.
     next_unused_id: QueueId,
@@ -593,7 +1000,15 @@ pub struct PlayerState {
     /// for every index j > i, the queued track at index j has no decoded
     /// blocks either. In other words, all decoded blocks are at the beginning
     /// of the queue.
-    queue: Vec<QueuedTrack>,
+    ///
+    /// The queue can hold an entire library, tens of thousands of tracks, so
+    /// it is a `VecDeque`, to make removing the current track O(1).
+    queue: VecDeque<QueuedTrack>,
+
+    /// The entries that played before the current one, the most recent one last.
+    ///
+    /// This holds at most `HISTORY_LEN` entries, it is only for going back.
+    history: VecDeque<PlayedEntry>,
 
     /// Sender for playback events.
     ///
@@ -602,19 +1017,78 @@ pub struct PlayerState {
 
     /// Random number generator used for shuffling.
     rng: shuffle::Prng,
+
+    /// Whether the player can play on the audio card, only the default player can.
+    has_audio_card: bool,
+
+    /// The cast device or browser that we play on instead of the audio card, if any.
+    remote_output: Option<RemoteOutput>,
+
+    /// Counter that changes whenever a remote output starts or stops.
+    ///
+    /// The cast thread and the browser watchdog exit when the session they
+    /// were started for is over.
+    cast_session: u64,
+
+    /// In party mode, the clients that voted to skip the current entry.
+    ///
+    /// When a different entry is current, the votes are for an entry that is
+    /// gone, and they no longer count.
+    skip_votes: Option<(QueueId, Vec<Client>)>,
+
+    /// When the server shuts down, the moment at which we started fading out.
+    ///
+    /// Playback gets softer over `FADE_OUT_DURATION`, and then it stops.
+    fade_out_started_at: Option<Instant>,
+
+    /// Whether the user paused playback.
+    ///
+    /// While paused, the playback thread releases the output, and the current
+    /// entry continues from where it was when playback resumes.
+    is_paused: bool,
+
+    /// When less than this much audio is decoded, the decoder should wake up.
+    decode_ahead_ms: u64,
+
+    /// A burst of decoding stops when the decoded blocks take this many bytes.
+    decode_buffer_bytes: usize,
+
+    /// Whether playback ran out of decoded audio, while there is more to play.
+    ///
+    /// We count a decode underrun when this goes from false to true, and it
+    /// goes back to false when playback continues.
+    is_starved: bool,
+
+    /// The format of the audio card, while the playback thread has it open.
+    output_format: Option<OutputFormat>,
+
+    /// With digital volume, the gain that the playback thread applies.
+    gain_status: Option<GainStatus>,
 }
 
 
 impl PlayerState {
-    pub fn new(events: SyncSender<PlaybackEvent>) -> PlayerState {
+    pub fn new(events: SyncSender<PlaybackEvent>, config: &Config) -> PlayerState {
         PlayerState {
             next_unused_id: QueueId(0),
             volume: Millibel(-1500),
             target_loudness: Lufs::new(-2300),
             current_track_loudness: None,
-            queue: Vec::new(),
+            queue: VecDeque::new(),
+            history: VecDeque::new(),
             events: events,
             rng: shuffle::Prng::new(),
+            has_audio_card: true,
+            remote_output: None,
+            cast_session: 0,
+            skip_votes: None,
+            fade_out_started_at: None,
+            is_paused: false,
+            decode_ahead_ms: config.decode_ahead_seconds * 1000,
+            decode_buffer_bytes: config.decode_buffer_mb as usize * 1_000_000,
+            is_starved: false,
+            output_format: None,
+            gain_status: None,
         }
     }
 
@@ -661,8 +1135,8 @@ impl PlayerState {
 
     /// Return the next block to play from, if any.
     pub fn peek_mut(&mut self) -> Option<&mut Block> {
-        match self.queue.first_mut() {
-            Some(qt) => qt.blocks.first_mut(),
+        match self.queue.front_mut() {
+            Some(qt) => qt.blocks.front_mut(),
             None => None,
         }
     }
@@ -672,11 +1146,274 @@ impl PlayerState {
         self.queue.is_empty()
     }
 
+    /// Return whether playback faded out completely, because we are shutting down.
+    pub fn is_faded_out(&self) -> bool {
+        match self.fade_out_started_at {
+            Some(t) => t.elapsed() >= FADE_OUT_DURATION,
+            None => false,
+        }
+    }
+
+    pub fn has_audio_card(&self) -> bool {
+        self.has_audio_card
+    }
+
+    /// Return whether the user paused playback, see [`PlayerState::pause`].
+    pub fn is_paused(&self) -> bool {
+        self.is_paused
+    }
+
+    /// Pause playback, return false if there is nothing to pause.
+    ///
+    /// A cast device or browser plays on its own, we can't pause it from here.
+    pub fn pause(&mut self) -> bool {
+        if self.is_paused || self.queue.is_empty() || self.is_casting() {
+            return false;
+        }
+        self.is_paused = true;
+        self.events.send(PlaybackEvent::Paused).expect("Failed to send pause event to history thread.");
+        true
+    }
+
+    /// Continue playback after a pause, return false if we were not paused.
+    pub fn play(&mut self) -> bool {
+        if !self.is_paused {
+            return false;
+        }
+        self.is_paused = false;
+        self.events.send(PlaybackEvent::Resumed).expect("Failed to send resume event to history thread.");
+        true
+    }
+
+    /// Drop the decoded audio, because the playback thread released the output for a pause.
+    ///
+    /// The output still held `unplayed_ms` of audio that we counted as played,
+    /// the current track continues that much earlier, so nothing goes missing.
+    pub fn hold_for_pause(&mut self, unplayed_ms: u64) {
+        self.restart_at_position();
+        if let Some(queued_track) = self.queue.front_mut() {
+            queued_track.resume_at_ms = queued_track.resume_at_ms.saturating_sub(unplayed_ms);
+        }
+    }
+
+    /// Record the format of the audio card, `None` when playback closed it.
+    pub fn set_output_format(&mut self, output_format: Option<OutputFormat>) {
+        self.output_format = output_format;
+    }
+
+    /// Record the gain that the playback thread applied last, with digital volume.
+    pub fn set_gain_status(&mut self, gain_status: Option<GainStatus>) {
+        self.gain_status = gain_status;
+    }
+
+    /// Return whether we play on a cast device or browser rather than the audio card.
+    pub fn is_casting(&self) -> bool {
+        self.remote_output.is_some()
+    }
+
+    pub fn cast_session(&self) -> u64 {
+        self.cast_session
+    }
+
+    /// Drop the decoded audio, and continue the current track at its position.
+    ///
+    /// This is for moving playback to another output, where the current track
+    /// continues from where it is now, rather than from the start. Radio
+    /// stations are live, they have no position to continue at.
+    fn restart_at_position(&mut self) {
+        if let Some(queued_track) = self.queue.front_mut() {
+            if let Source::Track(..) = queued_track.source {
+                queued_track.resume_at_ms = queued_track.position_ms().max(queued_track.resume_at_ms);
+            }
+        }
+        for queued_track in self.queue.iter_mut() {
+            queued_track.reset_decode();
+            queued_track.samples_played = 0;
+            queued_track.cast_position_ms = None;
+        }
+    }
+
+    /// Return the position that the current track starts at on a new output.
+    pub fn current_resume_at_ms(&self) -> u64 {
+        self.queue.front().map(|qt| qt.resume_at_ms).unwrap_or(0)
+    }
+
+    /// Play on the remote output from now on, return the new cast session.
+    ///
+    /// The remote output fetches the tracks itself, so we drop any decoded
+    /// audio. The current track continues at its position on the output.
+    fn start_casting(&mut self, output: RemoteOutput) -> u64 {
+        self.restart_at_position();
+        // The remote output starts playing right away, we can't pause it.
+        self.play();
+        self.remote_output = Some(output);
+        self.cast_session += 1;
+        self.cast_session
+    }
+
+    /// Play on the audio card again, the current track continues there.
+    pub fn stop_casting(&mut self) {
+        self.restart_at_position();
+        self.remote_output = None;
+        self.cast_session += 1;
+    }
+
+    /// Record that the browser of the session reported in.
+    ///
+    /// Returns false if the session is over, then the browser should stop.
+    pub fn touch_browser_output(&mut self, session: u64) -> bool {
+        match &mut self.remote_output {
+            Some(RemoteOutput::Browser { reported_at }) if self.cast_session == session => {
+                *reported_at = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Return how long ago the browser last reported in, if we play in a browser.
+    pub fn browser_output_silence(&self) -> Option<Duration> {
+        match &self.remote_output {
+            Some(RemoteOutput::Browser { reported_at }) => Some(reported_at.elapsed()),
+            _ => None,
+        }
+    }
+
+    /// Return the queue id and source of the entry at the front of the queue.
+    pub fn current_track(&self) -> Option<(QueueId, Source)> {
+        self.queue.front().map(|qt| (qt.queue_id, qt.source.clone()))
+    }
+
+    /// Send the event for the start of playback of the queued track.
+    fn send_started(&self, queued_track: &QueuedTrack) {
+        let event = match &queued_track.source {
+            Source::Track(track_id) => PlaybackEvent::Started(
+                queued_track.queue_id,
+                *track_id,
+                queued_track.client.clone(),
+                queued_track.user.clone(),
+            ),
+            Source::Radio(station) => PlaybackEvent::RadioStarted(
+                queued_track.queue_id,
+                station.clone(),
+                queued_track.stream_title.clone(),
+            ),
+        };
+        self.events.send(event).expect("Failed to send start event to history thread.");
+    }
+
+    /// Send the event for the end of playback of a track that did not complete.
+    fn send_skipped(&self, queued_track: &QueuedTrack) {
+        // If playback of the track did not start yet, then there is no listen
+        // that we skipped.
+        if !queued_track.started {
+            return;
+        }
+        let position_seconds = (queued_track.position_ms() / 1000) as u32;
+        let event = match &queued_track.source {
+            Source::Track(track_id) => {
+                PlaybackEvent::Skipped(queued_track.queue_id, *track_id, position_seconds)
+            }
+            // Radio is not something to skip, we just stop listening.
+            Source::Radio(..) => PlaybackEvent::RadioEnded(queued_track.queue_id),
+        };
+        self.events.send(event).expect("Failed to send skip event to history thread.");
+    }
+
+    /// Remember that the entry played, so we can go back to it.
+    fn push_history(&mut self, queued_track: &QueuedTrack) {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(PlayedEntry::new(queued_track));
+    }
+
+    /// Record the playback position that the cast device reported.
+    pub fn set_cast_position(&mut self, queue_id: QueueId, position_ms: u64) {
+        let queued_track = match self.queue.front_mut() {
+            Some(qt) if qt.queue_id == queue_id => qt,
+            _ => return,
+        };
+        queued_track.cast_position_ms = Some(position_ms);
+        if !queued_track.started {
+            queued_track.started = true;
+            self.send_started(&self.queue[0]);
+        }
+    }
+
+    /// Complete the current track after the cast device finished playing it.
+    pub fn complete_cast_track(&mut self, queue_id: QueueId) {
+        // The device may report the end of a track that we skipped already.
+        if matches!(self.queue.front(), Some(qt) if qt.queue_id == queue_id && qt.started) {
+            self.complete_current_track();
+        }
+    }
+
+    /// Remove the current track from the queue after playing it to the end.
+    fn complete_current_track(&mut self) {
+        let track = self.queue.pop_front().expect("Can only complete a track when there is one.");
+
+        if track.error.is_none() {
+            self.push_history(&track);
+        }
+
+        let event = match (&track.source, track.error) {
+            (Source::Track(track_id), Some(error)) => {
+                PlaybackEvent::Failed(track.queue_id, *track_id, error)
+            }
+            (Source::Track(track_id), None) => {
+                metrics::count_track_played();
+                let position_seconds = (track.position_ms() / 1000) as u32;
+                PlaybackEvent::Completed(track.queue_id, *track_id, position_seconds)
+            }
+            (Source::Radio(..), Some(error)) => PlaybackEvent::RadioFailed(track.queue_id, error),
+            // A radio stream only completes when the station ends it.
+            (Source::Radio(..), None) => PlaybackEvent::RadioEnded(track.queue_id),
+        };
+        self.events.send(event).expect("Failed to send completion event to history thread.");
+
+        let previous_album = track.album_id();
+        self.update_current_track_loudness(previous_album);
+        self.drop_failed_tracks();
+    }
+
+    /// Remove tracks at the front of the queue that failed without any audio.
+    ///
+    /// Playback only moves on to the next track after it consumed the last
+    /// sample of the current one, so a track that failed to open would block
+    /// the queue forever.
+    fn drop_failed_tracks(&mut self) {
+        let is_failed = match self.queue.front() {
+            Some(qt) => qt.error.is_some() && qt.blocks.is_empty() && matches!(qt.decode, Decode::Done),
+            None => false,
+        };
+        // Completing the track drops the failed tracks after it, if any.
+        if is_failed {
+            self.complete_current_track();
+        }
+    }
+
     /// Return the desired playback volume relative to full scale.
     ///
     /// This applies loudness normalization on top of the player target volume,
     /// to get the absolute playback volume.
     pub fn target_volume_full_scale(&self) -> Option<Millibel> {
+        let volume = self.volume_full_scale()?;
+
+        // While fading out, turn the volume down linearly in decibels.
+        let fade_millibel = match self.fade_out_started_at {
+            Some(t) => {
+                let elapsed_ms = t.elapsed().min(FADE_OUT_DURATION).as_millis() as u64;
+                elapsed_ms * FADE_OUT_MILLIBEL / FADE_OUT_DURATION.as_millis() as u64
+            }
+            None => 0,
+        };
+
+        Some(Millibel(volume.0 - fade_millibel as i16))
+    }
+
+    /// Return the playback volume relative to full scale, without the fade out.
+    pub fn volume_full_scale(&self) -> Option<Millibel> {
         let track_loudness = self.current_track_loudness?;
 
         let loudness_adjustment_millibel = self.target_loudness.0.get() - track_loudness.0.get();
@@ -690,7 +1427,7 @@ impl PlayerState {
     /// If there are tracks from the same album following or preceding in the
     /// queue, then we want to use the album loudness. If not, then we will use
     /// the track loudness.
-    fn update_current_track_loudness(&mut self, previous_album: AlbumId) {
+    fn update_current_track_loudness(&mut self, previous_album: Option<AlbumId>) {
         let current_track = match self.queue.get(0) {
             Some(t) => t,
             None => {
@@ -699,9 +1436,12 @@ impl PlayerState {
             }
         };
 
+        // Radio stations have no album, for them both loudnesses are the same.
+        let album_id = current_track.album_id();
         let loudness = match self.queue.get(1) {
-            Some(next_track) if current_track.album_id() == next_track.album_id() => current_track.album_loudness,
-            _ if current_track.album_id() == previous_album => current_track.album_loudness,
+            _ if album_id.is_none() => current_track.track_loudness,
+            Some(next_track) if album_id == next_track.album_id() => current_track.album_loudness,
+            _ if album_id == previous_album => current_track.album_loudness,
             _ => current_track.track_loudness,
         };
 
@@ -717,9 +1457,11 @@ impl PlayerState {
         // the initial track, but the difference shouldn't be *that* big.
         if self.queue.is_empty() {
             self.current_track_loudness = Some(track.album_loudness);
+            // With nothing left to hold, a pause is over.
+            self.is_paused = false;
         }
 
-        self.queue.push(track);
+        self.queue.push_back(track);
     }
 
     /// Dequeue the track, if it exists and is not currently playing.
@@ -733,17 +1475,100 @@ impl PlayerState {
         };
     }
 
-    /// Shuffle the queue.
-    pub fn shuffle(&mut self, index: &MemoryMetaIndex) {
-        if self.queue.len() < 3 {
-            // The track at index 0 is being played, we cannot move it, and then
-            // we need at least 2 more tracks to be able to shuffle anything at
+    /// Clear the decoded audio of the entry at index `i`, and of the entries after it.
+    ///
+    /// The entries after it can't keep their decoded audio, because decoded
+    /// entries must be at the front of the queue. Beyond the first entry that
+    /// did not start decoding, there is nothing to clear.
+    fn reset_decode_from(&mut self, i: usize) {
+        for (j, queued_track) in self.queue.range_mut(i..).enumerate() {
+            if j > 0 && matches!(queued_track.decode, Decode::NotStarted) {
+                break;
+            }
+            queued_track.reset_decode();
+        }
+    }
+
+    /// Play the queue entry from the given position, rather than from the start.
+    ///
+    /// If the entry is playing already, playback jumps to the position.
+    /// Returns false if the entry is not in the queue.
+    pub fn resume_at(&mut self, queue_id: QueueId, position_ms: u64) -> bool {
+        let i = match self.queue.iter().position(|qt| qt.queue_id == queue_id) {
+            Some(i) => i,
+            None => return false,
+        };
+
+        // We can't seek, so decoding starts over, and drops the samples before
+        // the position.
+        self.reset_decode_from(i);
+
+        let queued_track = &mut self.queue[i];
+        queued_track.samples_played = 0;
+        queued_track.resume_at_ms = position_ms;
+
+        #[cfg(debug)]
+        self.assert_invariants();
+
+        true
+    }
+
+    /// Remove all entries from the queue, to continue them on another player.
+    ///
+    /// The entries keep their queue ids, and the current track keeps its
+    /// position, so its listen continues on the other player, see
+    /// [`PlayerState::receive_queue`].
+    fn take_queue(&mut self) -> VecDeque<QueuedTrack> {
+        self.restart_at_position();
+        let entries = mem::take(&mut self.queue);
+        self.update_current_track_loudness(None);
+        entries
+    }
+
+    /// Play the entries of the queue of another player, before our own entries.
+    ///
+    /// If our current track started already, it counts as skipped.
+    fn receive_queue(&mut self, entries: VecDeque<QueuedTrack>) {
+        if entries.is_empty() {
+            return;
+        }
+
+        let previous_album = self.queue.front().and_then(|qt| qt.album_id());
+        if matches!(self.queue.front(), Some(qt) if qt.started) {
+            let track = self.queue.pop_front().expect("We checked that there is a track.");
+            self.send_skipped(&track);
+            self.push_history(&track);
+        }
+
+        // The new entries are not decoded, and decoded entries must be at the
+        // front of the queue.
+        self.reset_decode_from(0);
+        for queued_track in entries.into_iter().rev() {
+            self.queue.push_front(queued_track);
+        }
+        self.update_current_track_loudness(previous_album);
+
+        #[cfg(debug)]
+        self.assert_invariants();
+    }
+
+    /// Shuffle the queue.
+    pub fn shuffle(&mut self, index: &MemoryMetaIndex) {
+        if self.queue.len() < 3 {
+            // The track at index 0 is being played, we cannot move it, and then
+            // we need at least 2 more tracks to be able to shuffle anything at
             // all.
             return;
         }
 
-        let tracks = &mut self.queue[1..];
-        shuffle::shuffle(index, &mut self.rng, tracks);
+        // Radio stations play until they are skipped, so we keep them in
+        // place, and shuffle the tracks in between them separately.
+        let tracks = &mut self.queue.make_contiguous()[1..];
+        for run in tracks.split_mut(|qt| qt.album_id().is_none()) {
+            if run.len() > 1 {
+                shuffle::shuffle(index, &mut self.rng, run);
+            }
+        }
 
         // After the shuffle, the invariant that decoded samples are at the
         // front of the queue may be violated, so we need to restore that.
@@ -753,8 +1578,7 @@ impl PlayerState {
                 // Note, if the decode was running and we set it to not started
                 // now, the decode result will simply be dropped once the decode
                 // thread finishes the task.
-                queued_track.decode = Decode::NotStarted;
-                queued_track.blocks.clear();
+                queued_track.reset_decode();
             } else {
                 // If we still have any tracks done decoding in the front that's
                 // great, we can keep the samples, but as soon as there is any
@@ -777,6 +1601,106 @@ impl PlayerState {
         self.queue.truncate(1);
     }
 
+    /// Skip the currently playing track, and continue with the next one.
+    ///
+    /// Returns the queue id of the skipped track, if there was one.
+    pub fn skip(&mut self) -> Option<QueueId> {
+        let track = self.queue.pop_front()?;
+
+        self.send_skipped(&track);
+        if track.started {
+            self.push_history(&track);
+        }
+
+        let previous_album = track.album_id();
+        self.update_current_track_loudness(previous_album);
+        self.drop_failed_tracks();
+
+        #[cfg(debug)]
+        self.assert_invariants();
+
+        Some(track.queue_id)
+    }
+
+    /// Count a vote by `voter` to skip the current entry.
+    ///
+    /// Every client can vote once per entry. When `votes_needed` clients voted,
+    /// the entry gets skipped. Returns `None` when the queue is empty.
+    pub fn vote_skip(&mut self, voter: Client, votes_needed: u32) -> Option<SkipVote> {
+        let queue_id = self.queue.front()?.queue_id;
+        if !matches!(&self.skip_votes, Some((q, _)) if *q == queue_id) {
+            self.skip_votes = Some((queue_id, Vec::new()));
+        }
+        let voters = &mut self.skip_votes.as_mut().expect("We just set the votes.").1;
+        if !voters.contains(&voter) {
+            voters.push(voter);
+        }
+
+        let votes = voters.len() as u32;
+        let skipped = votes >= votes_needed;
+        if skipped {
+            self.skip_votes = None;
+            self.skip();
+        }
+
+        let result = SkipVote {
+            queue_id: queue_id,
+            votes: votes,
+            votes_needed: votes_needed,
+            skipped: skipped,
+        };
+        Some(result)
+    }
+
+    /// Go back: restart the current track, or play the previous one again.
+    ///
+    /// When the current track played for more than `PREVIOUS_RESTART_MS`, or
+    /// when there is no previous track, this restarts the current track.
+    /// Otherwise the entry that played before it goes in front of it, and the
+    /// current track plays from the start after that. Either way, the entry
+    /// to play gets a new queue id, because it is a new listen. Returns that
+    /// queue id, or `None` if there is nothing to go back to.
+    pub fn previous(&mut self) -> Option<QueueId> {
+        let should_restart = match self.queue.front() {
+            Some(qt) => qt.position_ms() > PREVIOUS_RESTART_MS || self.history.is_empty(),
+            None => false,
+        };
+
+        let entry = if should_restart {
+            let track = self.queue.pop_front().expect("We checked that there is a track.");
+            self.send_skipped(&track);
+            PlayedEntry::new(&track)
+        } else {
+            let entry = self.history.pop_back()?;
+            if let Some(track) = self.queue.front() {
+                self.send_skipped(track);
+            }
+            if let Some(track) = self.queue.front_mut() {
+                track.started = false;
+                track.samples_played = 0;
+                track.resume_at_ms = 0;
+                track.cast_position_ms = None;
+                track.stream_title = None;
+            }
+            entry
+        };
+
+        // The entry goes in front, and it has nothing decoded yet.
+        self.reset_decode_from(0);
+
+        let queue_id = self.next_unused_id;
+        self.next_unused_id = QueueId(queue_id.0 + 1);
+        self.queue.push_front(entry.into_queued_track(queue_id));
+
+        let previous_album = self.history.back().and_then(|e| e.source.track_id()).map(|t| t.album_id());
+        self.update_current_track_loudness(previous_album);
+
+        #[cfg(debug)]
+        self.assert_invariants();
+
+        Some(queue_id)
+    }
+
     /// Consume n samples from the peeked block.
     pub fn consume(&mut self, n: usize) {
         assert!(n > 0, "Must consume at least one sample.");
@@ -784,14 +1708,33 @@ impl PlayerState {
         let track_done = {
             let queued_track = &mut self.queue[0];
 
+            // Titles of radio streams change when we get to the samples that
+            // were decoded after the station announced the title.
+            let mut title_changed = false;
+            while let Some((at, _)) = queued_track.pending_titles.front() {
+                if *at > queued_track.samples_played {
+                    break;
+                }
+                let (_, title) = queued_track.pending_titles.pop_front().unwrap();
+                queued_track.stream_title = title;
+                title_changed = true;
+            }
+
             // If this is the first time that we consume samples from this
             // track, then that means it was just started.
-            if queued_track.samples_played == 0 {
-                self.events.send(
-                    PlaybackEvent::Started(queued_track.queue_id, queued_track.track_id)
-                ).expect("Failed to send completion event to history thread.");
+            if !queued_track.started {
+                queued_track.started = true;
+                self.send_started(&self.queue[0]);
+            } else if title_changed {
+                self.events.send(PlaybackEvent::RadioTitleChanged(
+                    queued_track.queue_id,
+                    queued_track.stream_title.clone(),
+                )).expect("Failed to send title event to history thread.");
             }
 
+            self.is_starved = false;
+
+            let queued_track = &mut self.queue[0];
             queued_track.samples_played += n as u64;
 
             let block_done = {
@@ -800,7 +1743,7 @@ impl PlayerState {
                 block.len() == 0
             };
             if block_done {
-                queued_track.blocks.remove(0);
+                queued_track.blocks.pop_front();
             }
             match &queued_track.decode {
                 Decode::Done => queued_track.blocks.is_empty(),
@@ -808,27 +1751,42 @@ impl PlayerState {
             }
         };
         if track_done {
-            let track = self.queue.remove(0);
-
-            self.events.send(PlaybackEvent::Completed(track.queue_id, track.track_id))
-                .expect("Failed to send completion event to history thread.");
-
-            let previous_album = track.album_id();
-            self.update_current_track_loudness(previous_album);
+            self.complete_current_track();
         }
 
         #[cfg(debug)]
         self.assert_invariants();
     }
 
+    /// Record that the audio device is playing, but we have no decoded audio for it.
+    ///
+    /// This happens when decoding falls behind, for example because reading
+    /// from the disk or network mount is slow. Playback continues by itself
+    /// when the decoder catches up, here we only log and count the underrun.
+    pub fn report_starved(&mut self) {
+        if !self.is_starved && !self.is_queue_empty() {
+            self.is_starved = true;
+            metrics::count_decode_underrun();
+            warn!("Playback ran out of decoded audio, waiting for the decoder.");
+        }
+    }
+
+    /// Return the queued tracks that have decoded blocks.
+    ///
+    /// By the invariant, those are at the front of the queue, so we don't have
+    /// to walk a long queue to the end.
+    fn decoded_tracks(&self) -> impl Iterator<Item = &QueuedTrack> {
+        self.queue.iter().take_while(|qt| !qt.blocks.is_empty())
+    }
+
     /// Return the duration of all unconsumed samples in milliseconds.
     pub fn pending_duration_ms(&self) -> u64 {
-        self.queue.iter().map(|qt| qt.duration_ms()).sum()
+        self.decoded_tracks().map(|qt| qt.duration_ms()).sum()
     }
 
     /// Return the size of all blocks in bytes.
     pub fn pending_size_bytes(&self) -> usize {
-        self.queue.iter().map(|qt| qt.size_bytes()).sum()
+        self.decoded_tracks().map(|qt| qt.size_bytes()).sum()
     }
 
     /// Return whether there are queue items that have not yet been fully decoded.
@@ -856,15 +1814,22 @@ impl PlayerState {
     /// IO is complete before we run out of samples to play.
     pub fn needs_decode(&self) -> bool {
         // Choose a safe margin; if spinning up the disks takes 10 to 15
-        // seconds, starting 30 seconds in advance should be sufficient.
-        let min_buffer_ms = 30_000;
-
-        let is_buffer_low = self.pending_duration_ms() < min_buffer_ms;
-        is_buffer_low && self.can_decode()
+        // seconds, starting 30 seconds in advance (the default) should be
+        // sufficient. A library on a network mount may need more, so the
+        // margin is configurable.
+
+        // While casting, the device decodes, we have nothing to do. Without
+        // audio card, there is nobody to play what we would decode.
+        let is_buffer_low = self.pending_duration_ms() < self.decode_ahead_ms;
+        is_buffer_low && self.can_decode() && !self.is_casting() && self.has_audio_card
     }
 
     /// Return a decode task, if there is something to decode.
     fn take_decode_task(&mut self) -> Option<DecodeTask> {
+        if self.is_casting() {
+            return None;
+        }
+
         for queued_track in self.queue.iter_mut() {
             match queued_track.decode {
                 Decode::Done => continue,
@@ -881,7 +1846,7 @@ impl PlayerState {
 
             match decode {
                 Decode::NotStarted => {
-                    return Some(DecodeTask::Start(queue_id, queued_track.track_id));
+                    return Some(DecodeTask::Start(queue_id, queued_track.source.clone()));
                 }
                 Decode::Partial(reader) => {
                     return Some(DecodeTask::Continue(queue_id, reader));
@@ -919,11 +1884,39 @@ impl PlayerState {
                     // seconds even in case of a buffer underrun, when there are
                     // no blocks.
                     queued_track.sample_rate = Some(result.block.format.sample_rate);
-                    queued_track.blocks.push(result.block);
+
+                    // For radio, remember where the title changed, so we can
+                    // announce it when playback gets there.
+                    let last_title = match queued_track.pending_titles.back() {
+                        Some((_, title)) => title,
+                        None => &queued_track.stream_title,
+                    };
+                    if *last_title != result.stream_title {
+                        queued_track.pending_titles.push_back(
+                            (queued_track.samples_decoded, result.stream_title)
+                        );
+                    }
+                    let mut block = result.block;
+                    queued_track.samples_decoded += block.len() as u64;
+
+                    // When we resume a track where we left off, drop what
+                    // comes before. Dropped samples count as played, so the
+                    // position is right.
+                    let n_skip = queued_track
+                        .samples_to_skip(block.format.sample_rate)
+                        .min(block.len() as u64);
+                    if n_skip > 0 {
+                        block.consume(n_skip as usize);
+                        queued_track.samples_played += n_skip;
+                    }
+                    if block.len() > 0 {
+                        queued_track.blocks.push_back(block);
+                    }
                     queued_track.decode = match result.reader {
                         Some(r) => Decode::Partial(r),
                         None => Decode::Done,
                     };
+                    queued_track.error = result.error;
 
                     break;
                 }
@@ -939,6 +1932,10 @@ impl PlayerState {
                 }
             }
         }
+
+        // If the current track failed before we decoded any audio for it,
+        // move on to the next one.
+        self.drop_failed_tracks();
     }
 }
 
@@ -953,9 +1950,8 @@ fn decode_burst(index: &MemoryMetaIndex, state_mutex: &Mutex<PlayerState>, filte
     // However, we do need to be able to hold all decoded samples in memory
     // then, and there is some risk of the decode being wasted work when the
     // queue changes. 85 MB will hold about 8 minutes of 16-bit 44.1 kHz audio,
-    // 105 MB will hold about 10 minutes of 16-bit 44.1 kHz audio.
-    // TODO: Make this configurable.
-    let stop_after_bytes = 105_000_000;
+    // 105 MB (the default) will hold about 10 minutes of 16-bit 44.1 kHz audio.
+    let stop_after_bytes = state_mutex.lock().unwrap().decode_buffer_bytes;
     let mut previous_result = None;
 
     loop {
@@ -971,7 +1967,7 @@ fn decode_burst(index: &MemoryMetaIndex, state_mutex: &Mutex<PlayerState>, filte
 
             let bytes_used = state.pending_size_bytes();
             if bytes_used >= stop_after_bytes {
-                println!("Buffer full, stopping decode for now.");
+                debug!("Buffer full, stopping decode for now.");
                 return
             }
 
@@ -994,10 +1990,23 @@ fn decode_burst(index: &MemoryMetaIndex, state_mutex: &Mutex<PlayerState>, filte
         let decode_bytes_per_ms = 44_100 * 4 * 5 / 1000;
         let decode_bytes_budget = decode_bytes_per_ms * pending_duration_ms as usize;
         let bytes_left = decode_bytes_budget.min(stop_after_bytes - bytes_used);
-        println!("Pending buffer stats:");
-        println!("  Duration: {:.3} seconds", pending_duration_ms as f32 / 1000.0);
-        println!("  Memory:   {:.3} / {:.3} MB", bytes_used as f32 * 1e-6, stop_after_bytes as f32 * 1e-6);
-        println!("  Budget:   {:.3} MB", bytes_left as f32 * 1e-6);
+
+        // A radio stream is decoded as it arrives, we would print every
+        // second, so skip the stats there.
+        if task.is_radio() {
+            previous_result = Some(task.run(index, filters, RADIO_CHUNK_BYTES));
+            continue;
+        }
+
+        debug!(
+            {
+                duration_ms: pending_duration_ms,
+                bytes_used: bytes_used,
+                bytes_max: stop_after_bytes,
+                bytes_budget: bytes_left,
+            },
+            "Pending buffer stats.",
+        );
         // Decode at most 10 MB at a time. This ensures that we produce the data
         // in blocks of at most 10 MB, which in turn ensures that we can free
         // the memory early when we are done playing. Without this, when the
@@ -1006,7 +2015,7 @@ fn decode_burst(index: &MemoryMetaIndex, state_mutex: &Mutex<PlayerState>, filte
         // already-played samples in a large block where the playhead is at the
         // end of the block.
         let result = task.run(index, filters, bytes_left.min(10_000_000));
-        println!("Decoded {:.3} MB.", result.block.size_bytes() as f32 * 1e-6);
+        debug!(bytes = result.block.size_bytes(), "Decoded a block.");
         previous_result = Some(result);
     }
 }
@@ -1020,8 +2029,11 @@ fn decode_main(
     index: Var<MemoryMetaIndex>,
     state_mutex: &Mutex<PlayerState>,
     high_pass_cutoff: Hertz,
+    bit_perfect: bool,
+    sample_rate_policy: SampleRatePolicy,
+    fixed_sample_rate: Hertz,
 ) {
-    let mut filters = Filters::new(high_pass_cutoff);
+    let mut filters = Filters::new(high_pass_cutoff, bit_perfect, sample_rate_policy, fixed_sample_rate);
 
     loop {
         let should_decode = {
@@ -1035,9 +2047,9 @@ fn decode_main(
             decode_burst(&current_index, state_mutex, &mut filters);
         }
 
-        println!("Decoder going to sleep.");
+        debug!("Decoder going to sleep.");
         thread::park();
-        println!("Decoder woken up.");
+        debug!("Decoder woken up.");
     }
 }
 
@@ -1045,17 +2057,26 @@ pub struct Player {
     state: Arc<Mutex<PlayerState>>,
     decode_thread: JoinHandle<()>,
     playback_thread: JoinHandle<()>,
-    history_thread: JoinHandle<()>,
-    exec_pre_post_thread: JoinHandle<()>,
+    /// The history thread, only for the player that started it, see [`Player::new_remote`].
+    history_thread: Option<JoinHandle<()>>,
+    history_status: Arc<Mutex<HistoryStatus>>,
+    exec_pre_post_thread: Option<JoinHandle<()>>,
     events: SyncSender<PlaybackEvent>,
+    event_bus: Arc<EventBus>,
+    index_var: Var<MemoryMetaIndex>,
+    scrobble_credentials: Var<Option<Credentials>>,
+    scrobble_events: SyncSender<ScrobbleEvent>,
 }
 
 pub struct TrackSnapshot {
     /// Queue id of the queued track.
     pub queue_id: QueueId,
 
-    /// Track id of the queued track.
-    pub track_id: TrackId,
+    /// The track or radio station of the queue entry.
+    pub source: Source,
+
+    /// For radio stations, the title that the station announced, if any.
+    pub stream_title: Option<String>,
 
     /// The current playback position in the track, in milliseconds.
     pub position_ms: u64,
@@ -1070,6 +2091,15 @@ pub struct TrackSnapshot {
     /// is blocked on IO. This can happen, for example when using spinning disks
     /// that need to spin up, or seek to the file.
     pub is_buffering: bool,
+
+    /// The position that playback of this entry resumes at, 0 if it plays from the start.
+    pub resume_at_ms: u64,
+
+    /// A saved position that playback of this entry could resume at.
+    ///
+    /// The player doesn't know about saved positions, this is `None` in the
+    /// snapshot, and the server fills it in from the database.
+    pub resume_offer_ms: Option<u64>,
 }
 
 pub struct QueueSnapshot {
@@ -1077,10 +2107,115 @@ pub struct QueueSnapshot {
     pub tracks: Vec<TrackSnapshot>,
 }
 
+/// A queue entry to restore when the server starts again, see [`Player::shut_down`].
+pub struct SavedQueueEntry {
+    pub source: Source,
+
+    /// Where to resume playback, nonzero only for the current track.
+    pub position_ms: u64,
+
+    pub client: Option<String>,
+    pub user: Option<String>,
+}
+
+/// What the player is doing, see [`Player::get_now_playing`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PlaybackState {
+    /// The queue is empty.
+    Stopped,
+
+    /// There is a current track, but no decoded audio for it is available yet.
+    Buffering,
+
+    /// The current track is playing.
+    Playing,
+
+    /// There is a current track, but the user paused playback.
+    Paused,
+}
+
+pub struct NowPlaying {
+    pub state: PlaybackState,
+
+    /// The currently playing track, if any.
+    pub current: Option<TrackSnapshot>,
+
+    /// The number of tracks in the queue, including the current one.
+    pub queue_len: usize,
+
+    pub volume: Millibel,
+
+    /// The format of the audio card, `None` when it is not open.
+    pub output_format: Option<OutputFormat>,
+
+    /// The gain that the playback thread applies, `None` without digital volume.
+    pub gain: Option<GainStatus>,
+}
+
+/// Start the decode thread and the playback thread for the player state.
+///
+/// Both run indefinitely, but we do need to periodically unpark the decode
+/// thread when there is new stuff to decode.
+fn spawn_decode_and_playback(
+    index_var: &Var<MemoryMetaIndex>,
+    state: &Arc<Mutex<PlayerState>>,
+    hist_sender: &SyncSender<PlaybackEvent>,
+    queue_events_sender: SyncSender<QueueEvent>,
+    config: &Config,
+) -> (JoinHandle<()>, JoinHandle<()>) {
+    let state_mutex_for_decode = state.clone();
+    let index_for_decode = index_var.clone();
+    let high_pass_cutoff = config.high_pass_cutoff;
+    let bit_perfect = config.bit_perfect;
+    let sample_rate_policy = config.sample_rate_policy;
+    let fixed_sample_rate = config.fixed_sample_rate;
+    let builder = std::thread::Builder::new();
+    let decode_join_handle = builder
+        .name("decoder".into())
+        .spawn(move || {
+            decode_main(
+                index_for_decode,
+                &state_mutex_for_decode,
+                high_pass_cutoff,
+                bit_perfect,
+                sample_rate_policy,
+                fixed_sample_rate,
+            );
+        }).unwrap();
+
+    let state_mutex_for_playback = state.clone();
+    let decode_thread_for_playback = decode_join_handle.thread().clone();
+    let config_for_playback = config.clone();
+    let hist_sender_for_playback = hist_sender.clone();
+
+    let builder = std::thread::Builder::new();
+    let playback_join_handle = builder
+        .name("playback".into())
+        .spawn(move || {
+            playback::main(
+                &config_for_playback,
+                state_mutex_for_playback,
+                &decode_thread_for_playback,
+                queue_events_sender,
+                hist_sender_for_playback,
+            );
+        }).unwrap();
+
+    (decode_join_handle, playback_join_handle)
+}
+
+/// Return the track loudness and album loudness of a track to enqueue.
+fn get_loudness(index: &MemoryMetaIndex, track_id: TrackId) -> (Lufs, Lufs) {
+    let track = index.get_track(track_id).expect("Can only enqueue existing tracks.");
+    let album = index.get_album(track_id.album_id()).expect("Track must belong to album.");
+    (track.loudness.unwrap_or_default(), album.loudness.unwrap_or_default())
+}
+
 impl Player {
     pub fn new(
         index_var: Var<MemoryMetaIndex>,
-        user_data: Arc<Mutex<UserData>>,
+        user_data: Arc<Mutex<UserDataSet>>,
+        event_bus: Arc<EventBus>,
         config: &Config,
     ) -> Player {
         // Build the channel to send playback events to the history thread. That
@@ -1091,46 +2226,92 @@ impl Player {
         // Same for playback start and end queue events, for the exec thread.
         let (queue_events_sender, queue_events_receiver) = mpsc::sync_channel(5);
 
-        let state = Arc::new(Mutex::new(PlayerState::new(hist_sender.clone())));
+        let state = Arc::new(Mutex::new(PlayerState::new(hist_sender.clone(), config)));
+        let (decode_join_handle, playback_join_handle) = spawn_decode_and_playback(
+            &index_var,
+            &state,
+            &hist_sender,
+            queue_events_sender,
+            config,
+        );
 
-        // Start the decode thread. It runs indefinitely, but we do need to
-        // periodically unpark it when there is new stuff to decode.
-        let state_mutex_for_decode = state.clone();
-        let index_for_decode = index_var.clone();
-        let high_pass_cutoff = config.high_pass_cutoff;
-        let builder = std::thread::Builder::new();
-        let decode_join_handle = builder
-            .name("decoder".into())
-            .spawn(move || {
-                decode_main(
-                    index_for_decode,
-                    &state_mutex_for_decode,
-                    high_pass_cutoff,
-                );
-            }).unwrap();
+        // The scrobbler thread runs even without Last.fm credentials, because
+        // they can be added when the config is reloaded. Submitting can take a
+        // while and can block on the network, so allow a larger backlog of
+        // events for it.
+        let scrobble_credentials = Arc::new(MVar::new(Arc::new(Credentials::from_config(config))));
+        let (scrobble_sender, scrobble_receiver) = mpsc::sync_channel(32);
+        {
+            let db_path = config.db_path.clone();
+            let index_for_scrobble = index_var.clone();
+            let credentials_for_scrobble = scrobble_credentials.clone();
+            let listen_threshold = config.listen_threshold();
+            let builder = std::thread::Builder::new();
+            builder
+                .name("scrobbler".into())
+                .spawn(move || {
+                    let result = scrobble::main(
+                        &db_path,
+                        index_for_scrobble,
+                        credentials_for_scrobble,
+                        scrobble_receiver,
+                        listen_threshold,
+                    );
+                    // Like the history thread, the scrobbler should not exit.
+                    error!("Scrobbler thread exited: {:?}", result);
+                    std::process::exit(1);
+                }).unwrap();
+        }
 
-        let state_mutex_for_playback = state.clone();
-        let decode_thread_for_playback = decode_join_handle.thread().clone();
-        let config_for_playback = config.clone();
-        let hist_sender_for_playback = hist_sender.clone();
+        // The webhook thread only runs when webhooks are configured.
+        let webhook_sender = if config.webhook_urls.is_empty() {
+            None
+        } else {
+            let (webhook_sender, webhook_receiver) = mpsc::sync_channel(32);
+            let urls = config.webhook_urls.clone();
+            let index_for_webhook = index_var.clone();
+            let builder = std::thread::Builder::new();
+            builder
+                .name("webhook".into())
+                .spawn(move || webhook::main(
+                    index_for_webhook,
+                    urls,
+                    webhook_receiver,
+                )).unwrap();
+            Some(webhook_sender)
+        };
 
-        let builder = std::thread::Builder::new();
-        let playback_join_handle = builder
-            .name("playback".into())
-            .spawn(move || {
-                playback::main(
-                    &config_for_playback,
-                    state_mutex_for_playback,
-                    &decode_thread_for_playback,
-                    queue_events_sender,
-                    hist_sender_for_playback,
-                );
-            }).unwrap();
+        // Likewise, the status thread only runs when there is something to
+        // notify of status changes.
+        let status_sender = if config.exec_status_change_path.is_none()
+            && config.gpio_playing.is_none()
+            && config.gpio_error.is_none()
+        {
+            None
+        } else {
+            let (status_sender, status_receiver) = mpsc::sync_channel(32);
+            let config_status = config.clone();
+            let builder = std::thread::Builder::new();
+            builder
+                .name("status".into())
+                .spawn(move || status_hook::main(
+                    &config_status,
+                    status_receiver,
+                )).unwrap();
+            Some(status_sender)
+        };
 
         let builder = std::thread::Builder::new();
-        let index_for_history = index_var;
+        let index_for_history = index_var.clone();
+        let scrobble_sender_for_history = scrobble_sender.clone();
+
+        let history_status = Arc::new(Mutex::new(HistoryStatus::default()));
+        let history_status_for_history = history_status.clone();
 
         let db_path = config.db_path.clone();
+        let event_bus_for_history = event_bus.clone();
+        let resume_min_duration_seconds = config.resume_min_duration_seconds;
+        let listen_threshold = config.listen_threshold();
         let history_join_handle = builder
             .name("history".into())
             .spawn(move || {
@@ -1139,10 +2320,17 @@ impl Player {
                     index_for_history,
                     user_data,
                     hist_receiver,
+                    Some(scrobble_sender_for_history),
+                    webhook_sender,
+                    status_sender,
+                    event_bus_for_history,
+                    history_status_for_history,
+                    resume_min_duration_seconds,
+                    listen_threshold,
                 );
                 // The history thread should not exit. When it does, that's a
                 // problem.
-                eprintln!("History thread exited: {:?}", result);
+                error!("History thread exited: {:?}", result);
                 std::process::exit(1);
             }).unwrap();
 
@@ -1159,34 +2347,126 @@ impl Player {
             state: state,
             decode_thread: decode_join_handle,
             playback_thread: playback_join_handle,
-            history_thread: history_join_handle,
-            exec_pre_post_thread: exec_pre_post_handle,
+            history_thread: Some(history_join_handle),
+            history_status: history_status,
+            exec_pre_post_thread: Some(exec_pre_post_handle),
             events: hist_sender,
+            event_bus: event_bus,
+            index_var: index_var,
+            scrobble_credentials: scrobble_credentials,
+            scrobble_events: scrobble_sender,
         }
     }
 
+    /// Start another player, that logs listens through the history thread of this one.
+    ///
+    /// The new player has its own queue, volume, and playback state, but it
+    /// has no audio card: it only plays on a cast device or in a browser. Its
+    /// queue ids start at `first_queue_id`, so they don't collide with the
+    /// queue ids of other players in the history thread.
+    pub fn new_remote(&self, config: &Config, first_queue_id: QueueId) -> Player {
+        let mut state = PlayerState::new(self.events.clone(), config);
+        state.next_unused_id = first_queue_id;
+        state.has_audio_card = false;
+        let state = Arc::new(Mutex::new(state));
+
+        // Without audio card, the playback thread never starts playback, so
+        // it doesn't send queue events, and we need no exec thread.
+        let (queue_events_sender, _) = mpsc::sync_channel(1);
+        let (decode_join_handle, playback_join_handle) = spawn_decode_and_playback(
+            &self.index_var,
+            &state,
+            &self.events,
+            queue_events_sender,
+            config,
+        );
+
+        Player {
+            state: state,
+            decode_thread: decode_join_handle,
+            playback_thread: playback_join_handle,
+            history_thread: None,
+            history_status: self.history_status.clone(),
+            exec_pre_post_thread: None,
+            events: self.events.clone(),
+            event_bus: self.event_bus.clone(),
+            index_var: self.index_var.clone(),
+            scrobble_credentials: self.scrobble_credentials.clone(),
+            scrobble_events: self.scrobble_events.clone(),
+        }
+    }
+
+    /// Replace the Last.fm credentials, when the config is reloaded.
+    pub fn set_scrobble_credentials(&self, credentials: Option<Credentials>) {
+        self.scrobble_credentials.set(Arc::new(credentials));
+        // Wake up the scrobbler, so it retries pending listens right away. If
+        // its queue is full, it is awake anyway.
+        let _ = self.scrobble_events.try_send(ScrobbleEvent::CredentialsChanged);
+    }
+
     /// Wait for the playback and decode thread to finish.
     pub fn join(self) {
         // Note: currently there is no way to to signal these threads to stop,
         // so this will block indefinitely.
         self.playback_thread.join().unwrap();
         self.decode_thread.join().unwrap();
-        self.history_thread.join().unwrap();
-        self.exec_pre_post_thread.join().unwrap();
+        if let Some(history_thread) = self.history_thread {
+            history_thread.join().unwrap();
+        }
+        if let Some(exec_pre_post_thread) = self.exec_pre_post_thread {
+            exec_pre_post_thread.join().unwrap();
+        }
     }
 
-    /// Send a track rating to the history thread for saving to the database.
-    pub fn set_track_rating(&self, track_id: TrackId, rating: Rating) {
-        self.events.send(PlaybackEvent::Rated { track_id, rating }).unwrap();
+    /// Return whether the history thread is keeping up with recording listens.
+    pub fn get_history_status(&self) -> HistoryStatus {
+        *self.history_status.lock().unwrap()
+    }
+
+    /// Send a track rating by the user to the history thread for saving to the database.
+    pub fn set_track_rating(&self, track_id: TrackId, rating: Rating, user: Option<&str>) {
+        let user = user.map(|u| u.to_string());
+        self.events.send(PlaybackEvent::Rated { track_id, rating, user }).unwrap();
+    }
+
+    /// Send an album rating by the user to the history thread for saving to the database.
+    pub fn set_album_rating(&self, album_id: AlbumId, rating: Rating, user: Option<&str>) {
+        let user = user.map(|u| u.to_string());
+        self.events.send(PlaybackEvent::AlbumRated { album_id, rating, user }).unwrap();
+    }
+
+    /// Send an artist rating by the user to the history thread for saving to the database.
+    pub fn set_artist_rating(&self, artist_id: ArtistId, rating: Rating, user: Option<&str>) {
+        let user = user.map(|u| u.to_string());
+        self.events.send(PlaybackEvent::ArtistRated { artist_id, rating, user }).unwrap();
     }
 
     /// Enqueue the track for playback at the end of the queue.
-    pub fn enqueue(&self, index: &MemoryMetaIndex, track_id: TrackId) -> QueueId {
-        let album_id = track_id.album_id();
-        let track = index.get_track(track_id).expect("Can only enqueue existing tracks.");
-        let album = index.get_album(album_id).expect("Track must belong to album.");
-        let track_loudness = track.loudness.unwrap_or_default();
-        let album_loudness = album.loudness.unwrap_or_default();
+    ///
+    /// The client name, if any, and the user, are recorded with the listen.
+    /// The queue is shared, but the listen counts for the user who enqueued
+    /// the track, not for whoever is listening at the time.
+    pub fn enqueue(
+        &self,
+        index: &MemoryMetaIndex,
+        track_id: TrackId,
+        client: Option<&str>,
+        user: Option<&str>,
+    ) -> QueueId {
+        let resume_at_ms = 0;
+        self.enqueue_at(index, track_id, client, user, resume_at_ms)
+    }
+
+    /// Enqueue the track, to start playing at the given position.
+    fn enqueue_at(
+        &self,
+        index: &MemoryMetaIndex,
+        track_id: TrackId,
+        client: Option<&str>,
+        user: Option<&str>,
+        resume_at_ms: u64,
+    ) -> QueueId {
+        let (track_loudness, album_loudness) = get_loudness(index, track_id);
 
         // If the queue is empty, then the playback thread may be parked,
         // so we may need to wake it after enqueuing something.
@@ -1195,7 +2475,15 @@ impl Player {
             let needs_wake = state.is_queue_empty();
             let id = state.next_unused_id;
             state.next_unused_id = QueueId(id.0 + 1);
-            let qt = QueuedTrack::new(id, track_id, track_loudness, album_loudness);
+            let mut qt = QueuedTrack::new(
+                id,
+                Source::Track(track_id),
+                client.map(|c| c.to_string()),
+                user.map(|u| u.to_string()),
+                track_loudness,
+                album_loudness,
+            );
+            qt.resume_at_ms = resume_at_ms;
             state.enqueue(qt);
             (id, needs_wake)
         };
@@ -1204,35 +2492,311 @@ impl Player {
             self.playback_thread.thread().unpark();
         }
 
+        self.event_bus.publish(Event::QueueChanged);
+
         queue_id
     }
 
+    /// Enqueue the tracks for playback at the end of the queue, in this order.
+    ///
+    /// This does the same as `enqueue` for every track, but it takes the lock
+    /// and publishes the queue change only once, so enqueueing an entire
+    /// library is one change rather than tens of thousands. If `shuffle_tracks`
+    /// is true, the new tracks are shuffled among themselves, the entries that
+    /// were in the queue already stay where they are. Returns the queue ids of
+    /// the new entries, in queue order.
+    pub fn enqueue_many(
+        &self,
+        index: &MemoryMetaIndex,
+        track_ids: &[TrackId],
+        client: Option<&str>,
+        user: Option<&str>,
+        shuffle_tracks: bool,
+    ) -> Vec<QueueId> {
+        if track_ids.is_empty() {
+            return Vec::new();
+        }
+
+        let (queue_ids, needs_wake) = {
+            let mut state = self.state.lock().unwrap();
+            let needs_wake = state.is_queue_empty();
+            let mut tracks = Vec::with_capacity(track_ids.len());
+            for &track_id in track_ids {
+                let (track_loudness, album_loudness) = get_loudness(index, track_id);
+                let id = state.next_unused_id;
+                state.next_unused_id = QueueId(id.0 + 1);
+                tracks.push(QueuedTrack::new(
+                    id,
+                    Source::Track(track_id),
+                    client.map(|c| c.to_string()),
+                    user.map(|u| u.to_string()),
+                    track_loudness,
+                    album_loudness,
+                ));
+            }
+            if shuffle_tracks && tracks.len() > 1 {
+                shuffle::shuffle(index, &mut state.rng, &mut tracks);
+            }
+            let queue_ids = tracks.iter().map(|qt| qt.queue_id).collect();
+            state.queue.reserve(tracks.len());
+            for qt in tracks {
+                state.enqueue(qt);
+            }
+            (queue_ids, needs_wake)
+        };
+
+        if needs_wake {
+            self.playback_thread.thread().unpark();
+        }
+
+        self.event_bus.publish(Event::QueueChanged);
+
+        queue_ids
+    }
+
+    /// Enqueue the radio station for playback at the end of the queue.
+    ///
+    /// The station plays until it is skipped, tracks that are enqueued after
+    /// it play after that.
+    pub fn enqueue_radio(&self, station: radio::Station, client: Option<&str>) -> QueueId {
+        let (queue_id, needs_wake) = {
+            let mut state = self.state.lock().unwrap();
+            let needs_wake = state.is_queue_empty();
+            let id = state.next_unused_id;
+            state.next_unused_id = QueueId(id.0 + 1);
+            let qt = QueuedTrack::new(
+                id,
+                Source::Radio(Arc::new(station)),
+                client.map(|c| c.to_string()),
+                // Radio listens don't count towards anybody's statistics.
+                None,
+                radio::assumed_loudness(),
+                radio::assumed_loudness(),
+            );
+            state.enqueue(qt);
+            (id, needs_wake)
+        };
+
+        if needs_wake {
+            self.playback_thread.thread().unpark();
+        }
+
+        self.event_bus.publish(Event::QueueChanged);
+
+        queue_id
+    }
+
+    /// Enqueue the entries of the queue that we saved when we last shut down.
+    ///
+    /// Tracks that are no longer in the library, and radio stations that were
+    /// deleted, are skipped.
+    pub fn restore_queue(&self, index: &MemoryMetaIndex, entries: Vec<db::SavedQueueEntry>) {
+        let mut n_restored = 0;
+        for entry in entries {
+            match (entry.track_id, entry.station_id, entry.name, entry.url) {
+                (Some(track_id), _, _, _) => {
+                    let track_id = TrackId(track_id as u64);
+                    let track = match index.get_track(track_id) {
+                        Some(track) => track,
+                        None => {
+                            warn!("Track {} of the saved queue is no longer in the library, skipping it.", track_id);
+                            continue;
+                        }
+                    };
+                    // A track that was nearly over when we shut down counts
+                    // as done, there is little point in playing its last bit.
+                    let position_ms = entry.position_ms as u64;
+                    if position_ms + 5_000 > track.duration_seconds as u64 * 1000 {
+                        continue;
+                    }
+                    self.enqueue_at(
+                        index,
+                        track_id,
+                        entry.client.as_deref(),
+                        entry.user_name.as_deref(),
+                        position_ms,
+                    );
+                }
+                (None, Some(station_id), Some(name), Some(url)) => {
+                    let station = radio::Station { id: station_id, name: name, url: url };
+                    self.enqueue_radio(station, entry.client.as_deref());
+                }
+                _ => {
+                    warn!("Radio station of the saved queue was deleted, skipping it.");
+                    continue;
+                }
+            }
+            n_restored += 1;
+        }
+        if n_restored > 0 {
+            info!("Restored {} entries of the saved queue.", n_restored);
+        }
+    }
+
+    /// Fade out playback, and save the queue, so we can exit.
+    ///
+    /// Playback stops after the fade out, and the queue stays as it is. The
+    /// history thread records the events that are still pending, and then
+    /// saves the queue, with the position in the current track. Returns
+    /// whether that happened before `timeout`.
+    pub fn shut_down(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        let is_playing = {
+            let mut state = self.state.lock().unwrap();
+            state.fade_out_started_at = Some(Instant::now());
+            !state.is_queue_empty() && !state.is_casting() && !state.is_paused()
+        };
+        if is_playing {
+            // The playback thread picks up the volume change within a few
+            // milliseconds, and stops after the fade out.
+            info!("Fading out playback ...");
+            thread::sleep(FADE_OUT_DURATION + Duration::from_millis(100));
+        }
+
+        let queue = {
+            let state = self.state.lock().unwrap();
+            state.queue.iter().enumerate().map(|(i, qt)| SavedQueueEntry {
+                source: qt.source.clone(),
+                position_ms: match (i, &qt.source) {
+                    (0, Source::Track(..)) => qt.position_ms().max(qt.resume_at_ms),
+                    _ => 0,
+                },
+                client: qt.client.clone(),
+                user: qt.user.clone(),
+            }).collect()
+        };
+
+        // The history thread handles events in order, so when it confirms that
+        // it saved the queue, it also recorded all listens before.
+        let (done_sender, done_receiver) = mpsc::sync_channel(1);
+        let event = PlaybackEvent::ShutDown { queue: queue, done: done_sender };
+        self.events.send(event).expect("Failed to send event to history thread.");
+        match done_receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(()) => true,
+            // When recording fails, the history thread drops the event, and
+            // with it the sender.
+            Err(mpsc::RecvTimeoutError::Disconnected) => false,
+            Err(mpsc::RecvTimeoutError::Timeout) => false,
+        }
+    }
+
     /// Enqueue the track for playback at the end of the queue.
     pub fn dequeue(&self, queue_id: QueueId) {
         self.state.lock().unwrap().dequeue(queue_id);
+        self.event_bus.publish(Event::QueueChanged);
+    }
+
+    /// Return the track of the queue entry, if it is in the queue and is a track.
+    pub fn get_queued_track(&self, queue_id: QueueId) -> Option<TrackId> {
+        let state = self.state.lock().unwrap();
+        let queued_track = state.queue.iter().find(|qt| qt.queue_id == queue_id)?;
+        queued_track.source.track_id()
+    }
+
+    /// Play the queue entry from the given position, see [`PlayerState::resume_at`].
+    pub fn resume_at(&self, queue_id: QueueId, position_ms: u64) -> bool {
+        let is_queued = self.state.lock().unwrap().resume_at(queue_id, position_ms);
+        // The decoder may have been idle, because the buffer was full.
+        if is_queued {
+            self.decode_thread.thread().unpark();
+            self.event_bus.publish(Event::QueueChanged);
+        }
+        is_queued
+    }
+
+    /// Jump to the position in the current track, return false if nothing is playing.
+    ///
+    /// Radio stations are live, there is nowhere to jump to, and a cast device
+    /// or browser fetches the track itself, so we can't jump there either.
+    pub fn seek(&self, position_ms: u64) -> bool {
+        let queue_id = {
+            let state = self.state.lock().unwrap();
+            if state.is_casting() {
+                return false;
+            }
+            match state.queue.front() {
+                Some(qt) if qt.source.track_id().is_some() => qt.queue_id,
+                _ => return false,
+            }
+        };
+        self.resume_at(queue_id, position_ms)
+    }
+
+    /// Pause playback, see [`PlayerState::pause`].
+    ///
+    /// The playback thread notices within a few milliseconds, and releases the
+    /// output. Returns false if there is nothing to pause.
+    pub fn pause(&self) -> bool {
+        let is_paused = self.state.lock().unwrap().pause();
+        if is_paused {
+            self.event_bus.publish(Event::PlaybackPaused);
+        }
+        is_paused
+    }
+
+    /// Continue playback after a pause, return false if we were not paused.
+    pub fn play(&self) -> bool {
+        let was_paused = self.state.lock().unwrap().play();
+        if was_paused {
+            // The playback thread parked itself when it released the output.
+            self.playback_thread.thread().unpark();
+            self.event_bus.publish(Event::PlaybackResumed);
+        }
+        was_paused
     }
 
     /// Return a snapshot of the queue.
     pub fn get_queue(&self) -> QueueSnapshot {
+        self.get_queue_page(0, None)
+    }
+
+    /// Return a snapshot of at most `limit` entries of the queue, from `offset`.
+    ///
+    /// For a long queue, this is a lot cheaper than a snapshot of the full
+    /// queue, and the caller only needs to look up the metadata of the entries
+    /// that it shows.
+    pub fn get_queue_page(&self, offset: usize, limit: Option<usize>) -> QueueSnapshot {
         let state = self.state.lock().unwrap();
 
-        let mut tracks = Vec::with_capacity(state.queue.len());
-        for queued_track in state.queue.iter() {
-            let t = TrackSnapshot {
-                queue_id: queued_track.queue_id,
-                track_id: queued_track.track_id,
-                position_ms: queued_track.position_ms(),
-                buffered_ms: queued_track.duration_ms(),
-                is_buffering: matches!(queued_track.decode, Decode::Running),
-            };
-            tracks.push(t);
-        }
+        let len = state.queue.len();
+        let begin = offset.min(len);
+        let end = match limit {
+            Some(n) => begin.saturating_add(n).min(len),
+            None => len,
+        };
+        let tracks = state.queue.range(begin..end).map(|qt| qt.snapshot()).collect();
 
         QueueSnapshot {
             tracks: tracks,
         }
     }
 
+    /// Return the current track, playback position, and volume.
+    ///
+    /// The position is the position of the samples handed to the audio device,
+    /// which runs ahead of what is audible by the device buffer, a few dozen
+    /// milliseconds.
+    pub fn get_now_playing(&self) -> NowPlaying {
+        let state = self.state.lock().unwrap();
+        let current = state.queue.front().map(|qt| qt.snapshot());
+        let playback_state = match &current {
+            None => PlaybackState::Stopped,
+            Some(..) if state.is_paused() => PlaybackState::Paused,
+            // While casting we don't buffer anything, the device does.
+            Some(t) if t.buffered_ms == 0 && !state.is_casting() => PlaybackState::Buffering,
+            Some(..) => PlaybackState::Playing,
+        };
+        NowPlaying {
+            state: playback_state,
+            current: current,
+            queue_len: state.queue.len(),
+            volume: state.volume,
+            output_format: state.output_format,
+            gain: state.gain_status,
+        }
+    }
+
     /// Shuffle the queue.
     pub fn shuffle(&self, index: &MemoryMetaIndex) {
         self.state.lock().unwrap().shuffle(index);
@@ -1241,11 +2805,65 @@ impl Player {
         // even if decoding was caught up before the shuffle, after the shuffle
         // we may need to start decoding right now.
         self.decode_thread.thread().unpark();
+
+        self.event_bus.publish(Event::QueueChanged);
     }
 
     /// Shuffle the queue.
     pub fn clear_queue(&self) {
         self.state.lock().unwrap().clear_queue();
+        self.event_bus.publish(Event::QueueChanged);
+    }
+
+    /// Skip the currently playing track, return its queue id, if any.
+    pub fn skip(&self) -> Option<QueueId> {
+        let result = self.state.lock().unwrap().skip();
+
+        // The next track may not have been decoded yet.
+        self.decode_thread.thread().unpark();
+
+        if result.is_some() {
+            self.event_bus.publish(Event::QueueChanged);
+        }
+
+        result
+    }
+
+    /// Vote to skip the current track, see [`PlayerState::vote_skip`].
+    pub fn vote_skip(&self, voter: Client, votes_needed: u32) -> Option<SkipVote> {
+        let result = self.state.lock().unwrap().vote_skip(voter, votes_needed);
+
+        if let Some(SkipVote { skipped: true, .. }) = result {
+            // The next track may not have been decoded yet.
+            self.decode_thread.thread().unpark();
+            self.event_bus.publish(Event::QueueChanged);
+        }
+
+        result
+    }
+
+    /// Restart the current track, or play the previous one, see [`PlayerState::previous`].
+    ///
+    /// Returns the queue id of the entry that plays now, if any.
+    pub fn previous(&self) -> Option<QueueId> {
+        let (result, needs_wake) = {
+            let mut state = self.state.lock().unwrap();
+            let needs_wake = state.is_queue_empty();
+            (state.previous(), needs_wake)
+        };
+
+        // The entry that plays now needs to be decoded from the start, and if
+        // the queue was empty, the playback thread may be parked.
+        self.decode_thread.thread().unpark();
+        if needs_wake {
+            self.playback_thread.thread().unpark();
+        }
+
+        if result.is_some() {
+            self.event_bus.publish(Event::QueueChanged);
+        }
+
+        result
     }
 
     /// Return the current playback volume.
@@ -1256,18 +2874,188 @@ impl Player {
 
     /// Add a (possibly negative) amount to the current volume, return the new volume.
     pub fn change_volume(&self, add: Millibel) -> Millibel {
-        let mut state = self.state.lock().unwrap();
-        state.volume.0 += add.0;
-
-        // It makes no sense to crank up the volume further than the target
-        // loudness: an extremely loud track at 0 LUFS played at a volume of
-        // 0 dB would be toned bown by target_loudness to reach the target
-        // loudness, so we can turn up the volume by that amount to make things
-        // louder without exceeding full scale.
-        state.volume = state.volume.min(Millibel(-state.target_loudness.0.get()));
-        // -60 dB is low enough to be pretty much silent.
-        state.volume = state.volume.max(Millibel(-6000));
+        let volume = {
+            let mut state = self.state.lock().unwrap();
+            state.volume.0 += add.0;
+
+            // It makes no sense to crank up the volume further than the target
+            // loudness: an extremely loud track at 0 LUFS played at a volume of
+            // 0 dB would be toned bown by target_loudness to reach the target
+            // loudness, so we can turn up the volume by that amount to make things
+            // louder without exceeding full scale.
+            state.volume = state.volume.min(Millibel(-state.target_loudness.0.get()));
+            // -60 dB is low enough to be pretty much silent.
+            state.volume = state.volume.max(Millibel(-6000));
+
+            state.volume
+        };
 
-        state.volume
+        self.event_bus.publish(Event::VolumeChanged { volume: volume });
+
+        volume
+    }
+
+    /// Return whether we play on a cast device or browser rather than the audio card.
+    pub fn is_casting(&self) -> bool {
+        self.state.lock().unwrap().is_casting()
+    }
+
+    /// Return the cast device that we play on, if any.
+    pub fn get_cast_device(&self) -> Option<cast::Device> {
+        match &self.state.lock().unwrap().remote_output {
+            Some(RemoteOutput::Cast(device)) => Some(device.clone()),
+            _ => None,
+        }
+    }
+
+    /// Play the queue on the cast device instead of the audio card.
+    ///
+    /// This replaces the previous cast device, if there was one. When the
+    /// connection to the device fails, playback moves back to the audio card.
+    pub fn cast_to(
+        &self,
+        device: cast::Device,
+        connection: cast::Connection,
+        media: cast::MediaSource,
+    ) {
+        let session = self.state.lock().unwrap().start_casting(RemoteOutput::Cast(device));
+
+        let state_mutex = self.state.clone();
+        let index_var = self.index_var.clone();
+        let playback_thread = self.playback_thread.thread().clone();
+        let decode_thread = self.decode_thread.thread().clone();
+        let event_bus = self.event_bus.clone();
+        let builder = std::thread::Builder::new();
+        builder
+            .name("cast".into())
+            .spawn(move || {
+                let result = cast::main(connection, session, state_mutex.clone(), index_var, media);
+                if let Err(err) = result {
+                    warn!("Casting stopped: {}", err);
+                    let mut state = state_mutex.lock().unwrap();
+                    // If casting changed in the meantime, it is not ours to stop.
+                    if state.cast_session() == session {
+                        state.stop_casting();
+                    }
+                }
+                playback_thread.unpark();
+                decode_thread.unpark();
+                event_bus.publish(Event::QueueChanged);
+            }).unwrap();
+
+        // Wake the playback thread so it releases the audio card.
+        self.playback_thread.thread().unpark();
+        self.event_bus.publish(Event::QueueChanged);
+    }
+
+    /// Move the queue of this player to the front of the queue of `target`.
+    ///
+    /// The current track continues at its position on the target, and its
+    /// listen continues too. Returns the number of entries that moved.
+    pub fn hand_off_queue(&self, target: &Player) -> usize {
+        // We don't hold both locks at the same time, so two hand-offs in
+        // opposite directions can't deadlock.
+        let entries = self.state.lock().unwrap().take_queue();
+        let n_moved = entries.len();
+        if n_moved == 0 {
+            return 0;
+        }
+
+        let needs_wake = {
+            let mut state = target.state.lock().unwrap();
+            let needs_wake = state.is_queue_empty();
+            state.receive_queue(entries);
+            needs_wake
+        };
+
+        // The playback thread of this player notices that the queue is empty,
+        // and the target has new entries to decode and play.
+        self.playback_thread.thread().unpark();
+        target.decode_thread.thread().unpark();
+        if needs_wake {
+            target.playback_thread.thread().unpark();
+        }
+        self.event_bus.publish(Event::QueueChanged);
+
+        n_moved
+    }
+
+    /// Play the queue in a browser tab instead of the audio card.
+    ///
+    /// Returns the session, the browser reports its progress for it, see
+    /// `browser_output.rs`. This replaces the cast device or browser that we
+    /// played on before, if any. When the browser stops reporting, playback
+    /// moves back to the audio card.
+    pub fn play_in_browser(&self) -> u64 {
+        let reported_at = Instant::now();
+        let session = self.state.lock().unwrap().start_casting(RemoteOutput::Browser { reported_at });
+
+        let state_mutex = self.state.clone();
+        let playback_thread = self.playback_thread.thread().clone();
+        let decode_thread = self.decode_thread.thread().clone();
+        let event_bus = self.event_bus.clone();
+        let builder = std::thread::Builder::new();
+        builder
+            .name("browser".into())
+            .spawn(move || {
+                browser_output::main(session, state_mutex);
+                playback_thread.unpark();
+                decode_thread.unpark();
+                event_bus.publish(Event::QueueChanged);
+            }).unwrap();
+
+        // Wake the playback thread so it releases the audio card.
+        self.playback_thread.thread().unpark();
+        self.event_bus.publish(Event::QueueChanged);
+
+        session
+    }
+
+    /// Handle a report of the browser output, see [`browser_output::handle_report`].
+    pub fn report_browser_output(
+        &self,
+        session: u64,
+        report: browser_output::Report,
+    ) -> Option<browser_output::Instruction> {
+        let changes_queue = matches!(
+            report,
+            browser_output::Report::Ended(..) | browser_output::Report::Failed(..)
+        );
+        let result = browser_output::handle_report(&mut self.state.lock().unwrap(), session, report);
+        if changes_queue && result.is_some() {
+            self.event_bus.publish(Event::QueueChanged);
+        }
+        result
+    }
+
+    /// Play on the audio card again, if we played in the browser of the session.
+    pub fn stop_browser_output(&self, session: u64) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.cast_session() != session || state.browser_output_silence().is_none() {
+                return false;
+            }
+            state.stop_casting();
+        }
+
+        // The watchdog thread notices that its session ended, and then wakes
+        // the playback and decode threads.
+        self.event_bus.publish(Event::QueueChanged);
+        true
+    }
+
+    /// Play on the audio card again, if we were casting.
+    pub fn stop_casting(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !state.is_casting() {
+                return;
+            }
+            state.stop_casting();
+        }
+
+        // The cast thread notices that its session ended, and then wakes the
+        // playback and decode threads.
+        self.event_bus.publish(Event::QueueChanged);
     }
 }
@@ -0,0 +1,101 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2021 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Manipulating the user-curated playlists stored in the database.
+//!
+//! Unlike the queue, which only lives in memory, playlists survive restarts.
+//! Entries are ordered by their `position` column; positions need not be
+//! contiguous, only their relative order matters.
+
+use crate::database as db;
+use crate::database::Transaction;
+use crate::prim::TrackId;
+
+/// Append a track to the end of the playlist, return the id of the new entry.
+pub fn append_track(
+    tx: &mut Transaction,
+    playlist_id: i64,
+    track_id: TrackId,
+) -> db::Result<i64> {
+    let position = db::select_playlist_next_position(tx, playlist_id)?;
+    db::insert_playlist_entry(tx, playlist_id, position, track_id.0 as i64)
+}
+
+/// Create a new playlist of the user with the given tracks, return its id.
+pub fn create_with_tracks(
+    tx: &mut Transaction,
+    name: &str,
+    created_at: &str,
+    user: Option<&str>,
+    tracks: &[TrackId],
+) -> db::Result<i64> {
+    let query = None;
+    let playlist_id = db::insert_playlist(tx, name, query, created_at, user)?;
+    for (position, track_id) in tracks.iter().enumerate() {
+        db::insert_playlist_entry(tx, playlist_id, position as i64, track_id.0 as i64)?;
+    }
+    Ok(playlist_id)
+}
+
+/// Move the element at index `from` so it ends up at index `to`.
+///
+/// When `to` is past the end, the element moves to the end.
+fn move_element<T>(xs: &mut Vec<T>, from: usize, to: usize) {
+    let x = xs.remove(from);
+    let to = to.min(xs.len());
+    xs.insert(to, x);
+}
+
+/// Move the entry to 0-based position `to` in the playlist.
+///
+/// Renumbers all entries of the playlist. Returns false if the playlist has
+/// no such entry.
+pub fn move_entry(
+    tx: &mut Transaction,
+    playlist_id: i64,
+    entry_id: i64,
+    to: usize,
+) -> db::Result<bool> {
+    let mut entries = Vec::new();
+    for entry in db::iter_playlist_entries(tx, playlist_id)? {
+        entries.push(entry?.id);
+    }
+
+    let from = match entries.iter().position(|&id| id == entry_id) {
+        Some(i) => i,
+        None => return Ok(false),
+    };
+
+    move_element(&mut entries, from, to);
+
+    for (position, &id) in entries.iter().enumerate() {
+        db::update_playlist_entry_position(tx, id, position as i64)?;
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::move_element;
+
+    #[test]
+    fn move_element_moves_forward_and_backward() {
+        let mut xs = vec![0, 1, 2, 3];
+        move_element(&mut xs, 0, 2);
+        assert_eq!(xs, [1, 2, 0, 3]);
+        move_element(&mut xs, 3, 0);
+        assert_eq!(xs, [3, 1, 2, 0]);
+    }
+
+    #[test]
+    fn move_element_clamps_to_end() {
+        let mut xs = vec![0, 1, 2];
+        move_element(&mut xs, 1, 100);
+        assert_eq!(xs, [0, 2, 1]);
+    }
+}
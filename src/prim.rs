@@ -73,6 +73,11 @@ pub struct AlbumId(pub u64);
 #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct ArtistId(pub u64);
 
+/// The MusicBrainz "Various Artists" artist, 89ad4ac3-39f7-470e-963a-56509c546377.
+///
+/// Compilations belong to this artist, see `BuildMetaIndex::insert_full`.
+pub const VARIOUS_ARTISTS: ArtistId = ArtistId(0x89ad4ac3_9c546377);
+
 /// Index into a byte array that contains length-prefixed strings.
 #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct StringRef(pub u32);
@@ -308,6 +313,13 @@ impl Instant {
         let use_z = true;
         self.to_datetime().to_rfc3339_opts(SecondsFormat::Secs, use_z)
     }
+
+    /// Return the offset from UTC of the local time zone at this instant.
+    pub fn local_utc_offset_seconds(&self) -> i64 {
+        use chrono::{Local, Offset, TimeZone};
+        let offset = Local.offset_from_utc_datetime(&self.to_datetime().naive_utc());
+        offset.fix().local_minus_utc() as i64
+    }
 }
 
 /// Indices of the album artist in the album artist array.
@@ -329,9 +341,10 @@ pub struct Album {
     pub original_release_date: Date,
     pub loudness: Option<Lufs>,
 
-    /// First time that we encountered this album, can be either:
-    /// * The minimal `mtime` across the files in the album.
-    /// * The first play of one of the tracks in the album. (TODO)
+    /// First time that we encountered this album, the minimum of:
+    /// * The minimal `imported_at` across the files in the album.
+    /// * The import time recorded in the `album_imports` table, if any.
+    /// * The first play of one of the tracks in the album.
     pub first_seen: Instant,
 }
 
@@ -0,0 +1,179 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Helpers for running behind a reverse proxy, and for cross-origin requests.
+
+use std::net::IpAddr;
+
+use tiny_http::Header;
+
+/// Normalize a `base_path` from the config, such as `/music/`, to `/music`.
+///
+/// The root is the empty string, so urls can be formed by appending a path
+/// that starts with a slash.
+pub fn parse_base_path(value: &str) -> Result<String, &'static str> {
+    if !value.starts_with('/') {
+        return Err("Invalid base_path value, must start with '/'.");
+    }
+    let valid = value
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"/-._~".contains(&b));
+    if !valid {
+        return Err("Invalid base_path value, must be a path of letters, digits, and -._~ only.");
+    }
+    Ok(value.trim_end_matches('/').to_string())
+}
+
+/// Remove the base path from the path of a request, if it starts with it.
+///
+/// Some proxies strip the prefix before they forward the request and some
+/// don't, so we accept paths both with and without the base path.
+pub fn strip_base_path<'a>(base_path: &str, path: &'a str) -> &'a str {
+    if base_path.is_empty() {
+        return path;
+    }
+    match path.strip_prefix(base_path) {
+        Some("") => "/",
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
+fn get_header<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+/// Return the address of the client that made the request.
+///
+/// When the request comes from a trusted proxy, the client is the last
+/// address in `X-Forwarded-For` that is not itself a trusted proxy. Addresses
+/// before that could have been set by anybody, so we don't look at them.
+pub fn client_ip(peer: IpAddr, headers: &[Header], trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+    let forwarded_for = match get_header(headers, "X-Forwarded-For") {
+        Some(value) => value,
+        None => return peer,
+    };
+    let mut client = peer;
+    for part in forwarded_for.rsplit(',') {
+        match part.trim().parse::<IpAddr>() {
+            Ok(addr) => {
+                client = addr;
+                if !trusted_proxies.contains(&addr) {
+                    break;
+                }
+            }
+            // If the header is malformed, we can't tell who the client is,
+            // the proxy is the best we know.
+            Err(..) => return peer,
+        }
+    }
+    client
+}
+
+/// Return whether a trusted proxy received the request over https.
+pub fn is_forwarded_https(peer: IpAddr, headers: &[Header], trusted_proxies: &[IpAddr]) -> bool {
+    if !trusted_proxies.contains(&peer) {
+        return false;
+    }
+    // With multiple proxies, the first one is the one that the client talks to.
+    match get_header(headers, "X-Forwarded-Proto") {
+        Some(value) => value.split(',').next().map(|p| p.trim()) == Some("https"),
+        None => false,
+    }
+}
+
+/// Return the `Access-Control-Allow-Origin` value for a request, if any.
+///
+/// The allowed origins come from the `cors_origin` lines in the config, where
+/// `*` allows any origin.
+pub fn cors_allow_origin<'a>(allowed: &[String], headers: &'a [Header]) -> Option<&'a str> {
+    let origin = get_header(headers, "Origin")?;
+    let is_allowed = allowed
+        .iter()
+        .any(|a| a == "*" || a.trim_end_matches('/') == origin);
+    if is_allowed {
+        Some(origin)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use tiny_http::Header;
+
+    use super::{client_ip, cors_allow_origin, is_forwarded_https, parse_base_path, strip_base_path};
+
+    fn header(name: &str, value: &str) -> Header {
+        Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        IpAddr::from_str(addr).unwrap()
+    }
+
+    #[test]
+    fn parse_base_path_normalizes_trailing_slash() {
+        assert_eq!(parse_base_path("/").unwrap(), "");
+        assert_eq!(parse_base_path("/music").unwrap(), "/music");
+        assert_eq!(parse_base_path("/music/").unwrap(), "/music");
+        assert_eq!(parse_base_path("/apps/music-2/").unwrap(), "/apps/music-2");
+        assert!(parse_base_path("music").is_err());
+        assert!(parse_base_path("/music?x").is_err());
+        assert!(parse_base_path("/mu\"sic").is_err());
+    }
+
+    #[test]
+    fn strip_base_path_accepts_urls_with_and_without_prefix() {
+        assert_eq!(strip_base_path("", "/api/queue"), "/api/queue");
+        assert_eq!(strip_base_path("/music", "/music/api/queue"), "/api/queue");
+        assert_eq!(strip_base_path("/music", "/api/queue"), "/api/queue");
+        assert_eq!(strip_base_path("/music", "/music"), "/");
+        assert_eq!(strip_base_path("/music", "/music/"), "/");
+        assert_eq!(strip_base_path("/music", "/musicals/x"), "/musicals/x");
+    }
+
+    #[test]
+    fn client_ip_only_trusts_forwarded_for_from_trusted_proxies() {
+        let proxies = [ip("127.0.0.1"), ip("10.0.0.2")];
+        let headers = [header("X-Forwarded-For", "203.0.113.7, 198.51.100.3, 10.0.0.2")];
+        assert_eq!(client_ip(ip("127.0.0.1"), &headers, &proxies), ip("198.51.100.3"));
+        assert_eq!(client_ip(ip("192.0.2.1"), &headers, &proxies), ip("192.0.2.1"));
+        assert_eq!(client_ip(ip("127.0.0.1"), &[], &proxies), ip("127.0.0.1"));
+
+        let garbage = [header("X-Forwarded-For", "not an address")];
+        assert_eq!(client_ip(ip("127.0.0.1"), &garbage, &proxies), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn is_forwarded_https_only_trusts_trusted_proxies() {
+        let proxies = [ip("::1")];
+        let headers = [header("X-Forwarded-Proto", "https")];
+        assert!(is_forwarded_https(ip("::1"), &headers, &proxies));
+        assert!(!is_forwarded_https(ip("192.0.2.1"), &headers, &proxies));
+        assert!(!is_forwarded_https(ip("::1"), &[header("X-Forwarded-Proto", "http")], &proxies));
+    }
+
+    #[test]
+    fn cors_allow_origin_matches_configured_origins() {
+        let allowed = vec!["https://music.example.com/".to_string()];
+        let headers = [header("Origin", "https://music.example.com")];
+        assert_eq!(cors_allow_origin(&allowed, &headers), Some("https://music.example.com"));
+        assert_eq!(cors_allow_origin(&allowed, &[header("Origin", "https://evil.example")]), None);
+        assert_eq!(cors_allow_origin(&allowed, &[]), None);
+        assert_eq!(cors_allow_origin(&["*".to_string()], &headers), Some("https://music.example.com"));
+    }
+}
@@ -0,0 +1,379 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Playing internet radio streams.
+//!
+//! A radio station is the url of an Icecast or Shoutcast stream, or any other
+//! http stream of mp3, aac, or opus. Like for transcoding, we leave the codecs
+//! to `ffmpeg`: `curl` fetches the stream, a pump thread strips the
+//! ICY metadata from it and feeds the audio to `ffmpeg`, and the
+//! decoder reads 16-bit 44.1 kHz samples from the `ffmpeg` output.
+//!
+//! Stations announce the title that is playing in the ICY
+//! metadata. When the client asks for it with an `Icy-MetaData: 1` header, the
+//! server inserts a metadata block after every `icy-metaint` bytes of audio.
+//! The block starts with one byte that holds its length divided by 16,
+//! followed by a string like `StreamTitle='Artist - Title';`, padded with
+//! zeros.
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::error::{Error, Result};
+use crate::prim::Hertz;
+use crate::Lufs;
+
+/// The sample rate that we ask `ffmpeg` to output.
+pub const SAMPLE_RATE: Hertz = Hertz(44_100);
+
+/// Return the loudness that we assume for radio streams.
+///
+/// We can't measure the loudness of a stream ahead of time. Most stations
+/// are mastered loud, and streaming services normalize to about -14 LUFS, so
+/// we assume that, which is louder than nearly all albums.
+pub fn assumed_loudness() -> Lufs {
+    Lufs::new(-1400)
+}
+
+/// A radio station that can be enqueued.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Station {
+    pub id: i64,
+    pub name: String,
+    pub url: String,
+}
+
+/// Check that the url is one that we can stream from.
+pub fn validate_url(url: &str) -> std::result::Result<(), &'static str> {
+    let is_http = url.starts_with("http://") || url.starts_with("https://");
+    if !is_http {
+        return Err("Invalid station url, must start with http:// or https://.");
+    }
+    // We pass the url to curl as an argument, it should not contain anything
+    // that curl could mistake for something else.
+    if url.chars().any(|ch| ch.is_whitespace() || ch.is_control()) {
+        return Err("Invalid station url, must not contain whitespace.");
+    }
+    Ok(())
+}
+
+/// Extract the title from an ICY metadata block.
+///
+/// Returns `None` when the block has no `StreamTitle` field. Stations that
+/// don't specify the encoding usually send Latin-1, so if the block is not
+/// valid UTF-8, we decode it as Latin-1.
+pub fn parse_stream_title(block: &[u8]) -> Option<String> {
+    let end = block.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    let block = &block[..end];
+    let text = match std::str::from_utf8(block) {
+        Ok(s) => s.to_string(),
+        Err(..) => block.iter().map(|b| *b as char).collect(),
+    };
+    let prefix = "StreamTitle='";
+    let start = text.find(prefix)? + prefix.len();
+    // The title itself may contain quotes, it ends at the quote that is
+    // followed by a semicolon, or at the end of the block.
+    let len = text[start..].find("';").unwrap_or(text.len() - start);
+    let title = text[start..start + len].trim_end_matches('\'').trim();
+    Some(title.to_string())
+}
+
+/// Read the response headers that `curl --include` prints, return the metaint.
+///
+/// When following redirects, curl prints the headers of every response, so we
+/// skip over redirects and informational responses. Returns the value of the
+/// `icy-metaint` header of the final response, if there is one.
+pub fn read_response_headers<R: BufRead>(r: &mut R) -> io::Result<Option<usize>> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 {
+            let err = io::Error::new(io::ErrorKind::UnexpectedEof, "Stream ended before the response.");
+            return Err(err);
+        }
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| u16::from_str(s).ok());
+        let status = match status {
+            Some(s) => s,
+            None => {
+                let err = io::Error::new(io::ErrorKind::InvalidData, "Invalid status line.");
+                return Err(err);
+            }
+        };
+
+        let mut metaint = None;
+        loop {
+            line.clear();
+            if r.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            let mut parts = line.splitn(2, ':');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                if name.trim().eq_ignore_ascii_case("icy-metaint") {
+                    metaint = usize::from_str(value.trim()).ok().filter(|n| *n > 0);
+                }
+            }
+        }
+
+        match status {
+            100..=199 | 300..=399 => continue,
+            200..=299 => return Ok(metaint),
+            _ => {
+                let err = io::Error::new(io::ErrorKind::Other, format!("Station responded with {}.", status));
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Strips ICY metadata from a stream, and tracks the title.
+pub struct IcyReader<R> {
+    inner: R,
+
+    /// The number of audio bytes between metadata blocks, if the stream has any.
+    metaint: Option<usize>,
+
+    /// The number of audio bytes left until the next metadata block.
+    until_metadata: usize,
+
+    /// The latest title that the station announced.
+    title: Arc<Mutex<Option<String>>>,
+}
+
+impl<R: Read> IcyReader<R> {
+    pub fn new(inner: R, metaint: Option<usize>, title: Arc<Mutex<Option<String>>>) -> IcyReader<R> {
+        IcyReader {
+            inner: inner,
+            metaint: metaint,
+            until_metadata: metaint.unwrap_or(0),
+            title: title,
+        }
+    }
+
+    /// Read a metadata block, return false if the stream ended.
+    fn read_metadata(&mut self) -> io::Result<bool> {
+        let mut len = [0_u8];
+        if self.inner.read(&mut len)? == 0 {
+            return Ok(false);
+        }
+        // Most blocks are empty, the station only sends the title when it
+        // changes.
+        if len[0] > 0 {
+            let mut block = vec![0_u8; len[0] as usize * 16];
+            self.inner.read_exact(&mut block)?;
+            if let Some(title) = parse_stream_title(&block) {
+                let title = if title.is_empty() { None } else { Some(title) };
+                *self.title.lock().unwrap() = title;
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for IcyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let metaint = match self.metaint {
+            Some(n) => n,
+            None => return self.inner.read(buf),
+        };
+        if self.until_metadata == 0 {
+            if !self.read_metadata()? {
+                return Ok(0);
+            }
+            self.until_metadata = metaint;
+        }
+        let n = buf.len().min(self.until_metadata);
+        let n_read = self.inner.read(&mut buf[..n])?;
+        self.until_metadata -= n_read;
+        Ok(n_read)
+    }
+}
+
+/// Reads decoded samples of a radio stream from `ffmpeg`.
+///
+/// Both child processes are killed when the reader is dropped, for example
+/// because the station was skipped.
+pub struct RadioReader {
+    curl: Child,
+    ffmpeg: Child,
+    stdout: ChildStdout,
+    title: Arc<Mutex<Option<String>>>,
+}
+
+impl RadioReader {
+    /// Return the latest title that the station announced.
+    pub fn stream_title(&self) -> Option<String> {
+        self.title.lock().unwrap().clone()
+    }
+}
+
+impl Read for RadioReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Drop for RadioReader {
+    fn drop(&mut self) {
+        // When the children exited already, killing fails, which is fine.
+        // Killing curl makes the pump thread exit too.
+        let _ = self.curl.kill();
+        let _ = self.ffmpeg.kill();
+        let _ = self.curl.wait();
+        let _ = self.ffmpeg.wait();
+    }
+}
+
+/// Start streaming from the station, return the reader for the samples.
+///
+/// This blocks until the station responded.
+pub fn open_stream(station: &Station) -> Result<RadioReader> {
+    let mut curl = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--location", "--include"])
+        .args(["--connect-timeout", "10"])
+        .args(["--header", "Icy-MetaData: 1"])
+        .arg(&station.url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::CommandError("Failed to spawn 'curl'.", e))?;
+
+    let curl_stdout = curl.stdout.take().expect("Stdout should be there, we piped it.");
+    let mut response = BufReader::new(curl_stdout);
+    let metaint = match read_response_headers(&mut response) {
+        Ok(n) => n,
+        Err(err) => {
+            let _ = curl.kill();
+            let _ = curl.wait();
+            return Err(Error::RadioError(format!("{}: {}", station.url, err)));
+        }
+    };
+
+    let mut ffmpeg = match Command::new("ffmpeg")
+        .args(["-nostdin", "-loglevel", "error"])
+        .args(["-i", "pipe:0"])
+        .args(["-map", "0:a"])
+        .args(["-f", "s16le", "-ac", "2"])
+        .arg("-ar")
+        .arg(SAMPLE_RATE.0.to_string())
+        .arg("pipe:1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            let _ = curl.kill();
+            let _ = curl.wait();
+            return Err(Error::CommandError("Failed to spawn 'ffmpeg'.", err));
+        }
+    };
+
+    let mut ffmpeg_stdin = ffmpeg.stdin.take().expect("Stdin should be there, we piped it.");
+    let stdout = ffmpeg.stdout.take().expect("Stdout should be there, we piped it.");
+    let title = Arc::new(Mutex::new(None));
+    let mut icy_reader = IcyReader::new(response, metaint, title.clone());
+
+    // The pump thread ends when curl exits, either because the stream ended,
+    // or because the reader was dropped. Dropping stdin then makes ffmpeg
+    // finish too.
+    let builder = thread::Builder::new();
+    builder
+        .name("radio_pump".into())
+        .spawn(move || {
+            if let Err(err) = io::copy(&mut icy_reader, &mut ffmpeg_stdin) {
+                if err.kind() != io::ErrorKind::BrokenPipe {
+                    error!("Error while reading radio stream: {}", err);
+                }
+            }
+            let _ = ffmpeg_stdin.flush();
+        })
+        .map_err(|e| Error::CommandError("Failed to spawn radio pump thread.", e))?;
+
+    let reader = RadioReader {
+        curl: curl,
+        ffmpeg: ffmpeg,
+        stdout: stdout,
+        title: title,
+    };
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_stream_title, read_response_headers, validate_url, IcyReader};
+    use std::io::{Cursor, Read};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn parse_stream_title_handles_padding_and_encodings() {
+        let block = b"StreamTitle='Muse - Uprising';StreamUrl='';\0\0\0\0";
+        assert_eq!(parse_stream_title(block), Some("Muse - Uprising".to_string()));
+
+        let block = b"StreamTitle='Sigur R\xf3s - Hopp\xedpolla';\0";
+        assert_eq!(parse_stream_title(block), Some("Sigur Rós - Hoppípolla".to_string()));
+
+        let block = b"StreamTitle='Don't Stop Me Now';\0\0";
+        assert_eq!(parse_stream_title(block), Some("Don't Stop Me Now".to_string()));
+
+        assert_eq!(parse_stream_title(b"StreamTitle='';\0\0"), Some("".to_string()));
+        assert_eq!(parse_stream_title(b"StreamUrl='http://example.com';"), None);
+    }
+
+    #[test]
+    fn read_response_headers_skips_redirects() {
+        let response = b"HTTP/1.1 302 Found\r\n\
+            Location: http://stream.example.com/live\r\n\
+            \r\n\
+            ICY 200 OK\r\n\
+            Content-Type: audio/mpeg\r\n\
+            icy-metaint: 16000\r\n\
+            \r\n\
+            audio";
+        let mut r = Cursor::new(&response[..]);
+        assert_eq!(read_response_headers(&mut r).unwrap(), Some(16000));
+        let mut body = String::new();
+        r.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "audio");
+
+        let mut r = Cursor::new(&b"HTTP/1.1 404 Not Found\r\n\r\n"[..]);
+        assert!(read_response_headers(&mut r).is_err());
+    }
+
+    #[test]
+    fn icy_reader_strips_metadata() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(b"abcd");
+        stream.push(2);
+        let mut block = b"StreamTitle='One';".to_vec();
+        block.resize(32, 0);
+        stream.extend_from_slice(&block);
+        stream.extend_from_slice(b"efgh");
+        stream.push(0);
+        stream.extend_from_slice(b"ij");
+
+        let title = Arc::new(Mutex::new(None));
+        let mut reader = IcyReader::new(Cursor::new(stream), Some(4), title.clone());
+        let mut audio = String::new();
+        reader.read_to_string(&mut audio).unwrap();
+        assert_eq!(audio, "abcdefghij");
+        assert_eq!(*title.lock().unwrap(), Some("One".to_string()));
+    }
+
+    #[test]
+    fn validate_url_requires_http() {
+        assert!(validate_url("https://stream.example.com/live.mp3").is_ok());
+        assert!(validate_url("file:///etc/passwd").is_err());
+        assert!(validate_url("http://example.com/a b").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Reading files with a timeout, for libraries on a network mount.
+//!
+//! When the server of an NFS or SMB mount goes away, reads from the mount can
+//! block for minutes, or, for a hard mount, until the server is back. There is
+//! no way to interrupt a blocking read, so a thread reads the file, and sends
+//! the data to the reader in chunks. When no chunk arrives within the timeout,
+//! the read fails, and the decoder can move on. The reading thread stays
+//! blocked until the mount recovers, then it notices that the reader is gone,
+//! and exits.
+
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Read the file in chunks of this many bytes.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Read end of a file that a background thread reads.
+pub struct TimeoutReader {
+    /// Chunks of the file, an empty chunk marks the end of the file.
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: io::Cursor<Vec<u8>>,
+    timeout: Duration,
+    is_eof: bool,
+}
+
+impl Read for TimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() || self.is_eof {
+                return Ok(n);
+            }
+            match self.receiver.recv_timeout(self.timeout) {
+                Ok(Ok(chunk)) if chunk.is_empty() => self.is_eof = true,
+                Ok(Ok(chunk)) => self.chunk = io::Cursor::new(chunk),
+                Ok(Err(err)) => return Err(err),
+                Err(RecvTimeoutError::Timeout) => {
+                    let msg = format!("No data after {} seconds.", self.timeout.as_secs());
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, msg));
+                }
+                // The thread exits after it sent an error, we already returned
+                // that error before.
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, "Reading the file failed before."));
+                }
+            }
+        }
+    }
+}
+
+/// Open a file, and `fadvise` that we will read it entirely.
+///
+/// In the decoder we might sometimes open a file, then decode it partially
+/// (because our buffer is full), and then resume decoding only a long time
+/// later. Possibly a disk will spin down. Tell the kernel that we are going to
+/// want the entire thing, so we can later finish decoding without having to
+/// spin the disk up again (for this particular file).
+///
+/// A related scenario that sometimes happens is that the disk is spun down, but
+/// a part of a file is still cached in the page cache. Then if we play it,
+/// playback starts immediately, but then gets stuck half-way because the
+/// decoder is waiting for the rest of the file. There too, it can help to tell
+/// the kernel early that we will need the entire thing.
+fn open_with_readahead(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::io::AsRawFd;
+    let file = fs::File::open(path)?;
+    let fd = file.as_raw_fd();
+    let offset = 0;
+    let len = file.metadata()?.len() as libc::off64_t;
+    unsafe {
+        let _ = libc::posix_fadvise64(fd, offset, len, libc::POSIX_FADV_SEQUENTIAL);
+        let _ = libc::posix_fadvise64(fd, offset, len, libc::POSIX_FADV_WILLNEED);
+    }
+    Ok(file)
+}
+
+/// Read the file into the channel, until the end, an error, or until the reader is gone.
+fn read_main(path: PathBuf, sender: SyncSender<io::Result<Vec<u8>>>) {
+    // Opening can block too, so that happens on this thread as well.
+    let mut file = match open_with_readahead(&path) {
+        Ok(f) => f,
+        Err(err) => {
+            let _ = sender.send(Err(err));
+            return;
+        }
+    };
+
+    loop {
+        let mut chunk = vec![0_u8; CHUNK_SIZE];
+        let result = match file.read(&mut chunk) {
+            Ok(n) => {
+                chunk.truncate(n);
+                Ok(chunk)
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => Err(err),
+        };
+        let is_last = match &result {
+            Ok(chunk) => chunk.is_empty(),
+            Err(..) => true,
+        };
+        if sender.send(result).is_err() || is_last {
+            return;
+        }
+    }
+}
+
+/// Open the file for reading on a background thread.
+///
+/// Errors, including the error for opening the file, surface on the first
+/// read. A read fails when no data arrived within `timeout`.
+pub fn open<P: AsRef<Path>>(path: P, timeout: Duration) -> TimeoutReader {
+    // Allow a few chunks in flight, so reading and decoding can overlap.
+    let (sender, receiver) = mpsc::sync_channel(4);
+    let path = path.as_ref().to_path_buf();
+    thread::Builder::new()
+        .name("file-reader".into())
+        .spawn(move || read_main(path, sender))
+        .expect("Failed to spawn file reader thread.");
+    TimeoutReader {
+        receiver: receiver,
+        chunk: io::Cursor::new(Vec::new()),
+        timeout: timeout,
+        is_eof: false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::time::Duration;
+
+    #[test]
+    fn open_reads_the_entire_file() {
+        let mut reader = super::open("Cargo.toml", Duration::from_secs(10));
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, std::fs::read_to_string("Cargo.toml").unwrap());
+    }
+
+    #[test]
+    fn open_reports_missing_file_on_read() {
+        let mut reader = super::open("does/not/exist.flac", Duration::from_secs(10));
+        let mut buf = [0_u8; 16];
+        let err = reader.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+}
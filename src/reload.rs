@@ -0,0 +1,127 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Reloading the settings that can change without a restart, on SIGHUP.
+//!
+//! Most settings are baked into the threads and the index at startup, but a
+//! few can be swapped safely: the log level, the Last.fm credentials, and the
+//! number of thumbnail generation threads. On SIGHUP, or when an admin posts
+//! to `/api/config/reload`, we read the config file again, and apply the
+//! changes to those. When TLS is enabled, SIGHUP reloads the certificate too.
+//!
+//! The server thread waits for SIGHUP with `sigwait`, which requires SIGHUP to
+//! be blocked in all threads, so we block it before any threads are spawned.
+
+use std::mem;
+use std::ptr;
+
+use crate::config::Config;
+use crate::log;
+use crate::scrobble::Credentials;
+
+/// The settings that we can change without a restart.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Settings {
+    pub log_level: log::Filter,
+    pub log_format: log::Format,
+    pub lastfm_credentials: Option<Credentials>,
+    pub thumbnail_threads: Option<usize>,
+}
+
+impl Settings {
+    pub fn from_config(config: &Config) -> Settings {
+        Settings {
+            log_level: config.log_level.clone(),
+            log_format: config.log_format,
+            lastfm_credentials: Credentials::from_config(config),
+            thumbnail_threads: config.thumbnail_threads,
+        }
+    }
+
+    /// Return the config keys of the settings that differ from `other`.
+    pub fn changed_keys(&self, other: &Settings) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        if self.log_level != other.log_level {
+            keys.push("log_level");
+        }
+        if self.log_format != other.log_format {
+            keys.push("log_format");
+        }
+        if self.lastfm_credentials != other.lastfm_credentials {
+            keys.push("lastfm_credentials");
+        }
+        if self.thumbnail_threads != other.thumbnail_threads {
+            keys.push("thumbnail_threads");
+        }
+        keys
+    }
+}
+
+fn sighup_set() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    }
+}
+
+/// Block SIGHUP for this thread, and for threads spawned from it afterwards.
+///
+/// Call this before spawning any threads, otherwise SIGHUP may be delivered to
+/// a thread that does not block it, and its default action terminates us.
+pub fn block_sighup() {
+    let set = sighup_set();
+    let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    assert_eq!(result, 0, "Failed to block SIGHUP.");
+}
+
+/// Block until the process receives SIGHUP.
+pub fn wait_for_sighup() {
+    let set = sighup_set();
+    let mut signal: libc::c_int = 0;
+    loop {
+        let result = unsafe { libc::sigwait(&set, &mut signal) };
+        if result == 0 && signal == libc::SIGHUP {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Settings;
+    use crate::config::Config;
+
+    #[test]
+    fn changed_keys_lists_only_reloadable_changes() {
+        let mut config_lines = vec![
+            "library_path = /home/user/music",
+            "db_path = /home/user/.local/share/musium/db.sqlite3",
+            "audio_device = UCM404HD 192k",
+            "audio_volume_control = UMC404HD 192k Output",
+            "unauthenticated = true",
+        ];
+        let before = Settings::from_config(&Config::parse(&config_lines).unwrap());
+
+        // A change that needs a restart is not reported.
+        config_lines.push("search_max_edits = 2");
+        let after = Settings::from_config(&Config::parse(&config_lines).unwrap());
+        assert!(before.changed_keys(&after).is_empty());
+
+        config_lines.push("log_level = info,scan=debug");
+        config_lines.push("thumbnail_threads = 2");
+        config_lines.push("lastfm_api_key = key");
+        config_lines.push("lastfm_api_secret = secret");
+        config_lines.push("lastfm_session_key = session");
+        let after = Settings::from_config(&Config::parse(&config_lines).unwrap());
+        assert_eq!(
+            before.changed_keys(&after),
+            vec!["log_level", "lastfm_credentials", "thumbnail_threads"],
+        );
+    }
+}
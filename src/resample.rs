@@ -0,0 +1,283 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Sample rate conversion, and the policy for when to do it.
+//!
+//! Tracks come in different sample rates. By default, we reopen the audio card
+//! at the rate of every track, which causes a brief gap when the rate changes.
+//! The other policies resample in the decoder instead, so consecutive tracks
+//! have the same format, and playback is gapless.
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::player::Format;
+use crate::prim::{AlbumId, Hertz};
+use crate::MetaIndex;
+
+/// The number of input frames on either side of an output frame that the
+/// resampling filter looks at.
+const TAPS: usize = 16;
+
+/// The number of fractional positions for which we tabulate the filter.
+const PHASES: usize = 256;
+
+/// What to do when consecutive tracks have different sample rates.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SampleRatePolicy {
+    /// Reopen the audio card at the rate of every track.
+    Switch,
+
+    /// Resample everything to `fixed_sample_rate`, in 24 bits.
+    Fixed,
+
+    /// Play every album at the highest sample rate and bit depth among its
+    /// tracks, resample the tracks that have a lower rate.
+    Album,
+}
+
+impl FromStr for SampleRatePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<SampleRatePolicy, &'static str> {
+        match s {
+            "switch" => Ok(SampleRatePolicy::Switch),
+            "fixed" => Ok(SampleRatePolicy::Fixed),
+            "album" => Ok(SampleRatePolicy::Album),
+            _ => Err("Invalid sample_rate_policy value, must be one of switch, fixed, album."),
+        }
+    }
+}
+
+impl fmt::Display for SampleRatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SampleRatePolicy::Switch => write!(f, "switch"),
+            SampleRatePolicy::Fixed => write!(f, "fixed"),
+            SampleRatePolicy::Album => write!(f, "album"),
+        }
+    }
+}
+
+/// Return the format to play a track in.
+///
+/// `album_format` is the highest format of the album of the track, if we know
+/// it, it only matters for the album policy.
+pub fn get_output_format(
+    policy: SampleRatePolicy,
+    fixed_sample_rate: Hertz,
+    format: Format,
+    album_format: Option<Format>,
+) -> Format {
+    match (policy, album_format) {
+        (SampleRatePolicy::Switch, _) => format,
+        (SampleRatePolicy::Fixed, _) => Format {
+            sample_rate: fixed_sample_rate,
+            bits_per_sample: 24,
+        },
+        // The file may have changed since we looked at the album, we never
+        // go below the format of the track itself.
+        (SampleRatePolicy::Album, Some(album)) => Format {
+            sample_rate: Hertz(album.sample_rate.0.max(format.sample_rate.0)),
+            bits_per_sample: album.bits_per_sample.max(format.bits_per_sample),
+        },
+        (SampleRatePolicy::Album, None) => format,
+    }
+}
+
+/// Return the highest sample rate and bit depth among the tracks of the album.
+///
+/// This reads the header of every file, it returns `None` when none of them
+/// can be read.
+pub fn get_album_format(index: &dyn MetaIndex, album_id: AlbumId) -> Option<Format> {
+    let mut result: Option<Format> = None;
+    for kv in index.get_album_tracks(album_id) {
+        let opts = claxon::FlacReaderOptions {
+            metadata_only: true,
+            read_picture: claxon::ReadPicture::Skip,
+            read_vorbis_comment: false,
+        };
+        let fname = index.get_filename(kv.track.filename);
+        let streaminfo = match claxon::FlacReader::open_ext(fname, opts) {
+            Ok(reader) => reader.streaminfo(),
+            Err(err) => {
+                warn!("Failed to read the format of {:?}: {:?}", fname, err);
+                continue;
+            }
+        };
+        let format = Format {
+            sample_rate: Hertz(streaminfo.sample_rate),
+            bits_per_sample: streaminfo.bits_per_sample,
+        };
+        result = Some(match result {
+            None => format,
+            Some(r) => Format {
+                sample_rate: Hertz(r.sample_rate.0.max(format.sample_rate.0)),
+                bits_per_sample: r.bits_per_sample.max(format.bits_per_sample),
+            },
+        });
+    }
+    result
+}
+
+/// Converts stereo frames from one sample rate to another.
+///
+/// This is a windowed sinc filter. The resampler keeps the last input frames,
+/// so a stream that is fed in chunks converts as one continuous signal.
+pub struct Resampler {
+    /// Input frames per output frame.
+    step: f64,
+
+    /// Windowed sinc filter, tabulated for `PHASES + 1` fractional positions.
+    ///
+    /// Empty when the input rate equals the output rate.
+    kernel: Vec<[f32; 2 * TAPS]>,
+
+    /// Input frames that we still need for upcoming output frames.
+    history: Vec<[f32; 2]>,
+
+    /// Position of the next output frame in `history`, in input frames.
+    pos: f64,
+}
+
+impl Resampler {
+    pub fn new(from: Hertz, to: Hertz) -> Resampler {
+        let step = from.0 as f64 / to.0 as f64;
+        let mut resampler = Resampler {
+            step: step,
+            kernel: Vec::new(),
+            history: Vec::new(),
+            pos: 0.0,
+        };
+
+        if from == to {
+            return resampler;
+        }
+
+        // When we downsample, the cutoff must be below the new Nyquist
+        // frequency to avoid aliasing. Leave a bit of room for the transition
+        // band, there is nothing audible up there anyway.
+        let cutoff = 0.95 * (1.0 / step).min(1.0);
+        for phase in 0..=PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            let mut taps = [0.0; 2 * TAPS];
+            for (i, tap) in taps.iter_mut().enumerate() {
+                // Distance from the output position to input frame i.
+                let x = i as f64 - (TAPS - 1) as f64 - frac;
+                let sinc = if x == 0.0 { 1.0 } else { (PI * cutoff * x).sin() / (PI * cutoff * x) };
+                // Blackman window over the width of the filter.
+                let w = (x / TAPS as f64 + 1.0) * 0.5;
+                let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+                *tap = (cutoff * sinc * window) as f32;
+            }
+            resampler.kernel.push(taps);
+        }
+
+        // Start with silence before the first frame, so the first output frame
+        // lines up with the first input frame.
+        resampler.history.resize(TAPS - 1, [0.0, 0.0]);
+        resampler.pos = (TAPS - 1) as f64;
+        resampler
+    }
+
+    /// Feed input frames, call `output` for every output frame.
+    pub fn process<I, F>(&mut self, frames: I, mut output: F)
+    where
+        I: Iterator<Item = [f32; 2]>,
+        F: FnMut([f32; 2]),
+    {
+        if self.kernel.is_empty() {
+            frames.for_each(output);
+            return;
+        }
+
+        self.history.extend(frames);
+
+        // We can produce an output frame when all frames under the filter are
+        // available.
+        while (self.pos as usize) + TAPS < self.history.len() {
+            let start = self.pos as usize + 1 - TAPS;
+            let phase_f = (self.pos - self.pos.floor()) * PHASES as f64;
+            let phase = phase_f as usize;
+            let t = (phase_f - phase as f64) as f32;
+            let (k0, k1) = (&self.kernel[phase], &self.kernel[phase + 1]);
+
+            let mut frame = [0.0, 0.0];
+            for i in 0..2 * TAPS {
+                let k = k0[i] + (k1[i] - k0[i]) * t;
+                let input = self.history[start + i];
+                frame[0] += k * input[0];
+                frame[1] += k * input[1];
+            }
+            output(frame);
+            self.pos += self.step;
+        }
+
+        // Drop the frames that no future output frame needs.
+        let n_drop = (self.pos as usize + 1).saturating_sub(TAPS).min(self.history.len());
+        self.history.drain(..n_drop);
+        self.pos -= n_drop as f64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use crate::player::Format;
+    use crate::prim::Hertz;
+    use super::{Resampler, SampleRatePolicy, get_output_format};
+
+    fn format(sample_rate: u32, bits_per_sample: u32) -> Format {
+        Format {
+            sample_rate: Hertz(sample_rate),
+            bits_per_sample: bits_per_sample,
+        }
+    }
+
+    #[test]
+    fn sample_rate_policy_parses() {
+        assert_eq!(SampleRatePolicy::from_str("switch"), Ok(SampleRatePolicy::Switch));
+        assert_eq!(SampleRatePolicy::from_str("album"), Ok(SampleRatePolicy::Album));
+        assert!(SampleRatePolicy::from_str("Fixed").is_err());
+    }
+
+    #[test]
+    fn get_output_format_follows_policy() {
+        let track = format(44_100, 16);
+        let album = Some(format(96_000, 24));
+        let fixed = Hertz(48_000);
+        assert_eq!(get_output_format(SampleRatePolicy::Switch, fixed, track, album), track);
+        assert_eq!(get_output_format(SampleRatePolicy::Fixed, fixed, track, album), format(48_000, 24));
+        assert_eq!(get_output_format(SampleRatePolicy::Album, fixed, track, album), format(96_000, 24));
+        assert_eq!(get_output_format(SampleRatePolicy::Album, fixed, track, None), track);
+        // A track with a higher rate than we saw for its album keeps its rate.
+        let album = Some(format(48_000, 16));
+        assert_eq!(get_output_format(SampleRatePolicy::Album, fixed, track, album), format(48_000, 16));
+        assert_eq!(
+            get_output_format(SampleRatePolicy::Album, fixed, format(96_000, 16), album),
+            format(96_000, 16),
+        );
+    }
+
+    #[test]
+    fn resampler_is_continuous_across_chunks() {
+        // A constant signal fed in uneven chunks stays constant after the
+        // edge at the start.
+        let mut resampler = Resampler::new(Hertz(44_100), Hertz(48_000));
+        let mut out = Vec::new();
+        for n in [1, 100, 7, 4410, 32].iter() {
+            resampler.process((0..*n).map(|_| [0.25, -0.25]), |frame| out.push(frame));
+        }
+        assert!(out.len() > 4500, "Got {} frames.", out.len());
+        for frame in &out[100..] {
+            assert!((frame[0] - 0.25).abs() < 0.005, "Got {:?}.", frame);
+            assert!((frame[1] + 0.25).abs() < 0.005, "Got {:?}.", frame);
+        }
+    }
+}
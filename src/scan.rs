@@ -25,6 +25,7 @@
 //! * We can do incremental updates. We don't have to read the tags from files
 //!   that haven't changed.
 
+use std::collections::{HashMap, HashSet};
 use std::thread::JoinHandle;
 use std::ffi::OsStr;
 use std::fmt;
@@ -34,19 +35,23 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{Receiver, SyncSender};
+use std::time::{Duration, Instant};
 
 use walkdir;
 
 use crate::config::Config;
+use crate::cuesheet;
 use crate::database_utils;
 use crate::database as db;
 use crate::database::{Connection, Transaction};
 use crate::error;
+use crate::events::{Event, EventBus};
 use crate::loudness;
+use crate::metrics;
 use crate::mvar::{MVar, Var};
 use crate::prim::Mtime;
 use crate::thumb_cache::ThumbCache;
-use crate::MemoryMetaIndex;
+use crate::{MemoryMetaIndex, MetaIndex};
 
 type FlacReader = claxon::FlacReader<fs::File>;
 
@@ -73,33 +78,45 @@ pub enum ScanStage {
     /// `status.files_processed_metadata` is now final.
     IndexingMetadata = 3,
 
+    /// Fingerprinting files that lack tags, and looking them up on AcoustID.
+    ///
+    /// Only happens when `acoustid_api_key` is configured.
+    /// `status.files_to_fingerprint` is now final.
+    Fingerprinting = 4,
+
     /// Determining which files to analyze loudness for.
-    PreProcessingLoudness = 4,
+    PreProcessingLoudness = 5,
 
     /// Analyzing loudness and track waveforms.
     ///
     /// `status.tracks_to_process_loudness` and
     /// `status.albums_to_process_loudness` are now final.
-    AnalyzingLoudness = 5,
+    AnalyzingLoudness = 6,
 
     /// Determining which thumbnails to generate.
     ///
     /// `status.tracks_processed_loudness` and
     /// `status.albums_processed_loudness` are now final.
-    PreProcessingThumbnails = 6,
+    PreProcessingThumbnails = 7,
 
     /// Generating thumbnails.
     ///
     /// `status.files_to_process_thumbnails` is now final.
-    GeneratingThumbnails = 7,
+    GeneratingThumbnails = 8,
 
     /// Loading thumbnails.
     ///
     /// `status.files_to_process_thumbnails` is now final.
-    LoadingThumbnails = 8,
+    LoadingThumbnails = 9,
+
+    /// Decoding files to check them against their MD5, see verify.rs.
+    ///
+    /// Only happens in `musium verify`, not as part of a scan.
+    /// `status.files_to_verify` is now final.
+    Verifying = 10,
 
     /// Done.
-    Done = 9,
+    Done = 11,
 }
 
 /// Counters to report progress during scanning.
@@ -114,12 +131,22 @@ pub struct Status {
     /// Number of files found in the library.
     pub files_discovered: u64,
 
+    /// Of the `files_discovered`, the number that moved since the last scan.
+    /// These keep their metadata, they don't need to be processed again.
+    pub files_moved: u64,
+
     /// Of the `files_discovered`, the number of files that need to be processed.
     pub files_to_process_metadata: u64,
 
     /// Of the `files_to_process_metadata`, the number processed so far.
     pub files_processed_metadata: u64,
 
+    /// The number of files without usable tags that we have not looked up yet.
+    pub files_to_fingerprint: u64,
+
+    /// Of the `files_to_fingerprint`, the number looked up so far.
+    pub files_fingerprinted: u64,
+
     /// The number of tracks that need their loudness analyzed.
     pub tracks_to_process_loudness: u64,
 
@@ -137,6 +164,15 @@ pub struct Status {
 
     /// Of the `files_to_process_thumbnails`, the number processed so far.
     pub files_processed_thumbnails: u64,
+
+    /// The number of files to verify.
+    pub files_to_verify: u64,
+
+    /// Of the `files_to_verify`, the number verified so far.
+    pub files_verified: u64,
+
+    /// Of the `files_verified`, the number that failed.
+    pub files_failed_verification: u64,
 }
 
 impl Status {
@@ -144,14 +180,20 @@ impl Status {
         Status {
             stage: ScanStage::Discovering,
             files_discovered: 0,
+            files_moved: 0,
             files_to_process_metadata: 0,
             files_processed_metadata: 0,
+            files_to_fingerprint: 0,
+            files_fingerprinted: 0,
             tracks_to_process_loudness: 0,
             tracks_processed_loudness: 0,
             albums_to_process_loudness: 0,
             albums_processed_loudness: 0,
             files_to_process_thumbnails: 0,
             files_processed_thumbnails: 0,
+            files_to_verify: 0,
+            files_verified: 0,
+            files_failed_verification: 0,
         }
     }
 }
@@ -166,9 +208,10 @@ impl fmt::Display for Status {
         };
         writeln!(
             f,
-            "{} Discovering files:     {}",
+            "{} Discovering files:     {}, {} moved",
             indicator(ScanStage::Discovering),
             self.files_discovered,
+            self.files_moved,
         )?;
         writeln!(
             f,
@@ -182,6 +225,13 @@ impl fmt::Display for Status {
             "{} Indexing metadata",
             indicator(ScanStage::IndexingMetadata),
         )?;
+        writeln!(
+            f,
+            "{} Fingerprinting:        {} of {} files",
+            indicator(ScanStage::Fingerprinting),
+            self.files_fingerprinted,
+            self.files_to_fingerprint,
+        )?;
         writeln!(
             f,
             "{} Analyzing loudness:    {} of {} tracks, {} of {} albums",
@@ -224,12 +274,9 @@ pub fn scan(
     // default string ordering.
     files_current.sort_by(|a, b| a.0.as_os_str().cmp(b.0.as_os_str()));
 
+    database_utils::migrate(connection)?;
     let mut db = Connection::new(connection);
 
-    let mut tx = db.begin()?;
-    db::ensure_schema_exists(&mut tx)?;
-    tx.commit()?;
-
     let mut tx = db.begin()?;
 
     let mut rows_to_delete = Vec::new();
@@ -241,14 +288,29 @@ pub fn scan(
         &mut paths_to_scan,
     )?;
 
+    let moves = find_moves(&mut tx, &rows_to_delete, &paths_to_scan)?;
+    let moved_ids: HashSet<i64> = moves.iter().map(|m| m.file_id.0).collect();
+    let moved_paths: HashSet<&Path> = moves.iter().map(|m| m.path.as_path()).collect();
+    rows_to_delete.retain(|id| !moved_ids.contains(&id.0));
+    paths_to_scan.retain(|(path, _)| !moved_paths.contains(path.as_path()));
+
     status.stage = ScanStage::ExtractingMetadata;
+    status.files_moved = moves.len() as u64;
     status.files_to_process_metadata = paths_to_scan.len() as u64;
     status_sender.send(*status).unwrap();
 
-    // Delete rows for outdated files, we will insert new rows below.
+    // Delete rows for outdated files, we will insert new rows below. Delete
+    // before we move, a file may have moved to the path of a deleted one.
     for file_id in &rows_to_delete {
         db::delete_file(&mut tx, file_id.0)?;
     }
+    for m in &moves {
+        // The path is valid UTF-8, `find_moves` checked that.
+        db::update_file_path(&mut tx, m.file_id.0, m.path.to_str().unwrap(), m.inode)?;
+    }
+
+    backfill_size_inode(&mut tx)?;
+    backfill_pregaps(&mut tx)?;
 
     // Format the current time, we store this in the `imported_at` column in the
     // `file_metadata` table.
@@ -326,10 +388,10 @@ pub fn enumerate_flac_files(
                     },
                     Ok(_not_flac) => None,
                     // TODO: Add a nicer way to report errors.
-                    Err(err) => { eprintln!("{}", err); None }
+                    Err(err) => { warn!("{}", err); None }
                 }
             }
-            Err(err) => { eprintln!("{}", err); None }
+            Err(err) => { warn!("{}", err); None }
         })
         .collect();
 
@@ -413,6 +475,193 @@ fn get_updates(
     Ok(())
 }
 
+/// A file that moved since the last scan.
+#[derive(Debug, Eq, PartialEq)]
+struct Move {
+    file_id: FileMetaId,
+    path: PathBuf,
+    inode: i64,
+}
+
+/// What we know about a file without reading it, to recognize it after a move.
+#[derive(Debug)]
+struct Identity {
+    mtime: i64,
+    size_bytes: i64,
+    inode: i64,
+}
+
+/// Format the MD5 of the unencoded audio from the streaminfo block as hex.
+///
+/// Returns `None` if the encoder did not compute it, then the MD5 is all zeros.
+fn format_streaminfo_md5(md5: &[u8; 16]) -> Option<String> {
+    if md5.iter().all(|b| *b == 0) {
+        return None;
+    }
+    let mut result = String::with_capacity(32);
+    for b in md5 {
+        result.push_str(&format!("{:02x}", b));
+    }
+    Some(result)
+}
+
+/// Read only the MD5 of the audio from a flac file.
+fn read_streaminfo_md5(path: &Path) -> Option<String> {
+    let opts = claxon::FlacReaderOptions {
+        metadata_only: true,
+        read_picture: claxon::ReadPicture::Skip,
+        read_vorbis_comment: false,
+    };
+    let reader = claxon::FlacReader::open_ext(path, opts).ok()?;
+    format_streaminfo_md5(&reader.streaminfo().md5sum)
+}
+
+/// Match vanished files in the database against new files.
+///
+/// A moved file keeps its mtime and size. If it moved within the same file
+/// system, it also keeps its inode. If it did not, for example when the library
+/// was restored from a backup, we compare the MD5 of the audio in the
+/// streaminfo block, which we only read for files that match by mtime and size.
+/// Returns pairs of indices into `vanished` and `appeared`.
+fn match_moves<F>(
+    vanished: &[db::FileIdentity],
+    appeared: &[Option<Identity>],
+    mut read_md5: F,
+) -> Vec<(usize, usize)>
+where
+    F: FnMut(usize) -> Option<String>,
+{
+    let mut result = Vec::new();
+
+    // Vanished files that have not been matched yet, by mtime and size.
+    let mut by_mtime_size: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+    for (i, file) in vanished.iter().enumerate() {
+        if let Some(size_bytes) = file.size_bytes {
+            by_mtime_size.entry((file.mtime, size_bytes)).or_default().push(i);
+        }
+    }
+
+    for (j, identity) in appeared.iter().enumerate() {
+        let identity = match identity {
+            Some(id) => id,
+            None => continue,
+        };
+        let candidates = match by_mtime_size.get_mut(&(identity.mtime, identity.size_bytes)) {
+            Some(c) => c,
+            None => continue,
+        };
+
+        let by_inode = candidates
+            .iter()
+            .find(|&&i| vanished[i].inode == Some(identity.inode));
+        let found = match by_inode {
+            Some(&i) => Some(i),
+            None if candidates.iter().any(|&i| vanished[i].streaminfo_md5.is_some()) => {
+                match read_md5(j) {
+                    Some(md5) => candidates
+                        .iter()
+                        .find(|&&i| vanished[i].streaminfo_md5.as_ref() == Some(&md5))
+                        .copied(),
+                    None => None,
+                }
+            }
+            None => None,
+        };
+
+        if let Some(i) = found {
+            candidates.retain(|&k| k != i);
+            result.push((i, j));
+        }
+    }
+
+    result
+}
+
+/// Find the files to delete that are actually files to scan at a different path.
+fn find_moves(
+    tx: &mut Transaction,
+    rows_to_delete: &[FileMetaId],
+    paths_to_scan: &[(PathBuf, Mtime)],
+) -> db::Result<Vec<Move>> {
+    if rows_to_delete.is_empty() || paths_to_scan.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Only files whose path no longer exists can have moved. Files whose
+    // path exists with a different mtime, were modified.
+    let ids_to_delete: HashSet<i64> = rows_to_delete.iter().map(|id| id.0).collect();
+    let paths: HashSet<&Path> = paths_to_scan.iter().map(|(p, _)| p.as_path()).collect();
+    let vanished: Vec<db::FileIdentity> = db::iter_file_identities(tx)?
+        .filter(|row| match row {
+            Ok(file) => {
+                ids_to_delete.contains(&file.id)
+                    && !paths.contains(Path::new(&file.filename))
+            }
+            Err(..) => true,
+        })
+        .collect::<db::Result<_>>()?;
+
+    let appeared: Vec<Option<Identity>> = paths_to_scan
+        .iter()
+        .map(|(path, mtime)| match (path.to_str(), fs::metadata(path)) {
+            (Some(_), Ok(m)) => Some(Identity {
+                mtime: mtime.0,
+                size_bytes: m.len() as i64,
+                inode: m.ino() as i64,
+            }),
+            _ => None,
+        })
+        .collect();
+
+    let matches = match_moves(&vanished, &appeared, |j| read_streaminfo_md5(&paths_to_scan[j].0));
+
+    let result = matches
+        .into_iter()
+        .map(|(i, j)| Move {
+            file_id: FileMetaId(vanished[i].id),
+            path: paths_to_scan[j].0.clone(),
+            inode: appeared[j].as_ref().unwrap().inode,
+        })
+        .collect();
+
+    Ok(result)
+}
+
+/// Record the size and inode of files that were scanned before we stored those.
+fn backfill_size_inode(tx: &mut Transaction) -> db::Result<()> {
+    let files = db::iter_files_without_inode(tx)?.collect::<db::Result<Vec<(i64, String)>>>()?;
+    for (file_id, filename) in files {
+        // If the file is gone, the next scan will delete it.
+        if let Ok(m) = fs::metadata(&filename) {
+            db::update_file_size_inode(tx, file_id, m.len() as i64, m.ino() as i64)?;
+        }
+    }
+    Ok(())
+}
+
+/// Record the pre-gap of files that were scanned before we stored those.
+fn backfill_pregaps(tx: &mut Transaction) -> db::Result<()> {
+    let files = db::iter_files_without_pregap(tx)?.collect::<db::Result<Vec<(i64, String)>>>()?;
+    for (file_id, filename) in files {
+        // If the file is gone, the next scan will delete it.
+        if let Some(pregap_samples) = read_pregap_samples(Path::new(&filename)) {
+            db::insert_file_pregap(tx, file_id, pregap_samples as i64)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the pre-gap from the cuesheet, log a warning if we can't.
+fn read_pregap_samples(path: &Path) -> Option<u64> {
+    match cuesheet::read_pregap_samples(path) {
+        Ok(n) => Some(n),
+        Err(err) => {
+            warn!("Failed to read the cuesheet of {:?}: {}", path, err);
+            None
+        }
+    }
+}
+
 pub fn insert_file_metadata_for_paths(
     tx: &mut Transaction,
     paths_to_scan: &[(PathBuf, Mtime)],
@@ -463,9 +712,9 @@ pub fn insert_file_metadata_for_paths(
         // receiving side.
         std::mem::drop(tx_file);
 
-        for (i, flac_reader) in rx_file.iter() {
+        for (i, flac_reader, metadata, pregap_samples) in rx_file.iter() {
             let (ref path, mtime) = paths_to_scan[i];
-            insert_file_metadata(tx, now_str, path, mtime, flac_reader)?;
+            insert_file_metadata(tx, now_str, path, mtime, &metadata, flac_reader, pregap_samples)?;
 
             // Keep the status up to date, and send it once in a while. We send
             // it more often here than when enumerating files, because reading
@@ -496,7 +745,7 @@ pub fn insert_file_metadata_for_paths(
 fn read_files(
     paths: &[(PathBuf, Mtime)],
     counter: &AtomicUsize,
-    sender: SyncSender<(usize, FlacReader)>,
+    sender: SyncSender<(usize, FlacReader, fs::Metadata, Option<u64>)>,
 ) {
     loop {
         let i = counter.fetch_add(1, Ordering::SeqCst);
@@ -512,11 +761,19 @@ fn read_files(
         let reader = match claxon::FlacReader::open_ext(path, opts) {
             Ok(r) => r,
             Err(err) => {
-                eprintln!("Failure while reading {:?}: {}", path, err);
+                error!("Failure while reading {:?}: {}", path, err);
+                continue;
+            }
+        };
+        let metadata = match fs::metadata(path) {
+            Ok(m) => m,
+            Err(err) => {
+                error!("Failure while reading {:?}: {}", path, err);
                 continue;
             }
         };
-        sender.send((i, reader)).unwrap();
+        let pregap_samples = read_pregap_samples(path);
+        sender.send((i, reader, metadata, pregap_samples)).unwrap();
     }
 }
 
@@ -526,17 +783,20 @@ fn insert_file_metadata(
     now_str: &str,
     path: &Path,
     mtime: Mtime,
+    metadata: &fs::Metadata,
     flac_reader: FlacReader,
+    pregap_samples: Option<u64>,
 ) -> db::Result<()> {
     let path_utf8 = match path.to_str() {
         Some(s) => s,
         None => {
-            eprintln!("Warning: Path {:?} is not valid UTF-8. Skipping.", path);
+            warn!("Path {:?} is not valid UTF-8. Skipping.", path);
             return Ok(())
         }
     };
 
     let streaminfo = flac_reader.streaminfo();
+    let md5 = format_streaminfo_md5(&streaminfo.md5sum);
 
     // Insert the basic details about the file, and its tags.
     let f = db::InsertFile {
@@ -548,10 +808,19 @@ fn insert_file_metadata(
         streaminfo_bits_per_sample: streaminfo.bits_per_sample as i64,
         streaminfo_num_samples: streaminfo.samples.map(|x| x as i64),
         streaminfo_sample_rate: streaminfo.sample_rate as i64,
+
+        size_bytes: metadata.len() as i64,
+        inode: metadata.ino() as i64,
+        streaminfo_md5: md5.as_deref(),
     };
 
     let file_id = db::insert_file(tx, f)?;
 
+    // When we could not read the cuesheet, the next scan tries again.
+    if let Some(n) = pregap_samples {
+        db::insert_file_pregap(tx, file_id, n as i64)?;
+    }
+
     // Then walk all tags and insert the interesting ones into the database.
     for (tag, value) in flac_reader.tags() {
         let tag_lower = &tag.to_ascii_lowercase()[..];
@@ -563,8 +832,11 @@ fn insert_file_metadata(
             | "albumartistsort"
             | "albumartistssort"
             | "artist"
+            | "compilation"
             | "date"
             | "discnumber"
+            | "discsubtitle"
+            | "genre"
             | "musicbrainz_albumartistid"
             | "musicbrainz_albumid"
             | "musicbrainz_trackid"
@@ -594,6 +866,8 @@ pub fn run_scan_in_thread(
 
     let db_path = config.db_path.clone();
     let library_path = config.library_path.clone();
+    let acoustid_api_key = config.acoustid_api_key.clone();
+    let album_identity = config.album_identity;
 
     let scan_thread = std::thread::Builder::new()
         .name("scan".to_string())
@@ -619,19 +893,47 @@ pub fn run_scan_in_thread(
             // generating those may take a while).
             let mut db = Connection::new(&connection);
             let mut db_tx = db.begin()?;
-            let (index, builder) = MemoryMetaIndex::from_database(&mut db_tx)?;
+            let (index, builder) = MemoryMetaIndex::from_database(&mut db_tx, album_identity)?;
+
+            // Record the import time of albums that are new to the library, so
+            // that it stays the same when their files get re-imported later.
+            for kv in index.get_albums() {
+                if !builder.album_imports.contains_key(&kv.album_id) {
+                    let imported_at = kv.album.first_seen.format_iso8601();
+                    db::insert_album_import(&mut db_tx, kv.album_id.0 as i64, &imported_at)?;
+                }
+            }
+
+            // Clean up data for files and albums that no longer exist, so the
+            // thumbnails of deleted albums don't keep taking up space.
+            db::delete_orphaned_file_data(&mut db_tx)?;
+            crate::thumb_gen::delete_stale_thumbnails(&mut db_tx, &index)?;
+
             let index_arc = Arc::new(index);
             index_var.set(index_arc.clone());
             db_tx.commit()?;
 
             // TODO: Move issue reporting to a better place. Maybe take the builder and
             // index as an argument to this method.
-            if !builder.issues.is_empty() {
-                eprintln!();
-                for issue in &builder.issues {
-                    eprintln!("{}", issue);
-                }
-                eprintln!("\n\n\n");
+            for issue in &builder.issues {
+                warn!("{}", issue);
+            }
+
+            // Files that we could not index for lack of tags, we can try to
+            // identify by their fingerprint, and propose tags for them.
+            if let Some(api_key) = acoustid_api_key.as_ref() {
+                status.stage = ScanStage::Fingerprinting;
+                tx.send(status).unwrap();
+
+                let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+                crate::acoustid::fingerprint_files(
+                    &mut db,
+                    api_key,
+                    &builder,
+                    &now,
+                    &mut status,
+                    &mut tx,
+                )?;
             }
 
             {
@@ -683,6 +985,9 @@ pub fn run_scan_in_thread(
     (scan_thread, rx)
 }
 
+/// Minimum time between scan status events within the same stage.
+const SCAN_STATUS_EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
 /// A scan that is happening in a background thread.
 struct BackgroundScan {
     /// The most recent scan status.
@@ -692,9 +997,10 @@ struct BackgroundScan {
     ///
     /// The actual scan runs in yet another thread, and it sends status updates
     /// over a channel, to allow for reactive UIs. However, a background scan
-    /// triggered by the webinterface, the webinterface polls the status, we
-    /// don’t push. So the supervisor thread sits here, listening for status
-    /// updates, and it writes them to the `status` mutex when there is one.
+    /// triggered by the webinterface, the webinterface polls the status, or
+    /// subscribes to events. So the supervisor thread sits here, listening for
+    /// status updates, and it writes them to the `status` mutex when there is
+    /// one, and publishes them as events.
     _supervisor: JoinHandle<()>,
 }
 
@@ -703,6 +1009,7 @@ impl BackgroundScan {
         config: Config,
         index_var: Var<MemoryMetaIndex>,
         thumb_cache_var: Var<ThumbCache>,
+        event_bus: Arc<EventBus>,
     ) -> Self {
         let status = Arc::new(MVar::new(Status::new()));
 
@@ -716,8 +1023,27 @@ impl BackgroundScan {
                     index_var,
                     thumb_cache_var,
                 );
+                // The scan sends a status update for every file, that is too
+                // many to push to clients, so we only publish the status when
+                // the stage changes, and otherwise at most a few times per second.
+                let mut last_published: Option<(ScanStage, Instant)> = None;
+                let mut stage_started = (ScanStage::Discovering, Instant::now());
                 for new_status in rx {
                     status.set(new_status);
+                    if new_status.stage != stage_started.0 {
+                        metrics::observe_scan_stage(stage_started.0, stage_started.1.elapsed());
+                        stage_started = (new_status.stage, Instant::now());
+                    }
+                    let should_publish = match last_published {
+                        None => true,
+                        Some((stage, at)) => {
+                            stage != new_status.stage || at.elapsed() >= SCAN_STATUS_EVENT_INTERVAL
+                        }
+                    };
+                    if should_publish {
+                        event_bus.publish(Event::ScanStatus { status: new_status });
+                        last_published = Some((new_status.stage, Instant::now()));
+                    }
                 }
                 scan_thread
                     .join()
@@ -730,6 +1056,7 @@ impl BackgroundScan {
                     ScanStage::Done,
                     "Final status update should be Done after scan thread exits.",
                 );
+                event_bus.publish(Event::LibraryUpdated);
             })
         .expect("Failed to spawn scan supervisor thread.");
 
@@ -757,17 +1084,22 @@ pub struct BackgroundScanner {
     ///
     /// The scanner replaces the inner value when thumb generation is complete.
     thumb_cache_var: Var<ThumbCache>,
+
+    /// Where scans publish their progress.
+    event_bus: Arc<EventBus>,
 }
 
 impl BackgroundScanner {
     pub fn new(
         index_var: Var<MemoryMetaIndex>,
         thumb_cache_var: Var<ThumbCache>,
+        event_bus: Arc<EventBus>,
     ) -> Self {
         Self {
             background_scan: Mutex::new(None),
             index_var: index_var,
             thumb_cache_var: thumb_cache_var,
+            event_bus: event_bus,
         }
     }
 
@@ -791,6 +1123,7 @@ impl BackgroundScanner {
             config,
             self.index_var.clone(),
             self.thumb_cache_var.clone(),
+            self.event_bus.clone(),
         );
         let status = new_scan.get_status();
         *bg_scan = Some(new_scan);
@@ -806,8 +1139,8 @@ impl BackgroundScanner {
 
 #[cfg(test)]
 mod test {
-    use crate::database::Connection;
-    use super::{Mtime, FileMetaId, get_updates};
+    use crate::database::{Connection, FileIdentity};
+    use super::{Identity, Mtime, FileMetaId, get_updates, match_moves};
     use std::path::PathBuf;
 
     fn ensure_schema_exists(db: &mut Connection) {
@@ -1062,4 +1395,46 @@ mod test {
         ]);
         assert_eq!(&rows_to_delete[..], &[]);
     }
+
+    fn file(id: i64, size_bytes: i64, inode: i64, md5: Option<&str>) -> FileIdentity {
+        FileIdentity {
+            id: id,
+            filename: format!("/old/{}.flac", id),
+            mtime: 1,
+            size_bytes: Some(size_bytes),
+            inode: Some(inode),
+            streaminfo_md5: md5.map(|s| s.to_string()),
+        }
+    }
+
+    #[test]
+    fn match_moves_matches_by_inode_then_md5() {
+        let vanished = [
+            file(1, 100, 11, None),
+            file(2, 200, 12, Some("aa")),
+            file(3, 300, 13, Some("bb")),
+        ];
+        let appeared = [
+            // Same inode, moved within the file system.
+            Some(Identity { mtime: 1, size_bytes: 100, inode: 11 }),
+            // Different inode but the same audio, copied.
+            Some(Identity { mtime: 1, size_bytes: 200, inode: 99 }),
+            // Different audio, this is a different file.
+            Some(Identity { mtime: 1, size_bytes: 300, inode: 98 }),
+            // Nothing with this size vanished.
+            Some(Identity { mtime: 1, size_bytes: 400, inode: 13 }),
+            None,
+        ];
+        let mut md5_reads = Vec::new();
+        let matches = match_moves(&vanished, &appeared, |j| {
+            md5_reads.push(j);
+            match j {
+                1 => Some("aa".to_string()),
+                _ => Some("cc".to_string()),
+            }
+        });
+        assert_eq!(matches, vec![(0, 0), (1, 1)]);
+        // We only read files that match a vanished file with an MD5.
+        assert_eq!(md5_reads, vec![1, 2]);
+    }
 }
@@ -0,0 +1,353 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Submitting listens and *now playing* updates to Last.fm.
+//!
+//! The scrobbler thread receives events from the history thread, after the
+//! history thread has recorded them in the database. The `listens` table is
+//! also the retry queue: listens that are eligible for scrobbling but that do
+//! not yet have `scrobbled_at` set, are submitted on the next attempt. This
+//! means that scrobbles survive restarts and network outages, and that the
+//! scrobbler plays nicely with `tools/scrobble.py`, which uses the same column.
+//!
+//! We talk to the Last.fm API through `curl`, to avoid pulling in a TLS stack.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+
+use crate::config::Config;
+use crate::database as db;
+use crate::database::{Connection, ListenToScrobble};
+use crate::database_utils;
+use crate::error::{Error, Result};
+use crate::listens::ListenThreshold;
+use crate::md5::md5_hex;
+use crate::mvar::Var;
+use crate::{MemoryMetaIndex, MetaIndex, TrackId};
+
+const API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+
+/// Last.fm rejects scrobbles older than 14 days.
+const MAX_SCROBBLE_AGE_SECONDS: i64 = 14 * 24 * 3600;
+
+/// After a failed submission, wait this long before we try again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Events for the scrobbler thread.
+pub enum ScrobbleEvent {
+    /// Playback of the track started.
+    NowPlaying(TrackId),
+
+    /// A listen ended and was recorded, it may now be eligible to scrobble.
+    ListenCompleted,
+
+    /// The user loved (true) or un-loved (false) the track.
+    Loved(TrackId, bool),
+
+    /// The credentials changed, because the config was reloaded.
+    CredentialsChanged,
+}
+
+/// Credentials for the Last.fm API, see also `docs/scrobbling.md`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Credentials {
+    pub api_key: String,
+    pub api_secret: String,
+    pub session_key: String,
+}
+
+impl Credentials {
+    pub fn from_config(config: &Config) -> Option<Credentials> {
+        config.lastfm_credentials().map(|(key, secret, session)| Credentials {
+            api_key: key.to_string(),
+            api_secret: secret.to_string(),
+            session_key: session.to_string(),
+        })
+    }
+}
+
+/// Compute the `api_sig` parameter for the request parameters.
+///
+/// See https://www.last.fm/api/authspec#_8-signing-calls.
+fn sign(params: &[(String, String)], api_secret: &str) -> String {
+    let mut sorted: Vec<&(String, String)> = params.iter().collect();
+    sorted.sort();
+    let mut message = String::new();
+    for (k, v) in sorted {
+        message.push_str(k);
+        message.push_str(v);
+    }
+    message.push_str(api_secret);
+    md5_hex(message.as_bytes())
+}
+
+/// Make a signed POST request to the Last.fm API, return the response.
+fn call(credentials: &Credentials, method: &str, mut params: Vec<(String, String)>) -> Result<serde_json::Value> {
+    params.push(("method".to_string(), method.to_string()));
+    params.push(("api_key".to_string(), credentials.api_key.clone()));
+    params.push(("sk".to_string(), credentials.session_key.clone()));
+    let signature = sign(&params, &credentials.api_secret);
+
+    let mut body = url::form_urlencoded::Serializer::new(String::new());
+    body.extend_pairs(params.iter());
+    body.append_pair("api_sig", &signature);
+    // The format is not part of the signature.
+    body.append_pair("format", "json");
+    let body = body.finish();
+
+    let mut curl = Command::new("curl")
+        .args(["--silent", "--show-error"])
+        .args(["--max-time", "30"])
+        // Read the request body from stdin, so the session key does not
+        // show up in the process list.
+        .args(["--data-binary", "@-"])
+        .arg(API_URL)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::CommandError("Failed to spawn 'curl'.", e))?;
+
+    {
+        let stdin = curl.stdin.as_mut().expect("Stdin is piped.");
+        stdin
+            .write_all(body.as_bytes())
+            .map_err(|e| Error::CommandError("Failed to write to 'curl'.", e))?;
+    }
+
+    let output = curl
+        .wait_with_output()
+        .map_err(|e| Error::CommandError("Failed to wait for 'curl'.", e))?;
+
+    if !output.status.success() {
+        return Err(Error::LastFmError(format!(
+            "curl exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )));
+    }
+
+    let response: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::LastFmError(format!("Invalid response: {}", e)))?;
+
+    if let Some(code) = response.get("error") {
+        return Err(Error::LastFmError(format!(
+            "Error {}: {}",
+            code,
+            response.get("message").and_then(|m| m.as_str()).unwrap_or(""),
+        )));
+    }
+
+    Ok(response)
+}
+
+/// Format the parameters of a listen for a batch `track.scrobble` request.
+fn push_scrobble_params(params: &mut Vec<(String, String)>, i: usize, listen: &ListenToScrobble) {
+    let mut push = |key: &str, value: String| params.push((format!("{}[{}]", key, i), value));
+    push("artist", listen.track_artist.clone());
+    push("track", listen.track_title.clone());
+    push("timestamp", listen.started_at_seconds.to_string());
+    push("album", listen.album_title.clone());
+    // Last.fm says "The album artist - if this differs from the track artist."
+    // But if we don't include it, it echos back empty string in the response.
+    push("albumArtist", listen.album_artist.clone());
+    push("duration", listen.duration_seconds.to_string());
+    if let Some(n) = listen.track_number {
+        push("trackNumber", n.to_string());
+    }
+    if let Some(mbid) = listen.track_mbid.as_ref() {
+        push("mbid", mbid.clone());
+    }
+}
+
+/// Scrobble all pending listens, in batches.
+///
+/// Listens that Last.fm accepted (or ignored, retrying those would not help)
+/// get marked as scrobbled. When a request fails, the remaining listens stay
+/// pending, and we return the error.
+fn scrobble_pending(
+    db: &mut Connection,
+    credentials: &Credentials,
+    threshold: ListenThreshold,
+) -> Result<()> {
+    loop {
+        let since = Utc::now().timestamp() - MAX_SCROBBLE_AGE_SECONDS;
+        let mut tx = db.begin()?;
+        let batch = db::iter_listens_to_scrobble(
+            &mut tx,
+            since,
+            threshold.percent as i64,
+            threshold.seconds as i64,
+        )?
+            .collect::<db::Result<Vec<ListenToScrobble>>>()?;
+        tx.commit()?;
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut params = Vec::new();
+        for (i, listen) in batch.iter().enumerate() {
+            push_scrobble_params(&mut params, i, listen);
+        }
+        call(credentials, "track.scrobble", params)?;
+
+        let use_zulu_suffix = true;
+        let now_str = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, use_zulu_suffix);
+        let mut tx = db.begin()?;
+        for listen in &batch {
+            db::update_listen_scrobbled(&mut tx, listen.id, &now_str)?;
+        }
+        tx.commit()?;
+
+        info!("Scrobbled {} listens to Last.fm.", batch.len());
+
+        // The query returns at most 50 listens, the maximum batch size that
+        // Last.fm accepts. If we got fewer, we are done.
+        if batch.len() < 50 {
+            return Ok(());
+        }
+    }
+}
+
+/// Return the artist and title parameters that identify a track for Last.fm.
+fn track_params(index: &MemoryMetaIndex, track_id: TrackId) -> Option<Vec<(String, String)>> {
+    let track = index.get_track(track_id)?;
+    let result = vec![
+        ("artist".to_string(), index.get_string(track.artist).to_string()),
+        ("track".to_string(), index.get_string(track.title).to_string()),
+    ];
+    Some(result)
+}
+
+fn update_now_playing(
+    index: &MemoryMetaIndex,
+    credentials: &Credentials,
+    track_id: TrackId,
+) -> Result<()> {
+    let track = match index.get_track(track_id) {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    let album = index.get_album(track_id.album_id()).unwrap();
+    let mut params = track_params(index, track_id).unwrap();
+    params.push(("album".to_string(), index.get_string(album.title).to_string()));
+    params.push(("albumArtist".to_string(), index.get_string(album.artist).to_string()));
+    params.push(("trackNumber".to_string(), track_id.track_number().to_string()));
+    params.push(("duration".to_string(), track.duration_seconds.to_string()));
+    call(credentials, "track.updateNowPlaying", params)?;
+    Ok(())
+}
+
+fn set_loved(
+    index: &MemoryMetaIndex,
+    credentials: &Credentials,
+    track_id: TrackId,
+    loved: bool,
+) -> Result<()> {
+    let params = match track_params(index, track_id) {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    let method = if loved { "track.love" } else { "track.unlove" };
+    call(credentials, method, params)?;
+    Ok(())
+}
+
+/// Main for the thread that submits to Last.fm.
+///
+/// The credentials can change when the config is reloaded. Without
+/// credentials, the thread drops now playing updates and loves, and listens
+/// remain pending until credentials are configured.
+pub fn main(
+    db_path: &Path,
+    index_var: Var<MemoryMetaIndex>,
+    credentials_var: Var<Option<Credentials>>,
+    events: Receiver<ScrobbleEvent>,
+    threshold: ListenThreshold,
+) -> Result<()> {
+    let connection = database_utils::connect_read_write(db_path)?;
+    let mut db = Connection::new(&connection);
+
+    // Listens may be pending from before a restart, so try those right away.
+    let mut has_pending = true;
+
+    loop {
+        let credentials = credentials_var.get();
+
+        if has_pending {
+            if let Some(credentials) = credentials.as_ref() {
+                match scrobble_pending(&mut db, credentials, threshold) {
+                    Ok(()) => has_pending = false,
+                    Err(err) => warn!("Failed to scrobble, will retry later: {:?}", err),
+                }
+            }
+        }
+
+        let event = if has_pending && credentials.is_some() {
+            match events.recv_timeout(RETRY_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        } else {
+            match events.recv() {
+                Ok(event) => event,
+                Err(..) => return Ok(()),
+            }
+        };
+
+        // Take the credentials again, they may have changed while we waited.
+        let credentials = match credentials_var.get().as_ref() {
+            Some(credentials) => credentials.clone(),
+            None => {
+                has_pending = has_pending || matches!(event, ScrobbleEvent::ListenCompleted);
+                continue;
+            }
+        };
+
+        match event {
+            ScrobbleEvent::NowPlaying(track_id) => {
+                // Now playing updates are not important enough to retry.
+                let index = index_var.get();
+                if let Err(err) = update_now_playing(&index, &credentials, track_id) {
+                    warn!("Failed to update now playing: {:?}", err);
+                }
+            }
+            ScrobbleEvent::ListenCompleted => has_pending = true,
+            ScrobbleEvent::Loved(track_id, loved) => {
+                let index = index_var.get();
+                if let Err(err) = set_loved(&index, &credentials, track_id, loved) {
+                    warn!("Failed to propagate love to Last.fm: {:?}", err);
+                }
+            }
+            // Listens that failed with the old credentials may succeed now.
+            ScrobbleEvent::CredentialsChanged => has_pending = true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::sign;
+
+    #[test]
+    fn sign_sorts_parameters_and_appends_secret() {
+        let params = vec![
+            ("method".to_string(), "auth.getSession".to_string()),
+            ("api_key".to_string(), "xxxxxxxxxx".to_string()),
+            ("token".to_string(), "yyyyyy".to_string()),
+        ];
+        // The md5 of "api_keyxxxxxxxxxxmethodauth.getSessiontokenyyyyyysecret".
+        assert_eq!(sign(&params, "secret"), "df8ce1a1cfd4f5083cd4b9d975870bcc");
+    }
+}
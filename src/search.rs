@@ -7,9 +7,10 @@
 
 use std::cmp;
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::collections::{BTreeSet, BinaryHeap};
 use std::iter;
 
+use crate::string_utils::edit_distance;
 use crate::word_index::{Values, WordIndex, WordMeta};
 
 /// Iterator over a value range of a word index.
@@ -378,3 +379,148 @@ pub fn search<'a, I: 'a + WordIndex, W: 'a + AsRef<str>>(
         into.push(item);
     }
 }
+
+/// The number of typos we tolerate in a query word of the given length.
+///
+/// For very short words, a single edit turns the word into a completely
+/// different one (“abba” vs. “abbe”), so only tolerate typos in longer words.
+fn edit_budget(word: &str, max_edits: u32) -> u32 {
+    match word.chars().count() {
+        0..=3 => 0,
+        4..=7 => max_edits.min(1),
+        _ => max_edits,
+    }
+}
+
+/// Call `f` for every combination of candidate words that uses exactly `edits` edits.
+fn for_each_combination<'a, F: FnMut(&[&'a str])>(
+    candidates: &[Vec<(u32, &'a str)>],
+    edits: u32,
+    combination: &mut Vec<&'a str>,
+    f: &mut F,
+) {
+    let depth = combination.len();
+    if depth == candidates.len() {
+        if edits == 0 { f(&combination[..]) }
+        return
+    }
+
+    for &(d, word) in &candidates[depth] {
+        if d > edits { continue }
+        combination.push(word);
+        for_each_combination(candidates, edits - d, combination, f);
+        combination.pop();
+    }
+}
+
+/// Like `search`, but tolerate up to `max_edits` typos in the query.
+///
+/// We first do a regular search. Then for every query word, we collect the
+/// words in the index that are within edit distance of the query word, and we
+/// search again with those substituted, in order of increasing total edit
+/// distance. Results are appended in that order and not reported twice, so
+/// exact matches always rank above fuzzy ones, and fuzzier matches rank lower.
+///
+/// Finding the candidates is a linear scan over all words in the index, but
+/// because most words can be rejected on length alone, this is fast enough
+/// for search-as-you-type.
+pub fn search_fuzzy<'a, I: 'a + WordIndex, W: 'a + AsRef<str>>(
+    index: &'a I,
+    words: &'a [W],
+    max_edits: u32,
+    into: &mut Vec<I::Item>
+) where I::Item: cmp::Ord + Copy {
+    // Substituting too many candidates for a word makes the number of
+    // combinations explode, and the candidates beyond the first few are
+    // unlikely to be what the user meant anyway.
+    const MAX_CANDIDATES: usize = 8;
+
+    let start = into.len();
+    search(index, words, into);
+
+    if max_edits == 0 || words.is_empty() { return }
+
+    let mut candidates: Vec<Vec<(u32, &str)>> = Vec::with_capacity(words.len());
+    for (i, word) in words.iter().enumerate() {
+        let word = word.as_ref();
+        let is_prefix = i + 1 == words.len();
+        let budget = edit_budget(word, max_edits);
+
+        let mut word_candidates = Vec::new();
+        if budget > 0 {
+            for k in 0..index.len() {
+                let key = index.get_word(k);
+                match edit_distance(word, key, budget, is_prefix) {
+                    // Distance 0 is already covered by the regular search.
+                    Some(d) if d > 0 => word_candidates.push((d, key)),
+                    _ => continue,
+                }
+            }
+        }
+
+        // Prefer the closest matches, and among those the shortest words,
+        // which are most similar to what was typed so far.
+        word_candidates.sort_by_key(|&(d, key)| (d, key.len()));
+        word_candidates.truncate(MAX_CANDIDATES);
+        word_candidates.insert(0, (0, word));
+        candidates.push(word_candidates);
+    }
+
+    let mut seen: BTreeSet<I::Item> = into[start..].iter().cloned().collect();
+    let mut combination = Vec::with_capacity(words.len());
+    let mut results = Vec::new();
+
+    for edits in 1..=max_edits {
+        for_each_combination(&candidates[..], edits, &mut combination, &mut |fuzzy_words| {
+            results.clear();
+            search(index, fuzzy_words, &mut results);
+            for &item in &results {
+                if seen.insert(item) {
+                    into.push(item);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::search_fuzzy;
+    use crate::word_index::{MemoryWordIndex, WordMeta};
+
+    fn build_index(elems: &[(&str, u32)]) -> MemoryWordIndex<u32> {
+        let mut set = BTreeSet::new();
+        for &(word, value) in elems {
+            set.insert((word.to_string(), value, WordMeta::new(word.len(), word.len(), 0, 2)));
+        }
+        MemoryWordIndex::new(&set)
+    }
+
+    #[test]
+    fn test_search_fuzzy_finds_typos() {
+        let index = build_index(&[("radiohead", 1), ("ros", 2), ("sigur", 2), ("rush", 3)]);
+
+        let mut results = Vec::new();
+        search_fuzzy(&index, &["radiohaed"], 1, &mut results);
+        assert_eq!(results, vec![1]);
+
+        results.clear();
+        search_fuzzy(&index, &["sigru", "ros"], 1, &mut results);
+        assert_eq!(results, vec![2]);
+
+        results.clear();
+        search_fuzzy(&index, &["radiohaed"], 0, &mut results);
+        assert_eq!(results, Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_search_fuzzy_ranks_exact_first() {
+        let index = build_index(&[("beatles", 1), ("beetles", 2)]);
+
+        let mut results = Vec::new();
+        search_fuzzy(&index, &["beetles"], 1, &mut results);
+        assert_eq!(results, vec![2, 1]);
+    }
+}
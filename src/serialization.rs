@@ -12,16 +12,33 @@ use serde_json;
 use std::io;
 use std::io::Write;
 
-use crate::player::{Millibel, TrackSnapshot};
+use crate::artist_alias;
+use crate::browser_output;
+use crate::cast;
+use crate::database as db;
+use crate::dynamics::{AlbumDynamics, Dynamics};
+use crate::history::HistoryStatus;
+use crate::library_stats;
+use crate::limits::LimitStatus;
+use crate::listens::{AlbumInProgress, Chart, Discoveries, DiscoveryMonth, OnThisDay, Rewind};
+use crate::maintenance;
+use crate::metadata_edit;
+use crate::player::{Millibel, NowPlaying, PlaybackState, QueueId, SkipVote, Source, TrackSnapshot};
+use crate::prim::Instant;
+use crate::radio;
 use crate::scan;
+use crate::sessions::SessionStats;
+use crate::snapcast;
+use crate::typeahead;
 use crate::user_data::UserData;
-use crate::{Album, AlbumId, Artist, ArtistId, MetaIndex, TrackId};
+use crate::{Album, AlbumId, Artist, ArtistId, MemoryUsage, MetaIndex, TrackId};
 
 /// Write an album, but only with the album details, not its tracks.
 ///
 /// Used for the list of all albums, and for the list of albums by artist.
 pub fn write_brief_album_json<W: Write>(
     index: &dyn MetaIndex,
+    user_data: &UserData,
     mut w: W,
     album_id: AlbumId,
     album: &Album,
@@ -39,22 +56,99 @@ pub fn write_brief_album_json<W: Write>(
     serde_json::to_writer(&mut w, index.get_string(album.artist))?;
     write!(
         w,
-        r#","release_date":"{}","first_seen":"{}"}}"#,
+        r#","release_date":"{}","first_seen":"{}","duration_seconds":{},"rating":{},"#,
         album.original_release_date,
         // TODO: Should this be a string, or integer? Integer is more efficient,
         // but worse for interpretability.
         album.first_seen.format_iso8601(),
+        index.get_album_duration_seconds(album_id),
+        user_data.get_album_rating(album_id) as i8,
     )?;
-    Ok(())
+    write_play_stats_json(
+        &mut w,
+        user_data.get_album_play_count(album_id),
+        user_data.get_album_last_played(album_id),
+    )?;
+    write!(w, "}}")
+}
+
+/// Write the `play_count` and `last_played` fields, without surrounding braces.
+fn write_play_stats_json<W: Write>(
+    mut w: W,
+    play_count: u32,
+    last_played: Option<Instant>,
+) -> io::Result<()> {
+    write!(w, r#""play_count":{},"last_played":"#, play_count)?;
+    match last_played {
+        Some(t) => write!(w, r#""{}""#, t.format_iso8601()),
+        None => write!(w, "null"),
+    }
 }
 
 /// Write a json representation of the album list to the writer.
-pub fn write_albums_json<W: Write>(index: &dyn MetaIndex, mut w: W) -> io::Result<()> {
+pub fn write_albums_json<W: Write>(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    mut w: W,
+    albums: &[AlbumId],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for &album_id in albums {
+        let album = index.get_album(album_id).unwrap();
+        if !first { write!(w, ",")?; }
+        write_brief_album_json(index, user_data, &mut w, album_id, album)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write a json representation of the artist list to the writer.
+pub fn write_artists_json<W: Write>(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    mut w: W,
+    artists: &[ArtistId],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for &artist_id in artists {
+        let artist = index.get_artist(artist_id).unwrap();
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","name":"#, artist_id)?;
+        serde_json::to_writer(&mut w, index.get_string(artist.name))?;
+        write!(w, r#","sort_name":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(artist.name_for_sort))?;
+        write!(
+            w,
+            r#","duration_seconds":{},"rating":{},"#,
+            index.get_artist_duration_seconds(artist_id),
+            user_data.get_artist_rating(artist_id) as i8,
+        )?;
+        write_play_stats_json(
+            &mut w,
+            user_data.get_artist_play_count(artist_id),
+            user_data.get_artist_last_played(artist_id),
+        )?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write a json representation of the track list to the writer.
+///
+/// Tracks use the same format as in search results.
+pub fn write_tracks_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    tracks: &[TrackId],
+) -> io::Result<()> {
     write!(w, "[")?;
     let mut first = true;
-    for kv in index.get_albums() {
+    for &track_id in tracks {
         if !first { write!(w, ",")?; }
-        write_brief_album_json(index, &mut w, kv.album_id, &kv.album)?;
+        write_search_track_json(index, &mut w, track_id)?;
         first = false;
     }
     write!(w, "]")
@@ -82,7 +176,20 @@ pub fn write_album_json<W: Write>(
     }
     write!(w, r#"],"artist":"#)?;
     serde_json::to_writer(&mut w, index.get_string(album.artist))?;
-    write!(w, r#","release_date":"{}","tracks":["#, album.original_release_date)?;
+    write!(
+        w,
+        r#","release_date":"{}","duration_seconds":{},"rating":{},"excluded":{},"#,
+        album.original_release_date,
+        index.get_album_duration_seconds(id),
+        user_data.get_album_rating(id) as i8,
+        user_data.is_album_excluded(id),
+    )?;
+    write_play_stats_json(
+        &mut w,
+        user_data.get_album_play_count(id),
+        user_data.get_album_last_played(id),
+    )?;
+    write!(w, r#","tracks":["#)?;
     let mut first = true;
     for kv in index.get_album_tracks(id) {
         let track_id = kv.track_id;
@@ -99,26 +206,87 @@ pub fn write_album_json<W: Write>(
         serde_json::to_writer(&mut w, index.get_string(kv.track.artist))?;
         write!(
             w,
-            r#","duration_seconds":{},"rating":{}}}"#,
+            r#","duration_seconds":{},"pregap_seconds":{:.03},"rating":{},"excluded":{},"#,
             kv.track.duration_seconds,
+            index.get_pregap_ms(track_id) as f32 * 1e-3,
             user_data.get_track_rating(track_id) as i8,
+            user_data.is_track_excluded(track_id),
+        )?;
+        write_play_stats_json(
+            &mut w,
+            user_data.get_track_play_count(track_id),
+            user_data.get_track_last_played(track_id),
         )?;
+        write!(w, "}}")?;
         first = false;
     }
-    write!(w, "]}}")
+    write!(w, "],")?;
+    write_discs_json(index, &mut w, id)?;
+    write!(w, "}}")
+}
+
+/// Write the `discs` of an album, and the `disc_count`.
+///
+/// Tracks are ordered by disc, so the tracks of a disc are adjacent. The disc
+/// count is the highest disc number, a box set can miss some of its discs.
+fn write_discs_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: AlbumId) -> io::Result<()> {
+    let tracks = index.get_album_tracks(id);
+    write!(w, r#""discs":["#)?;
+    let mut begin = 0;
+    let mut disc_count = 0;
+    while begin < tracks.len() {
+        let disc_number = tracks[begin].track_id.disc_number();
+        let len = tracks[begin..]
+            .iter()
+            .position(|kv| kv.track_id.disc_number() != disc_number)
+            .unwrap_or(tracks.len() - begin);
+        let disc_tracks = &tracks[begin..begin + len];
+        let duration_seconds: u64 = disc_tracks.iter().map(|kv| kv.track.duration_seconds as u64).sum();
+        if begin > 0 { write!(w, ",")?; }
+        write!(w, r#"{{"disc_number":{},"subtitle":"#, disc_number)?;
+        match index.get_disc_subtitle(id, disc_number) {
+            Some(subtitle) => serde_json::to_writer(&mut w, index.get_string(subtitle))?,
+            None => write!(w, "null")?,
+        }
+        write!(
+            w,
+            r#","track_count":{},"duration_seconds":{}}}"#,
+            disc_tracks.len(),
+            duration_seconds,
+        )?;
+        disc_count = disc_number;
+        begin += len;
+    }
+    write!(w, r#"],"disc_count":{}"#, disc_count)
 }
 
 /// Write a json representation of the artist and its albums.
 pub fn write_artist_json<W: Write>(
     index: &dyn MetaIndex,
+    user_data: &UserData,
     mut w: W,
+    artist_id: ArtistId,
     artist: &Artist,
     albums: &[(ArtistId, AlbumId)],
+    tags: &[String],
 ) -> io::Result<()> {
     write!(w, r#"{{"name":"#)?;
     serde_json::to_writer(&mut w, index.get_string(artist.name))?;
     write!(w, r#","sort_name":"#)?;
     serde_json::to_writer(&mut w, index.get_string(artist.name_for_sort))?;
+    write!(w, r#","tags":"#)?;
+    serde_json::to_writer(&mut w, tags)?;
+    write!(
+        w,
+        r#","duration_seconds":{},"rating":{},"#,
+        index.get_artist_duration_seconds(artist_id),
+        user_data.get_artist_rating(artist_id) as i8,
+    )?;
+    write_play_stats_json(
+        &mut w,
+        user_data.get_artist_play_count(artist_id),
+        user_data.get_artist_last_played(artist_id),
+    )?;
     write!(w, r#","albums":["#)?;
     let mut first = true;
     for &(_, album_id) in albums {
@@ -127,7 +295,7 @@ pub fn write_artist_json<W: Write>(
         // itself, not user input, so the album should be present.
         let album = index.get_album(album_id).unwrap();
         if !first { write!(w, ",")?; }
-        write_brief_album_json(index, &mut w, album_id, album)?;
+        write_brief_album_json(index, user_data, &mut w, album_id, album)?;
         first = false;
     }
     write!(w, "]}}")
@@ -205,22 +373,104 @@ pub fn write_search_track_json<W: Write>(index: &dyn MetaIndex, mut w: W, id: Tr
     write!(w, r#"}}"#)
 }
 
+/// Write the highlighted ranges of a typeahead result, see `typeahead::highlight`.
+fn write_highlights_json<W: Write>(mut w: W, display: &str, words: &[String]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for (begin, end) in typeahead::highlight(display, words) {
+        if !first { write!(w, ",")?; }
+        write!(w, "[{},{}]", begin, end)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write typeahead results, with the ranges that match the query `words`.
+pub fn write_typeahead_results_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    words: &[String],
+    artists: &[ArtistId],
+    albums: &[AlbumId],
+    tracks: &[TrackId],
+) -> io::Result<()> {
+    write!(w, r#"{{"artists":["#)?;
+    let mut first = true;
+    for &id in artists {
+        let artist = index.get_artist(id).unwrap();
+        let name = index.get_string(artist.name);
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","name":"#, id)?;
+        serde_json::to_writer(&mut w, name)?;
+        write!(w, r#","name_highlights":"#)?;
+        write_highlights_json(&mut w, name, words)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, r#"],"albums":["#)?;
+    let mut first = true;
+    for &id in albums {
+        let album = index.get_album(id).unwrap();
+        let title = index.get_string(album.title);
+        let artist = index.get_string(album.artist);
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","title":"#, id)?;
+        serde_json::to_writer(&mut w, title)?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, artist)?;
+        write!(w, r#","release_date":"{}","title_highlights":"#, album.original_release_date)?;
+        write_highlights_json(&mut w, title, words)?;
+        write!(w, r#","artist_highlights":"#)?;
+        write_highlights_json(&mut w, artist, words)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, r#"],"tracks":["#)?;
+    let mut first = true;
+    for &id in tracks {
+        let track = index.get_track(id).unwrap();
+        let album_id = id.album_id();
+        let album = index.get_album(album_id).unwrap();
+        let title = index.get_string(track.title);
+        let artist = index.get_string(track.artist);
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","title":"#, id)?;
+        serde_json::to_writer(&mut w, title)?;
+        write!(w, r#","album_id":"{}","album":"#, album_id)?;
+        serde_json::to_writer(&mut w, index.get_string(album.title))?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, artist)?;
+        write!(w, r#","title_highlights":"#)?;
+        write_highlights_json(&mut w, title, words)?;
+        write!(w, r#","artist_highlights":"#)?;
+        write_highlights_json(&mut w, artist, words)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]}}")
+}
+
 fn write_queued_track_json<W: Write>(
     index: &dyn MetaIndex,
     user_data: &UserData,
     mut w: W,
     queued_track: &TrackSnapshot,
 ) -> io::Result<()> {
+    let track_id = match &queued_track.source {
+        Source::Track(track_id) => *track_id,
+        Source::Radio(station) => return write_queued_radio_json(w, queued_track, station),
+    };
+
     // Same as the search result track format, but additionally includes
     // the duration, and playback information.
-    let album_id = queued_track.track_id.album_id();
-    let track = index.get_track(queued_track.track_id).unwrap();
+    let album_id = track_id.album_id();
+    let track = index.get_track(track_id).unwrap();
     let album = index.get_album(album_id).unwrap();
     write!(
         w,
         r#"{{"queue_id":"{}","track_id":"{}","title":"#,
         queued_track.queue_id,
-        queued_track.track_id,
+        track_id,
     )?;
     serde_json::to_writer(&mut w, index.get_string(track.title))?;
     write!(
@@ -242,12 +492,39 @@ fn write_queued_track_json<W: Write>(
     serde_json::to_writer(&mut w, index.get_string(track.artist))?;
     write!(
         w,
-        r#","release_date":"{}","duration_seconds":{},"rating":{}"#,
+        r#","release_date":"{}","duration_seconds":{},"pregap_seconds":{:.03},"rating":{}"#,
         album.original_release_date,
         track.duration_seconds,
-        user_data.get_track_rating(queued_track.track_id) as i8,
+        index.get_pregap_ms(track_id) as f32 * 1e-3,
+        user_data.get_track_rating(track_id) as i8,
+    )?;
+    match queued_track.resume_offer_ms {
+        Some(ms) => write!(w, r#","resume_offer_seconds":{:.03}"#, ms as f32 * 1e-3)?,
+        None => write!(w, r#","resume_offer_seconds":null"#)?,
+    }
+    write_queue_position_json(w, queued_track)
+}
+
+/// Write a queued radio station, it has no track id, album, or duration.
+fn write_queued_radio_json<W: Write>(
+    mut w: W,
+    queued_track: &TrackSnapshot,
+    station: &radio::Station,
+) -> io::Result<()> {
+    write!(
+        w,
+        r#"{{"queue_id":"{}","radio_station_id":{},"station":"#,
+        queued_track.queue_id,
+        station.id,
     )?;
+    serde_json::to_writer(&mut w, &station.name)?;
+    write!(w, r#","stream_title":"#)?;
+    serde_json::to_writer(&mut w, &queued_track.stream_title)?;
+    write_queue_position_json(w, queued_track)
+}
 
+/// Write the playback information of a queue entry, and close the object.
+fn write_queue_position_json<W: Write>(mut w: W, queued_track: &TrackSnapshot) -> io::Result<()> {
     let position_seconds = queued_track.position_ms as f32 * 1e-3;
     let buffered_seconds = queued_track.buffered_ms as f32 * 1e-3;
     write!(w, r#","position_seconds":{:.03}"#, position_seconds)?;
@@ -272,10 +549,349 @@ pub fn write_queue_json<W: Write>(
     write!(w, "]")
 }
 
+/// Write the queue ids of newly enqueued tracks as a json array.
+pub fn write_queue_ids_json<W: Write>(mut w: W, queue_ids: &[QueueId]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for queue_id in queue_ids {
+        if !first { write!(w, ",")?; }
+        write!(w, r#""{}""#, queue_id)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+pub fn write_now_playing_json<W: Write>(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    mut w: W,
+    now_playing: &NowPlaying,
+) -> io::Result<()> {
+    let state = match now_playing.state {
+        PlaybackState::Stopped => "stopped",
+        PlaybackState::Buffering => "buffering",
+        PlaybackState::Playing => "playing",
+        PlaybackState::Paused => "paused",
+    };
+    write!(
+        w,
+        r#"{{"state":"{}","queue_length":{},"volume_db":{:.02},"current":"#,
+        state,
+        now_playing.queue_len,
+        now_playing.volume.0 as f32 * 0.01,
+    )?;
+    match &now_playing.current {
+        None => write!(w, "null")?,
+        Some(t) => write_queued_track_json(index, user_data, &mut w, t)?,
+    }
+    write!(w, r#","output":"#)?;
+    match &now_playing.output_format {
+        None => write!(w, "null")?,
+        Some(output) => write!(
+            w,
+            r#"{{"sample_rate_hz":{},"bits_per_sample":{},"bit_perfect":{}}}"#,
+            output.format.sample_rate.0,
+            output.format.bits_per_sample,
+            output.bit_perfect,
+        )?,
+    }
+    write!(w, r#","gain":"#)?;
+    match &now_playing.gain {
+        None => write!(w, "null")?,
+        Some(gain) => write!(
+            w,
+            r#"{{"applied_db":{:.02},"limiter_reduction_db":{:.02}}}"#,
+            gain.gain.0 as f32 * 0.01,
+            gain.limiter_reduction_db,
+        )?,
+    }
+    write!(w, "}}")
+}
+
+/// Write the players as json, with their state, but without the current track.
+pub fn write_players_json<W: Write>(mut w: W, players: &[(&str, NowPlaying)]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for (name, now_playing) in players {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"name":"#)?;
+        serde_json::to_writer(&mut w, name)?;
+        let state = match now_playing.state {
+            PlaybackState::Stopped => "stopped",
+            PlaybackState::Buffering => "buffering",
+            PlaybackState::Playing => "playing",
+            PlaybackState::Paused => "paused",
+        };
+        write!(
+            w,
+            r#","state":"{}","queue_length":{},"volume_db":{:.02}}}"#,
+            state,
+            now_playing.queue_len,
+            now_playing.volume.0 as f32 * 0.01,
+        )?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+pub fn write_skip_vote_json<W: Write>(mut w: W, vote: &SkipVote) -> io::Result<()> {
+    write!(
+        w,
+        r#"{{"queue_id":"{}","votes":{},"votes_needed":{},"skipped":{}}}"#,
+        vote.queue_id,
+        vote.votes,
+        vote.votes_needed,
+        vote.skipped,
+    )
+}
+
 pub fn write_volume_json<W: Write>(mut w: W, current_volume: Millibel) -> io::Result<()> {
     write!(w, r#"{{"volume_db":{:.02}}}"#, current_volume.0 as f32 * 0.01)
 }
 
+fn write_cast_device_json<W: Write>(mut w: W, device: &cast::Device) -> io::Result<()> {
+    write!(w, r#"{{"id":"#)?;
+    serde_json::to_writer(&mut w, &device.id)?;
+    write!(w, r#","name":"#)?;
+    serde_json::to_writer(&mut w, &device.name)?;
+    write!(w, r#","model":"#)?;
+    serde_json::to_writer(&mut w, &device.model)?;
+    write!(w, r#","address":"{}"}}"#, device.address)
+}
+
+/// Write the cast devices found on the network as json.
+pub fn write_cast_devices_json<W: Write>(mut w: W, devices: &[cast::Device]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for device in devices {
+        if !first { write!(w, ",")?; }
+        write_cast_device_json(&mut w, device)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the cast device that we play on as json, null when not casting.
+pub fn write_cast_status_json<W: Write>(mut w: W, device: Option<&cast::Device>) -> io::Result<()> {
+    write!(w, r#"{{"device":"#)?;
+    match device {
+        Some(d) => write_cast_device_json(&mut w, d)?,
+        None => write!(w, "null")?,
+    }
+    write!(w, "}}")
+}
+
+/// Write what the browser output should play as json, see `browser_output.rs`.
+pub fn write_browser_output_json<W: Write>(
+    mut w: W,
+    session: u64,
+    instruction: &browser_output::Instruction,
+) -> io::Result<()> {
+    write!(w, r#"{{"session":{},"queue_id":"#, session)?;
+    match &instruction.current {
+        Some((queue_id, Source::Track(track_id))) => {
+            write!(w, r#""{}","url":"api/track/{}.flac""#, queue_id, track_id)?
+        }
+        Some((queue_id, Source::Radio(station))) => {
+            write!(w, r#""{}","url":"#, queue_id)?;
+            serde_json::to_writer(&mut w, &station.url)?;
+        }
+        None => write!(w, r#"null,"url":null"#)?,
+    }
+    write!(w, r#","start_seconds":{:.3}"#, instruction.start_ms as f64 / 1000.0)?;
+    match instruction.level {
+        Some(level) => write!(w, r#","level":{:.3}}}"#, level),
+        None => write!(w, r#","level":null}}"#),
+    }
+}
+
+/// Write the Snapcast zones as json.
+pub fn write_zones_json<W: Write>(mut w: W, zones: &[snapcast::Zone]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for zone in zones {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"#)?;
+        serde_json::to_writer(&mut w, &zone.id)?;
+        write!(w, r#","name":"#)?;
+        serde_json::to_writer(&mut w, &zone.name)?;
+        write!(
+            w,
+            r#","connected":{},"volume_percent":{},"muted":{}}}"#,
+            zone.connected,
+            zone.volume_percent,
+            zone.muted,
+        )?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the status of the server's background threads as json.
+pub fn write_status_json<W: Write>(
+    mut w: W,
+    history: HistoryStatus,
+    limits: LimitStatus,
+) -> io::Result<()> {
+    write!(
+        w,
+        r#"{{"history":{{"healthy":{},"buffered_events":{},"dropped_events":{}}},"#,
+        history.is_healthy(),
+        history.buffered_events,
+        history.dropped_events,
+    )?;
+    write!(
+        w,
+        r#""http":{{"rate_limited_requests":{},"oversized_requests":{}}}}}"#,
+        limits.rate_limited_requests,
+        limits.oversized_requests,
+    )
+}
+
+pub fn write_maintenance_report_json<W: Write>(
+    mut w: W,
+    report: &maintenance::Report,
+) -> io::Result<()> {
+    write!(w, r#"{{"integrity_ok":{},"integrity_errors":"#, report.is_ok())?;
+    serde_json::to_writer(&mut w, &report.integrity_errors)?;
+    write!(
+        w,
+        r#","full_vacuum":{},"size_before_bytes":{},"size_after_bytes":{},"duration_seconds":{:.3}}}"#,
+        report.full_vacuum,
+        report.size_before_bytes,
+        report.size_after_bytes,
+        report.duration.as_secs_f64(),
+    )
+}
+
+/// Write the keys of the settings that changed in a config reload.
+pub fn write_reload_json<W: Write>(mut w: W, changed: &[&str]) -> io::Result<()> {
+    write!(w, r#"{{"changed":"#)?;
+    serde_json::to_writer(&mut w, changed)?;
+    write!(w, "}}")
+}
+
+/// Write the changes of a metadata edit.
+pub fn write_metadata_changes_json<W: Write>(
+    mut w: W,
+    changes: &[metadata_edit::Change],
+    dry_run: bool,
+    written: &[metadata_edit::WrittenFile],
+) -> io::Result<()> {
+    write!(w, r#"{{"dry_run":{},"files_written":{},"changes":["#, dry_run, written.len())?;
+    let mut first = true;
+    for change in changes {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"track_id":"{}","field":"{}","old":"#, change.track_id, change.field.tag_name())?;
+        serde_json::to_writer(&mut w, &change.old_value)?;
+        write!(w, r#","new":"#)?;
+        serde_json::to_writer(&mut w, &change.new_value)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]}}")
+}
+
+pub fn write_metadata_edits_json<W: Write>(mut w: W, edits: &[db::MetadataEdit]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for edit in edits {
+        if !first { write!(w, ",")?; }
+        write!(
+            w,
+            r#"{{"id":{},"edited_at":"{}","track_id":"{}","field":"#,
+            edit.id,
+            edit.edited_at,
+            TrackId(edit.track_id as u64),
+        )?;
+        serde_json::to_writer(&mut w, &edit.field_name)?;
+        write!(w, r#","old":"#)?;
+        serde_json::to_writer(&mut w, &edit.old_value)?;
+        write!(w, r#","new":"#)?;
+        serde_json::to_writer(&mut w, &edit.new_value)?;
+        write!(w, r#","wrote_tags":{},"user":"#, edit.wrote_tags != 0)?;
+        serde_json::to_writer(&mut w, &edit.user_name)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+pub fn write_artist_merge_json<W: Write>(
+    mut w: W,
+    artist_id: ArtistId,
+    canonical_artist_id: ArtistId,
+) -> io::Result<()> {
+    write!(w, r#"{{"id":"{}","canonical_id":"{}"}}"#, artist_id, canonical_artist_id)
+}
+
+pub fn write_artist_aliases_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    aliases: &[artist_alias::Alias],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for alias in aliases {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","name":"#, alias.artist_id)?;
+        serde_json::to_writer(&mut w, &alias.name)?;
+        write!(w, r#","canonical_id":"{}","canonical_name":"#, alias.canonical_artist_id)?;
+        // The canonical artist can be gone from the library, if all of its
+        // albums were removed.
+        match index.get_artist(alias.canonical_artist_id) {
+            Some(artist) => serde_json::to_writer(&mut w, index.get_string(artist.name))?,
+            None => write!(w, "null")?,
+        }
+        write!(w, r#","created_at":"{}"}}"#, alias.created_at)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+pub fn write_acoustid_proposal_json<W: Write>(
+    mut w: W,
+    proposal: &db::AcoustidProposal,
+) -> io::Result<()> {
+    write!(w, r#"{{"file_id":{},"filename":"#, proposal.file_id)?;
+    serde_json::to_writer(&mut w, &proposal.filename)?;
+    write!(w, r#","acoustid":"{}","score":{:.3},"tags":{{"#, proposal.acoustid, proposal.score)?;
+    let tags = [
+        ("title", &proposal.title),
+        ("artist", &proposal.artist),
+        ("album", &proposal.album),
+        ("albumartist", &proposal.albumartist),
+        ("originaldate", &proposal.originaldate),
+        ("musicbrainz_trackid", &proposal.musicbrainz_trackid),
+        ("musicbrainz_albumid", &proposal.musicbrainz_albumid),
+        ("musicbrainz_albumartistid", &proposal.musicbrainz_albumartistid),
+    ];
+    for (field_name, value) in tags {
+        write!(w, r#""{}":"#, field_name)?;
+        serde_json::to_writer(&mut w, value)?;
+        write!(w, ",")?;
+    }
+    write!(
+        w,
+        r#""tracknumber":{},"discnumber":{}}},"status":"{}"}}"#,
+        proposal.tracknumber, proposal.discnumber, proposal.status,
+    )
+}
+
+pub fn write_acoustid_proposals_json<W: Write>(
+    mut w: W,
+    proposals: &[db::AcoustidProposal],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for proposal in proposals {
+        if !first { write!(w, ",")?; }
+        write_acoustid_proposal_json(&mut w, proposal)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
 pub fn write_scan_status_json<W: Write>(
     mut w: W,
     status_opt: Option<scan::Status>,
@@ -291,11 +907,13 @@ pub fn write_scan_status_json<W: Write>(
         ScanStage::PreProcessingMetadata => "preprocessing_metadata",
         ScanStage::ExtractingMetadata => "extracting_metadata",
         ScanStage::IndexingMetadata => "indexing_metadata",
+        ScanStage::Fingerprinting => "fingerprinting",
         ScanStage::PreProcessingLoudness => "preprocessing_loudness",
         ScanStage::AnalyzingLoudness => "analyzing_loudness",
         ScanStage::PreProcessingThumbnails => "preprocessing_thumbnails",
         ScanStage::GeneratingThumbnails => "generating_thumbnails",
         ScanStage::LoadingThumbnails => "loading_thumbnails",
+        ScanStage::Verifying => "verifying",
         ScanStage::Done => "done",
     };
 
@@ -303,8 +921,11 @@ pub fn write_scan_status_json<W: Write>(
         "{{\
         \"stage\":\"{}\",\
         \"files_discovered\":{},\
+        \"files_moved\":{},\
         \"files_to_process_metadata\":{},\
         \"files_processed_metadata\":{},\
+        \"files_to_fingerprint\":{},\
+        \"files_fingerprinted\":{},\
         \"tracks_to_process_loudness\":{},\
         \"tracks_processed_loudness\":{},\
         \"albums_to_process_loudness\":{},\
@@ -314,8 +935,11 @@ pub fn write_scan_status_json<W: Write>(
         }}",
         stage,
         status.files_discovered,
+        status.files_moved,
         status.files_to_process_metadata,
         status.files_processed_metadata,
+        status.files_to_fingerprint,
+        status.files_fingerprinted,
         status.tracks_to_process_loudness,
         status.tracks_processed_loudness,
         status.albums_to_process_loudness,
@@ -326,18 +950,639 @@ pub fn write_scan_status_json<W: Write>(
 }
 
 /// Write library statistics as json.
-pub fn write_stats_json<W: Write>(
-    index: &dyn MetaIndex,
+/// Write `(key, count)` pairs as a list of objects with the given field names.
+fn write_counts_json<W: Write, K: Clone + Into<serde_json::Value>>(
     mut w: W,
+    key_name: &str,
+    count_name: &str,
+    counts: &[(K, u64)],
 ) -> io::Result<()> {
-    write!(w,
-        "{{\
+    write!(w, "[")?;
+    let mut first = true;
+    for (key, count) in counts {
+        if !first { write!(w, ",")?; }
+        let key: serde_json::Value = key.clone().into();
+        write!(w, r#"{{"{}":"#, key_name)?;
+        serde_json::to_writer(&mut w, &key)?;
+        write!(w, r#","{}":{}}}"#, count_name, count)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+pub fn write_library_stats_json<W: Write>(
+    mut w: W,
+    stats: &library_stats::LibraryStats,
+) -> io::Result<()> {
+    write!(
+        w,
+        r#"{{"tracks":{},"albums":{},"artists":{},"duration_seconds":{},"size_bytes":{},"formats":"#,
+        stats.tracks,
+        stats.albums,
+        stats.artists,
+        stats.duration_seconds,
+        stats.size_bytes,
+    )?;
+    write_counts_json(&mut w, "format", "tracks", &stats.formats)?;
+    write!(w, r#","sample_rates":"#)?;
+    write_counts_json(&mut w, "sample_rate", "tracks", &stats.sample_rates)?;
+    write!(w, r#","bits_per_sample":"#)?;
+    write_counts_json(&mut w, "bits_per_sample", "tracks", &stats.bits_per_sample)?;
+    write!(w, r#","years":"#)?;
+    write_counts_json(&mut w, "year", "albums", &stats.years)?;
+    write!(w, r#","genres":"#)?;
+    write_counts_json(&mut w, "genre", "tracks", &stats.genres)?;
+    write!(w, "}}")
+}
+
+pub fn write_stats_json<W: Write>(
+    index: &dyn MetaIndex,
+    memory: &MemoryUsage,
+    mut w: W,
+) -> io::Result<()> {
+    write!(w,
+        "{{\
+        \"tracks\":{},\
+        \"albums\":{},\
+        \"artists\":{},\
+        \"memory_bytes\":{{\
+        \"tracks\":{},\
+        \"albums\":{},\
+        \"artists\":{},\
+        \"album_artists\":{},\
+        \"strings\":{},\
+        \"filenames\":{},\
+        \"words\":{},\
+        \"other\":{},\
+        \"total\":{}\
+        }}}}",
+        index.get_tracks().len(),
+        index.get_albums().len(),
+        index.get_artists().len(),
+        memory.tracks,
+        memory.albums,
+        memory.artists,
+        memory.album_artists,
+        memory.strings,
+        memory.filenames,
+        memory.words,
+        memory.other,
+        memory.total(),
+    )
+}
+
+/// Write the library generation and size, for `/api/sync`.
+pub fn write_sync_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    generation: &str,
+) -> io::Result<()> {
+    write!(w,
+        "{{\
+        \"generation\":\"{}\",\
         \"tracks\":{},\
         \"albums\":{},\
         \"artists\":{}\
         }}",
+        generation,
         index.get_tracks().len(),
         index.get_albums().len(),
         index.get_artists().len(),
     )
 }
+
+/// Write the list of playlists as json.
+pub fn write_playlists_json<W: Write>(
+    mut w: W,
+    playlists: &[db::Playlist],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for playlist in playlists {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":{},"name":"#, playlist.id)?;
+        serde_json::to_writer(&mut w, &playlist.name)?;
+        write!(w, r#","query":"#)?;
+        serde_json::to_writer(&mut w, &playlist.query)?;
+        // The track count of smart playlists is only known after evaluating
+        // the query, which we don't do for the listing.
+        match playlist.query {
+            Some(_) => write!(w, r#","track_count":null}}"#)?,
+            None => write!(w, r#","track_count":{}}}"#, playlist.track_count)?,
+        }
+        first = false;
+    }
+    write!(w, "]")
+}
+
+pub fn write_radio_stations_json<W: Write>(
+    mut w: W,
+    stations: &[db::RadioStation],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for station in stations {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":{},"name":"#, station.id)?;
+        serde_json::to_writer(&mut w, &station.name)?;
+        write!(w, r#","url":"#)?;
+        serde_json::to_writer(&mut w, &station.url)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write a playlist and its entries as json.
+///
+/// Entries are `(entry_id, track_id)` pairs, the entry id is `None` for smart
+/// playlists. Entries that refer to tracks that are no longer in the index (for
+/// example because the file was removed) are omitted.
+pub fn write_playlist_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    playlist_id: i64,
+    name: &str,
+    query: Option<&str>,
+    entries: &[(Option<i64>, TrackId)],
+) -> io::Result<()> {
+    write!(w, r#"{{"id":{},"name":"#, playlist_id)?;
+    serde_json::to_writer(&mut w, name)?;
+    write!(w, r#","query":"#)?;
+    serde_json::to_writer(&mut w, &query)?;
+    write!(w, r#","tracks":["#)?;
+    let mut first = true;
+    for &(entry_id, track_id) in entries {
+        let track = match index.get_track(track_id) {
+            Some(t) => t,
+            None => continue,
+        };
+        let album = index.get_album(track_id.album_id()).unwrap();
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"entry_id":"#)?;
+        serde_json::to_writer(&mut w, &entry_id)?;
+        write!(w, r#","id":"{}","title":"#, track_id)?;
+        serde_json::to_writer(&mut w, index.get_string(track.title))?;
+        write!(w, r#","album_id":"{}","album":"#, track_id.album_id())?;
+        serde_json::to_writer(&mut w, index.get_string(album.title))?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+        write!(w, r#","duration_seconds":{}}}"#, track.duration_seconds)?;
+        first = false;
+    }
+    write!(w, "]}}")
+}
+
+/// Write the result of a playlist import as json.
+///
+/// `missing` contains the paths of the entries that could not be resolved.
+pub fn write_playlist_import_json<W: Write>(
+    mut w: W,
+    playlist_id: i64,
+    track_count: usize,
+    missing: &[String],
+) -> io::Result<()> {
+    write!(w, r#"{{"id":{},"track_count":{},"missing":"#, playlist_id, track_count)?;
+    serde_json::to_writer(&mut w, missing)?;
+    write!(w, "}}")
+}
+
+/// Write a page of listens as json, with the cursor for the next page.
+pub fn write_listens_json<W: Write>(
+    mut w: W,
+    listens: &[db::ListenRow],
+    next_cursor: Option<i64>,
+) -> io::Result<()> {
+    write!(w, r#"{{"listens":["#)?;
+    let mut first = true;
+    for listen in listens {
+        if !first { write!(w, ",")?; }
+        write!(
+            w,
+            r#"{{"id":{},"track_id":"{}","album_id":"{}","album_artist_id":"{}","#,
+            listen.id,
+            TrackId(listen.track_id as u64),
+            AlbumId(listen.album_id as u64),
+            ArtistId(listen.album_artist_id as u64),
+        )?;
+        write!(w, r#""started_at":"#)?;
+        serde_json::to_writer(&mut w, &listen.started_at)?;
+        write!(w, r#","completed_at":"#)?;
+        serde_json::to_writer(&mut w, &listen.completed_at)?;
+        write!(w, r#","track_title":"#)?;
+        serde_json::to_writer(&mut w, &listen.track_title)?;
+        write!(w, r#","track_artist":"#)?;
+        serde_json::to_writer(&mut w, &listen.track_artist)?;
+        write!(w, r#","album_title":"#)?;
+        serde_json::to_writer(&mut w, &listen.album_title)?;
+        write!(w, r#","album_artist":"#)?;
+        serde_json::to_writer(&mut w, &listen.album_artist)?;
+        write!(w, r#","duration_seconds":{},"track_number":"#, listen.duration_seconds)?;
+        serde_json::to_writer(&mut w, &listen.track_number)?;
+        write!(w, r#","disc_number":"#)?;
+        serde_json::to_writer(&mut w, &listen.disc_number)?;
+        write!(w, r#","source":"#)?;
+        serde_json::to_writer(&mut w, &listen.source)?;
+        write!(w, r#","scrobbled_at":"#)?;
+        serde_json::to_writer(&mut w, &listen.scrobbled_at)?;
+        write!(w, r#","played_fraction":"#)?;
+        serde_json::to_writer(&mut w, &listen.played_fraction)?;
+        write!(w, r#","client":"#)?;
+        serde_json::to_writer(&mut w, &listen.client)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    // The cursor is opaque to clients, so we present it as a string.
+    match next_cursor {
+        Some(cursor) => write!(w, r#"],"next_cursor":"{}"}}"#, cursor),
+        None => write!(w, r#"],"next_cursor":null}}"#),
+    }
+}
+
+/// Write the most played album artists as json.
+pub fn write_top_artists_json<W: Write>(mut w: W, artists: &[db::TopArtist]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for artist in artists {
+        if !first { write!(w, ",")?; }
+        write_top_artist_json(&mut w, artist)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+fn write_top_artist_json<W: Write>(mut w: W, artist: &db::TopArtist) -> io::Result<()> {
+    write!(w, r#"{{"id":"{}","name":"#, ArtistId(artist.album_artist_id as u64))?;
+    serde_json::to_writer(&mut w, &artist.album_artist)?;
+    write!(
+        w,
+        r#","listens":{},"seconds":{}}}"#,
+        artist.listen_count, artist.listen_seconds,
+    )
+}
+
+fn write_top_album_json<W: Write>(mut w: W, album: &db::TopAlbum) -> io::Result<()> {
+    write!(w, r#"{{"id":"{}","title":"#, AlbumId(album.album_id as u64))?;
+    serde_json::to_writer(&mut w, &album.album_title)?;
+    write!(w, r#","artist":"#)?;
+    serde_json::to_writer(&mut w, &album.album_artist)?;
+    write!(
+        w,
+        r#","listens":{},"seconds":{}}}"#,
+        album.listen_count, album.listen_seconds,
+    )
+}
+
+/// Write the most played albums as json.
+pub fn write_top_albums_json<W: Write>(mut w: W, albums: &[db::TopAlbum]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for album in albums {
+        if !first { write!(w, ",")?; }
+        write_top_album_json(&mut w, album)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the most played tracks as json.
+pub fn write_top_tracks_json<W: Write>(mut w: W, tracks: &[db::TopTrack]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for track in tracks {
+        if !first { write!(w, ",")?; }
+        write_top_track_json(&mut w, track)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+fn write_top_track_json<W: Write>(mut w: W, track: &db::TopTrack) -> io::Result<()> {
+    write!(w, r#"{{"id":"{}","title":"#, TrackId(track.track_id as u64))?;
+    serde_json::to_writer(&mut w, &track.track_title)?;
+    write!(w, r#","artist":"#)?;
+    serde_json::to_writer(&mut w, &track.track_artist)?;
+    write!(w, r#","album":"#)?;
+    serde_json::to_writer(&mut w, &track.album_title)?;
+    write!(
+        w,
+        r#","listens":{},"seconds":{}}}"#,
+        track.listen_count, track.listen_seconds,
+    )
+}
+
+/// Write a chart as json, with every entry's item under `key`.
+fn write_chart_json<W: Write, T>(
+    mut w: W,
+    chart: &Chart<T>,
+    key: &str,
+    write_item: fn(&mut W, &T) -> io::Result<()>,
+) -> io::Result<()> {
+    write!(
+        w,
+        r#"{{"period":"{}","start":"{}","end":"{}","entries":["#,
+        chart.period.name(),
+        chart.start.format("%Y-%m-%d"),
+        chart.end.format("%Y-%m-%d"),
+    )?;
+    let mut first = true;
+    for entry in &chart.entries {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"rank":{},"previous_rank":"#, entry.rank)?;
+        serde_json::to_writer(&mut w, &entry.previous_rank)?;
+        write!(w, r#","change":"#)?;
+        let change = entry.previous_rank.map(|prev| prev as i64 - entry.rank as i64);
+        serde_json::to_writer(&mut w, &change)?;
+        write!(w, r#","{}":"#, key)?;
+        write_item(&mut w, &entry.item)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]}}")
+}
+
+/// Write a chart of the most played album artists as json.
+pub fn write_artist_chart_json<W: Write>(w: W, chart: &Chart<db::TopArtist>) -> io::Result<()> {
+    write_chart_json(w, chart, "artist", |w, artist| write_top_artist_json(w, artist))
+}
+
+/// Write a chart of the most played albums as json.
+pub fn write_album_chart_json<W: Write>(w: W, chart: &Chart<db::TopAlbum>) -> io::Result<()> {
+    write_chart_json(w, chart, "album", |w, album| write_top_album_json(w, album))
+}
+
+/// Write a chart of the most played tracks as json.
+pub fn write_track_chart_json<W: Write>(w: W, chart: &Chart<db::TopTrack>) -> io::Result<()> {
+    write_chart_json(w, chart, "track", |w, track| write_top_track_json(w, track))
+}
+
+/// Write the number of listens, the listening time in seconds, and the number
+/// of skips as json.
+pub fn write_listen_totals_json<W: Write>(
+    mut w: W,
+    listens: i64,
+    counted: i64,
+    seconds: i64,
+    skips: i64,
+) -> io::Result<()> {
+    write!(
+        w,
+        r#"{{"listens":{},"counted":{},"seconds":{},"skips":{}}}"#,
+        listens, counted, seconds, skips,
+    )
+}
+
+/// Write the most skipped tracks as json.
+///
+/// Tracks that are no longer in the library are omitted.
+pub fn write_skipped_tracks_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    tracks: &[db::SkippedTrack],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for skipped in tracks {
+        let track_id = TrackId(skipped.track_id as u64);
+        let track = match index.get_track(track_id) {
+            Some(t) => t,
+            None => continue,
+        };
+        let album = index.get_album(track_id.album_id()).unwrap();
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","title":"#, track_id)?;
+        serde_json::to_writer(&mut w, index.get_string(track.title))?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+        write!(w, r#","album":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(album.title))?;
+        write!(
+            w,
+            r#","skips":{},"listens":{}}}"#,
+            skipped.skip_count, skipped.listen_count,
+        )?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the listen counts per day of the week and hour as nested json arrays.
+pub fn write_weekday_hour_counts_json<W: Write>(mut w: W, counts: &[[i64; 24]; 7]) -> io::Result<()> {
+    serde_json::to_writer(&mut w, counts)?;
+    Ok(())
+}
+
+/// Write the listening session statistics as json.
+pub fn write_session_stats_json<W: Write>(mut w: W, stats: &SessionStats) -> io::Result<()> {
+    write!(
+        w,
+        r#"{{"sessions":{},"average_seconds":{},"average_listens":"#,
+        stats.sessions, stats.average_seconds,
+    )?;
+    serde_json::to_writer(&mut w, &stats.average_listens)?;
+    write!(w, r#","typical_start_hour":"#)?;
+    serde_json::to_writer(&mut w, &stats.typical_start_hour)?;
+    write!(w, "}}")
+}
+
+/// Write the albums listened to on this day in earlier years as json.
+pub fn write_on_this_day_json<W: Write>(mut w: W, days: &[OnThisDay]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first_day = true;
+    for day in days {
+        if !first_day { write!(w, ",")?; }
+        write!(w, r#"{{"year":{},"albums":["#, day.year)?;
+        let mut first = true;
+        for album in &day.albums {
+            if !first { write!(w, ",")?; }
+            write!(w, r#"{{"id":"{}","title":"#, AlbumId(album.album_id as u64))?;
+            serde_json::to_writer(&mut w, &album.album_title)?;
+            write!(w, r#","artist":"#)?;
+            serde_json::to_writer(&mut w, &album.album_artist)?;
+            write!(w, r#","listens":{}}}"#, album.listen_count)?;
+            first = false;
+        }
+        write!(w, "]}}")?;
+        first_day = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the albums that we listened to partway as json.
+pub fn write_albums_in_progress_json<W: Write>(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    mut w: W,
+    albums: &[AlbumInProgress],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for in_progress in albums {
+        // The listen may be of an album that is no longer in the library.
+        let album = match index.get_album(in_progress.album_id) {
+            Some(album) => album,
+            None => continue,
+        };
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"album":"#)?;
+        write_brief_album_json(index, user_data, &mut w, in_progress.album_id, album)?;
+        write!(
+            w,
+            r#","next_track_id":"{}","tracks_listened":{},"last_listen_at":"{}"}}"#,
+            in_progress.next_track_id,
+            in_progress.tracks_listened,
+            in_progress.last_listened.format_iso8601(),
+        )?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the summary of a year of listening as json.
+pub fn write_rewind_json<W: Write>(mut w: W, rewind: &Rewind) -> io::Result<()> {
+    write!(
+        w,
+        r#"{{"year":{},"listens":{},"seconds":{},"most_played_album":"#,
+        rewind.year, rewind.listen_count, rewind.listen_seconds,
+    )?;
+    match rewind.most_played_album.as_ref() {
+        Some(album) => write_top_album_json(&mut w, album)?,
+        None => write!(w, "null")?,
+    }
+    write!(w, r#","discoveries":"#)?;
+    write_album_discoveries_json(&mut w, &rewind.discoveries)?;
+    write!(w, "}}")
+}
+
+fn write_album_discoveries_json<W: Write>(mut w: W, albums: &[db::AlbumDiscovery]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for album in albums {
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","title":"#, AlbumId(album.album_id as u64))?;
+        serde_json::to_writer(&mut w, &album.album_title)?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, &album.album_artist)?;
+        write!(w, r#","listens":{}}}"#, album.listen_count)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the number of first listens, and the new albums, as json.
+pub fn write_discoveries_json<W: Write>(mut w: W, discoveries: &Discoveries) -> io::Result<()> {
+    write!(
+        w,
+        r#"{{"tracks":{},"albums":{},"artists":{},"top_albums":"#,
+        discoveries.tracks, discoveries.albums, discoveries.artists,
+    )?;
+    write_album_discoveries_json(&mut w, &discoveries.top_albums)?;
+    write!(w, "}}")
+}
+
+/// Write the listens and first listens per month as json.
+pub fn write_discovery_rate_json<W: Write>(mut w: W, months: &[DiscoveryMonth]) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for m in months {
+        if !first { write!(w, ",")?; }
+        // The share of listens that was a track we never heard before.
+        let rate = if m.listens > 0 { m.new_tracks as f64 / m.listens as f64 } else { 0.0 };
+        write!(
+            w,
+            r#"{{"month":"{}","listens":{},"new_tracks":{},"new_albums":{},"new_artists":{},"rate":"#,
+            m.month, m.listens, m.new_tracks, m.new_albums, m.new_artists,
+        )?;
+        serde_json::to_writer(&mut w, &rate)?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the `loudness`, `true_peak`, and `dynamic_range` fields, in dB with two decimals.
+fn write_dynamics_json<W: Write>(mut w: W, dynamics: Option<&Dynamics>) -> io::Result<()> {
+    let round = |x: f64| (x * 100.0).round() / 100.0;
+    write!(w, r#""loudness":"#)?;
+    serde_json::to_writer(&mut w, &dynamics.map(|d| round(d.loudness)))?;
+    write!(w, r#","true_peak":"#)?;
+    serde_json::to_writer(&mut w, &dynamics.and_then(|d| d.true_peak).map(round))?;
+    write!(w, r#","dynamic_range":"#)?;
+    serde_json::to_writer(&mut w, &dynamics.and_then(|d| d.dynamic_range()).map(round))
+}
+
+/// Write a listing of album loudness and dynamics as json.
+pub fn write_album_dynamics_list_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    albums: &[(AlbumId, Dynamics)],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for (album_id, dynamics) in albums {
+        let album = index.get_album(*album_id).unwrap();
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","title":"#, album_id)?;
+        serde_json::to_writer(&mut w, index.get_string(album.title))?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(album.artist))?;
+        write!(w, r#","release_date":"{}","#, album.original_release_date)?;
+        write_dynamics_json(&mut w, Some(dynamics))?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write a listing of track loudness and dynamics as json.
+pub fn write_track_dynamics_list_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    tracks: &[(TrackId, Dynamics)],
+) -> io::Result<()> {
+    write!(w, "[")?;
+    let mut first = true;
+    for (track_id, dynamics) in tracks {
+        let track = index.get_track(*track_id).unwrap();
+        if !first { write!(w, ",")?; }
+        write!(w, r#"{{"id":"{}","album_id":"{}","title":"#, track_id, track_id.album_id())?;
+        serde_json::to_writer(&mut w, index.get_string(track.title))?;
+        write!(w, r#","artist":"#)?;
+        serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+        write!(w, ",")?;
+        write_dynamics_json(&mut w, Some(dynamics))?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+/// Write the loudness and dynamics of an album and its tracks as json.
+pub fn write_album_dynamics_json<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    album_id: AlbumId,
+    dynamics: &AlbumDynamics,
+) -> io::Result<()> {
+    write!(w, r#"{{"id":"{}","#, album_id)?;
+    write_dynamics_json(&mut w, dynamics.album.as_ref())?;
+    write!(w, r#","tracks":["#)?;
+    let mut first = true;
+    for (track_id, track_dynamics) in &dynamics.tracks {
+        let track = index.get_track(*track_id).unwrap();
+        if !first { write!(w, ",")?; }
+        write!(
+            w,
+            r#"{{"id":"{}","disc_number":{},"track_number":{},"title":"#,
+            track_id,
+            track_id.disc_number(),
+            track_id.track_number(),
+        )?;
+        serde_json::to_writer(&mut w, index.get_string(track.title))?;
+        write!(w, ",")?;
+        write_dynamics_json(&mut w, track_dynamics.as_ref())?;
+        write!(w, "}}")?;
+        first = false;
+    }
+    write!(w, "]}}")
+}
@@ -5,29 +5,84 @@
 // you may not use this file except in compliance with the License.
 // A copy of the License has been included in the root of the repository.
 
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
+use std::env;
 use std::fs;
-use std::io;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use tiny_http::{Header, Request, Response, ResponseBox, Server};
+use tiny_http::{Header, Request, Response, ResponseBox, Server, SslConfig, StatusCode};
 use tiny_http::Method::{Delete, Get, Post, Put, self};
 
-use crate::config::Config;
+use crate::acoustid;
+use crate::album_download;
+use crate::artist_alias;
+use crate::assets;
+use crate::auth;
+use crate::backup;
+use crate::browser_output;
+use crate::cast;
+use crate::config::{self, Config};
 use crate::database_utils;
 use crate::database as db;
 use crate::database::Connection;
+use crate::dbus;
+use crate::dlna;
+use crate::dynamics::{self, DynamicsParams};
+use crate::enrichment;
+use crate::error::{self, Error};
+use crate::events::{self, EventBus};
+use crate::generation::GenerationCache;
+use crate::graphql;
+use crate::gzip;
+use crate::http_utils::{self, ContentEncoding, RangeRequest};
+use crate::library_stats;
+use crate::library_view::ViewCache;
+use crate::limits::{self, LimitCounters, RateLimiter};
+use crate::listen_export;
+use crate::listen_import;
+use crate::listens::{self, ChartParams, ListenParams, StatsParams};
+use crate::listing::{self, ListParams, RandomParams, SortOrder};
+use crate::log;
+use crate::m3u;
+use crate::maintenance;
+use crate::metadata_edit::{self, Target};
+use crate::metrics;
+use crate::mpd;
+use crate::mpris;
 use crate::mvar::Var;
-use crate::player::{Millibel, Player, QueueId};
-use crate::prim::{ArtistId, AlbumId, TrackId};
+use crate::openapi;
+use crate::playback;
+use crate::player::{self, Millibel, PlaybackState, Player, QueueId, TrackSnapshot};
+use crate::playlist;
+use crate::prim::{ArtistId, AlbumId, Instant, TrackId};
+use crate::proxy;
+use crate::radio;
+use crate::reload;
 use crate::scan::BackgroundScanner;
 use crate::serialization;
+use crate::sessions;
+use crate::shuffle::Prng;
+use crate::shutdown;
+use crate::similar;
+use crate::smart_playlist::Query as SmartQuery;
+use crate::snapcast;
 use crate::string_utils::normalize_words;
+use crate::subsonic;
 use crate::systemd;
 use crate::thumb_cache::ThumbCache;
-use crate::user_data::{Rating, UserData};
+use crate::thumb_gen;
+use crate::tls;
+use crate::transcode;
+use crate::typeahead;
+use crate::user_data::{Rating, UserDataSet};
+use crate::xspf;
 use crate::{MetaIndex, MemoryMetaIndex};
 
 fn header_content_type(content_type: &str) -> Header {
@@ -35,6 +90,41 @@ fn header_content_type(content_type: &str) -> Header {
         .expect("Failed to create content-type header, value is not ascii.")
 }
 
+fn header_content_encoding(encoding: ContentEncoding) -> Header {
+    Header::from_bytes(&b"Content-Encoding"[..], encoding.as_str().as_bytes())
+        .expect("Failed to create Content-Encoding header, value is not ascii.")
+}
+
+/// Tell caches that the response depends on the `Accept-Encoding` header.
+fn header_vary_accept_encoding() -> Header {
+    Header::from_bytes(&b"Vary"[..], &b"Accept-Encoding"[..])
+        .expect("Failed to create Vary header, value is not ascii.")
+}
+
+/// Responses smaller than this are not worth compressing.
+const MIN_COMPRESS_LEN: usize = 1024;
+
+/// Build a response with the given body, gzipped if the client accepts that.
+fn compressible_response(
+    data: Vec<u8>,
+    content_type: &str,
+    encoding: ContentEncoding,
+) -> Response<io::Cursor<Vec<u8>>> {
+    let response = if encoding == ContentEncoding::Gzip && data.len() >= MIN_COMPRESS_LEN {
+        Response::from_data(gzip::compress(&data))
+            .with_header(header_content_encoding(ContentEncoding::Gzip))
+    } else {
+        Response::from_data(data)
+    };
+    response
+        .with_header(header_content_type(content_type))
+        .with_header(header_vary_accept_encoding())
+}
+
+fn json_response(data: Vec<u8>, encoding: ContentEncoding) -> Response<io::Cursor<Vec<u8>>> {
+    compressible_response(data, "application/json", encoding)
+}
+
 fn header_expires_seconds(age_seconds: i64) -> Header {
     let now = chrono::Utc::now();
     let at = now.checked_add_signed(chrono::Duration::seconds(age_seconds)).unwrap();
@@ -44,33 +134,199 @@ fn header_expires_seconds(age_seconds: i64) -> Header {
         .expect("Failed to create content-type header, value is not ascii.")
 }
 
+fn header_cache_control(value: &str) -> Header {
+    Header::from_bytes(&b"Cache-Control"[..], value.as_bytes())
+        .expect("Failed to create Cache-Control header, value is not ascii.")
+}
+
+fn header_etag(etag: &str) -> Header {
+    Header::from_bytes(&b"ETag"[..], etag.as_bytes())
+        .expect("Failed to create ETag header, value is not ascii.")
+}
+
+fn header_content_disposition_attachment(file_name: &str) -> Header {
+    let value = http_utils::content_disposition_attachment(file_name);
+    Header::from_bytes(&b"Content-Disposition"[..], value)
+        .expect("Failed to create Content-Disposition header, value is not ascii.")
+}
+
+fn header_last_modified(metadata: &fs::Metadata) -> Option<Header> {
+    let value = http_utils::format_http_date(metadata.modified().ok()?);
+    Header::from_bytes(&b"Last-Modified"[..], value).ok()
+}
+
+/// Return the value of the request header with the given name, if present.
+fn get_header<'a>(headers: &'a [Header], name: &'static str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+/// Return whether the client's cached copy is still valid, per `If-None-Match`.
+fn is_not_modified(headers: &[Header], etag: &str) -> bool {
+    match get_header(headers, "If-None-Match") {
+        Some(value) => http_utils::etag_matches(value, etag),
+        None => false,
+    }
+}
+
+/// Cache lifetime for resources that may change, but rarely do.
+///
+/// After this, browsers revalidate with the etag, which is cheap for us.
+const CACHE_CONTROL_REVALIDATE_DAILY: &str = "public, max-age=86400";
+
+/// Cache lifetime for content-addressed resources, which never change.
+const CACHE_CONTROL_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Clients may store the resource, but must revalidate it before every use.
+///
+/// We use this for the webinterface itself, so the service worker and the
+/// browser cache pick up a new version right away, at the cost of a 304.
+const CACHE_CONTROL_NO_CACHE: &str = "no-cache";
+
+/// Format the current time for storing in the database.
+fn format_now_iso8601() -> String {
+    let now = chrono::Utc::now();
+    let use_zulu_suffix = true;
+    now.to_rfc3339_opts(chrono::SecondsFormat::Millis, use_zulu_suffix)
+}
+
+/// The kind of playlists that a playlist modification applies to.
+#[derive(Copy, Clone)]
+enum PlaylistKind {
+    Any,
+    Static,
+    Smart,
+}
+
 pub struct MetaServer {
     config: Config,
     index_var: Var<MemoryMetaIndex>,
     thumb_cache_var: Var<ThumbCache>,
-    user_data: Arc<Mutex<UserData>>,
+    user_data: Arc<Mutex<UserDataSet>>,
     player: Player,
+
+    /// The players besides the default one, by name, see `config.players`.
+    extra_players: Vec<(String, Player)>,
+
     scanner: BackgroundScanner,
+    event_bus: Arc<EventBus>,
+
+    /// The secret in track and cover urls that we hand to cast devices.
+    ///
+    /// It is new at every start, so urls stop working after a restart.
+    cast_key: String,
+
+    /// Request budgets per client, if `rate_limit_per_minute` is set.
+    rate_limiter: Option<RateLimiter>,
+    limit_counters: LimitCounters,
+
+    /// Enqueue budgets per guest, if `party_mode` is enabled.
+    party_enqueue_limiter: Option<RateLimiter>,
+
+    /// Generation of the album list, for clients that cache it.
+    generation_cache: GenerationCache,
+
+    /// The part of the library that users with `library_view` rules see.
+    library_views: ViewCache,
+
+    /// The config file, and the settings from it that we can reload.
+    config_path: PathBuf,
+    settings: Mutex<reload::Settings>,
+}
+
+/// How the client reached us, possibly through a reverse proxy.
+struct RequestOrigin {
+    /// The address of the client, from `X-Forwarded-For` for trusted proxies.
+    client_ip: IpAddr,
+    /// Whether the client connected over https, to us or to a trusted proxy.
+    is_https: bool,
+    /// The url of the webinterface as the client sees it, without trailing slash.
+    base_url: String,
+    /// Who the request counts against, for rate limits and skip votes.
+    client: limits::Client,
 }
 
 impl MetaServer {
     pub fn new(
         config: Config,
+        config_path: PathBuf,
         index_var: Var<MemoryMetaIndex>,
         thumb_cache_var: Var<ThumbCache>,
-        user_data: Arc<Mutex<UserData>>,
+        user_data: Arc<Mutex<UserDataSet>>,
         player: Player,
+        extra_players: Vec<(String, Player)>,
+        event_bus: Arc<EventBus>,
     ) -> MetaServer {
+        let rate_limiter = config.rate_limit_per_minute.map(RateLimiter::new);
+        let party_enqueue_limiter = match config.party_mode {
+            true => Some(RateLimiter::per_hour(config.party_enqueues_per_hour)),
+            false => None,
+        };
+        let library_views = ViewCache::new(&config);
+        let settings = reload::Settings::from_config(&config);
         MetaServer {
             config: config,
             index_var: index_var.clone(),
             thumb_cache_var: thumb_cache_var.clone(),
             user_data: user_data,
             player: player,
+            extra_players: extra_players,
             scanner: BackgroundScanner::new(
                 index_var,
                 thumb_cache_var,
+                event_bus.clone(),
             ),
+            event_bus: event_bus,
+            cast_key: cast::generate_key().expect("Failed to generate cast key."),
+            rate_limiter: rate_limiter,
+            limit_counters: LimitCounters::default(),
+            party_enqueue_limiter: party_enqueue_limiter,
+            generation_cache: GenerationCache::new(),
+            library_views: library_views,
+            config_path: config_path,
+            settings: Mutex::new(settings),
+        }
+    }
+
+    /// Read the config file again, and apply the settings that we can reload.
+    ///
+    /// Returns the keys of the settings that changed, or a description of why
+    /// the config is invalid. Other settings only take effect after a restart.
+    pub fn reload_config(&self) -> std::result::Result<Vec<&'static str>, String> {
+        let config = Config::load(&self.config_path)
+            .map_err(|err| config::describe_error(&self.config_path, &err))?;
+        let new_settings = reload::Settings::from_config(&config);
+
+        let mut settings = self.settings.lock().unwrap();
+        let changed = settings.changed_keys(&new_settings);
+
+        if changed.contains(&"log_level") || changed.contains(&"log_format") {
+            // Like at startup, `MUSIUM_LOG` takes precedence over the config.
+            let filter = env::var("MUSIUM_LOG")
+                .ok()
+                .and_then(|value| log::Filter::from_str(&value).ok())
+                .unwrap_or_else(|| new_settings.log_level.clone());
+            log::init(filter, new_settings.log_format);
+        }
+        if changed.contains(&"lastfm_credentials") {
+            self.player.set_scrobble_credentials(new_settings.lastfm_credentials.clone());
+        }
+        if changed.contains(&"thumbnail_threads") {
+            thumb_gen::set_max_threads(new_settings.thumbnail_threads);
+        }
+
+        *settings = new_settings;
+        Ok(changed)
+    }
+
+    /// Return the index as the user sees it, restricted to their library view.
+    fn get_index(&self, user: Option<&str>) -> Arc<dyn MetaIndex> {
+        let index = self.index_var.get();
+        match self.library_views.get(&index, user) {
+            Some(view) => view,
+            None => index,
         }
     }
 
@@ -80,6 +336,152 @@ impl MetaServer {
             .boxed()
     }
 
+    fn handle_unauthorized(&self) -> ResponseBox {
+        Response::from_string("Unauthorized")
+            .with_status_code(401) // "401 Unauthorized"
+            .with_header(
+                Header::from_bytes(&b"WWW-Authenticate"[..], &b"Bearer"[..])
+                    .expect("Failed to create WWW-Authenticate header."),
+            )
+            .boxed()
+    }
+
+    fn handle_forbidden(&self) -> ResponseBox {
+        Response::from_string("The token does not grant access to this endpoint.")
+            .with_status_code(403) // "403 Forbidden"
+            .boxed()
+    }
+
+    /// Redirect to `location`, a path relative to the base path.
+    fn handle_redirect(&self, location: &str, cookie: Option<String>) -> ResponseBox {
+        let location = format!("{}{}", self.config.base_path, location);
+        let mut response = Response::empty(303) // "303 See Other"
+            .with_header(
+                Header::from_bytes(&b"Location"[..], location.as_bytes())
+                    .expect("Failed to create Location header, value is not ascii."),
+            );
+        if let Some(value) = cookie {
+            response.add_header(
+                Header::from_bytes(&b"Set-Cookie"[..], value.as_bytes())
+                    .expect("Failed to create Set-Cookie header, value is not ascii."),
+            );
+        }
+        response.boxed()
+    }
+
+    /// Return an error response if the request may not call the endpoint.
+    fn check_authorized(
+        &self,
+        request: &Request,
+        endpoint: &str,
+        arg1: Option<&str>,
+        arg2: Option<&str>,
+        raw_query: &str,
+    ) -> Option<ResponseBox> {
+        if self.config.unauthenticated {
+            return None;
+        }
+        // Cast devices cannot present a token, they get the cast key in the
+        // url instead, which grants access to tracks and covers only.
+        if request.method() == &Get && (endpoint == "track" || endpoint == "cover") {
+            if let Some(key) = MetaServer::get_query_param(raw_query, "cast_key") {
+                if auth::constant_time_eq(key.as_bytes(), self.cast_key.as_bytes()) {
+                    return None;
+                }
+            }
+        }
+        let required = match self.config.party_mode {
+            true => auth::required_scope_party(request.method(), endpoint, arg1, arg2),
+            false => auth::required_scope(request.method(), endpoint, arg1, arg2),
+        };
+        let token = match auth::authenticate(&self.config.api_tokens, request) {
+            None => return Some(self.handle_unauthorized()),
+            Some(token) if token.scope < required => return Some(self.handle_forbidden()),
+            Some(token) => token,
+        };
+
+        // In party mode, guests can only enqueue so many tracks per hour.
+        let is_enqueue = request.method() == &Put && endpoint == "queue" && arg1.is_some() && arg2.is_none();
+        match self.party_enqueue_limiter.as_ref() {
+            Some(limiter) if is_enqueue && token.scope < auth::Scope::Full => {
+                let client = limits::Client::Token(token.name.clone());
+                match limiter.check(client, std::time::Instant::now()) {
+                    Ok(()) => None,
+                    Err(retry_after_seconds) => Some(self.handle_too_many_requests(retry_after_seconds)),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Serve the webinterface, or the login page if we need a token first.
+    fn handle_index(&self, request: &Request) -> ResponseBox {
+        let needs_login = !self.config.unauthenticated
+            && auth::authenticate(&self.config.api_tokens, request).is_none();
+        if needs_login {
+            return self.handle_redirect("/login", None);
+        }
+        self.handle_static_file(request.headers(), "index.html")
+    }
+
+    /// Set the token cookie for the webinterface, if the token is valid.
+    fn handle_login(&self, body: &str, origin: &RequestOrigin) -> ResponseBox {
+        // The login form posts the token form-urlencoded, like a query string.
+        let token = match MetaServer::get_query_param(body, "token") {
+            Some(t) => t,
+            None => return self.handle_bad_request("Missing token."),
+        };
+        if !self.config.api_tokens.iter().any(|t| t.matches(&token)) {
+            // Log the address, so tools like fail2ban can act on it.
+            warn!("Login with invalid token from {}.", origin.client_ip);
+            return self.handle_redirect("/login?failed", None);
+        }
+        let mut cookie = format!(
+            "{}={}; Path={}/; Max-Age=31536000; HttpOnly; SameSite=Strict",
+            auth::COOKIE_NAME,
+            token,
+            self.config.base_path,
+        );
+        // Over TLS, tell the browser to never send the token unencrypted.
+        if origin.is_https {
+            cookie.push_str("; Secure");
+        }
+        self.handle_redirect("/", Some(cookie))
+    }
+
+    fn handle_logout(&self) -> ResponseBox {
+        let cookie = format!(
+            "{}=; Path={}/; Max-Age=0; HttpOnly; SameSite=Strict",
+            auth::COOKIE_NAME,
+            self.config.base_path,
+        );
+        self.handle_redirect("/login", Some(cookie))
+    }
+
+    fn handle_not_modified(&self, etag: &str, cache_control: &str) -> ResponseBox {
+        Response::empty(304) // "304 Not Modified"
+            .with_header(header_etag(etag))
+            .with_header(header_cache_control(cache_control))
+            .boxed()
+    }
+
+    fn handle_too_many_requests(&self, retry_after_seconds: u64) -> ResponseBox {
+        let retry_after = retry_after_seconds.to_string();
+        Response::from_string("Too many requests, please slow down.")
+            .with_status_code(429) // "429 Too Many Requests"
+            .with_header(
+                Header::from_bytes(&b"Retry-After"[..], retry_after.as_bytes())
+                    .expect("Failed to create Retry-After header."),
+            )
+            .boxed()
+    }
+
+    fn handle_payload_too_large(&self) -> ResponseBox {
+        Response::from_string("Request body is too large.")
+            .with_status_code(413) // "413 Payload Too Large"
+            .boxed()
+    }
+
     fn handle_bad_request(&self, reason: &'static str) -> ResponseBox {
         Response::from_string(reason)
             .with_status_code(400) // "400 Bad Request"
@@ -92,27 +494,91 @@ impl MetaServer {
             .boxed()
     }
 
-    fn handle_static_file(&self, fname: &str, mime_type: &str) -> ResponseBox {
-        let file = match fs::File::open(fname) {
-            Ok(f) => f,
-            Err(..) => return self.handle_error("Failed to read static file."),
+    fn handle_static_file(&self, headers: &[Header], name: &str) -> ResponseBox {
+        let content_type = match assets::content_type(name) {
+            Some(t) => t,
+            None => return self.handle_not_found(),
         };
-        Response::from_file(file)
-            .with_header(header_content_type(mime_type))
+        let dir = self.config.webinterface_dir.as_deref();
+        let data = match assets::load(dir, name, ContentEncoding::Identity) {
+            Some(data) => data,
+            None => return self.handle_not_found(),
+        };
+
+        // The etag is a hash of the file. We send different bytes per
+        // encoding, so the etag includes the encoding too. Clients must
+        // revalidate, so a new version of the webinterface shows up right
+        // away, also when the service worker cached the old one.
+        let hash = &crate::md5::md5_hex(&data)[..16];
+        let etag_for = |encoding: ContentEncoding| match encoding {
+            ContentEncoding::Identity => format!(r#""{}""#, hash),
+            _ => format!(r#""{}-{}""#, hash, encoding.as_str()),
+        };
+
+        // If there is a precompressed version of the file (`make compress` in
+        // app/ creates those), serve that. We can't produce Brotli ourselves,
+        // and it saves us from compressing app.js on every request.
+        let accept_encoding = get_header(headers, "Accept-Encoding");
+        for &encoding in [ContentEncoding::Brotli, ContentEncoding::Gzip].iter() {
+            if !http_utils::accepts_encoding(accept_encoding, encoding.as_str()) {
+                continue;
+            }
+            if let Some(compressed) = assets::load(dir, name, encoding) {
+                let etag = etag_for(encoding);
+                if is_not_modified(headers, &etag) {
+                    return self.handle_not_modified(&etag, CACHE_CONTROL_NO_CACHE);
+                }
+                return Response::from_data(compressed.into_owned())
+                    .with_header(header_content_type(content_type))
+                    .with_header(header_content_encoding(encoding))
+                    .with_header(header_vary_accept_encoding())
+                    .with_header(header_etag(&etag))
+                    .with_header(header_cache_control(CACHE_CONTROL_NO_CACHE))
+                    .boxed();
+            }
+        }
+
+        // All of our static files are text, so they compress well.
+        let encoding = match data.len() {
+            n if n < MIN_COMPRESS_LEN => ContentEncoding::Identity,
+            _ => http_utils::negotiate_encoding(accept_encoding, &[ContentEncoding::Gzip]),
+        };
+        let etag = etag_for(encoding);
+        if is_not_modified(headers, &etag) {
+            return self.handle_not_modified(&etag, CACHE_CONTROL_NO_CACHE);
+        }
+        compressible_response(data.into_owned(), content_type, encoding)
+            .with_header(header_etag(&etag))
+            .with_header(header_cache_control(CACHE_CONTROL_NO_CACHE))
             .boxed()
     }
 
-    fn handle_album_cover(&self, id: &str) -> ResponseBox {
+    fn handle_album_cover(&self, headers: &[Header], id: &str, user: Option<&str>) -> ResponseBox {
         let album_id = match AlbumId::parse(id) {
             Some(aid) => aid,
             None => return self.handle_bad_request("Invalid album id."),
         };
 
-        let index = &*self.index_var.get();
-        let tracks = index.get_album_tracks(album_id);
+        let index = &*self.get_index(user);
+        let tracks = match index.get_album(album_id) {
+            Some(..) => index.get_album_tracks(album_id),
+            None => return self.handle_not_found(),
+        };
         let track = &tracks.first().expect("Albums have at least one track.").track;
         let fname = index.get_filename(track.filename);
 
+        // The cover is embedded in the file, so the file's etag is a valid
+        // etag for the cover too, and we can check it without reading the
+        // picture.
+        let metadata = match fs::metadata(fname) {
+            Ok(m) => m,
+            Err(..) => return self.handle_error("Failed to open flac file."),
+        };
+        let etag = http_utils::file_etag(&metadata);
+        if is_not_modified(headers, &etag) {
+            return self.handle_not_modified(&etag, CACHE_CONTROL_REVALIDATE_DAILY);
+        }
+
         let opts = claxon::FlacReaderOptions {
             metadata_only: true,
             read_picture: claxon::ReadPicture::CoverAsVec,
@@ -126,22 +592,29 @@ impl MetaServer {
         if let Some(cover) = reader.into_pictures().pop() {
             let content_type = header_content_type(&cover.mime_type);
             let data = cover.into_vec();
-            Response::from_data(data)
+            let mut response = Response::from_data(data)
                 .with_header(content_type)
-                .with_header(header_expires_seconds(3600 * 24 * 30))
-                .boxed()
+                .with_header(header_etag(&etag))
+                .with_header(header_cache_control(CACHE_CONTROL_REVALIDATE_DAILY));
+            if let Some(header) = header_last_modified(&metadata) {
+                response.add_header(header);
+            }
+            response.boxed()
         } else {
             // The file has no embedded front cover.
             self.handle_not_found()
         }
     }
 
-    fn handle_thumb(&self, id: &str) -> ResponseBox {
+    fn handle_thumb(&self, headers: &[Header], id: &str, raw_query: &str, user: Option<&str>) -> ResponseBox {
         // TODO: DRY this track id parsing and loading part.
         let album_id = match AlbumId::parse(id) {
             Some(aid) => aid,
             None => return self.handle_bad_request("Invalid album id."),
         };
+        if self.library_views.has_view(user) && self.get_index(user).get_album(album_id).is_none() {
+            return self.handle_not_found();
+        }
 
         let thumb_cache = self.thumb_cache_var.get();
 
@@ -150,13 +623,27 @@ impl MetaServer {
             Some(bytes) => bytes,
         };
 
+        // The etag is a hash of the image. When the client asks for this
+        // exact version with the `v` parameter, the url is content-addressed,
+        // and the response can be cached forever.
+        let hash = &crate::md5::md5_hex(img)[..16];
+        let etag = format!(r#""{}""#, hash);
+        let cache_control = match MetaServer::get_query_param(raw_query, "v") {
+            Some(v) if v == hash => CACHE_CONTROL_IMMUTABLE,
+            _ => CACHE_CONTROL_REVALIDATE_DAILY,
+        };
+        if is_not_modified(headers, &etag) {
+            return self.handle_not_modified(&etag, cache_control);
+        }
+
         Response::from_data(img)
             .with_header(header_content_type("image/jpeg"))
-            .with_header(header_expires_seconds(3600 * 24 * 30))
+            .with_header(header_etag(&etag))
+            .with_header(header_cache_control(cache_control))
             .boxed()
     }
 
-    fn handle_waveform(&self, db: &mut Connection, id: &str) -> ResponseBox {
+    fn handle_waveform(&self, db: &mut Connection, id: &str, user: Option<&str>) -> ResponseBox {
         use crate::waveform::Waveform;
 
         // TODO: DRY this track id parsing and loading part.
@@ -164,6 +651,9 @@ impl MetaServer {
             Some(tid) => tid,
             None => return self.handle_bad_request("Invalid track id."),
         };
+        if self.library_views.has_view(user) && self.get_index(user).get_track(track_id).is_none() {
+            return self.handle_not_found();
+        }
 
         let waveform = db
             .begin()
@@ -177,7 +667,7 @@ impl MetaServer {
             Ok(Some(data)) => Waveform::from_bytes(data),
             Ok(None) => return self.handle_not_found(),
             Err(err) => {
-                eprintln!("Error while loading waveform: {:?}", err);
+                error!("Error while loading waveform: {:?}", err);
                 return self.handle_error("Database error.");
             }
         };
@@ -191,7 +681,48 @@ impl MetaServer {
             .boxed()
     }
 
-    fn handle_track(&self, path: &str) -> ResponseBox {
+    /// Read the transcode format from the `format` and `bitrate` query
+    /// parameters, or from a configured `profile`. `None` means serve the flac.
+    fn get_transcode_format(&self, raw_query: &str) -> Result<Option<transcode::Format>, &'static str> {
+        if let Some(name) = MetaServer::get_query_param(raw_query, "profile") {
+            return match self.config.get_transcode_profile(&name) {
+                Some(profile) => Ok(Some(profile.format)),
+                None => Err("Unknown transcode profile."),
+            };
+        }
+        let codec = match MetaServer::get_query_param(raw_query, "format") {
+            None => return Ok(None),
+            Some(f) if f == "flac" => return Ok(None),
+            Some(f) => transcode::Codec::from_str(&f)?,
+        };
+        let bitrate_kbps = match MetaServer::get_query_param(raw_query, "bitrate") {
+            None => None,
+            Some(b) => match u32::from_str(&b) {
+                Ok(kbps) => Some(kbps),
+                Err(..) => return Err("Invalid bitrate, must be an integer."),
+            },
+        };
+        transcode::Format::new(codec, bitrate_kbps).map(Some)
+    }
+
+    fn handle_track_transcode(&self, fname: &Path, format: transcode::Format) -> ResponseBox {
+        let reader = match transcode::spawn_transcode(fname, format) {
+            Ok(r) => r,
+            Err(err) => {
+                error!("Error while transcoding {:?}: {:?}", fname, err);
+                return self.handle_error("Failed to start transcoding.");
+            }
+        };
+        // We don't know the length up front, and we can't seek in the output,
+        // so this response is chunked, and it does not accept ranges.
+        let headers = vec![
+            header_content_type(format.codec.content_type()),
+            header_cache_control("no-cache"),
+        ];
+        Response::new(StatusCode(200), headers, reader, None, None).boxed()
+    }
+
+    fn handle_track(&self, headers: &[Header], path: &str, raw_query: &str, user: Option<&str>) -> ResponseBox {
         // Track urls are of the form `/track/f7c153f2b16dc101.flac`.
         if !path.ends_with(".flac") {
             return self.handle_bad_request("Expected a path ending in .flac.")
@@ -203,7 +734,21 @@ impl MetaServer {
             None => return self.handle_bad_request("Invalid track id."),
         };
 
-        let index = &*self.index_var.get();
+        match self.get_transcode_format(raw_query) {
+            Ok(format) => self.serve_track(headers, track_id, format, user),
+            Err(msg) => self.handle_bad_request(msg),
+        }
+    }
+
+    /// Serve the track as flac with support for ranges, or transcoded.
+    fn serve_track(
+        &self,
+        headers: &[Header],
+        track_id: TrackId,
+        format: Option<transcode::Format>,
+        user: Option<&str>,
+    ) -> ResponseBox {
+        let index = &*self.get_index(user);
         let track = match index.get_track(track_id) {
             Some(t) => t,
             None => return self.handle_not_found(),
@@ -211,26 +756,114 @@ impl MetaServer {
 
         let fname = index.get_filename(track.filename);
 
+        if let Some(format) = format {
+            return self.handle_track_transcode(Path::new(fname), format);
+        }
+
         // TODO: Rather than reading the file into memory in userspace,
         // use sendfile.
-        // TODO: Handle requests with Range header.
-        let file = match fs::File::open(fname) {
+        let mut file = match fs::File::open(fname) {
             Ok(f) => f,
             Err(_) => return self.handle_error("Failed to open file."),
         };
+        let metadata = match file.metadata() {
+            Ok(m) => m,
+            Err(_) => return self.handle_error("Failed to open file."),
+        };
+        let len = metadata.len();
+        let etag = http_utils::file_etag(&metadata);
 
-        Response::from_file(file)
-            .with_header(header_content_type("audio/flac"))
-            .boxed()
+        if is_not_modified(headers, &etag) {
+            return self.handle_not_modified(&etag, CACHE_CONTROL_REVALIDATE_DAILY);
+        }
+
+        let mut response_headers = vec![
+            header_content_type("audio/flac"),
+            header_etag(&etag),
+            header_cache_control(CACHE_CONTROL_REVALIDATE_DAILY),
+            Header::from_bytes(&b"Accept-Ranges"[..], &b"bytes"[..])
+                .expect("Failed to create Accept-Ranges header."),
+        ];
+        response_headers.extend(header_last_modified(&metadata));
+
+        // With If-Range, the client only wants the range if its cached part
+        // is still valid, otherwise it wants the full file.
+        let range_header = match get_header(headers, "If-Range") {
+            Some(if_range) if if_range != etag => None,
+            _ => get_header(headers, "Range"),
+        };
+
+        match http_utils::parse_range(range_header, len) {
+            RangeRequest::Full => Response::new(
+                StatusCode(200),
+                response_headers,
+                file,
+                Some(len as usize),
+                None,
+            ).boxed(),
+            RangeRequest::Partial(range) => {
+                if file.seek(SeekFrom::Start(range.start)).is_err() {
+                    return self.handle_error("Failed to seek in file.");
+                }
+                let content_range = format!("bytes {}-{}/{}", range.start, range.end, len);
+                response_headers.push(
+                    Header::from_bytes(&b"Content-Range"[..], content_range)
+                        .expect("Failed to create Content-Range header."),
+                );
+                Response::new(
+                    StatusCode(206), // "206 Partial Content"
+                    response_headers,
+                    file.take(range.len()),
+                    Some(range.len() as usize),
+                    None,
+                ).boxed()
+            }
+            RangeRequest::Unsatisfiable => {
+                let content_range = format!("bytes */{}", len);
+                Response::empty(416) // "416 Range Not Satisfiable"
+                    .with_header(
+                        Header::from_bytes(&b"Content-Range"[..], content_range)
+                            .expect("Failed to create Content-Range header."),
+                    )
+                    .boxed()
+            }
+        }
     }
 
-    fn handle_album(&self, id: &str) -> ResponseBox {
+    fn handle_album_download(&self, id: &str, raw_query: &str, user: Option<&str>) -> ResponseBox {
         let album_id = match AlbumId::parse(id) {
             Some(aid) => aid,
             None => return self.handle_bad_request("Invalid album id."),
         };
+        let format = match self.get_transcode_format(raw_query) {
+            Ok(f) => f,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
 
-        let index = &*self.index_var.get();
+        let index = &*self.get_index(user);
+        let archive_name = match album_download::get_archive_name(index, album_id) {
+            Some(name) => name,
+            None => return self.handle_not_found(),
+        };
+        let files = album_download::get_album_files(index, album_id, &archive_name, format);
+
+        // The archive can be large, so we stream it from a separate thread.
+        // We don't know its length up front, so the response is chunked.
+        let reader = album_download::spawn_download(files, format);
+        let headers = vec![
+            header_content_type("application/zip"),
+            header_content_disposition_attachment(&format!("{}.zip", archive_name)),
+        ];
+        Response::new(StatusCode(200), headers, reader, None, None).boxed()
+    }
+
+    fn handle_album(&self, id: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let album_id = match AlbumId::parse(id) {
+            Some(aid) => aid,
+            None => return self.handle_bad_request("Invalid album id."),
+        };
+
+        let index = &*self.get_index(user);
         let album = match index.get_album(album_id) {
             Some(a) => a,
             None => return self.handle_not_found(),
@@ -241,360 +874,3931 @@ impl MetaServer {
 
         serialization::write_album_json(
             index,
-            &self.user_data.lock().unwrap(),
+            self.user_data.lock().unwrap().get(user),
             &mut w,
             album_id,
             album,
         ).unwrap();
 
-        Response::from_data(w.into_inner())
-            .with_header(header_content_type("application/json"))
-            .boxed()
+        json_response(w.into_inner(), encoding).boxed()
     }
 
-    fn handle_artist(&self, id: &str) -> ResponseBox {
-        let artist_id = match ArtistId::parse(id) {
+    fn handle_album_dynamics(&self, db: &mut Connection, id: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let album_id = match AlbumId::parse(id) {
             Some(aid) => aid,
-            None => return self.handle_bad_request("Invalid artist id."),
+            None => return self.handle_bad_request("Invalid album id."),
         };
 
-        let index = &*self.index_var.get();
-        let artist = match index.get_artist(artist_id) {
-            Some(a) => a,
-            None => return self.handle_not_found(),
-        };
+        let index = &*self.get_index(user);
+        if index.get_album(album_id).is_none() {
+            return self.handle_not_found();
+        }
 
-        let albums = index.get_albums_by_artist(artist_id);
+        let dynamics = db
+            .begin()
+            .and_then(|mut tx| {
+                let dynamics = dynamics::get_album_dynamics(&mut tx, index, album_id)?;
+                tx.commit()?;
+                Ok(dynamics)
+            });
+
+        let dynamics = match dynamics {
+            Ok(dynamics) => dynamics,
+            Err(err) => {
+                error!("Error while loading album dynamics: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
 
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
-        serialization::write_artist_json(index, &mut w, artist, albums).unwrap();
+        serialization::write_album_dynamics_json(index, &mut w, album_id, &dynamics).unwrap();
 
-        Response::from_data(w.into_inner())
-            .with_header(header_content_type("application/json"))
-            .boxed()
+        json_response(w.into_inner(), encoding).boxed()
     }
 
-    fn handle_albums(&self) -> ResponseBox {
-        let index = &*self.index_var.get();
+    fn handle_dynamics(
+        &self,
+        db: &mut Connection,
+        kind: &str,
+        raw_query: &str,
+        user: Option<&str>,
+        encoding: ContentEncoding,
+    ) -> ResponseBox {
+        let params = match DynamicsParams::parse(raw_query) {
+            Ok(params) => params,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let index = &*self.get_index(user);
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
-        serialization::write_albums_json(index, &mut w).unwrap();
 
-        Response::from_data(w.into_inner())
-            .with_header(header_content_type("application/json"))
-            .boxed()
-    }
+        let result = db
+            .begin()
+            .and_then(|mut tx| {
+                let found = match kind {
+                    "albums" => {
+                        let albums = dynamics::list_album_dynamics(&mut tx, index, &params)?;
+                        serialization::write_album_dynamics_list_json(index, &mut w, &albums).unwrap();
+                        true
+                    }
+                    "tracks" => {
+                        let tracks = dynamics::list_track_dynamics(&mut tx, index, &params)?;
+                        serialization::write_track_dynamics_list_json(index, &mut w, &tracks).unwrap();
+                        true
+                    }
+                    _ => false,
+                };
+                tx.commit()?;
+                Ok(found)
+            });
 
-    fn handle_rating(&self, track_id: &str, rating_str: &str) -> ResponseBox {
-        let rating = match i64::from_str(rating_str)
-            .map_err(|_| "Failed to parse rating.")
-            .and_then(Rating::try_from)
-        {
-            Ok(r) => r,
-            Err(_) => return self.handle_bad_request("Invalid rating."),
-        };
+        match result {
+            Ok(true) => json_response(w.into_inner(), encoding).boxed(),
+            Ok(false) => self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading dynamics: {:?}", err);
+                self.handle_error("Database error.")
+            }
+        }
+    }
 
-        let track_id = match TrackId::parse(track_id) {
-            Some(tid) => tid,
-            None => return self.handle_bad_request("Invalid track id."),
+    fn handle_artist(&self, db: &mut Connection, id: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let artist_id = match ArtistId::parse(id) {
+            Some(aid) => aid,
+            None => return self.handle_bad_request("Invalid artist id."),
         };
 
-        let index = &*self.index_var.get();
-
-        // Confirm that the track exists before we store its rating.
-        let _track = match index.get_track(track_id) {
-            Some(t) => t,
+        let index = &*self.get_index(user);
+        let artist = match index.get_artist(artist_id) {
+            Some(a) => a,
             None => return self.handle_not_found(),
         };
 
-        // Send the new rating to the history thread.
-        self.player.set_track_rating(track_id, rating);
-
-        // The history thread will write to the database and update the user
-        // data afterwards.
-        Response::empty(202).boxed()
-    }
+        let albums = index.get_albums_by_artist(artist_id);
+        let tags = match self.get_artist_enrichment(db, artist_id, index.get_string(artist.name)) {
+            Some(info) => info.tags,
+            None => Vec::new(),
+        };
 
-    fn handle_queue(&self) -> ResponseBox {
-        let index = &*self.index_var.get();
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
-        let queue = self.player.get_queue();
-        serialization::write_queue_json(
+        serialization::write_artist_json(
             index,
-            &self.user_data.lock().unwrap(),
+            self.user_data.lock().unwrap().get(user),
             &mut w,
-            &queue.tracks[..],
+            artist_id,
+            artist,
+            albums,
+            &tags,
         ).unwrap();
-        Response::from_data(w.into_inner())
-            .with_header(header_content_type("application/json"))
-            .boxed()
+
+        json_response(w.into_inner(), encoding).boxed()
     }
 
-    fn handle_enqueue(&self, id: &str) -> ResponseBox {
-        let track_id = match TrackId::parse(id) {
-            Some(tid) => tid,
-            None => return self.handle_bad_request("Invalid track id."),
-        };
+    fn handle_similar_artists(&self, db: &mut Connection, id: &str, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let artist_id = match ArtistId::parse(id) {
+            Some(aid) => aid,
+            None => return self.handle_bad_request("Invalid artist id."),
+        };
+        let limit = match MetaServer::get_limit_param(raw_query, 10) {
+            Ok(n) => n,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let index = &*self.get_index(user);
+        let artist = match index.get_artist(artist_id) {
+            Some(a) => a,
+            None => return self.handle_not_found(),
+        };
+
+        let artists = db
+            .begin()
+            .and_then(|mut tx| {
+                let artists = similar::get_similar_artists(&mut tx, artist_id, user)?;
+                tx.commit()?;
+                Ok(artists)
+            });
+
+        let mut artists = match artists {
+            Ok(artists) => artists,
+            Err(err) => {
+                error!("Error while finding similar artists: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        if let Some(info) = self.get_artist_enrichment(db, artist_id, index.get_string(artist.name)) {
+            let external = enrichment::match_artists(index, &info.similar);
+            artists = enrichment::blend(artists, &external, artist_id);
+        }
+
+        // The listens can be of artists that are no longer in the library.
+        let artists: Vec<ArtistId> = artists
+            .into_iter()
+            .filter(|a| index.get_artist(*a).is_some())
+            .take(limit)
+            .collect();
+
+        let user_data = self.user_data.lock().unwrap();
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_artists_json(index, user_data.get(user), &mut w, &artists[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_similar_tracks(&self, db: &mut Connection, id: &str, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let track_id = match TrackId::parse(id) {
+            Some(tid) => tid,
+            None => return self.handle_bad_request("Invalid track id."),
+        };
+        let limit = match MetaServer::get_limit_param(raw_query, 10) {
+            Ok(n) => n,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let index = &*self.get_index(user);
+        let album = match index.get_album(track_id.album_id()) {
+            Some(album) if index.get_track(track_id).is_some() => album,
+            _ => return self.handle_not_found(),
+        };
+
+        let tracks = db
+            .begin()
+            .and_then(|mut tx| {
+                let tracks = similar::get_similar_tracks(&mut tx, track_id, user)?;
+                tx.commit()?;
+                Ok(tracks)
+            });
+
+        let mut tracks = match tracks {
+            Ok(tracks) => tracks,
+            Err(err) => {
+                error!("Error while finding similar tracks: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        // From the external similar artists of the album artist, we suggest
+        // the track that the user likes best.
+        if let Some(&artist_id) = index.get_album_artists(album.artist_ids).first() {
+            let artist = index.get_artist(artist_id).unwrap();
+            if let Some(info) = self.get_artist_enrichment(db, artist_id, index.get_string(artist.name)) {
+                let external = enrichment::match_artists(index, &info.similar);
+                let external = enrichment::blend(Vec::new(), &external, artist_id);
+                let user_data = self.user_data.lock().unwrap();
+                tracks = enrichment::blend_tracks(index, user_data.get(user), tracks, &external);
+            }
+        }
+
+        // The listens can be of tracks that are no longer in the library.
+        let tracks: Vec<TrackId> = tracks
+            .into_iter()
+            .filter(|t| index.get_track(*t).is_some())
+            .take(limit)
+            .collect();
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_tracks_json(index, &mut w, &tracks[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    /// Return the similar artists and tags from the external service, if `enrichment` is configured.
+    fn get_artist_enrichment(&self, db: &mut Connection, artist_id: ArtistId, name: &str) -> Option<enrichment::ArtistInfo> {
+        let source = self.config.enrichment?;
+        let api_key = self.config.lastfm_api_key.as_deref();
+        match enrichment::get_artist_info(db, source, api_key, artist_id, name) {
+            Ok(info) => Some(info),
+            Err(err) => {
+                error!("Error while loading similar artists from {}: {:?}", source.name(), err);
+                None
+            }
+        }
+    }
+
+    fn handle_albums(&self, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let params = match ListParams::parse(raw_query) {
+            Ok(p) => p,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let index = &*self.get_index(user);
+        let user_data = self.user_data.lock().unwrap();
+        let user_data = user_data.get(user);
+        let albums = listing::list_albums(index, user_data, &params);
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_albums_json(index, user_data, &mut w, &albums[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_albums_recent(&self, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let mut params = match ListParams::parse(raw_query) {
+            Ok(p) => p,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        // This endpoint is meant for a "new in your library" shelf, so unlike
+        // the full listing, it has a default limit.
+        params.sort = SortOrder::RecentlyAdded;
+        params.limit = Some(params.limit.unwrap_or(25));
+
+        let index = &*self.get_index(user);
+        let user_data = self.user_data.lock().unwrap();
+        let user_data = user_data.get(user);
+        let albums = listing::list_albums(index, user_data, &params);
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_albums_json(index, user_data, &mut w, &albums[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_albums_recently_played(&self, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let mut params = match ListParams::parse(raw_query) {
+            Ok(p) => p,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        // Like the recently added albums, this is meant for a shelf on the
+        // home screen, so it has a default limit.
+        params.limit = Some(params.limit.unwrap_or(25));
+
+        let index = &*self.get_index(user);
+        let user_data = self.user_data.lock().unwrap();
+        let user_data = user_data.get(user);
+        let albums = listing::recently_played_albums(index, user_data, &params);
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_albums_json(index, user_data, &mut w, &albums[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_albums_in_progress(&self, db: &mut Connection, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let limit = match MetaServer::get_limit_param(raw_query, 10) {
+            Ok(n) => n,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let index = &*self.get_index(user);
+        let albums = db
+            .begin()
+            .and_then(|mut tx| {
+                let albums = listens::get_albums_in_progress(&mut tx, index, user, limit)?;
+                tx.commit()?;
+                Ok(albums)
+            });
+
+        let albums = match albums {
+            Ok(albums) => albums,
+            Err(err) => {
+                error!("Error while loading albums in progress: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let user_data = self.user_data.lock().unwrap();
+        let user_data = user_data.get(user);
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_albums_in_progress_json(index, user_data, &mut w, &albums[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_albums_random(&self, db: &mut Connection, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let params = match RandomParams::parse(raw_query) {
+            Ok(p) => p,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        // We only need to know which albums have been played if we filter on
+        // that, so avoid the table scan otherwise.
+        let mut played = HashSet::new();
+        if params.never_played {
+            let result = db
+                .begin()
+                .and_then(|mut tx| {
+                    for row in db::iter_album_first_listens(&mut tx)? {
+                        let (album_id, _started_at) = row?;
+                        played.insert(AlbumId(album_id as u64));
+                    }
+                    tx.commit()
+                });
+            if let Err(err) = result {
+                error!("Error while loading listened albums: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        }
+
+        let index = &*self.get_index(user);
+        let user_data = self.user_data.lock().unwrap();
+        let user_data = user_data.get(user);
+        let mut rng = Prng::new();
+        let albums = listing::random_albums(index, user_data, &params, &played, &mut rng);
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_albums_json(index, user_data, &mut w, &albums[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_artists(&self, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let params = match ListParams::parse(raw_query) {
+            Ok(p) => p,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let index = &*self.get_index(user);
+        let user_data = self.user_data.lock().unwrap();
+        let user_data = user_data.get(user);
+        let artists = listing::list_artists(index, user_data, &params);
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_artists_json(index, user_data, &mut w, &artists[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_tracks(&self, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let params = match ListParams::parse(raw_query) {
+            Ok(p) => p,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let index = &*self.get_index(user);
+        let user_data = self.user_data.lock().unwrap();
+        let tracks = listing::list_tracks(index, user_data.get(user), &params);
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_tracks_json(index, &mut w, &tracks[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    /// Parse the rating from the url. No rating means clearing it to neutral.
+    fn parse_rating(rating_str: Option<&str>) -> Result<Rating, &'static str> {
+        match rating_str {
+            None => Ok(Rating::Neutral),
+            Some(r) => i64::from_str(r)
+                .map_err(|_| "Failed to parse rating.")
+                .and_then(Rating::try_from),
+        }
+    }
+
+    fn handle_rating(&self, track_id: &str, rating_str: Option<&str>, user: Option<&str>) -> ResponseBox {
+        let rating = match MetaServer::parse_rating(rating_str) {
+            Ok(r) => r,
+            Err(_) => return self.handle_bad_request("Invalid rating."),
+        };
+
+        let track_id = match TrackId::parse(track_id) {
+            Some(tid) => tid,
+            None => return self.handle_bad_request("Invalid track id."),
+        };
+
+        let index = &*self.get_index(user);
+
+        // Confirm that the track exists before we store its rating.
+        let _track = match index.get_track(track_id) {
+            Some(t) => t,
+            None => return self.handle_not_found(),
+        };
+
+        // Send the new rating to the history thread.
+        self.player.set_track_rating(track_id, rating, user);
+
+        // The history thread will write to the database and update the user
+        // data afterwards.
+        Response::empty(202).boxed()
+    }
+
+    fn handle_album_rating(&self, album_id: &str, rating_str: Option<&str>, user: Option<&str>) -> ResponseBox {
+        let rating = match MetaServer::parse_rating(rating_str) {
+            Ok(r) => r,
+            Err(_) => return self.handle_bad_request("Invalid rating."),
+        };
+
+        let album_id = match AlbumId::parse(album_id) {
+            Some(aid) => aid,
+            None => return self.handle_bad_request("Invalid album id."),
+        };
+
+        let index = &*self.get_index(user);
+        if index.get_album(album_id).is_none() {
+            return self.handle_not_found();
+        }
+
+        self.player.set_album_rating(album_id, rating, user);
+        Response::empty(202).boxed()
+    }
+
+    fn handle_artist_rating(&self, artist_id: &str, rating_str: Option<&str>, user: Option<&str>) -> ResponseBox {
+        let rating = match MetaServer::parse_rating(rating_str) {
+            Ok(r) => r,
+            Err(_) => return self.handle_bad_request("Invalid rating."),
+        };
+
+        let artist_id = match ArtistId::parse(artist_id) {
+            Some(aid) => aid,
+            None => return self.handle_bad_request("Invalid artist id."),
+        };
+
+        let index = &*self.get_index(user);
+        if index.get_artist(artist_id).is_none() {
+            return self.handle_not_found();
+        }
+
+        self.player.set_artist_rating(artist_id, rating, user);
+        Response::empty(202).boxed()
+    }
+
+    /// Toggle the currently playing track between loved and neutral.
+    /// Exclude the track or album from shuffling, or include it again.
+    fn handle_exclusion(
+        &self,
+        db: &mut Connection,
+        kind: &str,
+        id: &str,
+        excluded: bool,
+        user: Option<&str>,
+    ) -> ResponseBox {
+        let index = &*self.get_index(user);
+        let now = format_now_iso8601();
+        let result = if kind == "track" {
+            let track_id = match TrackId::parse(id) {
+                Some(tid) => tid,
+                None => return self.handle_bad_request("Invalid track id."),
+            };
+            if index.get_track(track_id).is_none() {
+                return self.handle_not_found();
+            }
+            database_utils::with_write_transaction(db, |tx| match excluded {
+                true => db::insert_track_exclusion(tx, track_id.0 as i64, &now, user),
+                false => db::delete_track_exclusion(tx, track_id.0 as i64, user),
+            })
+            .map(|()| self.user_data.lock().unwrap().get_mut(user).set_track_excluded(track_id, excluded))
+        } else {
+            let album_id = match AlbumId::parse(id) {
+                Some(aid) => aid,
+                None => return self.handle_bad_request("Invalid album id."),
+            };
+            if index.get_album(album_id).is_none() {
+                return self.handle_not_found();
+            }
+            database_utils::with_write_transaction(db, |tx| match excluded {
+                true => db::insert_album_exclusion(tx, album_id.0 as i64, &now, user),
+                false => db::delete_album_exclusion(tx, album_id.0 as i64, user),
+            })
+            .map(|()| self.user_data.lock().unwrap().get_mut(user).set_album_excluded(album_id, excluded))
+        };
+
+        match result {
+            Ok(()) => Response::empty(204).boxed(),
+            Err(err) => {
+                error!("Error while saving exclusion: {:?}", err);
+                self.handle_error("Database error.")
+            }
+        }
+    }
+
+    fn handle_toggle_love(&self, player: &Player, user: Option<&str>) -> ResponseBox {
+        let now_playing = player.get_now_playing();
+        // A radio station can't be loved, only tracks in the library can.
+        let track_id = match now_playing.current.and_then(|t| t.source.track_id()) {
+            Some(t) => t,
+            None => return self.handle_not_found(),
+        };
+
+        let rating = match self.user_data.lock().unwrap().get(user).get_track_rating(track_id) {
+            Rating::Love => Rating::Neutral,
+            _ => Rating::Love,
+        };
+
+        // Like for other ratings, the history thread will write the new rating
+        // to the database and update the user data.
+        player.set_track_rating(track_id, rating, user);
+
+        let rating_json = format!(r#"{{"track_id":"{}","rating":{}}}"#, track_id, rating as i8);
+        Response::from_string(rating_json)
+            .with_status_code(202) // "202 Accepted"
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_favorites(&self, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let index = &*self.get_index(user);
+        let mut tracks = self.user_data.lock().unwrap().get(user).get_loved_tracks();
+
+        // The user data may contain ratings for tracks that are no longer in
+        // the library, we don't list those.
+        tracks.retain(|&track_id| index.get_track(track_id).is_some());
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_tracks_json(index, &mut w, &tracks[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    /// Return the value of the query parameter `key`, if it is present.
+    fn get_query_param(raw_query: &str, key: &str) -> Option<String> {
+        url::form_urlencoded::parse(raw_query.as_bytes())
+            .find(|(k, _v)| k == key)
+            .map(|(_k, v)| v.into_owned())
+    }
+
+    /// Return the `limit` query parameter, or the default when it is absent.
+    fn get_limit_param(raw_query: &str, default: usize) -> Result<usize, &'static str> {
+        match MetaServer::get_query_param(raw_query, "limit") {
+            Some(n) => usize::from_str(&n).map_err(|_| "Invalid limit, must be a non-negative integer."),
+            None => Ok(default),
+        }
+    }
+
+    /// Read the `name` query parameter for creating or renaming a playlist.
+    fn get_playlist_name(raw_query: &str) -> Result<String, &'static str> {
+        match MetaServer::get_query_param(raw_query, "name") {
+            Some(name) if !name.trim().is_empty() => Ok(name.trim().to_string()),
+            Some(_) => Err("Playlist name must not be empty."),
+            None => Err("Missing playlist name."),
+        }
+    }
+
+    /// Read the optional `client` query parameter when enqueueing tracks.
+    fn get_client(raw_query: &str) -> Result<Option<String>, &'static str> {
+        match MetaServer::get_query_param(raw_query, "client") {
+            Some(name) => match listens::parse_client(&name) {
+                Some(client) => Ok(Some(client)),
+                None => Err("Invalid client, must be a name of 1 to 64 bytes."),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn handle_listens(&self, db: &mut Connection, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let params = match ListenParams::parse(raw_query) {
+            Ok(p) => p,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let page = db
+            .begin()
+            .and_then(|mut tx| {
+                let page = listens::get_page(&mut tx, &params, user)?;
+                tx.commit()?;
+                Ok(page)
+            });
+
+        let (listens, next_cursor) = match page {
+            Ok(page) => page,
+            Err(err) => {
+                error!("Error while loading listens: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_listens_json(&mut w, &listens[..], next_cursor).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_edit_metadata(
+        &self,
+        db: &mut Connection,
+        endpoint: &str,
+        id: &str,
+        raw_query: &str,
+        user: Option<&str>,
+    ) -> ResponseBox {
+        let target = match endpoint {
+            "track" => match TrackId::parse(id) {
+                Some(tid) => Target::Track(tid),
+                None => return self.handle_bad_request("Invalid track id."),
+            },
+            _ => match AlbumId::parse(id) {
+                Some(aid) => Target::Album(aid),
+                None => return self.handle_bad_request("Invalid album id."),
+            },
+        };
+        let edits = match metadata_edit::parse_edits(target, raw_query) {
+            Ok(edits) => edits,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+        let is_true = |key| MetaServer::get_query_param(raw_query, key).as_deref() == Some("true");
+        let dry_run = is_true("dry_run");
+        let write_tags = is_true("write_tags");
+
+        // We edit the full index, library views only hide tracks.
+        let index = self.index_var.get();
+        let changes = db.begin().and_then(|mut tx| {
+            let changes = metadata_edit::plan(&mut tx, &index, target, &edits)?;
+            tx.commit()?;
+            Ok(changes)
+        });
+        let changes = match changes {
+            Ok(Some(changes)) => changes,
+            Ok(None) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading tags: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let mut written = Vec::new();
+        if !dry_run && !changes.is_empty() {
+            if write_tags {
+                written = match metadata_edit::write_files(&changes) {
+                    Ok(written) => written,
+                    Err(err) => {
+                        error!("Error while writing tags: {:?}", err);
+                        return self.handle_error("Failed to write the tags to the files.");
+                    }
+                };
+            }
+
+            let edited_at = format_now_iso8601();
+            let result = database_utils::with_write_transaction(db, |tx| {
+                metadata_edit::apply(tx, &changes, &written, &edited_at, user)
+            });
+            if let Err(err) = result {
+                error!("Error while storing metadata edits: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+
+            // Publish a new index with the edits, like a scan does.
+            if let Err(err) = self.rebuild_index(db) {
+                error!("Error while rebuilding the index: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        }
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_metadata_changes_json(&mut w, &changes, dry_run, &written).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Rebuild the index from the database and publish it, like a scan does.
+    fn rebuild_index(&self, db: &mut Connection) -> error::Result<Arc<MemoryMetaIndex>> {
+        let mut tx = db.begin()?;
+        let (new_index, _builder) = MemoryMetaIndex::from_database(&mut tx, self.config.album_identity)?;
+        tx.commit()?;
+        let new_index = Arc::new(new_index);
+        self.index_var.set(new_index.clone());
+        self.event_bus.publish(events::Event::LibraryUpdated);
+        Ok(new_index)
+    }
+
+    fn handle_merge_artist(
+        &self,
+        db: &mut Connection,
+        id: &str,
+        canonical_id: &str,
+    ) -> ResponseBox {
+        let (artist_id, canonical_id) = match (ArtistId::parse(id), ArtistId::parse(canonical_id)) {
+            (Some(a), Some(c)) => (a, c),
+            _ => return self.handle_bad_request("Invalid artist id."),
+        };
+
+        let index = self.index_var.get();
+        let name = match index.get_artist(artist_id) {
+            Some(artist) => index.get_string(artist.name).to_string(),
+            None => return self.handle_not_found(),
+        };
+
+        let result = database_utils::with_write_transaction(db, |tx| {
+            let aliases: HashMap<_, _> = artist_alias::load_aliases(tx)?
+                .into_iter()
+                .map(|alias| (alias.artist_id, alias.canonical_artist_id))
+                .collect();
+            let canonical_id = match artist_alias::resolve_canonical(&aliases, artist_id, canonical_id) {
+                Ok(canonical_id) if index.get_artist(canonical_id).is_some() => canonical_id,
+                Ok(_) => return Ok(Err(None)),
+                Err(msg) => return Ok(Err(Some(msg))),
+            };
+            artist_alias::merge(tx, artist_id, canonical_id, &name, &format_now_iso8601())?;
+            Ok(Ok(canonical_id))
+        });
+        let canonical_id = match result {
+            Ok(Ok(canonical_id)) => canonical_id,
+            Ok(Err(None)) => return self.handle_not_found(),
+            Ok(Err(Some(msg))) => return self.handle_bad_request(msg),
+            Err(err) => {
+                error!("Error while merging artists: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        if let Err(err) = self.rebuild_index(db).and_then(|_| self.reload_user_data(db)) {
+            error!("Error while rebuilding the index: {:?}", err);
+            return self.handle_error("Database error.");
+        }
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_artist_merge_json(&mut w, artist_id, canonical_id).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_unmerge_artist(&self, db: &mut Connection, id: &str) -> ResponseBox {
+        let artist_id = match ArtistId::parse(id) {
+            Some(aid) => aid,
+            None => return self.handle_bad_request("Invalid artist id."),
+        };
+
+        let result = database_utils::with_write_transaction(db, |tx| {
+            artist_alias::unmerge(tx, artist_id)
+        });
+        let canonical_id = match result {
+            Ok(Some(canonical_id)) => canonical_id,
+            Ok(None) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while undoing an artist merge: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        // Only with the alias back in the index do we know its albums, and
+        // which listens belong to it again.
+        let result = self.rebuild_index(db).and_then(|index| {
+            database_utils::with_write_transaction(db, |tx| {
+                artist_alias::restore_listens(tx, &index, artist_id, canonical_id)
+            })?;
+            self.reload_user_data(db)
+        });
+        if let Err(err) = result {
+            error!("Error while undoing an artist merge: {:?}", err);
+            return self.handle_error("Database error.");
+        }
+
+        self.handle_artist_aliases(db, ContentEncoding::Identity)
+    }
+
+    fn handle_artist_aliases(&self, db: &mut Connection, encoding: ContentEncoding) -> ResponseBox {
+        let aliases = db.begin().and_then(|mut tx| {
+            let aliases = artist_alias::load_aliases(&mut tx)?;
+            tx.commit()?;
+            Ok(aliases)
+        });
+        let aliases = match aliases {
+            Ok(aliases) => aliases,
+            Err(err) => {
+                error!("Error while loading artist aliases: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let index = self.index_var.get();
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_artist_aliases_json(&*index, &mut w, &aliases).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_acoustid_proposals(&self, db: &mut Connection, encoding: ContentEncoding) -> ResponseBox {
+        let proposals = db.begin().and_then(|mut tx| {
+            let proposals = db::iter_acoustid_proposals_pending(&mut tx)?.collect::<db::Result<Vec<_>>>()?;
+            tx.commit()?;
+            Ok(proposals)
+        });
+        let proposals = match proposals {
+            Ok(proposals) => proposals,
+            Err(err) => {
+                error!("Error while loading AcoustID proposals: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_acoustid_proposals_json(&mut w, &proposals).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    /// Accept or reject the tags that we proposed for a file.
+    fn handle_review_acoustid_proposal(
+        &self,
+        db: &mut Connection,
+        id: &str,
+        accept: bool,
+        raw_query: &str,
+    ) -> ResponseBox {
+        let file_id = match i64::from_str(id) {
+            Ok(fid) => fid,
+            Err(_) => return self.handle_bad_request("Invalid file id."),
+        };
+        let write_tags = MetaServer::get_query_param(raw_query, "write_tags").as_deref() == Some("true");
+
+        let proposal = db.begin().and_then(|mut tx| {
+            let proposal = db::select_acoustid_proposal(&mut tx, file_id)?;
+            tx.commit()?;
+            Ok(proposal)
+        });
+        let mut proposal = match proposal {
+            Ok(Some(proposal)) => proposal,
+            Ok(None) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading an AcoustID proposal: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+        if proposal.status != "pending" {
+            return self.handle_bad_request("The proposal was reviewed already.");
+        }
+
+        let result = if accept {
+            let written = match write_tags {
+                false => None,
+                true => match acoustid::write_file(&proposal) {
+                    Ok(written) => Some(written),
+                    Err(err) => {
+                        error!("Failed to write tags to {}: {:?}", proposal.filename, err);
+                        return self.handle_error("Failed to write tags.");
+                    }
+                },
+            };
+            database_utils::with_write_transaction(db, |tx| {
+                acoustid::accept(tx, &proposal, written.as_ref())
+            })
+        } else {
+            database_utils::with_write_transaction(db, |tx| {
+                db::update_acoustid_proposal_status(tx, file_id, "rejected")
+            })
+        };
+        if let Err(err) = result {
+            error!("Error while reviewing an AcoustID proposal: {:?}", err);
+            return self.handle_error("Database error.");
+        }
+
+        // With the tags in place, the file can now be part of the index.
+        if accept {
+            if let Err(err) = self.rebuild_index(db) {
+                error!("Error while rebuilding the index: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        }
+
+        proposal.status = match accept {
+            true => "accepted".to_string(),
+            false => "rejected".to_string(),
+        };
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_acoustid_proposal_json(&mut w, &proposal).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Recompute the user data after listens were attributed to other artists.
+    fn reload_user_data(&self, db: &mut Connection) -> error::Result<()> {
+        let mut tx = db.begin()?;
+        let user_data = UserDataSet::load_from_database(&mut tx)?;
+        tx.commit()?;
+        *self.user_data.lock().unwrap() = user_data;
+        Ok(())
+    }
+
+    fn handle_metadata_edits(&self, db: &mut Connection, raw_query: &str, encoding: ContentEncoding) -> ResponseBox {
+        let limit = match MetaServer::get_query_param(raw_query, "limit") {
+            None => 100,
+            Some(v) => match i64::from_str(&v) {
+                Ok(n) if (1..=1000).contains(&n) => n,
+                _ => return self.handle_bad_request("Invalid limit, must be an integer from 1 to 1000."),
+            },
+        };
+
+        let edits = db.begin().and_then(|mut tx| {
+            let edits = db::iter_metadata_edits(&mut tx, limit)?.collect::<db::Result<Vec<_>>>()?;
+            tx.commit()?;
+            Ok(edits)
+        });
+        let edits = match edits {
+            Ok(edits) => edits,
+            Err(err) => {
+                error!("Error while loading metadata edits: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_metadata_edits_json(&mut w, &edits).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_backup(&self) -> ResponseBox {
+        let db_path = &self.config.db_path;
+        let tmp_path = backup::temporary_path(db_path);
+        if let Err(err) = backup::backup(db_path, &tmp_path) {
+            error!("Error while backing up the database: {:?}", err);
+            let _ = fs::remove_file(&tmp_path);
+            return self.handle_error("Failed to back up the database.");
+        }
+
+        // Once we have the file open, we can delete it, the data remains
+        // available until we close it after sending the response.
+        let file = fs::File::open(&tmp_path);
+        if let Err(err) = fs::remove_file(&tmp_path) {
+            warn!("Failed to remove temporary backup file: {:?}", err);
+        }
+        let file = match file {
+            Ok(f) => f,
+            Err(err) => {
+                error!("Error while opening the backup: {:?}", err);
+                return self.handle_error("Failed to back up the database.");
+            }
+        };
+
+        let today = chrono::Utc::now().format("%Y-%m-%d");
+        let disposition = format!("attachment; filename=\"musium-{}.sqlite3\"", today);
+        Response::from_file(file)
+            .with_header(header_content_type("application/vnd.sqlite3"))
+            .with_header(
+                Header::from_bytes(&b"Content-Disposition"[..], disposition.as_bytes())
+                    .expect("File name is ascii."),
+            )
+            .boxed()
+    }
+
+    fn handle_maintenance(&self) -> ResponseBox {
+        let report = match maintenance::run(&self.config.db_path) {
+            Ok(report) => report,
+            Err(Error::MaintenanceRunning) => {
+                return Response::from_string("Maintenance is already running.")
+                    .with_status_code(409) // "409 Conflict"
+                    .boxed();
+            }
+            Err(err) => {
+                error!("Error during database maintenance: {:?}", err);
+                return self.handle_error("Database maintenance failed.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_maintenance_report_json(&mut w, &report).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_reload_config(&self) -> ResponseBox {
+        let changed = match self.reload_config() {
+            Ok(changed) => changed,
+            Err(msg) => {
+                warn!("Failed to reload the config, keeping the current one: {}", msg);
+                return Response::from_string(msg)
+                    .with_status_code(400) // "400 Bad Request"
+                    .boxed();
+            }
+        };
+        info!("Reloaded the config, changed: {:?}", changed);
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_reload_json(&mut w, &changed).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_listens_export(&self, raw_query: &str) -> ResponseBox {
+        let format = match MetaServer::get_query_param(raw_query, "format") {
+            None => listen_export::Format::Json,
+            Some(f) => match listen_export::Format::parse(&f) {
+                Some(format) => format,
+                None => return self.handle_bad_request("Invalid format, must be json or csv."),
+            },
+        };
+
+        // The export can be large, so we stream it from a separate thread,
+        // rather than building the full response in memory.
+        let reader = listen_export::spawn_export(self.config.db_path.clone(), format);
+        let disposition = format!("attachment; filename=\"{}\"", format.file_name());
+        let headers = vec![
+            header_content_type(format.content_type()),
+            Header::from_bytes(&b"Content-Disposition"[..], disposition.as_bytes())
+                .expect("File name is ascii."),
+        ];
+        Response::new(tiny_http::StatusCode(200), headers, reader, None, None).boxed()
+    }
+
+    fn handle_listen_stats(&self, db: &mut Connection, kind: &str, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let params = match StatsParams::parse(raw_query) {
+            Ok(p) => p,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+        let (since, until) = params.range();
+        let client = params.client.as_deref();
+        let limit = params.limit as i64;
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+
+        let result = db
+            .begin()
+            .and_then(|mut tx| {
+                let found = match kind {
+                    "artists" => {
+                        let artists = db::iter_top_artists(&mut tx, since, until, client, user, limit)?
+                            .collect::<db::Result<Vec<_>>>()?;
+                        serialization::write_top_artists_json(&mut w, &artists[..]).unwrap();
+                        true
+                    }
+                    "albums" => {
+                        let albums = db::iter_top_albums(&mut tx, since, until, client, user, limit)?
+                            .collect::<db::Result<Vec<_>>>()?;
+                        serialization::write_top_albums_json(&mut w, &albums[..]).unwrap();
+                        true
+                    }
+                    "tracks" => {
+                        let tracks = db::iter_top_tracks(&mut tx, since, until, client, user, limit)?
+                            .collect::<db::Result<Vec<_>>>()?;
+                        serialization::write_top_tracks_json(&mut w, &tracks[..]).unwrap();
+                        true
+                    }
+                    "totals" => {
+                        let (listens, seconds) = db::select_listen_totals(&mut tx, since, until, client, user)?;
+                        let threshold = self.config.listen_threshold();
+                        let counted = db::select_counted_listen_count(
+                            &mut tx, since, until, client, user,
+                            threshold.percent as i64,
+                            threshold.seconds as i64,
+                        )?;
+                        let skips = db::select_skip_count(&mut tx, since, until, client, user)?;
+                        serialization::write_listen_totals_json(&mut w, listens, counted, seconds, skips).unwrap();
+                        true
+                    }
+                    "skips" => {
+                        let tracks = db::iter_top_skipped_tracks(&mut tx, since, until, client, user, limit)?
+                            .collect::<db::Result<Vec<_>>>()?;
+                        let index = &*self.get_index(user);
+                        serialization::write_skipped_tracks_json(index, &mut w, &tracks[..]).unwrap();
+                        true
+                    }
+                    "hours" => {
+                        let counts = listens::get_weekday_hour_counts(&mut tx, &params, user)?;
+                        serialization::write_weekday_hour_counts_json(&mut w, &counts).unwrap();
+                        true
+                    }
+                    "sessions" => {
+                        let stats = sessions::get_session_stats(&mut tx, &params, user)?;
+                        serialization::write_session_stats_json(&mut w, &stats).unwrap();
+                        true
+                    }
+                    "discoveries" => {
+                        let discoveries = listens::get_discoveries(&mut tx, &params, user)?;
+                        serialization::write_discoveries_json(&mut w, &discoveries).unwrap();
+                        true
+                    }
+                    "discovery-rate" => {
+                        let months = listens::get_discovery_rate(&mut tx, &params, user)?;
+                        serialization::write_discovery_rate_json(&mut w, &months).unwrap();
+                        true
+                    }
+                    _ => false,
+                };
+                tx.commit()?;
+                Ok(found)
+            });
+
+        match result {
+            Ok(true) => json_response(w.into_inner(), encoding).boxed(),
+            Ok(false) => self.handle_not_found(),
+            Err(err) => {
+                error!("Error while computing listening statistics: {:?}", err);
+                self.handle_error("Database error.")
+            }
+        }
+    }
+
+    fn handle_library_stats(&self, db: &mut Connection, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let index = &*self.get_index(user);
+        let stats = db.begin().and_then(|mut tx| {
+            let stats = library_stats::compute(&mut tx, index)?;
+            tx.commit()?;
+            Ok(stats)
+        });
+        let stats = match stats {
+            Ok(stats) => stats,
+            Err(err) => {
+                error!("Error while computing library statistics: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_library_stats_json(&mut w, &stats).unwrap();
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_on_this_day(&self, db: &mut Connection, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let date = match MetaServer::get_query_param(raw_query, "date") {
+            Some(d) => match chrono::NaiveDate::parse_from_str(&d, "%Y-%m-%d") {
+                Ok(date) => date,
+                Err(_) => return self.handle_bad_request("Invalid date, must be formatted as YYYY-MM-DD."),
+            },
+            None => listens::today(),
+        };
+
+        let days = db
+            .begin()
+            .and_then(|mut tx| {
+                let albums_per_year = 5;
+                let days = listens::get_on_this_day(&mut tx, date, albums_per_year, user)?;
+                tx.commit()?;
+                Ok(days)
+            });
+
+        let days = match days {
+            Ok(days) => days,
+            Err(err) => {
+                error!("Error while loading listens on this day: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_on_this_day_json(&mut w, &days[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_chart(&self, db: &mut Connection, kind: Option<&str>, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let params = match ChartParams::parse(raw_query) {
+            Ok(p) => p,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+
+        let result = db
+            .begin()
+            .and_then(|mut tx| {
+                let found = match kind {
+                    Some("artists") => {
+                        let chart = listens::get_artist_chart(&mut tx, &params, user)?;
+                        serialization::write_artist_chart_json(&mut w, &chart).unwrap();
+                        true
+                    }
+                    Some("albums") => {
+                        let chart = listens::get_album_chart(&mut tx, &params, user)?;
+                        serialization::write_album_chart_json(&mut w, &chart).unwrap();
+                        true
+                    }
+                    Some("tracks") => {
+                        let chart = listens::get_track_chart(&mut tx, &params, user)?;
+                        serialization::write_track_chart_json(&mut w, &chart).unwrap();
+                        true
+                    }
+                    _ => false,
+                };
+                tx.commit()?;
+                Ok(found)
+            });
+
+        match result {
+            Ok(true) => json_response(w.into_inner(), encoding).boxed(),
+            Ok(false) => self.handle_not_found(),
+            Err(err) => {
+                error!("Error while computing a chart: {:?}", err);
+                self.handle_error("Database error.")
+            }
+        }
+    }
+
+    fn handle_rewind(&self, db: &mut Connection, year_str: Option<&str>, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let year = match year_str {
+            None => listens::current_year(),
+            Some(y) => match i32::from_str(y) {
+                Ok(year) if (1900..=9999).contains(&year) => year,
+                _ => return self.handle_bad_request("Invalid year."),
+            },
+        };
+
+        let rewind = db
+            .begin()
+            .and_then(|mut tx| {
+                let rewind = listens::get_rewind(&mut tx, year, user)?;
+                tx.commit()?;
+                Ok(rewind)
+            });
+
+        let rewind = match rewind {
+            Ok(rewind) => rewind,
+            Err(err) => {
+                error!("Error while computing the rewind for {}: {:?}", year, err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_rewind_json(&mut w, &rewind).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_playlists(&self, db: &mut Connection, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let playlists = db
+            .begin()
+            .and_then(|mut tx| {
+                let mut result = Vec::new();
+                for playlist in db::iter_playlists(&mut tx, user)? {
+                    result.push(playlist?);
+                }
+                tx.commit()?;
+                Ok(result)
+            });
+
+        let playlists = match playlists {
+            Ok(ps) => ps,
+            Err(err) => {
+                error!("Error while loading playlists: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_playlists_json(&mut w, &playlists[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_create_playlist(&self, db: &mut Connection, raw_query: &str, user: Option<&str>) -> ResponseBox {
+        let name = match MetaServer::get_playlist_name(raw_query) {
+            Ok(name) => name,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        // With a query, the new playlist is a smart playlist.
+        let query = MetaServer::get_query_param(raw_query, "query");
+        if let Some(q) = query.as_ref() {
+            if let Err(msg) = SmartQuery::from_str(q) {
+                return self.handle_bad_request(msg);
+            }
+        }
+
+        let now_str = format_now_iso8601();
+        let playlist_id = database_utils::with_write_transaction(db, |tx| {
+            db::insert_playlist(tx, &name, query.as_deref(), &now_str, user)
+        });
+
+        let playlist_id = match playlist_id {
+            Ok(id) => id,
+            Err(err) => {
+                error!("Error while creating playlist: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        Response::from_string(format!(r#"{{"id":{}}}"#, playlist_id))
+            .with_status_code(201) // "201 Created"
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_playlist(&self, db: &mut Connection, id: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let playlist_id = match i64::from_str(id) {
+            Ok(pid) => pid,
+            Err(_) => return self.handle_bad_request("Invalid playlist id."),
+        };
+
+        let (header, entries) = match self.load_playlist(db, playlist_id, user) {
+            Ok(Some(result)) => result,
+            Ok(None) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading playlist: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let index = &*self.get_index(user);
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_playlist_json(
+            index,
+            &mut w,
+            playlist_id,
+            &header.name,
+            header.query.as_deref(),
+            &entries[..],
+        ).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    /// Run a modification of an existing playlist in a transaction.
+    ///
+    /// Responds with 404 if the playlist does not exist, or if `f` returns
+    /// false, with 400 if the playlist is not of the `applies_to` kind, and
+    /// with 204 if the modification succeeded.
+    fn modify_playlist<F>(
+        &self,
+        db: &mut Connection,
+        id: &str,
+        user: Option<&str>,
+        applies_to: PlaylistKind,
+        mut f: F,
+    ) -> ResponseBox
+    where
+        F: FnMut(&mut db::Transaction, i64) -> db::Result<bool>,
+    {
+        let playlist_id = match i64::from_str(id) {
+            Ok(pid) => pid,
+            Err(_) => return self.handle_bad_request("Invalid playlist id."),
+        };
+
+        // In the cases where we return early, we did not write anything, so
+        // committing is the same as rolling back.
+        let result = database_utils::with_write_transaction(db, |tx| {
+            let is_smart = match db::select_playlist(tx, playlist_id, user)? {
+                Some(header) => header.query.is_some(),
+                None => return Ok(Ok(false)),
+            };
+            let error = match (applies_to, is_smart) {
+                (PlaylistKind::Static, true) => Some("Cannot edit the tracks of a smart playlist."),
+                (PlaylistKind::Smart, false) => Some("Not a smart playlist."),
+                _ => None,
+            };
+            if let Some(msg) = error {
+                return Ok(Err(msg));
+            }
+            let found = f(tx, playlist_id)?;
+            Ok(Ok(found))
+        });
+
+        match result {
+            Ok(Ok(true)) => Response::empty(204).boxed(),
+            Ok(Ok(false)) => self.handle_not_found(),
+            Ok(Err(msg)) => self.handle_bad_request(msg),
+            Err(err) => {
+                error!("Error while modifying playlist: {:?}", err);
+                self.handle_error("Database error.")
+            }
+        }
+    }
+
+    fn handle_rename_playlist(&self, db: &mut Connection, id: &str, raw_query: &str, user: Option<&str>) -> ResponseBox {
+        let name = match MetaServer::get_playlist_name(raw_query) {
+            Ok(name) => name,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+        self.modify_playlist(db, id, user, PlaylistKind::Any, |tx, playlist_id| {
+            db::update_playlist_name(tx, playlist_id, &name)?;
+            Ok(true)
+        })
+    }
+
+    fn handle_update_playlist_query(&self, db: &mut Connection, id: &str, raw_query: &str, user: Option<&str>) -> ResponseBox {
+        let query = match MetaServer::get_query_param(raw_query, "query") {
+            Some(q) => q,
+            None => return self.handle_bad_request("Missing query."),
+        };
+        if let Err(msg) = SmartQuery::from_str(&query) {
+            return self.handle_bad_request(msg);
+        }
+        self.modify_playlist(db, id, user, PlaylistKind::Smart, |tx, playlist_id| {
+            db::update_playlist_query(tx, playlist_id, &query)?;
+            Ok(true)
+        })
+    }
+
+    fn handle_delete_playlist(&self, db: &mut Connection, id: &str, user: Option<&str>) -> ResponseBox {
+        // The entries are deleted through the "on delete cascade".
+        self.modify_playlist(db, id, user, PlaylistKind::Any, |tx, playlist_id| {
+            db::delete_playlist(tx, playlist_id)?;
+            Ok(true)
+        })
+    }
+
+    fn handle_playlist_add_track(&self, db: &mut Connection, id: &str, track_id: &str, user: Option<&str>) -> ResponseBox {
+        let track_id = match TrackId::parse(track_id) {
+            Some(tid) => tid,
+            None => return self.handle_bad_request("Invalid track id."),
+        };
+
+        // Confirm that the track exists before we add it.
+        let index = &*self.get_index(user);
+        if index.get_track(track_id).is_none() {
+            return self.handle_not_found();
+        }
+
+        self.modify_playlist(db, id, user, PlaylistKind::Static, |tx, playlist_id| {
+            playlist::append_track(tx, playlist_id, track_id)?;
+            Ok(true)
+        })
+    }
+
+    fn handle_playlist_remove_entry(&self, db: &mut Connection, id: &str, entry_id: &str, user: Option<&str>) -> ResponseBox {
+        let entry_id = match i64::from_str(entry_id) {
+            Ok(eid) => eid,
+            Err(_) => return self.handle_bad_request("Invalid entry id."),
+        };
+        self.modify_playlist(db, id, user, PlaylistKind::Static, |tx, playlist_id| {
+            db::delete_playlist_entry(tx, playlist_id, entry_id)?;
+            Ok(true)
+        })
+    }
+
+    fn handle_playlist_move_entry(
+        &self,
+        db: &mut Connection,
+        id: &str,
+        entry_id: &str,
+        raw_query: &str,
+        user: Option<&str>,
+    ) -> ResponseBox {
+        let entry_id = match i64::from_str(entry_id) {
+            Ok(eid) => eid,
+            Err(_) => return self.handle_bad_request("Invalid entry id."),
+        };
+        let to = match MetaServer::get_query_param(raw_query, "to").map(|t| usize::from_str(&t)) {
+            Some(Ok(n)) => n,
+            Some(Err(_)) => return self.handle_bad_request("Invalid position, must be a non-negative integer."),
+            None => return self.handle_bad_request("Missing target position."),
+        };
+        self.modify_playlist(db, id, user, PlaylistKind::Static, |tx, playlist_id| {
+            playlist::move_entry(tx, playlist_id, entry_id, to)
+        })
+    }
+
+    /// Load the playlist of the user and its entries, or `None` if it does not
+    /// exist, or if it belongs to a different user.
+    ///
+    /// Entries are `(entry_id, track_id)` pairs. For smart playlists, we
+    /// evaluate the query, and the entries have no entry id.
+    fn load_playlist(
+        &self,
+        db: &mut Connection,
+        playlist_id: i64,
+        user: Option<&str>,
+    ) -> db::Result<Option<(db::PlaylistHeader, Vec<(Option<i64>, TrackId)>)>> {
+        let loaded = db
+            .begin()
+            .and_then(|mut tx| {
+                let header = match db::select_playlist(&mut tx, playlist_id, user)? {
+                    Some(h) => h,
+                    None => {
+                        tx.rollback()?;
+                        return Ok(None);
+                    }
+                };
+                let mut entries = Vec::new();
+                if header.query.is_none() {
+                    for entry in db::iter_playlist_entries(&mut tx, playlist_id)? {
+                        let entry = entry?;
+                        entries.push((Some(entry.id), TrackId(entry.track_id as u64)));
+                    }
+                }
+                tx.commit()?;
+                Ok(Some((header, entries)))
+            })?;
+
+        let (header, mut entries) = match loaded {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+
+        if let Some(query_str) = header.query.as_ref() {
+            entries = self
+                .evaluate_smart_playlist(query_str, user)
+                .into_iter()
+                .map(|track_id| (None, track_id))
+                .collect();
+        }
+
+        // Tracks outside the library view of the user are not in the library
+        // as far as they are concerned. Smart playlists already respect it.
+        if let Some(view) = self.library_views.get(&self.index_var.get(), user) {
+            entries.retain(|&(_, track_id)| view.get_track(track_id).is_some());
+        }
+
+        Ok(Some((header, entries)))
+    }
+
+    /// Return the tracks selected by a smart playlist query, for the user.
+    fn evaluate_smart_playlist(&self, query_str: &str, user: Option<&str>) -> Vec<TrackId> {
+        let query = match SmartQuery::from_str(query_str) {
+            Ok(q) => q,
+            // Queries are validated before we store them, so this can only
+            // happen if the query language changed in an incompatible way.
+            Err(msg) => {
+                error!("Invalid smart playlist query '{}': {}", query_str, msg);
+                return Vec::new();
+            }
+        };
+        let index = &*self.get_index(user);
+        let user_data = self.user_data.lock().unwrap();
+        let now = Instant { posix_seconds_utc: chrono::Utc::now().timestamp() };
+        let mut rng = Prng::new();
+        query.evaluate(index, user_data.get(user), now, &mut rng)
+    }
+
+    /// Append all tracks of the playlist to the queue.
+    fn handle_playlist_enqueue(&self, player: &Player, db: &mut Connection, id: &str, raw_query: &str, user: Option<&str>) -> ResponseBox {
+        let playlist_id = match i64::from_str(id) {
+            Ok(pid) => pid,
+            Err(_) => return self.handle_bad_request("Invalid playlist id."),
+        };
+        let client = match MetaServer::get_client(raw_query) {
+            Ok(c) => c,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let tracks: Vec<TrackId> = match self.load_playlist(db, playlist_id, user) {
+            Ok(Some((_header, entries))) => entries.into_iter().map(|(_, t)| t).collect(),
+            Ok(None) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading playlist: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let index = &*self.index_var.get();
+        // Skip entries for tracks that are no longer in the library.
+        let tracks: Vec<TrackId> = tracks
+            .into_iter()
+            .filter(|&track_id| index.get_track(track_id).is_some())
+            .collect();
+        let shuffle_tracks = false;
+        player.enqueue_many(index, &tracks, client.as_deref(), user, shuffle_tracks);
+
+        self.handle_queue(player, db, user)
+    }
+
+    fn handle_radio_stations(&self, db: &mut Connection, encoding: ContentEncoding) -> ResponseBox {
+        let stations = db
+            .begin()
+            .and_then(|mut tx| {
+                let mut result = Vec::new();
+                for station in db::iter_radio_stations(&mut tx)? {
+                    result.push(station?);
+                }
+                tx.commit()?;
+                Ok(result)
+            });
+
+        let stations = match stations {
+            Ok(ss) => ss,
+            Err(err) => {
+                error!("Error while loading radio stations: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_radio_stations_json(&mut w, &stations[..]).unwrap();
+
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    fn handle_add_radio_station(&self, db: &mut Connection, raw_query: &str) -> ResponseBox {
+        let name = match MetaServer::get_query_param(raw_query, "name") {
+            Some(name) if !name.trim().is_empty() => name.trim().to_string(),
+            Some(_) => return self.handle_bad_request("Station name must not be empty."),
+            None => return self.handle_bad_request("Missing station name."),
+        };
+        let url = match MetaServer::get_query_param(raw_query, "url") {
+            Some(url) => url,
+            None => return self.handle_bad_request("Missing station url."),
+        };
+        if let Err(msg) = radio::validate_url(&url) {
+            return self.handle_bad_request(msg);
+        }
+
+        let now_str = format_now_iso8601();
+        let station_id = database_utils::with_write_transaction(db, |tx| {
+            db::insert_radio_station(tx, &name, &url, &now_str)
+        });
+
+        let station_id = match station_id {
+            Ok(id) => id,
+            Err(err) => {
+                error!("Error while adding radio station: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        Response::from_string(format!(r#"{{"id":{}}}"#, station_id))
+            .with_status_code(201) // "201 Created"
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_delete_radio_station(&self, db: &mut Connection, id: &str) -> ResponseBox {
+        let station_id = match i64::from_str(id) {
+            Ok(sid) => sid,
+            Err(_) => return self.handle_bad_request("Invalid station id."),
+        };
+
+        // Listens of the station keep the station name, so they remain.
+        let result = database_utils::with_write_transaction(db, |tx| {
+            db::delete_radio_station(tx, station_id)
+        });
+
+        match result {
+            Ok(()) => Response::empty(200).boxed(),
+            Err(err) => {
+                error!("Error while deleting radio station: {:?}", err);
+                self.handle_error("Database error.")
+            }
+        }
+    }
+
+    /// Append the radio station to the queue.
+    fn handle_radio_enqueue(&self, player: &Player, db: &mut Connection, id: &str, raw_query: &str) -> ResponseBox {
+        let station_id = match i64::from_str(id) {
+            Ok(sid) => sid,
+            Err(_) => return self.handle_bad_request("Invalid station id."),
+        };
+        let client = match MetaServer::get_client(raw_query) {
+            Ok(c) => c,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let station = db
+            .begin()
+            .and_then(|mut tx| {
+                let station = db::select_radio_station(&mut tx, station_id)?;
+                tx.commit()?;
+                Ok(station)
+            });
+
+        let station = match station {
+            Ok(Some((name, url))) => radio::Station {
+                id: station_id,
+                name: name,
+                url: url,
+            },
+            Ok(None) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading radio station: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let queue_id = player.enqueue_radio(station, client.as_deref());
+        let queue_id_json = format!(r#""{}""#, queue_id);
+
+        Response::from_string(queue_id_json)
+            .with_status_code(201) // "201 Created"
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Create a playlist from an uploaded M3U or M3U8 file.
+    fn handle_import_playlist(&self, db: &mut Connection, raw_query: &str, body: &str, user: Option<&str>) -> ResponseBox {
+        let name = match MetaServer::get_playlist_name(raw_query) {
+            Ok(name) => name,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        let index = &*self.get_index(user);
+        let resolver = m3u::Resolver::new(index, self.config.search_max_edits);
+        let mut tracks = Vec::new();
+        let mut missing = Vec::new();
+
+        // An uploaded file has no location on disk, so relative paths can only
+        // be resolved by their trailing components, or by metadata.
+        for entry in m3u::parse(body) {
+            match resolver.resolve(&entry, None) {
+                Some(track_id) => tracks.push(track_id),
+                None => missing.push(entry.path),
+            }
+        }
+
+        let now_str = format_now_iso8601();
+        let playlist_id = database_utils::with_write_transaction(db, |tx| {
+            playlist::create_with_tracks(tx, &name, &now_str, user, &tracks[..])
+        });
+
+        let playlist_id = match playlist_id {
+            Ok(id) => id,
+            Err(err) => {
+                error!("Error while importing playlist: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_playlist_import_json(&mut w, playlist_id, tracks.len(), &missing[..]).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_status_code(201) // "201 Created"
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Respond with the tracks as an M3U8 file for download.
+    fn respond_m3u8(&self, index: &MemoryMetaIndex, tracks: &[TrackId]) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        m3u::write_m3u8(index, &mut w, tracks).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("audio/x-mpegurl; charset=utf-8"))
+            .boxed()
+    }
+
+    fn handle_playlist_m3u8(&self, db: &mut Connection, id: &str, user: Option<&str>) -> ResponseBox {
+        let playlist_id = match i64::from_str(id) {
+            Ok(pid) => pid,
+            Err(_) => return self.handle_bad_request("Invalid playlist id."),
+        };
+
+        let tracks: Vec<TrackId> = match self.load_playlist(db, playlist_id, user) {
+            Ok(Some((_header, entries))) => entries.into_iter().map(|(_, t)| t).collect(),
+            Ok(None) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading playlist: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let index = &*self.index_var.get();
+        self.respond_m3u8(index, &tracks[..])
+    }
+
+    /// Respond with the tracks as an XSPF playlist.
+    ///
+    /// Tracks are referenced by their streaming url on this server, under the
+    /// base url that the client used to reach us.
+    fn respond_xspf(
+        &self,
+        index: &MemoryMetaIndex,
+        base_url: &str,
+        title: &str,
+        tracks: &[TrackId],
+    ) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        xspf::write_xspf(index, &mut w, base_url, title, tracks).unwrap();
+
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/xspf+xml"))
+            .boxed()
+    }
+
+    fn handle_playlist_xspf(&self, db: &mut Connection, id: &str, base_url: &str, user: Option<&str>) -> ResponseBox {
+        let playlist_id = match i64::from_str(id) {
+            Ok(pid) => pid,
+            Err(_) => return self.handle_bad_request("Invalid playlist id."),
+        };
+
+        let (header, entries) = match self.load_playlist(db, playlist_id, user) {
+            Ok(Some(result)) => result,
+            Ok(None) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading playlist: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+
+        let tracks: Vec<TrackId> = entries.into_iter().map(|(_, t)| t).collect();
+        let index = &*self.index_var.get();
+        self.respond_xspf(index, base_url, &header.name, &tracks[..])
+    }
+
+    fn handle_queue_xspf(&self, player: &Player, base_url: &str) -> ResponseBox {
+        let index = &*self.index_var.get();
+        let queue = player.get_queue();
+        // Radio stations are not files that a playlist can refer to.
+        let tracks: Vec<TrackId> = queue.tracks.iter().filter_map(|t| t.source.track_id()).collect();
+        self.respond_xspf(index, base_url, "Musium queue", &tracks[..])
+    }
+
+    fn handle_queue_m3u8(&self, player: &Player) -> ResponseBox {
+        let index = &*self.index_var.get();
+        let queue = player.get_queue();
+        let tracks: Vec<TrackId> = queue.tracks.iter().filter_map(|t| t.source.track_id()).collect();
+        self.respond_m3u8(index, &tracks[..])
+    }
+
+    /// Fill in where queue entries could resume, for tracks with a saved position.
+    ///
+    /// We only offer to resume entries that don't already resume, and that did
+    /// not play past the saved position yet.
+    fn add_resume_offers(db: &mut Connection, tracks: &mut [TrackSnapshot]) -> db::Result<()> {
+        let mut tx = db.begin()?;
+        let positions: HashMap<i64, i64> = db::iter_resume_positions(&mut tx)?.collect::<db::Result<_>>()?;
+        tx.commit()?;
+
+        for queued_track in tracks.iter_mut() {
+            let track_id = match queued_track.source.track_id() {
+                Some(t) => t,
+                None => continue,
+            };
+            if let Some(&position_ms) = positions.get(&(track_id.0 as i64)) {
+                let position_ms = position_ms as u64;
+                if queued_track.resume_at_ms == 0 && queued_track.position_ms < position_ms {
+                    queued_track.resume_offer_ms = Some(position_ms);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_queue(&self, player: &Player, db: &mut Connection, user: Option<&str>) -> ResponseBox {
+        let index = &*self.index_var.get();
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let mut queue = player.get_queue();
+        if let Err(err) = MetaServer::add_resume_offers(db, &mut queue.tracks) {
+            error!("Failed to load resume positions: {:?}", err);
+        }
+        serialization::write_queue_json(
+            index,
+            self.user_data.lock().unwrap().get(user),
+            &mut w,
+            &queue.tracks[..],
+        ).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Return a page of the queue, selected by the `offset` and `limit` parameters.
+    fn handle_queue_page(&self, player: &Player, db: &mut Connection, raw_query: &str, user: Option<&str>) -> ResponseBox {
+        let params = match ListParams::parse(raw_query) {
+            Ok(p) if p.sort == SortOrder::Id => p,
+            Ok(..) => return self.handle_bad_request("The queue is in playback order, it cannot be sorted."),
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+        let index = &*self.index_var.get();
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        // Only the entries of the page get snapshotted and resolved against
+        // the index, no matter how long the queue is.
+        let mut queue = player.get_queue_page(params.offset, params.limit);
+        if let Err(err) = MetaServer::add_resume_offers(db, &mut queue.tracks) {
+            error!("Failed to load resume positions: {:?}", err);
+        }
+        serialization::write_queue_json(
+            index,
+            self.user_data.lock().unwrap().get(user),
+            &mut w,
+            &queue.tracks[..],
+        ).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_enqueue(&self, player: &Player, id: &str, raw_query: &str, user: Option<&str>) -> ResponseBox {
+        let track_id = match TrackId::parse(id) {
+            Some(tid) => tid,
+            None => return self.handle_bad_request("Invalid track id."),
+        };
+        let client = match MetaServer::get_client(raw_query) {
+            Ok(c) => c,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+
+        // Confirm that the track exists before we enqueue it.
+        let _track = match self.get_index(user).get_track(track_id) {
+            Some(t) => t,
+            None => return self.handle_not_found(),
+        };
+
+        let index = &*self.index_var.get();
+        let queue_id = player.enqueue(index, track_id, client.as_deref(), user);
+        let queue_id_json = format!(r#""{}""#, queue_id);
+
+        Response::from_string(queue_id_json)
+            .with_status_code(201) // "201 Created"
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Append the tracks to the queue, and respond with their queue ids.
+    fn respond_enqueue_many(&self, player: &Player, tracks: &[TrackId], raw_query: &str, user: Option<&str>) -> ResponseBox {
+        let client = match MetaServer::get_client(raw_query) {
+            Ok(c) => c,
+            Err(msg) => return self.handle_bad_request(msg),
+        };
+        let shuffle_tracks = MetaServer::get_query_param(raw_query, "shuffle").as_deref() == Some("true");
+
+        let index = &*self.index_var.get();
+        let queue_ids = player.enqueue_many(index, tracks, client.as_deref(), user, shuffle_tracks);
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_queue_ids_json(&mut w, &queue_ids).unwrap();
+        Response::from_data(w.into_inner())
+            .with_status_code(201) // "201 Created"
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Append the tracks in the body, a json array of track ids, to the queue.
+    fn handle_enqueue_many(&self, player: &Player, raw_query: &str, body: &str, user: Option<&str>) -> ResponseBox {
+        let ids: Vec<String> = match serde_json::from_str(body) {
+            Ok(ids) => ids,
+            Err(_) => return self.handle_bad_request("Expected a json array of track ids."),
+        };
+
+        // Confirm that all tracks exist before we enqueue any of them.
+        let index = self.get_index(user);
+        let mut tracks = Vec::with_capacity(ids.len());
+        for id in &ids {
+            match TrackId::parse(id) {
+                Some(tid) if index.get_track(tid).is_some() => tracks.push(tid),
+                Some(..) => return self.handle_not_found(),
+                None => return self.handle_bad_request("Invalid track id."),
+            }
+        }
+
+        self.respond_enqueue_many(player, &tracks, raw_query, user)
+    }
+
+    /// Append every track in the library to the queue.
+    ///
+    /// With `shuffle=true`, this is "shuffle the entire library". For users
+    /// with a library view, only the tracks in their view get enqueued.
+    fn handle_enqueue_library(&self, player: &Player, raw_query: &str, user: Option<&str>) -> ResponseBox {
+        // When shuffling, we pass over the tracks and albums that the user
+        // excluded from that. In order, the library is what it is.
+        let shuffle_tracks = MetaServer::get_query_param(raw_query, "shuffle").as_deref() == Some("true");
+        let tracks: Vec<TrackId> = {
+            let user_data = self.user_data.lock().unwrap();
+            let user_data = user_data.get(user);
+            self
+                .get_index(user)
+                .get_tracks()
+                .iter()
+                .map(|kv| kv.track_id)
+                .filter(|track_id| !(shuffle_tracks && user_data.is_track_excluded(*track_id)))
+                .collect()
+        };
+        self.respond_enqueue_many(player, &tracks, raw_query, user)
+    }
+
+    fn handle_dequeue(&self, player: &Player, id: &str) -> ResponseBox {
+        let queue_id = match QueueId::parse(id) {
+            Some(qid) => qid,
+            None => return self.handle_bad_request("Invalid queue id."),
+        };
+        player.dequeue(queue_id);
+        Response::empty(200).boxed()
+    }
+
+    /// Resume playback of the queue entry at the saved position of its track.
+    fn handle_queue_resume(&self, player: &Player, db: &mut Connection, id: &str) -> ResponseBox {
+        let queue_id = match QueueId::parse(id) {
+            Some(qid) => qid,
+            None => return self.handle_bad_request("Invalid queue id."),
+        };
+        let track_id = match player.get_queued_track(queue_id) {
+            Some(tid) => tid,
+            None => return self.handle_not_found(),
+        };
+        let position = db
+            .begin()
+            .and_then(|mut tx| {
+                let position = db::select_resume_position(&mut tx, track_id.0 as i64)?;
+                tx.commit()?;
+                Ok(position)
+            });
+        let position_ms = match position {
+            Ok(Some(ms)) => ms as u64,
+            Ok(None) => return self.handle_not_found(),
+            Err(err) => {
+                error!("Error while loading resume position: {:?}", err);
+                return self.handle_error("Database error.");
+            }
+        };
+        match player.resume_at(queue_id, position_ms) {
+            true => Response::empty(200).boxed(),
+            // The entry may have finished in the meantime.
+            false => self.handle_not_found(),
+        }
+    }
+
+    fn handle_queue_shuffle(&self, player: &Player, db: &mut Connection, user: Option<&str>) -> ResponseBox {
+        let index = &*self.index_var.get();
+        player.shuffle(index);
+        self.handle_queue(player, db, user)
+    }
+
+    fn handle_queue_clear(&self, player: &Player, db: &mut Connection, user: Option<&str>) -> ResponseBox {
+        player.clear_queue();
+        self.handle_queue(player, db, user)
+    }
+
+    fn handle_queue_skip(&self, player: &Player, db: &mut Connection, user: Option<&str>) -> ResponseBox {
+        match player.skip() {
+            Some(_) => self.handle_queue(player, db, user),
+            None => self.handle_not_found(),
+        }
+    }
+
+    fn handle_queue_vote_skip(&self, player: &Player, origin: &RequestOrigin) -> ResponseBox {
+        if !self.config.party_mode {
+            return self.handle_bad_request("Voting to skip is only possible in party mode.");
+        }
+        let vote = match player.vote_skip(origin.client.clone(), self.config.party_skip_votes) {
+            Some(vote) => vote,
+            None => return self.handle_not_found(),
+        };
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_skip_vote_json(&mut w, &vote).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_queue_previous(&self, player: &Player, db: &mut Connection, user: Option<&str>) -> ResponseBox {
+        match player.previous() {
+            Some(_) => self.handle_queue(player, db, user),
+            None => self.handle_not_found(),
+        }
+    }
+
+    fn handle_queue_handoff(
+        &self,
+        player: &Player,
+        db: &mut Connection,
+        raw_query: &str,
+        user: Option<&str>,
+    ) -> ResponseBox {
+        let target = match MetaServer::get_query_param(raw_query, "to") {
+            Some(name) => match self.get_player_by_name(&name) {
+                Some(target) => target,
+                None => return self.handle_not_found(),
+            },
+            None => return self.handle_bad_request("Missing 'to' query parameter."),
+        };
+        if std::ptr::eq(player, target) {
+            return self.handle_bad_request("Cannot hand off the queue to the same player.");
+        }
+        player.hand_off_queue(target);
+        self.handle_queue(target, db, user)
+    }
+
+    /// Return the player that the `player` query parameter selects, if it exists.
+    fn get_player(&self, raw_query: &str) -> Option<&Player> {
+        match MetaServer::get_query_param(raw_query, "player") {
+            None => Some(&self.player),
+            Some(name) => self.get_player_by_name(&name),
+        }
+    }
+
+    fn get_player_by_name(&self, name: &str) -> Option<&Player> {
+        if name == player::DEFAULT_PLAYER {
+            return Some(&self.player);
+        }
+        self.extra_players.iter().find(|(n, _)| n == name).map(|(_, p)| p)
+    }
+
+    fn handle_get_players(&self) -> ResponseBox {
+        let mut players = vec![(player::DEFAULT_PLAYER, self.player.get_now_playing())];
+        for (name, player) in &self.extra_players {
+            players.push((name.as_str(), player.get_now_playing()));
+        }
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_players_json(&mut w, &players).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_get_player(&self, player: &Player, user: Option<&str>) -> ResponseBox {
+        let index = &*self.index_var.get();
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let now_playing = player.get_now_playing();
+        serialization::write_now_playing_json(
+            index,
+            self.user_data.lock().unwrap().get(user),
+            &mut w,
+            &now_playing,
+        ).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_pause(&self, player: &Player, user: Option<&str>) -> ResponseBox {
+        if player.is_casting() {
+            return self.handle_bad_request("Can't pause while casting.");
+        }
+        // Pausing twice is fine, only with nothing to pause we fail.
+        if !player.pause() && player.get_now_playing().state != PlaybackState::Paused {
+            return self.handle_not_found();
+        }
+        self.handle_get_player(player, user)
+    }
+
+    fn handle_play(&self, player: &Player, user: Option<&str>) -> ResponseBox {
+        player.play();
+        self.handle_get_player(player, user)
+    }
+
+    fn handle_seek(&self, player: &Player, raw_query: &str, user: Option<&str>) -> ResponseBox {
+        let position_ms = match MetaServer::get_query_param(raw_query, "position").map(|p| p.parse::<f64>()) {
+            Some(Ok(seconds)) if seconds >= 0.0 && seconds.is_finite() => (seconds * 1000.0) as u64,
+            _ => return self.handle_bad_request("Expected a position in seconds."),
+        };
+        if player.is_casting() {
+            return self.handle_bad_request("Can't seek while casting.");
+        }
+        match player.seek(position_ms) {
+            true => self.handle_get_player(player, user),
+            false => self.handle_not_found(),
+        }
+    }
+
+    fn handle_get_volume(&self, player: &Player) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let volume = player.get_volume();
+        serialization::write_volume_json(&mut w, volume).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_change_volume(&self, player: &Player, add: Millibel) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let volume = player.change_volume(add);
+        serialization::write_volume_json(&mut w, volume).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_zones(&self) -> ResponseBox {
+        let address = match self.config.snapcast_control.as_ref() {
+            Some(a) => a,
+            None => return self.handle_bad_request("Snapcast control is not configured."),
+        };
+        let zones = match snapcast::get_zones(address) {
+            Ok(zones) => zones,
+            Err(err) => {
+                error!("Error while listing Snapcast zones: {:?}", err);
+                return self.handle_error("Failed to list Snapcast zones.");
+            }
+        };
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_zones_json(&mut w, &zones).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_set_zone_volume(&self, zone_id: &str, percent_str: &str) -> ResponseBox {
+        let address = match self.config.snapcast_control.as_ref() {
+            Some(a) => a,
+            None => return self.handle_bad_request("Snapcast control is not configured."),
+        };
+        let percent = match u8::from_str(percent_str) {
+            Ok(p) if p <= 100 => p,
+            _ => return self.handle_bad_request("Invalid volume, expected a percentage from 0 to 100."),
+        };
+        if let Err(err) = snapcast::set_zone_volume(address, zone_id, percent) {
+            error!("Error while setting Snapcast zone volume: {:?}", err);
+            return self.handle_error("Failed to set the zone volume.");
+        }
+        self.handle_zones()
+    }
+
+    fn handle_get_cast(&self, player: &Player) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let device = player.get_cast_device();
+        serialization::write_cast_status_json(&mut w, device.as_ref()).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    fn handle_cast_devices(&self) -> ResponseBox {
+        let devices = match cast::discover(Duration::from_secs(2)) {
+            Ok(devices) => devices,
+            Err(err) => {
+                error!("Error while discovering cast devices: {:?}", err);
+                return self.handle_error("Failed to discover cast devices.");
+            }
+        };
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_cast_devices_json(&mut w, &devices).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
+
+    /// Return the url under which the cast device can reach us.
+    fn get_cast_base_url(&self, connection: &cast::Connection) -> Result<String, &'static str> {
+        if let Some(url) = self.config.cast_base_url.as_ref() {
+            return Ok(url.clone());
+        }
+        // Over TLS, the device would need to trust our certificate for the
+        // address that it connects to, we can't know that from here.
+        if self.config.tls_paths().is_some() {
+            return Err("Casting with TLS enabled requires cast_base_url to be set.");
+        }
+        let port = match listen_port(&self.config.listen) {
+            Some(port) => port,
+            None => return Err("Cannot determine the port from the listen address, set cast_base_url."),
+        };
+        // The address that we reach the device from, is an address that the
+        // device can reach us at, even if we listen on all interfaces.
+        match connection.local_addr() {
+            Ok(addr) => Ok(format!("http://{}", SocketAddr::new(addr.ip(), port))),
+            Err(..) => Err("Failed to determine the local address of the cast connection."),
+        }
+    }
+
+    fn handle_cast_to(&self, player: &Player, device_id: &str) -> ResponseBox {
+        let devices = match cast::discover(Duration::from_secs(2)) {
+            Ok(devices) => devices,
+            Err(err) => {
+                error!("Error while discovering cast devices: {:?}", err);
+                return self.handle_error("Failed to discover cast devices.");
+            }
+        };
+        let device = match devices.into_iter().find(|d| d.id == device_id) {
+            Some(d) => d,
+            None => return self.handle_not_found(),
+        };
+        let connection = match cast::Connection::open(&device) {
+            Ok(c) => c,
+            Err(err) => {
+                error!("Error while connecting to cast device {:?}: {:?}", device, err);
+                return self.handle_error("Failed to connect to the cast device.");
+            }
+        };
+        let base_url = match self.get_cast_base_url(&connection) {
+            Ok(url) => url,
+            Err(msg) => return self.handle_error(msg),
+        };
+        let profile = self.config.cast_profile.as_ref().and_then(|name| self.config.get_transcode_profile(name));
+        let media = cast::MediaSource {
+            base_url: base_url,
+            key: self.cast_key.clone(),
+            profile: profile.map(|p| p.name.clone()),
+            content_type: profile.map(|p| p.format.codec.content_type()).unwrap_or("audio/flac"),
+        };
+        player.cast_to(device, connection, media);
+        self.handle_get_cast(player)
+    }
+
+    fn handle_stop_cast(&self, player: &Player) -> ResponseBox {
+        player.stop_casting();
+        self.handle_get_cast(player)
+    }
+
+    fn handle_play_in_browser(&self, player: &Player) -> ResponseBox {
+        let session = player.play_in_browser();
+        let report = browser_output::Report::Idle;
+        match player.report_browser_output(session, report) {
+            Some(instruction) => self.write_browser_output(session, &instruction),
+            // Somebody selected another output in the meantime.
+            None => self.handle_not_found(),
+        }
+    }
 
-        let index = &*self.index_var.get();
+    fn write_browser_output(&self, session: u64, instruction: &browser_output::Instruction) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_browser_output_json(&mut w, session, instruction).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
+    }
 
-        // Confirm that the track exists before we enqueue it.
-        let _track = match index.get_track(track_id) {
-            Some(t) => t,
-            None => return self.handle_not_found(),
+    fn handle_browser_report(&self, player: &Player, session_str: &str, kind: Option<&str>, raw_query: &str) -> ResponseBox {
+        let session = match u64::from_str(session_str) {
+            Ok(s) => s,
+            Err(..) => return self.handle_bad_request("Invalid session, must be an integer."),
+        };
+        let queue_id = MetaServer::get_query_param(raw_query, "queue_id").map(|q| QueueId::parse(&q));
+        let report = match (kind, queue_id) {
+            (_, Some(None)) => return self.handle_bad_request("Invalid queue id."),
+            (Some("progress"), None) => browser_output::Report::Idle,
+            (Some("progress"), Some(Some(queue_id))) => {
+                let position_ms = MetaServer::get_query_param(raw_query, "position_ms")
+                    .and_then(|p| u64::from_str(&p).ok());
+                match position_ms {
+                    Some(ms) => browser_output::Report::Progress { queue_id: queue_id, position_ms: ms },
+                    None => return self.handle_bad_request("Expected position_ms, an integer."),
+                }
+            }
+            (Some("ended"), Some(Some(queue_id))) => browser_output::Report::Ended(queue_id),
+            (Some("failed"), Some(Some(queue_id))) => browser_output::Report::Failed(queue_id),
+            (Some("ended"), None) | (Some("failed"), None) => {
+                return self.handle_bad_request("Expected a queue_id.")
+            }
+            _ => return self.handle_bad_request("No such browser output operation."),
         };
+        match player.report_browser_output(session, report) {
+            Some(instruction) => self.write_browser_output(session, &instruction),
+            // The session is over, the browser should stop playing.
+            None => self.handle_not_found(),
+        }
+    }
 
-        let queue_id = self.player.enqueue(index, track_id);
-        let queue_id_json = format!(r#""{}""#, queue_id);
+    fn handle_stop_browser(&self, player: &Player, session_str: &str) -> ResponseBox {
+        let session = match u64::from_str(session_str) {
+            Ok(s) => s,
+            Err(..) => return self.handle_bad_request("Invalid session, must be an integer."),
+        };
+        match player.stop_browser_output(session) {
+            true => Response::empty(200).boxed(),
+            // Another output took over already, there is nothing to stop.
+            false => self.handle_not_found(),
+        }
+    }
 
-        Response::from_string(queue_id_json)
-            .with_status_code(201) // "201 Created"
+    /// Serve the documents and streams for DLNA clients, see `dlna.rs`.
+    fn handle_dlna_request(
+        &self,
+        method: &Method,
+        headers: &[Header],
+        endpoint: &str,
+        arg: Option<&str>,
+        raw_query: &str,
+        body: &str,
+        base_url: &str,
+    ) -> ResponseBox {
+        let name = self.config.dlna_name.as_ref().expect("We only serve DLNA when it is enabled.");
+        let xml = header_content_type("text/xml; charset=\"utf-8\"");
+        match (method, endpoint, arg) {
+            (&Get, "description.xml", None) => {
+                let mut w = Vec::new();
+                dlna::write_device_description(&mut w, name, &dlna::device_uuid(name)).unwrap();
+                Response::from_data(w).with_header(xml).boxed()
+            }
+            (&Get, "ContentDirectory.xml", None) => {
+                Response::from_string(dlna::CONTENT_DIRECTORY_SCPD).with_header(xml).boxed()
+            }
+            (&Get, "ConnectionManager.xml", None) => {
+                Response::from_string(dlna::CONNECTION_MANAGER_SCPD).with_header(xml).boxed()
+            }
+            (&Post, "control", Some(..)) => {
+                let soap_action = headers
+                    .iter()
+                    .find(|h| h.field.equiv("SOAPAction"))
+                    .map(|h| h.value.as_str())
+                    .unwrap_or("");
+                let index = &*self.index_var.get();
+                let max_edits = self.config.search_max_edits;
+                match dlna::handle_control(index, max_edits, base_url, soap_action, body) {
+                    Ok(envelope) => Response::from_string(envelope).with_header(xml).boxed(),
+                    Err(fault) => {
+                        let mut w = Vec::new();
+                        dlna::write_fault(&mut w, fault).unwrap();
+                        Response::from_data(w)
+                            .with_status_code(500) // "500 Internal Server Error"
+                            .with_header(xml)
+                            .boxed()
+                    }
+                }
+            }
+            // Renderers often make a HEAD request first, tiny_http omits the
+            // body for those.
+            (&Get | &Method::Head, "track", Some(t)) => {
+                let is_transcoded = MetaServer::get_query_param(raw_query, "format").is_some();
+                self.handle_track(headers, t, raw_query, None)
+                    .with_header(
+                        Header::from_bytes(&b"contentFeatures.dlna.org"[..], dlna::content_features(is_transcoded).as_bytes())
+                            .expect("Failed to create contentFeatures header."),
+                    )
+                    .with_header(
+                        Header::from_bytes(&b"transferMode.dlna.org"[..], &b"Streaming"[..])
+                            .expect("Failed to create transferMode header."),
+                    )
+            }
+            (&Get | &Method::Head, "cover", Some(a)) => self.handle_album_cover(headers, a, None),
+            _ => self.handle_not_found(),
+        }
+    }
+
+    fn handle_search(&self, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let mut opt_query = None;
+        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
+            if k == "q" {
+                opt_query = Some(v);
+            }
+        };
+        let query = match opt_query {
+            Some(q) => q,
+            None => return self.handle_bad_request("Missing search query."),
+        };
+
+        let mut words = Vec::new();
+        normalize_words(query.as_ref(), &mut words);
+
+        let mut artists = Vec::new();
+        let mut albums = Vec::new();
+        let mut tracks = Vec::new();
+
+        let index = &*self.get_index(user);
+        let max_edits = self.config.search_max_edits;
+        index.search_artist(&words[..], max_edits, &mut artists);
+        index.search_album(&words[..], max_edits, &mut albums);
+        index.search_track(&words[..], max_edits, &mut tracks);
+
+        // Cap the number of search results we serve. We can easily produce many
+        // many results (especially when searching for "t", a prefix of "the",
+        // or when searching "a"). Searching is quite fast, but parsing and
+        // rendering the results in the frontend is slow, and having this many
+        // results is not useful anyway, so we cap them.
+        let n_artists = artists.len().min(250);
+        let n_albums = albums.len().min(250);
+        let n_tracks = tracks.len().min(250);
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_search_results_json(
+            index,
+            &mut w,
+            &artists[..n_artists],
+            &albums[..n_albums],
+            &tracks[..n_tracks],
+        ).unwrap();
+
+        json_response(w.into_inner(), encoding)
+            .with_status_code(200)
+            .boxed()
+    }
+
+    fn handle_typeahead(&self, raw_query: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let query = match MetaServer::get_query_param(raw_query, "q") {
+            Some(q) => q,
+            None => return self.handle_bad_request("Missing search query."),
+        };
+        let limit = match MetaServer::get_query_param(raw_query, "limit") {
+            None => typeahead::DEFAULT_LIMIT,
+            Some(v) => match usize::from_str(&v) {
+                Ok(n) if (1..=typeahead::MAX_LIMIT).contains(&n) => n,
+                _ => return self.handle_bad_request("Invalid limit, must be an integer from 1 to 25."),
+            },
+        };
+
+        let mut words = Vec::new();
+        normalize_words(query.as_ref(), &mut words);
+
+        let mut artists = Vec::new();
+        let mut albums = Vec::new();
+        let mut tracks = Vec::new();
+
+        // Search without typo tolerance, finding the fuzzy candidates is what
+        // makes the regular search slow on large libraries.
+        let index = &*self.get_index(user);
+        index.search_artist(&words[..], 0, &mut artists);
+        index.search_album(&words[..], 0, &mut albums);
+        index.search_track(&words[..], 0, &mut tracks);
+
+        artists.truncate(limit);
+        albums.truncate(limit);
+        tracks.truncate(limit);
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        serialization::write_typeahead_results_json(
+            index,
+            &mut w,
+            &words[..],
+            &artists[..],
+            &albums[..],
+            &tracks[..],
+        ).unwrap();
+
+        json_response(w.into_inner(), encoding)
+            .with_status_code(200)
+            .boxed()
+    }
+
+    fn handle_graphql(&self, db: &mut Connection, method: &Method, raw_query: &str, body: &str, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        if !self.config.graphql {
+            return self.handle_not_found();
+        }
+
+        // A GET request has the query in the url, a POST request in the body.
+        let request = match method {
+            &Get => graphql::parse_get_request(
+                MetaServer::get_query_param(raw_query, "query"),
+                MetaServer::get_query_param(raw_query, "variables"),
+            ),
+            _ => graphql::parse_json_request(body),
+        };
+
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+
+        // Errors in the request itself are a bad request, but errors while
+        // executing a valid query are part of a regular GraphQL response.
+        let status = match request {
+            Ok(request) => {
+                let index = &*self.get_index(user);
+                let thumb_cache = &*self.thumb_cache_var.get();
+                let user_data = self.user_data.lock().unwrap();
+                let queue = self.player.get_queue();
+                let mut ctx = graphql::Context {
+                    index: index,
+                    user: user,
+                    user_data: user_data.get(user),
+                    thumb_cache: thumb_cache,
+                    queue: &queue.tracks[..],
+                    db: db,
+                };
+                let result = graphql::execute(&mut ctx, &request.query, request.variables);
+                graphql::write_response(&mut w, &result).unwrap();
+                200
+            }
+            Err(msg) => {
+                graphql::write_response(&mut w, &Err(msg)).unwrap();
+                400
+            }
+        };
+
+        json_response(w.into_inner(), encoding)
+            .with_status_code(status)
+            .boxed()
+    }
+
+    fn handle_openapi(&self, encoding: ContentEncoding) -> ResponseBox {
+        let document = openapi::document();
+        json_response(serde_json::to_vec(&document).unwrap(), encoding).boxed()
+    }
+
+    fn handle_get_status(&self) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let history_status = self.player.get_history_status();
+        let limit_status = self.limit_counters.get_status();
+        serialization::write_status_json(&mut w, history_status, limit_status).unwrap();
+        Response::from_data(w.into_inner())
             .with_header(header_content_type("application/json"))
             .boxed()
     }
 
-    fn handle_dequeue(&self, id: &str) -> ResponseBox {
-        let queue_id = match QueueId::parse(id) {
-            Some(qid) => qid,
-            None => return self.handle_bad_request("Invalid queue id."),
+    fn handle_metrics(&self) -> ResponseBox {
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let queue_len = self.player.get_now_playing().queue_len;
+        let limit_status = self.limit_counters.get_status();
+        metrics::write_metrics(&mut w, queue_len, limit_status).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("text/plain; version=0.0.4"))
+            .boxed()
+    }
+
+    fn handle_healthz(&self, db: &mut Connection) -> ResponseBox {
+        let audio = playback::check_audio_output(&self.config);
+        let database = database_utils::check_writable(db).map_err(|err| {
+            warn!("Health check failed to write to the database: {:?}", err);
+            "The database is not writable."
+        });
+        let is_healthy = audio.is_ok() && database.is_ok();
+        let status = |result: Result<(), &'static str>| match result {
+            Ok(()) => "ok",
+            Err(msg) => msg,
         };
-        self.player.dequeue(queue_id);
-        Response::empty(200).boxed()
+        let body = serde_json::json!({
+            "healthy": is_healthy,
+            "audio": status(audio),
+            "database": status(database),
+        });
+        Response::from_data(serde_json::to_vec(&body).unwrap())
+            .with_header(header_content_type("application/json"))
+            .with_status_code(if is_healthy { 200 } else { 503 }) // "503 Service Unavailable"
+            .boxed()
     }
 
-    fn handle_queue_shuffle(&self) -> ResponseBox {
-        let index = &*self.index_var.get();
-        self.player.shuffle(index);
-        self.handle_queue()
+    fn handle_readyz(&self) -> ResponseBox {
+        // We only start listening after loading the index, and a scan swaps
+        // in a new index atomically, so if we can respond, we are ready.
+        let index = self.index_var.get();
+        let body = serde_json::json!({
+            "ready": true,
+            "tracks": index.get_tracks().len(),
+        });
+        Response::from_data(serde_json::to_vec(&body).unwrap())
+            .with_header(header_content_type("application/json"))
+            .boxed()
     }
 
-    fn handle_queue_clear(&self) -> ResponseBox {
-        self.player.clear_queue();
-        self.handle_queue()
+    fn handle_get_scan_status(&self) -> ResponseBox {
+        // TODO: We could add a long polling query parameter here, and version
+        // the status. Then in the request, include the previous version. If the
+        // current version is newer, respond immediately. If not, block for some
+        // time to wait for a new status, then return the current status. That
+        // way, we could make extremely responsive status updates.
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        let status = self.scanner.get_status();
+        serialization::write_scan_status_json(&mut w, status).unwrap();
+        Response::from_data(w.into_inner())
+            .with_header(header_content_type("application/json"))
+            .boxed()
     }
 
-    fn handle_get_volume(&self) -> ResponseBox {
+    fn handle_start_scan(&self) -> ResponseBox {
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
-        let volume = self.player.get_volume();
-        serialization::write_volume_json(&mut w, volume).unwrap();
+        let status = self.scanner.start(self.config.clone());
+        serialization::write_scan_status_json(&mut w, Some(status)).unwrap();
         Response::from_data(w.into_inner())
             .with_header(header_content_type("application/json"))
             .boxed()
     }
 
-    fn handle_change_volume(&self, add: Millibel) -> ResponseBox {
+    fn handle_sync(&self, headers: &[Header], user: Option<&str>) -> ResponseBox {
+        let index = self.index_var.get();
+        let user_data_version = self.user_data.lock().unwrap().get(user).version();
+        let generation = self.generation_cache.get(&index, user_data_version);
+
+        // Clients poll this endpoint, with the etag they mostly get a 304.
+        let etag = format!(r#""{}""#, generation);
+        if is_not_modified(headers, &etag) {
+            return self.handle_not_modified(&etag, CACHE_CONTROL_NO_CACHE);
+        }
+
         let buffer = Vec::new();
         let mut w = io::Cursor::new(buffer);
-        let volume = self.player.change_volume(add);
-        serialization::write_volume_json(&mut w, volume).unwrap();
+        serialization::write_sync_json(&*index, &mut w, &generation).unwrap();
         Response::from_data(w.into_inner())
             .with_header(header_content_type("application/json"))
+            .with_header(header_etag(&etag))
+            .with_header(header_cache_control(CACHE_CONTROL_NO_CACHE))
             .boxed()
     }
 
-    fn handle_search(&self, raw_query: &str) -> ResponseBox {
-        let mut opt_query = None;
-        for (k, v) in url::form_urlencoded::parse(raw_query.as_bytes()) {
-            if k == "q" {
-                opt_query = Some(v);
-            }
+    fn handle_stats(&self, user: Option<&str>, encoding: ContentEncoding) -> ResponseBox {
+        let index = &*self.get_index(user);
+        let buffer = Vec::new();
+        let mut w = io::Cursor::new(buffer);
+        // Library views share the strings and word indexes of the full index,
+        // so we report the memory of that.
+        let memory = self.index_var.get().memory_usage();
+        serialization::write_stats_json(index, &memory, &mut w).unwrap();
+        json_response(w.into_inner(), encoding).boxed()
+    }
+
+    /// Stream events to the client, on a thread of its own.
+    ///
+    /// The connection stays open for as long as the client is subscribed, so
+    /// we don't tie up one of the request handler threads with it.
+    fn handle_events(&self, request: Request) {
+        let receiver = self.event_bus.subscribe();
+        let builder = thread::Builder::new();
+        let result = builder.name("events".into()).spawn(move || {
+            let writer = request.into_writer();
+            // The client disconnecting ends the stream with an error, that's
+            // expected, so we don't report it.
+            let _ = events::stream_events(writer, receiver);
+        });
+        if let Err(err) = result {
+            error!("Error while spawning event stream thread: {:?}", err);
+        }
+    }
+
+    /// Write the Subsonic response document for the result of a call.
+    fn subsonic_response(&self, format: subsonic::Format, result: subsonic::ApiResult) -> ResponseBox {
+        let mut w = Vec::new();
+        subsonic::write_response(&mut w, format, &result).unwrap();
+        // Subsonic reports errors in the document, the http status is 200.
+        Response::from_data(w)
+            .with_header(header_content_type(format.content_type()))
+            .boxed()
+    }
+
+    /// Router function for all /rest/«method» calls, the Subsonic API.
+    fn handle_subsonic_request(
+        &self,
+        db: &mut Connection,
+        headers: &[Header],
+        endpoint: &str,
+        raw_query: &str,
+        body: &str,
+    ) -> ResponseBox {
+        use crate::subsonic::ApiError;
+
+        // Clients call either /rest/ping or /rest/ping.view.
+        let method = endpoint.strip_suffix(".view").unwrap_or(endpoint);
+        let params = subsonic::Params::parse(raw_query, body);
+        let format = subsonic::Format::from_params(&params);
+
+        // The Subsonic user name is the name of the token, listens, ratings,
+        // and playlists belong to the user of the token.
+        let (username, user, scope) = if self.config.unauthenticated {
+            (params.get("u").unwrap_or("musium").to_string(), None, auth::Scope::Full)
+        } else {
+            match subsonic::authenticate(&self.config.api_tokens, &params) {
+                Ok(token) => (token.name.clone(), token.user(), token.scope),
+                Err(err) => return self.subsonic_response(format, Err(err)),
+            }
+        };
+        if scope < subsonic::required_scope(method, &params) {
+            return self.subsonic_response(format, Err(ApiError::not_authorized()));
+        }
+
+        let index = &*self.get_index(user);
+        let result = match method {
+            "ping" => Ok(None),
+            "getLicense" => Ok(Some(subsonic::get_license())),
+            "getOpenSubsonicExtensions" => Ok(Some(subsonic::get_open_subsonic_extensions())),
+            "getMusicFolders" => Ok(Some(subsonic::get_music_folders())),
+            "getUser" => Ok(Some(subsonic::get_user(&username, scope))),
+            "getArtists" => Ok(Some(subsonic::get_artists(index))),
+            "getArtist" => subsonic::get_artist(index, &params).map(Some),
+            "getAlbum" => subsonic::get_album(index, &params).map(Some),
+            "getSong" => subsonic::get_song(index, &params).map(Some),
+            "getAlbumList2" => {
+                let user_data = self.user_data.lock().unwrap();
+                let mut rng = Prng::new();
+                subsonic::get_album_list2(index, user_data.get(user), &params, &mut rng).map(Some)
+            }
+            "search3" => subsonic::search3(index, self.config.search_max_edits, &params).map(Some),
+            "getPlaylists" => self.handle_subsonic_playlists(db, &username, user),
+            "getPlaylist" => match subsonic::parse_playlist_id(params.get("id").unwrap_or("")) {
+                Ok(playlist_id) => self.handle_subsonic_playlist(db, &username, user, playlist_id),
+                Err(err) => Err(err),
+            },
+            "createPlaylist" => self.handle_subsonic_create_playlist(db, &username, user, &params),
+            "updatePlaylist" => self.handle_subsonic_update_playlist(db, user, &params),
+            "deletePlaylist" => self.handle_subsonic_delete_playlist(db, user, &params),
+            "scrobble" => self.handle_subsonic_scrobble(db, user, &params),
+            "getNowPlaying" => Ok(Some(self.handle_subsonic_now_playing(&username))),
+            "jukeboxControl" => self.handle_subsonic_jukebox(user, &params),
+            "stream" | "download" | "getCoverArt" => {
+                // These respond with the file itself, not with a document,
+                // unless something went wrong.
+                match self.handle_subsonic_media(headers, method, &params, user) {
+                    Ok(response) => return response,
+                    Err(err) => Err(err),
+                }
+            }
+            _ => Err(ApiError::generic("Method not supported.")),
+        };
+
+        self.subsonic_response(format, result)
+    }
+
+    /// Serve a track or cover art for the Subsonic API.
+    fn handle_subsonic_media(
+        &self,
+        headers: &[Header],
+        method: &str,
+        params: &subsonic::Params,
+        user: Option<&str>,
+    ) -> Result<ResponseBox, subsonic::ApiError> {
+        let id = params.require("id")?;
+
+        if method == "getCoverArt" {
+            // We give out album ids as cover art ids, but some clients ask for
+            // the cover of a track by its id. Track ids are 16 hex digits,
+            // album ids 13.
+            let album_id = if id.len() == 16 {
+                subsonic::parse_track_id(id)?.album_id()
+            } else {
+                subsonic::parse_album_id(id)?
+            };
+            // Thumbnails are 140 pixels, when that is large enough, they are
+            // much smaller than the full cover.
+            let album_id_str = album_id.to_string();
+            return match params.get_usize("size", usize::MAX)? {
+                n if n <= 140 => Ok(self.handle_thumb(headers, &album_id_str, "", user)),
+                _ => Ok(self.handle_album_cover(headers, &album_id_str, user)),
+            };
+        }
+
+        let track_id = subsonic::parse_track_id(id)?;
+        let format = match method {
+            "stream" => MetaServer::get_subsonic_stream_format(params)?,
+            _ => None,
+        };
+        Ok(self.serve_track(headers, track_id, format, user))
+    }
+
+    /// Determine the format from the `format` and `maxBitRate` parameters.
+    fn get_subsonic_stream_format(
+        params: &subsonic::Params,
+    ) -> Result<Option<transcode::Format>, subsonic::ApiError> {
+        // A limit above what we transcode to means no limit, and so does 0.
+        let bitrate = match params.get_usize("maxBitRate", 0)? {
+            n if n > 0 && n <= 320 => Some(n.max(32) as u32),
+            _ => None,
+        };
+        let codec = match (params.get("format"), bitrate) {
+            (Some("opus"), _) => transcode::Codec::Opus,
+            (Some("mp3"), _) => transcode::Codec::Mp3,
+            (Some("raw"), _) | (_, None) => return Ok(None),
+            // Without a format that we support, we transcode only when the
+            // client limits the bitrate, to mp3, because every client plays it.
+            (_, Some(..)) => transcode::Codec::Mp3,
+        };
+        transcode::Format::new(codec, bitrate)
+            .map(Some)
+            .map_err(subsonic::ApiError::generic)
+    }
+
+    fn handle_subsonic_playlists(
+        &self,
+        db: &mut Connection,
+        username: &str,
+        user: Option<&str>,
+    ) -> subsonic::ApiResult {
+        let playlists = db
+            .begin()
+            .and_then(|mut tx| {
+                let mut result = Vec::new();
+                for playlist in db::iter_playlists(&mut tx, user)? {
+                    result.push(playlist?);
+                }
+                tx.commit()?;
+                Ok(result)
+            });
+        let playlists = match playlists {
+            Ok(ps) => ps,
+            Err(err) => {
+                error!("Error while loading playlists: {:?}", err);
+                return Err(subsonic::ApiError::generic("Database error."));
+            }
+        };
+
+        let index = &*self.get_index(user);
+        let mut result = subsonic::Element::new("playlists");
+        for playlist in playlists {
+            let tracks = match self.load_playlist(db, playlist.id, user) {
+                Ok(Some((_header, entries))) => entries.into_iter().map(|(_, t)| t).collect(),
+                Ok(None) => continue,
+                Err(err) => {
+                    error!("Error while loading playlist: {:?}", err);
+                    return Err(subsonic::ApiError::generic("Database error."));
+                }
+            };
+            let is_smart = playlist.query.is_some();
+            let element = subsonic::playlist_element(index, playlist.id, &playlist.name, username, is_smart, &tracks);
+            result = result.child(element);
+        }
+        Ok(Some(result))
+    }
+
+    fn handle_subsonic_playlist(
+        &self,
+        db: &mut Connection,
+        username: &str,
+        user: Option<&str>,
+        playlist_id: i64,
+    ) -> subsonic::ApiResult {
+        let (header, entries) = match self.load_playlist(db, playlist_id, user) {
+            Ok(Some(result)) => result,
+            Ok(None) => return Err(subsonic::ApiError::not_found("Playlist not found.")),
+            Err(err) => {
+                error!("Error while loading playlist: {:?}", err);
+                return Err(subsonic::ApiError::generic("Database error."));
+            }
+        };
+        let tracks: Vec<TrackId> = entries.into_iter().map(|(_, t)| t).collect();
+        let index = &*self.get_index(user);
+        let is_smart = header.query.is_some();
+        let element = subsonic::playlist_with_entries(index, playlist_id, &header.name, username, is_smart, &tracks);
+        Ok(Some(element))
+    }
+
+    /// Run a modification of an existing playlist in a transaction.
+    ///
+    /// Like `modify_playlist`, but reporting errors the Subsonic way.
+    fn modify_subsonic_playlist<F>(
+        &self,
+        db: &mut Connection,
+        playlist_id: i64,
+        user: Option<&str>,
+        applies_to: PlaylistKind,
+        mut f: F,
+    ) -> Result<(), subsonic::ApiError>
+    where
+        F: FnMut(&mut db::Transaction) -> db::Result<()>,
+    {
+        use crate::subsonic::ApiError;
+
+        let result = database_utils::with_write_transaction(db, |tx| {
+            let is_smart = match db::select_playlist(tx, playlist_id, user)? {
+                Some(header) => header.query.is_some(),
+                None => return Ok(Err(ApiError::not_found("Playlist not found."))),
+            };
+            if let (PlaylistKind::Static, true) = (applies_to, is_smart) {
+                return Ok(Err(ApiError::generic("Cannot edit the tracks of a smart playlist.")));
+            }
+            f(tx)?;
+            Ok(Ok(()))
+        });
+
+        match result {
+            Ok(r) => r,
+            Err(err) => {
+                error!("Error while modifying playlist: {:?}", err);
+                Err(ApiError::generic("Database error."))
+            }
+        }
+    }
+
+    /// Parse the track ids in the parameter, and confirm that they exist.
+    fn get_subsonic_tracks(
+        &self,
+        params: &subsonic::Params,
+        key: &str,
+        user: Option<&str>,
+    ) -> Result<Vec<TrackId>, subsonic::ApiError> {
+        let index = &*self.get_index(user);
+        let mut tracks = Vec::new();
+        for id in params.get_all(key) {
+            let track_id = subsonic::parse_track_id(id)?;
+            if index.get_track(track_id).is_none() {
+                return Err(subsonic::ApiError::not_found("Song not found."));
+            }
+            tracks.push(track_id);
+        }
+        Ok(tracks)
+    }
+
+    fn handle_subsonic_create_playlist(
+        &self,
+        db: &mut Connection,
+        username: &str,
+        user: Option<&str>,
+        params: &subsonic::Params,
+    ) -> subsonic::ApiResult {
+        use crate::subsonic::ApiError;
+
+        let tracks = self.get_subsonic_tracks(params, "songId", user)?;
+        let playlist_id = match (params.get("playlistId"), params.get("name")) {
+            // With an id, the call replaces the tracks of an existing playlist.
+            (Some(id), _) => {
+                let playlist_id = subsonic::parse_playlist_id(id)?;
+                self.modify_subsonic_playlist(db, playlist_id, user, PlaylistKind::Static, |tx| {
+                    let mut entries = Vec::new();
+                    for entry in db::iter_playlist_entries(tx, playlist_id)? {
+                        entries.push(entry?.id);
+                    }
+                    for entry_id in entries {
+                        db::delete_playlist_entry(tx, playlist_id, entry_id)?;
+                    }
+                    for &track_id in &tracks {
+                        playlist::append_track(tx, playlist_id, track_id)?;
+                    }
+                    Ok(())
+                })?;
+                playlist_id
+            }
+            (None, Some(name)) if !name.trim().is_empty() => {
+                let now_str = format_now_iso8601();
+                let result = database_utils::with_write_transaction(db, |tx| {
+                    playlist::create_with_tracks(tx, name.trim(), &now_str, user, &tracks)
+                });
+                match result {
+                    Ok(id) => id,
+                    Err(err) => {
+                        error!("Error while creating playlist: {:?}", err);
+                        return Err(ApiError::generic("Database error."));
+                    }
+                }
+            }
+            (None, Some(..)) => return Err(ApiError::generic("Playlist name must not be empty.")),
+            (None, None) => return Err(ApiError::missing_parameter("Expected 'playlistId' or 'name'.")),
+        };
+
+        // Since version 1.14 of the API, the response contains the playlist.
+        self.handle_subsonic_playlist(db, username, user, playlist_id)
+    }
+
+    fn handle_subsonic_update_playlist(
+        &self,
+        db: &mut Connection,
+        user: Option<&str>,
+        params: &subsonic::Params,
+    ) -> subsonic::ApiResult {
+        use crate::subsonic::ApiError;
+
+        let playlist_id = subsonic::parse_playlist_id(params.require("playlistId")?)?;
+        let name = match params.get("name").map(str::trim) {
+            Some("") => return Err(ApiError::generic("Playlist name must not be empty.")),
+            name => name,
         };
-        let query = match opt_query {
-            Some(q) => q,
-            None => return self.handle_bad_request("Missing search query."),
+        let to_add = self.get_subsonic_tracks(params, "songIdToAdd", user)?;
+        let mut to_remove = Vec::new();
+        for i in params.get_all("songIndexToRemove") {
+            match usize::from_str(i) {
+                Ok(i) => to_remove.push(i),
+                Err(..) => return Err(ApiError::generic("Invalid song index.")),
+            }
+        }
+
+        // A smart playlist can be renamed, but its tracks cannot be edited.
+        let applies_to = if to_add.is_empty() && to_remove.is_empty() {
+            PlaylistKind::Any
+        } else {
+            PlaylistKind::Static
         };
+        self.modify_subsonic_playlist(db, playlist_id, user, applies_to, |tx| {
+            if let Some(name) = name {
+                db::update_playlist_name(tx, playlist_id, name)?;
+            }
+            if !to_remove.is_empty() {
+                let mut entries = Vec::new();
+                for entry in db::iter_playlist_entries(tx, playlist_id)? {
+                    entries.push(entry?.id);
+                }
+                // The indices refer to the playlist before the removals.
+                for &i in &to_remove {
+                    if let Some(&entry_id) = entries.get(i) {
+                        db::delete_playlist_entry(tx, playlist_id, entry_id)?;
+                    }
+                }
+            }
+            for &track_id in &to_add {
+                playlist::append_track(tx, playlist_id, track_id)?;
+            }
+            Ok(())
+        })?;
 
-        let mut words = Vec::new();
-        normalize_words(query.as_ref(), &mut words);
+        Ok(None)
+    }
 
-        let mut artists = Vec::new();
-        let mut albums = Vec::new();
-        let mut tracks = Vec::new();
+    fn handle_subsonic_delete_playlist(
+        &self,
+        db: &mut Connection,
+        user: Option<&str>,
+        params: &subsonic::Params,
+    ) -> subsonic::ApiResult {
+        let playlist_id = subsonic::parse_playlist_id(params.require("id")?)?;
+        // The entries are deleted through the "on delete cascade".
+        self.modify_subsonic_playlist(db, playlist_id, user, PlaylistKind::Any, |tx| {
+            db::delete_playlist(tx, playlist_id)
+        })?;
+        Ok(None)
+    }
+
+    /// Record listens that a Subsonic client reports.
+    fn handle_subsonic_scrobble(
+        &self,
+        db: &mut Connection,
+        user: Option<&str>,
+        params: &subsonic::Params,
+    ) -> subsonic::ApiResult {
+        use crate::subsonic::ApiError;
+
+        // With `submission=false`, the client reports what it is playing now,
+        // rather than a listen. We don't track that for other players.
+        if params.get("submission") == Some("false") {
+            return Ok(None);
+        }
 
+        let client = params.get("c").and_then(listens::parse_client);
         let index = &*self.index_var.get();
-        index.search_artist(&words[..], &mut artists);
-        index.search_album(&words[..], &mut albums);
-        index.search_track(&words[..], &mut tracks);
+        let now = chrono::Utc::now().timestamp();
+        let times: Vec<&str> = params.get_all("time").collect();
 
-        // Cap the number of search results we serve. We can easily produce many
-        // many results (especially when searching for "t", a prefix of "the",
-        // or when searching "a"). Searching is quite fast, but parsing and
-        // rendering the results in the frontend is slow, and having this many
-        // results is not useful anyway, so we cap them.
-        let n_artists = artists.len().min(250);
-        let n_albums = albums.len().min(250);
-        let n_tracks = tracks.len().min(250);
+        let mut listens = Vec::new();
+        for (i, id) in params.get_all("id").enumerate() {
+            let track_id = subsonic::parse_track_id(id)?;
+            let track = index.get_track(track_id).ok_or(ApiError::not_found("Song not found."))?;
+            // The time is in milliseconds since the epoch. Clients scrobble when
+            // a track ends, so without time, we assume it started a track ago.
+            let posix_seconds_utc = match times.get(i) {
+                Some(t) => match i64::from_str(t) {
+                    Ok(ms) => ms / 1000,
+                    Err(..) => return Err(ApiError::generic("Invalid time, must be milliseconds since the epoch.")),
+                },
+                None => now - track.duration_seconds as i64,
+            };
+            listens.push((track_id, Instant { posix_seconds_utc }));
+        }
 
-        let buffer = Vec::new();
-        let mut w = io::Cursor::new(buffer);
-        serialization::write_search_results_json(
-            index,
-            &mut w,
-            &artists[..n_artists],
-            &albums[..n_albums],
-            &tracks[..n_tracks],
-        ).unwrap();
+        let result = database_utils::with_write_transaction(db, |tx| {
+            let mut inserted = Vec::new();
+            for &(track_id, started_at) in &listens {
+                let listen_id = listen_import::insert_listen(tx, index, "subsonic", started_at, track_id)?;
+                // When the client retries, we already have the listen.
+                if let Some(listen_id) = listen_id {
+                    if let Some(client) = client.as_ref() {
+                        db::insert_listen_client(tx, listen_id, client)?;
+                    }
+                    if let Some(user) = user {
+                        db::insert_listen_user(tx, listen_id, user)?;
+                    }
+                    db::insert_first_listen(tx, listen_id)?;
+                    inserted.push((track_id, started_at));
+                }
+            }
+            sessions::assign_sessions(tx)?;
+            Ok(inserted)
+        });
 
-        Response::from_data(w.into_inner())
-            .with_status_code(200)
-            .with_header(header_content_type("application/json"))
-            .boxed()
-    }
+        let inserted = match result {
+            Ok(inserted) => inserted,
+            Err(err) => {
+                error!("Error while recording scrobble: {:?}", err);
+                return Err(ApiError::generic("Database error."));
+            }
+        };
 
-    fn handle_get_scan_status(&self) -> ResponseBox {
-        // TODO: We could add a long polling query parameter here, and version
-        // the status. Then in the request, include the previous version. If the
-        // current version is newer, respond immediately. If not, block for some
-        // time to wait for a new status, then return the current status. That
-        // way, we could make extremely responsive status updates.
-        let buffer = Vec::new();
-        let mut w = io::Cursor::new(buffer);
-        let status = self.scanner.get_status();
-        serialization::write_scan_status_json(&mut w, status).unwrap();
-        Response::from_data(w.into_inner())
-            .with_header(header_content_type("application/json"))
-            .boxed()
+        let mut user_data = self.user_data.lock().unwrap();
+        let user_data = user_data.get_mut(user);
+        for (track_id, started_at) in inserted {
+            let album = index.get_album(track_id.album_id()).expect("Track album should be in index.");
+            let album_artist_id = index.get_album_artists(album.artist_ids)[0];
+            user_data.add_listen(track_id, album_artist_id, started_at);
+        }
+
+        Ok(None)
     }
 
-    fn handle_start_scan(&self) -> ResponseBox {
-        let buffer = Vec::new();
-        let mut w = io::Cursor::new(buffer);
-        let status = self.scanner.start(self.config.clone());
-        serialization::write_scan_status_json(&mut w, Some(status)).unwrap();
-        Response::from_data(w.into_inner())
-            .with_header(header_content_type("application/json"))
-            .boxed()
+    fn handle_subsonic_now_playing(&self, user: &str) -> subsonic::Element {
+        let index = &*self.index_var.get();
+        let now_playing = self.player.get_now_playing();
+        let entry = now_playing.current.and_then(|current| {
+            let track_id = current.source.track_id()?;
+            let track = index.get_track(track_id)?;
+            let entry = subsonic::song_element(index, "entry", track_id, track)
+                .attr("username", user)
+                .attr("minutesAgo", 0_u32)
+                .attr("playerId", 0_u32)
+                .attr("playerName", "musium");
+            Some(entry)
+        });
+        subsonic::Element::new("nowPlaying").children(entry)
     }
 
-    fn handle_stats(&self) -> ResponseBox {
+    /// Control the player through the Subsonic jukebox interface.
+    ///
+    /// Musium plays whenever the queue is not empty, so `stop` pauses, and
+    /// `start` continues after a pause.
+    fn handle_subsonic_jukebox(&self, user: Option<&str>, params: &subsonic::Params) -> subsonic::ApiResult {
+        use crate::subsonic::{ApiError, Element};
+
         let index = &*self.index_var.get();
-        let buffer = Vec::new();
-        let mut w = io::Cursor::new(buffer);
-        serialization::write_stats_json(index, &mut w).unwrap();
-        Response::from_data(w.into_inner())
-            .with_header(header_content_type("application/json"))
-            .boxed()
+        let action = params.require("action")?;
+        let client = params.get("c").and_then(listens::parse_client);
+        let get_queue_index = || {
+            let i = params.require("index")?;
+            usize::from_str(i).map_err(|_| ApiError::generic("Invalid index."))
+        };
+
+        match action {
+            "get" | "status" => {}
+            "start" => {
+                self.player.play();
+            }
+            "stop" => {
+                if self.player.is_casting() {
+                    return Err(ApiError::generic("Can't stop while casting."));
+                }
+                self.player.pause();
+            }
+            "add" | "set" => {
+                let tracks = self.get_subsonic_tracks(params, "id", user)?;
+                if action == "set" {
+                    self.player.clear_queue();
+                }
+                let shuffle_tracks = false;
+                self.player.enqueue_many(index, &tracks, client.as_deref(), user, shuffle_tracks);
+            }
+            "clear" => self.player.clear_queue(),
+            "shuffle" => self.player.shuffle(index),
+            "remove" => {
+                let i = get_queue_index()?;
+                let queue = self.player.get_queue();
+                match queue.tracks.get(i) {
+                    None => return Err(ApiError::not_found("No track at this index.")),
+                    // The current track cannot be dequeued, but it can be skipped.
+                    Some(..) if i == 0 => { self.player.skip(); }
+                    Some(t) => self.player.dequeue(t.queue_id),
+                }
+            }
+            "skip" => {
+                // Skip to the track at the index, by removing the ones before it.
+                let i = get_queue_index()?;
+                let queue = self.player.get_queue();
+                if i >= queue.tracks.len() {
+                    return Err(ApiError::not_found("No track at this index."));
+                }
+                for t in &queue.tracks[1..i.max(1)] {
+                    self.player.dequeue(t.queue_id);
+                }
+                if i > 0 {
+                    self.player.skip();
+                }
+            }
+            _ => return Err(ApiError::generic("Unsupported jukebox action.")),
+        }
+
+        let queue = self.player.get_queue();
+        let current = queue.tracks.first();
+        let current_index: i64 = if current.is_some() { 0 } else { -1 };
+        let is_playing = current.is_some() && self.player.get_now_playing().state != PlaybackState::Paused;
+        // Gain is a linear factor, our volume is in millibel.
+        let gain = 10.0_f64.powf(self.player.get_volume().0 as f64 / 2000.0).min(1.0);
+        let status = |name| {
+            Element::new(name)
+                .attr("currentIndex", current_index)
+                .attr("playing", is_playing)
+                .attr("gain", gain)
+                .attr_opt("position", current.map(|t| (t.position_ms / 1000) as i64))
+        };
+
+        if action == "get" {
+            let entries = queue.tracks.iter().filter_map(|t| {
+                let track_id = t.source.track_id()?;
+                let track = index.get_track(track_id)?;
+                Some(subsonic::song_element(index, "entry", track_id, track))
+            });
+            Ok(Some(status("jukeboxPlaylist").children(entries)))
+        } else {
+            Ok(Some(status("jukeboxStatus")))
+        }
     }
 
     /// Router function for all /api/«endpoint» calls.
+    ///
+    /// The `user` is the user of the token, `None` for the default user.
     fn handle_api_request(
         &self,
         db: &mut Connection,
         method: &Method,
+        headers: &[Header],
         endpoint: &str,
         arg1: Option<&str>,
         arg2: Option<&str>,
         arg3: Option<&str>,
         query: &str,
+        body: &str,
+        origin: &RequestOrigin,
+        user: Option<&str>,
+        encoding: ContentEncoding,
     ) -> ResponseBox {
+        // Endpoints that control playback act on the player that the `player`
+        // query parameter selects, the default player when it is absent.
+        let player = match self.get_player(query) {
+            Some(p) => p,
+            None => return self.handle_not_found(),
+        };
+
         match (method, endpoint, arg1) {
             // API endpoints.
-            (&Get, "cover",    Some(t)) => self.handle_album_cover(t),
-            (&Get, "thumb",    Some(t)) => self.handle_thumb(t),
-            (&Get, "waveform", Some(t)) => self.handle_waveform(db, t),
-            (&Get, "track",    Some(t)) => self.handle_track(t),
-            (&Get, "album",    Some(a)) => self.handle_album(a),
-            (&Get, "artist",   Some(a)) => self.handle_artist(a),
-            (&Get, "albums",   None)    => self.handle_albums(),
-            (&Get, "search",   None)    => self.handle_search(query),
-            (&Get, "stats",    None)    => self.handle_stats(),
-
-            // Rating.
-            (&Put, "track", Some(t)) => match (arg2, arg3) {
-                (Some("rating"), Some(r)) => self.handle_rating(t, r),
-                _ => {
-                    println!("{arg2:?} {arg3:?}");
-                    self.handle_bad_request("No such endpoint.")
+            (&Get, "cover",    Some(t)) => self.handle_album_cover(headers, t, user),
+            (&Get, "thumb",    Some(t)) => self.handle_thumb(headers, t, query, user),
+            (&Get, "waveform", Some(t)) => self.handle_waveform(db, t, user),
+            (&Get, "track",    Some(t)) if arg2 == Some("similar") => self.handle_similar_tracks(db, t, query, user, encoding),
+            (&Get, "artist",   Some(a)) if arg2 == Some("similar") => self.handle_similar_artists(db, a, query, user, encoding),
+            (&Get, "track",    Some(t)) => self.handle_track(headers, t, query, user),
+            (&Get, "album",    Some(a)) => match arg2 {
+                None             => self.handle_album(a, user, encoding),
+                Some("download") => self.handle_album_download(a, query, user),
+                Some("dynamics") => self.handle_album_dynamics(db, a, user, encoding),
+                _ => self.handle_bad_request("No such album operation."),
+            }
+            (&Get, "artist",   Some(a)) => self.handle_artist(db, a, user, encoding),
+            (&Get, "albums",   None)    => self.handle_albums(query, user, encoding),
+            (&Get, "albums",   Some("recent")) => self.handle_albums_recent(query, user, encoding),
+            (&Get, "albums",   Some("recently-played")) => self.handle_albums_recently_played(query, user, encoding),
+            (&Get, "albums",   Some("in-progress")) => self.handle_albums_in_progress(db, query, user, encoding),
+            (&Get, "albums",   Some("random")) => self.handle_albums_random(db, query, user, encoding),
+            (&Get, "artists",  None)    => self.handle_artists(query, user, encoding),
+            (&Get, "tracks",   None)    => self.handle_tracks(query, user, encoding),
+            (&Get, "dynamics", Some(kind)) => self.handle_dynamics(db, kind, query, user, encoding),
+            (&Get, "search",   None)    => self.handle_search(query, user, encoding),
+            (&Get, "typeahead", None)   => self.handle_typeahead(query, user, encoding),
+            (&Get | &Post, "graphql", None) => self.handle_graphql(db, method, query, body, user, encoding),
+            (&Get, "openapi.json", None) => self.handle_openapi(encoding),
+            (&Get, "stats",    None)    => self.handle_stats(user, encoding),
+            (&Get, "sync",     None)    => self.handle_sync(headers, user),
+            (&Get, "stats",    Some("library")) => self.handle_library_stats(db, user, encoding),
+            (&Get, "stats",    Some("on-this-day")) => self.handle_on_this_day(db, query, user, encoding),
+            (&Get, "stats",    Some("rewind")) => self.handle_rewind(db, arg2, user, encoding),
+            (&Get, "stats",    Some("charts")) => self.handle_chart(db, arg2, query, user, encoding),
+            (&Get, "stats",    Some(k)) => self.handle_listen_stats(db, k, query, user, encoding),
+            (&Get, "favorites", None)   => self.handle_favorites(user, encoding),
+            (&Get, "playlists", None)   => self.handle_playlists(db, user, encoding),
+            (&Get, "listens",   None)   => self.handle_listens(db, query, user, encoding),
+            (&Get, "listens",   Some("export")) => self.handle_listens_export(query),
+            (&Get, "playlist",  Some(p)) => match arg2 {
+                None         => self.handle_playlist(db, p, user, encoding),
+                Some("m3u8") => self.handle_playlist_m3u8(db, p, user),
+                Some("xspf") => self.handle_playlist_xspf(db, p, &origin.base_url, user),
+                _ => self.handle_bad_request("No such playlist operation."),
+            }
+
+            // Metadata edits.
+            (&Put, "track" | "album", Some(id)) if arg2 == Some("metadata") && arg3.is_none() => {
+                self.handle_edit_metadata(db, endpoint, id, query, user)
+            }
+            (&Get, "metadata", Some("edits")) => self.handle_metadata_edits(db, query, encoding),
+
+            // Artist aliases.
+            (&Post, "artist", Some(id)) if arg2 == Some("merge") && arg3.is_some() => {
+                self.handle_merge_artist(db, id, arg3.unwrap())
+            }
+            (&Delete, "artist", Some(id)) if arg2 == Some("merge") && arg3.is_none() => {
+                self.handle_unmerge_artist(db, id)
+            }
+            (&Get, "artists", Some("aliases")) => self.handle_artist_aliases(db, encoding),
+
+            // Tag proposals from fingerprints.
+            (&Get, "acoustid", Some("proposals")) => self.handle_acoustid_proposals(db, encoding),
+            (&Post, "acoustid", Some(id)) if arg2 == Some("accept") && arg3.is_none() => {
+                self.handle_review_acoustid_proposal(db, id, true, query)
+            }
+            (&Post, "acoustid", Some(id)) if arg2 == Some("reject") && arg3.is_none() => {
+                self.handle_review_acoustid_proposal(db, id, false, query)
+            }
+
+            // Exclusion from shuffling. A put excludes, a delete includes again.
+            (&Put | &Delete, "track" | "album", Some(id)) if arg2 == Some("exclusion") && arg3.is_none() => {
+                self.handle_exclusion(db, endpoint, id, method == &Put, user)
+            }
+
+            // Rating. A put sets the rating, a delete resets it to neutral.
+            (&Put | &Delete, "track" | "album" | "artist", Some(id)) => {
+                let rating_str = match (method, arg2, arg3) {
+                    (&Put, Some("rating"), Some(r)) => Some(r),
+                    (&Delete, Some("rating"), None) => None,
+                    _ => return self.handle_bad_request("No such endpoint."),
+                };
+                match endpoint {
+                    "track" => self.handle_rating(id, rating_str, user),
+                    "album" => self.handle_album_rating(id, rating_str, user),
+                    _ => self.handle_artist_rating(id, rating_str, user),
                 }
             }
 
+            // Playlist manipulation.
+            (&Post, "playlists", None) => self.handle_create_playlist(db, query, user),
+            (&Post, "playlists", Some("import")) => self.handle_import_playlist(db, query, body, user),
+            (&Put | &Delete | &Post, "playlist", Some(p)) => match (method, arg2, arg3) {
+                (&Put,    Some("name"),    None)    => self.handle_rename_playlist(db, p, query, user),
+                (&Put,    Some("query"),   None)    => self.handle_update_playlist_query(db, p, query, user),
+                (&Delete, None,            None)    => self.handle_delete_playlist(db, p, user),
+                (&Put,    Some("track"),   Some(t)) => self.handle_playlist_add_track(db, p, t, user),
+                (&Delete, Some("entry"),   Some(e)) => self.handle_playlist_remove_entry(db, p, e, user),
+                (&Post,   Some("move"),    Some(e)) => self.handle_playlist_move_entry(db, p, e, query, user),
+                (&Post,   Some("enqueue"), None)    => self.handle_playlist_enqueue(player, db, p, query, user),
+                _ => self.handle_bad_request("No such playlist operation."),
+            }
+
+            // Internet radio stations.
+            (&Get,    "radio", None)    => self.handle_radio_stations(db, encoding),
+            (&Post,   "radio", None)    => self.handle_add_radio_station(db, query),
+            (&Delete, "radio", Some(r)) if arg2.is_none() => self.handle_delete_radio_station(db, r),
+            (&Post,   "radio", Some(r)) => match arg2 {
+                Some("enqueue") => self.handle_radio_enqueue(player, db, r, query),
+                _ => self.handle_bad_request("No such radio operation."),
+            }
+
             // Play queue manipulation.
-            (&Get,    "queue",  None)            => self.handle_queue(),
-            (&Put,    "queue",  Some(t))         => self.handle_enqueue(t),
-            (&Delete, "queue",  Some(t))         => self.handle_dequeue(t),
-            (&Post,   "queue",  Some("shuffle")) => self.handle_queue_shuffle(),
-            (&Post,   "queue",  Some("clear"))   => self.handle_queue_clear(),
+            (&Get,    "queue",  None)            => self.handle_queue_page(player, db, query, user),
+            (&Get,    "queue",  Some("m3u8"))    => self.handle_queue_m3u8(player),
+            (&Get,    "queue",  Some("xspf"))    => self.handle_queue_xspf(player, &origin.base_url),
+            (&Put,    "queue",  Some(t))         => self.handle_enqueue(player, t, query, user),
+            (&Delete, "queue",  Some(t))         => self.handle_dequeue(player, t),
+            (&Post,   "queue",  Some("tracks"))  => self.handle_enqueue_many(player, query, body, user),
+            (&Post,   "queue",  Some("library")) => self.handle_enqueue_library(player, query, user),
+            (&Post,   "queue",  Some("shuffle")) => self.handle_queue_shuffle(player, db, user),
+            (&Post,   "queue",  Some("clear"))   => self.handle_queue_clear(player, db, user),
+            (&Post,   "queue",  Some("skip"))    => self.handle_queue_skip(player, db, user),
+            (&Post,   "queue",  Some("previous")) => self.handle_queue_previous(player, db, user),
+            (&Post,   "queue",  Some("handoff")) => self.handle_queue_handoff(player, db, query, user),
+            (&Post,   "queue",  Some("vote-skip")) => self.handle_queue_vote_skip(player, origin),
+            (&Post,   "queue",  Some(q)) if arg2 == Some("resume") => self.handle_queue_resume(player, db, q),
+            (&Post,   "queue",  Some("love"))    => self.handle_toggle_love(player, user),
+
+            // Setting and clearing the token cookie for the webinterface.
+            (&Post, "login", None)          => self.handle_login(body, origin),
+            (&Post, "logout", None)         => self.handle_logout(),
+
+            // The players, each with its own queue, volume, and output.
+            (&Get,  "players", None)        => self.handle_get_players(),
+
+            // The current track and playback position, in one response.
+            (&Get,  "player", None)         => self.handle_get_player(player, user),
+
+            // Pausing, and jumping to a position in the current track.
+            (&Post, "player", Some("pause")) => self.handle_pause(player, user),
+            (&Post, "player", Some("play"))  => self.handle_play(player, user),
+            (&Post, "player", Some("seek"))  => self.handle_seek(player, query, user),
 
             // Volume control, volume up/down change the volume by 1 dB.
-            (&Get,  "volume", None)         => self.handle_get_volume(),
-            (&Post, "volume", Some("up"))   => self.handle_change_volume(Millibel( 1_00)),
-            (&Post, "volume", Some("down")) => self.handle_change_volume(Millibel(-1_00)),
+            (&Get,  "volume", None)         => self.handle_get_volume(player),
+            (&Post, "volume", Some("up"))   => self.handle_change_volume(player, Millibel( 1_00)),
+            (&Post, "volume", Some("down")) => self.handle_change_volume(player, Millibel(-1_00)),
+
+            // Playing on a cast device instead of the audio card.
+            (&Get,    "cast", None)            => self.handle_get_cast(player),
+            (&Get,    "cast", Some("devices")) => self.handle_cast_devices(),
+            (&Put,    "cast", Some(id))        => self.handle_cast_to(player, id),
+            (&Delete, "cast", None)            => self.handle_stop_cast(player),
+
+            // Playing in the browser, with the web app as the audio output.
+            (&Put,    "browser", None)          => self.handle_play_in_browser(player),
+            (&Post,   "browser", Some(session)) => self.handle_browser_report(player, session, arg2, query),
+            (&Delete, "browser", Some(session)) => self.handle_stop_browser(player, session),
+
+            // Per-room volume when playing through Snapcast.
+            (&Get, "zones", None)     => self.handle_zones(),
+            (&Put, "zone",  Some(id)) => match (arg2, arg3) {
+                (Some("volume"), Some(percent)) => self.handle_set_zone_volume(id, percent),
+                _ => self.handle_bad_request("No such zone operation."),
+            }
+
+            // Health of the background threads.
+            (&Get,  "status", None)         => self.handle_get_status(),
 
             // Background library scanning.
             (&Get,  "scan", Some("status")) => self.handle_get_scan_status(),
             (&Post, "scan", Some("start"))  => self.handle_start_scan(),
 
+            // Reloading the settings that can change without a restart.
+            (&Post, "config", Some("reload")) => self.handle_reload_config(),
+
+            // Database backup (as a download) and maintenance.
+            (&Get,  "backup", None)         => self.handle_backup(),
+            (&Post, "maintenance", None)    => self.handle_maintenance(),
+
             _ => self.handle_bad_request("No such (method, endpoint, argument) combination."),
         }
     }
 
-    fn handle_request(&self, db: &mut Connection, request: Request) {
+    fn handle_request(&self, db: &mut Connection, mut request: Request) {
+        // Copy the url, because reading the body below borrows the request
+        // mutably.
+        let url = request.url().to_string();
+
         // Break url into the part before the ? and the part after. The part
         // before we split on slashes.
-        let mut url_iter = request.url().splitn(2, '?');
+        let mut url_iter = url.splitn(2, '?');
+        let path = url_iter.next().unwrap_or("/");
+        let query = url_iter.next().unwrap_or("");
+
+        // Behind a reverse proxy at /music/, the webinterface uses relative
+        // urls, so it must be served from /music/, not /music.
+        if !self.config.base_path.is_empty() && path == self.config.base_path {
+            let response = self.handle_redirect("/", None);
+            if let Err(err) = request.respond(response) {
+                error!("Error while responding to request: {:?}", err);
+            }
+            return;
+        }
 
         // The individual parts in between the slashes.
-        let mut p0 = None;
-        let mut p1 = None;
-        let mut p2 = None;
-        let mut p3 = None;
-        let mut p4 = None;
+        let mut parts = proxy::strip_base_path(&self.config.base_path, path)
+            .splitn(6, '/')
+            .filter(|x| x.len() > 0);
+        let p0 = parts.next();
+        let p1 = parts.next();
+        let p2 = parts.next();
+        let p3 = parts.next();
+        let p4 = parts.next();
+
+        // Exports that reference tracks by url need to know under which host
+        // the client reaches us. Fall back to the listen address if the client
+        // did not tell us.
+        let host = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Host"))
+            .map(|h| h.value.as_str().to_string())
+            .unwrap_or_else(|| self.config.listen.clone());
+        let peer = request.remote_addr().ip();
+        let trusted_proxies = &self.config.trusted_proxies[..];
+        let is_https = self.config.tls_paths().is_some()
+            || proxy::is_forwarded_https(peer, request.headers(), trusted_proxies);
+        let client_ip = proxy::client_ip(peer, request.headers(), trusted_proxies);
+
+        // Requests with a token count against the token, so clients behind
+        // the same address don't share a budget. Other requests, including
+        // attempts to guess a token, count against the address.
+        let client = match auth::authenticate(&self.config.api_tokens, &request) {
+            Some(token) => limits::Client::Token(token.name.clone()),
+            None => limits::Client::Ip(client_ip),
+        };
+        let origin = RequestOrigin {
+            client_ip: client_ip,
+            is_https: is_https,
+            base_url: format!(
+                "{}://{}{}",
+                if is_https { "https" } else { "http" },
+                host,
+                self.config.base_path,
+            ),
+            client: client,
+        };
+
+        // Other sites can call the API from the browser, if the config allows
+        // their origin.
+        let cors_origin = match p0 {
+            Some("api") => proxy::cors_allow_origin(&self.config.cors_origins, request.headers())
+                .map(|o| o.to_string()),
+            _ => None,
+        };
 
-        if let Some(base) = url_iter.next() {
-            let mut parts = base.splitn(6, '/').filter(|x| x.len() > 0);
+        // Browsers ask for permission before they make a cross-origin request
+        // with a token, and they don't include the token in that request.
+        if let (&Method::Options, Some("api"), Some(..)) = (request.method(), p0, p1) {
+            let response = match cors_origin.as_ref() {
+                Some(..) => Response::empty(204).boxed(), // "204 No Content"
+                None => Response::from_string("Cross-origin requests from this origin are not allowed.")
+                    .with_status_code(403) // "403 Forbidden"
+                    .boxed(),
+            };
+            self.respond(request, response, cors_origin.as_deref());
+            return;
+        }
 
-            p0 = parts.next();
-            p1 = parts.next();
-            p2 = parts.next();
-            p3 = parts.next();
-            p4 = parts.next();
+        if let Some(limiter) = self.rate_limiter.as_ref() {
+            if let Err(retry_after_seconds) = limiter.check(origin.client.clone(), std::time::Instant::now()) {
+                self.limit_counters.count_rate_limited();
+                let response = self.handle_too_many_requests(retry_after_seconds);
+                self.respond(request, response, cors_origin.as_deref());
+                return;
+            }
         }
 
-        let query = url_iter.next().unwrap_or("");
+        // Everything under /api requires a token, except for logging in.
+        if let (Some("api"), Some(endpoint)) = (p0, p1) {
+            if endpoint != "login" {
+                if let Some(response) = self.check_authorized(&request, endpoint, p2, p3, query) {
+                    self.respond(request, response, cors_origin.as_deref());
+                    return;
+                }
+            }
+        }
+
+        // Prometheus looks for metrics at /metrics, not under /api, but they
+        // require a token all the same.
+        if let (Some("metrics"), None) = (p0, p1) {
+            if let Some(response) = self.check_authorized(&request, "metrics", None, None, query) {
+                self.respond(request, response, cors_origin.as_deref());
+                return;
+            }
+        }
+
+        // Listens, ratings, and playlists belong to the user of the token.
+        // Without a token, or without a user in it, they are the default user's.
+        let user = auth::authenticate(&self.config.api_tokens, &request).and_then(|t| t.user());
+
+        // The event stream takes over the connection, so it does not produce
+        // a response like the other endpoints.
+        if let (&Get, Some("api"), Some("events"), None) = (request.method(), p0, p1, p2) {
+            self.handle_events(request);
+            return;
+        }
+
+        // Most endpoints take their arguments from the url, only uploads
+        // (playlist import), bulk enqueues, and Subsonic clients that post
+        // their parameters as a form have a body. Cap its size, see `limits.rs`. We check the
+        // declared length first, so we don't read a body that we'd reject.
+        let mut body = String::new();
+        if request.method() == &Post {
+            let max_body_len = limits::max_body_len(p0, p1, p2);
+            let declared_len = request.body_length().unwrap_or(0) as u64;
+            let read = if declared_len > max_body_len {
+                Ok(declared_len)
+            } else {
+                request
+                    .as_reader()
+                    .take(max_body_len + 1)
+                    .read_to_string(&mut body)
+                    .map(|n| n as u64)
+            };
+            if let Ok(n) = read {
+                if n > max_body_len {
+                    self.limit_counters.count_oversized();
+                    let response = self.handle_payload_too_large();
+                    self.respond(request, response, cors_origin.as_deref());
+                    return;
+                }
+            }
+            if read.is_err() {
+                let response = self.handle_bad_request("Request body must be UTF-8.");
+                self.respond(request, response, cors_origin.as_deref());
+                return;
+            }
+        }
+
+        // Large json responses, such as the album list, compress very well.
+        let encoding = http_utils::negotiate_encoding(
+            get_header(request.headers(), "Accept-Encoding"),
+            &[ContentEncoding::Gzip],
+        );
 
         // A very basic router. See also docs/api.md for an overview.
         let response = match (request.method(), p0, p1) {
             // API endpoints go through the API router, to keep this match arm
             // a bit more concise.
-            (method, Some("api"), Some(endpoint)) => self.handle_api_request(db, method, endpoint, p2, p3, p4, query),
+            (method, Some("api"), Some(endpoint)) => self.handle_api_request(db, method, request.headers(), endpoint, p2, p3, p4, query, &body, &origin, user, encoding),
+
+            // The Subsonic API, for compatibility with existing clients.
+            (_, Some("rest"), Some(endpoint)) => self.handle_subsonic_request(db, request.headers(), endpoint, query, &body),
+
+            // Browsing and streaming for DLNA clients, when enabled.
+            (method, Some("dlna"), Some(endpoint)) if self.config.dlna_name.is_some() => {
+                self.handle_dlna_request(method, request.headers(), endpoint, p2, query, &body, &origin.base_url)
+            }
 
             // Web endpoints.
-            (&Get, None,                  None) => self.handle_static_file("app/index.html", "text/html"),
-            (&Get, Some("style.css"),     None) => self.handle_static_file("app/style.css", "text/css"),
-            (&Get, Some("dark.css"),      None) => self.handle_static_file("app/dark.css", "text/css"),
-            (&Get, Some("manifest.json"), None) => self.handle_static_file("app/manifest.json", "text/javascript"),
-            (&Get, Some("app.js"),        None) => self.handle_static_file("app/output/app.js", "text/javascript"),
-            (&Get, Some(path),            None) if path.ends_with(".svg") => {
-                let mut file_path = "app/".to_string();
-                file_path.push_str(path);
-                self.handle_static_file(&file_path, "image/svg+xml")
+            (&Get, None,                  None) => self.handle_index(&request),
+            (&Get, Some("login"),         None) => self.handle_static_file(request.headers(), "login.html"),
+            (&Get, Some("metrics"),       None) => self.handle_metrics(),
+            (&Get, Some("healthz"),       None) => self.handle_healthz(db),
+            (&Get, Some("readyz"),        None) => self.handle_readyz(),
+            (&Get, Some(name),            None) if assets::content_type(name).is_some() => {
+                self.handle_static_file(request.headers(), name)
             }
             // Fallback.
             (&Get, _, _) => self.handle_not_found(),
             _ => self.handle_bad_request("Expected a GET request."),
         };
 
+        self.respond(request, response, cors_origin.as_deref());
+    }
+
+    /// Send the response, with CORS headers if the origin is allowed.
+    fn respond(&self, request: Request, mut response: ResponseBox, cors_origin: Option<&str>) {
+        if let Some(origin) = cors_origin {
+            let headers: [(&[u8], &[u8]); 5] = [
+                (b"Access-Control-Allow-Origin", origin.as_bytes()),
+                (b"Access-Control-Allow-Methods", b"GET, POST, PUT, DELETE"),
+                (b"Access-Control-Allow-Headers", b"Authorization, Content-Type"),
+                (b"Access-Control-Max-Age", b"86400"),
+                (b"Vary", b"Origin"),
+            ];
+            for (field, value) in headers.iter() {
+                response.add_header(
+                    Header::from_bytes(*field, *value)
+                        .expect("Failed to create CORS header, origin is not ascii."),
+                );
+            }
+        }
         match request.respond(response) {
             Ok(()) => {},
-            Err(err) => println!("Error while responding to request: {:?}", err),
+            Err(err) => error!("Error while responding to request: {:?}", err),
         }
     }
 }
 
-pub fn serve(bind: &str, service: Arc<MetaServer>) -> ! {
-    let server = match Server::http(bind) {
+/// Return the port of a listen address such as `0.0.0.0:8233`.
+fn listen_port(listen: &str) -> Option<u16> {
+    listen.rsplit(':').next().and_then(|port| u16::from_str(port).ok())
+}
+
+/// Start the server on `bind`, or on the socket that systemd passed, if any.
+fn start_server(bind: &str, listener: Option<&TcpListener>, ssl: Option<SslConfig>) -> Server {
+    let result = match (listener, ssl) {
+        // The server closes its listener when it stops, but we may need to
+        // start a new server on the same socket later, so hand it a copy.
+        (Some(listener), ssl) => match listener.try_clone() {
+            Ok(listener) => Server::from_listener(listener, ssl),
+            Err(err) => Err(err.into()),
+        },
+        (None, None) => Server::http(bind),
+        (None, Some(ssl_config)) => Server::https(bind, ssl_config),
+    };
+    match result {
         Ok(s) => s,
-        Err(..) => {
-            eprintln!("Failed to start server, could not bind to {}.", bind);
+        Err(err) => {
+            error!("Failed to start server on {}: {}", bind, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Accept connections from MPD clients, and serve each on its own thread.
+fn spawn_mpd_server(bind: &str, service: &Arc<MetaServer>) {
+    let listener = match TcpListener::bind(bind) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to listen for MPD clients on {}: {:?}", bind, err);
+            std::process::exit(1);
+        }
+    };
+    let service = service.clone();
+    let builder = thread::Builder::new().name("mpd_server".into());
+    builder.spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("Error while accepting MPD client: {:?}", err);
+                    continue;
+                }
+            };
+            let service_i = service.clone();
+            let events = service.event_bus.subscribe();
+            let builder = thread::Builder::new().name("mpd_client".into());
+            builder.spawn(move || {
+                let ctx = mpd::Context {
+                    config: &service_i.config,
+                    index_var: &service_i.index_var,
+                    player: &service_i.player,
+                };
+                // Errors here are mostly clients that went away, which is fine.
+                let _ = mpd::serve_client(&ctx, stream, events);
+            }).expect("Failed to spawn MPD client thread.");
+        }
+    }).expect("Failed to spawn MPD server thread.");
+}
+
+/// Announce the server to DLNA clients, and answer their searches.
+fn spawn_dlna_discovery(name: &str, service: &Arc<MetaServer>) {
+    let port = match listen_port(&service.config.listen) {
+        Some(port) => port,
+        None => {
+            error!("Cannot determine the port to announce over DLNA from {}.", service.config.listen);
+            std::process::exit(1);
+        }
+    };
+    let scheme = if service.config.tls_paths().is_some() { "https" } else { "http" };
+    let ssdp = match dlna::Ssdp::bind(dlna::device_uuid(name), port, scheme) {
+        Ok(ssdp) => ssdp,
+        Err(err) => {
+            error!("Failed to listen for DLNA discovery: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+    let builder = thread::Builder::new().name("dlna_ssdp".into());
+    builder.spawn(move || {
+        if let Err(err) = ssdp.serve() {
+            error!("DLNA discovery stopped: {:?}", err);
+        }
+    }).expect("Failed to spawn DLNA discovery thread.");
+}
+
+fn spawn_mpris(bus: dbus::Bus, service: &Arc<MetaServer>) {
+    let connection = match mpris::connect(bus) {
+        Ok(connection) => connection,
+        Err(err) => {
+            error!("Failed to connect to the D-Bus {:?} bus for MPRIS: {:?}", bus, err);
             std::process::exit(1);
         }
     };
+    let sender = connection.sender();
+    let art = Arc::new(mpris::ArtCache::default());
+
+    let service_calls = service.clone();
+    let art_calls = art.clone();
+    let builder = thread::Builder::new().name("mpris_calls".into());
+    builder.spawn(move || {
+        let ctx = mpris::Context {
+            index_var: &service_calls.index_var,
+            player: &service_calls.player,
+            art: &art_calls,
+        };
+        if let Err(err) = mpris::serve_calls(&ctx, connection) {
+            error!("Lost the D-Bus connection for MPRIS: {:?}", err);
+        }
+    }).expect("Failed to spawn MPRIS thread.");
+
+    let service = service.clone();
+    let builder = thread::Builder::new().name("mpris_events".into());
+    builder.spawn(move || {
+        let ctx = mpris::Context {
+            index_var: &service.index_var,
+            player: &service.player,
+            art: &art,
+        };
+        // The event bus drops subscribers that fall behind, resubscribe then.
+        loop {
+            let events = service.event_bus.subscribe();
+            if mpris::emit_changes(&ctx, &sender, events).is_err() {
+                // The calls thread reports the broken connection.
+                return;
+            }
+        }
+    }).expect("Failed to spawn MPRIS event thread.");
+}
+
+/// Ping the systemd watchdog, for as long as the player and the index respond.
+fn spawn_watchdog(timeout: Duration, service: &Arc<MetaServer>) {
+    let service = service.clone();
+    let builder = thread::Builder::new().name("watchdog".into());
+    builder.spawn(move || loop {
+        // Both of these take a lock that the playback, decode, and server
+        // threads take all the time. When one of those threads gets stuck
+        // while holding it, we block here and stop pinging, and then systemd
+        // restarts us.
+        let _ = service.player.get_now_playing();
+        let _ = service.index_var.get();
+        systemd::notify_watchdog();
+        thread::sleep(timeout / 2);
+    }).expect("Failed to spawn watchdog thread.");
+}
+
+/// How long we wait for the history thread, and for thumbnail generation, to
+/// finish when we shut down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wait for SIGTERM or SIGINT, then fade out, save the queue, and exit.
+fn spawn_shutdown_handler(service: &Arc<MetaServer>) {
+    let service = service.clone();
+    let builder = thread::Builder::new().name("shutdown".into());
+    builder.spawn(move || {
+        let signal = shutdown::wait_for_signal();
+        info!("Received {}, shutting down ...", signal);
+        systemd::notify_stopping_if_can_notify();
+
+        // A second signal means that whoever sent it does not want to wait.
+        let builder = thread::Builder::new().name("shutdown_force".into());
+        builder.spawn(|| {
+            let signal = shutdown::wait_for_signal();
+            warn!("Received {} again, exiting immediately.", signal);
+            std::process::exit(1);
+        }).expect("Failed to spawn shutdown thread.");
+
+        if !service.player.shut_down(SHUTDOWN_TIMEOUT) {
+            warn!("Failed to save the queue, it will be empty after a restart.");
+        }
+        if !thumb_gen::shut_down(SHUTDOWN_TIMEOUT) {
+            warn!("Thumbnail generation did not finish in time.");
+        }
 
-    let server = Arc::new(server);
+        info!("Shutdown complete.");
+        std::process::exit(0);
+    }).expect("Failed to spawn shutdown thread.");
+}
 
+fn spawn_handler_threads(server: &Arc<Server>, service: &Arc<MetaServer>) -> Vec<JoinHandle<()>> {
     // Browsers do not make more than 8 requests in parallel, so having more
     // handler threads is not useful; I expect only a single user to be
     // browsing at a time.
@@ -607,31 +4811,125 @@ pub fn serve(bind: &str, service: Arc<MetaServer>) -> ! {
         let name = format!("http_server_{}", i);
         let builder = thread::Builder::new().name(name);
         let join_handle = builder.spawn(move || {
-            let connection = database_utils::connect_readonly(&service_i.config.db_path)
+            // Most endpoints only read, but the playlist endpoints write, so
+            // we need a read-write connection.
+            let connection = database_utils::connect_read_write(&service_i.config.db_path)
                 .expect("Failed to connect to database.");
             let mut db = Connection::new(&connection);
             loop {
                 let request = match server_i.recv() {
                     Ok(rq) => rq,
                     Err(e) => {
-                        println!("Error: {:?}", e);
+                        error!("Error while receiving request: {:?}", e);
                         break;
                     }
                 };
+                let started_at = std::time::Instant::now();
                 service_i.handle_request(&mut db, request);
+                metrics::observe_http_request(started_at.elapsed());
             }
         }).unwrap();
         threads.push(join_handle);
     }
 
-    // When running under systemd, the service is ready when the server is
-    // accepting connections, which is now.
-    systemd::notify_ready_if_can_notify();
+    threads
+}
+
+/// Wait for SIGHUP, then reload the config.
+///
+/// With TLS, also load the certificate, and repeat until that succeeds.
+fn wait_for_reload(service: &MetaServer, tls_paths: Option<&(PathBuf, PathBuf)>) -> Option<SslConfig> {
+    loop {
+        reload::wait_for_sighup();
+        info!("Received SIGHUP, reloading configuration ...");
+        match service.reload_config() {
+            Ok(changed) => info!("Reloaded the config, changed: {:?}", changed),
+            Err(msg) => warn!("Failed to reload the config, keeping the current one: {}", msg),
+        }
+
+        let (cert, key) = tls_paths?;
+        info!("Reloading TLS certificate ...");
+        match tls::load_ssl_config(cert, key) {
+            Ok(ssl_config) => return Some(ssl_config),
+            Err(err) => warn!("Failed to load TLS certificate, keeping the current one: {:?}", err),
+        }
+    }
+}
+
+/// Serve requests, until we receive SIGTERM or SIGINT.
+///
+/// SIGTERM, SIGINT, and SIGHUP must be blocked before calling this, see
+/// [`shutdown::block_signals`] and [`reload::block_sighup`].
+pub fn serve(bind: &str, service: Arc<MetaServer>) -> ! {
+    let tls_paths = service
+        .config
+        .tls_paths()
+        .map(|(cert, key)| (cert.to_path_buf(), key.to_path_buf()));
+
+    let mut ssl = match &tls_paths {
+        None => None,
+        Some((cert, key)) => match tls::load_ssl_config(cert, key) {
+            Ok(ssl_config) => Some(ssl_config),
+            Err(err) => {
+                error!("Failed to load TLS certificate: {:?}", err);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    spawn_shutdown_handler(&service);
+
+    // With socket activation, systemd binds the socket, and passes it to us.
+    let listener = systemd::take_listen_socket();
+    if listener.is_some() {
+        info!("Accepting connections on the socket from systemd, instead of {}.", bind);
+    }
+
+    if let Some(timeout) = systemd::get_watchdog_timeout() {
+        spawn_watchdog(timeout, &service);
+    }
+
+    if let Some(mpd_bind) = service.config.mpd_listen.as_ref() {
+        spawn_mpd_server(mpd_bind, &service);
+    }
+
+    if let Some(bus) = service.config.mpris_bus {
+        spawn_mpris(bus, &service);
+    }
 
-    // Block until the server threads exit, which will not happen.
-    for handle in threads {
-        handle.join().unwrap();
+    if let Some(name) = service.config.dlna_name.as_ref() {
+        spawn_dlna_discovery(name, &service);
     }
 
-    unreachable!("The server runs indefinitely, joins should not return.")
+    loop {
+        let server = Arc::new(start_server(bind, listener.as_ref(), ssl));
+        let threads = spawn_handler_threads(&server, &service);
+
+        // When running under systemd, the service is ready when the server is
+        // accepting connections, which is now.
+        systemd::notify_ready_if_can_notify();
+
+        // Without TLS, a reload does not affect the server, it keeps running
+        // with the threads that we already have.
+        ssl = loop {
+            if let Some(ssl_config) = wait_for_reload(&service, tls_paths.as_ref()) {
+                break Some(ssl_config);
+            }
+        };
+
+        // The TLS configuration of a server is fixed, so to reload the
+        // certificate, we stop the server and start a new one. Requests that
+        // are in progress complete, new connections wait in the backlog.
+        for _ in 0..threads.len() {
+            server.unblock();
+        }
+        for handle in threads {
+            handle.join().unwrap();
+        }
+        // The threads are gone, so this drops the last reference to the
+        // server, which closes the listening socket, so we can bind again.
+        // With socket activation, it closes only its copy of the socket.
+        std::mem::drop(server);
+        info!("Reloaded TLS certificate.");
+    }
 }
@@ -0,0 +1,157 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Grouping listens into listening sessions.
+//!
+//! A session is a stretch of listening without long pauses. When a listen
+//! starts within `SESSION_GAP_SECONDS` after the previous listen of the same
+//! user ended, it continues that session, otherwise it starts a new one. We
+//! store the session id with the listen, so statistics and similarity do not
+//! have to reconstruct sessions every time.
+
+use crate::database as db;
+use crate::database::Transaction;
+use crate::listens::StatsParams;
+
+/// A pause longer than this between two listens starts a new session.
+pub const SESSION_GAP_SECONDS: i64 = 30 * 60;
+
+/// Return whether a listen that starts at the given time continues a session
+/// that ended at `prev_ended_at`.
+fn continues_session(prev_ended_at: i64, started_at: i64) -> bool {
+    started_at - prev_ended_at <= SESSION_GAP_SECONDS
+}
+
+/// Assign a session to every listen that does not have one yet.
+///
+/// Listens get their session when they start, but imported listens, and
+/// listens from before sessions existed, get it here. Returns the number of
+/// listens that were assigned a session.
+pub fn assign_sessions(tx: &mut Transaction) -> db::Result<usize> {
+    // We can't update the listens while we iterate them, so collect them
+    // first. There are only many of them right after an import.
+    let listens = db::iter_listens_without_session(tx)?.collect::<db::Result<Vec<_>>>()?;
+    if listens.is_empty() {
+        return Ok(0);
+    }
+
+    let mut next_session_id = db::select_max_listen_session_id(tx)? + 1;
+
+    for (listen_id, started_at, user) in listens.iter() {
+        let user = user.as_deref();
+        let session_id = match db::select_previous_listen_session(tx, *started_at, user)? {
+            Some((Some(session_id), prev_ended_at)) if continues_session(prev_ended_at, *started_at) => {
+                session_id
+            }
+            _ => {
+                let session_id = next_session_id;
+                next_session_id += 1;
+                session_id
+            }
+        };
+        db::update_listen_session(tx, *listen_id, session_id)?;
+    }
+
+    Ok(listens.len())
+}
+
+/// Statistics about the listening sessions in a time range.
+#[derive(Debug, PartialEq)]
+pub struct SessionStats {
+    /// The number of sessions.
+    pub sessions: i64,
+
+    /// The average time from the start of the first listen to the end of the
+    /// last listen in a session.
+    pub average_seconds: i64,
+
+    /// The average number of listens per session.
+    pub average_listens: f64,
+
+    /// The hour of the day at which most sessions start, in the local time of
+    /// the listen. `None` when there are no sessions.
+    pub typical_start_hour: Option<u32>,
+}
+
+/// Summarize sessions given as `(started_at, ended_at, listens, start_hour)`.
+fn summarize_sessions(sessions: &[(i64, i64, i64, i64)]) -> SessionStats {
+    let n = sessions.len() as i64;
+    let mut total_seconds = 0;
+    let mut total_listens = 0;
+    let mut hour_counts = [0_u32; 24];
+
+    for &(started_at, ended_at, listens, start_hour) in sessions {
+        total_seconds += ended_at - started_at;
+        total_listens += listens;
+        hour_counts[start_hour as usize % 24] += 1;
+    }
+
+    // On a tie, the earliest hour wins, so the result is deterministic.
+    let typical_start_hour = (0..24_u32)
+        .filter(|&h| hour_counts[h as usize] > 0)
+        .max_by_key(|&h| (hour_counts[h as usize], 24 - h));
+
+    SessionStats {
+        sessions: n,
+        average_seconds: if n > 0 { total_seconds / n } else { 0 },
+        average_listens: if n > 0 { total_listens as f64 / n as f64 } else { 0.0 },
+        typical_start_hour: typical_start_hour,
+    }
+}
+
+/// Return statistics about the sessions of the user in the requested range.
+///
+/// Only listens in the range count towards a session, so a session that
+/// crosses the start of the range is cut off there.
+pub fn get_session_stats(
+    tx: &mut Transaction,
+    params: &StatsParams,
+    user: Option<&str>,
+) -> db::Result<SessionStats> {
+    let (since, until) = params.range();
+    let client = params.client.as_deref();
+    let sessions = db::iter_listen_sessions(tx, since, until, client, user)?
+        .collect::<db::Result<Vec<_>>>()?;
+    Ok(summarize_sessions(&sessions))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{continues_session, summarize_sessions, SessionStats, SESSION_GAP_SECONDS};
+
+    #[test]
+    fn continues_session_measures_from_the_end_of_the_previous_listen() {
+        assert!(continues_session(1000, 1000));
+        assert!(continues_session(1000, 1000 + SESSION_GAP_SECONDS));
+        assert!(!continues_session(1000, 1001 + SESSION_GAP_SECONDS));
+    }
+
+    #[test]
+    fn summarize_sessions_averages_and_finds_typical_hour() {
+        let sessions = [
+            (0, 3600, 12, 20),
+            (10_000, 11_800, 6, 8),
+            (20_000, 20_600, 3, 20),
+            (30_000, 30_000, 1, 8),
+            (40_000, 40_400, 2, 21),
+        ];
+        let stats = summarize_sessions(&sessions);
+        assert_eq!(stats.sessions, 5);
+        assert_eq!(stats.average_seconds, (3600 + 1800 + 600 + 400) / 5);
+        assert_eq!(stats.average_listens, 24.0 / 5.0);
+        // Hours 8 and 20 both occur twice, the earliest one wins.
+        assert_eq!(stats.typical_start_hour, Some(8));
+
+        let expected = SessionStats {
+            sessions: 0,
+            average_seconds: 0,
+            average_listens: 0.0,
+            typical_start_hour: None,
+        };
+        assert_eq!(summarize_sessions(&[]), expected);
+    }
+}
@@ -16,7 +16,7 @@ use std::iter;
 use nanorand::Rng;
 
 use crate::player::QueuedTrack;
-use crate::prim::{AlbumId, ArtistId};
+use crate::prim::{AlbumId, ArtistId, VARIOUS_ARTISTS};
 use crate::{MemoryMetaIndex, MetaIndex};
 
 pub type Prng = nanorand::WyRand;
@@ -29,7 +29,7 @@ pub trait Shuffle {
     type Track;
 
     fn get_album_id(&self, track: &Self::Track) -> AlbumId;
-    fn get_artist_id(&self, album_id: AlbumId) -> ArtistId;
+    fn get_artist_id(&self, track: &Self::Track) -> ArtistId;
 }
 
 /// Shuffle implementation that is actually used in the server.
@@ -37,10 +37,14 @@ impl Shuffle for MemoryMetaIndex {
     type Track = QueuedTrack;
 
     fn get_album_id(&self, track: &QueuedTrack) -> AlbumId {
-        track.track_id.album_id()
+        track
+            .source
+            .track_id()
+            .expect("Radio stations are not shuffled.")
+            .album_id()
     }
 
-    fn get_artist_id(&self, album_id: AlbumId) -> ArtistId {
+    fn get_artist_id(&self, track: &QueuedTrack) -> ArtistId {
         // For "artist", we take the first artist of the album artists. Two
         // alternatives come to mind: counting every collaboration as a unique
         // artist (more smaller groups), or counting every connected component
@@ -51,11 +55,22 @@ impl Shuffle for MemoryMetaIndex {
         // risk having too few of them to properly interleave. So one artist per
         // album is probably okay, but also, it’s just the easiest thing to
         // implement.
+        let track_id = track.source.track_id().expect("Radio stations are not shuffled.");
         let album = self
-            .get_album(album_id)
+            .get_album(track_id.album_id())
             .expect("Queued tracks should exist on album.");
         let artist_ids = self.get_album_artists(album.artist_ids);
-        artist_ids[0]
+        if artist_ids[0] != VARIOUS_ARTISTS {
+            return artist_ids[0];
+        }
+
+        // Lumping all tracks of compilations together under "Various Artists"
+        // would make them one big artist, so for those we take the track
+        // artist instead. Track artists have no id, but equal names share a
+        // string ref, so we derive the id from that. Real artist ids are
+        // random 64-bit numbers, so they are unlikely to collide with these.
+        let track = self.get_track(track_id).expect("Queued tracks should exist.");
+        ArtistId(u64::MAX - track.artist.0 as u64)
     }
 }
 
@@ -64,7 +79,8 @@ impl Shuffle for MemoryMetaIndex {
 /// In the tests we use a triple of bytes as the track type:
 ///
 /// * Index 0 identifies the artist.
-/// * Index 1 identifies the album within the artist.
+/// * Index 1 identifies the album. When tracks of different artists share the
+///   album, it is a compilation.
 /// * Index 2 identifies the track on the album.
 ///
 /// This makes it easy to construct such ids as literals without having to build
@@ -75,11 +91,11 @@ impl Shuffle for TestShuffler {
     type Track = [u8; 3];
 
     fn get_album_id(&self, track: &[u8; 3]) -> AlbumId {
-        AlbumId(((track[0] as u64) << 8) | (track[1] as u64))
+        AlbumId(track[1] as u64)
     }
 
-    fn get_artist_id(&self, album_id: AlbumId) -> ArtistId {
-        ArtistId(album_id.0 >> 8)
+    fn get_artist_id(&self, track: &[u8; 3]) -> ArtistId {
+        ArtistId(track[0] as u64)
     }
 }
 
@@ -129,15 +145,18 @@ fn apply_permutation<T>(permutation: &[TrackRef], tracks: &mut [T]) {
 pub fn shuffle<Meta: Shuffle>(meta: &Meta, rng: &mut Prng, tracks: &mut [Meta::Track]) {
     // First we partition all tracks into albums. Rather than moving around the
     // full QueuedTrack all the time, we store indices into the tracks slice.
-    let mut albums = HashMap::<AlbumId, Vec<TrackRef>>::new();
+    // For compilations, the tracks of one album have different artists, and we
+    // partition them per artist, so the album is part of each artist.
+    let mut albums = HashMap::<(ArtistId, AlbumId), Vec<TrackRef>>::new();
     for (i, track) in tracks.iter().enumerate() {
         let album_id = meta.get_album_id(track);
+        let artist_id = meta.get_artist_id(track);
         let track_ref = TrackRef {
             orig_index: i as u32,
             // We fill the partition afterwards.
             partition: 0,
         };
-        albums.entry(album_id).or_default().push(track_ref);
+        albums.entry((artist_id, album_id)).or_default().push(track_ref);
     }
 
     // Then we shuffle the tracks in every album using a regular shuffle.
@@ -150,8 +169,7 @@ pub fn shuffle<Meta: Shuffle>(meta: &Meta, rng: &mut Prng, tracks: &mut [Meta::T
 
     // Then we group everything back on artist.
     let mut artists = HashMap::<ArtistId, Vec<Vec<TrackRef>>>::new();
-    for (album_id, album_tracks) in albums {
-        let artist_id = meta.get_artist_id(album_id);
+    for ((artist_id, _album_id), album_tracks) in albums {
         artists.entry(artist_id).or_default().push(album_tracks);
     }
 
@@ -424,6 +442,16 @@ mod test {
         ]);
     }
 
+    #[test]
+    fn shuffle_groups_compilation_tracks_by_track_artist() {
+        // Album 0 is a compilation with a track of A and of B. The track of A
+        // counts as A, so it should not end up next to the other album of A.
+        test_shuffle(&[
+            &[*b"A00", *b"B00", *b"A11"],
+            &[*b"A11", *b"B00", *b"A00"],
+        ]);
+    }
+
     /// Testcases found through fuzzing.
     #[test]
     fn shuffle_fuzz_cases() {
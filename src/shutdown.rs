@@ -0,0 +1,51 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Waiting for SIGTERM and SIGINT, to shut down gracefully.
+//!
+//! By default, these signals terminate the process on the spot, which cuts
+//! off the audio, and loses listens that the history thread did not record
+//! yet. Instead, a thread waits for them with `sigwait`, like for SIGHUP in
+//! `tls.rs`, so the signals must be blocked before any threads are spawned.
+
+use std::mem;
+use std::ptr;
+
+fn shutdown_set() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        set
+    }
+}
+
+/// Block SIGTERM and SIGINT for this thread, and for threads spawned from it afterwards.
+///
+/// Call this before spawning any threads, otherwise the signal may be
+/// delivered to a thread that does not block it, and terminate us.
+pub fn block_signals() {
+    let set = shutdown_set();
+    let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    assert_eq!(result, 0, "Failed to block SIGTERM and SIGINT.");
+}
+
+/// Block until the process receives SIGTERM or SIGINT, return its name.
+pub fn wait_for_signal() -> &'static str {
+    let set = shutdown_set();
+    let mut signal: libc::c_int = 0;
+    loop {
+        let result = unsafe { libc::sigwait(&set, &mut signal) };
+        match signal {
+            _ if result != 0 => continue,
+            libc::SIGTERM => return "SIGTERM",
+            libc::SIGINT => return "SIGINT",
+            _ => continue,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Finding similar artists and tracks from the listening history.
+//!
+//! Two artists or tracks are similar when we tend to play them in the same
+//! session, see sessions.rs for how we group listens into sessions. We count
+//! how many sessions the two have in common. To keep popular
+//! items from being similar to everything, the score is the number of common
+//! sessions divided by the geometric mean of the number of sessions of both,
+//! the cosine similarity. This needs no external service, only the listens, and
+//! it is cheap enough to compute on every request.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::database as db;
+use crate::database::Transaction;
+use crate::prim::{ArtistId, TrackId};
+
+/// Items need to share at least this many sessions to count as similar.
+///
+/// A single session in common is too much down to chance.
+const MIN_COMMON_SESSIONS: u32 = 2;
+
+/// Group listens, ordered by session id, into sessions of distinct items.
+fn group_sessions<T: Copy + Ord>(listens: &[(T, i64)]) -> Vec<Vec<T>> {
+    let mut sessions = Vec::new();
+    let mut session: Vec<T> = Vec::new();
+    let mut prev_session_id = None;
+
+    for &(item, session_id) in listens {
+        if prev_session_id.is_some() && prev_session_id != Some(session_id) {
+            sessions.push(session);
+            session = Vec::new();
+        }
+        session.push(item);
+        prev_session_id = Some(session_id);
+    }
+    sessions.push(session);
+
+    for session in sessions.iter_mut() {
+        session.sort();
+        session.dedup();
+    }
+    sessions.retain(|session| !session.is_empty());
+    sessions
+}
+
+/// Return the items similar to `target`, with their score, most similar first.
+///
+/// The sessions must be sorted, as `group_sessions` returns them. Items for
+/// which `exclude` returns true are not considered.
+fn rank_similar<T: Copy + Eq + Hash + Ord>(
+    sessions: &[Vec<T>],
+    target: T,
+    exclude: impl Fn(T) -> bool,
+) -> Vec<(T, f64)> {
+    let mut session_counts: HashMap<T, u32> = HashMap::new();
+    let mut common_counts: HashMap<T, u32> = HashMap::new();
+
+    for session in sessions {
+        let has_target = session.binary_search(&target).is_ok();
+        for &item in session {
+            *session_counts.entry(item).or_insert(0) += 1;
+            if has_target && item != target {
+                *common_counts.entry(item).or_insert(0) += 1;
+            }
+        }
+    }
+
+    let target_count = match session_counts.get(&target) {
+        Some(&n) => n as f64,
+        None => return Vec::new(),
+    };
+
+    let mut result: Vec<(T, f64)> = common_counts
+        .into_iter()
+        .filter(|&(item, common)| common >= MIN_COMMON_SESSIONS && !exclude(item))
+        .map(|(item, common)| {
+            let score = common as f64 / (target_count * session_counts[&item] as f64).sqrt();
+            (item, score)
+        })
+        .collect();
+
+    // Break ties by id, so the order is deterministic.
+    result.sort_by(|(id_a, score_a), (id_b, score_b)| {
+        score_b.partial_cmp(score_a).unwrap_or(Ordering::Equal).then(id_a.cmp(id_b))
+    });
+    result
+}
+
+/// Return artists that the user plays in the same sessions as the given artist.
+///
+/// Artists are album artists, the first one if an album has multiple. Returns
+/// the artists most similar first.
+pub fn get_similar_artists(
+    tx: &mut Transaction,
+    artist_id: ArtistId,
+    user: Option<&str>,
+) -> db::Result<Vec<ArtistId>> {
+    let mut listens = Vec::new();
+    for row in db::iter_listens_for_similarity(tx, user)? {
+        let (_track_id, album_artist_id, session_id) = row?;
+        listens.push((ArtistId(album_artist_id as u64), session_id));
+    }
+    let sessions = group_sessions(&listens);
+    let similar = rank_similar(&sessions, artist_id, |_| false);
+    Ok(similar.into_iter().map(|(id, _score)| id).collect())
+}
+
+/// Return tracks that the user plays in the same sessions as the given track.
+///
+/// When we play an album, all of its tracks share a session, so tracks from
+/// the album of the given track are left out. Returns the tracks most similar
+/// first.
+pub fn get_similar_tracks(
+    tx: &mut Transaction,
+    track_id: TrackId,
+    user: Option<&str>,
+) -> db::Result<Vec<TrackId>> {
+    let mut listens = Vec::new();
+    for row in db::iter_listens_for_similarity(tx, user)? {
+        let (listen_track_id, _album_artist_id, session_id) = row?;
+        listens.push((TrackId(listen_track_id as u64), session_id));
+    }
+    let sessions = group_sessions(&listens);
+    let album_id = track_id.album_id();
+    let similar = rank_similar(&sessions, track_id, |t| t.album_id() == album_id);
+    Ok(similar.into_iter().map(|(id, _score)| id).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{group_sessions, rank_similar};
+
+    #[test]
+    fn group_sessions_groups_by_session_id() {
+        let listens = [(3, -7), (1, 4), (3, 4), (1, 4), (2, 5), (1, 5)];
+        assert_eq!(group_sessions(&listens), vec![vec![3], vec![1, 3], vec![1, 2]]);
+        assert_eq!(group_sessions::<u32>(&[]), Vec::<Vec<u32>>::new());
+    }
+
+    #[test]
+    fn rank_similar_prefers_items_that_share_more_sessions() {
+        let sessions = vec![
+            vec![1, 2, 3],
+            vec![1, 2],
+            vec![1, 2, 4],
+            vec![1, 3, 4],
+            vec![3, 4],
+            vec![3, 4],
+        ];
+        let similar: Vec<u32> = rank_similar(&sessions, 1, |_| false)
+            .into_iter()
+            .map(|(item, _score)| item)
+            .collect();
+        // Item 2 only ever plays with 1. Items 3 and 4 share two sessions with
+        // 1, but they also play without it, so they score lower.
+        assert_eq!(similar, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn rank_similar_ignores_chance_and_excluded_items() {
+        let sessions = vec![vec![1, 2, 3], vec![1, 2], vec![1, 3]];
+        let similar = rank_similar(&sessions, 1, |item| item == 2);
+        assert_eq!(similar.len(), 1);
+        assert_eq!(similar[0].0, 3);
+
+        let sessions = vec![vec![1, 2]];
+        assert!(rank_similar(&sessions, 1, |_| false).is_empty());
+        assert!(rank_similar(&sessions, 5, |_| false).is_empty());
+    }
+}
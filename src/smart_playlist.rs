@@ -0,0 +1,600 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Smart playlists, which select their tracks with a query.
+//!
+//! A query is a list of conditions joined by `and`, optionally followed by an
+//! ordering and a limit, for example:
+//!
+//! ```text
+//! rating >= 1 and last played > 90 days ago, order by least played, limit 100
+//! ```
+//!
+//! Queries are stored as text, and evaluated on demand against the index and
+//! the user data, so the tracks change as the library and listens change. See
+//! docs/playlists.md for the full syntax.
+
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use nanorand::Rng;
+
+use crate::prim::{Instant, Track, TrackId};
+use crate::shuffle::Prng;
+use crate::user_data::UserData;
+use crate::MetaIndex;
+
+/// A property of a track that a condition or ordering can refer to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    Year,
+    /// Duration in seconds.
+    Duration,
+    Rating,
+    Plays,
+    LastPlayed,
+    /// When the album was added to the library, its `first_seen` time.
+    Added,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum FieldKind {
+    Text,
+    Integer,
+    /// A point in time, compared by age in days.
+    Time,
+}
+
+impl Field {
+    fn kind(self) -> FieldKind {
+        match self {
+            Field::Title | Field::Artist | Field::Album => FieldKind::Text,
+            Field::Year | Field::Duration | Field::Rating | Field::Plays => FieldKind::Integer,
+            Field::LastPlayed | Field::Added => FieldKind::Time,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+impl Op {
+    fn test(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Eq => ordering == Ordering::Equal,
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+            Op::Contains => unreachable!("Contains is only valid for text, checked at parse time."),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Value {
+    /// A lowercased string.
+    Text(String),
+    Integer(i64),
+    /// An age, e.g. "90 days ago".
+    DaysAgo(i64),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Condition {
+    pub field: Field,
+    pub op: Op,
+    pub value: Value,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Order {
+    /// Ascending by the field. For times, ascending means earliest first.
+    Ascending(Field),
+    Descending(Field),
+    Random,
+}
+
+/// A parsed smart playlist query.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Query {
+    pub conditions: Vec<Condition>,
+    pub order: Option<Order>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    /// A bare word, lowercased.
+    Word(String),
+    Number(i64),
+    /// A double-quoted string, with the quotes removed.
+    Quoted(String),
+    Op(Op),
+    Comma,
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+
+    while let Some(&ch) = chars.peek() {
+        match ch {
+            _ if ch.is_whitespace() => {
+                chars.next();
+            }
+            ',' => {
+                chars.next();
+                tokens.push(Token::Comma);
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => s.push(c),
+                        None => return Err("Unterminated string in query."),
+                    }
+                }
+                tokens.push(Token::Quoted(s));
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let has_eq = chars.peek() == Some(&'=');
+                if has_eq { chars.next(); }
+                let op = match (ch, has_eq) {
+                    ('=', _) => Op::Eq,
+                    ('!', true) => Op::Ne,
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    ('>', true) => Op::Ge,
+                    _ => return Err("Invalid operator in query, expected '!='."),
+                };
+                tokens.push(Token::Op(op));
+            }
+            _ if ch == '-' || ch.is_ascii_digit() => {
+                let mut s = String::new();
+                s.push(ch);
+                chars.next();
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_digit() { break }
+                    s.push(c);
+                    chars.next();
+                }
+                match i64::from_str(&s) {
+                    Ok(n) => tokens.push(Token::Number(n)),
+                    Err(_) => return Err("Invalid number in query."),
+                }
+            }
+            _ if ch.is_alphanumeric() || ch == '_' => {
+                let mut s = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_') { break }
+                    s.extend(c.to_lowercase());
+                    chars.next();
+                }
+                tokens.push(Token::Word(s));
+            }
+            _ => return Err("Unexpected character in query."),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_word(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Word(w)) => Some(&w[..]),
+            _ => None,
+        }
+    }
+
+    /// Consume the word if it is next, return whether it was.
+    fn eat_word(&mut self, word: &str) -> bool {
+        if self.peek_word() == Some(word) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_comma(&mut self) {
+        if self.peek() == Some(&Token::Comma) {
+            self.pos += 1;
+        }
+    }
+
+    /// Whether the next token starts a clause after the conditions.
+    fn at_clause_end(&self) -> bool {
+        match self.peek() {
+            None | Some(Token::Comma) => true,
+            Some(Token::Word(w)) => w == "and" || w == "order" || w == "limit",
+            _ => false,
+        }
+    }
+
+    fn parse_field(&mut self) -> Result<Field, &'static str> {
+        let field = match self.peek_word() {
+            Some("title") => Field::Title,
+            Some("artist") => Field::Artist,
+            Some("album") => Field::Album,
+            Some("year") => Field::Year,
+            Some("duration") => Field::Duration,
+            Some("rating") => Field::Rating,
+            Some("plays") => Field::Plays,
+            Some("added") => Field::Added,
+            Some("last_played") => Field::LastPlayed,
+            Some("play_count") => Field::Plays,
+            Some("last") => {
+                self.pos += 1;
+                if self.peek_word() != Some("played") {
+                    return Err("Expected 'played' after 'last'.");
+                }
+                Field::LastPlayed
+            }
+            Some("play") => {
+                self.pos += 1;
+                if self.peek_word() != Some("count") {
+                    return Err("Expected 'count' after 'play'.");
+                }
+                Field::Plays
+            }
+            Some(_) => return Err("Unknown field in query."),
+            None => return Err("Expected a field in query."),
+        };
+        self.pos += 1;
+        Ok(field)
+    }
+
+    fn parse_op(&mut self) -> Result<Op, &'static str> {
+        let op = match self.peek() {
+            Some(Token::Op(op)) => *op,
+            Some(Token::Word(w)) if w == "is" => {
+                self.pos += 1;
+                return if self.eat_word("not") { Ok(Op::Ne) } else { Ok(Op::Eq) };
+            }
+            Some(Token::Word(w)) if w == "contains" => Op::Contains,
+            _ => return Err("Expected an operator in query."),
+        };
+        self.pos += 1;
+        Ok(op)
+    }
+
+    fn parse_value(&mut self, kind: FieldKind) -> Result<Value, &'static str> {
+        match kind {
+            FieldKind::Text => {
+                if let Some(Token::Quoted(s)) = self.peek() {
+                    let value = Value::Text(s.to_lowercase());
+                    self.pos += 1;
+                    return Ok(value);
+                }
+                // Without quotes, the value extends up to the next clause.
+                let mut words = Vec::new();
+                while !self.at_clause_end() {
+                    match &self.tokens[self.pos] {
+                        Token::Word(w) => words.push(w.clone()),
+                        Token::Number(n) => words.push(n.to_string()),
+                        _ => return Err("Unexpected token in text value, use quotes."),
+                    }
+                    self.pos += 1;
+                }
+                if words.is_empty() {
+                    return Err("Expected a text value in query.");
+                }
+                Ok(Value::Text(words.join(" ")))
+            }
+            FieldKind::Integer => match self.peek() {
+                Some(&Token::Number(n)) => {
+                    self.pos += 1;
+                    Ok(Value::Integer(n))
+                }
+                _ => Err("Expected a number in query."),
+            },
+            FieldKind::Time => {
+                let n = match self.peek() {
+                    Some(&Token::Number(n)) => n,
+                    _ => return Err("Expected a time like '90 days ago' in query."),
+                };
+                self.pos += 1;
+                let days_per_unit = match self.peek_word() {
+                    Some("day") | Some("days") => 1,
+                    Some("week") | Some("weeks") => 7,
+                    Some("month") | Some("months") => 30,
+                    Some("year") | Some("years") => 365,
+                    _ => return Err("Expected days, weeks, months, or years in query."),
+                };
+                self.pos += 1;
+                if !self.eat_word("ago") {
+                    return Err("Expected 'ago' after a time in query.");
+                }
+                Ok(Value::DaysAgo(n * days_per_unit))
+            }
+        }
+    }
+
+    fn parse_condition(&mut self) -> Result<Condition, &'static str> {
+        let field = self.parse_field()?;
+        let op = self.parse_op()?;
+        match (field.kind(), op) {
+            (FieldKind::Text, Op::Eq | Op::Ne | Op::Contains) => {}
+            (FieldKind::Text, _) => return Err("Text fields support only 'is', 'is not', and 'contains'."),
+            (_, Op::Contains) => return Err("Only text fields support 'contains'."),
+            _ => {}
+        }
+        let value = self.parse_value(field.kind())?;
+        let result = Condition { field, op, value };
+        Ok(result)
+    }
+
+    fn parse_order(&mut self) -> Result<Order, &'static str> {
+        if self.eat_word("random") {
+            return Ok(Order::Random);
+        }
+        if self.eat_word("least") {
+            return if self.eat_word("played") {
+                Ok(Order::Ascending(Field::Plays))
+            } else {
+                Err("Expected 'played' after 'least'.")
+            };
+        }
+        if self.eat_word("most") {
+            return if self.eat_word("played") {
+                Ok(Order::Descending(Field::Plays))
+            } else {
+                Err("Expected 'played' after 'most'.")
+            };
+        }
+        let field = self.parse_field()?;
+        if self.eat_word("desc") {
+            Ok(Order::Descending(field))
+        } else {
+            self.eat_word("asc");
+            Ok(Order::Ascending(field))
+        }
+    }
+
+    fn parse_query(&mut self) -> Result<Query, &'static str> {
+        let mut query = Query {
+            conditions: Vec::new(),
+            order: None,
+            limit: None,
+        };
+
+        let starts_with_clause = match self.peek_word() {
+            Some("order") | Some("limit") => true,
+            _ => self.peek().is_none(),
+        };
+        if !starts_with_clause {
+            query.conditions.push(self.parse_condition()?);
+            while self.eat_word("and") {
+                query.conditions.push(self.parse_condition()?);
+            }
+        }
+
+        self.eat_comma();
+        if self.eat_word("order") {
+            if !self.eat_word("by") {
+                return Err("Expected 'by' after 'order'.");
+            }
+            query.order = Some(self.parse_order()?);
+        }
+
+        self.eat_comma();
+        if self.eat_word("limit") {
+            match self.peek() {
+                Some(&Token::Number(n)) if n >= 0 => query.limit = Some(n as usize),
+                _ => return Err("Expected a non-negative number after 'limit'."),
+            }
+            self.pos += 1;
+        }
+
+        if self.peek().is_some() {
+            return Err("Unexpected trailing input in query.");
+        }
+
+        Ok(query)
+    }
+}
+
+impl FromStr for Query {
+    type Err = &'static str;
+
+    fn from_str(src: &str) -> Result<Query, &'static str> {
+        let mut parser = Parser {
+            tokens: tokenize(src)?,
+            pos: 0,
+        };
+        parser.parse_query()
+    }
+}
+
+/// The value of a field for a particular track, used for comparing and sorting.
+#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum FieldValue {
+    Text(String),
+    Integer(i64),
+    /// `None` for tracks that were never played.
+    Time(Option<Instant>),
+}
+
+fn get_field(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    field: Field,
+    track_id: TrackId,
+    track: &Track,
+) -> FieldValue {
+    let album = index.get_album(track_id.album_id()).unwrap();
+    match field {
+        Field::Title => FieldValue::Text(index.get_string(track.title).to_lowercase()),
+        Field::Artist => FieldValue::Text(index.get_string(track.artist).to_lowercase()),
+        Field::Album => FieldValue::Text(index.get_string(album.title).to_lowercase()),
+        Field::Year => FieldValue::Integer(album.original_release_date.year as i64),
+        Field::Duration => FieldValue::Integer(track.duration_seconds as i64),
+        Field::Rating => FieldValue::Integer(user_data.get_track_rating(track_id) as i64),
+        Field::Plays => FieldValue::Integer(user_data.get_track_play_count(track_id) as i64),
+        Field::LastPlayed => FieldValue::Time(user_data.get_track_last_played(track_id)),
+        Field::Added => FieldValue::Time(Some(album.first_seen)),
+    }
+}
+
+impl Condition {
+    fn matches(&self, field_value: FieldValue, now: Instant) -> bool {
+        match (field_value, &self.value) {
+            (FieldValue::Text(x), Value::Text(v)) => match self.op {
+                Op::Contains => x.contains(&v[..]),
+                op => op.test(x[..].cmp(&v[..])),
+            },
+            (FieldValue::Integer(x), &Value::Integer(v)) => self.op.test(x.cmp(&v)),
+            (FieldValue::Time(t), &Value::DaysAgo(v)) => {
+                // We compare ages, so "last played > 90 days ago" selects
+                // tracks not played in the past 90 days. A track that was
+                // never played is infinitely old.
+                let age_days = match t {
+                    Some(t) => (now.posix_seconds_utc - t.posix_seconds_utc) / (24 * 3600),
+                    None => i64::MAX,
+                };
+                self.op.test(age_days.cmp(&v))
+            }
+            _ => unreachable!("Value type matches field kind, checked at parse time."),
+        }
+    }
+}
+
+impl Query {
+    /// Return the tracks that the query selects, in query order.
+    ///
+    /// Without an ordering, tracks are ordered by id.
+    pub fn evaluate(
+        &self,
+        index: &dyn MetaIndex,
+        user_data: &UserData,
+        now: Instant,
+        rng: &mut Prng,
+    ) -> Vec<TrackId> {
+        let mut result: Vec<TrackId> = index
+            .get_tracks()
+            .iter()
+            .filter(|kv| self.conditions.iter().all(|c| {
+                let value = get_field(index, user_data, c.field, kv.track_id, &kv.track);
+                c.matches(value, now)
+            }))
+            .map(|kv| kv.track_id)
+            .collect();
+
+        let key = |field: Field, track_id: TrackId| {
+            let track = index.get_track(track_id).unwrap();
+            get_field(index, user_data, field, track_id, track)
+        };
+
+        // Sorting is stable, so ties remain ordered by id.
+        match self.order {
+            None => {}
+            Some(Order::Ascending(field)) => {
+                result.sort_by_cached_key(|&t| key(field, t));
+            }
+            Some(Order::Descending(field)) => {
+                result.sort_by_cached_key(|&t| std::cmp::Reverse(key(field, t)));
+            }
+            Some(Order::Random) => {
+                for i in 0..result.len() {
+                    let j = rng.generate_range(i..result.len());
+                    result.swap(i, j);
+                }
+            }
+        }
+
+        if let Some(n) = self.limit {
+            result.truncate(n);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Condition, Field, Op, Order, Query, Value};
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_accepts_full_query() {
+        let query = Query::from_str(
+            "rating >= 1 AND last played > 90 days ago, order by least played, limit 100"
+        ).unwrap();
+        assert_eq!(
+            query,
+            Query {
+                conditions: vec![
+                    Condition { field: Field::Rating, op: Op::Ge, value: Value::Integer(1) },
+                    Condition { field: Field::LastPlayed, op: Op::Gt, value: Value::DaysAgo(90) },
+                ],
+                order: Some(Order::Ascending(Field::Plays)),
+                limit: Some(100),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_accepts_text_values() {
+        let query = Query::from_str(r#"artist is Daft Punk and title contains "One More""#).unwrap();
+        assert_eq!(
+            query.conditions,
+            vec![
+                Condition { field: Field::Artist, op: Op::Eq, value: Value::Text("daft punk".into()) },
+                Condition { field: Field::Title, op: Op::Contains, value: Value::Text("one more".into()) },
+            ]
+        );
+        let query = Query::from_str("artist is not Muse").unwrap();
+        assert_eq!(query.conditions[0].op, Op::Ne);
+    }
+
+    #[test]
+    fn parse_accepts_only_order_and_limit() {
+        let query = Query::from_str("order by year desc limit 5").unwrap();
+        assert_eq!(query.conditions, vec![]);
+        assert_eq!(query.order, Some(Order::Descending(Field::Year)));
+        assert_eq!(query.limit, Some(5));
+        assert_eq!(Query::from_str("").unwrap().order, None);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_queries() {
+        assert!(Query::from_str("genre is ambient").is_err());
+        assert!(Query::from_str("rating contains 1").is_err());
+        assert!(Query::from_str("title > foo").is_err());
+        assert!(Query::from_str("added > 3 days").is_err());
+        assert!(Query::from_str("year = 1999 limit").is_err());
+        assert!(Query::from_str("year = 1999 trailing").is_err());
+        assert!(Query::from_str(r#"title is "unterminated"#).is_err());
+    }
+}
@@ -0,0 +1,390 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Playing back through a Snapcast server, for multi-room audio.
+//!
+//! Snapcast clients in every room play the same stream in sync. The Snapcast
+//! server reads that stream as raw pcm from a fifo, in one fixed sample format
+//! per stream. Tracks come in different sample rates and bit depths, so the
+//! playback thread converts every block to the format of the stream, and it
+//! applies the playback volume in software, because there is no mixer. The
+//! fifo provides the backpressure that the audio card would otherwise provide.
+//!
+//! Every room is a Snapcast client, which we call a zone. Zones have their own
+//! volume on top of the stream volume, we control it over the json-rpc api of
+//! the Snapcast server.
+
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread::Thread;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::player::{Format, PlayerState};
+use crate::prim::Hertz;
+use crate::resample::Resampler;
+
+/// The maximum number of frames to convert while holding the state lock.
+const CHUNK_FRAMES: usize = 2048;
+
+/// Convert a sample from a block to a float in the range [-1.0, 1.0).
+fn read_sample(bytes: &[u8]) -> f32 {
+    match bytes.len() {
+        2 => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32_768.0,
+        // Put the 24 bits in the high bytes of an i32 to get the sign right.
+        3 => i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as f32 / 2_147_483_648.0,
+        n => panic!("Unsupported: {} bits per sample. Please re-index.", n * 8),
+    }
+}
+
+/// Converts blocks in any format to 16-bit samples at the stream sample rate.
+struct Converter {
+    target_rate: Hertz,
+
+    /// The format of the input that the resampler belongs to.
+    format: Format,
+
+    resampler: Resampler,
+}
+
+impl Converter {
+    fn new(target_rate: Hertz) -> Converter {
+        let format = Format::default();
+        Converter {
+            target_rate: target_rate,
+            format: format,
+            resampler: Resampler::new(format.sample_rate, target_rate),
+        }
+    }
+
+    /// Prepare for input in the given format, this drops any buffered input.
+    fn set_format(&mut self, format: Format) {
+        self.format = format;
+        self.resampler = Resampler::new(format.sample_rate, self.target_rate);
+    }
+
+    /// Convert interleaved stereo samples, append 16-bit samples to `out`.
+    fn convert(&mut self, format: Format, src: &[u8], gain: f32, out: &mut Vec<u8>) {
+        if format != self.format {
+            self.set_format(format);
+        }
+
+        let bytes_per_sample = format.bits_per_sample as usize / 8;
+        let frames = src
+            .chunks_exact(2 * bytes_per_sample)
+            .map(|f| [read_sample(&f[..bytes_per_sample]), read_sample(&f[bytes_per_sample..])]);
+
+        self.resampler.process(frames, |frame| {
+            for x in frame.iter() {
+                let sample = (x * gain * 32_768.0).round().clamp(-32_768.0, 32_767.0) as i16;
+                out.extend_from_slice(&sample.to_le_bytes());
+            }
+        });
+    }
+}
+
+/// Open the fifo that the Snapcast server reads from.
+///
+/// Opening a fifo for writing blocks until there is a reader. We don't want
+/// to wait for a Snapcast server that isn't running, so we open it without
+/// blocking, which fails immediately in that case, and then make writes
+/// blocking again, so the server can pace us.
+fn open_fifo(path: &Path) -> io::Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    unsafe {
+        let fd = file.as_raw_fd();
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags == -1 || libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+/// Play back what is in the queue to the Snapcast fifo.
+///
+/// Like the Alsa counterpart, this returns when the queue becomes empty, or
+/// when we start casting. It also returns when the Snapcast server goes away.
+pub fn play_queue(
+    fifo_path: &Path,
+    sample_rate: Hertz,
+    state_mutex: &Mutex<PlayerState>,
+    decode_thread: &Thread,
+) {
+    let mut fifo = match open_fifo(fifo_path) {
+        Ok(f) => f,
+        Err(err) => {
+            error!(
+                "Failed to open Snapcast fifo {}, is the Snapcast server running? {}",
+                fifo_path.to_string_lossy(), err,
+            );
+            return;
+        }
+    };
+
+    let mut converter = Converter::new(sample_rate);
+    let mut out = Vec::new();
+
+    loop {
+        let (n_consumed, needs_decode, is_queue_empty) = {
+            let mut state = state_mutex.lock().unwrap();
+
+            if state.is_casting() || state.is_faded_out() {
+                return;
+            }
+
+            // Snapserver buffers the audio itself, we don't know how much of
+            // it played, so after a pause we continue from what we wrote.
+            if state.is_paused() {
+                state.hold_for_pause(0);
+                decode_thread.unpark();
+                return;
+            }
+
+            // Without a mixer, we apply the volume ourselves. We can't go
+            // beyond full scale.
+            let gain = match state.target_volume_full_scale() {
+                Some(v) => 10.0_f32.powf(v.0 as f32 / 2000.0).min(1.0),
+                None => 1.0,
+            };
+
+            let n_consumed = match state.peek_mut() {
+                Some(block) => {
+                    let format = block.format();
+                    let bytes_per_sample = format.bits_per_sample as usize / 8;
+                    let n = block.len().min(2 * CHUNK_FRAMES);
+                    converter.convert(format, &block.slice()[..n * bytes_per_sample], gain, &mut out);
+                    n
+                }
+                None => 0,
+            };
+            if n_consumed > 0 {
+                state.consume(n_consumed);
+            }
+
+            (n_consumed, state.needs_decode(), state.is_queue_empty())
+        };
+
+        if needs_decode {
+            decode_thread.unpark();
+        }
+
+        if n_consumed == 0 {
+            if is_queue_empty {
+                return;
+            }
+            // The decoder is behind, the Snapcast server plays silence in the
+            // meantime. Give the decoder a chance to catch up.
+            thread::sleep(Duration::from_millis(15));
+            continue;
+        }
+
+        // This blocks when the fifo is full, until the server reads more.
+        if let Err(err) = fifo.write_all(&out) {
+            warn!("Failed to write to Snapcast fifo: {}", err);
+            return;
+        }
+        out.clear();
+    }
+}
+
+/// A Snapcast client, usually a room.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Zone {
+    /// The client id, by default the mac address of the client.
+    pub id: String,
+
+    /// The configured name of the client, or its host name if it has none.
+    pub name: String,
+
+    pub connected: bool,
+    pub volume_percent: u8,
+    pub muted: bool,
+}
+
+/// Call a method of the Snapcast json-rpc api, return the result.
+fn call(address: &str, method: &str, params: Value) -> io::Result<Value> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(2)))?;
+
+    let request = json!({ "id": 1, "jsonrpc": "2.0", "method": method, "params": params });
+    writeln!(&stream, "{}", request)?;
+
+    // The server may send notifications before our response, skip those.
+    let mut reader = io::BufReader::new(&stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Snapcast server closed the connection."));
+        }
+        let mut response: Value = match serde_json::from_str(&line) {
+            Ok(v) => v,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        };
+        if response["id"] != json!(1) {
+            continue;
+        }
+        if let Some(message) = response["error"]["message"].as_str() {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("Snapcast error: {}", message)));
+        }
+        return Ok(response["result"].take());
+    }
+}
+
+/// Extract the zones from the result of `Server.GetStatus`.
+fn parse_zones(status: &Value) -> Vec<Zone> {
+    let mut zones = Vec::new();
+    let groups = status["server"]["groups"].as_array().map(|g| &g[..]).unwrap_or(&[]);
+    for group in groups {
+        let clients = group["clients"].as_array().map(|c| &c[..]).unwrap_or(&[]);
+        for client in clients {
+            let id = match client["id"].as_str() {
+                Some(id) => id,
+                None => continue,
+            };
+            let name = match client["config"]["name"].as_str() {
+                Some(name) if !name.is_empty() => name,
+                _ => client["host"]["name"].as_str().unwrap_or(id),
+            };
+            let volume = &client["config"]["volume"];
+            zones.push(Zone {
+                id: id.to_string(),
+                name: name.to_string(),
+                connected: client["connected"].as_bool().unwrap_or(false),
+                volume_percent: volume["percent"].as_u64().unwrap_or(100).min(100) as u8,
+                muted: volume["muted"].as_bool().unwrap_or(false),
+            });
+        }
+    }
+    zones.sort_by(|a, b| a.name.cmp(&b.name));
+    zones
+}
+
+/// List the zones that the Snapcast server knows about.
+pub fn get_zones(address: &str) -> io::Result<Vec<Zone>> {
+    let status = call(address, "Server.GetStatus", json!({}))?;
+    Ok(parse_zones(&status))
+}
+
+/// Set the volume of a zone, this also unmutes it.
+pub fn set_zone_volume(address: &str, zone_id: &str, percent: u8) -> io::Result<()> {
+    let params = json!({
+        "id": zone_id,
+        "volume": { "percent": percent.min(100), "muted": false },
+    });
+    call(address, "Client.SetVolume", params)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::player::Format;
+    use crate::prim::Hertz;
+    use super::{Converter, Zone, parse_zones};
+
+    fn format(sample_rate: u32, bits_per_sample: u32) -> Format {
+        Format {
+            sample_rate: Hertz(sample_rate),
+            bits_per_sample: bits_per_sample,
+        }
+    }
+
+    #[test]
+    fn converter_passes_through_matching_rate() {
+        let mut converter = Converter::new(Hertz(44_100));
+        let mut out = Vec::new();
+        let src = [0x00, 0x40, 0x00, 0xc0];
+        converter.convert(format(44_100, 16), &src, 1.0, &mut out);
+        assert_eq!(&out[..], &src[..]);
+
+        // 24-bit samples lose their lowest byte, and the gain applies.
+        out.clear();
+        converter.convert(format(44_100, 24), &[0xff, 0x00, 0x40, 0xff, 0x00, 0xc0], 0.5, &mut out);
+        assert_eq!(&out[..], &[0x00, 0x20, 0x00, 0xe0]);
+    }
+
+    #[test]
+    fn converter_resamples_to_target_rate() {
+        let mut converter = Converter::new(Hertz(48_000));
+        let mut out = Vec::new();
+
+        // One second of a constant signal at 96 kHz should become roughly one
+        // second at 48 kHz, and apart from the edges, the signal is preserved.
+        let src: Vec<u8> = (0..96_000 * 2).flat_map(|_| 8192_i16.to_le_bytes()).collect();
+        for chunk in src.chunks(4 * 1000) {
+            converter.convert(format(96_000, 16), chunk, 1.0, &mut out);
+        }
+        let n_frames = out.len() / 4;
+        assert!(n_frames > 47_990 && n_frames <= 48_000, "Got {} frames.", n_frames);
+        let sample = i16::from_le_bytes([out[4 * 24_000], out[4 * 24_000 + 1]]);
+        assert!((sample - 8192).abs() < 16, "Got {}.", sample);
+    }
+
+    #[test]
+    fn parse_zones_reads_clients_of_all_groups() {
+        let status = json!({
+            "server": {
+                "groups": [
+                    {
+                        "id": "g1",
+                        "clients": [
+                            {
+                                "id": "b8:27:eb:00:00:01",
+                                "connected": true,
+                                "config": { "name": "", "volume": { "muted": false, "percent": 74 } },
+                                "host": { "name": "kitchen" },
+                            },
+                        ],
+                    },
+                    {
+                        "id": "g2",
+                        "clients": [
+                            {
+                                "id": "b8:27:eb:00:00:02",
+                                "connected": false,
+                                "config": { "name": "Bedroom", "volume": { "muted": true, "percent": 30 } },
+                                "host": { "name": "raspberrypi" },
+                            },
+                        ],
+                    },
+                ],
+            },
+        });
+        let zones = parse_zones(&status);
+        assert_eq!(zones, vec![
+            Zone {
+                id: "b8:27:eb:00:00:02".to_string(),
+                name: "Bedroom".to_string(),
+                connected: false,
+                volume_percent: 30,
+                muted: true,
+            },
+            Zone {
+                id: "b8:27:eb:00:00:01".to_string(),
+                name: "kitchen".to_string(),
+                connected: true,
+                volume_percent: 74,
+                muted: false,
+            },
+        ]);
+    }
+}
@@ -0,0 +1,139 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Running a program and driving GPIO lines when the playback status changes.
+//!
+//! This is for appliance builds, for example a Raspberry Pi with a front panel
+//! LED, or a relay that powers an amplifier. The status thread receives status
+//! changes from the history thread, and for every change, it sets the
+//! configured GPIO lines, and runs the status change program, if any, with the
+//! new status as argument. See `docs/configuration.md`.
+//!
+//! GPIO lines are driven through the sysfs interface, so this needs no
+//! libraries, only write access to `/sys/class/gpio`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+
+use crate::config::Config;
+use crate::exec_pre_post::execute_program_with_timeout;
+
+/// What the player is doing, as far as the outside world is concerned.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Status {
+    /// The queue is empty, nothing is playing.
+    Idle,
+
+    /// A track or radio station is playing.
+    Playing,
+
+    /// The user paused playback of the current track or radio station.
+    Paused,
+
+    /// A track or radio station failed to play, see `PlaybackEvent::Failed`
+    /// and `PlaybackEvent::RadioFailed`.
+    Error,
+}
+
+impl Status {
+    /// The name of the status, passed as argument to the status change program.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Idle => "idle",
+            Status::Playing => "playing",
+            Status::Paused => "paused",
+            Status::Error => "error",
+        }
+    }
+}
+
+/// Return the status to show after `event`, when we were showing `current`.
+///
+/// An error stays visible when the queue ends, otherwise a queue of tracks
+/// that all fail would end up idle, and nobody would notice. It clears when
+/// playback starts again.
+fn next_status(current: Status, event: Status) -> Status {
+    match (current, event) {
+        (Status::Error, Status::Idle) => Status::Error,
+        (_, event) => event,
+    }
+}
+
+/// Export the sysfs GPIO line, if it isn't already, and make it an output.
+fn gpio_export(line: u32) -> io::Result<()> {
+    let dir = format!("/sys/class/gpio/gpio{}", line);
+    if !Path::new(&dir).is_dir() {
+        fs::write("/sys/class/gpio/export", line.to_string())?;
+    }
+    fs::write(format!("{}/direction", dir), "out")
+}
+
+fn gpio_set(line: u32, is_high: bool) -> io::Result<()> {
+    let value = if is_high { "1" } else { "0" };
+    fs::write(format!("/sys/class/gpio/gpio{}/value", line), value)
+}
+
+/// Apply the status to the GPIO lines, and run the status change program.
+fn apply(config: &Config, status: Status) {
+    info!("Playback status is now {}.", status.name());
+
+    let lines = [
+        (config.gpio_playing, status == Status::Playing),
+        (config.gpio_error, status == Status::Error),
+    ];
+    for &(line, is_high) in &lines {
+        if let Some(line) = line {
+            if let Err(err) = gpio_set(line, is_high) {
+                warn!("Failed to set GPIO {}: {}", line, err);
+            }
+        }
+    }
+
+    if let Some(exe) = config.exec_status_change_path.as_ref() {
+        execute_program_with_timeout(exe, &[status.name()], "status change");
+    }
+}
+
+pub fn main(config: &Config, events: Receiver<Status>) {
+    for line in config.gpio_playing.iter().chain(config.gpio_error.iter()) {
+        if let Err(err) = gpio_export(*line) {
+            warn!("Failed to configure GPIO {} as output: {}", line, err);
+        }
+    }
+
+    let mut current = Status::Idle;
+    apply(config, current);
+
+    while let Ok(event) = events.recv() {
+        // While the program ran, more changes may have come in. Only the
+        // latest status matters, so we skip over the intermediate ones.
+        let mut next = next_status(current, event);
+        while let Ok(event) = events.try_recv() {
+            next = next_status(next, event);
+        }
+        if next != current {
+            current = next;
+            apply(config, current);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{next_status, Status};
+
+    #[test]
+    fn next_status_keeps_error_until_playback_starts() {
+        assert_eq!(next_status(Status::Playing, Status::Error), Status::Error);
+        assert_eq!(next_status(Status::Error, Status::Idle), Status::Error);
+        assert_eq!(next_status(Status::Error, Status::Playing), Status::Playing);
+        assert_eq!(next_status(Status::Playing, Status::Idle), Status::Idle);
+        assert_eq!(next_status(Status::Playing, Status::Paused), Status::Paused);
+    }
+}
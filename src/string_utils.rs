@@ -13,6 +13,58 @@ use std::collections::HashMap;
 
 use unicode_normalization::UnicodeNormalization;
 
+/// Strings stored back to back in a single allocation.
+///
+/// A `Vec<String>` has a heap allocation per string, which costs 24 bytes for
+/// the `String` itself, plus the overhead of the allocator. For hundreds of
+/// thousands of short titles and filenames, that is more than the text itself.
+pub struct StringArena {
+    data: String,
+    // The end offset of every string in `data`. A string starts where the
+    // previous one ends.
+    ends: Vec<u32>,
+}
+
+impl StringArena {
+    pub fn new() -> StringArena {
+        StringArena {
+            data: String::new(),
+            ends: Vec::new(),
+        }
+    }
+
+    /// Append the string, return its index.
+    pub fn push(&mut self, string: &str) -> u32 {
+        self.data.push_str(string);
+        assert!(self.data.len() <= u32::MAX as usize, "String arena can hold at most 4 GiB.");
+        self.ends.push(self.data.len() as u32);
+        self.ends.len() as u32 - 1
+    }
+
+    /// Return the string with the given index, or an empty string when out of bounds.
+    #[inline]
+    pub fn get(&self, index: u32) -> &str {
+        let i = index as usize;
+        if i >= self.ends.len() {
+            return "";
+        }
+        let begin = if i == 0 { 0 } else { self.ends[i - 1] as usize };
+        let end = self.ends[i] as usize;
+        &self.data[begin..end]
+    }
+
+    /// Release the excess capacity, once no more strings will be pushed.
+    pub fn shrink_to_fit(&mut self) {
+        self.data.shrink_to_fit();
+        self.ends.shrink_to_fit();
+    }
+
+    /// Return the number of bytes of heap memory that the arena uses.
+    pub fn size_bytes(&self) -> usize {
+        self.data.capacity() + self.ends.capacity() * mem::size_of::<u32>()
+    }
+}
+
 pub struct StringDeduper {
     strings_to_id: HashMap<String, u32>,
     strings: Vec<String>,
@@ -44,9 +96,16 @@ impl StringDeduper {
         next_id
     }
 
-    /// Return the underlying string vector, destroying the deduplicator.
-    pub fn into_vec(self) -> Vec<String> {
-        self.strings
+    /// Move the strings into an arena, destroying the deduplicator.
+    ///
+    /// Indices returned by `insert` are valid indices into the arena.
+    pub fn into_arena(self) -> StringArena {
+        let mut arena = StringArena::new();
+        for string in &self.strings {
+            arena.push(string);
+        }
+        arena.shrink_to_fit();
+        arena
     }
 
     /// Return the string with the given index. Panics when out of bounds.
@@ -235,9 +294,105 @@ pub fn normalize_words(title: &str, dest: &mut Vec<String>) {
     push_word(dest, &mut word);
 }
 
+/// Return whether the two strings are equal after extracting normalized words.
+pub fn equals_normalized(x1: &str, x2: &str) -> bool {
+    // TODO: Figure out a faster way to do this.
+    let mut w1 = Vec::new();
+    let mut w2 = Vec::new();
+    normalize_words(x1, &mut w1);
+    normalize_words(x2, &mut w2);
+    w1 == w2
+}
+
+/// Compute the edit distance between `query` and `word`, bounded by `max`.
+///
+/// This is the optimal string alignment distance: the number of insertions,
+/// deletions, substitutions, and transpositions of adjacent characters needed
+/// to turn `query` into `word`. Transpositions are included because swapping
+/// two letters is the most common typo (“Radiohaed”).
+///
+/// If `is_prefix` is true, then `query` only needs to match a prefix of `word`,
+/// this is what we want for the last word in search-as-you-type.
+///
+/// Returns `None` if the distance exceeds `max`.
+pub fn edit_distance(query: &str, word: &str, max: u32, is_prefix: bool) -> Option<u32> {
+    let a: Vec<char> = query.chars().collect();
+    let b: Vec<char> = word.chars().collect();
+    let max = max as usize;
+
+    // If the lengths differ by more than the budget, there is no way to close
+    // the gap, so we can skip the quadratic part. This check is what makes
+    // scanning all words in the index feasible.
+    if a.len() > b.len() + max { return None }
+    if !is_prefix && b.len() > a.len() + max { return None }
+
+    // We only need the current row and the two rows before it; the one two
+    // rows back is needed for transpositions.
+    let mut prev2: Vec<usize> = vec![0; b.len() + 1];
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut curr: Vec<usize> = vec![0; b.len() + 1];
+
+    for i in 1..=a.len() {
+        curr[0] = i;
+        let mut row_min = curr[0];
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut d = (prev[j] + 1).min(curr[j - 1] + 1).min(prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d = d.min(prev2[j - 2] + 1);
+            }
+            curr[j] = d;
+            row_min = row_min.min(d);
+        }
+        // The distance can only grow from here on, so if every cell in this
+        // row is over budget, we can stop early.
+        if row_min > max { return None }
+        mem::swap(&mut prev2, &mut prev);
+        mem::swap(&mut prev, &mut curr);
+    }
+
+    // After the final swap, `prev` holds the last row. For a prefix match we
+    // may stop anywhere in `word`, for a full match we must consume all of it.
+    let distance = if is_prefix {
+        *prev.iter().min().expect("Row has at least one element.")
+    } else {
+        prev[b.len()]
+    };
+
+    if distance <= max {
+        Some(distance as u32)
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::normalize_words;
+    use super::{edit_distance, normalize_words, StringArena, StringDeduper};
+
+    #[test]
+    pub fn test_string_arena() {
+        let mut arena = StringArena::new();
+        assert_eq!(arena.push("Takk"), 0);
+        assert_eq!(arena.push(""), 1);
+        assert_eq!(arena.push("Sigur Rós"), 2);
+        assert_eq!(arena.get(0), "Takk");
+        assert_eq!(arena.get(1), "");
+        assert_eq!(arena.get(2), "Sigur Rós");
+        assert_eq!(arena.get(3), "");
+    }
+
+    #[test]
+    pub fn test_string_deduper_into_arena() {
+        let mut deduper = StringDeduper::new();
+        let takk = deduper.insert("Takk");
+        let sigur_ros = deduper.insert("Sigur Rós");
+        assert_eq!(deduper.insert("Takk"), takk);
+        let arena = deduper.into_arena();
+        assert_eq!(arena.get(takk), "Takk");
+        assert_eq!(arena.get(sigur_ros), "Sigur Rós");
+        assert_eq!(arena.get(2), "");
+    }
 
     fn expect_normalize_words(input: &str, expected_output: &[&str]) {
         let mut words = Vec::new();
@@ -251,4 +406,23 @@ mod test {
         expect_normalize_words("Ṣānnu yārru lī", &["sannu", "yarru", "li"]);
         expect_normalize_words("Orð vǫlu", &["ord", "volu"]);
     }
+
+    #[test]
+    pub fn test_edit_distance() {
+        assert_eq!(edit_distance("radiohead", "radiohead", 2, false), Some(0));
+        assert_eq!(edit_distance("radiohaed", "radiohead", 2, false), Some(1));
+        assert_eq!(edit_distance("radoihaed", "radiohead", 2, false), Some(2));
+        assert_eq!(edit_distance("radoihaed", "radiohead", 1, false), None);
+        assert_eq!(edit_distance("sigr", "sigur", 1, false), Some(1));
+        assert_eq!(edit_distance("sigurr", "sigur", 1, false), Some(1));
+        assert_eq!(edit_distance("abba", "queen", 2, false), None);
+    }
+
+    #[test]
+    pub fn test_edit_distance_prefix() {
+        assert_eq!(edit_distance("radio", "radiohead", 1, true), Some(0));
+        assert_eq!(edit_distance("raido", "radiohead", 1, true), Some(1));
+        assert_eq!(edit_distance("radio", "radiohead", 1, false), None);
+        assert_eq!(edit_distance("radiohead", "radio", 1, true), None);
+    }
 }
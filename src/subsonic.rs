@@ -0,0 +1,809 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Compatibility with the Subsonic API, for existing mobile clients.
+//!
+//! Subsonic is a music server whose REST API many Android and iOS apps speak,
+//! see <http://www.subsonic.org/pages/api.jsp>, and the OpenSubsonic extensions
+//! at <https://opensubsonic.netlify.app/>. We implement the subset that those
+//! apps need to browse the library, stream, and scrobble. Responses are XML by
+//! default, or json with `f=json`. Both are built from the same tree of
+//! elements, this module builds that tree, `server.rs` dispatches requests.
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::auth::{ApiToken, Scope};
+use crate::listing::{self, ListParams, RandomParams, SortOrder};
+use crate::prim::{AlbumId, ArtistId, Track, TrackId};
+use crate::shuffle::Prng;
+use crate::string_utils::normalize_words;
+use crate::user_data::UserData;
+use crate::xspf::write_escaped;
+use crate::MetaIndex;
+
+/// The version of the Subsonic API that we implement.
+pub const API_VERSION: &str = "1.16.1";
+
+/// The value of an attribute.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Str(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Str(s)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(x: i64) -> Value {
+        Value::Int(x)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(x: u32) -> Value {
+        Value::Int(x as i64)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(x: u16) -> Value {
+        Value::Int(x as i64)
+    }
+}
+
+impl From<u8> for Value {
+    fn from(x: u8) -> Value {
+        Value::Int(x as i64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(x: usize) -> Value {
+        Value::Int(x as i64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Value {
+        Value::Float(x)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl Value {
+    fn write_xml<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Value::Str(s) => write_escaped(w, s),
+            Value::Int(x) => write!(w, "{}", x),
+            Value::Float(x) => write!(w, "{}", x),
+            Value::Bool(b) => write!(w, "{}", b),
+        }
+    }
+
+    fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Value::Str(s) => serde_json::to_writer(w, s).map_err(io::Error::from),
+            Value::Int(x) => write!(w, "{}", x),
+            Value::Float(x) => write!(w, "{}", x),
+            Value::Bool(b) => write!(w, "{}", b),
+        }
+    }
+}
+
+/// An element of a response, with attributes and child elements.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Element {
+    name: &'static str,
+    attributes: Vec<(&'static str, Value)>,
+    children: Vec<Element>,
+
+    /// Whether the element can occur multiple times in its parent.
+    ///
+    /// In XML this makes no difference, but in json, such elements go in an
+    /// array, even when there is only one.
+    is_list_item: bool,
+}
+
+impl Element {
+    pub fn new(name: &'static str) -> Element {
+        Element {
+            name: name,
+            attributes: Vec::new(),
+            children: Vec::new(),
+            is_list_item: false,
+        }
+    }
+
+    pub fn list_item(name: &'static str) -> Element {
+        Element {
+            is_list_item: true,
+            ..Element::new(name)
+        }
+    }
+
+    pub fn attr<V: Into<Value>>(mut self, key: &'static str, value: V) -> Element {
+        self.attributes.push((key, value.into()));
+        self
+    }
+
+    pub fn attr_opt<V: Into<Value>>(self, key: &'static str, value: Option<V>) -> Element {
+        match value {
+            Some(v) => self.attr(key, v),
+            None => self,
+        }
+    }
+
+    pub fn child(mut self, child: Element) -> Element {
+        self.children.push(child);
+        self
+    }
+
+    pub fn children<I: IntoIterator<Item = Element>>(mut self, children: I) -> Element {
+        self.children.extend(children);
+        self
+    }
+
+    fn write_xml<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "<{}", self.name)?;
+        for (key, value) in &self.attributes {
+            write!(w, r#" {}=""#, key)?;
+            value.write_xml(w)?;
+            write!(w, r#"""#)?;
+        }
+        if self.children.is_empty() {
+            return write!(w, "/>");
+        }
+        write!(w, ">")?;
+        for child in &self.children {
+            child.write_xml(w)?;
+        }
+        write!(w, "</{}>", self.name)
+    }
+
+    /// Write the attributes and children as a json object.
+    fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "{{")?;
+        let mut is_first = true;
+        for (key, value) in &self.attributes {
+            write!(w, r#"{}"{}":"#, if is_first { "" } else { "," }, key)?;
+            value.write_json(w)?;
+            is_first = false;
+        }
+
+        // Children with the same name are grouped under one key, in the order
+        // in which the names first occur.
+        for (i, child) in self.children.iter().enumerate() {
+            if self.children[..i].iter().any(|c| c.name == child.name) {
+                continue;
+            }
+            write!(w, r#"{}"{}":"#, if is_first { "" } else { "," }, child.name)?;
+            is_first = false;
+            if !child.is_list_item {
+                child.write_json(w)?;
+                continue;
+            }
+            write!(w, "[")?;
+            let siblings = self.children[i..].iter().filter(|c| c.name == child.name);
+            for (j, sibling) in siblings.enumerate() {
+                if j > 0 {
+                    write!(w, ",")?;
+                }
+                sibling.write_json(w)?;
+            }
+            write!(w, "]")?;
+        }
+        write!(w, "}}")
+    }
+}
+
+/// An error as reported to the client, with a Subsonic error code.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ApiError {
+    pub code: u32,
+    pub message: &'static str,
+}
+
+impl ApiError {
+    pub fn generic(message: &'static str) -> ApiError {
+        ApiError { code: 0, message: message }
+    }
+
+    pub fn missing_parameter(message: &'static str) -> ApiError {
+        ApiError { code: 10, message: message }
+    }
+
+    pub fn wrong_credentials() -> ApiError {
+        ApiError { code: 40, message: "Wrong username or password." }
+    }
+
+    pub fn not_authorized() -> ApiError {
+        ApiError { code: 50, message: "The token's scope does not allow this operation." }
+    }
+
+    pub fn not_found(message: &'static str) -> ApiError {
+        ApiError { code: 70, message: message }
+    }
+}
+
+pub type ApiResult = std::result::Result<Option<Element>, ApiError>;
+
+/// The response format that the client asks for with the `f` parameter.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    Xml,
+    Json,
+}
+
+impl Format {
+    pub fn from_params(params: &Params) -> Format {
+        match params.get("f") {
+            Some("json") => Format::Json,
+            _ => Format::Xml,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Xml => "text/xml; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+}
+
+/// Write the `subsonic-response` document that wraps every response.
+pub fn write_response<W: Write>(mut w: W, format: Format, result: &ApiResult) -> io::Result<()> {
+    let root = Element::new("subsonic-response");
+    let root = match format {
+        Format::Xml => root.attr("xmlns", "http://subsonic.org/restapi"),
+        Format::Json => root,
+    };
+    let root = root
+        .attr("status", if result.is_ok() { "ok" } else { "failed" })
+        .attr("version", API_VERSION)
+        .attr("type", "musium")
+        .attr("openSubsonic", true);
+    let root = match result {
+        Ok(None) => root,
+        Ok(Some(element)) => root.child(element.clone()),
+        Err(err) => root.child(
+            Element::new("error")
+                .attr("code", err.code)
+                .attr("message", err.message),
+        ),
+    };
+    match format {
+        Format::Xml => {
+            write!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+            root.write_xml(&mut w)
+        }
+        Format::Json => {
+            write!(w, r#"{{"subsonic-response":"#)?;
+            root.write_json(&mut w)?;
+            write!(w, "}}")
+        }
+    }
+}
+
+/// The parameters of a request, from the query string and a form body.
+///
+/// Some parameters, like `id` in `createPlaylist`, can occur multiple times.
+pub struct Params {
+    pairs: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn parse(raw_query: &str, body: &str) -> Params {
+        let pairs = url::form_urlencoded::parse(raw_query.as_bytes())
+            .chain(url::form_urlencoded::parse(body.as_bytes()))
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect();
+        Params { pairs: pairs }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs.iter().find(|(k, _v)| k == key).map(|(_k, v)| &v[..])
+    }
+
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.pairs.iter().filter(move |(k, _v)| k == key).map(|(_k, v)| &v[..])
+    }
+
+    pub fn require(&self, key: &str) -> Result<&str, ApiError> {
+        self.get(key).ok_or_else(|| ApiError::missing_parameter("A required parameter is missing."))
+    }
+
+    pub fn get_usize(&self, key: &str, default: usize) -> Result<usize, ApiError> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(v) => usize::from_str(v)
+                .map_err(|_| ApiError::generic("Invalid count or offset, must be a non-negative integer.")),
+        }
+    }
+}
+
+pub fn parse_track_id(id: &str) -> Result<TrackId, ApiError> {
+    TrackId::parse(id).ok_or_else(|| ApiError::not_found("Invalid track id."))
+}
+
+pub fn parse_album_id(id: &str) -> Result<AlbumId, ApiError> {
+    AlbumId::parse(id).ok_or_else(|| ApiError::not_found("Invalid album id."))
+}
+
+fn parse_artist_id(id: &str) -> Result<ArtistId, ApiError> {
+    ArtistId::parse(id).ok_or_else(|| ApiError::not_found("Invalid artist id."))
+}
+
+pub fn parse_playlist_id(id: &str) -> Result<i64, ApiError> {
+    i64::from_str(id).map_err(|_| ApiError::not_found("Invalid playlist id."))
+}
+
+/// Decode a password from the `p` parameter, which may be hex-encoded.
+fn decode_password(p: &str) -> Option<String> {
+    let hex = match p.strip_prefix("enc:") {
+        Some(hex) => hex,
+        None => return Some(p.to_string()),
+    };
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect();
+    String::from_utf8(bytes?).ok()
+}
+
+/// Return the configured token that the request authenticates with.
+///
+/// The username is the name of the token, and the password is its secret.
+/// Clients can send the password as-is, or as `md5(secret + salt)` together
+/// with the salt. The OpenSubsonic `apiKey` parameter takes the secret alone.
+pub fn authenticate<'a>(tokens: &'a [ApiToken], params: &Params) -> Result<&'a ApiToken, ApiError> {
+    if let Some(key) = params.get("apiKey") {
+        return tokens
+            .iter()
+            .find(|token| token.matches(key))
+            .ok_or(ApiError { code: 44, message: "Invalid API key." });
+    }
+
+    let user = params.require("u")?;
+    let token = tokens.iter().find(|token| token.name == user);
+    let is_valid = match (token, params.get("p"), params.get("t"), params.get("s")) {
+        (Some(token), Some(p), _, _) => match decode_password(p) {
+            Some(password) => token.matches(&password),
+            None => false,
+        },
+        (Some(token), None, Some(t), Some(s)) => token.matches_salted_md5(s, t),
+        (_, None, _, _) if params.get("t").is_none() || params.get("s").is_none() => {
+            return Err(ApiError::missing_parameter("Expected 'p', or 't' and 's'."));
+        }
+        _ => false,
+    };
+
+    match token {
+        Some(token) if is_valid => Ok(token),
+        _ => Err(ApiError::wrong_credentials()),
+    }
+}
+
+/// Return the scope needed to call the given method.
+///
+/// Like in the regular API, writing to the database needs full access.
+pub fn required_scope(method: &str, params: &Params) -> Scope {
+    match method {
+        "scrobble" | "createPlaylist" | "updatePlaylist" | "deletePlaylist" => Scope::Full,
+        "jukeboxControl" => match params.get("action") {
+            None | Some("get") | Some("status") => Scope::Read,
+            Some(..) => Scope::Queue,
+        },
+        _ => Scope::Read,
+    }
+}
+
+pub fn get_license() -> Element {
+    Element::new("license").attr("valid", true)
+}
+
+pub fn get_open_subsonic_extensions() -> Element {
+    Element::new("openSubsonicExtensions").child(
+        Element::list_item("openSubsonicExtensions")
+            .attr("name", "apiKeyAuthentication")
+            .child(Element::list_item("versions").attr("version", 1_u32)),
+    )
+}
+
+/// We serve the entire library as a single folder.
+pub fn get_music_folders() -> Element {
+    Element::new("musicFolders").child(
+        Element::list_item("musicFolder")
+            .attr("id", 1_u32)
+            .attr("name", "Music"),
+    )
+}
+
+/// Describe what the user may do, based on the scope of their token.
+pub fn get_user(name: &str, scope: Scope) -> Element {
+    Element::new("user")
+        .attr("username", name)
+        .attr("scrobblingEnabled", scope >= Scope::Full)
+        .attr("adminRole", false)
+        .attr("settingsRole", false)
+        .attr("downloadRole", true)
+        .attr("uploadRole", false)
+        .attr("playlistRole", scope >= Scope::Full)
+        .attr("coverArtRole", false)
+        .attr("commentRole", false)
+        .attr("podcastRole", false)
+        .attr("streamRole", true)
+        .attr("jukeboxRole", scope >= Scope::Queue)
+        .attr("shareRole", false)
+        .attr("videoConversionRole", false)
+        .child(Element::list_item("folder").attr("id", 1_u32))
+}
+
+fn artist_element(index: &dyn MetaIndex, artist_id: ArtistId) -> Option<Element> {
+    let artist = index.get_artist(artist_id)?;
+    let albums = index.get_albums_by_artist(artist_id);
+    let element = Element::list_item("artist")
+        .attr("id", artist_id.to_string())
+        .attr("name", index.get_string(artist.name))
+        .attr("albumCount", albums.len())
+        // Artists have no image, the cover of their first album comes closest.
+        .attr_opt("coverArt", albums.first().map(|(_, album_id)| album_id.to_string()));
+    Some(element)
+}
+
+fn album_element(index: &dyn MetaIndex, album_id: AlbumId) -> Option<Element> {
+    let album = index.get_album(album_id)?;
+    let tracks = index.get_album_tracks(album_id);
+    let artist_id = index.get_album_artists(album.artist_ids)[0];
+    let duration_seconds: u64 = tracks.iter().map(|t| t.track.duration_seconds as u64).sum();
+    let element = Element::list_item("album")
+        .attr("id", album_id.to_string())
+        .attr("name", index.get_string(album.title))
+        .attr("artist", index.get_string(album.artist))
+        .attr("artistId", artist_id.to_string())
+        .attr("coverArt", album_id.to_string())
+        .attr("songCount", tracks.len())
+        .attr("duration", duration_seconds as i64)
+        .attr("year", album.original_release_date.year)
+        .attr("created", album.first_seen.format_iso8601());
+    Some(element)
+}
+
+/// Build a `song` element, or an element with the same attributes but another
+/// name, such as `entry` in playlists.
+pub fn song_element(
+    index: &dyn MetaIndex,
+    name: &'static str,
+    track_id: TrackId,
+    track: &Track,
+) -> Element {
+    let album_id = track_id.album_id();
+    let album = index.get_album(album_id).expect("Track's album should be in the index.");
+    let artist_id = index.get_album_artists(album.artist_ids)[0];
+    Element::list_item(name)
+        .attr("id", track_id.to_string())
+        .attr("parent", album_id.to_string())
+        .attr("isDir", false)
+        .attr("title", index.get_string(track.title))
+        .attr("album", index.get_string(album.title))
+        .attr("artist", index.get_string(track.artist))
+        .attr("track", track_id.track_number())
+        .attr("discNumber", track_id.disc_number())
+        .attr("year", album.original_release_date.year)
+        .attr("coverArt", album_id.to_string())
+        .attr("duration", track.duration_seconds)
+        .attr("suffix", "flac")
+        .attr("contentType", "audio/flac")
+        .attr("type", "music")
+        .attr("albumId", album_id.to_string())
+        .attr("artistId", artist_id.to_string())
+}
+
+/// Build a `playlist` element, as listed by `getPlaylists`.
+///
+/// Musium has a single user, so every playlist is owned by the caller.
+pub fn playlist_element(
+    index: &dyn MetaIndex,
+    playlist_id: i64,
+    name: &str,
+    owner: &str,
+    is_smart: bool,
+    tracks: &[TrackId],
+) -> Element {
+    let duration_seconds: u64 = tracks
+        .iter()
+        .filter_map(|t| index.get_track(*t))
+        .map(|t| t.duration_seconds as u64)
+        .sum();
+    Element::list_item("playlist")
+        .attr("id", playlist_id)
+        .attr("name", name)
+        .attr("owner", owner)
+        .attr("public", false)
+        .attr("songCount", tracks.len())
+        .attr("duration", duration_seconds as i64)
+        .attr_opt("coverArt", tracks.first().map(|t| t.album_id().to_string()))
+        // Clients should not offer to edit the tracks of a smart playlist.
+        .attr_opt("readonly", if is_smart { Some(true) } else { None })
+}
+
+/// Build the `playlist` element with its tracks, for `getPlaylist`.
+pub fn playlist_with_entries(
+    index: &dyn MetaIndex,
+    playlist_id: i64,
+    name: &str,
+    owner: &str,
+    is_smart: bool,
+    tracks: &[TrackId],
+) -> Element {
+    let playlist = playlist_element(index, playlist_id, name, owner, is_smart, tracks);
+    let entries = tracks
+        .iter()
+        .filter_map(|t| index.get_track(*t).map(|track| song_element(index, "entry", *t, track)));
+    Element { is_list_item: false, ..playlist }.children(entries)
+}
+
+/// List the album artists, grouped by the first letter of their sort name.
+pub fn get_artists(index: &dyn MetaIndex) -> Element {
+    let mut artists: Vec<_> = index
+        .get_artists()
+        .iter()
+        .map(|kv| (index.get_string(kv.artist.name_for_sort).to_uppercase(), kv.artist_id))
+        .collect();
+    artists.sort();
+
+    let mut result = Element::new("artists").attr("ignoredArticles", "");
+    let mut group: Option<(String, Element)> = None;
+    for (sort_name, artist_id) in artists {
+        let letter = match sort_name.chars().next() {
+            Some(ch) if ch.is_alphabetic() => ch.to_string(),
+            _ => "#".to_string(),
+        };
+        let artist = match artist_element(index, artist_id) {
+            Some(a) => a,
+            None => continue,
+        };
+        group = match group {
+            Some((name, element)) if name == letter => Some((name, element.child(artist))),
+            previous => {
+                if let Some((_, element)) = previous {
+                    result = result.child(element);
+                }
+                let element = Element::list_item("index").attr("name", &letter[..]).child(artist);
+                Some((letter, element))
+            }
+        };
+    }
+    if let Some((_, element)) = group {
+        result = result.child(element);
+    }
+    result
+}
+
+pub fn get_artist(index: &dyn MetaIndex, params: &Params) -> Result<Element, ApiError> {
+    let artist_id = parse_artist_id(params.require("id")?)?;
+    let artist = artist_element(index, artist_id).ok_or(ApiError::not_found("Artist not found."))?;
+    let albums = index
+        .get_albums_by_artist(artist_id)
+        .iter()
+        .filter_map(|(_, album_id)| album_element(index, *album_id));
+    // The artist is a list item in `getArtists`, but the sole child here.
+    let artist = Element { is_list_item: false, ..artist };
+    Ok(artist.children(albums))
+}
+
+pub fn get_album(index: &dyn MetaIndex, params: &Params) -> Result<Element, ApiError> {
+    let album_id = parse_album_id(params.require("id")?)?;
+    let album = album_element(index, album_id).ok_or(ApiError::not_found("Album not found."))?;
+    let songs = index
+        .get_album_tracks(album_id)
+        .iter()
+        .map(|kv| song_element(index, "song", kv.track_id, &kv.track));
+    let album = Element { is_list_item: false, ..album };
+    Ok(album.children(songs))
+}
+
+pub fn get_song(index: &dyn MetaIndex, params: &Params) -> Result<Element, ApiError> {
+    let track_id = parse_track_id(params.require("id")?)?;
+    let track = index.get_track(track_id).ok_or(ApiError::not_found("Song not found."))?;
+    let song = song_element(index, "song", track_id, track);
+    Ok(Element { is_list_item: false, ..song })
+}
+
+/// List albums, for the home screens of clients.
+///
+/// We don't support starring and genres, for those types the list is empty.
+pub fn get_album_list2(
+    index: &dyn MetaIndex,
+    user_data: &UserData,
+    params: &Params,
+    rng: &mut Prng,
+) -> Result<Element, ApiError> {
+    let list_type = params.require("type")?;
+    let size = params.get_usize("size", 10)?.min(500);
+    let offset = params.get_usize("offset", 0)?;
+
+    let list_params = |sort| ListParams {
+        sort: sort,
+        offset: offset,
+        limit: Some(size),
+    };
+    let album_ids: Vec<AlbumId> = match list_type {
+        "random" => {
+            let random_params = RandomParams {
+                count: size,
+                decade: None,
+                never_played: false,
+                min_rating: None,
+            };
+            listing::random_albums(index, user_data, &random_params, &HashSet::new(), rng)
+        }
+        "newest" => listing::list_albums(index, user_data, &list_params(SortOrder::RecentlyAdded)),
+        "alphabeticalByName" => listing::list_albums(index, user_data, &list_params(SortOrder::Name)),
+        "frequent" => listing::list_albums(index, user_data, &list_params(SortOrder::MostPlayed)),
+        "highest" => listing::list_albums(index, user_data, &list_params(SortOrder::Rating)),
+        "alphabeticalByArtist" => {
+            let mut albums: Vec<_> = index.get_albums().iter().collect();
+            albums.sort_by_cached_key(|kv| index.get_string(kv.album.artist).to_lowercase());
+            list_params(SortOrder::Id).page(&albums[..]).iter().map(|kv| kv.album_id).collect()
+        }
+        "recent" => {
+            let mut albums: Vec<_> = index
+                .get_albums()
+                .iter()
+                .filter_map(|kv| Some((user_data.get_album_last_played(kv.album_id)?, kv.album_id)))
+                .collect();
+            albums.sort_by_key(|(t, _)| Reverse(*t));
+            list_params(SortOrder::Id).page(&albums[..]).iter().map(|(_, id)| *id).collect()
+        }
+        "byYear" => {
+            let parse_year = |key| {
+                u16::from_str(params.require(key)?)
+                    .map_err(|_| ApiError::generic("Invalid year, must be a non-negative integer."))
+            };
+            let from_year = parse_year("fromYear")?;
+            let to_year = parse_year("toYear")?;
+            let (min, max) = (from_year.min(to_year), from_year.max(to_year));
+            let mut albums: Vec<_> = index
+                .get_albums()
+                .iter()
+                .filter(|kv| (min..=max).contains(&kv.album.original_release_date.year))
+                .collect();
+            // When the years are reversed, the client wants newest first.
+            albums.sort_by_key(|kv| kv.album.original_release_date);
+            if from_year > to_year {
+                albums.reverse();
+            }
+            list_params(SortOrder::Id).page(&albums[..]).iter().map(|kv| kv.album_id).collect()
+        }
+        "starred" | "byGenre" => Vec::new(),
+        _ => return Err(ApiError::generic("Unsupported list type.")),
+    };
+
+    let albums = album_ids.into_iter().filter_map(|id| album_element(index, id));
+    Ok(Element::new("albumList2").children(albums))
+}
+
+/// Search for artists, albums, and songs.
+///
+/// Clients that sync the full library ask for an empty query, then we list
+/// everything, paginated with the offsets.
+pub fn search3(index: &dyn MetaIndex, max_edits: u32, params: &Params) -> Result<Element, ApiError> {
+    // Some clients send the empty query as `""`.
+    let query = params.require("query")?.trim_matches('"');
+    let mut words = Vec::new();
+    normalize_words(query, &mut words);
+
+    let mut artists = Vec::new();
+    let mut albums = Vec::new();
+    let mut tracks = Vec::new();
+    if words.is_empty() {
+        artists.extend(index.get_artists().iter().map(|kv| kv.artist_id));
+        albums.extend(index.get_albums().iter().map(|kv| kv.album_id));
+        tracks.extend(index.get_tracks().iter().map(|kv| kv.track_id));
+    } else {
+        index.search_artist(&words[..], max_edits, &mut artists);
+        index.search_album(&words[..], max_edits, &mut albums);
+        index.search_track(&words[..], max_edits, &mut tracks);
+    }
+
+    let page = |count_key, offset_key, len: usize| -> Result<std::ops::Range<usize>, ApiError> {
+        let count = params.get_usize(count_key, 20)?;
+        let offset = params.get_usize(offset_key, 0)?.min(len);
+        Ok(offset..offset.saturating_add(count).min(len))
+    };
+    let artist_range = page("artistCount", "artistOffset", artists.len())?;
+    let album_range = page("albumCount", "albumOffset", albums.len())?;
+    let track_range = page("songCount", "songOffset", tracks.len())?;
+
+    let result = Element::new("searchResult3")
+        .children(artists[artist_range].iter().filter_map(|id| artist_element(index, *id)))
+        .children(albums[album_range].iter().filter_map(|id| album_element(index, *id)))
+        .children(tracks[track_range].iter().filter_map(|id| {
+            index.get_track(*id).map(|track| song_element(index, "song", *id, track))
+        }));
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{authenticate, decode_password, write_response, Element, Format, Params};
+    use crate::auth::ApiToken;
+    use std::str::FromStr;
+
+    fn render(format: Format, element: Element) -> String {
+        let mut out = Vec::new();
+        write_response(&mut out, format, &Ok(Some(element))).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn write_response_renders_xml() {
+        let element = Element::new("album")
+            .attr("name", "Tom & Jerry")
+            .child(Element::list_item("song").attr("track", 1_u8));
+        assert_eq!(
+            render(Format::Xml, element),
+            r#"<?xml version="1.0" encoding="UTF-8"?><subsonic-response xmlns="http://subsonic.org/restapi" status="ok" version="1.16.1" type="musium" openSubsonic="true"><album name="Tom &amp; Jerry"><song track="1"/></album></subsonic-response>"#,
+        );
+    }
+
+    #[test]
+    fn write_response_renders_json_with_arrays_for_list_items() {
+        let element = Element::new("album")
+            .attr("name", "Tom & Jerry")
+            .child(Element::list_item("song").attr("track", 1_u8));
+        assert_eq!(
+            render(Format::Json, element),
+            r#"{"subsonic-response":{"status":"ok","version":"1.16.1","type":"musium","openSubsonic":true,"album":{"name":"Tom & Jerry","song":[{"track":1}]}}}"#,
+        );
+    }
+
+    #[test]
+    fn decode_password_handles_hex() {
+        assert_eq!(decode_password("sesame").as_deref(), Some("sesame"));
+        assert_eq!(decode_password("enc:736573616d65").as_deref(), Some("sesame"));
+        assert_eq!(decode_password("enc:7365736"), None);
+    }
+
+    #[test]
+    fn authenticate_accepts_password_and_salted_token() {
+        let tokens = [ApiToken::from_str("phone read 0123456789abcdef").unwrap()];
+        let auth = |query: &str| authenticate(&tokens, &Params::parse(query, "")).map(|t| &t.name[..]);
+        assert_eq!(auth("u=phone&p=0123456789abcdef"), Ok("phone"));
+        // The md5 of "0123456789abcdef" followed by the salt "c19b2d".
+        assert_eq!(auth("u=phone&t=cb18bc4af70a03562249b128d9e55380&s=c19b2d"), Ok("phone"));
+        assert_eq!(auth("u=phone&t=cb18bc4af70a03562249b128d9e55380&s=c19b2e").map_err(|e| e.code), Err(40));
+        assert_eq!(auth("apiKey=0123456789abcdef"), Ok("phone"));
+        assert_eq!(auth("u=phone&p=wrong").map_err(|e| e.code), Err(40));
+        assert_eq!(auth("u=laptop&p=0123456789abcdef").map_err(|e| e.code), Err(40));
+        assert_eq!(auth("u=phone").map_err(|e| e.code), Err(10));
+    }
+}
@@ -6,15 +6,32 @@
 // A copy of the License has been included in the root of the repository.
 
 //! Minimal bindings to libsystemd.
+//!
+//! Musium supports three parts of the systemd service protocol:
+//!
+//! * Readiness with `Type=notify`: we report `READY=1` once the server
+//!   accepts connections, and `STOPPING=1` when we shut down.
+//! * The watchdog with `WatchdogSec=`: we ping systemd regularly while the
+//!   player is responsive, so systemd restarts us when it locks up.
+//! * Socket activation: when systemd passes a listening socket, the server
+//!   accepts connections on it, instead of binding `listen` itself.
 
-use std::os::raw::{c_char, c_int};
 use std::ffi::CStr;
+use std::net::TcpListener;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
 
 #[link(name = "systemd")]
 extern {
     fn sd_notify(unset_environment: c_int, state: *const c_char) -> c_int;
+    fn sd_watchdog_enabled(unset_environment: c_int, usec: *mut u64) -> c_int;
+    fn sd_listen_fds(unset_environment: c_int) -> c_int;
 }
 
+/// The first file descriptor that systemd passes, see `sd_listen_fds(3)`.
+const SD_LISTEN_FDS_START: c_int = 3;
+
 /// Check whether we could notify systemd.
 ///
 /// Notifying the system daemon though libsystemd goes through a socket that
@@ -30,6 +47,8 @@ fn can_notify() -> bool {
 /// `KEY=value`. Standardized values are:
 ///
 /// * `READY=1` to signal startup completion.
+/// * `STOPPING=1` to signal that shutdown started.
+/// * `WATCHDOG=1` to signal that we are still alive.
 /// * `STATUS=message` to set a single-line status.
 /// * `EXTEND_TIMEOUT_USEC={microseconds}` to request a longer time to start.
 fn notify(kv_pairs: &CStr) -> Result<(), ()> {
@@ -51,3 +70,52 @@ pub fn notify_ready_if_can_notify() {
         notify(message).expect("Failed to notify systemd of readiness.");
     }
 }
+
+/// Signal to systemd that we are shutting down, if systemd is listening.
+pub fn notify_stopping_if_can_notify() {
+    if can_notify() {
+        let message = CStr::from_bytes_with_nul(b"STOPPING=1\0").unwrap();
+        // If this fails, systemd notices that we stopped once we exit.
+        let _ = notify(message);
+    }
+}
+
+/// Ping the systemd watchdog.
+pub fn notify_watchdog() {
+    let message = CStr::from_bytes_with_nul(b"WATCHDOG=1\0").unwrap();
+    if notify(message).is_err() {
+        warn!("Failed to ping the systemd watchdog.");
+    }
+}
+
+/// Return the watchdog timeout, if systemd expects us to ping the watchdog.
+pub fn get_watchdog_timeout() -> Option<Duration> {
+    let unset_environment = 0; // False
+    let mut usec: u64 = 0;
+    let result = unsafe { sd_watchdog_enabled(unset_environment, &mut usec) };
+    match result {
+        n if n > 0 => Some(Duration::from_micros(usec)),
+        _ => None,
+    }
+}
+
+/// Take the listening socket that systemd passed for socket activation, if any.
+///
+/// This unsets the environment variables, so this returns a socket only once,
+/// and child processes don't think the socket is for them.
+pub fn take_listen_socket() -> Option<TcpListener> {
+    let unset_environment = 1; // True
+    let n = unsafe { sd_listen_fds(unset_environment) };
+    if n <= 0 {
+        return None;
+    }
+    if n > 1 {
+        warn!("Systemd passed {} sockets, using only the first one.", n);
+    }
+    let fd = SD_LISTEN_FDS_START;
+    // Child processes, such as the thumbnailer, should not inherit it.
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    // Safety: systemd passes us the socket, and nothing else in the process
+    // uses this file descriptor.
+    Some(unsafe { TcpListener::from_raw_fd(fd) })
+}
@@ -12,16 +12,76 @@ use std::path::{Path, PathBuf};
 use std::process;
 use std::process::{Command, Stdio};
 use std::sync::mpsc::SyncSender;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::database;
 use crate::database::{Connection, Transaction};
 use crate::database_utils;
 use crate::error::{Error, Result};
+use crate::metrics;
 use crate::prim::{AlbumId, FileId};
 use crate::scan::{ScanStage, Status};
 use crate::{MemoryMetaIndex, MetaIndex};
 
+/// Thumbnails that are being generated, across all scans.
+///
+/// Generating a thumbnail runs `convert` and then `guetzli`. When the server
+/// shuts down, we don't start new ones, but we let the running ones finish,
+/// so we don't leave behind half-written intermediate files.
+struct InFlight {
+    /// Thumbnails that we started and that did not finish yet.
+    count: usize,
+    is_shutting_down: bool,
+}
+
+static IN_FLIGHT: Mutex<InFlight> = Mutex::new(InFlight { count: 0, is_shutting_down: false });
+static IN_FLIGHT_CHANGED: Condvar = Condvar::new();
+
+/// Reserve a slot for a new thumbnail, unless we are shutting down.
+fn try_start_thumbnail() -> bool {
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    if in_flight.is_shutting_down {
+        return false;
+    }
+    in_flight.count += 1;
+    true
+}
+
+fn finish_thumbnail() {
+    IN_FLIGHT.lock().unwrap().count -= 1;
+    IN_FLIGHT_CHANGED.notify_all();
+}
+
+/// Stop starting new thumbnails, and wait for the running ones to finish.
+///
+/// Returns whether they finished before `timeout`.
+pub fn shut_down(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    in_flight.is_shutting_down = true;
+    while in_flight.count > 0 {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::ZERO {
+            return false;
+        }
+        in_flight = IN_FLIGHT_CHANGED.wait_timeout(in_flight, remaining).unwrap().0;
+    }
+    true
+}
+
+/// The number of threads that generate thumbnails, 0 for one per CPU.
+///
+/// This is the `thumbnail_threads` setting. It can change when the config is
+/// reloaded, it takes effect at the next scan.
+static MAX_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the number of thumbnail generation threads, `None` for one per CPU.
+pub fn set_max_threads(n: Option<usize>) {
+    MAX_THREADS.store(n.unwrap_or(0), Ordering::Relaxed);
+}
+
 /// Tracks the process of generating a thumbnail.
 struct GenThumb<'a> {
     album_id: AlbumId,
@@ -257,6 +317,29 @@ impl<'a> GenThumbs<'a> {
     }
 }
 
+/// Delete thumbnails of albums that are no longer in the index.
+///
+/// When all files of an album are deleted, the foreign key cascades the delete
+/// to its thumbnail. But when an album's id changes, for example after a tag
+/// edit, the files still exist and the old thumbnail lingers. Returns the
+/// number of thumbnails deleted.
+pub fn delete_stale_thumbnails(
+    tx: &mut Transaction,
+    index: &MemoryMetaIndex,
+) -> Result<u32> {
+    let mut stale = Vec::new();
+    for opt_album_id in database::iter_thumbnail_album_ids(tx)? {
+        let album_id = AlbumId(opt_album_id? as u64);
+        if index.get_album(album_id).is_none() {
+            stale.push(album_id);
+        }
+    }
+    for album_id in &stale {
+        database::delete_thumbnail(tx, album_id.0 as i64)?;
+    }
+    Ok(stale.len() as u32)
+}
+
 pub fn generate_thumbnails(
     index: &MemoryMetaIndex,
     db_path: &Path,
@@ -305,14 +388,18 @@ pub fn generate_thumbnails(
     let mutex = Mutex::new(queue);
     let mutex_ref = &mutex;
 
-    // Start 1 + `num_cpus` worker threads. All these threads will do is block
-    // and wait on IO or the external process, but both `convert` and `guetzli`
-    // are CPU-bound, so this should keep the CPU busy. When thumbnailing many
+    // Start `num_cpus` worker threads, unless `thumbnail_threads` says
+    // otherwise. All these threads will do is block and wait on IO or the
+    // external process, but both `convert` and `guetzli` are CPU-bound, so
+    // this should keep the CPU busy. When thumbnailing many
     // albums with a cold page cache, IO to read the thumb from the file can be
     // a factor too, so add one additional thread to ensure we can keep the CPU
     // busy. Edit: Or not, usually it's not needed.
     crossbeam::scope::<_, Result<()>>(|scope| {
-        let n_threads = num_cpus::get();
+        let n_threads = match MAX_THREADS.load(Ordering::Relaxed) {
+            0 => num_cpus::get(),
+            n => n,
+        };
         let mut threads: Vec<crossbeam::ScopedJoinHandle<Result<()>>> =
             Vec::with_capacity(n_threads);
 
@@ -327,13 +414,30 @@ pub fn generate_thumbnails(
                     let mut tasks = mutex_ref.lock().unwrap();
                     tasks.pop()
                 } {
-                    let result = task
-                        .advance(&mut conn)
-                        // There is no simple way with the current version of
-                        // Crossbeam to get a result out of the thread, so we
-                        // just panic on error, it's what we would do elsewhere
-                        // anyway if we could get the result out.
-                        .expect("Thumbnail generation failed.");
+                    let album_id = task.album_id;
+
+                    // A pending task has no child process yet. When we are
+                    // shutting down, we drop it, the next scan can pick it up.
+                    let is_pending = matches!(task.state, GenThumbState::Pending { .. });
+                    if is_pending && !try_start_thumbnail() {
+                        continue;
+                    }
+
+                    let result = match task.advance(&mut conn) {
+                        Ok(result) => result,
+                        // A broken cover should not take down the scan. The
+                        // album has no thumbnail then, and we try again at the
+                        // next scan.
+                        Err(err) => {
+                            warn!(album_id = %album_id, "Failed to generate thumbnail: {:?}", err);
+                            metrics::count_thumbnail_failure();
+                            None
+                        }
+                    };
+
+                    if result.is_none() {
+                        finish_thumbnail();
+                    }
 
                     mutex_ref.lock().unwrap().put(result);
                 }
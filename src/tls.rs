@@ -0,0 +1,27 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Loading the TLS certificate, and reloading it on SIGHUP.
+//!
+//! Certificates from for example Let's Encrypt are valid for a few months, so
+//! we need to be able to swap them without restarting playback. The server
+//! reloads the certificate on SIGHUP, together with the config, see `reload.rs`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use tiny_http::SslConfig;
+
+/// Read the PEM-encoded certificate chain and private key.
+pub fn load_ssl_config(certificate_path: &Path, private_key_path: &Path) -> io::Result<SslConfig> {
+    let config = SslConfig {
+        certificate: fs::read(certificate_path)?,
+        private_key: fs::read(private_key_path)?,
+    };
+    Ok(config)
+}
@@ -0,0 +1,192 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Transcoding tracks to a lossy format for streaming.
+//!
+//! Streaming flac over mobile data is wasteful, and not every browser plays
+//! flac. Like thumbnail generation shells out to ImageMagick, we transcode with
+//! an `ffmpeg` child process, and stream its stdout to the client as it is
+//! produced. The child is killed when the response is dropped, for example
+//! because the client went away.
+
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Codec {
+    Opus,
+    Mp3,
+}
+
+impl FromStr for Codec {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Codec, &'static str> {
+        match s {
+            "opus" => Ok(Codec::Opus),
+            "mp3" => Ok(Codec::Mp3),
+            _ => Err("Invalid format, must be 'opus' or 'mp3'."),
+        }
+    }
+}
+
+impl Codec {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Codec::Opus => "audio/ogg",
+            Codec::Mp3 => "audio/mpeg",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::Opus => "opus",
+            Codec::Mp3 => "mp3",
+        }
+    }
+
+    /// The bitrate to use when the request does not specify one.
+    fn default_bitrate_kbps(&self) -> u32 {
+        match self {
+            // Opus is transparent for most material at 128 kbps.
+            Codec::Opus => 128,
+            Codec::Mp3 => 192,
+        }
+    }
+}
+
+/// A codec and bitrate to transcode to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Format {
+    pub codec: Codec,
+    pub bitrate_kbps: u32,
+}
+
+impl Format {
+    /// Create a format, with the default bitrate for the codec if none is given.
+    pub fn new(codec: Codec, bitrate_kbps: Option<u32>) -> std::result::Result<Format, &'static str> {
+        let bitrate_kbps = bitrate_kbps.unwrap_or_else(|| codec.default_bitrate_kbps());
+        if !(32..=320).contains(&bitrate_kbps) {
+            return Err("Invalid bitrate, must be between 32 and 320 kbps.");
+        }
+        let format = Format {
+            codec: codec,
+            bitrate_kbps: bitrate_kbps,
+        };
+        Ok(format)
+    }
+}
+
+/// A named format from the config file, for clients to refer to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub format: Format,
+}
+
+impl FromStr for Profile {
+    type Err = &'static str;
+
+    /// Parse a profile from the form `name codec bitrate`.
+    fn from_str(s: &str) -> std::result::Result<Profile, &'static str> {
+        let mut parts = s.split_whitespace();
+        let (name, codec, bitrate) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(codec), Some(bitrate), None) => (name, codec, bitrate),
+            _ => return Err("Invalid transcode_profile value, expected 'name codec bitrate'."),
+        };
+        let bitrate_kbps = match u32::from_str(bitrate) {
+            Ok(b) => b,
+            Err(..) => return Err("Invalid transcode_profile bitrate, must be an integer."),
+        };
+        let profile = Profile {
+            name: name.to_string(),
+            format: Format::new(Codec::from_str(codec)?, Some(bitrate_kbps))?,
+        };
+        Ok(profile)
+    }
+}
+
+/// Reads the transcoded audio from the `ffmpeg` child process.
+pub struct TranscodeReader {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for TranscodeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Drop for TranscodeReader {
+    fn drop(&mut self) {
+        // When the client disconnects halfway, ffmpeg would otherwise keep
+        // running until it fails to write. When it exited already, killing
+        // fails, which is fine. Waiting reaps the zombie process.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Start transcoding the flac file, return the reader for the output.
+pub fn spawn_transcode(flac_filename: &Path, format: Format) -> Result<TranscodeReader> {
+    let (encoder, container) = match format.codec {
+        Codec::Opus => ("libopus", "ogg"),
+        Codec::Mp3 => ("libmp3lame", "mp3"),
+    };
+    let mut child = Command::new("ffmpeg")
+        .args(["-nostdin", "-loglevel", "error"])
+        .arg("-i")
+        .arg(flac_filename)
+        // Only the audio, flac files can contain cover art, which ffmpeg
+        // would otherwise try to include as a video stream.
+        .args(["-map", "0:a"])
+        .args(["-codec:a", encoder])
+        .arg("-b:a")
+        .arg(format!("{}k", format.bitrate_kbps))
+        .args(["-f", container])
+        .arg("-")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::CommandError("Failed to spawn 'ffmpeg'.", e))?;
+
+    let stdout = child.stdout.take().expect("Stdout should be there, we piped it.");
+
+    let reader = TranscodeReader {
+        child: child,
+        stdout: stdout,
+    };
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Codec, Format, Profile};
+    use std::str::FromStr;
+
+    #[test]
+    fn profile_parses_name_codec_and_bitrate() {
+        let profile = Profile::from_str("mobile opus 96").unwrap();
+        assert_eq!(profile.name, "mobile");
+        assert_eq!(profile.format, Format { codec: Codec::Opus, bitrate_kbps: 96 });
+        assert!(Profile::from_str("mobile aac 96").is_err());
+        assert!(Profile::from_str("mobile opus 8").is_err());
+        assert!(Profile::from_str("mobile opus").is_err());
+    }
+
+    #[test]
+    fn format_uses_default_bitrate_per_codec() {
+        assert_eq!(Format::new(Codec::Opus, None).unwrap().bitrate_kbps, 128);
+        assert_eq!(Format::new(Codec::Mp3, None).unwrap().bitrate_kbps, 192);
+    }
+}
@@ -0,0 +1,561 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A terminal interface to a running server, for `musium tui`.
+//!
+//! It talks to the server through the API, see `client.rs`, so it works on a
+//! box that is only reachable over SSH, and against remote servers. We draw
+//! with ratatui on top of crossterm. We redraw the full screen after every key
+//! press, when the terminal is resized, and every second to update the
+//! playback position.
+
+use std::io;
+use std::panic;
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, execute, terminal};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{self, Gauge, ListItem, ListState, Paragraph, Tabs};
+use ratatui::{Frame, Terminal};
+use serde_json::Value;
+
+use crate::client::{encode_query_value, format_duration, Client};
+use crate::error::{Error, Result};
+
+/// Leave the alternate screen, show the cursor, and leave raw mode.
+fn restore_terminal() {
+    let _ = execute!(io::stdout(), terminal::LeaveAlternateScreen, cursor::Show);
+    let _ = terminal::disable_raw_mode();
+}
+
+/// Puts the terminal in raw mode on the alternate screen, restores it on drop.
+///
+/// We build with `panic = "abort"`, so on a panic, destructors don't run. A
+/// panic hook restores the terminal instead, before the default hook prints the
+/// message, otherwise the message would be lost with the alternate screen, and
+/// the shell would be left in raw mode.
+struct RawMode;
+
+impl RawMode {
+    fn enter() -> io::Result<RawMode> {
+        terminal::enable_raw_mode()?;
+        // From here on, the drop of the guard restores the terminal.
+        let raw_mode = RawMode;
+
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            previous_hook(info);
+        }));
+
+        execute!(io::stdout(), terminal::EnterAlternateScreen)?;
+        Ok(raw_mode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        restore_terminal();
+        // Drop our hook, this reinstates the default one, `musium tui` does
+        // not install any other.
+        let _ = panic::take_hook();
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Key {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Enter,
+    Escape,
+    Backspace,
+    Tab,
+    Char(char),
+}
+
+/// Convert a crossterm key event into a key that we handle.
+fn convert_key(event: KeyEvent) -> Option<Key> {
+    // On some platforms we get releases too, we act on presses only.
+    if event.kind != KeyEventKind::Press {
+        return None;
+    }
+    let key = match event.code {
+        KeyCode::Up => Key::Up,
+        KeyCode::Down => Key::Down,
+        KeyCode::PageUp => Key::PageUp,
+        KeyCode::PageDown => Key::PageDown,
+        KeyCode::Enter => Key::Enter,
+        KeyCode::Esc => Key::Escape,
+        KeyCode::Backspace => Key::Backspace,
+        KeyCode::Tab => Key::Tab,
+        // Control combinations are not bound to anything.
+        KeyCode::Char(_) if event.modifiers.contains(KeyModifiers::CONTROL) => return None,
+        KeyCode::Char(ch) => Key::Char(ch),
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// What the main loop woke up for.
+enum Input {
+    Key(Key),
+    /// The terminal was resized, or an event came in that we don't handle.
+    Redraw,
+    /// Nothing happened within the timeout.
+    Timeout,
+}
+
+/// Wait for input, for at most the timeout.
+fn read_input(timeout: Duration) -> io::Result<Input> {
+    if !event::poll(timeout)? {
+        return Ok(Input::Timeout);
+    }
+    let input = match event::read()? {
+        Event::Key(key_event) => match convert_key(key_event) {
+            Some(key) => Input::Key(key),
+            None => Input::Redraw,
+        },
+        // Ratatui resizes its buffers on the next draw.
+        _ => Input::Redraw,
+    };
+    Ok(input)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum View {
+    Library,
+    Album,
+    Search,
+    Queue,
+}
+
+/// What a line in a list refers to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Target {
+    Album(String),
+    Track(String),
+    QueueEntry(String),
+}
+
+struct Item {
+    target: Target,
+    label: String,
+}
+
+/// A list with a cursor.
+#[derive(Default)]
+struct List {
+    items: Vec<Item>,
+    cursor: usize,
+}
+
+impl List {
+    fn set_items(&mut self, items: Vec<Item>) {
+        self.items = items;
+        self.cursor = self.cursor.min(self.items.len().saturating_sub(1));
+    }
+
+    fn move_by(&mut self, delta: isize) {
+        let max = self.items.len().saturating_sub(1) as isize;
+        self.cursor = (self.cursor as isize + delta).clamp(0, max) as usize;
+    }
+
+    fn selected(&self) -> Option<&Target> {
+        self.items.get(self.cursor).map(|item| &item.target)
+    }
+}
+
+/// The currently playing track, from `/api/player`.
+struct NowPlaying {
+    title: String,
+    artist: String,
+    position_seconds: f64,
+    duration_seconds: f64,
+}
+
+fn get_str<'a>(v: &'a Value, key: &str) -> &'a str {
+    v.get(key).and_then(|x| x.as_str()).unwrap_or("")
+}
+
+fn get_f64(v: &Value, key: &str) -> f64 {
+    v.get(key).and_then(|x| x.as_f64()).unwrap_or(0.0)
+}
+
+fn get_array<'a>(v: &'a Value, key: &str) -> &'a [Value] {
+    v.get(key).and_then(|x| x.as_array()).map(|a| &a[..]).unwrap_or(&[])
+}
+
+fn album_item(album: &Value) -> Item {
+    let year = get_str(album, "release_date").get(..4).unwrap_or("");
+    Item {
+        target: Target::Album(get_str(album, "id").to_string()),
+        label: format!("{} — {} ({})", get_str(album, "artist"), get_str(album, "title"), year),
+    }
+}
+
+/// Label a track or radio station in the queue or the now playing line.
+fn queue_label(entry: &Value) -> (String, String) {
+    match entry.get("station") {
+        Some(..) => (get_str(entry, "stream_title").to_string(), get_str(entry, "station").to_string()),
+        None => (get_str(entry, "title").to_string(), get_str(entry, "artist").to_string()),
+    }
+}
+
+fn describe_error(err: &Error) -> String {
+    match err {
+        Error::ClientError(msg) => msg.clone(),
+        err => format!("{:?}", err),
+    }
+}
+
+struct App {
+    client: Client,
+    view: View,
+    library: List,
+    album: List,
+    album_title: String,
+    search: List,
+    query: String,
+    is_typing: bool,
+    queue: List,
+    now_playing: Option<NowPlaying>,
+    message: String,
+}
+
+impl App {
+    fn new(client: Client) -> App {
+        App {
+            client: client,
+            view: View::Library,
+            library: List::default(),
+            album: List::default(),
+            album_title: String::new(),
+            search: List::default(),
+            query: String::new(),
+            is_typing: false,
+            queue: List::default(),
+            now_playing: None,
+            message: String::new(),
+        }
+    }
+
+    fn current_list(&mut self) -> &mut List {
+        match self.view {
+            View::Library => &mut self.library,
+            View::Album => &mut self.album,
+            View::Search => &mut self.search,
+            View::Queue => &mut self.queue,
+        }
+    }
+
+    fn load_library(&mut self) -> Result<()> {
+        let albums = self.client.get("albums?sort=name")?;
+        let items = albums.as_array().map(|a| &a[..]).unwrap_or(&[]).iter().map(album_item).collect();
+        self.library.set_items(items);
+        Ok(())
+    }
+
+    fn open_album(&mut self, album_id: &str) -> Result<()> {
+        let album = self.client.get(&format!("album/{}", album_id))?;
+        self.album_title = format!("{} — {}", get_str(&album, "artist"), get_str(&album, "title"));
+        let items = get_array(&album, "tracks")
+            .iter()
+            .map(|track| Item {
+                target: Target::Track(get_str(track, "id").to_string()),
+                label: format!(
+                    "{:>2}  {} — {}  {}",
+                    track.get("track_number").and_then(|n| n.as_u64()).unwrap_or(0),
+                    get_str(track, "title"),
+                    get_str(track, "artist"),
+                    format_duration(get_f64(track, "duration_seconds") as u64),
+                ),
+            })
+            .collect();
+        self.album = List::default();
+        self.album.set_items(items);
+        self.view = View::Album;
+        Ok(())
+    }
+
+    fn run_search(&mut self) -> Result<()> {
+        let results = self.client.get(&format!("search?q={}", encode_query_value(&self.query)))?;
+        let mut items: Vec<Item> = get_array(&results, "albums").iter().map(album_item).collect();
+        items.extend(get_array(&results, "tracks").iter().map(|track| Item {
+            target: Target::Track(get_str(track, "id").to_string()),
+            label: format!(
+                "♪ {} — {} ({})",
+                get_str(track, "artist"),
+                get_str(track, "title"),
+                get_str(track, "album"),
+            ),
+        }));
+        self.search = List::default();
+        self.search.set_items(items);
+        Ok(())
+    }
+
+    fn load_queue(&mut self) -> Result<()> {
+        let queue = self.client.get("queue")?;
+        let items = queue
+            .as_array()
+            .map(|a| &a[..])
+            .unwrap_or(&[])
+            .iter()
+            .map(|entry| {
+                let (title, artist) = queue_label(entry);
+                Item {
+                    target: Target::QueueEntry(get_str(entry, "queue_id").to_string()),
+                    label: format!("{} — {}", artist, title),
+                }
+            })
+            .collect();
+        self.queue.set_items(items);
+        Ok(())
+    }
+
+    fn load_now_playing(&mut self) -> Result<()> {
+        let player = self.client.get("player")?;
+        self.now_playing = player.get("current").filter(|c| !c.is_null()).map(|current| {
+            let (title, artist) = queue_label(current);
+            NowPlaying {
+                title: title,
+                artist: artist,
+                position_seconds: get_f64(current, "position_seconds"),
+                duration_seconds: get_f64(current, "duration_seconds"),
+            }
+        });
+        Ok(())
+    }
+
+    fn enqueue_track(&mut self, track_id: &str) -> Result<()> {
+        self.client.put(&format!("queue/{}?client=tui", track_id))?;
+        self.message = "Enqueued.".to_string();
+        Ok(())
+    }
+
+    fn enqueue_album(&mut self, album_id: &str) -> Result<()> {
+        let album = self.client.get(&format!("album/{}", album_id))?;
+        let tracks = get_array(&album, "tracks");
+        for track in tracks {
+            self.client.put(&format!("queue/{}?client=tui", get_str(track, "id")))?;
+        }
+        self.message = format!("Enqueued {} tracks.", tracks.len());
+        Ok(())
+    }
+
+    /// Handle a key press, return whether to keep running.
+    fn handle_key(&mut self, key: Key) -> Result<bool> {
+        if self.is_typing {
+            match key {
+                Key::Enter => {
+                    self.is_typing = false;
+                    self.run_search()?;
+                }
+                Key::Escape => self.is_typing = false,
+                Key::Backspace => {
+                    self.query.pop();
+                }
+                Key::Char(ch) if !ch.is_control() => self.query.push(ch),
+                _ => {}
+            }
+            return Ok(true);
+        }
+
+        self.message.clear();
+        match key {
+            Key::Char('q') => return Ok(false),
+            Key::Up | Key::Char('k') => self.current_list().move_by(-1),
+            Key::Down | Key::Char('j') => self.current_list().move_by(1),
+            Key::PageUp => self.current_list().move_by(-10),
+            Key::PageDown => self.current_list().move_by(10),
+            Key::Tab => {
+                self.view = match self.view {
+                    View::Queue => View::Library,
+                    _ => View::Queue,
+                };
+                if self.view == View::Queue {
+                    self.load_queue()?;
+                }
+            }
+            Key::Char('/') => {
+                self.view = View::Search;
+                self.is_typing = true;
+                self.query.clear();
+            }
+            Key::Escape => self.view = View::Library,
+            Key::Char('n') => {
+                self.client.post("queue/skip")?;
+                self.load_queue()?;
+            }
+            Key::Enter => match self.current_list().selected().cloned() {
+                Some(Target::Album(id)) => self.open_album(&id)?,
+                Some(Target::Track(id)) => self.enqueue_track(&id)?,
+                _ => {}
+            },
+            Key::Char('a') => match self.current_list().selected().cloned() {
+                Some(Target::Album(id)) => self.enqueue_album(&id)?,
+                Some(Target::Track(id)) => self.enqueue_track(&id)?,
+                _ => {}
+            },
+            Key::Char('d') => {
+                if let Some(Target::QueueEntry(id)) = self.current_list().selected().cloned() {
+                    self.client.delete(&format!("queue/{}", id))?;
+                    self.load_queue()?;
+                }
+            }
+            Key::Char('c') if self.view == View::Queue => {
+                self.client.post("queue/clear")?;
+                self.load_queue()?;
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn draw(&mut self, frame: &mut Frame<CrosstermBackend<io::Stdout>>) {
+        // A line for the tabs and the subtitle at the top, three lines for
+        // now playing and help at the bottom, the list gets the rest.
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(1),
+                Constraint::Length(1),
+                Constraint::Min(0),
+                Constraint::Length(1),
+                Constraint::Length(1),
+                Constraint::Length(1),
+            ])
+            .split(frame.size());
+        let reversed = Style::default().add_modifier(Modifier::REVERSED);
+
+        let tab = match self.view {
+            View::Library | View::Album => 0,
+            View::Search => 1,
+            View::Queue => 2,
+        };
+        let tabs = Tabs::new(vec!["Library", "Search", "Queue"]).select(tab).highlight_style(reversed);
+        frame.render_widget(tabs, rows[0]);
+
+        let subtitle = match self.view {
+            View::Library => format!("{} albums", self.library.items.len()),
+            View::Album => self.album_title.clone(),
+            View::Search if self.is_typing => format!("/{}▏", self.query),
+            View::Search => format!("/{}", self.query),
+            View::Queue => format!("{} in queue", self.queue.items.len()),
+        };
+        frame.render_widget(Paragraph::new(subtitle), rows[1]);
+
+        let list = self.current_list();
+        let items: Vec<ListItem> = list.items.iter().map(|item| ListItem::new(item.label.as_str())).collect();
+        let mut state = ListState::default();
+        if !list.items.is_empty() {
+            state.select(Some(list.cursor));
+        }
+        // The list scrolls by itself to keep the selected item in view.
+        frame.render_stateful_widget(widgets::List::new(items).highlight_style(reversed), rows[2], &mut state);
+
+        match self.now_playing.as_ref() {
+            Some(np) => {
+                let title = format!("▶ {} — {}", np.artist, np.title);
+                frame.render_widget(Paragraph::new(title), rows[3]);
+                let ratio = match np.duration_seconds {
+                    d if d > 0.0 => (np.position_seconds / d).clamp(0.0, 1.0),
+                    _ => 0.0,
+                };
+                let times = format!(
+                    "{} / {}",
+                    format_duration(np.position_seconds as u64),
+                    format_duration(np.duration_seconds as u64),
+                );
+                frame.render_widget(Gauge::default().ratio(ratio).label(times), rows[4]);
+            }
+            None => frame.render_widget(Paragraph::new("Nothing playing."), rows[3]),
+        }
+
+        let help = match self.message.as_str() {
+            "" => "enter open/enqueue  a enqueue album  / search  tab queue  d dequeue  n skip  q quit",
+            msg => msg,
+        };
+        frame.render_widget(Paragraph::new(help), rows[5]);
+    }
+}
+
+/// Run the terminal interface until the user quits.
+pub fn main(client: Client) -> Result<()> {
+    let mut app = App::new(client);
+
+    // Check that we can reach the server before we take over the terminal,
+    // so the error is readable.
+    app.load_now_playing()?;
+    app.load_library()?;
+
+    let _raw_mode = RawMode::enter()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+
+        let result = match read_input(Duration::from_secs(1))? {
+            Input::Key(key) => match app.handle_key(key) {
+                Ok(true) => Ok(()),
+                Ok(false) => return Ok(()),
+                Err(err) => Err(err),
+            },
+            // After a resize, redraw without waiting for the server.
+            Input::Redraw => Ok(()),
+            // Without key presses, we refresh the playback position, and the
+            // queue when it's on screen, because tracks end.
+            Input::Timeout if app.view == View::Queue => app.load_now_playing().and_then(|()| app.load_queue()),
+            Input::Timeout => app.load_now_playing(),
+        };
+
+        if let Err(err) = result {
+            app.message = describe_error(&err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers};
+
+    use super::{convert_key, Key};
+
+    fn press(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent {
+            code: code,
+            modifiers: modifiers,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        }
+    }
+
+    #[test]
+    fn convert_key_maps_presses() {
+        assert_eq!(convert_key(press(KeyCode::Up, KeyModifiers::NONE)), Some(Key::Up));
+        assert_eq!(convert_key(press(KeyCode::PageDown, KeyModifiers::NONE)), Some(Key::PageDown));
+        assert_eq!(convert_key(press(KeyCode::Esc, KeyModifiers::NONE)), Some(Key::Escape));
+        assert_eq!(convert_key(press(KeyCode::Enter, KeyModifiers::NONE)), Some(Key::Enter));
+        assert_eq!(convert_key(press(KeyCode::Char('ó'), KeyModifiers::SHIFT)), Some(Key::Char('ó')));
+        assert_eq!(convert_key(press(KeyCode::F(5), KeyModifiers::NONE)), None);
+    }
+
+    #[test]
+    fn convert_key_ignores_releases_and_control() {
+        assert_eq!(convert_key(press(KeyCode::Char('c'), KeyModifiers::CONTROL)), None);
+        let release = KeyEvent {
+            kind: KeyEventKind::Release,
+            ..press(KeyCode::Char('q'), KeyModifiers::NONE)
+        };
+        assert_eq!(convert_key(release), None);
+    }
+}
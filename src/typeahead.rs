@@ -0,0 +1,131 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Search-as-you-type with a few results per category.
+//!
+//! The regular search returns up to 250 results per category, and tolerates
+//! typos, which takes a linear scan over all words in the index. For a search
+//! box that updates on every keystroke, we want an answer in a few
+//! milliseconds, so the typeahead search only looks up the query words in the
+//! word indexes, which already hold the normalized words of all titles and
+//! names, and returns at most `limit` results per category.
+//!
+//! To let clients show why a result matched, we report the ranges of the
+//! displayed strings that match a query word. We only compute those for the
+//! few results that we return.
+
+use crate::string_utils::normalize_words;
+
+/// The number of results per category if the request does not specify it.
+pub const DEFAULT_LIMIT: usize = 5;
+
+/// The maximum number of results per category that we return.
+pub const MAX_LIMIT: usize = 25;
+
+/// A half-open range of a displayed string, in UTF-16 code units.
+///
+/// We count in UTF-16 code units, because that is what string indices in
+/// Javascript count, so clients can pass the range to `String.slice`.
+pub type Range = (usize, usize);
+
+/// Return whether `ch` separates words, like it does in `normalize_words`.
+///
+/// Apart from whitespace, these characters also form a word of their own.
+fn is_cut(ch: char) -> bool {
+    ch.is_whitespace() || "/\\@_+-:;!?<>¿¡–—✝∞¥".contains(ch)
+}
+
+/// Return whether any of the normalized words matches the query.
+///
+/// Like in `search::search`, all query words but the last one need to match
+/// exactly, and the last one can be a prefix.
+fn matches(normalized: &[String], query: &[String]) -> bool {
+    let (prefix_word, full_words) = match query.split_last() {
+        Some(split) => split,
+        None => return false,
+    };
+    normalized.iter().any(|word| {
+        word.starts_with(prefix_word.as_str()) || full_words.iter().any(|q| q == word)
+    })
+}
+
+/// Return the ranges of `display` that match a word of the normalized query.
+///
+/// The ranges are ordered and do not overlap.
+pub fn highlight(display: &str, query: &[String]) -> Vec<Range> {
+    let mut ranges = Vec::new();
+    let mut normalized = Vec::new();
+
+    // For a segment of the string, check whether it matches, and if so, record
+    // its range. Positions are pairs of (byte offset, UTF-16 offset).
+    let mut push_segment = |begin: (usize, usize), end: (usize, usize)| {
+        if begin.0 == end.0 {
+            return;
+        }
+        normalized.clear();
+        normalize_words(&display[begin.0..end.0], &mut normalized);
+        if matches(&normalized, query) {
+            ranges.push((begin.1, end.1));
+        }
+    };
+
+    let mut begin = (0, 0);
+    let mut offset_utf16 = 0;
+
+    for (i, ch) in display.char_indices() {
+        let next_utf16 = offset_utf16 + ch.len_utf16();
+        if is_cut(ch) {
+            let at = (i, offset_utf16);
+            let next = (i + ch.len_utf8(), next_utf16);
+            push_segment(begin, at);
+            if !ch.is_whitespace() {
+                push_segment(at, next);
+            }
+            begin = next;
+        }
+        offset_utf16 = next_utf16;
+    }
+
+    push_segment(begin, (display.len(), offset_utf16));
+
+    ranges
+}
+
+#[cfg(test)]
+mod test {
+    use super::highlight;
+    use crate::string_utils::normalize_words;
+
+    fn highlight_query(display: &str, query: &str) -> Vec<(usize, usize)> {
+        let mut words = Vec::new();
+        normalize_words(query, &mut words);
+        highlight(display, &words)
+    }
+
+    #[test]
+    fn highlight_matches_full_words_and_last_prefix() {
+        assert_eq!(highlight_query("Sigur Rós", "sigur ro"), vec![(0, 5), (6, 9)]);
+        assert_eq!(highlight_query("Sigur Rós", "sig"), vec![(0, 5)]);
+        // Only the last word may be a prefix.
+        assert_eq!(highlight_query("Sigur Rós", "sig ros"), vec![(6, 9)]);
+        assert_eq!(highlight_query("Sigur Rós", "x"), vec![]);
+    }
+
+    #[test]
+    fn highlight_splits_at_punctuation() {
+        assert_eq!(highlight_query("AC/DC", "dc"), vec![(3, 5)]);
+        assert_eq!(highlight_query("S.P.Y", "spy"), vec![(0, 5)]);
+        assert_eq!(highlight_query("Wait – What?", "what"), vec![(7, 11)]);
+    }
+
+    #[test]
+    fn highlight_counts_utf16_code_units() {
+        // Characters outside the Basic Multilingual Plane take two code units.
+        assert_eq!(highlight_query("𝔄𝔅 Clef", "clef"), vec![(5, 9)]);
+        assert_eq!(highlight_query("𝔄𝔅 Clef", "ab"), vec![(0, 4)]);
+    }
+}
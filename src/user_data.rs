@@ -17,15 +17,13 @@
 //! playcount and rating. Unlike the data in the index, this user data is
 //! mutable, it can change during the lifetime of the server.
 //!
-//! This module is concerned with that mutable user data.
-
-// TODO: Remove once we add playcounts.
-#![allow(dead_code)]
+//! This module is concerned with that mutable user data. When several people
+//! share a server, each of them has their own, see `UserDataSet`.
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
 
-use crate::prim::{AlbumId, ArtistId, TrackId};
+use crate::prim::{AlbumId, ArtistId, Instant, TrackId};
 use crate::{database as db};
 
 /// Track rating.
@@ -67,17 +65,27 @@ impl TryFrom<i64> for Rating {
 #[derive(Default)]
 pub struct TrackState {
     rating: Rating,
-    // TODO: Add playcount.
+    play_count: u32,
+    last_played: Option<Instant>,
+    /// Whether shuffling passes over this track, see `is_track_excluded`.
+    excluded: bool,
 }
 
 #[derive(Default)]
 pub struct AlbumState {
-    // TODO: Add playcount and last/first seen/played.
+    rating: Rating,
+    excluded: bool,
+    /// The number of listens of tracks on this album.
+    play_count: u32,
+    last_played: Option<Instant>,
 }
 
 #[derive(Default)]
 pub struct ArtistState {
-    // TODO: Add playcount.
+    rating: Rating,
+    /// The number of listens of albums by this (first) album artist.
+    play_count: u32,
+    last_played: Option<Instant>,
 }
 
 /// Mutable metadata for tracks, albums, and artists, stemming from user usage.
@@ -85,6 +93,9 @@ pub struct UserData {
     tracks: HashMap<TrackId, TrackState>,
     albums: HashMap<AlbumId, AlbumState>,
     artists: HashMap<ArtistId, ArtistState>,
+
+    /// Incremented on every change, see `version()`.
+    version: u64,
 }
 
 impl Default for UserData {
@@ -96,6 +107,7 @@ impl Default for UserData {
             tracks: HashMap::with_hasher(s.clone()),
             albums: HashMap::with_hasher(s.clone()),
             artists: HashMap::with_hasher(s),
+            version: 0,
         }
     }
 
@@ -106,25 +118,305 @@ impl UserData {
         Self::default()
     }
 
-    /// Rebuild the user data from events saved in the database.
+    /// Forget all play counts and last played times, before a reload.
+    fn clear_play_stats(&mut self) {
+        self.version += 1;
+
+        for track in self.tracks.values_mut() {
+            track.play_count = 0;
+            track.last_played = None;
+        }
+        for album in self.albums.values_mut() {
+            album.play_count = 0;
+            album.last_played = None;
+        }
+        for artist in self.artists.values_mut() {
+            artist.play_count = 0;
+            artist.last_played = None;
+        }
+    }
+
+    /// Return a number that changes whenever the user data changes.
+    ///
+    /// It starts over when the server restarts, so on its own it is not a
+    /// good version for clients, see also `generation.rs`.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn set_track_rating(&mut self, track_id: TrackId, rating: Rating) {
+        self.version += 1;
+        self.tracks.entry(track_id).or_default().rating = rating;
+    }
+
+    pub fn get_track_rating(&self, track_id: TrackId) -> Rating {
+        self.tracks.get(&track_id).map(|t| t.rating).unwrap_or_default()
+    }
+
+    /// Return the loved tracks, ordered by track id.
+    pub fn get_loved_tracks(&self) -> Vec<TrackId> {
+        let mut result: Vec<TrackId> = self
+            .tracks
+            .iter()
+            .filter(|(_, state)| state.rating == Rating::Love)
+            .map(|(track_id, _)| *track_id)
+            .collect();
+        result.sort();
+        result
+    }
+
+    /// Record that playback of the track started at the given time.
+    ///
+    /// The listen counts towards the track, its album, and the first album
+    /// artist, like the listens in the database.
+    pub fn add_listen(&mut self, track_id: TrackId, album_artist_id: ArtistId, started_at: Instant) {
+        self.version += 1;
+
+        let track = self.tracks.entry(track_id).or_default();
+        track.play_count += 1;
+        track.last_played = Some(started_at);
+
+        let album = self.albums.entry(track_id.album_id()).or_default();
+        album.play_count += 1;
+        album.last_played = Some(started_at);
+
+        let artist = self.artists.entry(album_artist_id).or_default();
+        artist.play_count += 1;
+        artist.last_played = Some(started_at);
+    }
+
+    /// Return the number of times playback of the track was started.
+    pub fn get_track_play_count(&self, track_id: TrackId) -> u32 {
+        self.tracks.get(&track_id).map(|t| t.play_count).unwrap_or(0)
+    }
+
+    /// Return the time of the most recent listen of the track, if any.
+    pub fn get_track_last_played(&self, track_id: TrackId) -> Option<Instant> {
+        self.tracks.get(&track_id).and_then(|t| t.last_played)
+    }
+
+    pub fn set_track_excluded(&mut self, track_id: TrackId, excluded: bool) {
+        self.version += 1;
+        self.tracks.entry(track_id).or_default().excluded = excluded;
+    }
+
+    /// Return whether shuffling should pass over the track.
+    ///
+    /// This is the case when the track itself is excluded, or its album. The
+    /// track stays in the library, and it plays when enqueued explicitly, this
+    /// is for the one skit on an otherwise great album.
+    pub fn is_track_excluded(&self, track_id: TrackId) -> bool {
+        self.tracks.get(&track_id).map(|t| t.excluded).unwrap_or(false)
+            || self.is_album_excluded(track_id.album_id())
+    }
+
+    pub fn set_album_rating(&mut self, album_id: AlbumId, rating: Rating) {
+        self.version += 1;
+        self.albums.entry(album_id).or_default().rating = rating;
+    }
+
+    pub fn get_album_rating(&self, album_id: AlbumId) -> Rating {
+        self.albums.get(&album_id).map(|a| a.rating).unwrap_or_default()
+    }
+
+    pub fn set_album_excluded(&mut self, album_id: AlbumId, excluded: bool) {
+        self.version += 1;
+        self.albums.entry(album_id).or_default().excluded = excluded;
+    }
+
+    /// Return whether shuffling should pass over the album and its tracks.
+    pub fn is_album_excluded(&self, album_id: AlbumId) -> bool {
+        self.albums.get(&album_id).map(|a| a.excluded).unwrap_or(false)
+    }
+
+    /// Return the number of listens of tracks on the album.
+    pub fn get_album_play_count(&self, album_id: AlbumId) -> u32 {
+        self.albums.get(&album_id).map(|a| a.play_count).unwrap_or(0)
+    }
+
+    /// Return the time of the most recent listen of a track on the album, if any.
+    pub fn get_album_last_played(&self, album_id: AlbumId) -> Option<Instant> {
+        self.albums.get(&album_id).and_then(|a| a.last_played)
+    }
+
+    pub fn set_artist_rating(&mut self, artist_id: ArtistId, rating: Rating) {
+        self.version += 1;
+        self.artists.entry(artist_id).or_default().rating = rating;
+    }
+
+    pub fn get_artist_rating(&self, artist_id: ArtistId) -> Rating {
+        self.artists.get(&artist_id).map(|a| a.rating).unwrap_or_default()
+    }
+
+    /// Return the number of listens of albums by the artist.
+    ///
+    /// Only the first album artist of an album gets credited for its listens.
+    pub fn get_artist_play_count(&self, artist_id: ArtistId) -> u32 {
+        self.artists.get(&artist_id).map(|a| a.play_count).unwrap_or(0)
+    }
+
+    /// Return the time of the most recent listen of an album by the artist, if any.
+    pub fn get_artist_last_played(&self, artist_id: ArtistId) -> Option<Instant> {
+        self.artists.get(&artist_id).and_then(|a| a.last_played)
+    }
+}
+
+/// The user data of every user, see also `auth::ApiToken::user`.
+///
+/// The default user is `None`. Users who did not listen or rate anything yet
+/// have no entry, they get an empty `UserData`.
+#[derive(Default)]
+pub struct UserDataSet {
+    default: UserData,
+    users: HashMap<String, UserData>,
+
+    /// Returned by `get` for users without an entry.
+    empty: UserData,
+}
+
+impl UserDataSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rebuild the user data of all users from events saved in the database.
     pub fn load_from_database(tx: &mut db::Transaction) -> db::Result<Self> {
         let mut stats = Self::default();
 
         for opt_rating in db::iter_ratings(tx)? {
             let rating = opt_rating?;
             let tid = TrackId(rating.track_id as u64);
+            let user = rating.user_name.as_deref();
             let rating = Rating::try_from(rating.rating).expect("Invalid rating value in the database.");
-            stats.set_track_rating(tid, rating);
+            stats.get_mut(user).set_track_rating(tid, rating);
         }
 
+        for opt_rating in db::iter_album_ratings(tx)? {
+            let rating = opt_rating?;
+            let aid = AlbumId(rating.album_id as u64);
+            let user = rating.user_name.as_deref();
+            let rating = Rating::try_from(rating.rating).expect("Invalid rating value in the database.");
+            stats.get_mut(user).set_album_rating(aid, rating);
+        }
+
+        for opt_rating in db::iter_artist_ratings(tx)? {
+            let rating = opt_rating?;
+            let aid = ArtistId(rating.artist_id as u64);
+            let user = rating.user_name.as_deref();
+            let rating = Rating::try_from(rating.rating).expect("Invalid rating value in the database.");
+            stats.get_mut(user).set_artist_rating(aid, rating);
+        }
+
+        for opt_exclusion in db::iter_track_exclusions(tx)? {
+            let (track_id, user) = opt_exclusion?;
+            stats.get_mut(user.as_deref()).set_track_excluded(TrackId(track_id as u64), true);
+        }
+
+        for opt_exclusion in db::iter_album_exclusions(tx)? {
+            let (album_id, user) = opt_exclusion?;
+            stats.get_mut(user.as_deref()).set_album_excluded(AlbumId(album_id as u64), true);
+        }
+
+        stats.reload_play_stats(tx)?;
+
         Ok(stats)
     }
 
-    pub fn set_track_rating(&mut self, track_id: TrackId, rating: Rating) {
-        self.tracks.entry(track_id).or_default().rating = rating;
+    /// Replace the play counts and last played times with those in the database.
+    ///
+    /// The history thread keeps the play counts up to date as listens happen,
+    /// but listens can also enter the database in other ways, for example when
+    /// importing them from an export.
+    pub fn reload_play_stats(&mut self, tx: &mut db::Transaction) -> db::Result<()> {
+        self.default.clear_play_stats();
+        for user_data in self.users.values_mut() {
+            user_data.clear_play_stats();
+        }
+
+        for opt_stats in db::iter_track_play_stats(tx)? {
+            let play_stats = opt_stats?;
+            let tid = TrackId(play_stats.track_id as u64);
+            let user_data = self.get_mut(play_stats.user_name.as_deref());
+            let track = user_data.tracks.entry(tid).or_default();
+            track.play_count = play_stats.play_count as u32;
+            track.last_played = Instant::from_iso8601(&play_stats.last_played_at);
+        }
+
+        for opt_stats in db::iter_album_play_stats(tx)? {
+            let play_stats = opt_stats?;
+            let aid = AlbumId(play_stats.album_id as u64);
+            let user_data = self.get_mut(play_stats.user_name.as_deref());
+            let album = user_data.albums.entry(aid).or_default();
+            album.play_count = play_stats.play_count as u32;
+            album.last_played = Instant::from_iso8601(&play_stats.last_played_at);
+        }
+
+        for opt_stats in db::iter_artist_play_stats(tx)? {
+            let play_stats = opt_stats?;
+            let aid = ArtistId(play_stats.album_artist_id as u64);
+            let user_data = self.get_mut(play_stats.user_name.as_deref());
+            let artist = user_data.artists.entry(aid).or_default();
+            artist.play_count = play_stats.play_count as u32;
+            artist.last_played = Instant::from_iso8601(&play_stats.last_played_at);
+        }
+
+        Ok(())
     }
 
-    pub fn get_track_rating(&self, track_id: TrackId) -> Rating {
-        self.tracks.get(&track_id).map(|t| t.rating).unwrap_or_default()
+    /// Return the user data of the user, `None` for the default user.
+    pub fn get(&self, user: Option<&str>) -> &UserData {
+        match user {
+            None => &self.default,
+            Some(name) => self.users.get(name).unwrap_or(&self.empty),
+        }
+    }
+
+    /// Return the user data of the user, creating it if needed.
+    pub fn get_mut(&mut self, user: Option<&str>) -> &mut UserData {
+        match user {
+            None => &mut self.default,
+            Some(name) => self.users.entry(name.to_string()).or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rating, UserDataSet};
+    use crate::prim::{ArtistId, Instant, TrackId};
+
+    #[test]
+    fn user_data_set_keeps_users_apart() {
+        let mut users = UserDataSet::new();
+        let track_id = TrackId(0x0000_0000_0010_0101);
+        let started_at = Instant { posix_seconds_utc: 1_700_000_000 };
+        users.get_mut(None).set_track_rating(track_id, Rating::Love);
+        users.get_mut(Some("alex")).add_listen(track_id, ArtistId(7), started_at);
+
+        assert_eq!(users.get(None).get_track_rating(track_id), Rating::Love);
+        assert_eq!(users.get(None).get_track_play_count(track_id), 0);
+        assert_eq!(users.get(Some("alex")).get_track_rating(track_id), Rating::Neutral);
+        assert_eq!(users.get(Some("alex")).get_track_play_count(track_id), 1);
+        assert_eq!(users.get(Some("sam")).get_track_play_count(track_id), 0);
+        assert_eq!(users.get(Some("sam")).get_loved_tracks(), []);
+    }
+
+    #[test]
+    fn excluding_an_album_excludes_its_tracks() {
+        let mut users = UserDataSet::new();
+        let track_id = TrackId(0x0000_0000_0010_0101);
+        let other_track_id = TrackId(0x0000_0000_0020_0101);
+        let user_data = users.get_mut(None);
+        assert!(!user_data.is_track_excluded(track_id));
+
+        user_data.set_album_excluded(track_id.album_id(), true);
+        assert!(user_data.is_track_excluded(track_id));
+        assert!(!user_data.is_track_excluded(other_track_id));
+
+        user_data.set_album_excluded(track_id.album_id(), false);
+        user_data.set_track_excluded(other_track_id, true);
+        assert!(!user_data.is_track_excluded(track_id));
+        assert!(user_data.is_track_excluded(other_track_id));
+        assert!(!users.get(Some("alex")).is_track_excluded(other_track_id));
     }
 }
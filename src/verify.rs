@@ -0,0 +1,308 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Verifying files against the MD5 of their audio, to detect bit rot.
+//!
+//! The streaminfo block of a flac file holds the MD5 of the decoded audio. We
+//! decode the entire file and hash the samples, if the hash differs, the file
+//! changed after it was encoded. Every frame has a CRC too, claxon checks those
+//! while decoding, so damaged frames show up as decode errors. We record the
+//! files that fail in the `file_issues` table.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::JoinHandle;
+
+use crate::database as db;
+use crate::database::Connection;
+use crate::database_utils;
+use crate::error::Result;
+use crate::md5::Md5;
+use crate::scan::{ScanStage, Status};
+
+/// The `kind` of the issues that verification records.
+pub const ISSUE_KIND: &str = "verify";
+
+/// The result of verifying a single file.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// The decoded audio matches the MD5 in the streaminfo.
+    Ok,
+
+    /// The file decodes, but the encoder did not store an MD5 to compare to.
+    NoMd5,
+
+    /// The file is damaged or unreadable. Contains a description of the problem.
+    Failed(String),
+}
+
+/// The outcome of verifying all files.
+#[derive(Debug)]
+pub struct Report {
+    pub files_verified: u64,
+
+    /// Of the `files_verified`, the number that we could only decode.
+    pub files_without_md5: u64,
+
+    /// The filename and the problem of every file that failed.
+    pub failures: Vec<(String, String)>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Append the samples to `out` in the layout that the MD5 covers.
+///
+/// That is interleaved, signed little endian, with as many bytes per sample
+/// as the bit depth needs.
+fn append_interleaved(channels: &[&[i32]], bytes_per_sample: usize, out: &mut Vec<u8>) {
+    let n = channels.iter().map(|ch| ch.len()).min().unwrap_or(0);
+    for i in 0..n {
+        for ch in channels {
+            out.extend_from_slice(&ch[i].to_le_bytes()[..bytes_per_sample]);
+        }
+    }
+}
+
+/// Decode the file and compare its audio against the MD5 in the streaminfo.
+pub fn verify_file(path: &Path) -> Outcome {
+    let mut reader = match claxon::FlacReader::open(path) {
+        Ok(reader) => reader,
+        Err(err) => return Outcome::Failed(format!("Failed to open the file: {}", err)),
+    };
+    let streaminfo = reader.streaminfo();
+    let bytes_per_sample = (streaminfo.bits_per_sample as usize + 7) / 8;
+
+    let mut md5 = Md5::new();
+    let mut bytes = Vec::new();
+    let mut num_samples: u64 = 0;
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::new();
+
+    loop {
+        let block = match blocks.read_next_or_eof(buffer) {
+            Ok(Some(block)) => block,
+            Ok(None) => break,
+            Err(err) => return Outcome::Failed(format!(
+                "Failed to decode after {} samples: {}", num_samples, err,
+            )),
+        };
+        let channels: Vec<&[i32]> = (0..block.channels()).map(|ch| block.channel(ch)).collect();
+        bytes.clear();
+        append_interleaved(&channels, bytes_per_sample, &mut bytes);
+        md5.update(&bytes);
+        num_samples += block.duration() as u64;
+        buffer = block.into_buffer();
+    }
+
+    if let Some(expected) = streaminfo.samples {
+        if num_samples != expected {
+            return Outcome::Failed(format!(
+                "Decoded {} samples, but the streaminfo says {}.", num_samples, expected,
+            ));
+        }
+    }
+
+    // Encoders that don't compute the MD5 leave it zero.
+    if streaminfo.md5sum.iter().all(|b| *b == 0) {
+        return Outcome::NoMd5;
+    }
+
+    let digest = md5.finish();
+    if digest != streaminfo.md5sum {
+        return Outcome::Failed(format!(
+            "The MD5 of the audio is {}, but the streaminfo says {}.",
+            Hex(&digest),
+            Hex(&streaminfo.md5sum),
+        ));
+    }
+
+    Outcome::Ok
+}
+
+/// Verify files from `files` as long as `counter` is less than `files.len()`,
+/// send the outcomes through the sender.
+fn verify_files(
+    files: &[(i64, String)],
+    counter: &AtomicUsize,
+    sender: SyncSender<(usize, Outcome)>,
+) {
+    loop {
+        let i = counter.fetch_add(1, Ordering::SeqCst);
+        if i >= files.len() {
+            break;
+        }
+        let outcome = verify_file(Path::new(&files[i].1));
+        if sender.send((i, outcome)).is_err() {
+            // The receiver stopped early, because of a database error.
+            break;
+        }
+    }
+}
+
+/// Verify all files in the database, record the failures as file issues.
+///
+/// Files that pass lose their earlier verification issue, if they had one.
+pub fn verify_all(
+    connection: &sqlite::Connection,
+    status: &mut Status,
+    status_sender: &mut SyncSender<Status>,
+) -> Result<Report> {
+    let mut db = Connection::new(connection);
+    let mut tx = db.begin()?;
+    let files = db::iter_files_for_verification(&mut tx)?.collect::<db::Result<Vec<_>>>()?;
+    tx.commit()?;
+
+    let now = chrono::Utc::now();
+    let use_zulu_suffix = true;
+    let now_str = now.to_rfc3339_opts(chrono::SecondsFormat::Millis, use_zulu_suffix);
+
+    status.stage = ScanStage::Verifying;
+    status.files_to_verify = files.len() as u64;
+    status_sender.send(*status).unwrap();
+
+    let mut report = Report {
+        files_verified: 0,
+        files_without_md5: 0,
+        failures: Vec::new(),
+    };
+
+    // Decoding is CPU-bound, so more threads than the CPU has would only
+    // compete for it, and on a spinning disk they would make it seek more.
+    let num_threads = num_cpus::get();
+    let (tx_outcome, rx_outcome) = sync_channel(num_threads * 4);
+    let counter = AtomicUsize::new(0);
+
+    crossbeam::scope::<_, Result<()>>(|scope| {
+        for i in 0..num_threads {
+            let tx = tx_outcome.clone();
+            let files_ref = &files[..];
+            let counter_ref = &counter;
+            scope
+                .builder()
+                .name(format!("verify_{}", i))
+                .spawn(move || verify_files(files_ref, counter_ref, tx))
+                .expect("Failed to spawn OS thread.");
+        }
+
+        // Close the original sender, so the loop below ends when all threads
+        // are done. We own the receiver in here, so if we return early, it is
+        // dropped before the threads are joined, and they stop.
+        std::mem::drop(tx_outcome);
+        let rx_outcome = rx_outcome;
+
+        for (i, outcome) in rx_outcome.iter() {
+            let (file_id, ref filename) = files[i];
+
+            // The server may be running while we verify, so we use a short
+            // transaction per file, instead of one that holds the write lock
+            // for the entire run.
+            let detail = match outcome {
+                Outcome::Ok => None,
+                Outcome::NoMd5 => {
+                    report.files_without_md5 += 1;
+                    None
+                }
+                Outcome::Failed(detail) => Some(detail),
+            };
+            database_utils::with_write_transaction(&mut db, |tx| match detail {
+                Some(ref detail) => db::insert_file_issue(tx, file_id, ISSUE_KIND, detail, &now_str),
+                None => db::delete_file_issue(tx, file_id, ISSUE_KIND),
+            })?;
+            if let Some(detail) = detail {
+                report.failures.push((filename.clone(), detail));
+                status.files_failed_verification += 1;
+            }
+
+            report.files_verified += 1;
+            status.files_verified += 1;
+            status_sender.send(*status).unwrap();
+        }
+
+        Ok(())
+    })?;
+
+    status.stage = ScanStage::Done;
+    status_sender.send(*status).unwrap();
+
+    Ok(report)
+}
+
+/// Verify all files in a background thread.
+///
+/// Returns the join handle for the thread, and a receiver for status updates.
+pub fn run_verify_in_thread(db_path: PathBuf) -> (JoinHandle<Result<Report>>, Receiver<Status>) {
+    // Status updates should print much faster than they are produced, so use
+    // a small buffer for them.
+    let (mut tx, rx) = sync_channel(15);
+
+    let verify_thread = std::thread::Builder::new()
+        .name("verify".to_string())
+        .spawn(move || {
+            let mut status = Status::new();
+            let connection = database_utils::connect_read_write(&db_path)?;
+            // The issues table may be newer than the database.
+            database_utils::migrate(&connection)?;
+            verify_all(&connection, &mut status, &mut tx)
+        })
+        .expect("Failed to spawn verify thread.");
+
+    (verify_thread, rx)
+}
+
+/// Print the report in a human-readable form.
+pub fn print_report(report: &Report) {
+    for (filename, detail) in &report.failures {
+        println!("{}:\n  error: {}", filename, detail);
+    }
+    println!(
+        "Verified {} files, {} failed.",
+        report.files_verified,
+        report.failures.len(),
+    );
+    if report.files_without_md5 > 0 {
+        println!(
+            "{} files have no MD5 in their streaminfo, we only checked that they decode.",
+            report.files_without_md5,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::append_interleaved;
+
+    #[test]
+    fn append_interleaved_writes_little_endian_frames() {
+        let left: &[i32] = &[1, -2];
+        let right: &[i32] = &[3, 4];
+        let mut out = Vec::new();
+        append_interleaved(&[left, right], 2, &mut out);
+        assert_eq!(out, vec![0x01, 0x00, 0x03, 0x00, 0xfe, 0xff, 0x04, 0x00]);
+
+        let mono: &[i32] = &[-1, 0x123456];
+        out.clear();
+        append_interleaved(&[mono], 3, &mut out);
+        assert_eq!(out, vec![0xff, 0xff, 0xff, 0x56, 0x34, 0x12]);
+    }
+}
@@ -0,0 +1,167 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Notifying webhooks when playback of a track starts, completes, or is skipped.
+//!
+//! The webhook thread receives events from the history thread, after the
+//! history thread has recorded them in the database, like the scrobbler. It
+//! posts a json payload to every configured url, see `docs/webhooks.md`.
+//!
+//! Webhooks are for home automation, where only the current state matters, so
+//! we do not retry failed requests. Like the scrobbler, we post through `curl`.
+
+use std::io;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::Receiver;
+
+use crate::error::{Error, Result};
+use crate::mvar::Var;
+use crate::player::QueueId;
+use crate::{MemoryMetaIndex, MetaIndex, TrackId};
+
+/// What happened to the queue entry.
+pub enum EventType {
+    /// Playback of the track started, the client is the one that enqueued it.
+    Started { client: Option<String> },
+
+    /// Playback of the track completed.
+    Completed,
+
+    /// The user skipped the track, at the given position.
+    Skipped { position_seconds: u32 },
+}
+
+/// Event for the webhook thread.
+pub struct WebhookEvent {
+    pub event_type: EventType,
+
+    /// The time of the event, RFC 3339 formatted.
+    pub at: String,
+
+    pub queue_id: QueueId,
+    pub track_id: TrackId,
+}
+
+/// Write the json payload for the event.
+///
+/// Returns `Ok(false)` without writing anything if the track is not in the index.
+fn write_payload<W: Write>(
+    index: &MemoryMetaIndex,
+    mut w: W,
+    event: &WebhookEvent,
+) -> io::Result<bool> {
+    let track = match index.get_track(event.track_id) {
+        Some(t) => t,
+        None => return Ok(false),
+    };
+    let album_id = event.track_id.album_id();
+    let album = index.get_album(album_id).unwrap();
+
+    let name = match event.event_type {
+        EventType::Started { .. } => "started",
+        EventType::Completed => "completed",
+        EventType::Skipped { .. } => "skipped",
+    };
+    write!(w, r#"{{"event":"{}","at":"#, name)?;
+    serde_json::to_writer(&mut w, &event.at)?;
+    write!(
+        w,
+        r#","queue_id":"{}","track_id":"{}","album_id":"{}","title":"#,
+        event.queue_id,
+        event.track_id,
+        album_id,
+    )?;
+    serde_json::to_writer(&mut w, index.get_string(track.title))?;
+    write!(w, r#","artist":"#)?;
+    serde_json::to_writer(&mut w, index.get_string(track.artist))?;
+    write!(w, r#","album":"#)?;
+    serde_json::to_writer(&mut w, index.get_string(album.title))?;
+    write!(w, r#","album_artist":"#)?;
+    serde_json::to_writer(&mut w, index.get_string(album.artist))?;
+    write!(w, r#","duration_seconds":{}"#, track.duration_seconds)?;
+
+    match &event.event_type {
+        EventType::Started { client } => {
+            write!(w, r#","client":"#)?;
+            serde_json::to_writer(&mut w, client)?;
+        }
+        EventType::Completed => {}
+        EventType::Skipped { position_seconds } => {
+            write!(w, r#","position_seconds":{}"#, position_seconds)?;
+        }
+    }
+
+    write!(w, "}}")?;
+    Ok(true)
+}
+
+/// Post the json payload to the url.
+fn post(url: &str, payload: &[u8]) -> Result<()> {
+    let mut curl = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail"])
+        // Webhooks are for things that happen now, a late notification is
+        // not much better than none.
+        .args(["--max-time", "10"])
+        .args(["--header", "Content-Type: application/json"])
+        .args(["--data-binary", "@-"])
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::CommandError("Failed to spawn 'curl'.", e))?;
+
+    {
+        let stdin = curl.stdin.as_mut().expect("Stdin is piped.");
+        stdin
+            .write_all(payload)
+            .map_err(|e| Error::CommandError("Failed to write to 'curl'.", e))?;
+    }
+
+    let output = curl
+        .wait_with_output()
+        .map_err(|e| Error::CommandError("Failed to wait for 'curl'.", e))?;
+
+    if !output.status.success() {
+        return Err(Error::WebhookError(format!(
+            "curl exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        )));
+    }
+
+    Ok(())
+}
+
+/// Main for the thread that posts to webhooks.
+pub fn main(
+    index_var: Var<MemoryMetaIndex>,
+    urls: Vec<String>,
+    events: Receiver<WebhookEvent>,
+) {
+    for event in events {
+        let mut payload = Vec::new();
+        let index = index_var.get();
+        match write_payload(&index, &mut payload, &event) {
+            Ok(true) => {}
+            // The index may have been replaced by a rescan since the event.
+            Ok(false) => continue,
+            Err(err) => {
+                error!("Failed to format webhook payload: {:?}", err);
+                continue;
+            }
+        }
+
+        // The urls may contain secrets, so we refer to them by number.
+        for (i, url) in urls.iter().enumerate() {
+            if let Err(err) = post(url, &payload) {
+                warn!("Failed to post to webhook {}: {:?}", i + 1, err);
+            }
+        }
+    }
+}
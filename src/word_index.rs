@@ -176,6 +176,9 @@ pub trait WordIndex {
 
     /// Return the metadata associated with the values at the offset.
     fn get_meta(&self, offset: u32) -> &WordMeta;
+
+    /// Return the `i`-th word in the index, in memcmp order.
+    fn get_word(&self, i: usize) -> &str;
 }
 
 pub struct MemoryWordIndex<T> {
@@ -202,13 +205,20 @@ pub struct WordIndexSize {
     num_values: usize,
 }
 
+impl WordIndexSize {
+    /// Return the total number of bytes that the index uses.
+    pub fn total_bytes(&self) -> usize {
+        self.key_data_bytes + self.value_data_bytes + self.meta_data_bytes + self.slice_bytes
+    }
+}
+
 impl fmt::Display for WordIndexSize {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f,
             "{:5} keys, {:5} values, {:4} kB ({:3} kB keys, {:3} kB values, {:3} kB meta, {:3} kB slices)",
             self.num_keys,
             self.num_values,
-            (self.key_data_bytes + self.value_data_bytes + self.meta_data_bytes + self.slice_bytes) / 1000,
+            self.total_bytes() / 1000,
             self.key_data_bytes / 1000,
             self.value_data_bytes / 1000,
             self.meta_data_bytes / 1000,
@@ -403,6 +413,10 @@ impl<T> WordIndex for MemoryWordIndex<T> {
         &self.meta_data[offset as usize]
     }
 
+    fn get_word(&self, i: usize) -> &str {
+        self.get_key(self.key_slices[i])
+    }
+
     fn search_exact(&self, word: &str) -> Option<Values> {
         let index = self.find_lower(word);
 
@@ -0,0 +1,98 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Writing playlists in the XSPF format, see https://xspf.org/spec.
+//!
+//! Unlike our M3U8 export, which references files on disk, XSPF playlists
+//! reference the tracks by their streaming url, so other players on the network
+//! can play them.
+
+use std::io;
+use std::io::Write;
+
+use crate::prim::TrackId;
+use crate::MetaIndex;
+
+/// Write the string with the characters that are special in XML escaped.
+pub(crate) fn write_escaped<W: Write>(mut w: W, s: &str) -> io::Result<()> {
+    let mut start = 0;
+    for (i, ch) in s.char_indices() {
+        let escaped = match ch {
+            '&' => "&amp;",
+            '<' => "&lt;",
+            '>' => "&gt;",
+            '"' => "&quot;",
+            '\'' => "&apos;",
+            _ => continue,
+        };
+        w.write_all(s[start..i].as_bytes())?;
+        w.write_all(escaped.as_bytes())?;
+        start = i + 1;
+    }
+    w.write_all(s[start..].as_bytes())
+}
+
+fn write_element<W: Write>(mut w: W, tag: &str, content: &str) -> io::Result<()> {
+    write!(w, "<{}>", tag)?;
+    write_escaped(&mut w, content)?;
+    writeln!(w, "</{}>", tag)
+}
+
+/// Write the tracks as an XSPF playlist.
+///
+/// `base_url` is the url under which the server is reachable, without trailing
+/// slash, e.g. `http://musium.local:8233`.
+pub fn write_xspf<W: Write>(
+    index: &dyn MetaIndex,
+    mut w: W,
+    base_url: &str,
+    title: &str,
+    tracks: &[TrackId],
+) -> io::Result<()> {
+    writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(w, r#"<playlist version="1" xmlns="http://xspf.org/ns/0/">"#)?;
+    write_element(&mut w, "title", title)?;
+    writeln!(w, "<trackList>")?;
+    for &track_id in tracks {
+        let track = match index.get_track(track_id) {
+            Some(t) => t,
+            None => continue,
+        };
+        let album_id = track_id.album_id();
+        let album = index.get_album(album_id).unwrap();
+        writeln!(w, "<track>")?;
+        write_element(&mut w, "location", &format!("{}/api/track/{}.flac", base_url, track_id))?;
+        write_element(&mut w, "title", index.get_string(track.title))?;
+        write_element(&mut w, "creator", index.get_string(track.artist))?;
+        write_element(&mut w, "album", index.get_string(album.title))?;
+        write_element(&mut w, "trackNum", &track_id.track_number().to_string())?;
+        // XSPF durations are in milliseconds.
+        write_element(&mut w, "duration", &(track.duration_seconds as u64 * 1000).to_string())?;
+        write_element(&mut w, "image", &format!("{}/api/cover/{}", base_url, album_id))?;
+        writeln!(w, "</track>")?;
+    }
+    writeln!(w, "</trackList>")?;
+    writeln!(w, "</playlist>")
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_escaped;
+
+    #[test]
+    fn write_escaped_escapes_special_characters() {
+        let mut out = Vec::new();
+        write_escaped(&mut out, r#"Tom & Jerry's <"Best">"#).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Tom &amp; Jerry&apos;s &lt;&quot;Best&quot;&gt;",
+        );
+        let mut out = Vec::new();
+        write_escaped(&mut out, "Daði").unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "Daði");
+    }
+}
@@ -0,0 +1,313 @@
+// Musium -- Music playback daemon with web-based library browser
+// Copyright 2023 Ruud van Asseldonk
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! A minimal streaming zip writer.
+//!
+//! Audio does not compress, so we only support the "stored" method, which
+//! means the archive is just the files with headers in between. The sizes and
+//! checksum of an entry follow its data in a data descriptor, so we can write
+//! an entry without knowing its size up front, which we don't when transcoding.
+//!
+//! Individual entries must be smaller than 4 GiB, but the archive as a whole
+//! may be larger, in which case we write the Zip64 end of central directory.
+//! See also <https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT>.
+
+use std::io;
+use std::io::{Read, Write};
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+
+/// Flag bit 3: sizes and crc are in the data descriptor after the data.
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+
+/// Flag bit 11: the file name is UTF-8.
+const FLAG_UTF8: u16 = 1 << 11;
+
+/// Version 2.0, needed for directories and data descriptors.
+const VERSION_DEFAULT: u16 = 20;
+
+/// Version 4.5, needed for Zip64 extensions.
+const VERSION_ZIP64: u16 = 45;
+
+/// "Version made by" with the upper byte 3 for Unix, so the attributes apply.
+const VERSION_MADE_BY: u16 = (3 << 8) | VERSION_ZIP64;
+
+/// Regular file with mode 0644, in the upper half of the external attributes.
+const EXTERNAL_ATTRIBUTES: u32 = 0o100644 << 16;
+
+const CRC32_TABLE: [u32; 256] = make_crc32_table();
+
+const fn make_crc32_table() -> [u32; 256] {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            k += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continue the CRC-32 of the bytes before `data`, start with `crc = 0`.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Return the MS-DOS (date, time) pair that zip uses for modification times.
+fn dos_date_time(t: NaiveDateTime) -> (u16, u16) {
+    // The format cannot represent anything before 1980.
+    if t.year() < 1980 {
+        return ((1 << 5) | 1, 0);
+    }
+    let date = (((t.year() - 1980) as u16) << 9) | ((t.month() as u16) << 5) | t.day() as u16;
+    let time = ((t.hour() as u16) << 11) | ((t.minute() as u16) << 5) | (t.second() as u16 / 2);
+    (date, time)
+}
+
+struct Entry {
+    name: String,
+    date: u16,
+    time: u16,
+    crc32: u32,
+    size: u32,
+    offset: u64,
+}
+
+/// Writes a zip archive to `W`, one entry at a time.
+pub struct ZipWriter<W: Write> {
+    inner: W,
+    offset: u64,
+    entries: Vec<Entry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(inner: W) -> ZipWriter<W> {
+        ZipWriter {
+            inner: inner,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_u16(&mut self, x: u16) -> io::Result<()> {
+        self.write_all(&x.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, x: u32) -> io::Result<()> {
+        self.write_all(&x.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, x: u64) -> io::Result<()> {
+        self.write_all(&x.to_le_bytes())
+    }
+
+    /// Add an entry to the archive, with the contents that `data` yields.
+    ///
+    /// Use forward slashes in `name` to put the file in a directory.
+    pub fn add_file<R: Read>(
+        &mut self,
+        name: &str,
+        modified: NaiveDateTime,
+        mut data: R,
+    ) -> io::Result<()> {
+        if name.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Zip entry name is too long."));
+        }
+        let (date, time) = dos_date_time(modified);
+        let offset = self.offset;
+
+        // Local file header. The crc and sizes are zero, they follow in the
+        // data descriptor.
+        self.write_u32(0x0403_4b50)?;
+        self.write_u16(VERSION_DEFAULT)?;
+        self.write_u16(FLAG_DATA_DESCRIPTOR | FLAG_UTF8)?;
+        self.write_u16(0)?; // Compression method: stored.
+        self.write_u16(time)?;
+        self.write_u16(date)?;
+        self.write_u32(0)?; // Crc-32.
+        self.write_u32(0)?; // Compressed size.
+        self.write_u32(0)?; // Uncompressed size.
+        self.write_u16(name.len() as u16)?;
+        self.write_u16(0)?; // Extra field length.
+        self.write_all(name.as_bytes())?;
+
+        let mut crc32 = 0;
+        let mut size: u64 = 0;
+        let mut buffer = vec![0_u8; 64 * 1024];
+        loop {
+            let n = match data.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            crc32 = crc32_update(crc32, &buffer[..n]);
+            size += n as u64;
+            self.write_all(&buffer[..n])?;
+        }
+
+        if size >= u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Zip entry exceeds 4 GiB."));
+        }
+        let size = size as u32;
+
+        // Data descriptor.
+        self.write_u32(0x0807_4b50)?;
+        self.write_u32(crc32)?;
+        self.write_u32(size)?; // Compressed size.
+        self.write_u32(size)?; // Uncompressed size.
+
+        let entry = Entry {
+            name: name.to_string(),
+            date: date,
+            time: time,
+            crc32: crc32,
+            size: size,
+            offset: offset,
+        };
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Write the central directory, and return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let entries = std::mem::take(&mut self.entries);
+        let cd_offset = self.offset;
+
+        for entry in &entries {
+            let needs_zip64 = entry.offset >= u32::MAX as u64;
+            self.write_u32(0x0201_4b50)?;
+            self.write_u16(VERSION_MADE_BY)?;
+            self.write_u16(if needs_zip64 { VERSION_ZIP64 } else { VERSION_DEFAULT })?;
+            self.write_u16(FLAG_DATA_DESCRIPTOR | FLAG_UTF8)?;
+            self.write_u16(0)?; // Compression method: stored.
+            self.write_u16(entry.time)?;
+            self.write_u16(entry.date)?;
+            self.write_u32(entry.crc32)?;
+            self.write_u32(entry.size)?; // Compressed size.
+            self.write_u32(entry.size)?; // Uncompressed size.
+            self.write_u16(entry.name.len() as u16)?;
+            self.write_u16(if needs_zip64 { 12 } else { 0 })?; // Extra field length.
+            self.write_u16(0)?; // Comment length.
+            self.write_u16(0)?; // Disk number start.
+            self.write_u16(0)?; // Internal attributes.
+            self.write_u32(EXTERNAL_ATTRIBUTES)?;
+            self.write_u32(if needs_zip64 { u32::MAX } else { entry.offset as u32 })?;
+            self.write_all(entry.name.as_bytes())?;
+            if needs_zip64 {
+                // Zip64 extended information, with only the offset.
+                self.write_u16(0x0001)?;
+                self.write_u16(8)?;
+                self.write_u64(entry.offset)?;
+            }
+        }
+
+        let cd_size = self.offset - cd_offset;
+        let num_entries = entries.len() as u64;
+        let needs_zip64 = cd_offset >= u32::MAX as u64
+            || cd_size >= u32::MAX as u64
+            || num_entries >= u16::MAX as u64;
+
+        if needs_zip64 {
+            let zip64_eocd_offset = self.offset;
+
+            // Zip64 end of central directory record.
+            self.write_u32(0x0606_4b50)?;
+            self.write_u64(44)?; // Size of the remainder of this record.
+            self.write_u16(VERSION_MADE_BY)?;
+            self.write_u16(VERSION_ZIP64)?;
+            self.write_u32(0)?; // Number of this disk.
+            self.write_u32(0)?; // Disk where the central directory starts.
+            self.write_u64(num_entries)?; // Entries on this disk.
+            self.write_u64(num_entries)?; // Total entries.
+            self.write_u64(cd_size)?;
+            self.write_u64(cd_offset)?;
+
+            // Zip64 end of central directory locator.
+            self.write_u32(0x0706_4b50)?;
+            self.write_u32(0)?; // Disk with the zip64 end of central directory.
+            self.write_u64(zip64_eocd_offset)?;
+            self.write_u32(1)?; // Total number of disks.
+        }
+
+        // End of central directory record. When a field does not fit, it is
+        // all ones, and readers take it from the Zip64 record.
+        self.write_u32(0x0605_4b50)?;
+        self.write_u16(0)?; // Number of this disk.
+        self.write_u16(0)?; // Disk where the central directory starts.
+        self.write_u16(num_entries.min(u16::MAX as u64) as u16)?; // Entries on this disk.
+        self.write_u16(num_entries.min(u16::MAX as u64) as u16)?; // Total entries.
+        self.write_u32(cd_size.min(u32::MAX as u64) as u32)?;
+        self.write_u32(cd_offset.min(u32::MAX as u64) as u32)?;
+        self.write_u16(0)?; // Comment length.
+
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc32_update, dos_date_time, ZipWriter};
+    use chrono::NaiveDate;
+
+    #[test]
+    fn crc32_matches_check_value() {
+        assert_eq!(crc32_update(0, b"123456789"), 0xcbf4_3926);
+        // Updating incrementally gives the same result.
+        assert_eq!(crc32_update(crc32_update(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn dos_date_time_packs_fields() {
+        let t = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap().and_hms_opt(18, 30, 21).unwrap();
+        let (date, time) = dos_date_time(t);
+        assert_eq!(date, (43 << 9) | (6 << 5) | 1);
+        assert_eq!(time, (18 << 11) | (30 << 5) | 10);
+    }
+
+    #[test]
+    fn zip_writer_writes_entries_and_central_directory() {
+        let t = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let mut zip = ZipWriter::new(Vec::new());
+        zip.add_file("Album/01 Intro.flac", t, &b"hello"[..]).unwrap();
+        zip.add_file("Album/02 Outro.flac", t, &b""[..]).unwrap();
+        let bytes = zip.finish().unwrap();
+
+        // Local file header, then the name and data.
+        assert_eq!(&bytes[0..4], b"PK\x03\x04");
+        assert_eq!(&bytes[30..49], b"Album/01 Intro.flac");
+        assert_eq!(&bytes[49..54], b"hello");
+        // Data descriptor, with the crc of "hello" and its size twice.
+        assert_eq!(&bytes[54..58], b"PK\x07\x08");
+        assert_eq!(&bytes[58..62], &0x3610_a686_u32.to_le_bytes());
+        assert_eq!(&bytes[62..70], &[5, 0, 0, 0, 5, 0, 0, 0]);
+
+        // The end of central directory record lists both entries, and points
+        // at the central directory.
+        let eocd = &bytes[bytes.len() - 22..];
+        assert_eq!(&eocd[0..4], b"PK\x05\x06");
+        assert_eq!(&eocd[8..12], &[2, 0, 2, 0]);
+        let cd_offset = u32::from_le_bytes([eocd[16], eocd[17], eocd[18], eocd[19]]) as usize;
+        assert_eq!(&bytes[cd_offset..cd_offset + 4], b"PK\x01\x02");
+    }
+}